@@ -0,0 +1,83 @@
+// Benchmarks for issuing certificates in bulk, with the CA's static extensions cached across
+// issuances and rebuilt for each one. Run with `cargo bench --bench issuance`.
+
+use std::time::{Duration, SystemTime};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use openssl::{
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::PKey,
+    x509::{extension::SubjectAlternativeName, X509Name, X509Req},
+};
+
+use coyote::acme::ca::CA;
+
+const ISSUANCES: u64 = 1000;
+
+fn csr() -> X509Req {
+    let key = PKey::from_ec_key(
+        EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap(),
+    )
+    .unwrap();
+
+    let mut name = X509Name::builder().unwrap();
+    name.append_entry_by_text("CN", "example.com").unwrap();
+
+    let mut req = X509Req::builder().unwrap();
+    req.set_subject_name(&name.build()).unwrap();
+    req.set_pubkey(&key).unwrap();
+
+    let mut extensions = openssl::stack::Stack::new().unwrap();
+    extensions
+        .push(
+            SubjectAlternativeName::new()
+                .dns("example.com")
+                .build(&req.x509v3_context(None))
+                .unwrap(),
+        )
+        .unwrap();
+    req.add_extensions(&extensions).unwrap();
+    req.sign(&key, MessageDigest::sha256()).unwrap();
+    req.build()
+}
+
+fn issuance(c: &mut Criterion) {
+    let ca = CA::new_test_ca()
+        .unwrap()
+        .with_ocsp_url("http://ocsp.example.com")
+        .with_crl_url("http://crl.example.com/ca.crl");
+    let req = csr();
+    let not_after = SystemTime::now() + Duration::from_secs(24 * 60 * 60);
+
+    let mut group = c.benchmark_group("issue 1000 certificates");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(ISSUANCES));
+
+    group.bench_function("cached extensions", |b| {
+        b.iter(|| {
+            for _ in 0..ISSUANCES {
+                ca.generate_and_sign_cert(req.clone(), SystemTime::now(), not_after)
+                    .unwrap();
+            }
+        })
+    });
+
+    // any with_* call drops the cached template, so each issuance builds the extensions afresh.
+    group.bench_function("uncached extensions", |b| {
+        b.iter(|| {
+            for _ in 0..ISSUANCES {
+                ca.clone()
+                    .with_client_auth(false)
+                    .generate_and_sign_cert(req.clone(), SystemTime::now(), not_after)
+                    .unwrap();
+            }
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, issuance);
+criterion_main!(benches);
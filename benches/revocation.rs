@@ -0,0 +1,90 @@
+// Benchmarks for checking whether a serial is revoked, against 100,000 revocations: the indexed
+// lookup of Postgres::is_serial_revoked, and the same query made to scan the table. The database
+// is named by COYOTE_BENCH_POSTGRES_URL, in the form COYOTE_TEST_POSTGRES_URL takes; the
+// benchmarks keep to a schema of their own in it. Run with
+// `COYOTE_BENCH_POSTGRES_URL=... cargo bench --bench revocation`.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use tokio::runtime::Runtime;
+
+use coyote::models::{Postgres, PostgresConfig};
+
+const POSTGRES_URL_VAR: &str = "COYOTE_BENCH_POSTGRES_URL";
+const SCHEMA: &str = "coyote_bench_revocation";
+const REVOCATIONS: i64 = 100_000;
+
+fn revocation(c: &mut Criterion) {
+    let url = match std::env::var(POSTGRES_URL_VAR) {
+        Ok(url) if !url.is_empty() => url,
+        _ => {
+            eprintln!(
+                "{} is not set; skipping the revocation benchmarks",
+                POSTGRES_URL_VAR
+            );
+            return;
+        }
+    };
+
+    let rt = Runtime::new().unwrap();
+
+    let (db, scan) = rt.block_on(async {
+        let db = Postgres::with_config(PostgresConfig::new(&url).with_schema(SCHEMA))
+            .await
+            .unwrap();
+        db.migrate().await.unwrap();
+
+        let client = db.clone().client().await.unwrap();
+        client
+            .execute("truncate revocations", &[])
+            .await
+            .unwrap();
+        client
+            .execute(
+                "insert into revocations (serial) select int8send(g) from generate_series(1, $1) g",
+                &[&REVOCATIONS],
+            )
+            .await
+            .unwrap();
+        client.execute("analyze revocations", &[]).await.unwrap();
+
+        // a connection of its own, on which the planner may not use the serial index.
+        let scan = Postgres::connect_one(&url).await.unwrap();
+        scan.batch_execute(&format!(
+            "set search_path = {}; set enable_indexscan = off; set enable_indexonlyscan = off; set enable_bitmapscan = off;",
+            SCHEMA
+        ))
+        .await
+        .unwrap();
+
+        (db, scan)
+    });
+
+    let revoked = (REVOCATIONS / 2).to_be_bytes().to_vec();
+    let missing = (REVOCATIONS * 2).to_be_bytes().to_vec();
+
+    let mut group = c.benchmark_group("is serial revoked, 100000 revocations");
+
+    for (name, serial) in vec![("revoked", &revoked), ("not revoked", &missing)] {
+        group.bench_function(format!("index lookup, {}", name), |b| {
+            b.to_async(&rt)
+                .iter(|| async { db.is_serial_revoked(serial).await.unwrap() })
+        });
+
+        group.bench_function(format!("table scan, {}", name), |b| {
+            b.to_async(&rt).iter(|| async {
+                scan.query_one(
+                    "select exists(select 1 from revocations where serial = $1)",
+                    &[serial],
+                )
+                .await
+                .unwrap()
+                .get::<_, bool>(0)
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, revocation);
+criterion_main!(benches);
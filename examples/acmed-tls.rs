@@ -1,24 +1,27 @@
 use std::{
     io::Write,
     ops::Add,
+    sync::Arc,
     time::{Duration, SystemTime},
 };
 
 use openssl::{
     error::ErrorStack,
+    hash::MessageDigest,
     pkey::{PKey, Private},
     rsa::Rsa,
-    x509::{X509Extension, X509Name, X509Req},
+    x509::{X509Extension, X509Name, X509Req, X509},
 };
 
 use coyote::{
     acme::{
-        ca::{CACollector, CA},
+        ca::{CACollector, RotationPolicy, CA},
         challenge::Challenger,
-        handlers::{configure_routes, ServiceState},
+        handlers::{configure_routes, serve, ServiceState, TlsConfig},
+        http01::ReqwestHttpClient,
         PostgresNonceValidator,
     },
-    models::Postgres,
+    models::{Postgres, PostgresConfig},
 };
 
 use ratpack::prelude::*;
@@ -40,12 +43,15 @@ async fn main() -> Result<(), ServerError> {
     //
     // make postgres
     //
-    let pg = Postgres::new("host=localhost dbname=coyote user=postgres", 10)
-        .await
-        .unwrap();
+    let pg = Postgres::with_config(
+        PostgresConfig::new("host=localhost dbname=coyote user=postgres").with_max_connections(10),
+    )
+    .await
+    .unwrap();
     pg.migrate().await.unwrap();
 
-    let c = Challenger::new(Some(chrono::Duration::seconds(CHALLENGE_EXPIRATION)));
+    let c = Challenger::new(Some(chrono::Duration::seconds(CHALLENGE_EXPIRATION)))
+        .with_http_client(Arc::new(ReqwestHttpClient::default()));
     let ca = CACollector::new(Duration::MAX);
 
     let pg2 = pg.clone();
@@ -55,7 +61,7 @@ async fn main() -> Result<(), ServerError> {
     tokio::spawn(async move {
         loop {
             // FIXME whitelist all challenge requests. This is not how ACME is supposed to work. You have to write this.
-            c2.tick(|_c| Some(())).await;
+            c2.tick(|_c, _| Some(())).await;
             // NOTE this will explode violently if it unwraps to error, e.g. if the db goes down.
             c2.reconcile(pg2.clone()).await.unwrap();
 
@@ -63,6 +69,28 @@ async fn main() -> Result<(), ServerError> {
         }
     });
 
+    let pg3 = pg.clone();
+
+    tokio::spawn(async move {
+        // archive finished orders older than 90 days, once a week.
+        pg3.archive_orders_periodically(
+            Duration::from_secs(90 * 24 * 60 * 60),
+            Duration::from_secs(7 * 24 * 60 * 60),
+        )
+        .await
+    });
+
+    let pg4 = pg.clone();
+
+    tokio::spawn(async move {
+        // hourly, report orders that will expire in the next day without being finalized.
+        pg4.warn_expiring_orders_periodically(
+            Duration::from_secs(24 * 60 * 60),
+            Duration::from_secs(60 * 60),
+        )
+        .await
+    });
+
     let mut ca2 = ca.clone();
     let (csr, key) = generate_csr(dnsname)?;
 
@@ -89,36 +117,42 @@ async fn main() -> Result<(), ServerError> {
         let cert = test_ca.clone().certificate().to_pem().unwrap();
         buf.write(&cert).unwrap();
 
-        ca2.spawn_collector(|| -> Result<CA, ErrorStack> { Ok(test_ca.clone()) })
+        ca2.spawn_collector(
+            || -> Result<(CA, Vec<X509>), ErrorStack> { Ok((test_ca.clone(), vec![])) },
+            RotationPolicy::default(),
+        )
+        .await
+    });
+
+    let validator = PostgresNonceValidator::new(pg.clone(), None);
+    let validator2 = validator.clone();
+
+    tokio::spawn(async move {
+        // remove nonces which expired without being used, every minute.
+        validator2
+            .cleanup_periodically(Duration::from_secs(60))
             .await
     });
 
-    let validator = PostgresNonceValidator::new(pg.clone());
-    let ss = ServiceState::new(
-        format!("https://{}:8000", dnsname),
-        pg.clone(),
-        c,
-        ca,
-        validator,
-    )?;
+    let mut cert_pem = cert.to_pem()?;
+    cert_pem.append(&mut test_ca2.certificate().to_pem()?);
+
+    let ss = ServiceState::builder()
+        .url(format!("https://{}:8000", dnsname))
+        .db(pg.clone())
+        .challenger(c)
+        .ca(ca)
+        .nonce_validator(validator)
+        .build()?
+        .with_tls(TlsConfig {
+            cert_pem,
+            key_pem: key.private_key_to_pem()?,
+        });
     let mut app = App::with_state(ss);
 
     configure_routes(&mut app, None);
 
-    let key = key.private_key_to_der()?;
-
-    let config = rustls::ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(
-            vec![
-                rustls::Certificate(cert.to_der()?),
-                rustls::Certificate(test_ca2.certificate().to_der()?),
-            ],
-            rustls::PrivateKey(key),
-        )?;
-
-    Ok(app.serve_tls("0.0.0.0:8000", config).await?)
+    serve(app, "0.0.0.0:8000").await
 }
 
 fn generate_csr(dnsname: &str) -> Result<(X509Req, Rsa<Private>), ErrorStack> {
@@ -138,9 +172,9 @@ fn generate_csr(dnsname: &str) -> Result<(X509Req, Rsa<Private>), ErrorStack> {
     req.set_version(2)?;
 
     let key = Rsa::generate(4096).unwrap();
-    // FIXME there has to be a much better way of doing this!
-    let pubkey = PKey::public_key_from_pem(&key.public_key_to_pem().unwrap()).unwrap();
+    let pkey = PKey::from_rsa(key.clone())?;
 
-    req.set_pubkey(&pubkey).unwrap();
+    req.set_pubkey(&pkey).unwrap();
+    req.sign(&pkey, MessageDigest::sha256())?;
     Ok((req.build(), key))
 }
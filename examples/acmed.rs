@@ -1,15 +1,16 @@
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
 
-use openssl::error::ErrorStack;
+use openssl::{error::ErrorStack, x509::X509};
 
 use coyote::{
     acme::{
-        ca::{CACollector, CA},
+        ca::{CACollector, CRLCollector, RotationPolicy, SigningAlgorithm, CA},
         challenge::Challenger,
         handlers::{configure_routes, ServiceState},
+        http01::ReqwestHttpClient,
         PostgresNonceValidator,
     },
-    models::Postgres,
+    models::{Postgres, PostgresConfig},
 };
 
 use ratpack::prelude::*;
@@ -26,12 +27,15 @@ async fn main() -> Result<(), ServerError> {
     //
     // make postgres
     //
-    let pg = Postgres::new("host=localhost dbname=coyote user=postgres", 10)
-        .await
-        .unwrap();
+    let pg = Postgres::with_config(
+        PostgresConfig::new("host=localhost dbname=coyote user=postgres").with_max_connections(10),
+    )
+    .await
+    .unwrap();
     pg.migrate().await.unwrap();
 
-    let c = Challenger::new(Some(chrono::Duration::seconds(CHALLENGE_EXPIRATION)));
+    let c = Challenger::new(Some(chrono::Duration::seconds(CHALLENGE_EXPIRATION)))
+        .with_http_client(Arc::new(ReqwestHttpClient::default()));
     let ca = CACollector::new(Duration::MAX);
 
     let pg2 = pg.clone();
@@ -41,7 +45,7 @@ async fn main() -> Result<(), ServerError> {
     tokio::spawn(async move {
         loop {
             // FIXME whitelist all challenge requests. This is not how ACME is supposed to work. You have to write this.
-            c2.tick(|_c| Some(())).await;
+            c2.tick(|_c, _| Some(())).await;
             // NOTE this will explode violently if it unwraps to error, e.g. if the db goes down.
             c2.reconcile(pg2.clone()).await.unwrap();
 
@@ -49,22 +53,81 @@ async fn main() -> Result<(), ServerError> {
         }
     });
 
+    let pg3 = pg.clone();
+
+    tokio::spawn(async move {
+        // archive finished orders older than 90 days, once a week.
+        pg3.archive_orders_periodically(
+            Duration::from_secs(90 * 24 * 60 * 60),
+            Duration::from_secs(7 * 24 * 60 * 60),
+        )
+        .await
+    });
+
+    let pg4 = pg.clone();
+
+    tokio::spawn(async move {
+        // hourly, report orders that will expire in the next day without being finalized.
+        pg4.warn_expiring_orders_periodically(
+            Duration::from_secs(24 * 60 * 60),
+            Duration::from_secs(60 * 60),
+        )
+        .await
+    });
+
     let mut ca2 = ca.clone();
-    let test_ca = CA::new_test_ca().unwrap();
+    // the root only signs the intermediate; in a real deployment it would be kept offline.
+    let root_ca = CA::new_test_root_ca(SigningAlgorithm::Rsa4096).unwrap();
+    let test_ca = CA::new_intermediate(
+        &root_ca,
+        "Intermediate Signing Certificate",
+        SigningAlgorithm::EcdsaP256,
+        Duration::from_secs(90 * 24 * 60 * 60),
+    )
+    .unwrap()
+    .with_crl_url("http://127.0.0.1:8000/crl");
+    let root_cert = root_ca.certificate();
+
+    tokio::spawn(async move {
+        ca2.spawn_collector(
+            || -> Result<(CA, Vec<X509>), ErrorStack> {
+                Ok((test_ca.clone(), vec![root_cert.clone()]))
+            },
+            RotationPolicy::default(),
+        )
+        .await
+    });
+
+    // regenerate the CRL hourly; each one is valid for a day.
+    let crl = CRLCollector::new(
+        Duration::from_secs(60 * 60),
+        Duration::from_secs(24 * 60 * 60),
+    );
+    let (crl2, ca3, pg5) = (crl.clone(), ca.clone(), pg.clone());
+
+    tokio::spawn(async move {
+        crl2.spawn_collector(ca3, || pg5.list_revoked_certificates())
+            .await
+    });
+
+    let validator = PostgresNonceValidator::new(pg.clone(), None);
+    let validator2 = validator.clone();
 
     tokio::spawn(async move {
-        ca2.spawn_collector(|| -> Result<CA, ErrorStack> { Ok(test_ca.clone()) })
+        // remove nonces which expired without being used, every minute.
+        validator2
+            .cleanup_periodically(Duration::from_secs(60))
             .await
     });
 
-    let validator = PostgresNonceValidator::new(pg.clone());
-    let ss = ServiceState::new(
-        "http://127.0.0.1:8000".to_string(),
-        pg.clone(),
-        c,
-        ca,
-        validator,
-    )?;
+    let ss = ServiceState::builder()
+        .url("http://127.0.0.1:8000".to_string())
+        .db(pg.clone())
+        .challenger(c)
+        .ca(ca)
+        .nonce_validator(validator)
+        .build()?
+        .with_crl(crl);
     let mut app = App::with_state(ss);
 
     configure_routes(&mut app, None);
@@ -0,0 +1,2 @@
+alter table nonces add column created_at timestamptz default CURRENT_TIMESTAMP not null;
+create index nonces_created_at_idx on nonces (created_at);
@@ -0,0 +1 @@
+alter table orders_challenges add column retry_count integer default 0 not null;
@@ -0,0 +1,2 @@
+-- the identifier type of the authorization; existing rows were all dns names.
+alter table orders_authorizations add column kind varchar default 'dns' not null check (kind in ('dns', 'ip'));
@@ -0,0 +1,13 @@
+-- set by the challenger once an order or authorization is past its expires timestamp (RFC8555
+-- 7.1.3, 7.1.4). orders created before this carried no deadline, so they are given the default
+-- lifetime from their creation.
+update orders set expires = created_at + interval '7 days' where expires is null;
+-- authorizations were stored with the time of their creation as their deadline, which would make
+-- every one of them expire at once; they get 30 days from their creation instead.
+update orders_authorizations set expires = created_at + interval '30 days' where expires <= created_at;
+alter table orders add column expired bool default false not null;
+-- archived orders are copied column for column.
+alter table orders_archive add column expired bool default false not null;
+alter table orders_authorizations add column expired bool default false not null;
+create index orders_expires_idx on orders (expires) where not expired and deleted_at is null;
+create index orders_authorizations_expires_idx on orders_authorizations (expires) where not expired and deleted_at is null;
@@ -0,0 +1,8 @@
+-- accounts, orders and authorizations belong to a tenant; the empty tenant is the default, so
+-- that existing rows belong to it.
+alter table jwks add column tenant_id text not null default '';
+alter table accounts add column tenant_id text not null default '';
+alter table orders add column tenant_id text not null default '';
+-- archived orders are copied column for column.
+alter table orders_archive add column tenant_id text not null default '';
+alter table orders_authorizations add column tenant_id text not null default '';
@@ -0,0 +1,17 @@
+-- an audit trail of authorizations which became invalid or expired without being validated.
+create table failed_authorizations (
+  id serial primary key,
+  account_id integer,
+  authorization_id varchar not null,
+  identifier varchar not null,
+  -- the challenge which failed; null when the authorization expired.
+  challenge_type varchar,
+  reason varchar not null,
+  tenant_id text not null default '',
+  created_at timestamptz default CURRENT_TIMESTAMP not null,
+
+  -- an authorization only fails once.
+  UNIQUE (authorization_id)
+);
+--
+create index failed_authorizations_account_id_idx on failed_authorizations (account_id, created_at);
@@ -0,0 +1,3 @@
+-- whether the client agreed to the terms of service in its newAccount request (RFC8555 7.3), so
+-- that the account object can report it later. it cannot be updated by the client.
+alter table accounts add column terms_of_service_agreed bool default false not null;
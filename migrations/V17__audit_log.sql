@@ -0,0 +1,23 @@
+-- an append-only record of certificate issuance and revocation and of account changes, for
+-- compliance audits. rows are never updated or deleted; the trigger below enforces that.
+create table audit_log (
+  id bigserial primary key,
+  event_type text not null,
+  account_id text,
+  order_id text,
+  serial text,
+  detail jsonb not null default '{}',
+  tenant_id text not null default '',
+  occurred_at timestamptz default now() not null
+);
+--
+create index audit_log_occurred_at_idx on audit_log (tenant_id, occurred_at);
+--
+create function audit_log_immutable() returns trigger as $$
+begin
+  raise exception 'audit_log is append-only';
+end;
+$$ language plpgsql;
+--
+create trigger audit_log_immutable before update or delete on audit_log
+  for each row execute function audit_log_immutable();
@@ -0,0 +1,3 @@
+-- when the account was deactivated by its client (RFC8555 7.3.6). a deactivated account cannot
+-- be used again, so this is never cleared.
+alter table accounts add column deactivated_at timestamptz;
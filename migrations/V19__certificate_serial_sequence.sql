@@ -0,0 +1,3 @@
+-- serial numbers of issued certificates, shared by every instance using this database. they start
+-- above the 32-bit random serials certificates were issued with before, so they cannot collide.
+create sequence cert_serial_seq start with 4294967296;
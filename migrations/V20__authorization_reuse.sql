@@ -0,0 +1,13 @@
+-- authorizations which an order took over from an earlier order of the same account, which had
+-- already validated them (RFC8555 7.1.4). authorization_id holds the authorization's reference,
+-- as it does in orders_challenges.
+create table orders_reused_authorizations (
+  id serial primary key,
+  order_id varchar not null,
+  authorization_id varchar not null,
+  created_at timestamptz default CURRENT_TIMESTAMP not null,
+
+  UNIQUE (order_id, authorization_id)
+);
+--
+create index orders_authorizations_identifier_idx on orders_authorizations (kind, identifier) where not expired and deleted_at is null;
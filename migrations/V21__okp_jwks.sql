@@ -0,0 +1,13 @@
+-- Ed25519 keys (RFC8037) are OKP keys, with an x and no y. The key type and curve are kept so
+-- that keys load back as the type they were registered with.
+alter table jwks add column kty varchar;
+alter table jwks add column crv varchar;
+update jwks set kty = case when n is not null then 'RSA' else 'EC' end;
+update jwks set crv = case alg when 'ES256' then 'P-256' when 'ES384' then 'P-384' end where kty = 'EC';
+--
+alter table jwks drop constraint jwks_check;
+alter table jwks add constraint jwks_check check (
+  (n is not null and e is not null) or
+  (x is not null and y is not null) or
+  (kty = 'OKP' and x is not null and y is null)
+);
@@ -0,0 +1,4 @@
+-- the CA which signed each certificate, so that OCSP, CRLs and downloads keep working for the
+-- certificates of a CA which was rotated away from. both are null for certificates issued before.
+alter table orders_certificate add column issuer_key_hash bytea;
+alter table orders_certificate add column chain bytea;
@@ -0,0 +1,3 @@
+alter table orders_certificate add column serial bytea;
+--
+create index orders_certificate_serial_idx on orders_certificate (serial);
@@ -0,0 +1,9 @@
+create table account_key_history (
+  id serial primary key,
+  account_id integer not null,
+  old_thumbprint varchar not null,
+  new_thumbprint varchar not null,
+  changed_at timestamptz default CURRENT_TIMESTAMP not null
+);
+--
+create index account_key_history_account_id_idx on account_key_history (account_id);
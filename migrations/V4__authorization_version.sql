@@ -0,0 +1 @@
+alter table orders_authorizations add column version bigint not null default 0;
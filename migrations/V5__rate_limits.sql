@@ -0,0 +1,11 @@
+create table rate_limits (
+  account_id integer not null,
+  operation varchar not null,
+  window_start timestamptz not null,
+  count integer not null,
+
+  primary key (account_id, operation, window_start)
+);
+--
+-- rate limit windows which have ended are pruned by their start.
+create index rate_limits_window_start_idx on rate_limits (window_start);
@@ -0,0 +1,9 @@
+create table revocations (
+  id serial primary key,
+  serial bytea not null,
+  -- RFC5280 5.3.1 CRLReason
+  reason integer not null default 0,
+  revoked_at timestamptz default CURRENT_TIMESTAMP not null
+);
+--
+create unique index revocations_serial_idx on revocations (serial);
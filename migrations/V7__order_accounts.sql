@@ -0,0 +1,3 @@
+alter table orders add column account_id integer;
+--
+create index orders_account_id_idx on orders (account_id);
@@ -0,0 +1,3 @@
+-- archived orders keep the shape of the orders table, but carry no constraints or indexes so
+-- that they can be pruned freely.
+create table orders_archive (like orders including defaults);
@@ -0,0 +1,7 @@
+create table eab_credentials (
+  kid varchar primary key,
+  hmac_key bytea not null,
+  used bool not null default false,
+  created_at timestamptz default CURRENT_TIMESTAMP not null,
+  used_at timestamptz
+);
@@ -1,20 +1,49 @@
 use std::{
     convert::TryInto,
-    sync::Arc,
-    time::{Duration, SystemTime},
+    future::Future,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
+use chrono::Datelike;
 use log::warn;
 use openssl::{
-    asn1::Asn1Time,
+    asn1::{Asn1Object, Asn1OctetString, Asn1Time, Asn1TimeRef, Asn1Type},
     bn::BigNum,
+    ec::{EcGroup, EcKey},
     error::ErrorStack,
-    hash::MessageDigest,
-    pkey::{PKey, Private},
+    hash::{hash, DigestBytes, MessageDigest},
+    nid::Nid,
+    ocsp::OcspResponseStatus,
+    pkcs12::Pkcs12,
+    pkey::{Id, PKey, Private},
     rsa::Rsa,
-    x509::{X509Extension, X509Name, X509Req, X509},
+    sign::Signer,
+    stack::Stack,
+    x509::{
+        extension::SubjectAlternativeName, store::X509StoreBuilder, verify::X509VerifyFlags,
+        X509Crl, X509Extension, X509Name, X509Req, X509StoreContext, X509,
+    },
 };
-use tokio::sync::RwLock;
+use tokio::sync::{Notify, RwLock};
+use x509_parser::prelude::*;
+
+use crate::{
+    acme::ip_from_octets,
+    errors::ca::{CaLoadError, ChainError, CrlError, CsrError, OcspError, Pkcs12Error},
+};
+
+/// asn1_to_st is the inverse of [st_to_asn1]; times before the epoch are clamped to it.
+pub(crate) fn asn1_to_st(time: &Asn1TimeRef) -> Result<SystemTime, ErrorStack> {
+    let diff = Asn1Time::from_unix(0)?.diff(time)?;
+    let secs = diff.days as i64 * 24 * 60 * 60 + diff.secs as i64;
+
+    Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(secs.try_into().unwrap_or_default()))
+}
 
 pub(crate) fn st_to_asn1(time: SystemTime) -> Result<Asn1Time, ErrorStack> {
     Asn1Time::from_unix(
@@ -26,6 +55,52 @@ pub(crate) fn st_to_asn1(time: SystemTime) -> Result<Asn1Time, ErrorStack> {
     )
 }
 
+/// SigningAlgorithm is the kind of key a CA signs with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SigningAlgorithm {
+    Rsa2048,
+    Rsa4096,
+    EcdsaP256,
+    EcdsaP384,
+    Ed25519,
+}
+
+impl SigningAlgorithm {
+    /// generate_key generates a new private key for the algorithm.
+    pub fn generate_key(&self) -> Result<PKey<Private>, ErrorStack> {
+        match self {
+            SigningAlgorithm::Rsa2048 => PKey::from_rsa(Rsa::generate(2048)?),
+            SigningAlgorithm::Rsa4096 => PKey::from_rsa(Rsa::generate(4096)?),
+            SigningAlgorithm::EcdsaP256 => PKey::from_ec_key(EcKey::generate(
+                EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?.as_ref(),
+            )?),
+            SigningAlgorithm::EcdsaP384 => PKey::from_ec_key(EcKey::generate(
+                EcGroup::from_curve_name(Nid::SECP384R1)?.as_ref(),
+            )?),
+            SigningAlgorithm::Ed25519 => PKey::generate_ed25519(),
+        }
+    }
+}
+
+/// is_p384 returns true if the key is an elliptic curve key on P-384, which is signed with
+/// SHA-384 rather than SHA-256.
+fn is_p384(key: &PKey<Private>) -> bool {
+    key.ec_key()
+        .map(|ec| ec.group().curve_name() == Some(Nid::SECP384R1))
+        .unwrap_or_default()
+}
+
+/// certificate_digest returns the digest to sign certificates with for the key. Ed25519 does its
+/// own hashing, so no digest is used.
+fn certificate_digest(key: &PKey<Private>) -> MessageDigest {
+    match key.id() {
+        Id::ED25519 => MessageDigest::null(),
+        Id::EC if is_p384(key) => MessageDigest::sha384(),
+        Id::EC => MessageDigest::sha256(),
+        _ => MessageDigest::sha512(),
+    }
+}
+
 /// CA defines a certificate authority in the standard sense of the word; it is used to sign
 /// certificate signing requests and return them as fully functional certificates. To create one,
 /// use the ::new constructor.
@@ -33,316 +108,3142 @@ pub(crate) fn st_to_asn1(time: SystemTime) -> Result<Asn1Time, ErrorStack> {
 pub struct CA {
     certificate: X509,
     private_key: PKey<Private>,
+    client_auth: bool,
+    ocsp_url: Option<String>,
+    crl_url: Option<String>,
+    certificate_policies: Vec<CertificatePolicy>,
+    cn_truncation: bool,
+    subject_template: Option<SubjectTemplate>,
+    profile: CertProfile,
+    chain: Vec<X509>,
+    template: Arc<OnceLock<ExtensionTemplate>>,
 }
 
-impl CA {
-    /// new constructs a new certificate authority with a X.509 certificate and private key.
-    pub fn new(certificate: X509, private_key: PKey<Private>) -> Self {
+/// CertProfile controls the validity period of the certificates a CA issues.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CertProfile {
+    /// how far notBefore is backdated from the requested start, to tolerate clients whose clocks
+    /// run slow.
+    pub not_before_offset: Duration,
+    /// how long certificates are valid for, from the requested start, when the order does not ask
+    /// for a notAfter of its own.
+    pub not_after_offset: Duration,
+    /// the longest validity, backdating included, a certificate may be issued with. Requests for
+    /// more are rejected rather than shortened.
+    pub max_validity: Option<Duration>,
+}
+
+impl Default for CertProfile {
+    fn default() -> Self {
         Self {
-            certificate,
-            private_key,
+            not_before_offset: Duration::ZERO,
+            not_after_offset: Duration::from_secs(365 * 24 * 60 * 60),
+            max_validity: None,
         }
     }
+}
 
-    /// returns the certificate
-    pub fn certificate(self) -> X509 {
-        self.certificate
-    }
-
-    /// returns the private key
-    pub fn private_key(self) -> PKey<Private> {
-        self.private_key
-    }
-
-    /// signs a CSR with the CA's private key. The not_before and not_after parameters can be used
-    /// to control its lifetime.
-    pub fn generate_and_sign_cert(
+impl CertProfile {
+    /// validity returns the notBefore and notAfter of a certificate requested for the period
+    /// provided. A missing not_after is filled in from `not_after_offset`.
+    pub fn validity(
         &self,
-        req: X509Req,
         not_before: SystemTime,
-        not_after: SystemTime,
-    ) -> Result<X509, ErrorStack> {
-        let mut builder = X509::builder()?;
-        builder.set_pubkey(req.public_key()?.as_ref())?;
-        builder.set_issuer_name(self.certificate.issuer_name())?;
-        builder.set_serial_number(
-            BigNum::from_u32(rand::random::<u32>())?
-                .as_ref()
-                .to_asn1_integer()?
-                .as_ref(),
-        )?;
+        not_after: Option<SystemTime>,
+    ) -> Result<(SystemTime, SystemTime), CsrError> {
+        let not_after = not_after.unwrap_or(not_before + self.not_after_offset);
+        let not_before = not_before
+            .checked_sub(self.not_before_offset)
+            .unwrap_or(not_before);
 
-        let exts = req.extensions();
-        if let Ok(exts) = exts {
-            for ext in exts {
-                builder.append_extension(ext)?;
+        if let Some(max_validity) = self.max_validity {
+            let requested = not_after.duration_since(not_before).unwrap_or_default();
+            if requested > max_validity {
+                return Err(CsrError::ValidityTooLong {
+                    requested: requested.as_secs(),
+                    max: max_validity.as_secs(),
+                });
             }
         }
 
-        builder.append_extension(X509Extension::new(
-            None,
-            Some(&builder.x509v3_context(None, None)),
-            "keyUsage",
-            "critical,keyEncipherment,digitalSignature",
-        )?)?;
-
-        builder.append_extension(X509Extension::new(
-            None,
-            Some(&builder.x509v3_context(None, None)),
-            "extendedKeyUsage",
-            "critical,serverAuth",
-        )?)?;
-
-        builder.append_extension(X509Extension::new(
-            None,
-            Some(&builder.x509v3_context(None, None)),
-            "authorityKeyIdentifier",
-            "issuer",
-        )?)?;
+        Ok((not_before, not_after))
+    }
+}
 
-        builder.append_extension(X509Extension::new(
-            None,
-            Some(&builder.x509v3_context(None, None)),
-            "subjectKeyIdentifier",
-            "hash",
-        )?)?;
+/// KeyUsage is a bit of the keyUsage extension (RFC5280 4.2.1.3). CA certificates need
+/// `KeyCertSign`, and `CRLSign` to sign CRLs; end-entity certificates `DigitalSignature`, with
+/// `KeyEncipherment` or `KeyAgreement` depending on the key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyUsage {
+    DigitalSignature,
+    NonRepudiation,
+    KeyEncipherment,
+    DataEncipherment,
+    KeyAgreement,
+    KeyCertSign,
+    CRLSign,
+    EncipherOnly,
+    DecipherOnly,
+}
 
-        builder.append_extension(X509Extension::new(
-            None,
-            Some(&builder.x509v3_context(None, None)),
-            "issuerAltName",
-            "issuer:copy",
-        )?)?;
+impl KeyUsage {
+    /// the name of the bit in openssl's configuration syntax.
+    fn name(&self) -> &'static str {
+        match self {
+            KeyUsage::DigitalSignature => "digitalSignature",
+            KeyUsage::NonRepudiation => "nonRepudiation",
+            KeyUsage::KeyEncipherment => "keyEncipherment",
+            KeyUsage::DataEncipherment => "dataEncipherment",
+            KeyUsage::KeyAgreement => "keyAgreement",
+            KeyUsage::KeyCertSign => "keyCertSign",
+            KeyUsage::CRLSign => "cRLSign",
+            KeyUsage::EncipherOnly => "encipherOnly",
+            KeyUsage::DecipherOnly => "decipherOnly",
+        }
+    }
+}
 
-        builder.set_subject_name(req.subject_name())?;
-        builder.set_version(2)?;
-        builder.set_not_before(st_to_asn1(not_before)?.as_ref())?;
-        builder.set_not_after(st_to_asn1(not_after)?.as_ref())?;
+/// CaConfig describes the self-signed root CA created by [CA::generate_root].
+pub struct CaConfig {
+    /// the subject, and issuer, of the CA certificate.
+    pub subject: X509Name,
+    /// the bits of the keyUsage extension, which is marked critical.
+    pub key_usage: Vec<KeyUsage>,
+    /// how many intermediate CAs may follow this one in a chain; None for no limit.
+    pub path_len_constraint: Option<u32>,
+    /// the kind of key the CA signs with.
+    pub algorithm: SigningAlgorithm,
+    /// how long the CA certificate is valid for.
+    pub validity: Duration,
+}
 
-        builder.sign(&self.private_key, MessageDigest::sha512())?;
-        Ok(builder.build())
+impl CaConfig {
+    /// new configures a CA for `subject` which signs certificates and CRLs with a 4096 bit RSA
+    /// key, may not sign intermediate CAs, and is valid for a year.
+    pub fn new(subject: X509Name) -> Self {
+        Self {
+            subject,
+            key_usage: vec![KeyUsage::KeyCertSign, KeyUsage::CRLSign],
+            path_len_constraint: Some(0),
+            algorithm: SigningAlgorithm::Rsa4096,
+            validity: Duration::from_secs(365 * 24 * 60 * 60),
+        }
     }
+}
 
-    /// new_test_ca is a convenience function for creating a quick and dirty CA for use in tests
-    /// and demo applications (such as the examples).
-    pub fn new_test_ca() -> Result<Self, ErrorStack> {
-        let mut builder = X509::builder()?;
+/// CertificatePolicy is a policy asserted in the certificatePolicies extension of issued
+/// certificates, e.g. the CA/Browser Forum domain-validated policy `2.23.140.1.2.1`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CertificatePolicy {
+    /// the policy OID in dotted decimal notation.
+    pub oid: String,
+    /// URI of the certification practice statement, if any.
+    pub cps_uri: Option<String>,
+    /// explicit text of a user notice, if any.
+    pub user_notice: Option<String>,
+}
 
-        let mut namebuilder = X509Name::builder()?;
-        namebuilder.append_entry_by_text("C", "US")?;
-        namebuilder.append_entry_by_text("O", "ZeroTier")?;
-        namebuilder.append_entry_by_text("CN", "CA Signing Certificate")?;
-        namebuilder.append_entry_by_text("ST", "California")?;
-        namebuilder.append_entry_by_text("L", "Irvine")?;
-        namebuilder.append_entry_by_text("OU", "A Test Suite")?;
-        builder.set_subject_name(&namebuilder.build())?;
+impl CertificatePolicy {
+    /// domain_validated is the CA/Browser Forum domain-validated policy, `2.23.140.1.2.1`, which
+    /// suits ACME issuance: control of each name is validated, and nothing else about the
+    /// subject is.
+    pub fn domain_validated() -> Self {
+        Self {
+            oid: "2.23.140.1.2.1".to_string(),
+            cps_uri: None,
+            user_notice: None,
+        }
+    }
+}
 
-        let mut namebuilder = X509Name::builder()?;
-        namebuilder.append_entry_by_text("C", "US")?;
-        namebuilder.append_entry_by_text("O", "ZeroTier")?;
-        namebuilder.append_entry_by_text("CN", "CA Signing Certificate")?;
-        namebuilder.append_entry_by_text("ST", "California")?;
-        namebuilder.append_entry_by_text("L", "Irvine")?;
-        namebuilder.append_entry_by_text("OU", "A Test Suite")?;
-        builder.set_issuer_name(&namebuilder.build())?;
+/// SubjectTemplate holds the subject fields an operator sets on every certificate a CA issues.
+/// When a template is in use, CSRs may not choose these fields for themselves; see
+/// `allow_csr_fields`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SubjectTemplate {
+    pub organization: Option<String>,
+    pub organizational_unit: Option<String>,
+    /// the two-letter ISO 3166 country code.
+    pub country: Option<String>,
+    pub state: Option<String>,
+    /// keep the CSR's values for any of the fields above which the template leaves unset. By
+    /// default they are dropped.
+    pub allow_csr_fields: bool,
+}
 
-        builder.set_serial_number(
-            BigNum::from_u32(rand::random::<u32>())?
-                .as_ref()
-                .to_asn1_integer()?
-                .as_ref(),
-        )?;
+impl SubjectTemplate {
+    /// fields pairs each field with its attribute type, in the order they appear in the subject.
+    fn fields(&self) -> [(Nid, &Option<String>); 4] {
+        [
+            (Nid::COUNTRYNAME, &self.country),
+            (Nid::STATEORPROVINCENAME, &self.state),
+            (Nid::ORGANIZATIONNAME, &self.organization),
+            (Nid::ORGANIZATIONALUNITNAME, &self.organizational_unit),
+        ]
+    }
 
-        let key = Rsa::generate(4096)?;
-        // FIXME there has to be a much better way of doing this!
-        let pubkey = PKey::public_key_from_pem(&key.public_key_to_pem().unwrap()).unwrap();
+    /// entries returns the fields which are set, in the order they appear in the subject.
+    fn entries(&self) -> Vec<(Nid, &str)> {
+        self.fields()
+            .into_iter()
+            .filter_map(|(nid, value)| value.as_deref().map(|value| (nid, value)))
+            .collect()
+    }
 
-        builder.set_pubkey(&pubkey)?;
-        builder.set_version(2)?;
-        builder.set_not_before(Asn1Time::days_from_now(0)?.as_ref())?;
-        builder.set_not_after(Asn1Time::days_from_now(365)?.as_ref())?;
+    /// overrides returns true if a CSR's subject entry of this type is to be dropped.
+    fn overrides(&self, nid: Nid) -> bool {
+        match self.fields().into_iter().find(|(n, _)| *n == nid) {
+            Some((_, value)) => !self.allow_csr_fields || value.is_some(),
+            None => false,
+        }
+    }
+}
 
-        builder.append_extension(X509Extension::new(
-            None,
-            Some(&builder.x509v3_context(None, None)),
-            "basicConstraints",
-            "critical,CA:true,pathlen:0",
-        )?)?;
+/// the upper bound on commonName from RFC 5280 (ub-common-name).
+const MAX_CN_LENGTH: usize = 64;
 
-        builder.append_extension(X509Extension::new(
-            None,
-            Some(&builder.x509v3_context(None, None)),
-            "keyUsage",
-            "critical,keyCertSign",
-        )?)?;
+const OID_CERTIFICATE_POLICIES: &str = "2.5.29.32";
+const OID_QT_CPS: &str = "1.3.6.1.5.5.7.2.1";
+const OID_QT_UNOTICE: &str = "1.3.6.1.5.5.7.2.2";
 
-        builder.append_extension(X509Extension::new(
-            None,
-            Some(&builder.x509v3_context(None, None)),
-            "subjectKeyIdentifier",
-            "hash",
-        )?)?;
+const DER_IA5STRING: u8 = 0x16;
+const DER_OID: u8 = 0x06;
+const DER_SEQUENCE: u8 = 0x30;
+const DER_UTF8STRING: u8 = 0x0c;
 
-        builder.append_extension(X509Extension::new(
-            None,
-            Some(&builder.x509v3_context(None, None)),
-            "issuerAltName",
-            "issuer:copy",
-        )?)?;
+fn der_tlv(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = contents.len();
 
-        let privkey = PKey::from_rsa(key)?;
-        builder.sign(privkey.as_ref(), MessageDigest::sha512())?;
-        Ok(Self::new(builder.build(), privkey))
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        out.push(0x80 | (bytes.len() - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
     }
-}
 
-/// CACollector is an async observer which waits for a CA to arrive, and fosters the creation of
-/// signed CSRs as certificates. This allows for the rotation of CA certificates, or delayed
-/// loading, without loss of functionality due to race conditions. Please see the `acmed` example for usage.
-#[derive(Clone, Debug)]
-pub struct CACollector {
-    poll_interval: Duration,
-    ca: SharedCA,
+    out.extend_from_slice(contents);
+    out
 }
 
-/// SharedCA is a simple type for managing the locking around a CA.
-type SharedCA = Arc<RwLock<Option<CA>>>;
+/// der_oid encodes a dotted decimal OID, returning None if it is not a valid numeric OID.
+fn der_oid(oid: &str) -> Option<Vec<u8>> {
+    let arcs = oid
+        .split('.')
+        .map(|arc| arc.parse::<u64>())
+        .collect::<Result<Vec<u64>, _>>()
+        .ok()?;
 
-impl CACollector {
-    /// new is a constructor; the duration provided determines how often the loop will awake and
-    /// process a CA injection.
-    pub fn new(poll_interval: Duration) -> Self {
-        Self {
-            poll_interval,
-            ca: Arc::new(RwLock::new(None)),
-        }
+    if arcs.len() < 2 || arcs[0] > 2 || (arcs[0] < 2 && arcs[1] >= 40) {
+        return None;
     }
 
-    /// returns the CA as a SharedCA.
-    pub fn ca(self) -> SharedCA {
-        self.ca.clone()
+    // the first two arcs share a single subidentifier.
+    let first = arcs[0] * 40 + arcs[1];
+
+    let mut contents = Vec::new();
+    for arc in std::iter::once(first).chain(arcs.iter().skip(2).copied()) {
+        let mut chunk = vec![(arc & 0x7f) as u8];
+        let mut rest = arc >> 7;
+        while rest > 0 {
+            chunk.push((rest & 0x7f) as u8 | 0x80);
+            rest >>= 7;
+        }
+        chunk.reverse();
+        contents.extend(chunk);
     }
 
-    /// majority of callers will use this function to collect the CA. It takes a closure which
-    /// accepts a CA and returns it to this function so that it can overwrite the previous CA.
-    pub async fn spawn_collector<F>(&mut self, f: F)
-    where
-        F: Fn() -> Result<CA, ErrorStack>,
-    {
-        loop {
-            let res = f();
+    Some(der_tlv(DER_OID, &contents))
+}
 
-            match res {
-                Ok(ca) => { self.ca.write().await.replace(ca); },
-                Err(e) => warn!("Failed to retrieve CA, signing will will continue to use the old CA, if any. Error: {}", e.to_string())
-            }
-            tokio::time::sleep(self.poll_interval).await;
+/// certificate_policies_der encodes the certificatePolicies extension value (RFC 5280 4.2.1.4).
+fn certificate_policies_der(policies: &[CertificatePolicy]) -> Result<Vec<u8>, CsrError> {
+    let mut infos = Vec::new();
+
+    for policy in policies {
+        let mut info = der_oid(&policy.oid)
+            .ok_or_else(|| CsrError::InvalidCertificatePolicyOid(policy.oid.clone()))?;
+        let mut qualifiers = Vec::new();
+
+        if let Some(uri) = &policy.cps_uri {
+            let mut qualifier = der_oid(OID_QT_CPS).unwrap();
+            qualifier.extend(der_tlv(DER_IA5STRING, uri.as_bytes()));
+            qualifiers.extend(der_tlv(DER_SEQUENCE, &qualifier));
         }
-    }
 
-    /// similar to CA::generate_and_sign_cert, this signs the CSR through the SharedCA provided by
-    /// the collector.
-    pub async fn sign(
-        self,
-        req: X509Req,
-        not_before: SystemTime,
-        not_after: SystemTime,
-    ) -> Result<X509, ErrorStack> {
-        Ok(self
-            .ca()
-            .read()
-            .await
-            .clone()
-            .unwrap()
-            .generate_and_sign_cert(req, not_before, not_after)?)
+        if let Some(text) = &policy.user_notice {
+            let mut qualifier = der_oid(OID_QT_UNOTICE).unwrap();
+            qualifier.extend(der_tlv(
+                DER_SEQUENCE,
+                &der_tlv(DER_UTF8STRING, text.as_bytes()),
+            ));
+            qualifiers.extend(der_tlv(DER_SEQUENCE, &qualifier));
+        }
+
+        if !qualifiers.is_empty() {
+            info.extend(der_tlv(DER_SEQUENCE, &qualifiers));
+        }
+
+        infos.extend(der_tlv(DER_SEQUENCE, &info));
     }
+
+    Ok(der_tlv(DER_SEQUENCE, &infos))
 }
 
-#[cfg(test)]
-mod tests {
-    use openssl::{error::ErrorStack, x509::X509Req};
+const OID_SHA1: &str = "1.3.14.3.2.26";
+const OID_SHA256: &str = "2.16.840.1.101.3.4.2.1";
+const OID_SHA256_WITH_RSA: &str = "1.2.840.113549.1.1.11";
+const OID_ECDSA_WITH_SHA256: &str = "1.2.840.10045.4.3.2";
+const OID_ECDSA_WITH_SHA384: &str = "1.2.840.10045.4.3.3";
+const OID_ED25519: &str = "1.3.101.112";
+const OID_OCSP_BASIC: &str = "1.3.6.1.5.5.7.48.1.1";
+const OID_AUTHORITY_KEY_IDENTIFIER: &str = "2.5.29.35";
+const OID_CRL_NUMBER: &str = "2.5.29.20";
+const OID_CRL_REASON: &str = "2.5.29.21";
 
-    fn generate_csr() -> Result<X509Req, ErrorStack> {
-        use openssl::{pkey::PKey, rsa::Rsa, x509::X509Name};
+const DER_BIT_STRING: u8 = 0x03;
+const DER_ENUMERATED: u8 = 0x0a;
+const DER_GENERALIZED_TIME: u8 = 0x18;
+const DER_INTEGER: u8 = 0x02;
+const DER_NULL: u8 = 0x05;
+const DER_OCTET_STRING: u8 = 0x04;
+const DER_UTC_TIME: u8 = 0x17;
 
-        let mut namebuilder = X509Name::builder().unwrap();
-        namebuilder
-            .append_entry_by_text("CN", "example.org")
-            .unwrap();
-        let mut req = X509Req::builder().unwrap();
-        req.set_subject_name(&namebuilder.build()).unwrap();
+/// a single DER element, as read by der_read.
+struct DerElement<'a> {
+    tag: u8,
+    contents: &'a [u8],
+    raw: &'a [u8],
+}
 
-        let key = Rsa::generate(4096).unwrap();
-        // FIXME there has to be a much better way of doing this!
-        let pubkey = PKey::public_key_from_pem(&key.public_key_to_pem().unwrap()).unwrap();
+/// der_read reads the first DER element from the input, returning it and the remaining input.
+fn der_read(input: &[u8]) -> Result<(DerElement<'_>, &[u8]), OcspError> {
+    let truncated = || OcspError::Malformed("truncated DER element".to_string());
 
-        req.set_pubkey(&pubkey).unwrap();
-        Ok(req.build())
+    if input.len() < 2 {
+        return Err(truncated());
     }
 
-    #[test]
-    fn test_basic_ca_sign() {
-        use spectral::prelude::*;
+    let (len, header) = if input[1] < 0x80 {
+        (input[1] as usize, 2)
+    } else {
+        let n = (input[1] & 0x7f) as usize;
+        if n == 0 || n > 4 || input.len() < 2 + n {
+            return Err(truncated());
+        }
 
-        use super::{st_to_asn1, CA};
-        use openssl::{pkey::PKey, rsa::Rsa};
-        use std::time::SystemTime;
+        (
+            input[2..2 + n]
+                .iter()
+                .fold(0usize, |len, b| (len << 8) | *b as usize),
+            2 + n,
+        )
+    };
 
-        let now = SystemTime::now();
+    if input.len() - header < len {
+        return Err(truncated());
+    }
 
-        let ca = CA::new_test_ca().unwrap();
-        let signed = ca
-            .generate_and_sign_cert(generate_csr().unwrap(), SystemTime::UNIX_EPOCH, now)
-            .unwrap();
+    let end = header + len;
 
-        let result = signed.verify(&ca.private_key());
-        assert_that!(result).is_ok();
-        assert_that!(result.unwrap()).is_true();
+    Ok((
+        DerElement {
+            tag: input[0],
+            contents: &input[header..end],
+            raw: &input[..end],
+        },
+        &input[end..],
+    ))
+}
 
-        let badkey = Rsa::generate(4096).unwrap();
-        let result = signed.verify(PKey::from_rsa(badkey).unwrap().as_ref());
-        assert_that!(result).is_ok();
-        assert_that!(result.unwrap()).is_false();
+/// der_expect is der_read, but fails if the element does not carry the expected tag.
+fn der_expect(input: &[u8], tag: u8) -> Result<(DerElement<'_>, &[u8]), OcspError> {
+    let (element, rest) = der_read(input)?;
 
-        assert_that!(signed.not_before())
-            .is_equal_to(&*st_to_asn1(SystemTime::UNIX_EPOCH).unwrap());
-        assert_that!(signed.not_after()).is_equal_to(&*st_to_asn1(now).unwrap());
+    if element.tag != tag {
+        return Err(OcspError::Malformed(format!(
+            "expected tag {:#04x}, found {:#04x}",
+            tag, element.tag
+        )));
     }
 
-    #[tokio::test(flavor = "multi_thread")]
-    async fn test_ca_collector() {
-        use super::{st_to_asn1, CACollector, CA};
-        use openssl::{pkey::PKey, rsa::Rsa};
-        use spectral::prelude::*;
-        use std::time::Duration;
-        use std::time::SystemTime;
+    Ok((element, rest))
+}
 
-        let collector = CACollector::new(Duration::new(0, 500));
+fn der_generalized_time(time: chrono::DateTime<chrono::Utc>) -> Vec<u8> {
+    der_tlv(
+        DER_GENERALIZED_TIME,
+        time.format("%Y%m%d%H%M%SZ").to_string().as_bytes(),
+    )
+}
 
-        let mut inner = collector.clone();
-        let handle = tokio::spawn(async move {
-            // we only want one of these, instead of polling for new ones, in this test.
-            let ca = CA::new_test_ca().unwrap();
-            inner
-                .spawn_collector(|| -> Result<CA, ErrorStack> { Ok(ca.clone()) })
-                .await
-        });
+/// der_time encodes a Time as RFC 5280 requires: UTCTime through 2049, GeneralizedTime after.
+fn der_time(time: chrono::DateTime<chrono::Utc>) -> Vec<u8> {
+    if time.year() < 2050 {
+        der_tlv(
+            DER_UTC_TIME,
+            time.format("%y%m%d%H%M%SZ").to_string().as_bytes(),
+        )
+    } else {
+        der_generalized_time(time)
+    }
+}
 
-        tokio::time::sleep(Duration::new(1, 0)).await;
+/// serial_with_entropy makes a serial number of 64 bits from a CSPRNG above the 64 bits of `low`,
+/// so that serial numbers cannot be predicted (CA/Browser Forum Baseline Requirements 7.1) while
+/// `low` keeps them unique.
+fn serial_with_entropy(low: u64) -> Result<BigNum, ErrorStack> {
+    let mut bytes = [0u8; 16];
+    openssl::rand::rand_bytes(&mut bytes[..8])?;
+    bytes[8..].copy_from_slice(&low.to_be_bytes());
 
-        let now = SystemTime::now();
-        let signed = collector
-            .clone()
-            .sign(generate_csr().unwrap(), SystemTime::UNIX_EPOCH, now)
-            .await
-            .unwrap();
+    BigNum::from_slice(&bytes)
+}
 
-        let result = signed.verify(&collector.ca().read().await.clone().unwrap().private_key());
-        assert_that!(result).is_ok();
-        assert_that!(result.unwrap()).is_true();
+/// random_serial makes a serial number that is random throughout, for certificates not given one
+/// from [crate::models::Postgres::next_serial].
+fn random_serial() -> Result<BigNum, ErrorStack> {
+    let mut low = [0u8; 8];
+    openssl::rand::rand_bytes(&mut low)?;
 
-        let badkey = Rsa::generate(4096).unwrap();
-        let result = signed.verify(PKey::from_rsa(badkey).unwrap().as_ref());
+    serial_with_entropy(u64::from_be_bytes(low))
+}
+
+/// der_integer encodes an unsigned big-endian integer, such as a serial number.
+fn der_integer(value: &[u8]) -> Vec<u8> {
+    let value = &value[value.iter().take_while(|b| **b == 0).count()..];
+
+    let mut contents = Vec::new();
+    if value.first().map_or(true, |b| b & 0x80 != 0) {
+        contents.push(0);
+    }
+    contents.extend_from_slice(value);
+
+    der_tlv(DER_INTEGER, &contents)
+}
+
+/// der_extension encodes a non-critical extension with the DER-encoded value provided.
+fn der_extension(oid: &str, value: &[u8]) -> Vec<u8> {
+    let mut extension = der_oid(oid).unwrap();
+    extension.extend(der_tlv(DER_OCTET_STRING, value));
+    der_tlv(DER_SEQUENCE, &extension)
+}
+
+/// OcspCertRequest is a query for the status of a single certificate, taken from an OCSP request
+/// (RFC 6960 4.1.1).
+#[derive(Clone, Debug, PartialEq)]
+pub struct OcspCertRequest {
+    /// the CertID exactly as requested, to be echoed in the response.
+    cert_id: Vec<u8>,
+    hash_algorithm: Vec<u8>,
+    issuer_name_hash: Vec<u8>,
+    issuer_key_hash: Vec<u8>,
+    /// the serial number, in the same form as stored with issued certificates.
+    pub serial: Vec<u8>,
+}
+
+/// CertificateStatus is the status of a certificate as reported in an OCSP response.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CertificateStatus {
+    Good,
+    Revoked {
+        revoked_at: chrono::DateTime<chrono::Utc>,
+        /// the RFC5280 CRLReason code.
+        reason: i32,
+    },
+    Unknown,
+}
+
+/// parse_ocsp_request parses a DER-encoded OCSPRequest into the certificates it queries.
+/// Request extensions (e.g. nonces) and signatures are ignored.
+pub fn parse_ocsp_request(der: &[u8]) -> Result<Vec<OcspCertRequest>, OcspError> {
+    let (request, _) = der_expect(der, DER_SEQUENCE)?;
+    let (tbs, _) = der_expect(request.contents, DER_SEQUENCE)?;
+
+    // skip the optional version and requestorName, which are context tagged.
+    let mut input = tbs.contents;
+    let list = loop {
+        let (element, rest) = der_read(input)?;
+        if element.tag == DER_SEQUENCE {
+            break element;
+        }
+        input = rest;
+    };
+
+    let mut requests = Vec::new();
+    let mut input = list.contents;
+
+    while !input.is_empty() {
+        let (request, rest) = der_expect(input, DER_SEQUENCE)?;
+        input = rest;
+
+        let (cert_id, _) = der_expect(request.contents, DER_SEQUENCE)?;
+        let (algorithm, rest) = der_expect(cert_id.contents, DER_SEQUENCE)?;
+        let (oid, _) = der_expect(algorithm.contents, DER_OID)?;
+        let (name_hash, rest) = der_expect(rest, DER_OCTET_STRING)?;
+        let (key_hash, rest) = der_expect(rest, DER_OCTET_STRING)?;
+        let (serial, _) = der_expect(rest, DER_INTEGER)?;
+
+        requests.push(OcspCertRequest {
+            cert_id: cert_id.raw.to_vec(),
+            hash_algorithm: oid.raw.to_vec(),
+            issuer_name_hash: name_hash.contents.to_vec(),
+            issuer_key_hash: key_hash.contents.to_vec(),
+            serial: BigNum::from_slice(serial.contents)?.to_vec(),
+        });
+    }
+
+    if requests.is_empty() {
+        return Err(OcspError::Malformed(
+            "no certificates were requested".to_string(),
+        ));
+    }
+
+    Ok(requests)
+}
+
+/// ocsp_status_response encodes an unsigned OCSPResponse carrying only the status, as used for
+/// errors such as `malformedRequest` or `tryLater`.
+pub fn ocsp_status_response(status: OcspResponseStatus) -> Vec<u8> {
+    der_tlv(
+        DER_SEQUENCE,
+        &der_tlv(DER_ENUMERATED, &[status.as_raw() as u8]),
+    )
+}
+
+/// ExtensionTemplate holds the extensions which are identical for every certificate a CA issues.
+/// It is built on first use and shared between clones of the CA. Anything derived from the
+/// subject or issuer (SAN, AKID, SKID) is never part of the template.
+struct ExtensionTemplate(Vec<X509Extension>);
+
+impl std::fmt::Debug for ExtensionTemplate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ExtensionTemplate({} extensions)", self.0.len())
+    }
+}
+
+impl CA {
+    /// new constructs a new certificate authority with a X.509 certificate and private key.
+    pub fn new(certificate: X509, private_key: PKey<Private>) -> Self {
+        Self {
+            certificate,
+            private_key,
+            client_auth: false,
+            ocsp_url: None,
+            crl_url: None,
+            certificate_policies: Vec::new(),
+            cn_truncation: false,
+            subject_template: None,
+            profile: Default::default(),
+            chain: Vec::new(),
+            template: Default::default(),
+        }
+    }
+
+    /// from_pem loads a CA whose certificate and private key already exist, e.g. to keep the
+    /// CA across restarts. The key may be PKCS#8 or in its algorithm's traditional format, but
+    /// must not be encrypted. It fails if the key is not the one the certificate was issued for.
+    pub fn from_pem(cert_pem: &[u8], key_pem: &[u8]) -> Result<Self, CaLoadError> {
+        let certificate = X509::from_pem(cert_pem)?;
+        let private_key = PKey::private_key_from_pem(key_pem)?;
+
+        if !private_key.public_eq(&certificate.public_key()?) {
+            return Err(CaLoadError::KeyMismatch);
+        }
+
+        Ok(Self::new(certificate, private_key))
+    }
+
+    /// new_intermediate creates an intermediate CA signed by `root`, so that the root's key can
+    /// be kept offline. The intermediate's subject is the root's with the common name replaced,
+    /// its key is generated for `algorithm`, and its certificate is valid for `validity`. The
+    /// root's own chain is carried over; see [CA::chain].
+    pub fn new_intermediate(
+        root: &CA,
+        common_name: &str,
+        algorithm: SigningAlgorithm,
+        validity: Duration,
+    ) -> Result<CA, ErrorStack> {
+        let mut builder = X509::builder()?;
+
+        let mut namebuilder = X509Name::builder()?;
+        for entry in root.certificate.subject_name().entries() {
+            if entry.object().nid() != Nid::COMMONNAME {
+                namebuilder.append_entry_by_nid(
+                    entry.object().nid(),
+                    &entry.data().as_utf8()?.to_string(),
+                )?;
+            }
+        }
+        namebuilder.append_entry_by_nid(Nid::COMMONNAME, common_name)?;
+        builder.set_subject_name(&namebuilder.build())?;
+        builder.set_issuer_name(root.certificate.subject_name())?;
+
+        builder.set_serial_number(random_serial()?.as_ref().to_asn1_integer()?.as_ref())?;
+
+        let privkey = algorithm.generate_key()?;
+        builder.set_pubkey(&privkey)?;
+        builder.set_version(2)?;
+        builder.set_not_before(Asn1Time::days_from_now(0)?.as_ref())?;
+        builder.set_not_after(st_to_asn1(SystemTime::now() + validity)?.as_ref())?;
+
+        builder.append_extension(X509Extension::new(
+            None,
+            Some(&builder.x509v3_context(Some(&root.certificate), None)),
+            "basicConstraints",
+            "critical,CA:true,pathlen:0",
+        )?)?;
+
+        builder.append_extension(X509Extension::new(
+            None,
+            Some(&builder.x509v3_context(Some(&root.certificate), None)),
+            "keyUsage",
+            "critical,keyCertSign,cRLSign,digitalSignature",
+        )?)?;
+
+        builder.append_extension(X509Extension::new(
+            None,
+            Some(&builder.x509v3_context(Some(&root.certificate), None)),
+            "subjectKeyIdentifier",
+            "hash",
+        )?)?;
+
+        builder.append_extension(X509Extension::new(
+            None,
+            Some(&builder.x509v3_context(Some(&root.certificate), None)),
+            "authorityKeyIdentifier",
+            "keyid:always",
+        )?)?;
+
+        builder.sign(&root.private_key, certificate_digest(&root.private_key))?;
+
+        Ok(Self::new(builder.build(), privkey)
+            .with_cert_profile(root.profile.clone())
+            .with_chain(root.chain()))
+    }
+
+    /// with_chain sets the certificates of the CAs above this one, starting with its issuer and
+    /// ending with the root. It is empty for a self-signed CA.
+    pub fn with_chain(mut self, chain: Vec<X509>) -> Self {
+        self.chain = chain;
+        self
+    }
+
+    /// chain returns the full chain of CA certificates: this CA's, then its issuers' up to the
+    /// root.
+    pub fn chain(&self) -> Vec<X509> {
+        let mut chain = vec![self.certificate.clone()];
+        chain.extend(self.chain.iter().cloned());
+        chain
+    }
+
+    /// chain_pem returns [CA::chain] as concatenated PEM certificates.
+    pub fn chain_pem(&self) -> Result<Vec<u8>, ErrorStack> {
+        let mut pem = Vec::new();
+        for cert in self.chain() {
+            pem.extend(cert.to_pem()?);
+        }
+
+        Ok(pem)
+    }
+
+    /// with_cert_profile sets the validity period of the certificates this CA issues. By default
+    /// certificates are valid for a year and not backdated.
+    pub fn with_cert_profile(mut self, profile: CertProfile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    /// with_client_auth controls whether issued certificates also carry the clientAuth extended
+    /// key usage in addition to serverAuth. It is off by default.
+    pub fn with_client_auth(mut self, client_auth: bool) -> Self {
+        self.client_auth = client_auth;
+        self.template = Default::default();
+        self
+    }
+
+    /// with_ocsp_url sets the URL of the OCSP responder for certificates issued by this CA. When
+    /// set, it is advertised in the authorityInfoAccess extension of each certificate.
+    pub fn with_ocsp_url(mut self, url: &str) -> Self {
+        self.ocsp_url = Some(url.to_string());
+        self.template = Default::default();
+        self
+    }
+
+    /// with_crl_url sets the URL certificate revocation lists for this CA are published at. When
+    /// set, it is advertised in the cRLDistributionPoints extension of each certificate.
+    pub fn with_crl_url(mut self, url: &str) -> Self {
+        self.crl_url = Some(url.to_string());
+        self.template = Default::default();
+        self
+    }
+
+    /// with_certificate_policy adds a policy to the certificatePolicies extension of every
+    /// certificate issued by this CA. It may be called more than once to assert several policies.
+    pub fn with_certificate_policy(mut self, policy: CertificatePolicy) -> Self {
+        self.certificate_policies.push(policy);
+        self.template = Default::default();
+        self
+    }
+
+    /// with_cn_truncation controls what happens to CSRs whose common name is longer than the 64
+    /// characters X.509 allows. When enabled the CN is truncated, since the subjectAltName is the
+    /// authoritative name anyway; otherwise the CSR is rejected. It is off by default.
+    pub fn with_cn_truncation(mut self, enabled: bool) -> Self {
+        self.cn_truncation = enabled;
+        self
+    }
+
+    /// with_subject_template sets the organization, organizational unit, country and state of
+    /// every certificate this CA issues, in place of those in the CSR. Without a template the
+    /// CSR's subject is used as is.
+    pub fn with_subject_template(mut self, template: SubjectTemplate) -> Self {
+        self.subject_template = Some(template);
+        self
+    }
+
+    /// subject_name returns the subject for a certificate issued from `req`, enforcing the
+    /// common name length limit and applying the [SubjectTemplate], if any.
+    fn subject_name(&self, req: &X509Req) -> Result<X509Name, CsrError> {
+        let mut namebuilder = X509Name::builder()?;
+
+        if let Some(template) = &self.subject_template {
+            for (nid, value) in template.entries() {
+                namebuilder.append_entry_by_nid(nid, value)?;
+            }
+        }
+
+        for entry in req.subject_name().entries() {
+            if let Some(template) = &self.subject_template {
+                if template.overrides(entry.object().nid()) {
+                    continue;
+                }
+            }
+
+            if entry.object().nid() != Nid::COMMONNAME {
+                namebuilder.append_entry(entry)?;
+                continue;
+            }
+
+            let cn = entry.data().as_utf8()?.to_string();
+            if cn.chars().count() <= MAX_CN_LENGTH {
+                namebuilder.append_entry(entry)?;
+            } else if self.cn_truncation {
+                namebuilder.append_entry_by_nid_with_type(
+                    Nid::COMMONNAME,
+                    &cn.chars().take(MAX_CN_LENGTH).collect::<String>(),
+                    Asn1Type::UTF8STRING,
+                )?;
+            } else {
+                return Err(CsrError::CnTooLong);
+            }
+        }
+
+        Ok(namebuilder.build())
+    }
+
+    /// template returns the cached set of static extensions, building it if necessary.
+    fn template(&self) -> Result<&ExtensionTemplate, CsrError> {
+        if let Some(template) = self.template.get() {
+            return Ok(template);
+        }
+
+        let mut extensions = vec![
+            X509Extension::new(
+                None,
+                None,
+                "keyUsage",
+                "critical,keyEncipherment,digitalSignature",
+            )?,
+            X509Extension::new(
+                None,
+                None,
+                "extendedKeyUsage",
+                if self.client_auth {
+                    "critical,serverAuth,clientAuth"
+                } else {
+                    "critical,serverAuth"
+                },
+            )?,
+        ];
+
+        if let Some(url) = &self.ocsp_url {
+            extensions.push(X509Extension::new(
+                None,
+                None,
+                "authorityInfoAccess",
+                &format!("OCSP;URI:{}", url),
+            )?);
+        }
+
+        if let Some(url) = &self.crl_url {
+            extensions.push(X509Extension::new(
+                None,
+                None,
+                "crlDistributionPoints",
+                &format!("URI:{}", url),
+            )?);
+        }
+
+        if !self.certificate_policies.is_empty() {
+            extensions.push(X509Extension::new_from_der(
+                Asn1Object::from_str(OID_CERTIFICATE_POLICIES)?.as_ref(),
+                false,
+                Asn1OctetString::new_from_bytes(&certificate_policies_der(
+                    &self.certificate_policies,
+                )?)?
+                .as_ref(),
+            )?);
+        }
+
+        // if another issuance won the race, its template is used and ours is dropped.
+        let _ = self.template.set(ExtensionTemplate(extensions));
+        Ok(self.template.get().unwrap())
+    }
+
+    /// returns the certificate
+    pub fn certificate(self) -> X509 {
+        self.certificate
+    }
+
+    /// returns the private key
+    pub fn private_key(self) -> PKey<Private> {
+        self.private_key
+    }
+
+    /// returns the CA's public key (SubjectPublicKeyInfo) in PEM format, for key pinning.
+    pub fn public_key_pem(&self) -> Result<Vec<u8>, ErrorStack> {
+        self.certificate.public_key()?.public_key_to_pem()
+    }
+
+    /// export_pkcs12 bundles a certificate, its private key and the CA certificates in `chain`
+    /// (usually [CA::chain]) into a DER-encoded PKCS#12 (`.pfx`) archive encrypted with
+    /// `passphrase`. The bundle is named after the certificate's common name, if it has one.
+    ///
+    /// The passphrase must be UTF-8, as it is handed to openssl as a string; otherwise
+    /// [Pkcs12Error::InvalidPassphrase] is returned rather than an archive with another one.
+    pub fn export_pkcs12(
+        cert: &X509,
+        key: &PKey<Private>,
+        chain: &[X509],
+        passphrase: &[u8],
+    ) -> Result<Vec<u8>, Pkcs12Error> {
+        let passphrase =
+            std::str::from_utf8(passphrase).map_err(|_| Pkcs12Error::InvalidPassphrase)?;
+
+        let mut stack = Stack::new()?;
+        for ca in chain {
+            stack.push(ca.clone())?;
+        }
+
+        let name = cert
+            .subject_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .next()
+            .and_then(|cn| cn.data().as_utf8().ok())
+            .map(|cn| cn.to_string())
+            .unwrap_or_default();
+
+        let mut builder = Pkcs12::builder();
+        builder.ca(stack);
+        Ok(builder.build(passphrase, &name, key, cert)?.to_der()?)
+    }
+
+    /// signature_algorithm returns the AlgorithmIdentifier for signatures made by sign_der, or
+    /// None if the CA's key type is not supported.
+    fn signature_algorithm(&self) -> Option<Vec<u8>> {
+        let algorithm = match self.private_key.id() {
+            Id::RSA => {
+                let mut algorithm = der_oid(OID_SHA256_WITH_RSA)?;
+                algorithm.extend(der_tlv(DER_NULL, &[]));
+                algorithm
+            }
+            Id::EC if is_p384(&self.private_key) => der_oid(OID_ECDSA_WITH_SHA384)?,
+            Id::EC => der_oid(OID_ECDSA_WITH_SHA256)?,
+            Id::ED25519 => der_oid(OID_ED25519)?,
+            _ => return None,
+        };
+
+        Some(der_tlv(DER_SEQUENCE, &algorithm))
+    }
+
+    /// sign_der signs DER-encoded data with the CA's private key, returning the signature as a
+    /// BIT STRING.
+    fn sign_der(&self, tbs: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+        let mut signature = vec![0];
+
+        if self.private_key.id() == Id::ED25519 {
+            let mut signer = Signer::new_without_digest(&self.private_key)?;
+            signature.extend(signer.sign_oneshot_to_vec(tbs)?);
+        } else {
+            let digest = if is_p384(&self.private_key) {
+                MessageDigest::sha384()
+            } else {
+                MessageDigest::sha256()
+            };
+
+            let mut signer = Signer::new(digest, &self.private_key)?;
+            signer.update(tbs)?;
+            signature.extend(signer.sign_to_vec()?);
+        }
+
+        Ok(der_tlv(DER_BIT_STRING, &signature))
+    }
+
+    /// is_issuer_of returns true if the OCSP request identifies this CA as the issuer of the
+    /// certificate queried. Only SHA-1 and SHA-256 issuer hashes are supported.
+    pub fn is_issuer_of(&self, req: &OcspCertRequest) -> Result<bool, OcspError> {
+        let digest = if Some(&req.hash_algorithm) == der_oid(OID_SHA1).as_ref() {
+            MessageDigest::sha1()
+        } else if Some(&req.hash_algorithm) == der_oid(OID_SHA256).as_ref() {
+            MessageDigest::sha256()
+        } else {
+            return Ok(false);
+        };
+
+        let name_hash = hash(digest, &self.certificate.subject_name().to_der()?)?;
+        let key_hash = self.key_hash(digest)?;
+
+        Ok(*name_hash == *req.issuer_name_hash && *key_hash == *req.issuer_key_hash)
+    }
+
+    /// key_hash returns the issuerKeyHash of certificates signed by this CA, as used in OCSP
+    /// CertIDs and renewal information requests.
+    pub fn key_hash(&self, digest: MessageDigest) -> Result<DigestBytes, OcspError> {
+        key_hash(&self.certificate, digest)
+    }
+
+    /// sign_ocsp_response builds a successful OCSPResponse carrying a BasicOCSPResponse for the
+    /// statuses provided, signed with the CA's private key. Responses are valid for
+    /// `next_update`, after which clients should ask again.
+    pub fn sign_ocsp_response(
+        &self,
+        statuses: &[(OcspCertRequest, CertificateStatus)],
+        next_update: Duration,
+    ) -> Result<Vec<u8>, OcspError> {
+        let now = chrono::Utc::now();
+        let next_update = now
+            + chrono::Duration::from_std(next_update).unwrap_or_else(|_| chrono::Duration::zero());
+
+        let mut responses = Vec::new();
+
+        for (req, status) in statuses {
+            let mut response = req.cert_id.clone();
+
+            response.extend(match status {
+                CertificateStatus::Good => der_tlv(0x80, &[]),
+                CertificateStatus::Revoked { revoked_at, reason } => {
+                    let mut info = der_generalized_time(*revoked_at);
+                    info.extend(der_tlv(0xa0, &der_tlv(DER_ENUMERATED, &[*reason as u8])));
+                    der_tlv(0xa1, &info)
+                }
+                CertificateStatus::Unknown => der_tlv(0x82, &[]),
+            });
+
+            response.extend(der_generalized_time(now));
+            response.extend(der_tlv(0xa0, &der_generalized_time(next_update)));
+            responses.extend(der_tlv(DER_SEQUENCE, &response));
+        }
+
+        // the responder is identified by name, as it is the CA itself.
+        let mut data = der_tlv(0xa1, &self.certificate.subject_name().to_der()?);
+        data.extend(der_generalized_time(now));
+        data.extend(der_tlv(DER_SEQUENCE, &responses));
+        let tbs = der_tlv(DER_SEQUENCE, &data);
+
+        let algorithm = self
+            .signature_algorithm()
+            .ok_or(OcspError::UnsupportedKey)?;
+
+        let mut basic = tbs.clone();
+        basic.extend(algorithm);
+        basic.extend(self.sign_der(&tbs)?);
+
+        let mut bytes = der_oid(OID_OCSP_BASIC).unwrap();
+        bytes.extend(der_tlv(DER_OCTET_STRING, &der_tlv(DER_SEQUENCE, &basic)));
+
+        let mut response = der_tlv(
+            DER_ENUMERATED,
+            &[OcspResponseStatus::SUCCESSFUL.as_raw() as u8],
+        );
+        response.extend(der_tlv(0xa0, &der_tlv(DER_SEQUENCE, &bytes)));
+
+        Ok(der_tlv(DER_SEQUENCE, &response))
+    }
+
+    /// generate_crl produces a signed X.509 v2 CRL listing the revoked certificates provided.
+    /// Clients should fetch a new CRL after `next_update`.
+    pub fn generate_crl(
+        &self,
+        revoked: &[RevokedCertificate],
+        next_update: Duration,
+    ) -> Result<X509Crl, CrlError> {
+        let now = chrono::Utc::now();
+        let next_update = now
+            + chrono::Duration::from_std(next_update).unwrap_or_else(|_| chrono::Duration::zero());
+
+        let algorithm = self.signature_algorithm().ok_or(CrlError::UnsupportedKey)?;
+
+        let mut entries = Vec::new();
+
+        for revocation in revoked {
+            let mut entry = der_integer(&revocation.serial);
+            entry.extend(der_time(revocation.revoked_at));
+
+            // the reason code is omitted for unspecified (0), as RFC 5280 5.3.1 recommends.
+            if revocation.reason != 0 {
+                entry.extend(der_tlv(
+                    DER_SEQUENCE,
+                    &der_extension(
+                        OID_CRL_REASON,
+                        &der_tlv(DER_ENUMERATED, &[revocation.reason as u8]),
+                    ),
+                ));
+            }
+
+            entries.extend(der_tlv(DER_SEQUENCE, &entry));
+        }
+
+        // the CRL number only needs to increase monotonically, which the time does for us.
+        let mut extensions = der_extension(
+            OID_CRL_NUMBER,
+            &der_integer(&(now.timestamp() as u64).to_be_bytes()),
+        );
+
+        if let Some(skid) = self.certificate.subject_key_id() {
+            extensions.extend(der_extension(
+                OID_AUTHORITY_KEY_IDENTIFIER,
+                &der_tlv(DER_SEQUENCE, &der_tlv(0x80, skid.as_slice())),
+            ));
+        }
+
+        let mut tbs = der_integer(&[1]); // v2
+        tbs.extend(algorithm.clone());
+        tbs.extend(self.certificate.subject_name().to_der()?);
+        tbs.extend(der_time(now));
+        tbs.extend(der_time(next_update));
+        if !entries.is_empty() {
+            tbs.extend(der_tlv(DER_SEQUENCE, &entries));
+        }
+        tbs.extend(der_tlv(0xa0, &der_tlv(DER_SEQUENCE, &extensions)));
+        let tbs = der_tlv(DER_SEQUENCE, &tbs);
+
+        let mut crl = tbs.clone();
+        crl.extend(algorithm);
+        crl.extend(self.sign_der(&tbs)?);
+
+        Ok(X509Crl::from_der(&der_tlv(DER_SEQUENCE, &crl))?)
+    }
+
+    /// signs a CSR with the CA's private key. The not_before and not_after parameters can be used
+    /// to control its lifetime, subject to the CA's [CertProfile].
+    ///
+    /// CSRs which are not signed by the key they carry are rejected with
+    /// [CsrError::InvalidSignature].
+    ///
+    /// Only the subjectAltName requested in the CSR is carried into the certificate; key usage is
+    /// always decided by the CA. CSRs requesting an extended key usage other than serverAuth or
+    /// clientAuth are rejected.
+    ///
+    /// The time taken to issue each certificate is logged at debug level as `signing_duration_ms`.
+    ///
+    /// The certificate is given a random serial number; see
+    /// [CA::generate_and_sign_cert_with_serial] to provide one.
+    pub fn generate_and_sign_cert(
+        &self,
+        req: X509Req,
+        not_before: SystemTime,
+        not_after: SystemTime,
+    ) -> Result<X509, CsrError> {
+        let serial = random_serial()?;
+        self.sign_with_timing(req, not_before, not_after, serial)
+    }
+
+    /// generate_and_sign_cert_with_serial is like [CA::generate_and_sign_cert], but the low 64
+    /// bits of the certificate's serial number are the `serial` provided, e.g. one from
+    /// [crate::models::Postgres::next_serial], which is unique across every instance sharing the
+    /// database. The bits above it are random; see [serial_with_entropy].
+    pub fn generate_and_sign_cert_with_serial(
+        &self,
+        req: X509Req,
+        not_before: SystemTime,
+        not_after: SystemTime,
+        serial: u64,
+    ) -> Result<X509, CsrError> {
+        let serial = serial_with_entropy(serial)?;
+        self.sign_with_timing(req, not_before, not_after, serial)
+    }
+
+    fn sign_with_timing(
+        &self,
+        req: X509Req,
+        not_before: SystemTime,
+        not_after: SystemTime,
+        serial: BigNum,
+    ) -> Result<X509, CsrError> {
+        let start = tokio::time::Instant::now();
+        let res = self.sign_cert(req, not_before, not_after, serial);
+        let elapsed = start.elapsed();
+
+        log::debug!(
+            "certificate signing {}: signing_duration_ms={}",
+            if res.is_ok() { "succeeded" } else { "failed" },
+            elapsed.as_millis()
+        );
+
+        crate::acme::metrics::SIGNING_DURATION_HISTOGRAM.observe(elapsed.as_secs_f64());
+
+        res
+    }
+
+    fn sign_cert(
+        &self,
+        req: X509Req,
+        not_before: SystemTime,
+        not_after: SystemTime,
+        serial: BigNum,
+    ) -> Result<X509, CsrError> {
+        let (not_before, not_after) = self.profile.validity(not_before, Some(not_after))?;
+
+        // RFC8555 7.4: the CSR must be signed by the key it asks to certify.
+        if !req.verify(req.public_key()?.as_ref())? {
+            return Err(CsrError::InvalidSignature);
+        }
+
+        CsrValidator::validate_public_key(&req)?;
+        let (names, addresses) = requested_names(&req)?;
+
+        let mut builder = X509::builder()?;
+        builder.set_pubkey(req.public_key()?.as_ref())?;
+        builder.set_issuer_name(self.certificate.subject_name())?;
+        builder.set_serial_number(serial.as_ref().to_asn1_integer()?.as_ref())?;
+
+        if !names.is_empty() || !addresses.is_empty() {
+            let mut san = SubjectAlternativeName::new();
+            for name in &names {
+                san.dns(name);
+            }
+
+            for addr in &addresses {
+                san.ip(&addr.to_string());
+            }
+
+            builder.append_extension(san.build(&builder.x509v3_context(None, None))?)?;
+        }
+
+        for ext in &self.template()?.0 {
+            builder.append_extension2(ext)?;
+        }
+
+        builder.append_extension(X509Extension::new(
+            None,
+            Some(&builder.x509v3_context(None, None)),
+            "authorityKeyIdentifier",
+            "issuer",
+        )?)?;
+
+        builder.append_extension(X509Extension::new(
+            None,
+            Some(&builder.x509v3_context(None, None)),
+            "subjectKeyIdentifier",
+            "hash",
+        )?)?;
+
+        builder.append_extension(X509Extension::new(
+            None,
+            Some(&builder.x509v3_context(None, None)),
+            "issuerAltName",
+            "issuer:copy",
+        )?)?;
+
+        builder.set_subject_name(&self.subject_name(&req)?)?;
+        builder.set_version(2)?;
+        builder.set_not_before(st_to_asn1(not_before)?.as_ref())?;
+        builder.set_not_after(st_to_asn1(not_after)?.as_ref())?;
+
+        builder.sign(&self.private_key, certificate_digest(&self.private_key))?;
+        Ok(builder.build())
+    }
+
+    /// verify_chain checks that `leaf` chains up through `chain`, ordered as [CA::chain] returns
+    /// it, to the root at its end, which is the only certificate trusted. It catches a CA whose
+    /// chain is misconfigured, e.g. with the wrong issuer or a path length constraint the chain
+    /// exceeds, before certificates which clients cannot validate are handed out.
+    ///
+    /// Validity periods are not checked: certificates may be issued to start in the future, and
+    /// the expiry of the CA is dealt with by [CACollector].
+    pub fn verify_chain(leaf: &X509, chain: &[X509]) -> Result<(), ChainError> {
+        let (root, intermediates) = chain.split_last().ok_or(ChainError::Empty)?;
+
+        let mut store = X509StoreBuilder::new()?;
+        store.add_cert(root.clone())?;
+        store.set_flags(X509VerifyFlags::NO_CHECK_TIME)?;
+        let store = store.build();
+
+        let mut untrusted = Stack::new()?;
+        for cert in intermediates {
+            untrusted.push(cert.clone())?;
+        }
+
+        let failure = X509StoreContext::new()?.init(&store, leaf, &untrusted, |ctx| {
+            Ok(match ctx.verify_cert()? {
+                true => None,
+                false => Some(ctx.error().error_string()),
+            })
+        })?;
+
+        match failure {
+            None => Ok(()),
+            Some(reason) => Err(ChainError::Invalid(reason.to_string())),
+        }
+    }
+
+    /// ca_fingerprint_sha256 returns the SHA-256 fingerprint of the DER encoding of the CA
+    /// certificate, which identifies the CA across rotations and restarts.
+    pub fn ca_fingerprint_sha256(&self) -> Result<[u8; 32], ErrorStack> {
+        let mut fingerprint = [0; 32];
+        fingerprint.copy_from_slice(&self.certificate.digest(MessageDigest::sha256())?);
+        Ok(fingerprint)
+    }
+
+    /// returns how long until the CA certificate expires, or zero if it already has.
+    pub fn expires_in(&self) -> Result<Duration, ErrorStack> {
+        let diff = Asn1Time::days_from_now(0)?.diff(self.certificate.not_after())?;
+        let secs = diff.days as i64 * 86400 + diff.secs as i64;
+        Ok(Duration::from_secs(secs.max(0) as u64))
+    }
+
+    /// generate_root creates a self-signed root CA as described by `config`. The certificate
+    /// carries critical basicConstraints and keyUsage extensions per RFC5280 4.2.1.9 and 4.2.1.3,
+    /// and a subjectKeyIdentifier.
+    pub fn generate_root(config: CaConfig) -> Result<Self, ErrorStack> {
+        let mut builder = X509::builder()?;
+        builder.set_subject_name(&config.subject)?;
+        builder.set_issuer_name(&config.subject)?;
+
+        builder.set_serial_number(random_serial()?.as_ref().to_asn1_integer()?.as_ref())?;
+
+        let privkey = config.algorithm.generate_key()?;
+        builder.set_pubkey(&privkey)?;
+        builder.set_version(2)?;
+        builder.set_not_before(Asn1Time::days_from_now(0)?.as_ref())?;
+        builder.set_not_after(st_to_asn1(SystemTime::now() + config.validity)?.as_ref())?;
+
+        let basic_constraints = match config.path_len_constraint {
+            Some(pathlen) => format!("critical,CA:true,pathlen:{}", pathlen),
+            None => "critical,CA:true".to_string(),
+        };
+
+        builder.append_extension(X509Extension::new(
+            None,
+            Some(&builder.x509v3_context(None, None)),
+            "basicConstraints",
+            &basic_constraints,
+        )?)?;
+
+        if !config.key_usage.is_empty() {
+            let key_usage = config
+                .key_usage
+                .iter()
+                .map(KeyUsage::name)
+                .collect::<Vec<_>>()
+                .join(",");
+
+            builder.append_extension(X509Extension::new(
+                None,
+                Some(&builder.x509v3_context(None, None)),
+                "keyUsage",
+                &format!("critical,{}", key_usage),
+            )?)?;
+        }
+
+        builder.append_extension(X509Extension::new(
+            None,
+            Some(&builder.x509v3_context(None, None)),
+            "subjectKeyIdentifier",
+            "hash",
+        )?)?;
+
+        builder.append_extension(X509Extension::new(
+            None,
+            Some(&builder.x509v3_context(None, None)),
+            "issuerAltName",
+            "issuer:copy",
+        )?)?;
+
+        builder.sign(privkey.as_ref(), certificate_digest(&privkey))?;
+        Ok(Self::new(builder.build(), privkey))
+    }
+
+    /// new_test_ca is a convenience function for creating a quick and dirty CA for use in tests
+    /// and demo applications (such as the examples). The CA certificate is valid for a year and
+    /// uses a 4096 bit RSA key; the certificates it issues are valid for 90 days.
+    pub fn new_test_ca() -> Result<Self, ErrorStack> {
+        Self::new_test_ca_with_validity(Duration::from_secs(365 * 24 * 60 * 60))
+    }
+
+    /// new_test_ca_with_validity is like new_test_ca, but the CA certificate expires after
+    /// `validity`. Useful for exercising CA expiry.
+    pub fn new_test_ca_with_validity(validity: Duration) -> Result<Self, ErrorStack> {
+        Self::new_test_ca_with(SigningAlgorithm::Rsa4096, validity, 0)
+    }
+
+    /// new_test_ca_with_algorithm is like new_test_ca, but the CA key is generated for the
+    /// algorithm provided.
+    pub fn new_test_ca_with_algorithm(algorithm: SigningAlgorithm) -> Result<Self, ErrorStack> {
+        Self::new_test_ca_with(algorithm, Duration::from_secs(365 * 24 * 60 * 60), 0)
+    }
+
+    /// new_test_root_ca is like new_test_ca, but the CA may sign an intermediate CA with
+    /// [CA::new_intermediate].
+    pub fn new_test_root_ca(algorithm: SigningAlgorithm) -> Result<Self, ErrorStack> {
+        Self::new_test_ca_with(algorithm, Duration::from_secs(365 * 24 * 60 * 60), 1)
+    }
+
+    fn new_test_ca_with(
+        algorithm: SigningAlgorithm,
+        validity: Duration,
+        pathlen: u32,
+    ) -> Result<Self, ErrorStack> {
+        let mut namebuilder = X509Name::builder()?;
+        namebuilder.append_entry_by_text("C", "US")?;
+        namebuilder.append_entry_by_text("O", "ZeroTier")?;
+        namebuilder.append_entry_by_text("CN", "CA Signing Certificate")?;
+        namebuilder.append_entry_by_text("ST", "California")?;
+        namebuilder.append_entry_by_text("L", "Irvine")?;
+        namebuilder.append_entry_by_text("OU", "A Test Suite")?;
+
+        let ca = Self::generate_root(CaConfig {
+            path_len_constraint: Some(pathlen),
+            algorithm,
+            validity,
+            ..CaConfig::new(namebuilder.build())
+        })?;
+
+        Ok(ca.with_cert_profile(CertProfile {
+            not_after_offset: Duration::from_secs(90 * 24 * 60 * 60),
+            ..Default::default()
+        }))
+    }
+}
+
+/// the smallest RSA modulus, in bits, we will issue certificates for.
+const MIN_RSA_BITS: u32 = 2048;
+
+/// CsrValidator holds the checks a CSR must pass before it is signed.
+pub struct CsrValidator;
+
+impl CsrValidator {
+    /// validate_public_key rejects CSRs whose public key is too weak to certify: RSA keys smaller
+    /// than 2048 bits, EC keys which are not on a named curve (or not on their curve at all),
+    /// Ed25519 keys of the wrong length, and keys of any other type.
+    pub fn validate_public_key(csr: &X509Req) -> Result<(), CsrError> {
+        let key = csr.public_key()?;
+
+        let weak = |algorithm: &str, reason: String| {
+            Err(CsrError::WeakPublicKey {
+                algorithm: algorithm.to_string(),
+                reason,
+            })
+        };
+
+        match key.id() {
+            Id::RSA => {
+                if key.bits() < MIN_RSA_BITS {
+                    return weak(
+                        "RSA",
+                        format!(
+                            "{} bit keys are too small; at least {} bits are required",
+                            key.bits(),
+                            MIN_RSA_BITS
+                        ),
+                    );
+                }
+            }
+            Id::EC => {
+                let ec = key.ec_key()?;
+                if ec.group().curve_name().is_none() {
+                    return weak("EC", "keys must be on a named curve".to_string());
+                }
+
+                if ec.check_key().is_err() {
+                    return weak("EC", "public key is not on its curve".to_string());
+                }
+            }
+            Id::ED25519 => {
+                let raw = key.raw_public_key()?;
+                if raw.len() != 32 {
+                    return weak(
+                        "Ed25519",
+                        format!("public key is {} bytes; 32 are required", raw.len()),
+                    );
+                }
+            }
+            id => return weak(&format!("{:?}", id), "unsupported key type".to_string()),
+        }
+
+        Ok(())
+    }
+}
+
+/// requested_names returns the DNS names and IP addresses requested by the CSR's subjectAltName
+/// extension. It also enforces that any requested extended key usage is compatible with a TLS
+/// server certificate.
+fn requested_names(req: &X509Req) -> Result<(Vec<String>, Vec<IpAddr>), CsrError> {
+    let der = req.to_der()?;
+    let (_, csr) =
+        X509CertificationRequest::from_der(&der).map_err(|e| CsrError::Parse(e.to_string()))?;
+
+    let mut names = Vec::new();
+    let mut addresses = Vec::new();
+
+    if let Some(extensions) = csr.requested_extensions() {
+        for extension in extensions {
+            match extension {
+                ParsedExtension::SubjectAlternativeName(san) => {
+                    for name in san.general_names.iter() {
+                        match name {
+                            GeneralName::DNSName(name) => names.push(name.to_string()),
+                            GeneralName::IPAddress(octets) => match ip_from_octets(octets) {
+                                Some(addr) => addresses.push(addr),
+                                None => return Err(CsrError::InvalidIPAddress(octets.len())),
+                            },
+                            _ => {}
+                        }
+                    }
+                }
+                ParsedExtension::ExtendedKeyUsage(eku) => {
+                    if eku.any
+                        || eku.code_signing
+                        || eku.email_protection
+                        || eku.time_stamping
+                        || eku.ocsp_signing
+                        || !eku.other.is_empty()
+                    {
+                        return Err(CsrError::ProhibitedExtendedKeyUsage);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok((names, addresses))
+}
+
+/// CACollector is an async observer which waits for a CA to arrive, and fosters the creation of
+/// signed CSRs as certificates. This allows for the rotation of CA certificates, or delayed
+/// loading, without loss of functionality due to race conditions. Please see the `acmed` example for usage.
+#[derive(Clone, Debug)]
+pub struct CACollector {
+    poll_interval: Duration,
+    ca: SharedCA,
+    profile: Option<CertProfile>,
+    rotations: Arc<AtomicU64>,
+    /// milliseconds since the epoch of the last rotation; zero if there was none.
+    last_rotation: Arc<AtomicU64>,
+    issued: Arc<AtomicU64>,
+    certs_issued: Arc<AtomicU64>,
+    signing_errors: Arc<AtomicU64>,
+    issued_notify: Arc<Notify>,
+    hooks: RotationHooks,
+    /// the CAs rotated away from, kept until they expire so the certificates they issued can
+    /// still be checked, revoked and served with their chain; see [CACollector::issuers].
+    retired: Arc<RwLock<Vec<CA>>>,
+}
+
+/// CollectorStats counts what a [CACollector] has done since it was created; see
+/// [CACollector::stats].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CollectorStats {
+    /// certificates signed, across all CAs collected.
+    pub certs_issued: u64,
+    /// CSRs which could not be signed, e.g. because they failed validation.
+    pub signing_errors: u64,
+    /// see [CACollector::rotations].
+    pub ca_rotations: u64,
+    /// when the CA was last rotated, if it ever was.
+    pub last_rotation: Option<SystemTime>,
+}
+
+/// SharedCA is a simple type for managing the locking around a CA.
+type SharedCA = Arc<RwLock<Option<CA>>>;
+
+/// RotationHook is called with each CA the [CACollector] starts signing with; see
+/// [CACollector::with_rotation_hook].
+pub type RotationHook = Box<dyn Fn(&CA) + Send + Sync>;
+
+#[derive(Clone, Default)]
+struct RotationHooks(Vec<Arc<RotationHook>>);
+
+impl std::fmt::Debug for RotationHooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RotationHooks({} hooks)", self.0.len())
+    }
+}
+
+/// RotationPolicy limits how much a CA is used before [CACollector::spawn_collector] asks its
+/// closure for a new one, without waiting for the next poll. The default imposes no limits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RotationPolicy {
+    /// the number of certificates signed through the collector with the same CA.
+    pub max_certs: Option<u64>,
+    /// how long the same CA is signed with, from when it was collected.
+    pub max_age: Option<Duration>,
+}
+
+impl CACollector {
+    /// new is a constructor; the duration provided determines how often the loop will awake and
+    /// process a CA injection.
+    pub fn new(poll_interval: Duration) -> Self {
+        Self {
+            poll_interval,
+            ca: Arc::new(RwLock::new(None)),
+            profile: None,
+            rotations: Default::default(),
+            last_rotation: Default::default(),
+            issued: Default::default(),
+            certs_issued: Default::default(),
+            signing_errors: Default::default(),
+            issued_notify: Default::default(),
+            hooks: Default::default(),
+            retired: Default::default(),
+        }
+    }
+
+    /// rotations returns how many times the collected CA has been replaced by one with a
+    /// different certificate. Collecting the first CA is not a rotation.
+    pub fn rotations(&self) -> u64 {
+        self.rotations.load(Ordering::Relaxed)
+    }
+
+    /// stats returns the collector's counters. Clones share them.
+    pub fn stats(&self) -> CollectorStats {
+        let last_rotation = match self.last_rotation.load(Ordering::Relaxed) {
+            0 => None,
+            millis => Some(SystemTime::UNIX_EPOCH + Duration::from_millis(millis)),
+        };
+
+        CollectorStats {
+            certs_issued: self.certs_issued.load(Ordering::Relaxed),
+            signing_errors: self.signing_errors.load(Ordering::Relaxed),
+            ca_rotations: self.rotations(),
+            last_rotation,
+        }
+    }
+
+    /// with_cert_profile overrides the [CertProfile] of whichever CA is collected, so that the
+    /// validity of issued certificates survives CA rotation.
+    pub fn with_cert_profile(mut self, profile: CertProfile) -> Self {
+        self.profile = Some(profile);
+        self
+    }
+
+    /// with_rotation_hook registers a hook which is called with every CA the collector starts
+    /// signing with, including the first, e.g. to write its certificate to disk. Hooks run on
+    /// the collector's task after the CA has been swapped in, so they should not block for long.
+    pub fn with_rotation_hook(mut self, hook: RotationHook) -> Self {
+        self.hooks.0.push(Arc::new(hook));
+        self
+    }
+
+    /// returns the CA as a SharedCA.
+    pub fn ca(self) -> SharedCA {
+        self.ca.clone()
+    }
+
+    /// issuers returns the current CA, followed by the CAs it replaced which have not expired
+    /// yet. A certificate cannot outlive the CA which issued it, so these are all the CAs whose
+    /// certificates may still be in use.
+    pub async fn issuers(&self) -> Vec<CA> {
+        let mut issuers: Vec<CA> = self.ca.read().await.iter().cloned().collect();
+        issuers.extend(
+            self.retired
+                .read()
+                .await
+                .iter()
+                .filter(|ca| !is_expired(ca))
+                .cloned(),
+        );
+
+        issuers
+    }
+
+    /// issuer_of returns the CA among [CACollector::issuers] which signed `cert`, if any.
+    pub async fn issuer_of(&self, cert: &X509) -> Option<CA> {
+        let issuer_name = cert.issuer_name().to_der().ok()?;
+
+        self.issuers().await.into_iter().find(|ca| {
+            ca.certificate.subject_name().to_der().ok().as_ref() == Some(&issuer_name)
+                && matches!(
+                    ca.certificate
+                        .public_key()
+                        .and_then(|key| cert.verify(&key)),
+                    Ok(true)
+                )
+        })
+    }
+
+    /// is_known_issuer reports whether `key_hash`, the SHA-1 issuerKeyHash of e.g. an OCSP or
+    /// renewal information certID, is that of one of the [CACollector::issuers].
+    pub async fn is_known_issuer(&self, key_hash: &[u8]) -> bool {
+        self.issuers()
+            .await
+            .iter()
+            .any(|ca| matches!(ca.key_hash(MessageDigest::sha1()), Ok(hash) if *hash == *key_hash))
+    }
+
+    /// certificate_info returns the SHA-256 fingerprint (hex encoded) and the expiry of the current
+    /// CA certificate, or None if no CA has been collected yet.
+    pub async fn certificate_info(&self) -> Result<Option<(String, String)>, ErrorStack> {
+        match self.ca.read().await.as_ref() {
+            Some(ca) => {
+                let fingerprint = ca
+                    .ca_fingerprint_sha256()?
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<String>();
+                Ok(Some((fingerprint, ca.certificate.not_after().to_string())))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// majority of callers will use this function to collect the CA. It takes a closure which
+    /// returns the CA that signs certificates, along with the certificates of the CAs above it
+    /// (issuer first, root last; empty for a self-signed CA), so that it can overwrite the
+    /// previous CA. The chain is served with issued certificates.
+    ///
+    /// If the CA certificate expires before the next poll, the closure is called again as soon
+    /// as it does. Likewise when the CA exceeds a limit of the [RotationPolicy]; a closure which
+    /// then returns the same CA again is not asked for another until the next poll.
+    pub async fn spawn_collector<F>(&mut self, f: F, policy: RotationPolicy)
+    where
+        F: Fn() -> Result<(CA, Vec<X509>), ErrorStack>,
+    {
+        let mut collected = Instant::now();
+        let mut rotation_due = false;
+
+        loop {
+            let res = f();
+
+            match res {
+                Ok((ca, chain)) => {
+                    let ca = ca.with_chain(chain);
+                    let mut current = self.ca.write().await;
+                    let changed = match current.as_ref() {
+                        Some(previous) => {
+                            previous.certificate.to_der().ok() != ca.certificate.to_der().ok()
+                        }
+                        None => true,
+                    };
+
+                    if changed {
+                        if current.is_some() {
+                            self.rotations.fetch_add(1, Ordering::Relaxed);
+                            self.last_rotation.store(
+                                SystemTime::now()
+                                    .duration_since(SystemTime::UNIX_EPOCH)
+                                    .unwrap_or_default()
+                                    .as_millis() as u64,
+                                Ordering::Relaxed,
+                            );
+                        }
+                        self.issued.store(0, Ordering::Relaxed);
+                        collected = Instant::now();
+                        rotation_due = false;
+                    } else if rotation_due {
+                        warn!("CA rotation policy exceeded, but the same CA was collected again; it will continue to be used");
+                    }
+
+                    let previous = current.replace(ca.clone());
+                    drop(current);
+
+                    if changed {
+                        let mut retired = self.retired.write().await;
+                        // a CA collected again is current, not retired.
+                        let der = ca.certificate.to_der().ok();
+                        retired.retain(|r| !is_expired(r) && r.certificate.to_der().ok() != der);
+                        if let Some(previous) = previous {
+                            retired.push(previous);
+                        }
+                        drop(retired);
+
+                        for hook in self.hooks.0.iter() {
+                            hook(&ca);
+                        }
+                    }
+                },
+                Err(e) => warn!("Failed to retrieve CA, signing will will continue to use the old CA, if any. Error: {}", e.to_string())
+            }
+
+            let expires_in = match self.ca.read().await.as_ref() {
+                Some(ca) => ca.expires_in().ok(),
+                None => None,
+            };
+
+            // an already expired CA would otherwise have us spinning on the closure.
+            let mut sleep = match expires_in {
+                Some(expires_in) if !expires_in.is_zero() && expires_in < self.poll_interval => {
+                    // certificate times have a granularity of one second
+                    expires_in + Duration::from_secs(1)
+                }
+                _ => self.poll_interval,
+            };
+
+            // likewise, a CA which outlived max_age and was collected again waits for the poll.
+            if let (Some(max_age), false) = (policy.max_age, rotation_due) {
+                sleep = sleep.min(max_age.saturating_sub(collected.elapsed()));
+            }
+
+            let sleep = tokio::time::sleep(sleep);
+            tokio::pin!(sleep);
+            loop {
+                tokio::select! {
+                    _ = &mut sleep => break,
+                    _ = self.issued_notify.notified() => {
+                        if matches!(policy.max_certs, Some(max) if !rotation_due && self.issued.load(Ordering::Relaxed) >= max) {
+                            rotation_due = true;
+                            break;
+                        }
+                    }
+                }
+            }
+
+            if let Some(max_age) = policy.max_age {
+                rotation_due = rotation_due || collected.elapsed() >= max_age;
+            }
+
+            if let Some(ca) = self.ca.read().await.as_ref() {
+                if matches!(ca.expires_in(), Ok(expires_in) if expires_in.is_zero()) {
+                    warn!("CA certificate has expired; collecting a new one");
+                }
+            }
+        }
+    }
+
+    /// similar to CA::generate_and_sign_cert, this signs the CSR through the SharedCA provided by
+    /// the collector. Without a not_after, the certificate is valid for as long as the
+    /// [CertProfile] in effect allows.
+    pub async fn sign(
+        self,
+        req: X509Req,
+        not_before: SystemTime,
+        not_after: Option<SystemTime>,
+    ) -> Result<X509, CsrError> {
+        self.sign_inner(req, not_before, not_after, None)
+            .await
+            .map(|(cert, _)| cert)
+    }
+
+    /// sign_with_serial is like [CACollector::sign], but the certificate is given the serial
+    /// number provided; see [CA::generate_and_sign_cert_with_serial]. It is returned with the
+    /// chain of the CA which signed it, as the collector may have replaced that CA since.
+    pub async fn sign_with_serial(
+        self,
+        req: X509Req,
+        not_before: SystemTime,
+        not_after: Option<SystemTime>,
+        serial: u64,
+    ) -> Result<(X509, Vec<X509>), CsrError> {
+        self.sign_inner(req, not_before, not_after, Some(serial))
+            .await
+    }
+
+    async fn sign_inner(
+        self,
+        req: X509Req,
+        not_before: SystemTime,
+        not_after: Option<SystemTime>,
+        serial: Option<u64>,
+    ) -> Result<(X509, Vec<X509>), CsrError> {
+        let mut ca = self.ca.read().await.clone().unwrap();
+        if let Some(profile) = self.profile {
+            ca = ca.with_cert_profile(profile);
+        }
+
+        let not_after = not_after.unwrap_or(not_before + ca.profile.not_after_offset);
+        let res = match serial {
+            Some(serial) => {
+                ca.generate_and_sign_cert_with_serial(req, not_before, not_after, serial)
+            }
+            None => ca.generate_and_sign_cert(req, not_before, not_after),
+        };
+
+        let cert = match res {
+            Ok(cert) => cert,
+            Err(e) => {
+                self.signing_errors.fetch_add(1, Ordering::Relaxed);
+                return Err(e);
+            }
+        };
+
+        self.issued.fetch_add(1, Ordering::Relaxed);
+        self.certs_issued.fetch_add(1, Ordering::Relaxed);
+        self.issued_notify.notify_one();
+
+        Ok((cert, ca.chain()))
+    }
+}
+
+/// key_hash returns the hash of the key of the CA certificate provided, as certificates it signed
+/// are identified by in OCSP CertIDs and renewal information requests; see [CA::key_hash].
+pub fn key_hash(certificate: &X509, digest: MessageDigest) -> Result<DigestBytes, OcspError> {
+    // the key hash covers the subjectPublicKey bits, without the tag, length or the unused bits
+    // octet.
+    let spki = certificate.public_key()?.public_key_to_der()?;
+    let (spki, _) = der_expect(&spki, DER_SEQUENCE)?;
+    let (_, rest) = der_expect(spki.contents, DER_SEQUENCE)?;
+    let (key, _) = der_expect(rest, DER_BIT_STRING)?;
+
+    Ok(hash(digest, key.contents.get(1..).unwrap_or_default())?)
+}
+
+/// is_expired reports whether the CA certificate has expired.
+fn is_expired(ca: &CA) -> bool {
+    matches!(ca.expires_in(), Ok(expires_in) if expires_in.is_zero())
+}
+
+/// RevokedCertificate is an entry of a CRL generated by [CA::generate_crl].
+#[derive(Clone, Debug, PartialEq)]
+pub struct RevokedCertificate {
+    pub serial: Vec<u8>,
+    /// the RFC5280 CRLReason code.
+    pub reason: i32,
+    pub revoked_at: chrono::DateTime<chrono::Utc>,
+    /// the SHA-1 key hash of the CA which issued the certificate, if known. Certificates of an
+    /// unknown issuer are listed in the CRLs of every CA.
+    pub issuer_key_hash: Option<Vec<u8>>,
+}
+
+/// CRLCollector periodically generates a CRL from the revoked certificates its caller loads,
+/// signed by each of the [CACollector::issuers], and keeps the latest ones for distribution.
+#[derive(Clone, Debug)]
+pub struct CRLCollector {
+    interval: Duration,
+    next_update: Duration,
+    /// the DER-encoded CRL of each issuer, by the SHA-1 hash of its key; the current CA first.
+    crls: Arc<RwLock<Vec<(Vec<u8>, Vec<u8>)>>>,
+}
+
+impl CRLCollector {
+    /// new is a constructor; the CRL is regenerated every `interval`, and each CRL advertises
+    /// `next_update` as its validity. `next_update` should be comfortably longer than `interval`.
+    pub fn new(interval: Duration, next_update: Duration) -> Self {
+        Self {
+            interval,
+            next_update,
+            crls: Default::default(),
+        }
+    }
+
+    /// crl returns the latest DER-encoded CRL of the current CA, or None if none has been
+    /// generated yet.
+    pub async fn crl(&self) -> Option<Vec<u8>> {
+        self.crls.read().await.first().map(|(_, crl)| crl.clone())
+    }
+
+    /// crl_for_issuer is like [CRLCollector::crl], but returns the CRL of the CA whose key has
+    /// the SHA-1 hash provided, which may have been rotated away from.
+    pub async fn crl_for_issuer(&self, key_hash: &[u8]) -> Option<Vec<u8>> {
+        self.crls
+            .read()
+            .await
+            .iter()
+            .find(|(hash, _)| hash.as_slice() == key_hash)
+            .map(|(_, crl)| crl.clone())
+    }
+
+    /// spawn_collector regenerates the CRL forever, from the revoked certificates returned by
+    /// the closure. Failures are logged and the previous CRL, if any, continues to be served.
+    pub async fn spawn_collector<F, Fut, E>(&self, ca: CACollector, f: F)
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<Vec<RevokedCertificate>, E>>,
+        E: std::fmt::Display,
+    {
+        loop {
+            let res = match f().await {
+                Ok(revoked) => self.refresh(ca.clone(), &revoked).await,
+                Err(e) => Err(CrlError::Load(e.to_string())),
+            };
+
+            if let Err(e) = res {
+                warn!(
+                    "Failed to generate CRL, the previous CRL will be served. Error: {}",
+                    e
+                )
+            }
+
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+
+    /// refresh regenerates the CRLs immediately from the revoked certificates provided, e.g.
+    /// after a revocation. Nothing is generated if the collector has not loaded a CA yet.
+    pub async fn refresh(
+        &self,
+        ca: CACollector,
+        revoked: &[RevokedCertificate],
+    ) -> Result<(), CrlError> {
+        let mut crls = Vec::new();
+
+        for ca in ca.issuers().await {
+            let key_hash = ca
+                .key_hash(MessageDigest::sha1())
+                .map_err(|e| CrlError::OpenSSL(e.to_string()))?
+                .to_vec();
+            let revoked = revoked
+                .iter()
+                .filter(|r| !matches!(&r.issuer_key_hash, Some(hash) if *hash != key_hash))
+                .cloned()
+                .collect::<Vec<RevokedCertificate>>();
+
+            let crl = ca.generate_crl(&revoked, self.next_update)?.to_der()?;
+            crls.push((key_hash, crl));
+        }
+
+        if !crls.is_empty() {
+            *self.crls.write().await = crls;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::{
+        error::ErrorStack,
+        x509::{X509Req, X509},
+    };
+
+    fn generate_csr() -> Result<X509Req, ErrorStack> {
+        generate_csr_with_extensions(&[("subjectAltName", "DNS:example.org")])
+    }
+
+    fn generate_csr_with_extensions(extensions: &[(&str, &str)]) -> Result<X509Req, ErrorStack> {
+        use openssl::{hash::MessageDigest, pkey::PKey, rsa::Rsa};
+
+        let key = PKey::from_rsa(Rsa::generate(4096).unwrap()).unwrap();
+        generate_csr_for_key(extensions, &key, MessageDigest::sha256())
+    }
+
+    fn generate_csr_for_key(
+        extensions: &[(&str, &str)],
+        key: &openssl::pkey::PKeyRef<openssl::pkey::Private>,
+        digest: openssl::hash::MessageDigest,
+    ) -> Result<X509Req, ErrorStack> {
+        use openssl::{stack::Stack, x509::X509Extension, x509::X509Name};
+
+        let mut namebuilder = X509Name::builder().unwrap();
+        namebuilder
+            .append_entry_by_text("CN", "example.org")
+            .unwrap();
+        let mut req = X509Req::builder().unwrap();
+        req.set_subject_name(&namebuilder.build()).unwrap();
+
+        let mut stack = Stack::new()?;
+        for (name, value) in extensions {
+            stack.push(X509Extension::new(
+                None,
+                Some(&req.x509v3_context(None)),
+                name,
+                value,
+            )?)?;
+        }
+        req.add_extensions(&stack)?;
+
+        req.set_pubkey(key).unwrap();
+        req.sign(key, digest)?;
+        Ok(req.build())
+    }
+
+    #[test]
+    fn test_basic_ca_sign() {
+        use spectral::prelude::*;
+
+        use super::{st_to_asn1, CA};
+        use openssl::{pkey::PKey, rsa::Rsa};
+        use std::time::SystemTime;
+
+        let now = SystemTime::now();
+
+        let ca = CA::new_test_ca().unwrap();
+        let signed = ca
+            .generate_and_sign_cert(generate_csr().unwrap(), SystemTime::UNIX_EPOCH, now)
+            .unwrap();
+
+        let result = signed.verify(&ca.private_key());
+        assert_that!(result).is_ok();
+        assert_that!(result.unwrap()).is_true();
+
+        let badkey = Rsa::generate(4096).unwrap();
+        let result = signed.verify(PKey::from_rsa(badkey).unwrap().as_ref());
+        assert_that!(result).is_ok();
+        assert_that!(result.unwrap()).is_false();
+
+        assert_that!(signed.not_before())
+            .is_equal_to(&*st_to_asn1(SystemTime::UNIX_EPOCH).unwrap());
+        assert_that!(signed.not_after()).is_equal_to(&*st_to_asn1(now).unwrap());
+    }
+
+    #[test]
+    fn test_extended_key_usage() {
+        use super::CA;
+        use crate::errors::ca::CsrError;
+        use spectral::prelude::*;
+        use std::time::SystemTime;
+        use x509_parser::prelude::*;
+
+        let now = SystemTime::now();
+        let ca = CA::new_test_ca().unwrap();
+
+        for client_auth in [false, true] {
+            let ca = ca.clone().with_client_auth(client_auth);
+            let signed = ca
+                .generate_and_sign_cert(
+                    generate_csr_with_extensions(&[
+                        ("subjectAltName", "DNS:example.org"),
+                        ("extendedKeyUsage", "serverAuth"),
+                    ])
+                    .unwrap(),
+                    SystemTime::UNIX_EPOCH,
+                    now,
+                )
+                .unwrap();
+
+            let der = signed.to_der().unwrap();
+            let (_, cert) = X509Certificate::from_der(&der).unwrap();
+            let (critical, eku) = cert.tbs_certificate.extended_key_usage().unwrap();
+            assert_that!(critical).is_true();
+            assert_that!(eku.server_auth).is_true();
+            assert_that!(eku.client_auth).is_equal_to(client_auth);
+            assert_that!(eku.any).is_false();
+            assert_that!(eku.code_signing).is_false();
+            assert_that!(eku.email_protection).is_false();
+            assert_that!(eku.time_stamping).is_false();
+            assert_that!(eku.ocsp_signing).is_false();
+            assert_that!(eku.other).is_empty();
+
+            let (_, san) = cert.tbs_certificate.subject_alternative_name().unwrap();
+            assert_that!(san.general_names).is_equal_to(vec![GeneralName::DNSName("example.org")]);
+        }
+
+        for usage in [
+            "codeSigning",
+            "serverAuth,emailProtection",
+            "anyExtendedKeyUsage",
+        ] {
+            let res = ca.generate_and_sign_cert(
+                generate_csr_with_extensions(&[("extendedKeyUsage", usage)]).unwrap(),
+                SystemTime::UNIX_EPOCH,
+                now,
+            );
+            assert_that!(res.err()).is_equal_to(Some(CsrError::ProhibitedExtendedKeyUsage));
+        }
+    }
+
+    #[test]
+    fn test_ip_address_san() {
+        use super::CA;
+        use spectral::prelude::*;
+        use std::time::SystemTime;
+        use x509_parser::prelude::*;
+
+        let ca = CA::new_test_ca().unwrap();
+        let signed = ca
+            .generate_and_sign_cert(
+                generate_csr_with_extensions(&[(
+                    "subjectAltName",
+                    "DNS:example.org, IP:127.0.0.1, IP:2001:0db8:0:0::0001",
+                )])
+                .unwrap(),
+                SystemTime::UNIX_EPOCH,
+                SystemTime::now(),
+            )
+            .unwrap();
+
+        let der = signed.to_der().unwrap();
+        let (_, cert) = X509Certificate::from_der(&der).unwrap();
+        let (_, san) = cert.tbs_certificate.subject_alternative_name().unwrap();
+
+        let mut v6 = [0u8; 16];
+        v6[0..4].copy_from_slice(&[0x20, 0x01, 0x0d, 0xb8]);
+        v6[15] = 1;
+
+        assert_that!(san.general_names).is_equal_to(vec![
+            GeneralName::DNSName("example.org"),
+            GeneralName::IPAddress(&[127, 0, 0, 1]),
+            GeneralName::IPAddress(&v6),
+        ]);
+    }
+
+    #[test]
+    fn test_validate_public_key() {
+        use super::{CsrValidator, CA};
+        use crate::errors::ca::CsrError;
+        use openssl::{
+            bn::{BigNum, BigNumContext},
+            ec::{EcGroup, EcKey, EcPoint},
+            hash::MessageDigest,
+            nid::Nid,
+            pkey::PKey,
+            rsa::Rsa,
+        };
+        use spectral::prelude::*;
+        use std::time::SystemTime;
+
+        let san = &[("subjectAltName", "DNS:example.org")];
+
+        let is_weak = |res: Result<(), CsrError>, alg: &str| match res {
+            Err(CsrError::WeakPublicKey { algorithm, .. }) => algorithm == alg,
+            _ => false,
+        };
+
+        // RSA
+        let key = PKey::from_rsa(Rsa::generate(1024).unwrap()).unwrap();
+        let csr = generate_csr_for_key(san, &key, MessageDigest::sha256()).unwrap();
+        assert_that!(is_weak(CsrValidator::validate_public_key(&csr), "RSA")).is_true();
+
+        // weak keys never make it to signing
+        let ca = CA::new_test_ca().unwrap();
+        assert_that!(ca
+            .generate_and_sign_cert(csr, SystemTime::UNIX_EPOCH, SystemTime::now())
+            .is_err())
+        .is_true();
+
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let csr = generate_csr_for_key(san, &key, MessageDigest::sha256()).unwrap();
+        assert_that!(CsrValidator::validate_public_key(&csr)).is_ok();
+
+        // EC on a named curve
+        let named = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&named).unwrap()).unwrap();
+        let csr = generate_csr_for_key(san, &key, MessageDigest::sha256()).unwrap();
+        assert_that!(CsrValidator::validate_public_key(&csr)).is_ok();
+
+        // EC on a custom curve: P-256's parameters with a different generator, so it can't be
+        // mistaken for the named curve when the CSR is decoded.
+        let mut ctx = BigNumContext::new().unwrap();
+        let (mut p, mut a, mut b) = (
+            BigNum::new().unwrap(),
+            BigNum::new().unwrap(),
+            BigNum::new().unwrap(),
+        );
+        named
+            .components_gfp(&mut p, &mut a, &mut b, &mut ctx)
+            .unwrap();
+        let mut order = BigNum::new().unwrap();
+        named.order(&mut order, &mut ctx).unwrap();
+        let mut cofactor = BigNum::new().unwrap();
+        named.cofactor(&mut cofactor, &mut ctx).unwrap();
+
+        let mut g2 = EcPoint::new(&named).unwrap();
+        g2.mul_generator(&named, &BigNum::from_u32(2).unwrap(), &ctx)
+            .unwrap();
+        let (mut x, mut y) = (BigNum::new().unwrap(), BigNum::new().unwrap());
+        g2.affine_coordinates_gfp(&named, &mut x, &mut y, &mut ctx)
+            .unwrap();
+
+        let mut custom = EcGroup::from_components(p, a, b, &mut ctx).unwrap();
+        let mut generator = EcPoint::new(&custom).unwrap();
+        generator
+            .set_affine_coordinates_gfp(&custom, &x, &y, &mut ctx)
+            .unwrap();
+        custom.set_generator(generator, order, cofactor).unwrap();
+
+        let key = PKey::from_ec_key(EcKey::generate(&custom).unwrap()).unwrap();
+        let csr = generate_csr_for_key(san, &key, MessageDigest::sha256()).unwrap();
+        assert_that!(is_weak(CsrValidator::validate_public_key(&csr), "EC")).is_true();
+
+        // Ed25519
+        let key = PKey::generate_ed25519().unwrap();
+        let csr = generate_csr_for_key(san, &key, MessageDigest::null()).unwrap();
+        assert_that!(CsrValidator::validate_public_key(&csr)).is_ok();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sequential_serials() {
+        use super::CA;
+        use crate::test::PGTest;
+        use openssl::{bn::BigNum, hash::MessageDigest};
+        use spectral::prelude::*;
+        use std::time::SystemTime;
+
+        let pg = PGTest::new("test_sequential_serials").await.unwrap();
+        let ca = CA::new_test_ca().unwrap();
+        let now = SystemTime::now();
+
+        let mut serials = Vec::new();
+        for _ in 0..2 {
+            let serial = pg.db().next_serial().await.unwrap();
+            let cert = ca
+                .generate_and_sign_cert_with_serial(
+                    generate_csr().unwrap(),
+                    SystemTime::UNIX_EPOCH,
+                    now,
+                    serial,
+                )
+                .unwrap();
+
+            // the sequence is kept in the low 64 bits, below 64 random ones.
+            let bytes = cert.serial_number().to_bn().unwrap().to_vec();
+            assert_that!(bytes.len()).is_greater_than(8);
+            assert_that!(bytes[bytes.len() - 8..].to_vec())
+                .is_equal_to(serial.to_be_bytes().to_vec());
+            serials.push(serial);
+        }
+
+        assert_that!(serials[1]).is_greater_than(serials[0]);
+        // above the random serials issued before the sequence existed.
+        assert_that!(serials[0]).is_greater_than(u32::MAX as u64);
+
+        // the same sequence value does not make for the same serial number.
+        let signed = (0..2)
+            .map(|_| {
+                ca.generate_and_sign_cert_with_serial(
+                    generate_csr().unwrap(),
+                    SystemTime::UNIX_EPOCH,
+                    now,
+                    serials[0],
+                )
+                .unwrap()
+                .serial_number()
+                .to_bn()
+                .unwrap()
+            })
+            .collect::<Vec<BigNum>>();
+        assert_that!(signed[0]).is_not_equal_to(signed[1].clone());
+
+        assert_that!(ca.ca_fingerprint_sha256().unwrap().to_vec()).is_equal_to(
+            ca.clone()
+                .certificate()
+                .digest(MessageDigest::sha256())
+                .unwrap()
+                .to_vec(),
+        );
+    }
+
+    #[test]
+    fn test_verify_chain() {
+        use super::{SigningAlgorithm, CA};
+        use crate::errors::ca::ChainError;
+        use spectral::prelude::*;
+        use std::time::{Duration, SystemTime};
+
+        let now = SystemTime::now();
+        let root = CA::new_test_root_ca(SigningAlgorithm::EcdsaP256).unwrap();
+        let intermediate = CA::new_intermediate(
+            &root,
+            "Intermediate Signing Certificate",
+            SigningAlgorithm::EcdsaP256,
+            Duration::from_secs(24 * 60 * 60),
+        )
+        .unwrap();
+
+        let cert = root
+            .generate_and_sign_cert(generate_csr().unwrap(), now, now)
+            .unwrap();
+        assert_that!(CA::verify_chain(&cert, &root.chain())).is_ok();
+
+        let cert = intermediate
+            .generate_and_sign_cert(generate_csr().unwrap(), now, now)
+            .unwrap();
+        assert_that!(intermediate.chain().len()).is_equal_to(2);
+        assert_that!(CA::verify_chain(&cert, &intermediate.chain())).is_ok();
+
+        // a chain of some other CA, or missing the issuer of the certificate, is caught.
+        let other = CA::new_test_ca_with_algorithm(SigningAlgorithm::EcdsaP256).unwrap();
+        assert_that!(matches!(
+            CA::verify_chain(&cert, &other.chain()),
+            Err(ChainError::Invalid(_))
+        ))
+        .is_true();
+        assert_that!(matches!(
+            CA::verify_chain(&cert, &root.chain()),
+            Err(ChainError::Invalid(_))
+        ))
+        .is_true();
+        assert_that!(CA::verify_chain(&cert, &[])).is_equal_to(Err(ChainError::Empty));
+    }
+
+    #[test]
+    fn test_extension_template() {
+        use super::CA;
+        use spectral::prelude::*;
+        use std::time::SystemTime;
+        use x509_parser::prelude::*;
+
+        let now = SystemTime::now();
+        let ca = CA::new_test_ca().unwrap();
+
+        let mut serials = std::collections::HashSet::new();
+
+        for _ in 0..10 {
+            // clones share the template
+            let signed = ca
+                .clone()
+                .generate_and_sign_cert(generate_csr().unwrap(), SystemTime::UNIX_EPOCH, now)
+                .unwrap();
+
+            let der = signed.to_der().unwrap();
+            let (_, cert) = X509Certificate::from_der(&der).unwrap();
+
+            let (critical, ku) = cert.tbs_certificate.key_usage().unwrap();
+            assert_that!(critical).is_true();
+            assert_that!(ku.digital_signature()).is_true();
+            assert_that!(ku.key_encipherment()).is_true();
+            assert_that!(ku.key_cert_sign()).is_false();
+
+            let (_, eku) = cert.tbs_certificate.extended_key_usage().unwrap();
+            assert_that!(eku.server_auth).is_true();
+            assert_that!(eku.client_auth).is_false();
+
+            // per-issuance values are still set for each certificate
+            assert_that!(cert.tbs_certificate.subject_alternative_name()).is_some();
+            assert_that!(serials.insert(cert.tbs_certificate.raw_serial().to_vec())).is_true();
+        }
+
+        assert_that!(ca.template.get()).is_some();
+
+        // changing the policy drops the cached template
+        let ca = ca.with_client_auth(true);
+        assert_that!(ca.template.get()).is_none();
+    }
+
+    #[test]
+    fn test_ocsp_url() {
+        use super::CA;
+        use spectral::prelude::*;
+        use std::time::SystemTime;
+
+        let now = SystemTime::now();
+        let ca = CA::new_test_ca().unwrap();
+
+        let signed = ca
+            .clone()
+            .with_ocsp_url("http://ocsp.example.com/")
+            .generate_and_sign_cert(generate_csr().unwrap(), SystemTime::UNIX_EPOCH, now)
+            .unwrap();
+
+        let responders = signed
+            .ocsp_responders()
+            .unwrap()
+            .iter()
+            .map(|r| r.to_string())
+            .collect::<Vec<String>>();
+        assert_that!(responders).is_equal_to(vec!["http://ocsp.example.com/".to_string()]);
+
+        let signed = ca
+            .generate_and_sign_cert(generate_csr().unwrap(), SystemTime::UNIX_EPOCH, now)
+            .unwrap();
+
+        // no responder means no AIA extension at all, not an empty one.
+        let text = String::from_utf8(signed.to_text().unwrap()).unwrap();
+        assert_that!(text.contains("Authority Information Access")).is_false();
+        assert_that!(signed.ocsp_responders()).is_err();
+    }
+
+    #[test]
+    fn test_ocsp_response() {
+        use super::{parse_ocsp_request, CertificateStatus, CA};
+        use openssl::{
+            hash::MessageDigest,
+            ocsp::{
+                OcspCertId, OcspCertStatus, OcspFlag, OcspRequest, OcspResponse, OcspResponseStatus,
+            },
+            stack::Stack,
+            x509::store::X509StoreBuilder,
+        };
+        use spectral::prelude::*;
+        use std::time::{Duration, SystemTime};
+
+        let now = SystemTime::now();
+        let ca = CA::new_test_ca().unwrap();
+        let other = CA::new_test_ca().unwrap();
+
+        let signed = ca
+            .generate_and_sign_cert(generate_csr().unwrap(), SystemTime::UNIX_EPOCH, now)
+            .unwrap();
+
+        for digest in vec![MessageDigest::sha1(), MessageDigest::sha256()] {
+            let id = OcspCertId::from_cert(digest, &signed, &ca.clone().certificate()).unwrap();
+            let mut req = OcspRequest::new().unwrap();
+            req.add_id(id).unwrap();
+
+            let requests = parse_ocsp_request(&req.to_der().unwrap()).unwrap();
+            assert_that!(requests.len()).is_equal_to(1);
+            assert_that!(requests[0].serial)
+                .is_equal_to(signed.serial_number().to_bn().unwrap().to_vec());
+            assert_that!(ca.is_issuer_of(&requests[0]).unwrap()).is_true();
+            assert_that!(other.is_issuer_of(&requests[0]).unwrap()).is_false();
+
+            let revoked_at = chrono::Utc::now() - chrono::Duration::hours(1);
+            let der = ca
+                .sign_ocsp_response(
+                    &[(
+                        requests[0].clone(),
+                        CertificateStatus::Revoked {
+                            revoked_at,
+                            reason: 1,
+                        },
+                    )],
+                    Duration::from_secs(3600),
+                )
+                .unwrap();
+
+            let resp = OcspResponse::from_der(&der).unwrap();
+            assert_that!(resp.status()).is_equal_to(OcspResponseStatus::SUCCESSFUL);
+
+            let basic = resp.basic().unwrap();
+            let mut store = X509StoreBuilder::new().unwrap();
+            store.add_cert(ca.clone().certificate()).unwrap();
+            let store = store.build();
+
+            let mut certs = Stack::new().unwrap();
+            certs.push(ca.clone().certificate()).unwrap();
+            assert_that!(basic.verify(&certs, &store, OcspFlag::empty())).is_ok();
+
+            let id = OcspCertId::from_cert(digest, &signed, &ca.clone().certificate()).unwrap();
+            let status = basic.find_status(&id).unwrap();
+            assert_that!(status.status).is_equal_to(OcspCertStatus::REVOKED);
+            assert_that!(status.check_validity(300, None)).is_ok();
+        }
+
+        assert_that!(parse_ocsp_request(&[0x30, 0x03, 0x30, 0x01])).is_err();
+        assert_that!(parse_ocsp_request(b"garbage")).is_err();
+    }
+
+    #[test]
+    fn test_crl_url() {
+        use super::CA;
+        use spectral::prelude::*;
+        use std::time::SystemTime;
+
+        let signed = CA::new_test_ca()
+            .unwrap()
+            .with_crl_url("http://crl.example.com/crl")
+            .generate_and_sign_cert(
+                generate_csr().unwrap(),
+                SystemTime::UNIX_EPOCH,
+                SystemTime::now(),
+            )
+            .unwrap();
+
+        let text = String::from_utf8(signed.to_text().unwrap()).unwrap();
+        assert_that!(text.contains("X509v3 CRL Distribution Points")).is_true();
+        assert_that!(text.contains("URI:http://crl.example.com/crl")).is_true();
+    }
+
+    #[test]
+    fn test_generate_crl() {
+        use super::{RevokedCertificate, CA};
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        let ca = CA::new_test_ca().unwrap();
+
+        let crl = ca.generate_crl(&[], Duration::from_secs(3600)).unwrap();
+        assert_that!(crl.get_revoked().is_none()).is_true();
+
+        let revoked = vec![
+            RevokedCertificate {
+                serial: vec![0x80, 0x01],
+                reason: 1,
+                revoked_at: chrono::Utc::now(),
+                issuer_key_hash: None,
+            },
+            RevokedCertificate {
+                serial: vec![0x01],
+                reason: 0,
+                revoked_at: chrono::Utc::now(),
+                issuer_key_hash: None,
+            },
+        ];
+
+        let crl = ca
+            .generate_crl(&revoked, Duration::from_secs(3600))
+            .unwrap();
+
+        let pubkey = ca.clone().certificate().public_key().unwrap();
+        assert_that!(crl.verify(&pubkey).unwrap()).is_true();
+        assert_that!(crl.issuer_name().to_der().unwrap())
+            .is_equal_to(ca.clone().certificate().subject_name().to_der().unwrap());
+        assert_that!(crl.next_update().unwrap() > crl.last_update()).is_true();
+
+        let serials = crl
+            .get_revoked()
+            .unwrap()
+            .iter()
+            .map(|r| r.serial_number().to_bn().unwrap().to_vec())
+            .collect::<Vec<Vec<u8>>>();
+        assert_that!(serials).is_equal_to(vec![vec![0x80, 0x01], vec![0x01]]);
+    }
+
+    #[test]
+    fn test_signing_algorithms() {
+        use super::{SigningAlgorithm, CA};
+        use openssl::{nid::Nid, pkey::Id};
+        use spectral::prelude::*;
+        use std::time::{Duration, SystemTime};
+
+        for (algorithm, id) in vec![
+            (SigningAlgorithm::Rsa2048, Id::RSA),
+            (SigningAlgorithm::EcdsaP256, Id::EC),
+            (SigningAlgorithm::EcdsaP384, Id::EC),
+            (SigningAlgorithm::Ed25519, Id::ED25519),
+        ] {
+            let ca = CA::new_test_ca_with_algorithm(algorithm).unwrap();
+            let pubkey = ca.clone().certificate().public_key().unwrap();
+            assert_that!(pubkey.id()).is_equal_to(id);
+            assert_that!(ca.clone().certificate().verify(&pubkey).unwrap()).is_true();
+
+            let signed = ca
+                .generate_and_sign_cert(
+                    generate_csr().unwrap(),
+                    SystemTime::UNIX_EPOCH,
+                    SystemTime::now(),
+                )
+                .unwrap();
+            assert_that!(signed.verify(&pubkey).unwrap()).is_true();
+
+            if algorithm == SigningAlgorithm::EcdsaP384 {
+                assert_that!(signed.signature_algorithm().object().nid())
+                    .is_equal_to(Nid::ECDSA_WITH_SHA384);
+            }
+
+            let crl = ca.generate_crl(&[], Duration::from_secs(3600)).unwrap();
+            assert_that!(crl.verify(&pubkey).unwrap()).is_true();
+        }
+    }
+
+    #[test]
+    fn test_generate_root() {
+        use super::{CaConfig, KeyUsage, SigningAlgorithm, CA};
+        use openssl::{
+            stack::Stack,
+            x509::{store::X509StoreBuilder, X509Name, X509StoreContext},
+        };
+        use spectral::prelude::*;
+        use std::time::{Duration, SystemTime};
+        use x509_parser::prelude::*;
+
+        let mut namebuilder = X509Name::builder().unwrap();
+        namebuilder.append_entry_by_text("CN", "Root").unwrap();
+        let subject = namebuilder.build();
+
+        let ca = CA::generate_root(CaConfig {
+            algorithm: SigningAlgorithm::EcdsaP256,
+            ..CaConfig::new(subject)
+        })
+        .unwrap();
+        let cacert = ca.clone().certificate();
+
+        let der = cacert.to_der().unwrap();
+        let (_, cert) = X509Certificate::from_der(&der).unwrap();
+
+        let (critical, bc) = cert.tbs_certificate.basic_constraints().unwrap();
+        assert_that!(critical).is_true();
+        assert_that!(bc.ca).is_true();
+        assert_that!(bc.path_len_constraint).is_equal_to(Some(0));
+
+        let (critical, ku) = cert.tbs_certificate.key_usage().unwrap();
+        assert_that!(critical).is_true();
+        assert_that!(ku.key_cert_sign()).is_true();
+        assert_that!(ku.crl_sign()).is_true();
+        assert_that!(ku.digital_signature()).is_false();
+
+        assert_that!(cert.tbs_certificate.issuer.to_string())
+            .is_equal_to(cert.tbs_certificate.subject.to_string());
+
+        // openssl accepts it as a trust anchor for the certificates it issues.
+        let now = SystemTime::now();
+        let signed = ca
+            .generate_and_sign_cert(
+                generate_csr().unwrap(),
+                now,
+                now + Duration::from_secs(24 * 60 * 60),
+            )
+            .unwrap();
+
+        let mut store = X509StoreBuilder::new().unwrap();
+        store.add_cert(cacert).unwrap();
+        let store = store.build();
+
+        let mut ctx = X509StoreContext::new().unwrap();
+        let verified = ctx
+            .init(&store, &signed, &Stack::new().unwrap(), |c| c.verify_cert())
+            .unwrap();
+        assert_that!(verified).is_true();
+
+        // without a path length constraint, basicConstraints carries none.
+        let mut namebuilder = X509Name::builder().unwrap();
+        namebuilder
+            .append_entry_by_text("CN", "Unconstrained")
+            .unwrap();
+        let ca = CA::generate_root(CaConfig {
+            key_usage: vec![
+                KeyUsage::KeyCertSign,
+                KeyUsage::CRLSign,
+                KeyUsage::DigitalSignature,
+            ],
+            path_len_constraint: None,
+            algorithm: SigningAlgorithm::Ed25519,
+            ..CaConfig::new(namebuilder.build())
+        })
+        .unwrap();
+
+        let der = ca.certificate().to_der().unwrap();
+        let (_, cert) = X509Certificate::from_der(&der).unwrap();
+        let (_, bc) = cert.tbs_certificate.basic_constraints().unwrap();
+        assert_that!(bc.ca).is_true();
+        assert_that!(bc.path_len_constraint).is_none();
+        let (_, ku) = cert.tbs_certificate.key_usage().unwrap();
+        assert_that!(ku.digital_signature()).is_true();
+
+        // the test CAs are roots made the same way.
+        let der = CA::new_test_ca().unwrap().certificate().to_der().unwrap();
+        let (_, cert) = X509Certificate::from_der(&der).unwrap();
+        let (_, ku) = cert.tbs_certificate.key_usage().unwrap();
+        assert_that!(ku.key_cert_sign()).is_true();
+        assert_that!(ku.crl_sign()).is_true();
+    }
+
+    #[test]
+    fn test_intermediate_ca() {
+        use super::{SigningAlgorithm, CA};
+        use openssl::{
+            nid::Nid,
+            stack::Stack,
+            x509::{store::X509StoreBuilder, X509StoreContext},
+        };
+        use spectral::prelude::*;
+        use std::time::{Duration, SystemTime};
+
+        let root = CA::new_test_root_ca(SigningAlgorithm::EcdsaP256).unwrap();
+        let intermediate = CA::new_intermediate(
+            &root,
+            "Intermediate Signing Certificate",
+            SigningAlgorithm::EcdsaP384,
+            Duration::from_secs(30 * 24 * 60 * 60),
+        )
+        .unwrap();
+
+        let rootcert = root.clone().certificate();
+        let intcert = intermediate.clone().certificate();
+
+        assert_that!(root.chain().len()).is_equal_to(1);
+        let chain = intermediate.chain();
+        assert_that!(chain.len()).is_equal_to(2);
+        assert_that!(chain[0].to_der().unwrap()).is_equal_to(intcert.to_der().unwrap());
+        assert_that!(chain[1].to_der().unwrap()).is_equal_to(rootcert.to_der().unwrap());
+
+        let pem = String::from_utf8(intermediate.chain_pem().unwrap()).unwrap();
+        assert_that!(pem.matches("BEGIN CERTIFICATE").count()).is_equal_to(2);
+
+        assert_that!(intcert.verify(&rootcert.public_key().unwrap()).unwrap()).is_true();
+        assert_that!(intcert.issuer_name().to_der().unwrap())
+            .is_equal_to(rootcert.subject_name().to_der().unwrap());
+        let cn = intcert
+            .subject_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .next()
+            .unwrap()
+            .data()
+            .as_utf8()
+            .unwrap()
+            .to_string();
+        assert_that!(cn).is_equal_to("Intermediate Signing Certificate".to_string());
+
+        let now = SystemTime::now();
+        let signed = intermediate
+            .generate_and_sign_cert(
+                generate_csr().unwrap(),
+                now,
+                now + Duration::from_secs(24 * 60 * 60),
+            )
+            .unwrap();
+        assert_that!(signed.issuer_name().to_der().unwrap())
+            .is_equal_to(intcert.subject_name().to_der().unwrap());
+
+        // the leaf verifies up to the root through the intermediate.
+        let mut store = X509StoreBuilder::new().unwrap();
+        store.add_cert(rootcert).unwrap();
+        let store = store.build();
+
+        let mut untrusted = Stack::new().unwrap();
+        untrusted.push(intcert).unwrap();
+
+        let mut ctx = X509StoreContext::new().unwrap();
+        let verified = ctx
+            .init(&store, &signed, &untrusted, |c| c.verify_cert())
+            .unwrap();
+        assert_that!(verified).is_true();
+    }
+
+    #[test]
+    fn test_cert_profile() {
+        use super::{st_to_asn1, CertProfile, CA};
+        use crate::errors::ca::CsrError;
+        use spectral::prelude::*;
+        use std::time::{Duration, SystemTime};
+
+        let hour = Duration::from_secs(3600);
+        let now = SystemTime::now();
+
+        let profile = CertProfile {
+            not_before_offset: hour,
+            not_after_offset: 24 * hour,
+            max_validity: Some(48 * hour),
+        };
+
+        assert_that!(profile.validity(now, None)).is_ok_containing((now - hour, now + 24 * hour));
+        assert_that!(profile.validity(now, Some(now + 47 * hour)))
+            .is_ok_containing((now - hour, now + 47 * hour));
+        // backdating counts towards the maximum.
+        assert_that!(profile.validity(now, Some(now + 48 * hour))).is_err_containing(
+            CsrError::ValidityTooLong {
+                requested: 49 * 3600,
+                max: 48 * 3600,
+            },
+        );
+
+        let ca = CA::new_test_ca().unwrap().with_cert_profile(profile);
+
+        let signed = ca
+            .generate_and_sign_cert(generate_csr().unwrap(), now, now + 24 * hour)
+            .unwrap();
+        assert_that!(signed.not_before()).is_equal_to(&*st_to_asn1(now - hour).unwrap());
+        assert_that!(signed.not_after()).is_equal_to(&*st_to_asn1(now + 24 * hour).unwrap());
+
+        assert_that!(ca.generate_and_sign_cert(
+            generate_csr().unwrap(),
+            now,
+            now + 365 * 24 * hour
+        ))
+        .is_err();
+    }
+
+    #[test]
+    fn test_certificate_policies() {
+        use super::{CertificatePolicy, CA};
+        use crate::errors::ca::CsrError;
+        use spectral::prelude::*;
+        use std::time::SystemTime;
+        use x509_parser::prelude::*;
+
+        let now = SystemTime::now();
+        let ca = CA::new_test_ca()
+            .unwrap()
+            .with_certificate_policy(CertificatePolicy::domain_validated())
+            .with_certificate_policy(CertificatePolicy {
+                oid: "1.3.6.1.4.1.44947.1.1.1".to_string(),
+                cps_uri: Some("http://cps.example.com/".to_string()),
+                user_notice: Some("for testing only".to_string()),
+            });
+
+        let signed = ca
+            .generate_and_sign_cert(generate_csr().unwrap(), SystemTime::UNIX_EPOCH, now)
+            .unwrap();
+
+        let der = signed.to_der().unwrap();
+        let (_, cert) = X509Certificate::from_der(&der).unwrap();
+
+        let policies = cert
+            .tbs_certificate
+            .extensions()
+            .iter()
+            .find_map(|ext| match ext.parsed_extension() {
+                ParsedExtension::CertificatePolicies(policies) => Some(policies),
+                _ => None,
+            })
+            .unwrap();
+
+        assert_that!(policies.len()).is_equal_to(2);
+
+        assert_that!(policies[0].policy_id.to_id_string())
+            .is_equal_to("2.23.140.1.2.1".to_string());
+        assert_that!(policies[0].policy_qualifiers).is_none();
+
+        assert_that!(policies[1].policy_id.to_id_string())
+            .is_equal_to("1.3.6.1.4.1.44947.1.1.1".to_string());
+        let qualifiers = policies[1].policy_qualifiers.as_ref().unwrap();
+        assert_that!(qualifiers.len()).is_equal_to(2);
+        assert_that!(qualifiers[0].policy_qualifier_id.to_id_string())
+            .is_equal_to("1.3.6.1.5.5.7.2.1".to_string());
+        assert_that!(qualifiers[0]
+            .qualifier
+            .ends_with("http://cps.example.com/".as_bytes()))
+        .is_true();
+        assert_that!(qualifiers[1].policy_qualifier_id.to_id_string())
+            .is_equal_to("1.3.6.1.5.5.7.2.2".to_string());
+        assert_that!(qualifiers[1]
+            .qualifier
+            .ends_with("for testing only".as_bytes()))
+        .is_true();
+
+        // openssl agrees with our encoding
+        let text = String::from_utf8(signed.to_text().unwrap()).unwrap();
+        assert_that!(text.contains("Policy: 2.23.140.1.2.1")).is_true();
+        assert_that!(text.contains("CPS: http://cps.example.com/")).is_true();
+
+        // invalid OIDs fail issuance rather than producing a broken certificate
+        let res = CA::new_test_ca()
+            .unwrap()
+            .with_certificate_policy(CertificatePolicy {
+                oid: "not an oid".to_string(),
+                cps_uri: None,
+                user_notice: None,
+            })
+            .generate_and_sign_cert(generate_csr().unwrap(), SystemTime::UNIX_EPOCH, now);
+        assert_that!(res.err()).is_equal_to(Some(CsrError::InvalidCertificatePolicyOid(
+            "not an oid".to_string(),
+        )));
+    }
+
+    fn generate_csr_with_cn(cn: &str) -> X509Req {
+        use openssl::{
+            asn1::Asn1Type, hash::MessageDigest, nid::Nid, pkey::PKey, rsa::Rsa, x509::X509Name,
+        };
+
+        // append_entry_by_text enforces the length limit, so the CN is set with an explicit type.
+        let mut namebuilder = X509Name::builder().unwrap();
+        namebuilder
+            .append_entry_by_text("O", "Example Organization")
+            .unwrap();
+        namebuilder
+            .append_entry_by_nid_with_type(Nid::COMMONNAME, cn, Asn1Type::UTF8STRING)
+            .unwrap();
+
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut req = X509Req::builder().unwrap();
+        req.set_subject_name(&namebuilder.build()).unwrap();
+        req.set_pubkey(&key).unwrap();
+        req.sign(&key, MessageDigest::sha256()).unwrap();
+        req.build()
+    }
+
+    #[test]
+    fn test_csr_signature_mismatch() {
+        use super::CA;
+        use crate::errors::ca::CsrError;
+        use openssl::{hash::MessageDigest, pkey::PKey, rsa::Rsa, x509::X509Req};
+        use spectral::prelude::*;
+        use std::time::SystemTime;
+
+        // the CSR carries one key, but is signed by another.
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let other = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+        let csr = generate_csr_with_cn("example.com");
+        let mut req = X509Req::builder().unwrap();
+        req.set_subject_name(csr.subject_name()).unwrap();
+        req.set_pubkey(&key).unwrap();
+        req.sign(&other, MessageDigest::sha256()).unwrap();
+
+        let ca = CA::new_test_ca().unwrap();
+        let res = ca.generate_and_sign_cert(req.build(), SystemTime::UNIX_EPOCH, SystemTime::now());
+        assert_that!(res.err()).is_equal_to(Some(CsrError::InvalidSignature));
+    }
+
+    #[test]
+    fn test_cn_truncation() {
+        use super::CA;
+        use openssl::nid::Nid;
+        use spectral::prelude::*;
+        use std::time::SystemTime;
+
+        let cn = "a".repeat(100);
+        let ca = CA::new_test_ca().unwrap().with_cn_truncation(true);
+
+        let signed = ca
+            .generate_and_sign_cert(
+                generate_csr_with_cn(&cn),
+                SystemTime::UNIX_EPOCH,
+                SystemTime::now(),
+            )
+            .unwrap();
+
+        let subject = signed.subject_name();
+        let cns = subject
+            .entries_by_nid(Nid::COMMONNAME)
+            .map(|e| e.data().as_utf8().unwrap().to_string())
+            .collect::<Vec<String>>();
+        assert_that!(cns).is_equal_to(vec!["a".repeat(64)]);
+
+        // other subject entries are carried over untouched
+        let orgs = subject
+            .entries_by_nid(Nid::ORGANIZATIONNAME)
+            .map(|e| e.data().as_utf8().unwrap().to_string())
+            .collect::<Vec<String>>();
+        assert_that!(orgs).is_equal_to(vec!["Example Organization".to_string()]);
+    }
+
+    #[test]
+    fn test_subject_template() {
+        use super::{SubjectTemplate, CA};
+        use openssl::nid::Nid;
+        use spectral::prelude::*;
+        use std::time::SystemTime;
+
+        let entries = |ca: &CA, nid: Nid| {
+            ca.generate_and_sign_cert(
+                generate_csr_with_cn("example.com"),
+                SystemTime::UNIX_EPOCH,
+                SystemTime::now(),
+            )
+            .unwrap()
+            .subject_name()
+            .entries_by_nid(nid)
+            .map(|e| e.data().as_utf8().unwrap().to_string())
+            .collect::<Vec<String>>()
+        };
+
+        let ca = CA::new_test_ca()
+            .unwrap()
+            .with_subject_template(SubjectTemplate {
+                organization: Some("Test Corp".to_string()),
+                country: Some("US".to_string()),
+                ..Default::default()
+            });
+
+        // the CSR asks for "Example Organization", which the template replaces.
+        assert_that!(entries(&ca, Nid::ORGANIZATIONNAME))
+            .is_equal_to(vec!["Test Corp".to_string()]);
+        assert_that!(entries(&ca, Nid::COUNTRYNAME)).is_equal_to(vec!["US".to_string()]);
+        assert_that!(entries(&ca, Nid::COMMONNAME)).is_equal_to(vec!["example.com".to_string()]);
+
+        // fields the template leaves unset are dropped from the CSR, unless allowed.
+        let ca = CA::new_test_ca()
+            .unwrap()
+            .with_subject_template(SubjectTemplate {
+                country: Some("US".to_string()),
+                ..Default::default()
+            });
+        assert_that!(entries(&ca, Nid::ORGANIZATIONNAME)).is_empty();
+
+        let ca = CA::new_test_ca()
+            .unwrap()
+            .with_subject_template(SubjectTemplate {
+                country: Some("US".to_string()),
+                allow_csr_fields: true,
+                ..Default::default()
+            });
+        assert_that!(entries(&ca, Nid::ORGANIZATIONNAME))
+            .is_equal_to(vec!["Example Organization".to_string()]);
+    }
+
+    #[test]
+    fn test_cn_too_long() {
+        use super::CA;
+        use crate::errors::ca::CsrError;
+        use spectral::prelude::*;
+        use std::time::SystemTime;
+
+        let ca = CA::new_test_ca().unwrap();
+
+        let res = ca.generate_and_sign_cert(
+            generate_csr_with_cn(&"a".repeat(100)),
+            SystemTime::UNIX_EPOCH,
+            SystemTime::now(),
+        );
+        assert_that!(res.err()).is_equal_to(Some(CsrError::CnTooLong));
+
+        // exactly 64 characters is fine
+        assert_that!(ca
+            .generate_and_sign_cert(
+                generate_csr_with_cn(&"a".repeat(64)),
+                SystemTime::UNIX_EPOCH,
+                SystemTime::now(),
+            )
+            .is_ok())
+        .is_true();
+    }
+
+    #[test]
+    fn test_signing_duration() {
+        use super::{SigningAlgorithm, CA};
+        use crate::acme::metrics::SIGNING_DURATION_HISTOGRAM;
+        use openssl::{ec::EcGroup, ec::EcKey, hash::MessageDigest, nid::Nid, pkey::PKey};
+        use spectral::prelude::*;
+        use std::time::{Duration, Instant, SystemTime};
+
+        let ca = CA::new_test_ca_with_algorithm(SigningAlgorithm::EcdsaP256).unwrap();
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+
+        // other tests sign concurrently, so the count only has a lower bound.
+        let before = SIGNING_DURATION_HISTOGRAM.get_sample_count();
+
+        let mut durations = Vec::new();
+        for _ in 0..100 {
+            let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+            let csr = generate_csr_for_key(
+                &[("subjectAltName", "DNS:example.org")],
+                &key,
+                MessageDigest::sha256(),
+            )
+            .unwrap();
+
+            let start = Instant::now();
+            ca.generate_and_sign_cert(csr, SystemTime::UNIX_EPOCH, SystemTime::now())
+                .unwrap();
+            durations.push(start.elapsed());
+        }
+
+        durations.sort();
+        let p99 = durations[98];
+        assert_that!(p99).is_less_than(Duration::from_millis(100));
+
+        assert_that!(SIGNING_DURATION_HISTOGRAM.get_sample_count())
+            .is_greater_than_or_equal_to(before + 100);
+        assert_that!(SIGNING_DURATION_HISTOGRAM.get_sample_sum()).is_greater_than(0.0);
+    }
+
+    #[test]
+    fn test_public_key_pem() {
+        use super::CA;
+        use openssl::pkey::{Id, PKey};
+        use spectral::prelude::*;
+
+        let ca = CA::new_test_ca().unwrap();
+        let pem = ca.public_key_pem().unwrap();
+
+        let key = PKey::public_key_from_pem(&pem).unwrap();
+        assert_that!(key.id()).is_equal_to(Id::RSA);
+        assert_that!(key.bits()).is_equal_to(4096);
+        assert_that!(key.public_eq(&ca.private_key())).is_true();
+    }
+
+    #[test]
+    fn test_from_pem() {
+        use super::{SigningAlgorithm, CA};
+        use crate::errors::ca::CaLoadError;
+        use spectral::prelude::*;
+
+        let ca = CA::new_test_ca().unwrap();
+        let cert_pem = ca.clone().certificate().to_pem().unwrap();
+        let key_pem = ca.clone().private_key().private_key_to_pem_pkcs8().unwrap();
+
+        let loaded = CA::from_pem(&cert_pem, &key_pem).unwrap();
+        assert_that!(loaded.clone().certificate().to_der().unwrap())
+            .is_equal_to(ca.clone().certificate().to_der().unwrap());
+        assert_that!(loaded.private_key().public_eq(&ca.clone().private_key())).is_true();
+
+        // the traditional format of the key's algorithm loads too.
+        let rsa_pem = ca
+            .clone()
+            .private_key()
+            .rsa()
+            .unwrap()
+            .private_key_to_pem()
+            .unwrap();
+        assert_that!(CA::from_pem(&cert_pem, &rsa_pem)).is_ok();
+
+        let other = CA::new_test_ca_with_algorithm(SigningAlgorithm::EcdsaP256).unwrap();
+        let other_key = other.private_key().private_key_to_pem_pkcs8().unwrap();
+        assert_that!(CA::from_pem(&cert_pem, &other_key))
+            .is_err_containing(CaLoadError::KeyMismatch);
+
+        assert_that!(matches!(
+            CA::from_pem(b"garbage", &key_pem),
+            Err(CaLoadError::OpenSSL(_))
+        ))
+        .is_true();
+    }
+
+    #[test]
+    fn test_export_pkcs12() {
+        use super::{SigningAlgorithm, CA};
+        use crate::errors::ca::Pkcs12Error;
+        use openssl::{hash::MessageDigest, pkcs12::Pkcs12, pkey::PKey, rsa::Rsa};
+        use spectral::prelude::*;
+        use std::time::{Duration, SystemTime};
+
+        let root = CA::new_test_root_ca(SigningAlgorithm::EcdsaP256).unwrap();
+        let intermediate = CA::new_intermediate(
+            &root,
+            "Intermediate Signing Certificate",
+            SigningAlgorithm::EcdsaP256,
+            Duration::from_secs(30 * 24 * 60 * 60),
+        )
+        .unwrap();
+
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let now = SystemTime::now();
+        let signed = intermediate
+            .generate_and_sign_cert(
+                generate_csr_for_key(
+                    &[("subjectAltName", "DNS:example.org")],
+                    &key,
+                    MessageDigest::sha256(),
+                )
+                .unwrap(),
+                now,
+                now + Duration::from_secs(24 * 60 * 60),
+            )
+            .unwrap();
+
+        let der =
+            CA::export_pkcs12(&signed, &key, &intermediate.chain(), b"correct horse").unwrap();
+        let pkcs12 = Pkcs12::from_der(&der).unwrap();
+        assert_that!(pkcs12.parse("battery staple").is_err()).is_true();
+
+        let parsed = pkcs12.parse("correct horse").unwrap();
+        assert_that!(parsed.cert.subject_name().to_der().unwrap())
+            .is_equal_to(signed.subject_name().to_der().unwrap());
+        assert_that!(parsed.pkey.public_eq(&key)).is_true();
+        assert_that!(parsed.chain.unwrap().len()).is_equal_to(2);
+
+        // a passphrase which is not UTF-8 is refused rather than altered.
+        assert_that!(CA::export_pkcs12(
+            &signed,
+            &key,
+            &intermediate.chain(),
+            b"correct \xff horse"
+        ))
+        .is_equal_to(Err(Pkcs12Error::InvalidPassphrase));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_ca_collector() {
+        use super::{st_to_asn1, CACollector, RotationPolicy, CA};
+        use openssl::{pkey::PKey, rsa::Rsa};
+        use spectral::prelude::*;
+        use std::time::Duration;
+        use std::time::SystemTime;
+
+        let collector = CACollector::new(Duration::new(0, 500));
+
+        let mut inner = collector.clone();
+        let handle = tokio::spawn(async move {
+            // we only want one of these, instead of polling for new ones, in this test.
+            let ca = CA::new_test_ca_with_validity(Duration::from_secs(5)).unwrap();
+            inner
+                .spawn_collector(
+                    || -> Result<(CA, Vec<X509>), ErrorStack> { Ok((ca.clone(), vec![])) },
+                    RotationPolicy::default(),
+                )
+                .await
+        });
+
+        tokio::time::sleep(Duration::new(1, 0)).await;
+
+        let now = SystemTime::now();
+        let signed = collector
+            .clone()
+            .sign(generate_csr().unwrap(), SystemTime::UNIX_EPOCH, Some(now))
+            .await
+            .unwrap();
+
+        let result = signed.verify(&collector.ca().read().await.clone().unwrap().private_key());
+        assert_that!(result).is_ok();
+        assert_that!(result.unwrap()).is_true();
+
+        let badkey = Rsa::generate(4096).unwrap();
+        let result = signed.verify(PKey::from_rsa(badkey).unwrap().as_ref());
         assert_that!(result).is_ok();
         assert_that!(result.unwrap()).is_false();
 
@@ -350,6 +3251,266 @@ mod tests {
             .is_equal_to(&*st_to_asn1(SystemTime::UNIX_EPOCH).unwrap());
         assert_that!(signed.not_after()).is_equal_to(&*st_to_asn1(now).unwrap());
 
+        // the chain of the signing CA comes back with the certificate.
+        let (signed, chain) = collector
+            .clone()
+            .sign_with_serial(
+                generate_csr().unwrap(),
+                SystemTime::UNIX_EPOCH,
+                Some(now),
+                1,
+            )
+            .await
+            .unwrap();
+        assert_that!(chain.len()).is_greater_than(0);
+        assert_that!(CA::verify_chain(&signed, &chain)).is_ok();
+
+        handle.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_ca_collector_stats() {
+        use super::{CACollector, CollectorStats, RotationPolicy, SigningAlgorithm, CA};
+        use openssl::{hash::MessageDigest, pkey::PKey, rsa::Rsa, x509::X509Req};
+        use spectral::prelude::*;
+        use std::time::{Duration, SystemTime};
+
+        let collector = CACollector::new(Duration::from_secs(3600));
+        assert_that!(collector.stats()).is_equal_to(CollectorStats::default());
+
+        let ca = CA::new_test_ca_with_algorithm(SigningAlgorithm::EcdsaP256).unwrap();
+        let mut inner = collector.clone();
+        let handle = tokio::spawn(async move {
+            inner
+                .spawn_collector(
+                    || -> Result<(CA, Vec<X509>), ErrorStack> { Ok((ca.clone(), vec![])) },
+                    RotationPolicy::default(),
+                )
+                .await
+        });
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let now = SystemTime::now();
+        for _ in 0..2 {
+            collector
+                .clone()
+                .sign(generate_csr().unwrap(), now, None)
+                .await
+                .unwrap();
+        }
+
+        // a CSR signed by a key other than its own.
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let other = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let csr = generate_csr().unwrap();
+        let mut req = X509Req::builder().unwrap();
+        req.set_subject_name(csr.subject_name()).unwrap();
+        req.set_pubkey(&key).unwrap();
+        req.sign(&other, MessageDigest::sha256()).unwrap();
+        assert_that!(collector.clone().sign(req.build(), now, None).await).is_err();
+
+        let stats = collector.stats();
+        assert_that!(stats.certs_issued).is_equal_to(2);
+        assert_that!(stats.signing_errors).is_equal_to(1);
+        assert_that!(stats.ca_rotations).is_equal_to(0);
+        assert_that!(stats.last_rotation).is_none();
+
+        handle.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_ca_collector_expiry() {
+        use super::{CACollector, RotationPolicy, CA};
+        use spectral::prelude::*;
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+        use std::time::Duration;
+
+        // the poll interval is far longer than the test; only expiry can cause a reload.
+        let collector = CACollector::new(Duration::from_secs(3600));
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let ca = CA::new_test_ca_with_validity(Duration::from_secs(3)).unwrap();
+        assert_that!(ca.expires_in().unwrap()).is_less_than_or_equal_to(Duration::from_secs(3));
+
+        let mut inner = collector.clone();
+        let inner_calls = calls.clone();
+        let handle = tokio::spawn(async move {
+            inner
+                .spawn_collector(
+                    || -> Result<(CA, Vec<X509>), ErrorStack> {
+                        inner_calls.fetch_add(1, Ordering::SeqCst);
+                        Ok((ca.clone(), vec![]))
+                    },
+                    RotationPolicy::default(),
+                )
+                .await
+        });
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_that!(calls.load(Ordering::SeqCst)).is_equal_to(1);
+
+        tokio::time::sleep(Duration::from_secs(4)).await;
+        assert_that!(calls.load(Ordering::SeqCst)).is_greater_than_or_equal_to(2);
+
+        let expired = collector.ca().read().await.clone().unwrap();
+        assert_that!(expired.expires_in().unwrap()).is_equal_to(Duration::ZERO);
+
+        handle.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_ca_collector_rotations() {
+        use super::{CACollector, RotationPolicy, SigningAlgorithm, CA};
+        use openssl::hash::MessageDigest;
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        let collector = CACollector::new(Duration::from_millis(250));
+
+        // a fresh CA on every poll.
+        let mut inner = collector.clone();
+        let handle = tokio::spawn(async move {
+            inner
+                .spawn_collector(
+                    || {
+                        CA::new_test_ca_with_algorithm(SigningAlgorithm::EcdsaP256)
+                            .map(|ca| (ca, vec![]))
+                    },
+                    RotationPolicy::default(),
+                )
+                .await
+        });
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_that!(collector.rotations()).is_equal_to(0);
+        let first = collector
+            .clone()
+            .ca()
+            .read()
+            .await
+            .as_ref()
+            .unwrap()
+            .key_hash(MessageDigest::sha1())
+            .unwrap()
+            .to_vec();
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_that!(collector.rotations()).is_greater_than_or_equal_to(2);
+        assert_that!(collector.stats().ca_rotations).is_equal_to(collector.rotations());
+        assert_that!(collector.stats().last_rotation).is_some();
+
+        // certificates of the CAs rotated away from are still recognised.
+        assert_that!(collector.is_known_issuer(&first).await).is_true();
+        assert_that!(collector.is_known_issuer(&[0; 20]).await).is_false();
+
+        handle.abort();
+
+        // the same CA collected again is not a rotation.
+        let collector = CACollector::new(Duration::from_millis(250));
+        let ca = CA::new_test_ca_with_algorithm(SigningAlgorithm::EcdsaP256).unwrap();
+        let mut inner = collector.clone();
+        let handle = tokio::spawn(async move {
+            inner
+                .spawn_collector(
+                    || -> Result<(CA, Vec<X509>), ErrorStack> { Ok((ca.clone(), vec![])) },
+                    RotationPolicy::default(),
+                )
+                .await
+        });
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_that!(collector.rotations()).is_equal_to(0);
+
+        handle.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_ca_collector_rotation_policy() {
+        use super::{CACollector, RotationPolicy, SigningAlgorithm, CA};
+        use spectral::prelude::*;
+        use std::sync::{Arc, Mutex};
+        use std::time::{Duration, SystemTime};
+
+        let factory =
+            || CA::new_test_ca_with_algorithm(SigningAlgorithm::EcdsaP256).map(|ca| (ca, vec![]));
+
+        // the poll interval is far longer than the test; only the policy can cause a rotation.
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let hook_seen = seen.clone();
+        let collector = CACollector::new(Duration::from_secs(3600)).with_rotation_hook(Box::new(
+            move |ca: &CA| {
+                hook_seen
+                    .lock()
+                    .unwrap()
+                    .push(ca.clone().certificate().to_der().unwrap())
+            },
+        ));
+
+        let mut inner = collector.clone();
+        let handle = tokio::spawn(async move {
+            inner
+                .spawn_collector(
+                    factory,
+                    RotationPolicy {
+                        max_certs: Some(2),
+                        max_age: None,
+                    },
+                )
+                .await
+        });
+
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert_that!(seen.lock().unwrap().len()).is_equal_to(1);
+
+        let first = collector.ca().read().await.clone().unwrap();
+        let now = SystemTime::now();
+        for _ in 0..2 {
+            let signed = collector
+                .clone()
+                .sign(generate_csr().unwrap(), now, None)
+                .await
+                .unwrap();
+            assert_that!(signed.verify(&first.clone().private_key()).unwrap()).is_true();
+        }
+
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert_that!(collector.rotations()).is_equal_to(1);
+
+        let second = collector.ca().read().await.clone().unwrap();
+        let seen_certs = seen.lock().unwrap().clone();
+        assert_that!(seen_certs.len()).is_equal_to(2);
+        assert_that!(seen_certs[1]).is_equal_to(second.clone().certificate().to_der().unwrap());
+
+        let signed = collector
+            .clone()
+            .sign(generate_csr().unwrap(), now, None)
+            .await
+            .unwrap();
+        assert_that!(signed.verify(&second.private_key()).unwrap()).is_true();
+
+        handle.abort();
+
+        let collector = CACollector::new(Duration::from_secs(3600));
+        let mut inner = collector.clone();
+        let handle = tokio::spawn(async move {
+            inner
+                .spawn_collector(
+                    factory,
+                    RotationPolicy {
+                        max_certs: None,
+                        max_age: Some(Duration::from_millis(300)),
+                    },
+                )
+                .await
+        });
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert_that!(collector.rotations()).is_greater_than_or_equal_to(2);
+
         handle.abort();
     }
 }
@@ -1,26 +1,44 @@
+use async_trait::async_trait;
+use futures::StreamExt;
+use openssl::sha::sha256;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, convert::TryFrom, ops::Add, sync::Arc};
-use tokio::sync::Mutex;
+use std::{
+    collections::HashMap, convert::TryFrom, net::IpAddr, ops::Add, panic::AssertUnwindSafe,
+    sync::Arc, time::Instant,
+};
+use tokio::sync::{watch, Mutex};
+use tokio_postgres::Transaction;
 
 use crate::{
-    errors::db::{LoadError, SaveError},
-    models::{order::Challenge, Postgres},
+    errors::{
+        db::{LoadError, SaveError},
+        ChallengeError, ResolverError,
+    },
+    models::{
+        failed_authorization::{record_failed_authorization, FailureReason},
+        order::{mark_expired, Challenge},
+        Postgres,
+    },
 };
 
-use super::handlers::order::OrderStatus;
+use super::{handlers::order::OrderStatus, tls_alpn::TlsAlpnConfig};
+
+/// how many challenges a [Challenger] gathers evidence for at once, unless set otherwise.
+pub const DEFAULT_EVIDENCE_CONCURRENCY: usize = 32;
 
 // most of this is RFC8555 section 8
 // read RFC8555 7.1.6 on state transitions between different parts of the challenge
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(into = "String")]
-/// ChallengeType is an enum describing the challenge types coyote supports. Currently tls-alpn is
-/// unsupported.
+/// ChallengeType is an enum describing the challenge types coyote supports.
 pub enum ChallengeType {
     /// dns-01 challenge type
     DNS01,
     /// http-01 challenge type
     HTTP01,
+    /// tls-alpn-01 challenge type
+    TLSALPN01,
 }
 
 impl TryFrom<&str> for ChallengeType {
@@ -29,6 +47,7 @@ impl TryFrom<&str> for ChallengeType {
         match value {
             "dns-01" => Ok(ChallengeType::DNS01),
             "http-01" => Ok(ChallengeType::HTTP01),
+            "tls-alpn-01" => Ok(ChallengeType::TLSALPN01),
             _ => Err(LoadError::InvalidEnum),
         }
     }
@@ -39,6 +58,7 @@ impl Into<String> for ChallengeType {
         match self {
             ChallengeType::DNS01 => "dns-01",
             ChallengeType::HTTP01 => "http-01",
+            ChallengeType::TLSALPN01 => "tls-alpn-01",
         }
         .to_string()
     }
@@ -50,39 +70,285 @@ impl ChallengeType {
     }
 }
 
+#[async_trait]
+/// DnsResolver looks up the TXT records used by dns-01 challenges (RFC8555 section 8.4). Callers
+/// implement it over whatever resolver suits their deployment and hand it to
+/// [Challenger::with_dns_resolver].
+pub trait DnsResolver {
+    /// txt_records returns the contents of all TXT records for `name`, which is always a fully
+    /// qualified `_acme-challenge.` name. A name without records should yield an empty list.
+    async fn txt_records(&self, name: &str) -> Result<Vec<String>, ResolverError>;
+}
+
+#[async_trait]
+/// ChallengeHttpClient fetches the key authorizations served for http-01 challenges (RFC8555
+/// section 8.3). [crate::acme::http01::ReqwestHttpClient] does so over the network; tests may
+/// answer from a table instead. Hand one to [Challenger::with_http_client].
+pub trait ChallengeHttpClient {
+    /// get fetches `url`, which is always one made by [http01_url], following redirects, and
+    /// returns the body. Responses other than a success are errors.
+    async fn get(&self, url: &str) -> Result<String, ChallengeError>;
+}
+
+/// dns01_record_name returns the name of the TXT record holding the dns-01 validation for
+/// `identifier`. Wildcard identifiers are validated at their base domain.
+pub fn dns01_record_name(identifier: &str) -> String {
+    format!(
+        "_acme-challenge.{}",
+        identifier.trim_start_matches("*.").trim_end_matches('.')
+    )
+}
+
+/// http01_url returns the URL a ticker fetches to validate an http-01 challenge for `identifier`
+/// (RFC8555 section 8.3). IP address identifiers are contacted on the bare address (RFC8738
+/// section 6), with IPv6 addresses bracketed.
+pub fn http01_url(identifier: &str, token: &str) -> String {
+    let host = match identifier.parse::<IpAddr>() {
+        Ok(IpAddr::V6(addr)) => format!("[{}]", addr),
+        Ok(IpAddr::V4(addr)) => addr.to_string(),
+        Err(_) => identifier.to_string(),
+    };
+
+    format!("http://{}/.well-known/acme-challenge/{}", host, token)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// ChallengeEvidence is what the Challenger gathered on behalf of the ticker before asking it to
+/// decide on a challenge.
+pub enum ChallengeEvidence {
+    /// nothing was gathered; the ticker must perform the check itself, e.g. fetch the http-01
+    /// token from [http01_url].
+    None,
+    /// the body served at [http01_url] for an http-01 challenge; compare it, with surrounding
+    /// whitespace trimmed, to the key authorization. Only provided when a [ChallengeHttpClient]
+    /// is configured.
+    HTTPBody(String),
+    /// the TXT records found at [dns01_record_name] for a dns-01 challenge. Only provided when a
+    /// [DnsResolver] is configured.
+    TXTRecords(Vec<String>),
+    /// the digest from the acmeIdentifier extension of the certificate presented for a
+    /// tls-alpn-01 challenge. If the challenge was scheduled with its key authorization, the
+    /// digest has been found to be its SHA-256 digest; otherwise, compare it to that. Only
+    /// provided when tls-alpn-01 is enabled with [Challenger::with_tls_alpn].
+    ACMEIdentifier(Vec<u8>),
+}
+
 #[derive(Clone)]
 /// Challenger is an async supervisor used to perform challenges on demand. This is a simple
 /// monitored queue with expiration applied at every loop iteration.
 pub struct Challenger {
     list: Arc<Mutex<HashMap<String, Challenge>>>,
+    retries: Arc<Mutex<HashMap<String, Retry>>>,
     expiration: Option<chrono::Duration>,
+    max_retries: Option<u32>,
+    retry_backoff: chrono::Duration,
+    resolver: Option<Arc<dyn DnsResolver + Send + Sync>>,
+    http_client: Option<Arc<dyn ChallengeHttpClient + Send + Sync>>,
+    tls_alpn: Option<TlsAlpnConfig>,
+    evidence_concurrency: usize,
+    key_authorizations: Arc<Mutex<HashMap<String, String>>>,
+    stats: Arc<Mutex<TickStats>>,
+    ticks: Arc<watch::Sender<TickStats>>,
+}
+
+/// TickStats is what [Challenger::tick_receiver] is sent after each reconcile. The counts are
+/// totals since the challenger was made, so a receiver which misses a reconcile loses nothing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TickStats {
+    /// when the last tick started; when the challenger was made, before the first.
+    pub last_tick_at: Instant,
+    /// challenges attempted, whether they were decided or not.
+    pub challenges_processed: u64,
+    /// challenges which passed.
+    pub challenges_validated: u64,
+    /// failed attempts at challenges, retried or not, and challenges which expired.
+    pub challenges_failed: u64,
+}
+
+impl TickStats {
+    fn new() -> Self {
+        Self {
+            last_tick_at: Instant::now(),
+            challenges_processed: 0,
+            challenges_validated: 0,
+            challenges_failed: 0,
+        }
+    }
+}
+
+/// AuthorizationSummary describes a challenge waiting in the [Challenger]'s queue to be decided,
+/// and so the authorization waiting on it; see [Challenger::active_authorizations].
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AuthorizationSummary {
+    pub authorization_id: String,
+    pub identifier: String,
+    pub challenge_type: ChallengeType,
+    pub created_at: chrono::DateTime<chrono::Local>,
+    /// failed attempts at the challenge so far.
+    pub retry_count: i32,
+}
+
+#[derive(Clone, Debug)]
+/// Retry tracks when a failed challenge may next be attempted, and whether its retry count has
+/// been written back to storage yet.
+struct Retry {
+    next_attempt: chrono::DateTime<chrono::Local>,
+    persisted: bool,
 }
 
 impl Challenger {
     /// Construct a new challenger; challenges will last as long as `expiriation` is set to, or
     /// forever if Option::None.
     pub fn new(expiration: Option<chrono::Duration>) -> Self {
+        let (ticks, _) = watch::channel(TickStats::new());
+
         Self {
             list: Arc::new(Mutex::new(HashMap::new())),
+            retries: Arc::new(Mutex::new(HashMap::new())),
             expiration,
+            max_retries: None,
+            retry_backoff: chrono::Duration::zero(),
+            resolver: None,
+            http_client: None,
+            tls_alpn: None,
+            evidence_concurrency: DEFAULT_EVIDENCE_CONCURRENCY,
+            key_authorizations: Arc::new(Mutex::new(HashMap::new())),
+            stats: Arc::new(Mutex::new(TickStats::new())),
+            ticks: Arc::new(ticks),
         }
     }
 
+    /// with_retries limits how many times a challenge which failed is attempted again; should the
+    /// last retry fail too, it is marked invalid along with its authorization, having been
+    /// attempted `max_retries + 1` times in all. After each failure, the challenge is not
+    /// attempted again until `retry_backoff` has passed. Without a limit, failed challenges are
+    /// retried on every tick until they expire.
+    pub fn with_retries(mut self, max_retries: u32, retry_backoff: chrono::Duration) -> Self {
+        self.max_retries = Some(max_retries);
+        self.retry_backoff = retry_backoff;
+        self
+    }
+
+    /// with_dns_resolver configures the resolver used to gather TXT records for dns-01 challenges.
+    /// Without one, dns-01 challenges are handed to the ticker with [ChallengeEvidence::None].
+    pub fn with_dns_resolver(mut self, resolver: Arc<dyn DnsResolver + Send + Sync>) -> Self {
+        self.resolver = Some(resolver);
+        self
+    }
+
+    /// with_http_client configures the client used to fetch the key authorizations of http-01
+    /// challenges, e.g. a [crate::acme::http01::ReqwestHttpClient]. Without one, http-01
+    /// challenges are handed to the ticker with [ChallengeEvidence::None].
+    pub fn with_http_client(mut self, client: Arc<dyn ChallengeHttpClient + Send + Sync>) -> Self {
+        self.http_client = Some(client);
+        self
+    }
+
+    /// with_tls_alpn enables dialing clients for tls-alpn-01 challenges. Without it, tls-alpn-01
+    /// challenges are handed to the ticker with [ChallengeEvidence::None].
+    pub fn with_tls_alpn(mut self, config: TlsAlpnConfig) -> Self {
+        self.tls_alpn = Some(config);
+        self
+    }
+
+    /// with_evidence_concurrency sets how many challenges evidence is gathered for at once on
+    /// each tick, [DEFAULT_EVIDENCE_CONCURRENCY] by default; the others wait their turn. Each
+    /// gathering resolves names or dials the client, so this bounds the sockets a tick opens.
+    pub fn with_evidence_concurrency(mut self, limit: usize) -> Self {
+        self.evidence_concurrency = limit.max(1);
+        self
+    }
+
+    /// tick_receiver is told the [TickStats] after each [Challenger::reconcile], once the
+    /// challenges decided by the ticks before it are written, e.g. for tests waiting on a
+    /// validation, or for monitoring that the challenger is still running.
+    pub fn tick_receiver(&self) -> watch::Receiver<TickStats> {
+        self.ticks.subscribe()
+    }
+
     pub(crate) async fn schedule(&self, c: Challenge) {
         self.list.lock().await.insert(c.reference.clone(), c);
     }
 
+    /// schedule_with_key_authorization is like schedule, but with the key authorization of the
+    /// challenge (RFC8555 8.1), so that evidence which can be checked against it is checked
+    /// before the ticker is asked.
+    pub(crate) async fn schedule_with_key_authorization(
+        &self,
+        c: Challenge,
+        key_authorization: String,
+    ) {
+        self.key_authorizations
+            .lock()
+            .await
+            .insert(c.reference.clone(), key_authorization);
+        self.schedule(c).await
+    }
+
+    /// status_counts returns the number of challenges in the queue for each status. Challenges
+    /// leave the queue when they are reconciled.
+    pub async fn status_counts(&self) -> HashMap<String, usize> {
+        let mut counts = HashMap::new();
+
+        for c in self.list.lock().await.values() {
+            *counts.entry(c.status.to_string()).or_insert(0) += 1;
+        }
+
+        counts
+    }
+
+    /// pending_count returns the number of challenges in the queue which have not been decided
+    /// yet. A count which keeps growing suggests validations are stuck.
+    pub async fn pending_count(&self) -> usize {
+        self.list
+            .lock()
+            .await
+            .values()
+            .filter(|c| matches!(c.status, OrderStatus::Pending | OrderStatus::Processing))
+            .count()
+    }
+
+    /// active_authorizations summarizes the challenges counted by [Challenger::pending_count],
+    /// oldest first.
+    pub async fn active_authorizations(&self) -> Vec<AuthorizationSummary> {
+        let mut ret = self
+            .list
+            .lock()
+            .await
+            .values()
+            .filter(|c| matches!(c.status, OrderStatus::Pending | OrderStatus::Processing))
+            .map(|c| AuthorizationSummary {
+                authorization_id: c.authorization_id.clone(),
+                identifier: c.identifier.clone(),
+                challenge_type: c.challenge_type.clone(),
+                created_at: c.created_at,
+                retry_count: c.retry_count,
+            })
+            .collect::<Vec<AuthorizationSummary>>();
+
+        ret.sort_by_key(|s| s.created_at);
+        ret
+    }
+
     /// tick should be called in a loop in its own async routine with an interval between
     /// iterations. This performs each challenge in the queue and invalidates any expired
     /// challenges. To commit to storage, call reconcile.
+    ///
+    /// The ticker is called with each challenge and any [ChallengeEvidence] gathered for it; it
+    /// should dispatch on the challenge's `challenge_type`, and return Some(()) if the challenge
+    /// passed. A challenge which fails, whose evidence cannot be gathered before it expires, or
+    /// whose ticker panics, counts a retry; see [Challenger::with_retries]. The other challenges
+    /// in the queue are decided regardless.
     pub async fn tick<T>(&self, ticker: T)
     where
-        T: Fn(Challenge) -> Option<()>,
+        T: Fn(Challenge, ChallengeEvidence) -> Option<()>,
     {
+        let started = Instant::now();
         let mut lock = self.list.lock().await;
         let mut ch = HashMap::new();
         let mut sv = Vec::new();
         let mut iv = Vec::new();
+        let mut fv = Vec::new();
 
         for (s, c) in lock.iter_mut() {
             match c.status {
@@ -97,6 +363,9 @@ impl Challenger {
 
         let expires = self.expiration.is_some();
         let now = chrono::DateTime::<chrono::Local>::from(std::time::SystemTime::now());
+        let retries = self.retries.lock().await.clone();
+
+        let mut due = Vec::new();
 
         for (s, c) in ch {
             if expires && c.created_at.add(self.expiration.unwrap()) < now {
@@ -104,15 +373,102 @@ impl Challenger {
                 continue;
             }
 
-            match ticker(c.clone()) {
-                Some(_) => {
+            if let Some(retry) = retries.get(&s) {
+                if retry.next_attempt > now {
+                    continue;
+                }
+            }
+
+            due.push((s, c));
+        }
+
+        // evidence is gathered for several challenges at once, up to the configured limit, and
+        // none may take longer than it has left to live, so that a slow challenge does not hold
+        // up the others.
+        let gathered = futures::stream::iter(due.into_iter().map(|(s, c)| async move {
+            let evidence = match self.expiration {
+                Some(expiration) => {
+                    // a challenge may have waited for its turn, so what it has left is taken
+                    // when it gets one.
+                    let left = (c.created_at.add(expiration) - chrono::Local::now())
+                        .to_std()
+                        .unwrap_or_default();
+
+                    match tokio::time::timeout(left, self.evidence(&c)).await {
+                        Ok(evidence) => evidence,
+                        Err(_) => {
+                            log::warn!("gathering evidence for challenge {} timed out", s);
+                            None
+                        }
+                    }
+                }
+                None => self.evidence(&c).await,
+            };
+
+            (s, c, evidence)
+        }))
+        .buffer_unordered(self.evidence_concurrency)
+        .collect::<Vec<_>>()
+        .await;
+
+        for (s, c, evidence) in gathered {
+            let evidence = match evidence {
+                Some(evidence) => evidence,
+                None => {
+                    fv.push(s.clone());
+                    continue;
+                }
+            };
+
+            // a ticker which panics fails the challenge it was deciding, not the whole tick.
+            match std::panic::catch_unwind(AssertUnwindSafe(|| ticker(c.clone(), evidence))) {
+                Ok(Some(_)) => {
                     sv.push(s.clone());
                 }
-                None => {}
+                Ok(None) => {
+                    fv.push(s.clone());
+                }
+                Err(_) => {
+                    log::error!("ticker panicked deciding challenge {}", s);
+                    fv.push(s.clone());
+                }
             }
         }
 
+        {
+            let mut stats = self.stats.lock().await;
+            stats.last_tick_at = started;
+            stats.challenges_processed += (sv.len() + fv.len() + iv.len()) as u64;
+            stats.challenges_validated += sv.len() as u64;
+            stats.challenges_failed += (fv.len() + iv.len()) as u64;
+        }
+
         let mut lock = self.list.lock().await;
+        let mut retries = self.retries.lock().await;
+
+        for s in fv {
+            match lock.get_mut(&s) {
+                Some(i) => {
+                    i.retry_count += 1;
+
+                    match self.max_retries {
+                        Some(max) if i.retry_count as u32 > max => {
+                            i.status = OrderStatus::Invalid;
+                        }
+                        _ => {
+                            retries.insert(
+                                s,
+                                Retry {
+                                    next_attempt: now.add(self.retry_backoff),
+                                    persisted: false,
+                                },
+                            );
+                        }
+                    }
+                }
+                None => {}
+            }
+        }
 
         for s in sv {
             match lock.get_mut(&s) {
@@ -129,32 +485,154 @@ impl Challenger {
         }
     }
 
+    /// persist_decided writes a challenge which passed or failed, and for one which failed, the
+    /// failure of its authorization.
+    async fn persist_decided(
+        &self,
+        mut c: crate::models::order::Challenge,
+        tx: &Transaction<'_>,
+    ) -> Result<(), SaveError> {
+        c.persist_status(tx).await?;
+
+        // RFC8555 7.1.6: a failed challenge invalidates its authorization.
+        if c.status == OrderStatus::Invalid {
+            c.invalidate_authorization(tx).await?;
+
+            let reason = match self.max_retries {
+                Some(max) if c.retry_count as u32 > max => FailureReason::RetriesExhausted,
+                _ => FailureReason::ChallengeExpired,
+            };
+
+            record_failed_authorization(
+                &c.authorization_id,
+                Some(c.challenge_type.clone()),
+                reason,
+                tx,
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// evidence gathers the [ChallengeEvidence] for the challenge, or returns None, having logged
+    /// why, if it could not be gathered.
+    async fn evidence(&self, c: &Challenge) -> Option<ChallengeEvidence> {
+        if let (ChallengeType::HTTP01, Some(client)) = (&c.challenge_type, &self.http_client) {
+            let url = http01_url(&c.identifier, &c.token);
+            return match client.get(&url).await {
+                Ok(body) => Some(ChallengeEvidence::HTTPBody(body)),
+                Err(e) => {
+                    log::warn!("could not fetch {}: {}", url, e);
+                    None
+                }
+            };
+        }
+
+        match (&c.challenge_type, &self.resolver, &self.tls_alpn) {
+            (ChallengeType::DNS01, Some(resolver), _) => {
+                let name = dns01_record_name(&c.identifier);
+                match resolver.txt_records(&name).await {
+                    Ok(records) => Some(ChallengeEvidence::TXTRecords(records)),
+                    Err(e) => {
+                        log::warn!("could not resolve {}: {}", name, e);
+                        None
+                    }
+                }
+            }
+            (ChallengeType::TLSALPN01, _, Some(config)) => {
+                match config.acme_identifier(&c.identifier).await {
+                    Ok(digest) => match self.key_authorizations.lock().await.get(&c.reference) {
+                        // RFC8737 3: the extension holds the SHA-256 digest of the key
+                        // authorization.
+                        Some(key_authorization)
+                            if sha256(key_authorization.as_bytes())[..] != digest[..] =>
+                        {
+                            log::warn!(
+                                "tls-alpn-01 check for {} failed: the acmeIdentifier does not match the key authorization",
+                                c.identifier
+                            );
+                            None
+                        }
+                        _ => Some(ChallengeEvidence::ACMEIdentifier(digest)),
+                    },
+                    Err(e) => {
+                        log::warn!("tls-alpn-01 check for {} failed: {}", c.identifier, e);
+                        None
+                    }
+                }
+            }
+            _ => Some(ChallengeEvidence::None),
+        }
+    }
+
     /// reconcile should be called after tick. This actually commits the challenge results to the
-    /// backing storage.
+    /// backing storage, and marks orders and authorizations which are past their expiry as
+    /// expired (RFC8555 7.1.6).
     pub async fn reconcile(&self, db: Postgres) -> Result<(), SaveError> {
         let mut lock = self.list.lock().await;
+        let mut retries = self.retries.lock().await;
         let mut db_lock = db.client().await?;
-        let tx = db_lock.transaction().await?;
+        let mut tx = db_lock.transaction().await?;
         let mut sv = Vec::new();
 
         // FIXME needs to manage challenge statuses, or that needs to move up a level
+        //
+        // each challenge is written in a savepoint of its own, so that one which cannot be
+        // written is left in the queue for the next reconcile without losing the others.
         for (s, c) in lock.iter_mut() {
             match c.status {
-                OrderStatus::Pending | OrderStatus::Processing => {}
+                OrderStatus::Pending | OrderStatus::Processing => {
+                    if let Some(retry) = retries.get_mut(s) {
+                        if !retry.persisted {
+                            let sp = tx.transaction().await?;
+                            let mut c: crate::models::order::Challenge = c.clone().into();
+
+                            match c.persist_status(&sp).await {
+                                Ok(()) => {
+                                    sp.commit().await?;
+                                    retry.persisted = true;
+                                }
+                                Err(e) => log::error!("could not persist challenge {}: {}", s, e),
+                            }
+                        }
+                    }
+                }
                 _ => {
-                    let mut c: crate::models::order::Challenge = c.clone().into();
-                    c.persist_status(&tx).await?;
-                    sv.push(s.clone());
+                    let sp = tx.transaction().await?;
+
+                    match self.persist_decided(c.clone(), &sp).await {
+                        Ok(()) => {
+                            sp.commit().await?;
+                            sv.push(s.clone());
+                        }
+                        Err(e) => log::error!("could not persist challenge {}: {}", s, e),
+                    }
                 }
             }
         }
 
+        let mut key_authorizations = self.key_authorizations.lock().await;
         for s in sv {
             lock.remove(&s);
+            retries.remove(&s);
+            key_authorizations.remove(&s);
+        }
+        drop(key_authorizations);
+
+        let (orders, authorizations) = mark_expired(chrono::Local::now(), &tx).await?;
+        if orders > 0 || authorizations > 0 {
+            log::info!(
+                "expired {} orders and {} authorizations",
+                orders,
+                authorizations
+            );
         }
 
         tx.commit().await?;
 
+        self.ticks.send_replace(self.stats.lock().await.clone());
+
         Ok(())
     }
 }
@@ -199,12 +677,13 @@ mod tests {
             created_at: chrono::DateTime::<chrono::Local>::from(std::time::SystemTime::now()),
             deleted_at: None,
             validated: None,
+            retry_count: 0,
         };
 
         challenge.create(pg.db()).await.unwrap();
 
         c.schedule(challenge.clone()).await;
-        c.tick(|_c| Some(())).await;
+        c.tick(|_c, _| Some(())).await;
         c.reconcile(pg.db()).await.unwrap();
 
         let challenges = order
@@ -230,6 +709,7 @@ mod tests {
             created_at: chrono::DateTime::<chrono::Local>::from(std::time::SystemTime::now()),
             deleted_at: None,
             validated: None,
+            retry_count: 0,
         };
 
         challenge.create(pg.db()).await.unwrap();
@@ -238,7 +718,7 @@ mod tests {
         tokio::time::sleep(Duration::new(2, 0)).await;
 
         c.schedule(challenge.clone()).await;
-        c.tick(|_c| None).await;
+        c.tick(|_c, _| None).await;
         c.reconcile(pg.db()).await.unwrap();
 
         let challenges = order
@@ -251,6 +731,286 @@ mod tests {
         assert_that!(challenges[1].status).is_equal_to(OrderStatus::Invalid);
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_challenger_introspection() {
+        use super::{ChallengeType, Challenger};
+        use crate::acme::handlers::order::OrderStatus;
+        use crate::models::order::{Authorization, Challenge, Order};
+        use crate::models::Record;
+        use crate::test::PGTest;
+        use crate::util::make_nonce;
+        use spectral::prelude::*;
+
+        let pg = PGTest::new("test_challenger_introspection").await.unwrap();
+        let c = Challenger::new(None);
+        let mut ticks = c.tick_receiver();
+
+        assert_that!(c.pending_count().await).is_equal_to(0);
+        assert_that!(c.active_authorizations().await).is_empty();
+
+        let mut order = Order::default();
+        order.create(pg.db()).await.unwrap();
+
+        let mut identifiers = Vec::new();
+
+        for i in 0..3 {
+            let identifier = format!("{}.example.com", i);
+
+            let mut authz = Authorization::default();
+            authz.order_id = order.order_id.clone();
+            authz.identifier = Some(identifier.clone());
+            authz.create(pg.db()).await.unwrap();
+
+            let mut challenge = Challenge {
+                id: None,
+                order_id: order.order_id.clone(),
+                authorization_id: authz.reference.clone(),
+                identifier: identifier.clone(),
+                challenge_type: ChallengeType::HTTP01,
+                reference: make_nonce(None),
+                token: make_nonce(None),
+                status: OrderStatus::Processing,
+                issuing_address: "127.0.0.1".to_string(),
+                created_at: chrono::Local::now() + chrono::Duration::seconds(i),
+                deleted_at: None,
+                validated: None,
+                retry_count: 0,
+            };
+            challenge.create(pg.db()).await.unwrap();
+
+            c.schedule(challenge).await;
+            identifiers.push(identifier);
+
+            assert_that!(c.pending_count().await).is_equal_to(i as usize + 1);
+        }
+
+        let active = c.active_authorizations().await;
+        assert_that!(active
+            .iter()
+            .map(|s| s.identifier.clone())
+            .collect::<Vec<String>>())
+        .is_equal_to(identifiers.clone());
+        assert_that!(active[0].retry_count).is_equal_to(0);
+        assert_that!(serde_json::to_value(&active[0]).unwrap()["challenge_type"].as_str())
+            .is_equal_to(Some("http-01"));
+
+        // only the first passes; the others fail, and stay in the queue.
+        c.tick(|c, _| (c.identifier == identifiers[0]).then(|| ()))
+            .await;
+        c.reconcile(pg.db()).await.unwrap();
+
+        assert_that!(c.pending_count().await).is_equal_to(2);
+        let active = c.active_authorizations().await;
+        assert_that!(active.len()).is_equal_to(2);
+        assert_that!(active[0].identifier).is_equal_to(identifiers[1].clone());
+        assert_that!(active[0].retry_count).is_equal_to(1);
+
+        assert_that!(ticks.has_changed().unwrap()).is_true();
+        let first = ticks.borrow_and_update().clone();
+        assert_that!(first.challenges_processed).is_equal_to(3);
+        assert_that!(first.challenges_validated).is_equal_to(1);
+        assert_that!(first.challenges_failed).is_equal_to(2);
+
+        c.tick(|_, _| Some(())).await;
+        assert_that!(ticks.has_changed().unwrap()).is_false();
+        c.reconcile(pg.db()).await.unwrap();
+        assert_that!(c.pending_count().await).is_equal_to(0);
+
+        // the counts are totals.
+        ticks.changed().await.unwrap();
+        let second = ticks.borrow_and_update().clone();
+        assert_that!(second.last_tick_at).is_greater_than(first.last_tick_at);
+        assert_that!(second.challenges_processed).is_equal_to(5);
+        assert_that!(second.challenges_validated).is_equal_to(3);
+        assert_that!(second.challenges_failed).is_equal_to(2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_challenger_ticker_panic() {
+        use super::{ChallengeType, Challenger};
+        use crate::acme::handlers::order::OrderStatus;
+        use crate::models::order::{Authorization, Challenge, Order};
+        use crate::models::Record;
+        use crate::test::PGTest;
+        use crate::util::make_nonce;
+        use spectral::prelude::*;
+
+        let pg = PGTest::new("test_challenger_ticker_panic").await.unwrap();
+        let c = Challenger::new(Some(chrono::Duration::seconds(60)));
+
+        let mut order = Order::default();
+        order.create(pg.db()).await.unwrap();
+
+        for identifier in ["panic.example.com", "a.example.com", "b.example.com"] {
+            let mut authz = Authorization::default();
+            authz.order_id = order.order_id.clone();
+            authz.identifier = Some(identifier.to_string());
+            authz.create(pg.db()).await.unwrap();
+
+            let mut challenge = Challenge {
+                id: None,
+                order_id: order.order_id.clone(),
+                authorization_id: authz.reference.clone(),
+                identifier: identifier.to_string(),
+                challenge_type: ChallengeType::HTTP01,
+                reference: make_nonce(None),
+                token: make_nonce(None),
+                status: OrderStatus::Processing,
+                issuing_address: "127.0.0.1".to_string(),
+                created_at: chrono::Local::now(),
+                deleted_at: None,
+                validated: None,
+                retry_count: 0,
+            };
+            challenge.create(pg.db()).await.unwrap();
+
+            c.schedule(challenge).await;
+        }
+
+        c.tick(|c, _| {
+            if c.identifier == "panic.example.com" {
+                panic!("validator blew up");
+            }
+
+            Some(())
+        })
+        .await;
+        c.reconcile(pg.db()).await.unwrap();
+
+        // the others were decided in the same tick; the one which panicked failed, and is retried.
+        let active = c.active_authorizations().await;
+        assert_that!(active.len()).is_equal_to(1);
+        assert_that!(active[0].identifier.as_str()).is_equal_to("panic.example.com");
+        assert_that!(active[0].retry_count).is_equal_to(1);
+
+        let challenges = order
+            .challenges(&pg.db().client().await.unwrap().transaction().await.unwrap())
+            .await
+            .unwrap();
+        assert_that!(challenges
+            .iter()
+            .filter(|c| c.status == OrderStatus::Valid)
+            .count())
+        .is_equal_to(2);
+
+        c.tick(|_, _| Some(())).await;
+        c.reconcile(pg.db()).await.unwrap();
+        assert_that!(c.pending_count().await).is_equal_to(0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_challenger_concurrent_evidence() {
+        use super::{ChallengeEvidence, ChallengeType, Challenger, DnsResolver};
+        use crate::acme::handlers::order::OrderStatus;
+        use crate::errors::ResolverError;
+        use crate::models::order::Challenge;
+        use async_trait::async_trait;
+        use spectral::prelude::*;
+        use std::sync::Arc;
+        use tokio::sync::Barrier;
+
+        // lookups only complete once all of them are underway; one at a time, each would wait
+        // until its challenge expired.
+        struct BarrierResolver(Barrier);
+
+        #[async_trait]
+        impl DnsResolver for BarrierResolver {
+            async fn txt_records(&self, _name: &str) -> Result<Vec<String>, ResolverError> {
+                self.0.wait().await;
+                Ok(vec!["good".to_string()])
+            }
+        }
+
+        let names = ["a.example.com", "b.example.com", "c.example.com"];
+        let c = Challenger::new(Some(chrono::Duration::seconds(5)))
+            .with_dns_resolver(Arc::new(BarrierResolver(Barrier::new(names.len()))));
+
+        for name in names {
+            c.schedule(Challenge::new(
+                "order".to_string(),
+                name.to_string(),
+                ChallengeType::DNS01,
+                name.to_string(),
+                "127.0.0.1".to_string(),
+                OrderStatus::Processing,
+            ))
+            .await;
+        }
+
+        c.tick(|_, evidence| match evidence {
+            ChallengeEvidence::TXTRecords(records) => {
+                records.contains(&"good".to_string()).then(|| ())
+            }
+            _ => None,
+        })
+        .await;
+
+        let counts = c.status_counts().await;
+        assert_that!(counts.get(&OrderStatus::Valid.to_string())).is_equal_to(Some(&names.len()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_challenger_evidence_concurrency() {
+        use super::{ChallengeEvidence, ChallengeType, Challenger, DnsResolver};
+        use crate::acme::handlers::order::OrderStatus;
+        use crate::errors::ResolverError;
+        use crate::models::order::Challenge;
+        use async_trait::async_trait;
+        use spectral::prelude::*;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        // counts the lookups underway, and the most there were at once.
+        #[derive(Default)]
+        struct CountingResolver {
+            underway: AtomicUsize,
+            most: AtomicUsize,
+        }
+
+        #[async_trait]
+        impl DnsResolver for CountingResolver {
+            async fn txt_records(&self, _name: &str) -> Result<Vec<String>, ResolverError> {
+                let underway = self.underway.fetch_add(1, Ordering::SeqCst) + 1;
+                self.most.fetch_max(underway, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                self.underway.fetch_sub(1, Ordering::SeqCst);
+                Ok(vec!["good".to_string()])
+            }
+        }
+
+        let resolver = Arc::new(CountingResolver::default());
+        let c = Challenger::new(Some(chrono::Duration::seconds(5)))
+            .with_dns_resolver(resolver.clone())
+            .with_evidence_concurrency(2);
+
+        let names = ["a", "b", "c", "d", "e", "f"].map(|n| format!("{}.example.com", n));
+        for name in &names {
+            c.schedule(Challenge::new(
+                "order".to_string(),
+                name.to_string(),
+                ChallengeType::DNS01,
+                name.to_string(),
+                "127.0.0.1".to_string(),
+                OrderStatus::Processing,
+            ))
+            .await;
+        }
+
+        c.tick(|_, evidence| match evidence {
+            ChallengeEvidence::TXTRecords(records) => {
+                records.contains(&"good".to_string()).then(|| ())
+            }
+            _ => None,
+        })
+        .await;
+
+        // every challenge is decided, but no more than two lookups were ever underway.
+        let counts = c.status_counts().await;
+        assert_that!(counts.get(&OrderStatus::Valid.to_string())).is_equal_to(Some(&names.len()));
+        assert_that!(resolver.most.load(Ordering::SeqCst)).is_equal_to(2);
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_challenge_scheduler_async() {
         use super::{ChallengeType, Challenger};
@@ -274,7 +1034,7 @@ mod tests {
         let db2 = db.clone();
         let supervisor = tokio::spawn(async move {
             loop {
-                c2.tick(|_c| Some(())).await;
+                c2.tick(|_c, _| Some(())).await;
                 c2.reconcile(db2.clone()).await.unwrap();
                 tokio::time::sleep(Duration::new(1, 0)).await;
             }
@@ -308,6 +1068,7 @@ mod tests {
                         ),
                         deleted_at: None,
                         validated: None,
+                        retry_count: 0,
                     };
 
                     challenge.create(db2.clone()).await.unwrap();
@@ -337,4 +1098,500 @@ mod tests {
 
         supervisor.abort();
     }
+
+    #[test]
+    fn test_dns01_record_name() {
+        use super::dns01_record_name;
+        use spectral::prelude::*;
+
+        assert_that!(dns01_record_name("example.com"))
+            .is_equal_to("_acme-challenge.example.com".to_string());
+        assert_that!(dns01_record_name("*.example.com"))
+            .is_equal_to("_acme-challenge.example.com".to_string());
+        assert_that!(dns01_record_name("example.com."))
+            .is_equal_to("_acme-challenge.example.com".to_string());
+    }
+
+    #[test]
+    fn test_http01_url() {
+        use super::http01_url;
+        use spectral::prelude::*;
+
+        assert_that!(http01_url("example.com", "token"))
+            .is_equal_to("http://example.com/.well-known/acme-challenge/token".to_string());
+        assert_that!(http01_url("127.0.0.1", "token"))
+            .is_equal_to("http://127.0.0.1/.well-known/acme-challenge/token".to_string());
+        assert_that!(http01_url("2001:db8::1", "token"))
+            .is_equal_to("http://[2001:db8::1]/.well-known/acme-challenge/token".to_string());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_challenge_dns_resolver() {
+        use super::{ChallengeEvidence, ChallengeType, Challenger, DnsResolver};
+        use crate::acme::handlers::order::OrderStatus;
+        use crate::errors::ResolverError;
+        use crate::models::order::{Authorization, Challenge, Order};
+        use crate::models::Record;
+        use crate::test::PGTest;
+        use crate::util::make_nonce;
+        use async_trait::async_trait;
+        use spectral::prelude::*;
+        use std::collections::HashMap;
+        use std::sync::Arc;
+
+        struct StaticResolver(HashMap<String, Vec<String>>);
+
+        #[async_trait]
+        impl DnsResolver for StaticResolver {
+            async fn txt_records(&self, name: &str) -> Result<Vec<String>, ResolverError> {
+                match self.0.get(name) {
+                    Some(records) => Ok(records.clone()),
+                    None => Err(ResolverError::Lookup(
+                        name.to_string(),
+                        "SERVFAIL".to_string(),
+                    )),
+                }
+            }
+        }
+
+        let pg = PGTest::new("test_challenge_dns_resolver").await.unwrap();
+        let db = pg.db();
+
+        let mut records = HashMap::new();
+        records.insert(
+            "_acme-challenge.example.com".to_string(),
+            vec!["good".to_string()],
+        );
+        records.insert(
+            "_acme-challenge.example.org".to_string(),
+            vec!["bad".to_string()],
+        );
+
+        let c = Challenger::new(Some(chrono::Duration::seconds(60)))
+            .with_dns_resolver(Arc::new(StaticResolver(records)));
+
+        let mut order = Order::default();
+        order.create(db.clone()).await.unwrap();
+
+        let mut challenges = Vec::new();
+        for (identifier, challenge_type) in [
+            ("*.example.com", ChallengeType::DNS01),
+            ("example.org", ChallengeType::DNS01),
+            ("example.net", ChallengeType::DNS01),
+            ("example.org", ChallengeType::HTTP01),
+        ] {
+            let mut authz = Authorization::default();
+            authz.order_id = order.order_id.clone();
+            authz.identifier = Some(identifier.to_string());
+            authz.create(db.clone()).await.unwrap();
+
+            let mut challenge = Challenge::new(
+                order.order_id.clone(),
+                authz.reference.clone(),
+                challenge_type,
+                identifier.to_string(),
+                "127.0.0.1".to_string(),
+                OrderStatus::Processing,
+            );
+            challenge.token = make_nonce(None);
+            challenge.create(db.clone()).await.unwrap();
+            c.schedule(challenge.clone()).await;
+            challenges.push(challenge);
+        }
+
+        c.tick(|ch, evidence| match (ch.challenge_type, evidence) {
+            (ChallengeType::DNS01, ChallengeEvidence::TXTRecords(records)) => {
+                records.contains(&"good".to_string()).then(|| ())
+            }
+            (ChallengeType::HTTP01, ChallengeEvidence::None) => Some(()),
+            _ => None,
+        })
+        .await;
+
+        let counts = c.status_counts().await;
+        // the wildcard and http-01 challenges pass; the bad record and failed lookup are left to
+        // be retried.
+        assert_that!(counts.get(&OrderStatus::Valid.to_string())).is_equal_to(Some(&2));
+        assert_that!(counts.get(&OrderStatus::Processing.to_string())).is_equal_to(Some(&2));
+
+        c.reconcile(db.clone()).await.unwrap();
+
+        let mut client = db.clone().client().await.unwrap();
+        let tx = client.transaction().await.unwrap();
+        let statuses = order
+            .challenges(&tx)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|ch| (ch.reference, ch.status))
+            .collect::<HashMap<String, OrderStatus>>();
+
+        assert_that!(statuses.get(&challenges[0].reference)).is_equal_to(Some(&OrderStatus::Valid));
+        assert_that!(statuses.get(&challenges[1].reference))
+            .is_equal_to(Some(&OrderStatus::Processing));
+        assert_that!(statuses.get(&challenges[2].reference))
+            .is_equal_to(Some(&OrderStatus::Processing));
+        assert_that!(statuses.get(&challenges[3].reference)).is_equal_to(Some(&OrderStatus::Valid));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_challenge_http_client() {
+        use super::{ChallengeEvidence, ChallengeHttpClient, ChallengeType, Challenger};
+        use crate::acme::handlers::order::OrderStatus;
+        use crate::errors::ChallengeError;
+        use crate::models::order::Challenge;
+        use async_trait::async_trait;
+        use spectral::prelude::*;
+        use std::collections::HashMap;
+        use std::sync::{Arc, Mutex};
+
+        // answers from a table, and remembers what was asked for.
+        #[derive(Default)]
+        struct CannedClient {
+            responses: HashMap<String, Result<String, ChallengeError>>,
+            requested: Mutex<Vec<String>>,
+        }
+
+        #[async_trait]
+        impl ChallengeHttpClient for CannedClient {
+            async fn get(&self, url: &str) -> Result<String, ChallengeError> {
+                self.requested.lock().unwrap().push(url.to_string());
+                self.responses
+                    .get(url)
+                    .cloned()
+                    .unwrap_or(Err(ChallengeError::HttpStatus(404)))
+            }
+        }
+
+        let mut client = CannedClient::default();
+        client.responses.insert(
+            "http://example.com/.well-known/acme-challenge/good".to_string(),
+            Ok("good.thumbprint\n".to_string()),
+        );
+        client.responses.insert(
+            "http://example.org/.well-known/acme-challenge/bad".to_string(),
+            Ok("something else".to_string()),
+        );
+        client.responses.insert(
+            "http://[2001:db8::1]/.well-known/acme-challenge/down".to_string(),
+            Err(ChallengeError::Timeout),
+        );
+        let client = Arc::new(client);
+
+        let c =
+            Challenger::new(Some(chrono::Duration::seconds(60))).with_http_client(client.clone());
+
+        for (identifier, token, challenge_type) in [
+            ("example.com", "good", ChallengeType::HTTP01),
+            ("example.org", "bad", ChallengeType::HTTP01),
+            ("2001:db8::1", "down", ChallengeType::HTTP01),
+            ("example.net", "missing", ChallengeType::HTTP01),
+            ("example.com", "dns", ChallengeType::DNS01),
+        ] {
+            let mut challenge = Challenge::new(
+                "order".to_string(),
+                format!("authz-{}", token),
+                challenge_type,
+                identifier.to_string(),
+                "127.0.0.1".to_string(),
+                OrderStatus::Processing,
+            );
+            challenge.token = token.to_string();
+            c.schedule(challenge).await;
+        }
+
+        c.tick(|ch, evidence| match (ch.challenge_type, evidence) {
+            (ChallengeType::HTTP01, ChallengeEvidence::HTTPBody(body)) => {
+                (body.trim() == format!("{}.thumbprint", ch.token)).then(|| ())
+            }
+            (ChallengeType::DNS01, ChallengeEvidence::None) => Some(()),
+            _ => None,
+        })
+        .await;
+
+        let counts = c.status_counts().await;
+        // the good token and the dns-01 challenge pass; the wrong body and the failed fetches
+        // are left to be retried.
+        assert_that!(counts.get(&OrderStatus::Valid.to_string())).is_equal_to(Some(&2));
+        assert_that!(counts.get(&OrderStatus::Processing.to_string())).is_equal_to(Some(&3));
+
+        // dns-01 challenges are not fetched.
+        let mut requested = client.requested.lock().unwrap().clone();
+        requested.sort();
+        assert_that!(requested).is_equal_to(vec![
+            "http://[2001:db8::1]/.well-known/acme-challenge/down".to_string(),
+            "http://example.com/.well-known/acme-challenge/good".to_string(),
+            "http://example.net/.well-known/acme-challenge/missing".to_string(),
+            "http://example.org/.well-known/acme-challenge/bad".to_string(),
+        ]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_challenge_retries() {
+        use super::{ChallengeEvidence, ChallengeType, Challenger};
+        use crate::acme::handlers::order::OrderStatus;
+        use crate::models::order::{Authorization, Challenge, Order};
+        use crate::models::{Postgres, Record};
+        use crate::test::PGTest;
+        use spectral::prelude::*;
+        use std::collections::HashMap;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Duration;
+
+        let pg = PGTest::new("test_challenge_retries").await.unwrap();
+        let db = pg.db();
+        let c = Challenger::new(Some(chrono::Duration::seconds(60)))
+            .with_retries(2, chrono::Duration::seconds(1));
+
+        let mut order = Order::default();
+        order.create(db.clone()).await.unwrap();
+
+        let mut authz = Authorization::default();
+        authz.order_id = order.order_id.clone();
+        authz.identifier = Some("example.com".to_string());
+        authz.create(db.clone()).await.unwrap();
+
+        let mut challenges = Vec::new();
+        for (challenge_type, status) in [
+            (ChallengeType::HTTP01, OrderStatus::Processing),
+            (ChallengeType::DNS01, OrderStatus::Pending),
+        ] {
+            let mut challenge = Challenge::new(
+                order.order_id.clone(),
+                authz.reference.clone(),
+                challenge_type,
+                "example.com".to_string(),
+                "127.0.0.1".to_string(),
+                status,
+            );
+            challenge.create(db.clone()).await.unwrap();
+            challenges.push(challenge);
+        }
+
+        c.schedule(challenges[0].clone()).await;
+
+        async fn load(db: Postgres, order: &Order) -> HashMap<String, Challenge> {
+            let mut client = db.client().await.unwrap();
+            let tx = client.transaction().await.unwrap();
+            order
+                .challenges(&tx)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|ch| (ch.reference.clone(), ch))
+                .collect()
+        }
+
+        let attempts = AtomicUsize::new(0);
+        let fail = |_: Challenge, _: ChallengeEvidence| {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            None
+        };
+
+        // the first failure is recorded, and the challenge is left to be retried.
+        c.tick(fail).await;
+        c.reconcile(db.clone()).await.unwrap();
+
+        let stored = load(db.clone(), &order).await;
+        let first = stored.get(&challenges[0].reference).unwrap();
+        assert_that!(first.status).is_equal_to(OrderStatus::Processing);
+        assert_that!(first.retry_count).is_equal_to(1);
+
+        // the challenge is not attempted again until the backoff has passed.
+        c.tick(|_, _| panic!("attempted during backoff")).await;
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        // the first retry fails, leaving one more.
+        c.tick(fail).await;
+        c.reconcile(db.clone()).await.unwrap();
+
+        let stored = load(db.clone(), &order).await;
+        let first = stored.get(&challenges[0].reference).unwrap();
+        assert_that!(first.status).is_equal_to(OrderStatus::Processing);
+        assert_that!(first.retry_count).is_equal_to(2);
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        c.tick(fail).await;
+        c.reconcile(db.clone()).await.unwrap();
+        assert_that!(c.status_counts().await.is_empty()).is_true();
+
+        // the first attempt and both retries were made, and no more.
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        c.tick(fail).await;
+        assert_that!(attempts.load(Ordering::SeqCst)).is_equal_to(3);
+
+        let stored = load(db.clone(), &order).await;
+        let first = stored.get(&challenges[0].reference).unwrap();
+        assert_that!(first.status).is_equal_to(OrderStatus::Invalid);
+        assert_that!(first.retry_count).is_equal_to(3);
+
+        // the authorization's other challenges fail along with it.
+        let second = stored.get(&challenges[1].reference).unwrap();
+        assert_that!(second.status).is_equal_to(OrderStatus::Invalid);
+        assert_that!(second.retry_count).is_equal_to(0);
+
+        let authz = db.get_authorization(&authz.reference).await.unwrap();
+        assert_that!(authz.version).is_equal_to(1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_reconcile_expires_orders() {
+        use super::Challenger;
+        use crate::acme::handlers::order::{AuthStatus, OrderStatus};
+        use crate::models::order::{Authorization, Order};
+        use crate::models::Record;
+        use crate::test::PGTest;
+        use spectral::prelude::*;
+
+        let pg = PGTest::new("test_reconcile_expires_orders").await.unwrap();
+        let c = Challenger::new(Some(chrono::Duration::seconds(1)));
+        let now = chrono::Local::now();
+
+        let mut expired = Order::default();
+        expired.expires = Some(now - chrono::Duration::seconds(1));
+        expired.create(pg.db()).await.unwrap();
+
+        let mut live = Order::default();
+        live.expires = Some(now + chrono::Duration::hours(1));
+        live.create(pg.db()).await.unwrap();
+
+        let mut stale = Authorization::default();
+        stale.order_id = expired.order_id.clone();
+        stale.identifier = Some("example.com".to_string());
+        stale.expires = now - chrono::Duration::seconds(1);
+        stale.create(pg.db()).await.unwrap();
+
+        let mut fresh = Authorization::default();
+        fresh.order_id = live.order_id.clone();
+        fresh.identifier = Some("example.com".to_string());
+        fresh.create(pg.db()).await.unwrap();
+
+        c.reconcile(pg.db()).await.unwrap();
+
+        let expired = Order::find(expired.id().unwrap().unwrap(), pg.db())
+            .await
+            .unwrap();
+        assert_that!(expired.expired).is_true();
+        assert_that!(expired.status).is_equal_to(OrderStatus::Invalid);
+
+        let live = Order::find(live.id().unwrap().unwrap(), pg.db())
+            .await
+            .unwrap();
+        assert_that!(live.expired).is_false();
+        assert_that!(live.status).is_equal_to(OrderStatus::Pending);
+
+        let stale = pg.db().get_authorization(&stale.reference).await.unwrap();
+        assert_that!(stale.expired).is_true();
+        assert_that!(stale.version).is_equal_to(1);
+        assert_that!(AuthStatus::from_challenges(
+            false,
+            stale.is_expired(),
+            Vec::<OrderStatus>::new().iter()
+        ))
+        .is_equal_to(AuthStatus::Expired);
+
+        let fresh = pg.db().get_authorization(&fresh.reference).await.unwrap();
+        assert_that!(fresh.expired).is_false();
+        assert_that!(fresh.version).is_equal_to(0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_failed_authorizations() {
+        use super::{ChallengeType, Challenger};
+        use crate::acme::handlers::order::OrderStatus;
+        use crate::models::failed_authorization::{record_failed_authorization, FailureReason};
+        use crate::models::order::{Authorization, Challenge, Order};
+        use crate::models::Record;
+        use crate::test::PGTest;
+        use spectral::prelude::*;
+
+        let pg = PGTest::new("test_failed_authorizations").await.unwrap();
+        let db = pg.db();
+        let c = Challenger::new(Some(chrono::Duration::seconds(60)))
+            .with_retries(0, chrono::Duration::zero());
+        let since = chrono::Utc::now() - chrono::Duration::seconds(1);
+        let now = chrono::Local::now();
+
+        let mut order = Order::default();
+        order.account_id = Some(42);
+        order.create(db.clone()).await.unwrap();
+
+        let mut failing = Authorization::default();
+        failing.order_id = order.order_id.clone();
+        failing.identifier = Some("failing.example.com".to_string());
+        failing.create(db.clone()).await.unwrap();
+
+        let mut stale = Authorization::default();
+        stale.order_id = order.order_id.clone();
+        stale.identifier = Some("stale.example.com".to_string());
+        stale.expires = now - chrono::Duration::seconds(1);
+        stale.create(db.clone()).await.unwrap();
+
+        let mut challenge = Challenge::new(
+            order.order_id.clone(),
+            failing.reference.clone(),
+            ChallengeType::DNS01,
+            "failing.example.com".to_string(),
+            "127.0.0.1".to_string(),
+            OrderStatus::Processing,
+        );
+        challenge.create(db.clone()).await.unwrap();
+
+        c.schedule(challenge).await;
+        c.tick(|_, _| None).await;
+        c.reconcile(db.clone()).await.unwrap();
+        // authorizations are only recorded once, even by concurrent attempts.
+        c.reconcile(db.clone()).await.unwrap();
+
+        let attempts = (0..10)
+            .map(|_| {
+                let (db, reference) = (db.clone(), failing.reference.clone());
+                tokio::spawn(async move {
+                    let mut client = db.client().await.unwrap();
+                    let tx = client.transaction().await.unwrap();
+                    record_failed_authorization(
+                        &reference,
+                        Some(ChallengeType::DNS01),
+                        FailureReason::RetriesExhausted,
+                        &tx,
+                    )
+                    .await
+                    .unwrap();
+                    tx.commit().await.unwrap();
+                })
+            })
+            .collect::<Vec<_>>();
+        for attempt in attempts {
+            attempt.await.unwrap();
+        }
+
+        let failed = db.list_failed_authorizations(42, since).await.unwrap();
+        assert_that!(failed.len()).is_equal_to(2);
+
+        let by_reference = |reference: &str| {
+            failed
+                .iter()
+                .find(|f| f.authorization_id == reference)
+                .unwrap()
+                .clone()
+        };
+
+        let f = by_reference(&failing.reference);
+        assert_that!(f.account_id).is_equal_to(Some(42));
+        assert_that!(f.identifier.as_str()).is_equal_to("failing.example.com");
+        assert_that!(f.challenge_type).is_equal_to(Some(ChallengeType::DNS01));
+        assert_that!(f.reason).is_equal_to(FailureReason::RetriesExhausted);
+
+        let f = by_reference(&stale.reference);
+        assert_that!(f.identifier.as_str()).is_equal_to("stale.example.com");
+        assert_that!(f.challenge_type).is_none();
+        assert_that!(f.reason).is_equal_to(FailureReason::Expired);
+
+        assert_that!(db.list_failed_authorizations(43, since).await.unwrap()).is_empty();
+        assert_that!(db
+            .list_failed_authorizations(42, chrono::Utc::now() + chrono::Duration::hours(1))
+            .await
+            .unwrap())
+        .is_empty();
+    }
 }
@@ -15,6 +15,46 @@ impl DNSName {
     pub(crate) fn to_string(&self) -> String {
         self.0.to_string()
     }
+
+    /// validate_hostname checks that the name is a hostname per RFC1123 section 2.1: at least two
+    /// labels of letters, digits and inner hyphens, of at most 63 bytes each and 253 in all. The
+    /// last label may not be all digits, so addresses such as `127.0.0.1` are refused; they are
+    /// requested as IP identifiers. The error says what is wrong with the name.
+    pub(crate) fn validate_hostname(&self) -> Result<(), String> {
+        let name = self.to_string();
+        let name = name.trim_end_matches('.');
+
+        if name.len() > 253 {
+            return Err(format!("{} is longer than 253 bytes", name));
+        }
+
+        let labels = name.split('.').collect::<Vec<&str>>();
+        if labels.len() < 2 {
+            return Err(format!("{} is not a fully qualified name", name));
+        }
+
+        for label in &labels {
+            if label.is_empty() || label.len() > 63 {
+                return Err(format!(
+                    "{} has a label which is not between 1 and 63 bytes long",
+                    name
+                ));
+            }
+
+            if label.starts_with('-')
+                || label.ends_with('-')
+                || !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            {
+                return Err(format!("{} is not a valid hostname", name));
+            }
+        }
+
+        if labels[labels.len() - 1].chars().all(|c| c.is_ascii_digit()) {
+            return Err(format!("{} is an IP address, not a hostname", name));
+        }
+
+        Ok(())
+    }
 }
 
 /// serde codec implementation
@@ -57,6 +97,48 @@ impl<'de> Deserialize<'de> for DNSName {
 }
 
 mod tests {
+    #[test]
+    fn test_validate_hostname() {
+        use super::DNSName;
+        use spectral::prelude::*;
+
+        for name in [
+            "foo.com",
+            "foo.com.",
+            "a-b.example.org",
+            "xn--bcher-kva.example",
+            "1password.com",
+        ] {
+            assert_that!(DNSName::from_str(name).unwrap().validate_hostname())
+                .named(name)
+                .is_ok();
+        }
+
+        let long_label = format!("{}.com", "a".repeat(64));
+        let long_name = format!("{}com", "abcdefghi.".repeat(26));
+        for name in [
+            "com",
+            "localhost",
+            "127.0.0.1",
+            "10.0.0.300",
+            "*.foo.com",
+            "-foo.com",
+            "foo-.com",
+            "foo_bar.com",
+            long_name.as_str(),
+        ] {
+            // some of these are refused by the parser already.
+            if let Ok(parsed) = DNSName::from_str(name) {
+                assert_that!(parsed.validate_hostname())
+                    .named(name)
+                    .is_err();
+            }
+        }
+
+        // names the parser refuses are refused before validation.
+        assert_that!(DNSName::from_str(&long_label).is_err()).is_true();
+    }
+
     #[test]
     fn test_dns_serde() {
         use super::DNSName;
@@ -6,12 +6,19 @@ use url::Url;
 
 use ratpack::prelude::*;
 
-use super::{uri_to_url, HandlerState, ServiceState};
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+
+use super::{request_page, uri_to_url, HandlerState, ServiceState, PAGE_SIZE};
 use crate::{
+    acme::{
+        jose::{ACMEKey, ACMEProtectedHeader, JWS},
+        rate_limit::RateLimitedEndpoint,
+        ACME_EXPECTED_ALGS,
+    },
     errors::{acme::JWSError, ACMEValidationError},
     models::{
-        account::{new_accounts, JWK},
-        Record,
+        account::JWK,
+        audit::{AuditEvent, AuditEventType},
     },
 };
 
@@ -38,7 +45,7 @@ impl Default for Account {
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub enum AccountStatus {
     Valid,
@@ -46,6 +53,45 @@ pub enum AccountStatus {
     Revoked,
 }
 
+/// AcmeAccount is the account object the account endpoints answer with, in the shape of RFC8555
+/// 7.1.2. Unlike [Account], which is also parsed from requests, the fields the server always
+/// provides are not optional here.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AcmeAccount {
+    pub status: AccountStatus,
+    pub contact: Vec<String>,
+    pub terms_of_service_agreed: bool,
+    /// where the account's orders are listed (RFC8555 7.1.2.1).
+    pub orders: Url,
+    /// the binding the account was created with; only echoed in the newAccount response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external_account_binding: Option<ExternalBinding>,
+}
+
+impl AcmeAccount {
+    /// from_record describes a stored account, whose orders list is served below `baseurl`.
+    pub(crate) fn from_record(
+        account: &crate::models::account::Account,
+        status: AccountStatus,
+        baseurl: &Url,
+    ) -> Result<Self, url::ParseError> {
+        Ok(Self {
+            status,
+            contact: account.contacts().to_vec(),
+            terms_of_service_agreed: account.terms_of_service_agreed(),
+            orders: baseurl.join(&format!("orders/{}", account.orders_nonce()))?,
+            external_account_binding: None,
+        })
+    }
+}
+
+/// RFC8555 7.1.2.1
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct OrdersList {
+    pub orders: Vec<Url>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AccountUrl(Url);
 
@@ -86,8 +132,134 @@ impl Into<String> for AccountUrl {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ExternalBinding {}
+/// ExternalBinding is the `externalAccountBinding` field of a newAccount request: a flattened JWS
+/// over the account key, MACed with a credential the client received out of band. RFC8555 7.3.4
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ExternalBinding {
+    protected: String,
+    payload: String,
+    signature: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ExternalBindingHeader {
+    alg: String,
+    kid: String,
+    url: Url,
+    nonce: Option<String>,
+}
+
+impl ExternalBinding {
+    fn header(&self) -> Result<ExternalBindingHeader, ACMEValidationError> {
+        let header: ExternalBindingHeader = serde_json::from_slice(
+            &base64::decode_config(&self.protected, base64::URL_SAFE_NO_PAD)
+                .map_err(|e| ACMEValidationError::ExternalAccountBinding(e.to_string()))?,
+        )
+        .map_err(|e| ACMEValidationError::ExternalAccountBinding(e.to_string()))?;
+
+        if header.nonce.is_some() {
+            return Err(ACMEValidationError::ExternalAccountBinding(
+                "nonce must not be present".to_string(),
+            ));
+        }
+
+        Ok(header)
+    }
+
+    /// verify checks the binding was made for `url` over `account_key` with `hmac_key`.
+    fn verify(
+        &self,
+        url: &Url,
+        account_key: &crate::acme::jose::JWK,
+        hmac_key: &[u8],
+    ) -> Result<(), ACMEValidationError> {
+        let err = |s: &str| ACMEValidationError::ExternalAccountBinding(s.to_string());
+        let header = self.header()?;
+
+        let digest = match header.alg.as_str() {
+            "HS256" => MessageDigest::sha256(),
+            "HS384" => MessageDigest::sha384(),
+            _ => return Err(err("alg must be HS256 or HS384")),
+        };
+
+        if &header.url != url {
+            return Err(err("url does not match the request"));
+        }
+
+        // compare the keys as decoded, so field order and encoding don't matter
+        let bound_key: crate::acme::jose::JWK = serde_json::from_slice(
+            &base64::decode_config(&self.payload, base64::URL_SAFE_NO_PAD)
+                .map_err(|_| err("payload is not valid base64"))?,
+        )
+        .map_err(|_| err("payload is not a JWK"))?;
+
+        if serde_json::to_value(&bound_key).ok() != serde_json::to_value(account_key).ok() {
+            return Err(err("payload does not match the account key"));
+        }
+
+        let signature = base64::decode_config(&self.signature, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| err("signature is not valid base64"))?;
+
+        let mac = (|| {
+            let key = PKey::hmac(hmac_key)?;
+            let mut signer = Signer::new(digest, &key)?;
+            signer.sign_oneshot_to_vec(format!("{}.{}", self.protected, self.payload).as_bytes())
+        })()
+        .map_err(|e| err(&e.to_string()))?;
+
+        if mac.len() != signature.len() || !openssl::memcmp::eq(&mac, &signature) {
+            return Err(ACMEValidationError::InvalidSignature);
+        }
+
+        Ok(())
+    }
+}
+
+/// check_external_account_binding enforces the service's [super::EabPolicy], if any, for a new
+/// account. The key id of the credential it was bound with is returned, to be consumed as the
+/// account is created.
+async fn check_external_account_binding(
+    appstate: &ServiceState,
+    newacct: &NewAccount,
+    protected: &mut ACMEProtectedHeader,
+    url: &Url,
+) -> Result<Option<String>, ACMEValidationError> {
+    let policy = match &appstate.eab_policy {
+        Some(policy) => policy,
+        None => return Ok(None),
+    };
+
+    let eab = match &newacct.external_account_binding {
+        Some(eab) => eab,
+        None if policy.required => {
+            return Err(ACMEValidationError::ExternalAccountBinding(
+                "an external account binding is required".to_string(),
+            ))
+        }
+        None => return Ok(None),
+    };
+
+    let account_key = protected
+        .jwk()
+        .cloned()
+        .ok_or(ACMEValidationError::NoKeyProvided)?;
+    let kid = eab.header()?.kid;
+
+    let credential = match appstate.db.find_eab_credential(&kid).await {
+        Ok(Some(credential)) if !credential.used => credential,
+        Ok(_) => {
+            return Err(ACMEValidationError::ExternalAccountBinding(format!(
+                "unknown or used key id {}",
+                kid
+            )))
+        }
+        Err(e) => return Err(ACMEValidationError::Other(e.to_string())),
+    };
+
+    eab.verify(url, &account_key, &credential.hmac_key)?;
+
+    Ok(Some(kid))
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -102,16 +274,6 @@ impl NewAccount {
     pub fn contacts(&self) -> Option<Vec<AccountUrl>> {
         self.contact.clone()
     }
-
-    pub fn to_account(&self) -> Account {
-        Account {
-            status: AccountStatus::Valid,
-            contact: self.contact.clone(),
-            terms_of_service_agreed: self.terms_of_service_agreed,
-            external_account_binding: None,
-            orders: None, // FIXME needs to be populated with a slug for user orders
-        }
-    }
 }
 
 impl Default for NewAccount {
@@ -139,46 +301,99 @@ pub(crate) async fn new_account(
         Some(mut jws) => {
             let newacct = jws.clone().payload::<NewAccount>()?;
             let uri = req.uri().clone();
-            let url = uri_to_url(appstate.clone().baseurl, uri).await?;
+            let baseurl = appstate.request_baseurl(&req);
+            let url = uri_to_url(baseurl.clone(), uri).await?;
+
+            if let Some(retry_after) = appstate
+                .check_rate_limit(RateLimitedEndpoint::NewAccount, &req, Some(jws.clone()))
+                .await?
+            {
+                let resp = state.rate_limited(
+                    url,
+                    "too many requests; try again later",
+                    Some(retry_after),
+                )?;
+                return Ok((req, Some(resp), state));
+            }
 
-            let protected = jws.protected()?;
+            let mut protected = jws.protected()?;
 
             if protected.kid().is_some() && newacct.only_return_existing.unwrap_or_default() {
                 let rec =
-                    match JWK::find_by_kid(protected.kid().unwrap(), appstate.db.clone()).await {
+                    match JWK::find_by_kid(protected.kid().unwrap(), appstate.request_db(&req))
+                        .await
+                    {
                         Ok(rec) => rec,
                         Err(_) => return Err(ACMEValidationError::AccountDoesNotExist.to_status()),
                     };
 
+                let acct = crate::models::account::Account::find_by_kid(
+                    rec.id.unwrap(),
+                    appstate.request_db(&req),
+                )
+                .await?;
+
                 let resp = state
                     .decorate_response(url.clone(), Response::builder())?
                     .status(StatusCode::OK)
                     .header(
                         "Location",
-                        url.clone()
-                            .join(&format!("./account/{}", &rec.clone().nonce_key()))?
+                        baseurl
+                            .join(&format!("account/{}", &rec.clone().nonce_key()))?
                             .to_string(),
                     )
-                    .body(Body::from(serde_json::to_string(&rec)?))
+                    .body(Body::from(serde_json::to_string(
+                        &AcmeAccount::from_record(&acct, AccountStatus::Valid, &baseurl)?,
+                    )?))
                     .unwrap();
                 return Ok((req, Some(resp), state));
             } else {
-                let mut jwk = jws.into_db_jwk()?;
+                let eab_kid =
+                    match check_external_account_binding(&appstate, &newacct, &mut protected, &url)
+                        .await
+                    {
+                        Ok(eab_kid) => eab_kid,
+                        Err(e) => return Err(e.to_status()),
+                    };
 
-                jwk.create(appstate.db.clone()).await?;
+                let mut jwk = jws.into_db_jwk()?;
+                let event = AuditEvent::new(AuditEventType::AccountCreated).with_detail(
+                    serde_json::json!({
+                        "key": jwk.nonce_key(),
+                        "external_account_binding": newacct.external_account_binding.is_some(),
+                    }),
+                );
 
-                let mut acct = new_accounts(newacct.clone(), jwk.clone(), appstate.db.clone())?;
-                acct.create(appstate.db.clone()).await?;
+                // the credential is used up along with the account, and the audit entry written
+                // with it, or not at all.
+                let acct = match appstate
+                    .request_db(&req)
+                    .create_account(&mut jwk, newacct.clone(), eab_kid.as_deref(), &event)
+                    .await?
+                {
+                    Some(acct) => acct,
+                    None => {
+                        return Err(ACMEValidationError::ExternalAccountBinding(format!(
+                            "unknown or used key id {}",
+                            eab_kid.unwrap_or_default()
+                        ))
+                        .to_status())
+                    }
+                };
 
                 let resp = state
                     .decorate_response(url.clone(), Response::builder())?
                     .status(StatusCode::CREATED)
                     .header(
                         "Location",
-                        url.join(&format!("./account/{}", &jwk.nonce_key()))?
+                        baseurl
+                            .join(&format!("account/{}", &jwk.nonce_key()))?
                             .to_string(),
                     )
-                    .body(Body::from(serde_json::to_string(&newacct.to_account())?))
+                    .body(Body::from(serde_json::to_string(&AcmeAccount {
+                        external_account_binding: newacct.external_account_binding.clone(),
+                        ..AcmeAccount::from_record(&acct, AccountStatus::Valid, &baseurl)?
+                    })?))
                     .unwrap();
                 return Ok((req, Some(resp), state));
             }
@@ -195,7 +410,7 @@ pub(crate) async fn new_account(
 pub(crate) async fn post_account(
     req: Request<Body>,
     _resp: Option<Response<Body>>,
-    _params: Params,
+    params: Params,
     app: App<ServiceState, HandlerState>,
     state: HandlerState,
 ) -> HTTPResult<HandlerState> {
@@ -205,6 +420,47 @@ pub(crate) async fn post_account(
     // FIXME this still needs code to update contact lists; see 7.3.2.
     match state.clone().jws {
         Some(mut jws) => {
+            // RFC8555 7.3: a POST-as-GET to the account URL fetches the account object.
+            if jws.is_post_as_get() {
+                let kid = match jws.protected()?.kid() {
+                    Some(kid) => kid,
+                    None => return Err(ACMEValidationError::NoKeyProvided.to_status()),
+                };
+
+                let db = appstate.request_db(&req);
+                let target = JWK::find_by_kid(kid, db.clone()).await?;
+
+                if &target.nonce_key() != params.get("key_id").unwrap() {
+                    return Err(ratpack::Error::StatusCode(
+                        StatusCode::FORBIDDEN,
+                        "other accounts may not be fetched".to_string(),
+                    ));
+                }
+
+                let account =
+                    crate::models::account::Account::find_by_kid(target.id.unwrap(), db).await?;
+                let baseurl = appstate.request_baseurl(&req);
+                let url = uri_to_url(baseurl.clone(), req.uri().clone()).await?;
+
+                return Ok((
+                    req,
+                    Some(
+                        state
+                            .decorate_response(url.clone(), Response::builder())?
+                            .status(StatusCode::OK)
+                            .body(Body::from(serde_json::to_string(
+                                &AcmeAccount::from_record(
+                                    &account,
+                                    AccountStatus::Valid,
+                                    &baseurl,
+                                )?,
+                            )?))
+                            .unwrap(),
+                    ),
+                    state,
+                ));
+            }
+
             let acct: Account = jws.payload()?;
 
             match acct.status {
@@ -217,7 +473,7 @@ pub(crate) async fn post_account(
                     }
 
                     let kid = kid.unwrap();
-                    let target = JWK::find_by_kid(kid, appstate.db.clone()).await?;
+                    let target = JWK::find_by_kid(kid, appstate.request_db(&req)).await?;
                     let target_jwk: crate::acme::jose::JWK = target.clone().try_into()?;
 
                     match target_jwk.try_into() {
@@ -232,8 +488,24 @@ pub(crate) async fn post_account(
                         Err(e) => return Err(e.into()),
                     }
 
-                    target.delete(appstate.db.clone()).await?;
-                    let url = uri_to_url(appstate.clone().baseurl, req.uri().clone()).await?;
+                    let mut account = crate::models::account::Account::find_by_kid(
+                        target.id.unwrap(),
+                        appstate.request_db(&req),
+                    )
+                    .await?;
+
+                    // deactivation is final: the account's key is removed with it, and
+                    // requests signed by it are refused in handle_jws from then on.
+                    account
+                        .deactivate(
+                            appstate.request_db(&req),
+                            &AuditEvent::new(AuditEventType::AccountDeactivated)
+                                .with_account_id(account.id.unwrap())
+                                .with_detail(serde_json::json!({ "key": target.nonce_key() })),
+                        )
+                        .await?;
+                    let baseurl = appstate.request_baseurl(&req);
+                    let url = uri_to_url(baseurl.clone(), req.uri().clone()).await?;
 
                     return Ok((
                         req,
@@ -241,7 +513,13 @@ pub(crate) async fn post_account(
                             state
                                 .decorate_response(url.clone(), Response::builder())?
                                 .status(StatusCode::OK)
-                                .body(Body::from(serde_json::to_string(&target)?))
+                                .body(Body::from(serde_json::to_string(
+                                    &AcmeAccount::from_record(
+                                        &account,
+                                        AccountStatus::Deactivated,
+                                        &baseurl,
+                                    )?,
+                                )?))
                                 .unwrap(),
                         ),
                         state,
@@ -261,6 +539,237 @@ pub(crate) async fn post_account(
     return Err(ACMEValidationError::InvalidRequest.to_status());
 }
 
+/// account_orders lists the URLs of an account's orders (RFC8555 7.1.2.1), a page at a time; see
+/// [request_page]. The list is only served to the account itself.
+pub(crate) async fn account_orders(
+    req: Request<Body>,
+    _resp: Option<Response<Body>>,
+    params: Params,
+    app: App<ServiceState, HandlerState>,
+    state: HandlerState,
+) -> HTTPResult<HandlerState> {
+    let appstate_opt = app.state().await.clone().unwrap();
+    let appstate = appstate_opt.lock().await;
+
+    let mut jws = match state.clone().jws {
+        Some(jws) => jws,
+        None => return Err(ACMEValidationError::InvalidRequest.to_status()),
+    };
+
+    let kid = match jws.protected()?.kid() {
+        Some(kid) => kid,
+        None => return Err(ACMEValidationError::NoKeyProvided.to_status()),
+    };
+
+    let db = appstate.request_db(&req);
+
+    let account = match crate::models::account::Account::find_by_orders_nonce(
+        params.get("orders_id").unwrap(),
+        db.clone(),
+    )
+    .await
+    {
+        Ok(account) => account,
+        Err(_) => {
+            return Err(ratpack::Error::StatusCode(
+                StatusCode::NOT_FOUND,
+                "orders not found".to_string(),
+            ))
+        }
+    };
+
+    let requester = JWK::find_by_kid(kid, db.clone()).await?;
+    let requester =
+        crate::models::account::Account::find_by_kid(requester.id.unwrap(), db.clone()).await?;
+
+    if requester.id != account.id {
+        return Err(ratpack::Error::StatusCode(
+            StatusCode::FORBIDDEN,
+            "orders of other accounts may not be listed".to_string(),
+        ));
+    }
+
+    let baseurl = appstate.request_baseurl(&req);
+    let url = uri_to_url(baseurl.clone(), req.uri().clone()).await?;
+    let (page, next) = request_page(&url)?;
+
+    let (summaries, more) = db
+        .list_orders_by_account(account.id.unwrap(), page, PAGE_SIZE)
+        .await?;
+
+    let mut orders = Vec::new();
+    for order in summaries {
+        orders.push(baseurl.join(&format!("order/{}", order.order_id))?);
+    }
+
+    let mut builder = state.decorate_response(url.clone(), Response::builder())?;
+
+    // RFC8555 7.1.2.1: the following page, if any, is linked to with rel="next".
+    if more {
+        builder = builder.header("Link", format!(r#"<{}>;rel="next""#, next));
+    }
+
+    Ok((
+        req,
+        Some(
+            builder
+                .status(StatusCode::OK)
+                .body(Body::from(serde_json::to_string(&OrdersList { orders })?))
+                .unwrap(),
+        ),
+        state,
+    ))
+}
+
+/// RFC8555 7.3.5. This is the payload of the inner JWS of a key change.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyChange {
+    account: Url,
+    old_key: crate::acme::jose::JWK,
+}
+
+/// key_change replaces the key of the account signing the outer JWS with the key of the inner
+/// JWS it carries. RFC8555 7.3.5
+pub(crate) async fn key_change(
+    req: Request<Body>,
+    _resp: Option<Response<Body>>,
+    _params: Params,
+    app: App<ServiceState, HandlerState>,
+    state: HandlerState,
+) -> HTTPResult<HandlerState> {
+    let appstate_opt = app.state().await.clone().unwrap();
+    let appstate = appstate_opt.lock().await;
+
+    let mut jws = match state.clone().jws {
+        Some(jws) => jws,
+        None => return Err(ACMEValidationError::InvalidRequest.to_status()),
+    };
+
+    let kid = match jws.protected()?.kid() {
+        Some(kid) => kid,
+        None => return Err(ACMEValidationError::NoKeyProvided.to_status()),
+    };
+
+    let rejected = |s: &str| ACMEValidationError::KeyChange(s.to_string()).to_status();
+
+    let baseurl = appstate.request_baseurl(&req);
+    let url = uri_to_url(baseurl.clone(), req.uri().clone()).await?;
+
+    // the outer JWS was verified against the account key by handle_jws; the inner one is signed
+    // by the new key, which it carries.
+    let mut inner: JWS = jws.payload()?;
+    let mut inner_protected = inner.protected()?;
+
+    if !inner_protected.nonce().is_empty() {
+        return Err(rejected("the inner JWS must not carry a nonce"));
+    }
+
+    if inner_protected.url() != url {
+        return Err(ACMEValidationError::URLNotEqual(
+            url.to_string(),
+            inner_protected.url().to_string(),
+        )
+        .to_status());
+    }
+
+    if !ACME_EXPECTED_ALGS.contains(&inner_protected.alg()) {
+        return Err(ACMEValidationError::AlgNotEqual(
+            ACME_EXPECTED_ALGS.join(", "),
+            inner_protected.alg(),
+        )
+        .to_status());
+    }
+
+    let new_jwk = match inner_protected.jwk() {
+        Some(jwk) => jwk.clone(),
+        None => return Err(ACMEValidationError::NoKeyProvided.to_status()),
+    };
+
+    match inner.verify(ACMEKey::try_from(&mut new_jwk.clone())?) {
+        Ok(true) => {}
+        Ok(false) => return Err(ACMEValidationError::InvalidSignature.to_status()),
+        Err(e) => return Err(e.into()),
+    }
+
+    let change: KeyChange = inner.payload()?;
+    if change.account != kid {
+        return Err(rejected("account does not match the key id of the request"));
+    }
+
+    let target = JWK::find_by_kid(kid, appstate.request_db(&req)).await?;
+    let old_jwk: crate::acme::jose::JWK = target.clone().try_into()?;
+
+    let claimed_old = JWK {
+        n: change.old_key.n.clone(),
+        e: change.old_key.e.clone(),
+        x: change.old_key.x.clone(),
+        y: change.old_key.y.clone(),
+        ..target.clone()
+    };
+    if !target.same_key(&claimed_old) {
+        return Err(rejected("oldKey is not the key of the account"));
+    }
+
+    let replacement = inner.into_db_jwk()?;
+
+    if let Some(existing) = replacement
+        .find_by_public_key(appstate.request_db(&req))
+        .await?
+    {
+        return Ok((
+            req,
+            Some(
+                state
+                    .decorate_response(url.clone(), Response::builder())?
+                    .status(StatusCode::CONFLICT)
+                    .header(
+                        "Location",
+                        baseurl
+                            .join(&format!("account/{}", existing.nonce_key()))?
+                            .to_string(),
+                    )
+                    .body(Body::default())
+                    .unwrap(),
+            ),
+            state,
+        ));
+    }
+
+    let db = appstate.request_db(&req);
+    let account =
+        crate::models::account::Account::find_by_kid(target.id.unwrap(), db.clone()).await?;
+
+    db.rollover_account_key(
+        account.id.unwrap(),
+        target.id.unwrap(),
+        &replacement,
+        &old_jwk.thumbprint()?,
+        &new_jwk.thumbprint()?,
+        &AuditEvent::new(AuditEventType::AccountKeyChanged)
+            .with_account_id(account.id.unwrap())
+            .with_detail(serde_json::json!({
+                "old_key": old_jwk.thumbprint()?,
+                "new_key": new_jwk.thumbprint()?,
+            })),
+    )
+    .await?;
+
+    let updated = AcmeAccount::from_record(&account, AccountStatus::Valid, &baseurl)?;
+
+    Ok((
+        req,
+        Some(
+            state
+                .decorate_response(url.clone(), Response::builder())?
+                .status(StatusCode::OK)
+                .body(Body::from(serde_json::to_string(&updated)?))
+                .unwrap(),
+        ),
+        state,
+    ))
+}
+
 mod tests {
     #[tokio::test(flavor = "multi_thread")]
     async fn new_account_failures() {
@@ -280,6 +789,140 @@ mod tests {
         assert_that!(res.status()).is_equal_to(StatusCode::FORBIDDEN);
     }
 
+    #[test]
+    fn test_external_binding_verify() {
+        use super::ExternalBinding;
+        use crate::acme::jose::JWK;
+        use crate::errors::ACMEValidationError;
+        use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+        use spectral::prelude::*;
+        use url::Url;
+
+        let url = Url::parse("http://127.0.0.1:8000/account").unwrap();
+        let key = b"0123456789abcdef0123456789abcdef";
+
+        let jwk = JWK {
+            alg: None,
+            crv: Some("P-256".to_string()),
+            kty: "EC".to_string(),
+            _use: None,
+            x: Some("x".to_string()),
+            y: Some("y".to_string()),
+            n: None,
+            e: None,
+        };
+
+        let bind = |alg: &str, url: &Url, jwk: &JWK, key: &[u8]| {
+            let protected = base64::encode_config(
+                serde_json::to_string(&serde_json::json!({
+                    "alg": alg,
+                    "kid": "kid-1",
+                    "url": url.to_string(),
+                }))
+                .unwrap(),
+                base64::URL_SAFE_NO_PAD,
+            );
+            let payload =
+                base64::encode_config(serde_json::to_string(jwk).unwrap(), base64::URL_SAFE_NO_PAD);
+
+            let digest = if alg == "HS384" {
+                MessageDigest::sha384()
+            } else {
+                MessageDigest::sha256()
+            };
+            let pkey = PKey::hmac(key).unwrap();
+            let mut signer = Signer::new(digest, &pkey).unwrap();
+            let signature = signer
+                .sign_oneshot_to_vec(format!("{}.{}", protected, payload).as_bytes())
+                .unwrap();
+
+            ExternalBinding {
+                protected,
+                payload,
+                signature: base64::encode_config(signature, base64::URL_SAFE_NO_PAD),
+            }
+        };
+
+        assert_that!(bind("HS256", &url, &jwk, key).verify(&url, &jwk, key)).is_ok();
+        assert_that!(bind("HS384", &url, &jwk, key).verify(&url, &jwk, key)).is_ok();
+        assert_that!(bind("HS256", &url, &jwk, key).header().unwrap().kid)
+            .is_equal_to("kid-1".to_string());
+
+        assert_that!(bind("HS256", &url, &jwk, b"wrong key").verify(&url, &jwk, key))
+            .is_err_containing(ACMEValidationError::InvalidSignature);
+
+        let is_rejected = |res: Result<(), ACMEValidationError>| {
+            matches!(res, Err(ACMEValidationError::ExternalAccountBinding(_)))
+        };
+
+        assert_that!(is_rejected(
+            bind("HS512", &url, &jwk, key).verify(&url, &jwk, key)
+        ))
+        .is_true();
+
+        let other_url = Url::parse("http://127.0.0.1:8000/other").unwrap();
+        assert_that!(is_rejected(
+            bind("HS256", &other_url, &jwk, key).verify(&url, &jwk, key)
+        ))
+        .is_true();
+
+        let other_jwk = JWK {
+            x: Some("other".to_string()),
+            ..jwk.clone()
+        };
+        assert_that!(is_rejected(
+            bind("HS256", &url, &other_jwk, key).verify(&url, &jwk, key)
+        ))
+        .is_true();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn account_register_with_eab() {
+        use crate::acme::handlers::EabPolicy;
+        use crate::test::TestService;
+        use spectral::prelude::*;
+
+        let srv = TestService::new_with_state("account_register_with_eab", |state| {
+            state.with_eab_policy(EabPolicy { required: true })
+        })
+        .await;
+
+        let key = b"0123456789abcdef0123456789abcdef";
+        srv.pg
+            .db()
+            .create_eab_credential("kid-1", key)
+            .await
+            .unwrap();
+
+        let eab = format!(
+            "--eab-kid kid-1 --eab-hmac-key {}",
+            base64::encode_config(key, base64::URL_SAFE_NO_PAD)
+        );
+
+        // no binding
+        let res = srv.clone().run_certbot_register("erik@hollensbe.org").await;
+        assert_that!(res).is_err();
+
+        let res = srv
+            .clone()
+            .certbot(
+                None,
+                format!("register -m 'erik@hollensbe.org' --agree-tos {}", eab),
+            )
+            .await;
+        assert_that!(res).is_ok();
+
+        // credentials are single-use
+        let res = srv
+            .clone()
+            .certbot(
+                None,
+                format!("register -m 'erik@hollensbe.org' --agree-tos {}", eab),
+            )
+            .await;
+        assert_that!(res).is_err();
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn account_register_with_certbot() {
         use crate::test::TestService;
@@ -288,13 +931,7 @@ mod tests {
         let srv = TestService::new("account_register_with_certbot").await;
 
         for _ in 0..10 {
-            let res = srv
-                .clone()
-                .certbot(
-                    None,
-                    "register -m 'erik@hollensbe.org' --agree-tos".to_string(),
-                )
-                .await;
+            let res = srv.clone().run_certbot_register("erik@hollensbe.org").await;
             assert_that!(res).is_ok();
 
             let dir = res.unwrap();
@@ -309,4 +946,466 @@ mod tests {
             assert_that!(res).is_ok();
         }
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn account_key_change() {
+        use super::{AccountStatus, AcmeAccount, OrdersList};
+        use crate::acme::jose::{
+            ACMEPrivateKey, ACMEProtectedHeader, EC_GROUP, EC_GROUP_P384, JWK, JWS,
+        };
+        use crate::test::{jws_alg, TestService};
+        use hyper::StatusCode;
+        use openssl::{
+            ec::EcKey,
+            pkey::{PKey, Private},
+            x509::X509Req,
+        };
+        use serde_json::json;
+        use spectral::prelude::*;
+        use std::convert::TryFrom;
+        use url::Url;
+
+        // the inner JWS of a key change: signed by the new key, and without a nonce.
+        fn key_change(
+            srv: &TestService,
+            new: &EcKey<Private>,
+            old: &EcKey<Private>,
+            kid: &str,
+        ) -> JWS {
+            let protected = ACMEProtectedHeader::new_jwk(
+                JWK::try_from(new).unwrap(),
+                Url::parse(&format!("{}/key-change", srv.url)).unwrap(),
+                String::new(),
+            )
+            .with_alg(jws_alg(new));
+
+            JWS::new(
+                &protected,
+                &json!({"account": kid, "oldKey": JWK::try_from(old).unwrap()}),
+            )
+            .sign(ACMEPrivateKey::ECDSA(new.clone()))
+            .unwrap()
+        }
+
+        let srv = TestService::new("account_key_change").await;
+        let key_change_url = format!("{}/key-change", srv.url);
+
+        let mut nonce = srv.app.head("/nonce").await.headers()[super::super::REPLAY_NONCE_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let old = EcKey::generate(&EC_GROUP).unwrap();
+        let new = EcKey::generate(&EC_GROUP_P384).unwrap();
+
+        let (res, body) = srv
+            .post_jws(
+                &old,
+                None,
+                &mut nonce,
+                &format!("{}/account", srv.url),
+                &json!({"contact": ["mailto:erik@hollensbe.org"], "termsOfServiceAgreed": true}),
+            )
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::CREATED);
+        let kid = res.headers()["Location"].to_str().unwrap().to_string();
+
+        // the account object has the fields of RFC8555 7.1.2, under their names there.
+        for field in ["status", "contact", "termsOfServiceAgreed", "orders"] {
+            assert_that!(body.get(field)).is_some();
+        }
+        assert_that!(body.get("externalAccountBinding")).is_none();
+
+        let account: AcmeAccount = serde_json::from_value(body).unwrap();
+        assert_that!(account.status).is_equal_to(AccountStatus::Valid);
+        assert_that!(account.contact).is_equal_to(vec!["mailto:erik@hollensbe.org".to_string()]);
+        assert_that!(account.terms_of_service_agreed).is_true();
+        assert_that!(account.orders.as_str()).starts_with(&format!("{}/orders/", srv.url));
+        let orders_url = account.orders.to_string();
+
+        // a key change naming another account is rejected as malformed.
+        let other = format!("{}/account/nope", srv.url);
+        let (res, body) = srv
+            .post_jws(
+                &old,
+                Some(&kid),
+                &mut nonce,
+                &key_change_url,
+                &key_change(&srv, &new, &old, &other),
+            )
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::BAD_REQUEST);
+        assert_that!(body["type"]).is_equal_to(json!("urn:ietf:params:acme:error:malformed"));
+
+        // so is one where the inner JWS was not signed by the key it carries.
+        let forged = JWS::new(
+            &ACMEProtectedHeader::new_jwk(
+                JWK::try_from(&new).unwrap(),
+                Url::parse(&key_change_url).unwrap(),
+                String::new(),
+            ),
+            &json!({"account": kid, "oldKey": JWK::try_from(&old).unwrap()}),
+        )
+        .sign(ACMEPrivateKey::ECDSA(old.clone()))
+        .unwrap();
+        let (res, _) = srv
+            .post_jws(&old, Some(&kid), &mut nonce, &key_change_url, &forged)
+            .await;
+        assert_that!(res.status().is_success()).is_false();
+
+        let (res, body) = srv
+            .post_jws(
+                &old,
+                Some(&kid),
+                &mut nonce,
+                &key_change_url,
+                &key_change(&srv, &new, &old, &kid),
+            )
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::OK);
+
+        let updated: AcmeAccount = serde_json::from_value(body).unwrap();
+        assert_that!(updated.orders.to_string()).is_equal_to(orders_url.clone());
+
+        // the account keeps its URL, but the old key no longer signs for it.
+        let (res, _) = srv
+            .post_jws(
+                &old,
+                Some(&kid),
+                &mut nonce,
+                &format!("{}/order", srv.url),
+                &json!({"identifiers": [{"type": "dns", "value": "foo.com"}]}),
+            )
+            .await;
+        assert_that!(res.status().is_success()).is_false();
+
+        // another account cannot take the new key over.
+        let third = EcKey::generate(&EC_GROUP).unwrap();
+        let (res, _) = srv
+            .post_jws(
+                &third,
+                None,
+                &mut nonce,
+                &format!("{}/account", srv.url),
+                &json!({"contact": ["mailto:erik@hollensbe.org"], "termsOfServiceAgreed": true}),
+            )
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::CREATED);
+        let third_kid = res.headers()["Location"].to_str().unwrap().to_string();
+
+        let (res, _) = srv
+            .post_jws(
+                &third,
+                Some(&third_kid),
+                &mut nonce,
+                &key_change_url,
+                &key_change(&srv, &new, &third, &third_kid),
+            )
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::CONFLICT);
+        assert_that!(res.headers()["Location"].to_str().unwrap()).is_equal_to(kid.as_str());
+
+        // and the order flow completes with the new key.
+        let (res, order) = srv
+            .post_jws(
+                &new,
+                Some(&kid),
+                &mut nonce,
+                &format!("{}/order", srv.url),
+                &json!({"identifiers": [{"type": "dns", "value": "foo.com"}]}),
+            )
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::CREATED);
+        let order_url = res.headers()["Location"].to_str().unwrap().to_string();
+
+        for authz in order["authorizations"].as_array().unwrap() {
+            let authz = authz.as_str().unwrap();
+            let (_, body) = srv.post_jws(&new, Some(&kid), &mut nonce, authz, "").await;
+
+            let challenge = body["challenges"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|c| c["type"] == "http-01")
+                .unwrap()
+                .clone();
+
+            let (res, _) = srv
+                .post_jws(
+                    &new,
+                    Some(&kid),
+                    &mut nonce,
+                    challenge["url"].as_str().unwrap(),
+                    &json!({}),
+                )
+                .await;
+            assert_that!(res.status()).is_equal_to(StatusCode::OK);
+
+            loop {
+                let (_, body) = srv.post_jws(&new, Some(&kid), &mut nonce, authz, "").await;
+                if body["status"] == "valid" {
+                    break;
+                }
+
+                srv.wait_for_reconcile().await;
+            }
+        }
+
+        let certkey = PKey::from_ec_key(EcKey::generate(&EC_GROUP).unwrap()).unwrap();
+        let mut name = openssl::x509::X509Name::builder().unwrap();
+        name.append_entry_by_text("CN", "foo.com").unwrap();
+        let mut csr = X509Req::builder().unwrap();
+        csr.set_subject_name(&name.build()).unwrap();
+        let mut extensions = openssl::stack::Stack::new().unwrap();
+        extensions
+            .push(
+                openssl::x509::extension::SubjectAlternativeName::new()
+                    .dns("foo.com")
+                    .build(&csr.x509v3_context(None))
+                    .unwrap(),
+            )
+            .unwrap();
+        csr.add_extensions(&extensions).unwrap();
+        csr.set_pubkey(&certkey).unwrap();
+        csr.sign(&certkey, openssl::hash::MessageDigest::sha256())
+            .unwrap();
+        let csr = base64::encode_config(csr.build().to_der().unwrap(), base64::URL_SAFE_NO_PAD);
+
+        let (res, _) = srv
+            .post_jws(
+                &new,
+                Some(&kid),
+                &mut nonce,
+                order["finalize"].as_str().unwrap(),
+                &json!({ "csr": csr }),
+            )
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::OK);
+
+        let (_, order) = srv
+            .post_jws(&new, Some(&kid), &mut nonce, &order_url, "")
+            .await;
+        assert_that!(order["status"]).is_equal_to(json!("valid"));
+
+        // the order is in the account's orders list, which only the account may fetch.
+        let (res, body) = srv
+            .post_jws(&new, Some(&kid), &mut nonce, &orders_url, "")
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::OK);
+        let list: OrdersList = serde_json::from_value(body).unwrap();
+        assert_that!(list.orders).is_equal_to(vec![Url::parse(&order_url).unwrap()]);
+
+        let (res, _) = srv
+            .post_jws(&third, Some(&third_kid), &mut nonce, &orders_url, "")
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::FORBIDDEN);
+
+        // the rollover was recorded in the account's key history.
+        let db = srv.pg.db();
+        let jwk = crate::models::account::JWK::find_by_kid(Url::parse(&kid).unwrap(), db.clone())
+            .await
+            .unwrap();
+        let account = crate::models::account::Account::find_by_kid(jwk.id.unwrap(), db.clone())
+            .await
+            .unwrap();
+        let history = db.get_key_history(account.id.unwrap()).await.unwrap();
+        assert_that!(history.len()).is_equal_to(1);
+        assert_that!(history[0].old_thumbprint).is_not_equal_to(history[0].new_thumbprint.clone());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn account_deactivate() {
+        use super::{AccountStatus, AcmeAccount};
+        use crate::acme::jose::{ACMEPrivateKey, ACMEProtectedHeader, EC_GROUP, JWS};
+        use crate::test::{jws_alg, TestService};
+        use hyper::StatusCode;
+        use openssl::ec::EcKey;
+        use serde_json::json;
+        use spectral::prelude::*;
+        use url::Url;
+
+        let srv = TestService::new("account_deactivate").await;
+
+        let mut nonce = srv.app.head("/nonce").await.headers()[super::super::REPLAY_NONCE_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let key = EcKey::generate(&EC_GROUP).unwrap();
+        let register =
+            json!({"contact": ["mailto:erik@hollensbe.org"], "termsOfServiceAgreed": true});
+
+        let (res, _) = srv
+            .post_jws(
+                &key,
+                None,
+                &mut nonce,
+                &format!("{}/account", srv.url),
+                &register,
+            )
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::CREATED);
+        let kid = res.headers()["Location"].to_str().unwrap().to_string();
+
+        // deactivate over the wire, as any client would.
+        let jws = JWS::new(
+            &ACMEProtectedHeader::new_kid(
+                Url::parse(&kid).unwrap(),
+                Url::parse(&kid).unwrap(),
+                nonce.clone(),
+            )
+            .with_alg(jws_alg(&key)),
+            &json!({"status": "deactivated"}),
+        )
+        .sign(ACMEPrivateKey::ECDSA(key.clone()))
+        .unwrap();
+
+        let res = reqwest::Client::new()
+            .post(&kid)
+            .header("Content-Type", "application/jose+json")
+            .body(serde_json::to_string(&jws).unwrap())
+            .send()
+            .await
+            .unwrap();
+        assert_that!(res.status().as_u16()).is_equal_to(StatusCode::OK.as_u16());
+        nonce = res.headers()[super::super::REPLAY_NONCE_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let account: AcmeAccount = serde_json::from_slice(&res.bytes().await.unwrap()).unwrap();
+        assert_that!(account.status).is_equal_to(AccountStatus::Deactivated);
+
+        // its key is removed with it.
+        let db = srv.pg.db();
+        let url = Url::parse(&kid).unwrap();
+        assert_that!(crate::models::account::JWK::find_by_kid(url.clone(), db.clone()).await)
+            .is_err();
+        assert_that!(
+            crate::models::account::Account::deactivated_for_kid(&url, db)
+                .await
+                .unwrap()
+        )
+        .is_true();
+
+        // the account can no longer place orders,
+        let (res, _) = srv
+            .post_jws(
+                &key,
+                Some(&kid),
+                &mut nonce,
+                &format!("{}/order", srv.url),
+                &json!({"identifiers": [{"type": "dns", "value": "example.com"}]}),
+            )
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::UNAUTHORIZED);
+
+        // be reactivated,
+        let (res, _) = srv
+            .post_jws(
+                &key,
+                Some(&kid),
+                &mut nonce,
+                &kid,
+                &json!({"status": "valid"}),
+            )
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::UNAUTHORIZED);
+
+        // or be registered again with the same key.
+        let (res, _) = srv
+            .post_jws(
+                &key,
+                None,
+                &mut nonce,
+                &format!("{}/account", srv.url),
+                &register,
+            )
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::UNAUTHORIZED);
+
+        srv.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn account_post_as_get() {
+        use super::{AccountStatus, AcmeAccount};
+        use crate::acme::jose::{ACMEPrivateKey, ACMEProtectedHeader, EC_GROUP, JWS};
+        use crate::test::{jws_alg, TestService};
+        use hyper::StatusCode;
+        use openssl::ec::EcKey;
+        use serde_json::json;
+        use spectral::prelude::*;
+        use url::Url;
+
+        let srv = TestService::new("account_post_as_get").await;
+
+        let mut nonce = srv.app.head("/nonce").await.headers()[super::super::REPLAY_NONCE_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let mut kids = Vec::new();
+        let keys = vec![
+            EcKey::generate(&EC_GROUP).unwrap(),
+            EcKey::generate(&EC_GROUP).unwrap(),
+        ];
+
+        for key in &keys {
+            let (res, _) = srv
+                .post_jws(
+                    key,
+                    None,
+                    &mut nonce,
+                    &format!("{}/account", srv.url),
+                    &json!({"contact": ["mailto:erik@hollensbe.org"], "termsOfServiceAgreed": true}),
+                )
+                .await;
+            assert_that!(res.status()).is_equal_to(StatusCode::CREATED);
+            kids.push(res.headers()["Location"].to_str().unwrap().to_string());
+        }
+
+        let post_as_get =
+            |key: &EcKey<openssl::pkey::Private>, kid: &str, url: &str, nonce: &str| {
+                let jws = JWS::post_as_get(
+                    &ACMEProtectedHeader::new_kid(
+                        Url::parse(kid).unwrap(),
+                        Url::parse(url).unwrap(),
+                        nonce.to_string(),
+                    )
+                    .with_alg(jws_alg(key)),
+                )
+                .sign(ACMEPrivateKey::ECDSA(key.clone()))
+                .unwrap();
+
+                reqwest::Client::new()
+                    .post(url)
+                    .header("Content-Type", "application/jose+json")
+                    .body(serde_json::to_string(&jws).unwrap())
+                    .send()
+            };
+
+        // an empty payload fetches the account object (RFC8555 7.3).
+        let res = post_as_get(&keys[0], &kids[0], &kids[0], &nonce)
+            .await
+            .unwrap();
+        assert_that!(res.status().as_u16()).is_equal_to(StatusCode::OK.as_u16());
+        nonce = res.headers()[super::super::REPLAY_NONCE_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let account: AcmeAccount = serde_json::from_slice(&res.bytes().await.unwrap()).unwrap();
+        assert_that!(account.status).is_equal_to(AccountStatus::Valid);
+        assert_that!(account.contact).is_equal_to(vec!["mailto:erik@hollensbe.org".to_string()]);
+        assert_that!(account.terms_of_service_agreed).is_true();
+
+        // but only that of the account signing the request.
+        let res = post_as_get(&keys[1], &kids[1], &kids[0], &nonce)
+            .await
+            .unwrap();
+        assert_that!(res.status().as_u16()).is_equal_to(StatusCode::FORBIDDEN.as_u16());
+
+        srv.shutdown().await;
+    }
 }
@@ -0,0 +1,583 @@
+// administrative handlers. These are not a part of ACME; they exist for operators investigating
+// the state of the service.
+
+use std::time::Duration;
+
+use openssl::bn::BigNum;
+use ratpack::prelude::*;
+use serde::Serialize;
+
+use super::{
+    bearer_token_matches, request_page, uri_to_url, HandlerState, ServiceState, ACME_CONTENT_TYPE,
+    PAGE_SIZE,
+};
+
+/// how old records must be for [vacuum] to delete them, unless the request says otherwise.
+const DEFAULT_VACUUM_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// VacuumReport is the response of [vacuum]: how many records of each kind were deleted.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub(crate) struct VacuumReport {
+    nonces: u64,
+    orders: u64,
+    authorizations: u64,
+}
+
+/// admin_unauthorized answers requests which do not carry the service's admin token; see
+/// [ServiceState::with_admin_token]. None is returned for those which do.
+fn admin_unauthorized(appstate: &ServiceState, req: &Request<Body>) -> Option<Response<Body>> {
+    let authorized = match &appstate.admin_token {
+        Some(token) => bearer_token_matches(req, token),
+        None => false,
+    };
+
+    if authorized {
+        return None;
+    }
+
+    Some(
+        Response::builder()
+            .header(http::header::WWW_AUTHENTICATE, "Bearer")
+            .status(StatusCode::UNAUTHORIZED)
+            .body(Body::default())
+            .unwrap(),
+    )
+}
+
+/// certificate_order returns the orders which produced the certificate with the hex-encoded
+/// serial number provided in the path. An unknown serial returns an empty list.
+pub(crate) async fn certificate_order(
+    req: Request<Body>,
+    _resp: Option<Response<Body>>,
+    params: Params,
+    app: App<ServiceState, HandlerState>,
+    state: HandlerState,
+) -> HTTPResult<HandlerState> {
+    let appstate_opt = app.state().await.clone().unwrap();
+    let appstate = appstate_opt.lock().await;
+
+    let serial = match BigNum::from_hex_str(params.get("serial").unwrap()) {
+        Ok(serial) => serial.to_vec(),
+        Err(_) => {
+            return Err(ratpack::Error::StatusCode(
+                StatusCode::BAD_REQUEST,
+                "invalid serial".to_string(),
+            ))
+        }
+    };
+
+    let baseurl = appstate.request_baseurl(&req);
+
+    let mut orders = Vec::new();
+    for order in appstate.db.get_orders_for_certificate(&serial).await? {
+        orders.push(order.into_handler_order(baseurl.clone())?);
+    }
+
+    Ok((
+        req,
+        Some(
+            Response::builder()
+                .header("content-type", ACME_CONTENT_TYPE)
+                .status(StatusCode::OK)
+                .body(Body::from(serde_json::to_string(&orders)?))
+                .unwrap(),
+        ),
+        state,
+    ))
+}
+
+/// account_key_history returns the key changes recorded for the account with the id provided in
+/// the path, oldest first.
+pub(crate) async fn account_key_history(
+    req: Request<Body>,
+    _resp: Option<Response<Body>>,
+    params: Params,
+    app: App<ServiceState, HandlerState>,
+    state: HandlerState,
+) -> HTTPResult<HandlerState> {
+    let appstate_opt = app.state().await.clone().unwrap();
+    let appstate = appstate_opt.lock().await;
+
+    let account_id = match params.get("account_id").unwrap().parse::<i32>() {
+        Ok(id) => id,
+        Err(_) => {
+            return Err(ratpack::Error::StatusCode(
+                StatusCode::BAD_REQUEST,
+                "invalid account id".to_string(),
+            ))
+        }
+    };
+
+    let history = appstate.db.get_key_history(account_id).await?;
+
+    Ok((
+        req,
+        Some(
+            Response::builder()
+                .header("content-type", ACME_CONTENT_TYPE)
+                .status(StatusCode::OK)
+                .body(Body::from(serde_json::to_string(&history)?))
+                .unwrap(),
+        ),
+        state,
+    ))
+}
+
+/// list_accounts returns a page of the accounts, oldest first; see [request_page]. Requests must
+/// carry the service's admin token.
+pub(crate) async fn list_accounts(
+    req: Request<Body>,
+    _resp: Option<Response<Body>>,
+    _params: Params,
+    app: App<ServiceState, HandlerState>,
+    state: HandlerState,
+) -> HTTPResult<HandlerState> {
+    let appstate_opt = app.state().await.clone().unwrap();
+    let appstate = appstate_opt.lock().await;
+
+    if let Some(resp) = admin_unauthorized(&appstate, &req) {
+        return Ok((req, Some(resp), state));
+    }
+
+    let url = uri_to_url(appstate.request_baseurl(&req), req.uri().clone()).await?;
+    let (page, next) = request_page(&url)?;
+
+    let (accounts, more) = appstate.db.list_accounts(page, PAGE_SIZE).await?;
+
+    let mut builder = Response::builder().header("content-type", ACME_CONTENT_TYPE);
+    if more {
+        builder = builder.header("Link", format!(r#"<{}>;rel="next""#, next));
+    }
+
+    Ok((
+        req,
+        Some(
+            builder
+                .status(StatusCode::OK)
+                .body(Body::from(serde_json::to_string(&accounts)?))
+                .unwrap(),
+        ),
+        state,
+    ))
+}
+
+/// list_account_orders returns a page of the URLs of the orders of the account with the id
+/// provided in the path, oldest first; see [request_page]. Requests must carry the service's
+/// admin token.
+pub(crate) async fn list_account_orders(
+    req: Request<Body>,
+    _resp: Option<Response<Body>>,
+    params: Params,
+    app: App<ServiceState, HandlerState>,
+    state: HandlerState,
+) -> HTTPResult<HandlerState> {
+    let appstate_opt = app.state().await.clone().unwrap();
+    let appstate = appstate_opt.lock().await;
+
+    if let Some(resp) = admin_unauthorized(&appstate, &req) {
+        return Ok((req, Some(resp), state));
+    }
+
+    let account_id = match params.get("account_id").unwrap().parse::<i32>() {
+        Ok(id) => id,
+        Err(_) => {
+            return Err(ratpack::Error::StatusCode(
+                StatusCode::BAD_REQUEST,
+                "invalid account id".to_string(),
+            ))
+        }
+    };
+
+    let baseurl = appstate.request_baseurl(&req);
+    let url = uri_to_url(baseurl.clone(), req.uri().clone()).await?;
+    let (page, next) = request_page(&url)?;
+
+    let (summaries, more) = appstate
+        .db
+        .list_orders_by_account(account_id, page, PAGE_SIZE)
+        .await?;
+
+    let mut orders = Vec::new();
+    for order in summaries {
+        orders.push(baseurl.join(&format!("order/{}", order.order_id))?);
+    }
+
+    let mut builder = Response::builder().header("content-type", ACME_CONTENT_TYPE);
+    if more {
+        builder = builder.header("Link", format!(r#"<{}>;rel="next""#, next));
+    }
+
+    Ok((
+        req,
+        Some(
+            builder
+                .status(StatusCode::OK)
+                .body(Body::from(serde_json::to_string(&orders)?))
+                .unwrap(),
+        ),
+        state,
+    ))
+}
+
+/// vacuum deletes nonces, and orders and authorizations which expired or failed, created more
+/// than the `older_than` query parameter's seconds ago (by default a week), and responds with a
+/// [VacuumReport]. Requests must carry the service's admin token; see
+/// [ServiceState::with_admin_token].
+pub(crate) async fn vacuum(
+    req: Request<Body>,
+    _resp: Option<Response<Body>>,
+    _params: Params,
+    app: App<ServiceState, HandlerState>,
+    state: HandlerState,
+) -> HTTPResult<HandlerState> {
+    let appstate_opt = app.state().await.clone().unwrap();
+    let appstate = appstate_opt.lock().await;
+
+    if let Some(resp) = admin_unauthorized(&appstate, &req) {
+        return Ok((req, Some(resp), state));
+    }
+
+    let url = uri_to_url(appstate.request_baseurl(&req), req.uri().clone()).await?;
+    let older_than = match url.query_pairs().find(|(k, _)| k == "older_than") {
+        Some((_, v)) => match v.parse::<u64>() {
+            Ok(secs) => Duration::from_secs(secs),
+            Err(_) => {
+                return Err(ratpack::Error::StatusCode(
+                    StatusCode::BAD_REQUEST,
+                    "invalid older_than".to_string(),
+                ))
+            }
+        },
+        None => DEFAULT_VACUUM_AGE,
+    };
+
+    // the service state is not held while vacuuming, as every other request would wait on it.
+    let db = appstate.db.clone();
+    drop(appstate);
+
+    let nonces = db.vacuum_nonces(older_than).await?;
+    let (orders, authorizations) = db.vacuum_orders(older_than).await?;
+    log::info!(
+        "vacuumed {} nonces, {} orders and {} authorizations",
+        nonces,
+        orders,
+        authorizations
+    );
+
+    let report = VacuumReport {
+        nonces,
+        orders,
+        authorizations,
+    };
+
+    Ok((
+        req,
+        Some(
+            Response::builder()
+                .header("content-type", ACME_CONTENT_TYPE)
+                .status(StatusCode::OK)
+                .body(Body::from(serde_json::to_string(&report)?))
+                .unwrap(),
+        ),
+        state,
+    ))
+}
+
+mod tests {
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_account_key_history() {
+        use crate::test::TestService;
+        use http::StatusCode;
+        use spectral::prelude::*;
+
+        let srv = TestService::new("test_account_key_history").await;
+
+        let res = srv.app.get("/admin/accounts/nope/key-history").await;
+        assert_that!(res.status()).is_equal_to(StatusCode::BAD_REQUEST);
+
+        let db = srv.pg.db();
+        db.record_key_rollover(42, "first", "second").await.unwrap();
+        db.record_key_rollover(42, "second", "third").await.unwrap();
+
+        let mut res = srv.app.get("/admin/accounts/42/key-history").await;
+        assert_that!(res.status()).is_equal_to(StatusCode::OK);
+
+        let body = hyper::body::to_bytes(res.body_mut()).await.unwrap();
+        let history: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+        assert_that!(history.len()).is_equal_to(2);
+        assert_that!(history[0]["old_thumbprint"].as_str()).is_equal_to(Some("first"));
+        assert_that!(history[0]["new_thumbprint"].as_str()).is_equal_to(Some("second"));
+        assert_that!(history[1]["old_thumbprint"].as_str()).is_equal_to(Some("second"));
+        assert_that!(history[1]["new_thumbprint"].as_str()).is_equal_to(Some("third"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_certificate_order() {
+        use crate::test::TestService;
+        use http::StatusCode;
+        use openssl::x509::X509;
+        use spectral::prelude::*;
+        use std::sync::Arc;
+        use tempfile::TempDir;
+
+        let srv = TestService::new("test_certificate_order").await;
+
+        let mut res = srv.app.get("/admin/certificates/DEADBEEF/order").await;
+        assert_that!(res.status()).is_equal_to(StatusCode::OK);
+        let body = hyper::body::to_bytes(res.body_mut()).await.unwrap();
+        let orders: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+        assert_that!(orders).is_empty();
+
+        let res = srv.app.get("/admin/certificates/nothex/order").await;
+        assert_that!(res.status()).is_equal_to(StatusCode::BAD_REQUEST);
+
+        let dir = Arc::new(TempDir::new().unwrap());
+
+        let res = srv
+            .clone()
+            .run_certbot_certonly(Some(dir.clone()), "foo.com", "erik@hollensbe.org")
+            .await;
+        assert_that!(res).is_ok();
+
+        let res = srv
+            .clone()
+            .certbot(Some(dir.clone()), "update_symlinks".to_string())
+            .await;
+        assert_that!(res).is_ok();
+
+        let mut path = dir.path().to_path_buf();
+        path.push("live/foo.com/cert.pem");
+
+        let cert = X509::from_pem(&std::fs::read(path).unwrap()).unwrap();
+        let serial = cert.serial_number().to_bn().unwrap().to_hex_str().unwrap();
+
+        let mut res = srv
+            .app
+            .get(&format!("/admin/certificates/{}/order", serial))
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::OK);
+
+        let body = hyper::body::to_bytes(res.body_mut()).await.unwrap();
+        let orders: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+        assert_that!(orders.len()).is_equal_to(1);
+        assert_that!(orders[0]["status"].as_str()).is_equal_to(Some("valid"));
+        assert_that!(orders[0]["identifiers"]).is_equal_to(serde_json::json!([
+            {"type": "dns", "value": "foo.com"}
+        ]));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_list_account_orders() {
+        use crate::models::{
+            account::{Account, JWK},
+            order::Order,
+            Record,
+        };
+        use crate::test::TestService;
+        use http::StatusCode;
+        use spectral::prelude::*;
+
+        let srv = TestService::new_with_state("test_list_account_orders", |state| {
+            state.with_admin_token("s3cret")
+        })
+        .await;
+        let db = srv.pg.db();
+
+        let get = |uri: &str| {
+            srv.app.dispatch(
+                http::Request::get(uri)
+                    .header("Authorization", "Bearer s3cret")
+                    .body(hyper::Body::default())
+                    .unwrap(),
+            )
+        };
+
+        let mut jwk = JWK::new_es256("x".to_string(), "y".to_string());
+        jwk.create(db.clone()).await.unwrap();
+        let mut account = Account::new(jwk.id.unwrap(), vec!["mailto:a@example.com".to_string()]);
+        account.create(db.clone()).await.unwrap();
+
+        let mut created = Vec::new();
+        for _ in 0..15 {
+            let mut order = Order::default();
+            order.account_id = account.id;
+            order.create(db.clone()).await.unwrap();
+            created.push(format!("{}/order/{}", srv.url, order.order_id));
+        }
+
+        let path = format!("/admin/accounts/{}/orders", account.id.unwrap());
+
+        // the listings are only served with the admin token.
+        for uri in [path.as_str(), "/admin/accounts"] {
+            let res = srv.app.get(uri).await;
+            assert_that!(res.status()).is_equal_to(StatusCode::UNAUTHORIZED);
+        }
+
+        let mut res = get(&path).await;
+        assert_that!(res.status()).is_equal_to(StatusCode::OK);
+        let next = res.headers()["Link"].to_str().unwrap().to_string();
+        assert_that!(next).is_equal_to(format!(r#"<{}{}?page=1>;rel="next""#, srv.url, path));
+
+        let body = hyper::body::to_bytes(res.body_mut()).await.unwrap();
+        let orders: Vec<String> = serde_json::from_slice(&body).unwrap();
+        assert_that!(orders).is_equal_to(created[..10].to_vec());
+
+        let mut res = get(&format!("{}?page=1", path)).await;
+        assert_that!(res.status()).is_equal_to(StatusCode::OK);
+        assert_that!(res.headers().get("Link")).is_none();
+
+        let body = hyper::body::to_bytes(res.body_mut()).await.unwrap();
+        let orders: Vec<String> = serde_json::from_slice(&body).unwrap();
+        assert_that!(orders).is_equal_to(created[10..].to_vec());
+
+        // a full last page does not link to an empty one.
+        for _ in 0..5 {
+            let mut order = Order::default();
+            order.account_id = account.id;
+            order.create(db.clone()).await.unwrap();
+        }
+
+        let res = get(&format!("{}?page=1", path)).await;
+        assert_that!(res.status()).is_equal_to(StatusCode::OK);
+        assert_that!(res.headers().get("Link")).is_none();
+
+        let res = get(&format!("{}?page=nope", path)).await;
+        assert_that!(res.status()).is_equal_to(StatusCode::BAD_REQUEST);
+
+        let mut res = get("/admin/accounts").await;
+        assert_that!(res.status()).is_equal_to(StatusCode::OK);
+        assert_that!(res.headers().get("Link")).is_none();
+
+        let body = hyper::body::to_bytes(res.body_mut()).await.unwrap();
+        let accounts: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+        assert_that!(accounts.len()).is_equal_to(1);
+        assert_that!(accounts[0]["id"]).is_equal_to(serde_json::json!(account.id.unwrap()));
+        assert_that!(accounts[0]["contacts"])
+            .is_equal_to(serde_json::json!(["mailto:a@example.com"]));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_vacuum() {
+        use crate::acme::{challenge::ChallengeType, handlers::order::OrderStatus};
+        use crate::models::{
+            order::{Authorization, Challenge, Order},
+            Record,
+        };
+        use crate::test::TestService;
+        use crate::util::make_nonce;
+        use http::StatusCode;
+        use spectral::prelude::*;
+
+        let srv =
+            TestService::new_with_state("test_vacuum", |state| state.with_admin_token("s3cret"))
+                .await;
+        let db = srv.pg.db();
+
+        let stale = (0..100).map(|_| make_nonce(None)).collect::<Vec<String>>();
+        assert_that!(db.insert_nonces(&stale).await.unwrap().len()).is_equal_to(100);
+
+        // an order which expired, and one still in progress.
+        let mut expired = Order::default();
+        expired.create(db.clone()).await.unwrap();
+        let mut pending = Order::default();
+        pending.create(db.clone()).await.unwrap();
+
+        for order in [&expired, &pending] {
+            let mut authz = Authorization::default();
+            authz.order_id = order.order_id.clone();
+            authz.identifier = Some("example.com".to_string());
+            authz.create(db.clone()).await.unwrap();
+
+            let mut challenge = Challenge::new(
+                order.order_id.clone(),
+                authz.reference.clone(),
+                ChallengeType::HTTP01,
+                "example.com".to_string(),
+                "127.0.0.1".to_string(),
+                OrderStatus::Pending,
+            );
+            challenge.create(db.clone()).await.unwrap();
+        }
+
+        let client = db.clone().client().await.unwrap();
+        client
+            .execute(
+                "update nonces set created_at = now() - interval '2 days' where nonce = any($1)",
+                &[&stale],
+            )
+            .await
+            .unwrap();
+        // only the order is old: its authorization and challenge go with it all the same.
+        client
+            .execute(
+                "update orders set expired = true, created_at = now() - interval '2 days' where order_id = $1",
+                &[&expired.order_id],
+            )
+            .await
+            .unwrap();
+
+        let res = srv.app.post("/admin/vacuum", hyper::Body::default()).await;
+        assert_that!(res.status()).is_equal_to(StatusCode::UNAUTHORIZED);
+
+        let req = |token: &str, uri: &str| {
+            http::Request::post(uri)
+                .header("Authorization", format!("Bearer {}", token))
+                .body(hyper::Body::default())
+                .unwrap()
+        };
+
+        let res = srv.app.dispatch(req("wrong", "/admin/vacuum")).await;
+        assert_that!(res.status()).is_equal_to(StatusCode::UNAUTHORIZED);
+
+        let res = srv
+            .app
+            .dispatch(req("s3cret", "/admin/vacuum?older_than=nope"))
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::BAD_REQUEST);
+
+        let mut res = srv
+            .app
+            .dispatch(req("s3cret", "/admin/vacuum?older_than=86400"))
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::OK);
+
+        let body = hyper::body::to_bytes(res.body_mut()).await.unwrap();
+        let report: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_that!(report).is_equal_to(serde_json::json!({
+            "nonces": 100,
+            "orders": 1,
+            "authorizations": 1,
+        }));
+
+        let left: i64 = client
+            .query_one(
+                "select count(*) from nonces where nonce = any($1)",
+                &[&stale],
+            )
+            .await
+            .unwrap()
+            .get(0);
+        assert_that!(left).is_equal_to(0);
+
+        for table in ["orders_authorizations", "orders_challenges"] {
+            let left: i64 = client
+                .query_one(
+                    &*format!("select count(*) from {} where order_id = $1", table),
+                    &[&expired.order_id],
+                )
+                .await
+                .unwrap()
+                .get(0);
+            assert_that!(left).named(table).is_equal_to(0);
+        }
+
+        let challenges: i64 = client
+            .query_one("select count(*) from orders_challenges", &[])
+            .await
+            .unwrap()
+            .get(0);
+        assert_that!(challenges).is_equal_to(1);
+
+        assert_that!(Order::find(pending.id().unwrap().unwrap(), db.clone()).await).is_ok();
+    }
+}
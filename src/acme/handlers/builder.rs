@@ -0,0 +1,354 @@
+// ServiceStateBuilder keeps track of which required fields have been set in its type, so that
+// build() is only available once all of them are.
+
+use std::sync::Arc;
+
+use super::{
+    normalize_rootpath, DomainPolicy, EabPolicy, ServiceState, DEFAULT_AUTHZ_LIFETIME,
+    DEFAULT_ORDER_LIFETIME, DEFAULT_RENEWAL_WINDOW,
+};
+use crate::{
+    acme::{
+        ca::CACollector, challenge::Challenger, metrics::Metrics, rate_limit::RateLimitConfig,
+        rate_limit::RateLimiter, NonceValidator,
+    },
+    models::{Postgres, TenantId},
+};
+
+/// Unset marks a required field of a [ServiceStateBuilder] which has not been set yet.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Unset;
+
+/// Set holds a required field of a [ServiceStateBuilder] once it has been set.
+#[derive(Clone, Debug)]
+pub struct Set<T>(T);
+
+/// ServiceStateBuilder builds a [ServiceState]; see [ServiceState::builder]. The URL, database,
+/// challenger, CA and nonce validator are required, and [ServiceStateBuilder::build] cannot be
+/// called until each of them is set. Everything else may be adjusted afterwards with the
+/// `with_*` methods of [ServiceState].
+pub struct ServiceStateBuilder<U, D, C, A, N> {
+    url: U,
+    basepath: Option<String>,
+    db: D,
+    challenger: C,
+    ca: A,
+    nonces: N,
+    rate_limits: Option<RateLimitConfig>,
+    eab_policy: Option<EabPolicy>,
+    domain_policy: Option<DomainPolicy>,
+    tenant: Option<TenantId>,
+    debug_log_responses: Option<bool>,
+    terms_of_service: Option<url::Url>,
+    website: Option<url::Url>,
+    caa_identities: Vec<String>,
+}
+
+impl Default for ServiceStateBuilder<Unset, Unset, Unset, Unset, Unset> {
+    fn default() -> Self {
+        Self {
+            url: Unset,
+            basepath: None,
+            db: Unset,
+            challenger: Unset,
+            ca: Unset,
+            nonces: Unset,
+            rate_limits: None,
+            eab_policy: None,
+            domain_policy: None,
+            tenant: None,
+            debug_log_responses: None,
+            terms_of_service: None,
+            website: None,
+            caa_identities: Vec::new(),
+        }
+    }
+}
+
+impl<U, D, C, A, N> ServiceStateBuilder<U, D, C, A, N> {
+    /// url sets the URL the service is reachable at, which is used to build the URLs handed to
+    /// clients.
+    pub fn url(self, url: String) -> ServiceStateBuilder<Set<String>, D, C, A, N> {
+        ServiceStateBuilder {
+            url: Set(url),
+            basepath: self.basepath,
+            db: self.db,
+            challenger: self.challenger,
+            ca: self.ca,
+            nonces: self.nonces,
+            rate_limits: self.rate_limits,
+            eab_policy: self.eab_policy,
+            domain_policy: self.domain_policy,
+            tenant: self.tenant,
+            debug_log_responses: self.debug_log_responses,
+            terms_of_service: self.terms_of_service,
+            website: self.website,
+            caa_identities: self.caa_identities,
+        }
+    }
+
+    /// db sets the database the service keeps its records in.
+    pub fn db(self, db: Postgres) -> ServiceStateBuilder<U, Set<Postgres>, C, A, N> {
+        ServiceStateBuilder {
+            url: self.url,
+            basepath: self.basepath,
+            db: Set(db),
+            challenger: self.challenger,
+            ca: self.ca,
+            nonces: self.nonces,
+            rate_limits: self.rate_limits,
+            eab_policy: self.eab_policy,
+            domain_policy: self.domain_policy,
+            tenant: self.tenant,
+            debug_log_responses: self.debug_log_responses,
+            terms_of_service: self.terms_of_service,
+            website: self.website,
+            caa_identities: self.caa_identities,
+        }
+    }
+
+    /// challenger sets the [Challenger] which validates the challenges clients respond to.
+    pub fn challenger(self, c: Challenger) -> ServiceStateBuilder<U, D, Set<Challenger>, A, N> {
+        ServiceStateBuilder {
+            url: self.url,
+            basepath: self.basepath,
+            db: self.db,
+            challenger: Set(c),
+            ca: self.ca,
+            nonces: self.nonces,
+            rate_limits: self.rate_limits,
+            eab_policy: self.eab_policy,
+            domain_policy: self.domain_policy,
+            tenant: self.tenant,
+            debug_log_responses: self.debug_log_responses,
+            terms_of_service: self.terms_of_service,
+            website: self.website,
+            caa_identities: self.caa_identities,
+        }
+    }
+
+    /// ca sets the collector of the CA which signs certificates.
+    pub fn ca(self, ca: CACollector) -> ServiceStateBuilder<U, D, C, Set<CACollector>, N> {
+        ServiceStateBuilder {
+            url: self.url,
+            basepath: self.basepath,
+            db: self.db,
+            challenger: self.challenger,
+            ca: Set(ca),
+            nonces: self.nonces,
+            rate_limits: self.rate_limits,
+            eab_policy: self.eab_policy,
+            domain_policy: self.domain_policy,
+            tenant: self.tenant,
+            debug_log_responses: self.debug_log_responses,
+            terms_of_service: self.terms_of_service,
+            website: self.website,
+            caa_identities: self.caa_identities,
+        }
+    }
+
+    /// nonce_validator sets where nonces are kept, e.g. a [crate::acme::PostgresNonceValidator].
+    pub fn nonce_validator<V>(
+        self,
+        validator: V,
+    ) -> ServiceStateBuilder<U, D, C, A, Set<Arc<dyn NonceValidator + Send + Sync>>>
+    where
+        V: NonceValidator + Send + Sync + 'static,
+    {
+        ServiceStateBuilder {
+            url: self.url,
+            basepath: self.basepath,
+            db: self.db,
+            challenger: self.challenger,
+            ca: self.ca,
+            nonces: Set(Arc::new(validator)),
+            rate_limits: self.rate_limits,
+            eab_policy: self.eab_policy,
+            domain_policy: self.domain_policy,
+            tenant: self.tenant,
+            debug_log_responses: self.debug_log_responses,
+            terms_of_service: self.terms_of_service,
+            website: self.website,
+            caa_identities: self.caa_identities,
+        }
+    }
+
+    /// basepath is the path the service is reachable under, e.g. when it is mounted at a subpath
+    /// of a reverse proxy; it should match the one given to [super::configure_routes]. Without
+    /// it, the path of the URL is used.
+    pub fn basepath(mut self, basepath: Option<&str>) -> Self {
+        self.basepath = basepath.map(str::to_string);
+        self
+    }
+
+    /// rate_limits is [ServiceState::with_rate_limits]; None, the default, does not limit.
+    pub fn rate_limits(mut self, config: Option<RateLimitConfig>) -> Self {
+        self.rate_limits = config;
+        self
+    }
+
+    /// eab_policy is [ServiceState::with_eab_policy]; None, the default, disables external
+    /// account binding.
+    pub fn eab_policy(mut self, policy: Option<EabPolicy>) -> Self {
+        self.eab_policy = policy;
+        self
+    }
+
+    /// domain_policy is [ServiceState::with_domain_policy]; None, the default, permits any name.
+    pub fn domain_policy(mut self, policy: Option<DomainPolicy>) -> Self {
+        self.domain_policy = policy;
+        self
+    }
+
+    /// tenant is [ServiceState::with_tenant]; None serves the default tenant.
+    pub fn tenant(mut self, tenant: Option<TenantId>) -> Self {
+        self.tenant = tenant;
+        self
+    }
+
+    /// debug_log_responses is [ServiceState::with_debug_log_responses]; None leaves it off.
+    pub fn debug_log_responses(mut self, enabled: Option<bool>) -> Self {
+        self.debug_log_responses = enabled;
+        self
+    }
+
+    /// terms_of_service_url is [ServiceState::with_terms_of_service_url]; None, the default,
+    /// leaves it out of the directory.
+    pub fn terms_of_service_url(mut self, url: Option<url::Url>) -> Self {
+        self.terms_of_service = url;
+        self
+    }
+
+    /// website_url is [ServiceState::with_website_url]; None, the default, leaves it out of the
+    /// directory.
+    pub fn website_url(mut self, url: Option<url::Url>) -> Self {
+        self.website = url;
+        self
+    }
+
+    /// caa_identities is [ServiceState::with_caa_identities]; by default, none are advertised.
+    pub fn caa_identities(mut self, identities: Vec<String>) -> Self {
+        self.caa_identities = identities;
+        self
+    }
+}
+
+impl
+    ServiceStateBuilder<
+        Set<String>,
+        Set<Postgres>,
+        Set<Challenger>,
+        Set<CACollector>,
+        Set<Arc<dyn NonceValidator + Send + Sync>>,
+    >
+{
+    /// build the [ServiceState]. It fails if the URL cannot be parsed.
+    pub fn build(self) -> Result<ServiceState, url::ParseError> {
+        let mut baseurl: url::Url = self.url.0.parse()?;
+        let basepath = normalize_rootpath(Some(
+            self.basepath.as_deref().unwrap_or_else(|| baseurl.path()),
+        ));
+        baseurl.set_path(&basepath);
+
+        Ok(ServiceState {
+            baseurl,
+            db: self.db.0,
+            storage: None,
+            c: self.challenger.0,
+            ca: self.ca.0,
+            nonces: self.nonces.0,
+            hostnames: Vec::new(),
+            debug_log_responses: self.debug_log_responses.unwrap_or_default(),
+            account_rate_limit: None,
+            max_certificates_per_account: None,
+            eab_policy: self.eab_policy,
+            domain_policy: self.domain_policy,
+            rate_limiter: self.rate_limits.map(RateLimiter::new),
+            ocsp_responder: false,
+            crl: None,
+            metrics: Metrics::new(Arc::new(prometheus::Registry::new()))
+                .expect("could not register metrics with an empty registry"),
+            metrics_token: None,
+            admin_token: None,
+            health_check: false,
+            debug_endpoints: false,
+            cors: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            order_lifetime: DEFAULT_ORDER_LIFETIME,
+            authz_lifetime: DEFAULT_AUTHZ_LIFETIME,
+            tenant: self.tenant.unwrap_or_default(),
+            order_event_hooks: Vec::new(),
+            renewal_window: DEFAULT_RENEWAL_WINDOW,
+            renewal_explanation_url: None,
+            terms_of_service: self.terms_of_service,
+            website: self.website,
+            caa_identities: self.caa_identities,
+        })
+    }
+}
+
+mod tests {
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_service_state_builder() {
+        use super::super::{EabPolicy, ServiceState};
+        use crate::acme::{
+            ca::CACollector, challenge::Challenger, rate_limit::RateLimitConfig,
+            PostgresNonceValidator,
+        };
+        use crate::models::TenantId;
+        use crate::test::PGTest;
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        let pg = PGTest::new("test_service_state_builder").await.unwrap();
+
+        let state = ServiceState::builder()
+            .url("https://example.com/acme".to_string())
+            .db(pg.db())
+            .challenger(Challenger::new(None))
+            .ca(CACollector::new(Duration::new(0, 250)))
+            .nonce_validator(PostgresNonceValidator::new(pg.db(), None))
+            .build()
+            .unwrap();
+
+        assert_that!(state.baseurl.as_str()).is_equal_to("https://example.com/acme/");
+        assert_that!(state.eab_policy).is_none();
+        assert_that!(state.rate_limiter.is_none()).is_true();
+        assert_that!(state.debug_log_responses).is_false();
+        assert_that!(state.tenant).is_equal_to(TenantId::default());
+        assert_that!(state.terms_of_service).is_none();
+        assert_that!(state.caa_identities).is_empty();
+
+        // required fields may come in any order, and optional ones anywhere in between.
+        let state = ServiceState::builder()
+            .eab_policy(Some(EabPolicy { required: true }))
+            .nonce_validator(PostgresNonceValidator::new(pg.db(), None))
+            .ca(CACollector::new(Duration::new(0, 250)))
+            .rate_limits(Some(RateLimitConfig::default()))
+            .challenger(Challenger::new(None))
+            .basepath(Some("/pki/acme"))
+            .db(pg.db())
+            .debug_log_responses(Some(true))
+            .terms_of_service_url(Some("https://example.com/tos".parse().unwrap()))
+            .url("https://example.com".to_string())
+            .build()
+            .unwrap();
+
+        assert_that!(state.baseurl.as_str()).is_equal_to("https://example.com/pki/acme/");
+        assert_that!(state.eab_policy).is_equal_to(Some(EabPolicy { required: true }));
+        assert_that!(state.rate_limiter.is_some()).is_true();
+        assert_that!(state.debug_log_responses).is_true();
+        assert_that!(state.terms_of_service.map(|u| u.to_string()))
+            .is_equal_to(Some("https://example.com/tos".to_string()));
+
+        let res = ServiceState::builder()
+            .url("not a url".to_string())
+            .db(pg.db())
+            .challenger(Challenger::new(None))
+            .ca(CACollector::new(Duration::new(0, 250)))
+            .nonce_validator(PostgresNonceValidator::new(pg.db(), None))
+            .build();
+        assert_that!(res.is_err()).is_true();
+    }
+}
@@ -0,0 +1,345 @@
+// handlers for distributing the CA's public material.
+
+use ratpack::prelude::*;
+
+use super::{HandlerState, ServiceState};
+
+/// ca_pubkey returns the CA's public key in PEM format.
+pub(crate) async fn ca_pubkey(
+    req: Request<Body>,
+    _resp: Option<Response<Body>>,
+    _params: Params,
+    app: App<ServiceState, HandlerState>,
+    state: HandlerState,
+) -> HTTPResult<HandlerState> {
+    let appstate_opt = app.state().await.clone().unwrap();
+    let appstate = appstate_opt.lock().await;
+
+    let ca = appstate.ca.clone().ca().read().await.clone();
+
+    let pem = match ca {
+        Some(ca) => ca.public_key_pem()?,
+        None => {
+            return Err(ratpack::Error::StatusCode(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "CA is not available yet".to_string(),
+            ))
+        }
+    };
+
+    Ok((
+        req,
+        Some(
+            Response::builder()
+                .header("content-type", "application/x-pem-file")
+                .status(StatusCode::OK)
+                .body(Body::from(pem))
+                .unwrap(),
+        ),
+        state,
+    ))
+}
+
+/// ca_chain returns the CA's certificate chain in PEM format, from the CA issuing certificates
+/// up to the root.
+pub(crate) async fn ca_chain(
+    req: Request<Body>,
+    _resp: Option<Response<Body>>,
+    _params: Params,
+    app: App<ServiceState, HandlerState>,
+    state: HandlerState,
+) -> HTTPResult<HandlerState> {
+    let appstate_opt = app.state().await.clone().unwrap();
+    let appstate = appstate_opt.lock().await;
+
+    let ca = appstate.ca.clone().ca().read().await.clone();
+
+    let pem = match ca {
+        Some(ca) => ca.chain_pem()?,
+        None => {
+            return Err(ratpack::Error::StatusCode(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "CA is not available yet".to_string(),
+            ))
+        }
+    };
+
+    Ok((
+        req,
+        Some(
+            Response::builder()
+                .header("content-type", "application/pem-certificate-chain")
+                .status(StatusCode::OK)
+                .body(Body::from(pem))
+                .unwrap(),
+        ),
+        state,
+    ))
+}
+
+/// crl returns the latest certificate revocation list of the current CA in DER format.
+pub(crate) async fn crl(
+    req: Request<Body>,
+    _resp: Option<Response<Body>>,
+    _params: Params,
+    app: App<ServiceState, HandlerState>,
+    state: HandlerState,
+) -> HTTPResult<HandlerState> {
+    let der = crl_der(app, None).await?;
+    Ok((req, Some(crl_http_response(der)), state))
+}
+
+/// crl_issuer returns the latest certificate revocation list of the CA identified in the path by
+/// the base64url-encoded SHA-1 hash of its key, as in renewal information certIDs. This serves
+/// the CRLs of CAs which were rotated away from, as long as their certificates are valid.
+pub(crate) async fn crl_issuer(
+    req: Request<Body>,
+    _resp: Option<Response<Body>>,
+    params: Params,
+    app: App<ServiceState, HandlerState>,
+    state: HandlerState,
+) -> HTTPResult<HandlerState> {
+    let key_hash =
+        match base64::decode_config(params.get("issuer").unwrap(), base64::URL_SAFE_NO_PAD) {
+            Ok(key_hash) => key_hash,
+            Err(_) => {
+                return Err(ratpack::Error::StatusCode(
+                    StatusCode::BAD_REQUEST,
+                    "invalid issuer".to_string(),
+                ))
+            }
+        };
+
+    let der = crl_der(app, Some(key_hash)).await?;
+    Ok((req, Some(crl_http_response(der)), state))
+}
+
+/// crl_der returns the CRL of the issuer with the key hash provided, or of the current CA.
+async fn crl_der(
+    app: App<ServiceState, HandlerState>,
+    key_hash: Option<Vec<u8>>,
+) -> Result<Vec<u8>, ratpack::Error> {
+    let appstate_opt = app.state().await.clone().unwrap();
+    let appstate = appstate_opt.lock().await;
+
+    let collector = match &appstate.crl {
+        Some(collector) => collector,
+        None => {
+            return Err(ratpack::Error::StatusCode(
+                StatusCode::NOT_FOUND,
+                "CRLs are not published by this service".to_string(),
+            ))
+        }
+    };
+
+    match key_hash {
+        Some(key_hash) => match collector.crl_for_issuer(&key_hash).await {
+            Some(der) => Ok(der),
+            None => Err(ratpack::Error::StatusCode(
+                StatusCode::NOT_FOUND,
+                "no CRL is published for this issuer".to_string(),
+            )),
+        },
+        None => match collector.crl().await {
+            Some(der) => Ok(der),
+            None => Err(ratpack::Error::StatusCode(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "CRL is not available yet".to_string(),
+            )),
+        },
+    }
+}
+
+fn crl_http_response(der: Vec<u8>) -> Response<Body> {
+    Response::builder()
+        .header("content-type", "application/pkix-crl")
+        .status(StatusCode::OK)
+        .body(Body::from(der))
+        .unwrap()
+}
+
+mod tests {
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_ca_pubkey() {
+        use crate::test::TestService;
+        use http::StatusCode;
+        use openssl::pkey::{Id, PKey};
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        let srv = TestService::new("test_ca_pubkey").await;
+
+        // give the collector a chance to load the CA
+        tokio::time::sleep(Duration::new(1, 0)).await;
+
+        let mut res = srv.app.get("/ca-pubkey").await;
+        assert_that!(res.status()).is_equal_to(StatusCode::OK);
+
+        let body = hyper::body::to_bytes(res.body_mut()).await.unwrap();
+        let key = PKey::public_key_from_pem(&body).unwrap();
+        assert_that!(key.id()).is_equal_to(Id::RSA);
+        assert_that!(key.bits()).is_equal_to(4096);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_ca_chain() {
+        use crate::test::TestService;
+        use http::StatusCode;
+        use openssl::x509::X509;
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        let srv = TestService::new_with_intermediate_ca("test_ca_chain").await;
+
+        // give the collector a chance to load the CA
+        tokio::time::sleep(Duration::new(1, 0)).await;
+
+        let mut res = srv.app.get("/ca-chain").await;
+        assert_that!(res.status()).is_equal_to(StatusCode::OK);
+
+        let body = hyper::body::to_bytes(res.body_mut()).await.unwrap();
+        let chain = X509::stack_from_pem(&body).unwrap();
+        assert_that!(chain.len()).is_equal_to(2);
+
+        // the intermediate first, then the root which issued it.
+        assert_that!(chain[0].issuer_name().to_der().unwrap())
+            .is_equal_to(chain[1].subject_name().to_der().unwrap());
+        assert_that!(chain[1].issuer_name().to_der().unwrap())
+            .is_equal_to(chain[1].subject_name().to_der().unwrap());
+        assert_that!(chain[0].verify(&chain[1].public_key().unwrap()).unwrap()).is_true();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_crl() {
+        use crate::{acme::ca::CRLCollector, test::TestService};
+        use http::StatusCode;
+        use openssl::x509::X509Crl;
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        let collector = CRLCollector::new(Duration::from_millis(250), Duration::from_secs(3600));
+        let c2 = collector.clone();
+
+        let srv = TestService::new_with_state("test_crl", |state| state.with_crl(c2)).await;
+
+        let res = srv.app.get("/crl").await;
+        assert_that!(res.status()).is_equal_to(StatusCode::SERVICE_UNAVAILABLE);
+
+        srv.pg
+            .db()
+            .record_revocation(&[0xca, 0xfe], 1)
+            .await
+            .unwrap();
+
+        let (ca, db) = (srv.ca.clone(), srv.pg.db());
+        tokio::spawn(async move {
+            collector
+                .spawn_collector(ca, || db.list_revoked_certificates())
+                .await
+        });
+
+        // give the collectors a chance to load the CA and generate the CRL
+        tokio::time::sleep(Duration::new(1, 0)).await;
+
+        let mut res = srv.app.get("/crl").await;
+        assert_that!(res.status()).is_equal_to(StatusCode::OK);
+
+        let body = hyper::body::to_bytes(res.body_mut()).await.unwrap();
+        let crl = X509Crl::from_der(&body).unwrap();
+        let serials = crl
+            .get_revoked()
+            .unwrap()
+            .iter()
+            .map(|r| r.serial_number().to_bn().unwrap().to_vec())
+            .collect::<Vec<Vec<u8>>>();
+        assert_that!(serials).is_equal_to(vec![vec![0xca, 0xfe]]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_crl_after_rotation() {
+        use crate::acme::ca::{key_hash, CRLCollector, SigningAlgorithm, CA};
+        use crate::test::TestService;
+        use http::StatusCode;
+        use openssl::{
+            hash::MessageDigest,
+            x509::{X509Crl, X509},
+        };
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        let collector = CRLCollector::new(Duration::from_secs(3600), Duration::from_secs(3600));
+        let c2 = collector.clone();
+
+        let srv =
+            TestService::new_with_state("test_crl_after_rotation", |state| state.with_crl(c2))
+                .await;
+
+        let dir = srv
+            .clone()
+            .run_certbot_certonly(None, "foo.com", "erik@hollensbe.org")
+            .await
+            .unwrap();
+
+        let mut live = dir.path().to_path_buf();
+        live.push("live/foo.com");
+
+        let cert = X509::from_pem(&std::fs::read(live.join("cert.pem")).unwrap()).unwrap();
+        let issuer = X509::from_pem(&std::fs::read(live.join("chain.pem")).unwrap()).unwrap();
+        let serial = cert.serial_number().to_bn().unwrap().to_vec();
+
+        let revoke = "revoke --cert-path /etc/letsencrypt/live/foo.com/cert.pem --reason keycompromise --no-delete-after-revoke".to_string();
+        assert_that!(srv.clone().certbot(Some(dir.clone()), revoke).await).is_ok();
+
+        let rotated = CA::new_test_ca_with_algorithm(SigningAlgorithm::EcdsaP256).unwrap();
+        srv.rotate_ca(rotated.clone(), vec![]).await;
+
+        collector
+            .refresh(
+                srv.ca.clone(),
+                &srv.pg.db().list_revoked_certificates().await.unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let crl = |path: String| {
+            let app = srv.app.clone();
+            async move {
+                let mut res = app.get(&path).await;
+                assert_that!(res.status()).is_equal_to(StatusCode::OK);
+                X509Crl::from_der(&hyper::body::to_bytes(res.body_mut()).await.unwrap()).unwrap()
+            }
+        };
+        let serials = |crl: &X509Crl| {
+            crl.get_revoked()
+                .map(|revoked| {
+                    revoked
+                        .iter()
+                        .map(|r| r.serial_number().to_bn().unwrap().to_vec())
+                        .collect::<Vec<Vec<u8>>>()
+                })
+                .unwrap_or_default()
+        };
+
+        // the CA which issued the certificate still publishes its revocation.
+        let old_hash = base64::encode_config(
+            key_hash(&issuer, MessageDigest::sha1()).unwrap(),
+            base64::URL_SAFE_NO_PAD,
+        );
+        let old = crl(format!("/crl/{}", old_hash)).await;
+        assert_that!(old.verify(&issuer.public_key().unwrap()).unwrap()).is_true();
+        assert_that!(serials(&old)).is_equal_to(vec![serial]);
+
+        // the current CA's CRL only lists what it issued.
+        let current = crl("/crl".to_string()).await;
+        assert_that!(current
+            .verify(&rotated.clone().certificate().public_key().unwrap())
+            .unwrap())
+        .is_true();
+        assert_that!(serials(&current)).is_empty();
+
+        let res = srv.app.get("/crl/AAAA").await;
+        assert_that!(res.status()).is_equal_to(StatusCode::NOT_FOUND);
+
+        srv.shutdown().await;
+    }
+}
@@ -0,0 +1,244 @@
+// CORS support, for ACME clients running in a browser. This is not a part of ACME.
+
+use http::HeaderValue;
+use ratpack::prelude::*;
+
+use super::{HandlerState, ServiceState};
+
+/// the methods the ACME API is served with.
+const ALLOWED_METHODS: &str = "GET, HEAD, POST";
+/// the request headers browsers may send; ACME requests are `application/jose+json`, which
+/// makes browsers ask first.
+const ALLOWED_HEADERS: &str = "Content-Type";
+/// the response headers scripts may read; clients need the nonce and the URLs in them.
+const EXPOSED_HEADERS: &str = "Replay-Nonce, Location, Link, Retry-After, X-Request-ID";
+
+/// CorsConfig allows browser-based clients on the listed origins to use the ACME API; see
+/// [ServiceState::with_cors].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CorsConfig {
+    /// origins such as `https://example.com`, as browsers send them in the `Origin` header. `*`
+    /// allows any origin.
+    pub allowed_origins: Vec<String>,
+    /// how long browsers may cache the answer to a preflight request.
+    pub max_age_seconds: u32,
+}
+
+impl CorsConfig {
+    /// allows reports whether requests from `origin` may be answered with CORS headers.
+    pub fn allows(&self, origin: &str) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|allowed| allowed == "*" || allowed == origin)
+    }
+}
+
+/// handle_cors records the request's `Origin` if CORS is enabled and it is allowed, so that
+/// [super::log_response] can add `Access-Control-Allow-Origin` to the response.
+pub(crate) async fn handle_cors(
+    req: Request<Body>,
+    _resp: Option<Response<Body>>,
+    _params: Params,
+    app: App<ServiceState, HandlerState>,
+    mut state: HandlerState,
+) -> HTTPResult<HandlerState> {
+    let origin = match req.headers().get("Origin").map(|o| o.to_str()) {
+        Some(Ok(origin)) => origin.to_string(),
+        _ => return Ok((req, None, state)),
+    };
+
+    let appstate_opt = app.state().await.unwrap();
+    let appstate = appstate_opt.lock().await;
+
+    if appstate
+        .cors
+        .as_ref()
+        .map_or(false, |cors| cors.allows(&origin))
+    {
+        state.cors_origin = Some(origin);
+    }
+
+    Ok((req, None, state))
+}
+
+/// cors_preflight answers the `OPTIONS` requests browsers send before a cross-origin request. It
+/// is only served when CORS is enabled with [ServiceState::with_cors]; origins which are not
+/// allowed get an answer without CORS headers, which browsers take as a refusal.
+pub(crate) async fn cors_preflight(
+    req: Request<Body>,
+    _resp: Option<Response<Body>>,
+    _params: Params,
+    app: App<ServiceState, HandlerState>,
+    state: HandlerState,
+) -> HTTPResult<HandlerState> {
+    let appstate_opt = app.state().await.unwrap();
+    let appstate = appstate_opt.lock().await;
+
+    let cors = match &appstate.cors {
+        Some(cors) => cors,
+        None => {
+            return Err(ratpack::Error::StatusCode(
+                StatusCode::NOT_FOUND,
+                "CORS is not enabled".to_string(),
+            ))
+        }
+    };
+
+    let mut builder = Response::builder().status(StatusCode::NO_CONTENT);
+
+    if state.cors_origin.is_some() {
+        builder = builder
+            .header("Access-Control-Allow-Methods", ALLOWED_METHODS)
+            .header("Access-Control-Allow-Headers", ALLOWED_HEADERS)
+            .header("Access-Control-Max-Age", cors.max_age_seconds.to_string());
+    }
+
+    Ok((req, Some(builder.body(Body::empty()).unwrap()), state))
+}
+
+/// add_cors_headers allows the request's origin, recorded by [handle_cors], to read the response.
+pub(crate) fn add_cors_headers(state: &HandlerState, resp: &mut Response<Body>) {
+    let origin = match state
+        .cors_origin
+        .as_ref()
+        .and_then(|origin| HeaderValue::from_str(origin).ok())
+    {
+        Some(origin) => origin,
+        None => return,
+    };
+
+    let headers = resp.headers_mut();
+    headers.insert("Access-Control-Allow-Origin", origin);
+    headers.insert("Vary", HeaderValue::from_static("Origin"));
+    headers.insert(
+        "Access-Control-Expose-Headers",
+        HeaderValue::from_static(EXPOSED_HEADERS),
+    );
+}
+
+mod tests {
+    #[test]
+    fn test_cors_config_allows() {
+        use super::CorsConfig;
+        use spectral::prelude::*;
+
+        let cors = CorsConfig {
+            allowed_origins: vec!["https://example.com".to_string()],
+            max_age_seconds: 600,
+        };
+        assert_that!(cors.allows("https://example.com")).is_true();
+        assert_that!(cors.allows("https://example.org")).is_false();
+        assert_that!(cors.allows("http://example.com")).is_false();
+
+        let any = CorsConfig {
+            allowed_origins: vec!["*".to_string()],
+            max_age_seconds: 600,
+        };
+        assert_that!(any.allows("https://example.org")).is_true();
+        assert_that!(CorsConfig::default().allows("https://example.com")).is_false();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cors() {
+        use super::CorsConfig;
+        use crate::test::TestService;
+        use http::{Method, Request, StatusCode};
+        use hyper::Body;
+        use spectral::prelude::*;
+
+        let request = |method: Method, path: &str, origin: &str| {
+            Request::builder()
+                .method(method)
+                .uri(path)
+                .header("Origin", origin)
+                .header("Access-Control-Request-Method", "POST")
+                .body(Body::default())
+                .unwrap()
+        };
+
+        // without a configuration, there is no CORS.
+        let srv = TestService::new("test_cors_disabled").await;
+        let res = srv
+            .app
+            .dispatch(request(Method::OPTIONS, "/order", "https://example.com"))
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::NOT_FOUND);
+        let res = srv
+            .app
+            .dispatch(request(Method::HEAD, "/nonce", "https://example.com"))
+            .await;
+        assert_that!(res.headers().get("Access-Control-Allow-Origin")).is_none();
+
+        let srv = TestService::new_with_state("test_cors", |state| {
+            state.with_cors(CorsConfig {
+                allowed_origins: vec!["https://example.com".to_string()],
+                max_age_seconds: 600,
+            })
+        })
+        .await;
+
+        for path in ["/", "/nonce", "/account", "/order", "/order/foo/finalize"] {
+            let res = srv
+                .app
+                .dispatch(request(Method::OPTIONS, path, "https://example.com"))
+                .await;
+            assert_that!(res.status()).is_equal_to(StatusCode::NO_CONTENT);
+
+            let headers = res.headers();
+            assert_that!(headers["Access-Control-Allow-Origin"].to_str().unwrap())
+                .is_equal_to("https://example.com");
+            assert_that!(headers["Access-Control-Allow-Methods"].to_str().unwrap())
+                .is_equal_to("GET, HEAD, POST");
+            assert_that!(headers["Access-Control-Allow-Headers"].to_str().unwrap())
+                .is_equal_to("Content-Type");
+            assert_that!(headers["Access-Control-Max-Age"].to_str().unwrap()).is_equal_to("600");
+        }
+
+        let res = srv
+            .app
+            .dispatch(request(Method::OPTIONS, "/order", "https://example.org"))
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::NO_CONTENT);
+        assert_that!(res.headers().get("Access-Control-Allow-Origin")).is_none();
+        assert_that!(res.headers().get("Access-Control-Allow-Methods")).is_none();
+
+        let res = srv
+            .app
+            .dispatch(request(Method::HEAD, "/nonce", "https://example.com"))
+            .await;
+        assert_that!(res.headers()["Access-Control-Allow-Origin"]
+            .to_str()
+            .unwrap())
+        .is_equal_to("https://example.com");
+        assert_that!(res.headers()["Access-Control-Expose-Headers"]
+            .to_str()
+            .unwrap()
+            .contains("Replay-Nonce"))
+        .is_true();
+
+        let res = srv
+            .app
+            .dispatch(request(Method::HEAD, "/nonce", "https://example.org"))
+            .await;
+        assert_that!(res.headers().get("Access-Control-Allow-Origin")).is_none();
+
+        // error responses may be read as well, so that clients can act on problem documents.
+        let res = srv
+            .app
+            .dispatch(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/order")
+                    .header("Origin", "https://example.com")
+                    .header("Content-Type", "application/jose+json")
+                    .body(Body::from("garbage"))
+                    .unwrap(),
+            )
+            .await;
+        assert_that!(res.status().is_client_error()).is_true();
+        assert_that!(res.headers()["Access-Control-Allow-Origin"]
+            .to_str()
+            .unwrap())
+        .is_equal_to("https://example.com");
+    }
+}
@@ -0,0 +1,175 @@
+// debugging handlers. These expose internal runtime state and are only compiled into debug builds.
+
+use std::collections::HashMap;
+
+use ratpack::prelude::*;
+use serde::Serialize;
+
+use super::{HandlerState, ServiceState, ACME_CONTENT_TYPE};
+use crate::{acme::challenge::AuthorizationSummary, models::PoolStats};
+
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct CAState {
+    fingerprint: String,
+    not_after: String,
+}
+
+/// DebugState is the snapshot returned by the `/debug/state` endpoint.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct DebugState {
+    ca: Option<CAState>,
+    nonces: i64,
+    challenges: HashMap<String, usize>,
+    orders: HashMap<String, usize>,
+    pool: PoolStats,
+}
+
+/// debug_state returns a JSON snapshot of the service's runtime state.
+pub(crate) async fn debug_state(
+    req: Request<Body>,
+    _resp: Option<Response<Body>>,
+    _params: Params,
+    app: App<ServiceState, HandlerState>,
+    state: HandlerState,
+) -> HTTPResult<HandlerState> {
+    let appstate_opt = app.state().await.clone().unwrap();
+    let appstate = appstate_opt.lock().await;
+
+    let ca = appstate
+        .ca
+        .certificate_info()
+        .await?
+        .map(|(fingerprint, not_after)| CAState {
+            fingerprint,
+            not_after,
+        });
+
+    let challenges = appstate.c.status_counts().await;
+    let pool = appstate.db.pool_stats();
+
+    // the database is queried without holding the service state, as that would stall every
+    // other request until it answered.
+    let db = appstate.request_db(&req);
+    drop(appstate);
+
+    let debug = DebugState {
+        ca,
+        nonces: db.nonce_count().await?,
+        challenges,
+        orders: db.order_status_counts().await?,
+        pool,
+    };
+
+    Ok((
+        req,
+        Some(
+            Response::builder()
+                .header("content-type", ACME_CONTENT_TYPE)
+                .status(StatusCode::OK)
+                .body(Body::from(serde_json::to_string(&debug)?))
+                .unwrap(),
+        ),
+        state,
+    ))
+}
+
+/// ChallengerState is the snapshot returned by the `/debug/challenger` endpoint.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct ChallengerState {
+    pending: usize,
+    authorizations: Vec<AuthorizationSummary>,
+}
+
+/// debug_challenger returns a JSON snapshot of the challenges waiting to be validated. It must be
+/// enabled with [ServiceState::with_debug_endpoints].
+pub(crate) async fn debug_challenger(
+    req: Request<Body>,
+    _resp: Option<Response<Body>>,
+    _params: Params,
+    app: App<ServiceState, HandlerState>,
+    state: HandlerState,
+) -> HTTPResult<HandlerState> {
+    let appstate_opt = app.state().await.clone().unwrap();
+    let appstate = appstate_opt.lock().await;
+
+    if !appstate.debug_endpoints {
+        return Err(ratpack::Error::StatusCode(
+            StatusCode::NOT_FOUND,
+            "debug endpoints are not enabled".to_string(),
+        ));
+    }
+
+    let debug = ChallengerState {
+        pending: appstate.c.pending_count().await,
+        authorizations: appstate.c.active_authorizations().await,
+    };
+
+    Ok((
+        req,
+        Some(
+            Response::builder()
+                .header("content-type", ACME_CONTENT_TYPE)
+                .status(StatusCode::OK)
+                .body(Body::from(serde_json::to_string(&debug)?))
+                .unwrap(),
+        ),
+        state,
+    ))
+}
+
+mod tests {
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_debug_state() {
+        use crate::test::TestService;
+        use http::StatusCode;
+        use spectral::prelude::*;
+        use std::sync::Arc;
+        use tempfile::TempDir;
+
+        let srv = TestService::new("test_debug_state").await;
+
+        let dir = Arc::new(TempDir::new().unwrap());
+
+        let res = srv
+            .clone()
+            .run_certbot_certonly(Some(dir.clone()), "foo.com", "erik@hollensbe.org")
+            .await;
+        assert_that!(res).is_ok();
+
+        let mut res = srv.app.get("/debug/state").await;
+        assert_that!(res.status()).is_equal_to(StatusCode::OK);
+
+        let body = hyper::body::to_bytes(res.body_mut()).await.unwrap();
+        let state: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_that!(state["orders"]["valid"].as_u64()).is_equal_to(Some(1));
+        assert_that!(state["ca"]["fingerprint"].as_str().unwrap().len()).is_equal_to(64);
+        assert_that!(state["pool"]["max_size"].as_u64()).is_some();
+        assert_that!(state["nonces"].as_i64()).is_some();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_debug_challenger() {
+        use crate::test::TestService;
+        use http::StatusCode;
+        use spectral::prelude::*;
+
+        let srv = TestService::new("test_debug_challenger").await;
+        let res = srv.app.get("/debug/challenger").await;
+        assert_that!(res.status()).is_equal_to(StatusCode::NOT_FOUND);
+
+        let srv = TestService::new_with_state("test_debug_challenger_enabled", |state| {
+            state.with_debug_endpoints(true)
+        })
+        .await;
+
+        let mut res = srv.app.get("/debug/challenger").await;
+        assert_that!(res.status()).is_equal_to(StatusCode::OK);
+
+        let body = hyper::body::to_bytes(res.body_mut()).await.unwrap();
+        let state: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_that!(state["pending"].as_u64()).is_equal_to(Some(0));
+        assert_that!(state["authorizations"].as_array().unwrap()).is_empty();
+    }
+}
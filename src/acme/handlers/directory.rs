@@ -1,4 +1,4 @@
-use super::{uri_to_url, HandlerState, ServiceState, REPLAY_NONCE_HEADER};
+use super::{HandlerState, ServiceState, REPLAY_NONCE_HEADER};
 use ratpack::prelude::*;
 use serde::{Deserialize, Serialize};
 
@@ -14,6 +14,9 @@ pub struct DirectoryMeta {
     caa_identities: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     external_account_required: Option<bool>,
+    /// where the CA's certificate chain can be fetched; not a part of RFC8555.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ca_chain: Option<url::Url>,
 }
 
 impl Default for DirectoryMeta {
@@ -23,6 +26,7 @@ impl Default for DirectoryMeta {
             website: None,
             caa_identities: None,
             external_account_required: None,
+            ca_chain: None,
         }
     }
 }
@@ -37,6 +41,8 @@ pub struct Directory {
     new_authz: url::Url,
     revoke_cert: url::Url,
     key_change: url::Url,
+    /// where renewal information is served; see draft-ietf-acme-ari.
+    renewal_info: url::Url,
     #[serde(skip_serializing_if = "Option::is_none")]
     meta: Option<DirectoryMeta>,
 }
@@ -48,17 +54,37 @@ pub(crate) async fn directory(
     app: App<ServiceState, HandlerState>,
     state: HandlerState,
 ) -> HTTPResult<HandlerState> {
-    let uri = req.uri().clone();
-    let url = uri_to_url(app.state().await.unwrap().lock().await.baseurl.clone(), uri).await?;
+    let appstate_opt = app.state().await.unwrap();
+    let appstate = appstate_opt.lock().await;
+    // the directory is served at the base URL, so all of its URLs are relative to it.
+    let url = appstate.request_baseurl(&req);
+
+    let external_account_required = match &appstate.eab_policy {
+        Some(policy) if policy.required => Some(true),
+        _ => None,
+    };
+
+    let meta = Some(DirectoryMeta {
+        terms_of_service: appstate.terms_of_service.as_ref().map(url::Url::to_string),
+        website: appstate.website.as_ref().map(url::Url::to_string),
+        caa_identities: match appstate.caa_identities.is_empty() {
+            true => None,
+            false => Some(appstate.caa_identities.clone()),
+        },
+        external_account_required,
+        ca_chain: Some(url.join("ca-chain")?),
+    });
+    drop(appstate);
 
     let dir = Directory {
-        new_nonce: url.join("./nonce")?,
-        new_account: url.join("./account")?,
-        new_order: url.join("./order")?,
-        new_authz: url.join("./authz")?,
-        revoke_cert: url.join("./revoke")?,
-        key_change: url.join("./key")?,
-        meta: None,
+        new_nonce: url.join("nonce")?,
+        new_account: url.join("account")?,
+        new_order: url.join("order")?,
+        new_authz: url.join("authz")?,
+        revoke_cert: url.join("revoke")?,
+        key_change: url.join("key-change")?,
+        renewal_info: url.join("renewal-info")?,
+        meta,
     };
 
     Ok((
@@ -78,7 +104,7 @@ pub(crate) async fn directory(
 mod tests {
     #[tokio::test(flavor = "multi_thread")]
     async fn test_basic_directory() {
-        use super::{super::*, Directory};
+        use super::{super::*, Directory, DirectoryMeta};
         use crate::test::PGTest;
         use ratpack::app::TestApp;
         use spectral::prelude::*;
@@ -87,14 +113,14 @@ mod tests {
         let pg = PGTest::new("test_basic_directory").await.unwrap();
         let c = Challenger::new(Some(chrono::Duration::seconds(1)));
         let mut app = App::with_state(
-            ServiceState::new(
-                "http://example.com".to_string(),
-                pg.db(),
-                c.clone(),
-                CACollector::new(Duration::MAX),
-                PostgresNonceValidator::new(pg.db()),
-            )
-            .unwrap(),
+            ServiceState::builder()
+                .url("http://example.com".to_string())
+                .db(pg.db())
+                .challenger(c.clone())
+                .ca(CACollector::new(Duration::MAX))
+                .nonce_validator(PostgresNonceValidator::new(pg.db(), None))
+                .build()
+                .unwrap(),
         );
         configure_routes(&mut app, None);
 
@@ -111,19 +137,23 @@ mod tests {
             new_order: "http://example.com/order".parse().unwrap(),
             new_authz: "http://example.com/authz".parse().unwrap(),
             revoke_cert: "http://example.com/revoke".parse().unwrap(),
-            key_change: "http://example.com/key".parse().unwrap(),
-            meta: None,
+            key_change: "http://example.com/key-change".parse().unwrap(),
+            renewal_info: "http://example.com/renewal-info".parse().unwrap(),
+            meta: Some(DirectoryMeta {
+                ca_chain: Some("http://example.com/ca-chain".parse().unwrap()),
+                ..Default::default()
+            }),
         });
 
         let mut app = App::with_state(
-            ServiceState::new(
-                "http://example.com/acme".to_string(),
-                pg.db(),
-                c,
-                CACollector::new(Duration::MAX),
-                PostgresNonceValidator::new(pg.db()),
-            )
-            .unwrap(),
+            ServiceState::builder()
+                .url("http://example.com/acme".to_string())
+                .db(pg.db())
+                .challenger(c)
+                .ca(CACollector::new(Duration::MAX))
+                .nonce_validator(PostgresNonceValidator::new(pg.db(), None))
+                .build()
+                .unwrap(),
         );
 
         configure_routes(&mut app, Some("/acme"));
@@ -140,8 +170,263 @@ mod tests {
             new_order: "http://example.com/acme/order".parse().unwrap(),
             new_authz: "http://example.com/acme/authz".parse().unwrap(),
             revoke_cert: "http://example.com/acme/revoke".parse().unwrap(),
-            key_change: "http://example.com/acme/key".parse().unwrap(),
-            meta: None,
+            key_change: "http://example.com/acme/key-change".parse().unwrap(),
+            renewal_info: "http://example.com/acme/renewal-info".parse().unwrap(),
+            meta: Some(DirectoryMeta {
+                ca_chain: Some("http://example.com/acme/ca-chain".parse().unwrap()),
+                ..Default::default()
+            }),
         });
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_directory_base_path() {
+        use super::{super::*, Directory};
+        use crate::test::PGTest;
+        use ratpack::app::TestApp;
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        let pg = PGTest::new("test_directory_base_path").await.unwrap();
+        let c = Challenger::new(Some(chrono::Duration::seconds(1)));
+        let mut app = App::with_state(
+            ServiceState::builder()
+                .url("http://example.com".to_string())
+                .basepath(Some("/pki/acme"))
+                .db(pg.db())
+                .challenger(c)
+                .ca(CACollector::new(Duration::MAX))
+                .nonce_validator(PostgresNonceValidator::new(pg.db(), None))
+                .build()
+                .unwrap(),
+        );
+        configure_routes(&mut app, Some("/pki/acme"));
+
+        let app = TestApp::new(app);
+
+        let mut res = app.get("/pki/acme/").await;
+        let res = hyper::body::to_bytes(res.body_mut()).await.unwrap();
+        let res = serde_json::from_slice::<Directory>(&res).unwrap();
+
+        assert_that!(res.new_nonce).is_equal_to(
+            "http://example.com/pki/acme/nonce"
+                .parse::<url::Url>()
+                .unwrap(),
+        );
+        assert_that!(res.new_account).is_equal_to(
+            "http://example.com/pki/acme/account"
+                .parse::<url::Url>()
+                .unwrap(),
+        );
+        assert_that!(res.new_order).is_equal_to(
+            "http://example.com/pki/acme/order"
+                .parse::<url::Url>()
+                .unwrap(),
+        );
+        assert_that!(res.revoke_cert).is_equal_to(
+            "http://example.com/pki/acme/revoke"
+                .parse::<url::Url>()
+                .unwrap(),
+        );
+        assert_that!(res.key_change).is_equal_to(
+            "http://example.com/pki/acme/key-change"
+                .parse::<url::Url>()
+                .unwrap(),
+        );
+
+        // the prefixed routes are reachable, and link back to the prefixed directory.
+        let res = app.head("/pki/acme/nonce").await;
+        assert_that!(res.status()).is_equal_to(StatusCode::OK);
+        assert_that!(res.headers().get("Link").unwrap().to_str().unwrap())
+            .is_equal_to(r#"<http://example.com/pki/acme/>;rel="index""#);
+    }
+
+    #[test]
+    fn test_normalize_rootpath() {
+        use super::super::normalize_rootpath;
+        use spectral::prelude::*;
+
+        assert_that!(normalize_rootpath(None)).is_equal_to("/".to_string());
+        assert_that!(normalize_rootpath(Some("/"))).is_equal_to("/".to_string());
+        assert_that!(normalize_rootpath(Some("acme"))).is_equal_to("/acme/".to_string());
+        assert_that!(normalize_rootpath(Some("/pki/acme/"))).is_equal_to("/pki/acme/".to_string());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_directory_hostnames() {
+        use super::{super::*, Directory};
+        use crate::errors::ConfigError;
+        use crate::test::PGTest;
+        use ratpack::app::TestApp;
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        let pg = PGTest::new("test_directory_hostnames").await.unwrap();
+        let c = Challenger::new(Some(chrono::Duration::seconds(1)));
+        let state = ServiceState::builder()
+            .url("http://internal.example.com:8000".to_string())
+            .db(pg.db())
+            .challenger(c)
+            .ca(CACollector::new(Duration::MAX))
+            .nonce_validator(PostgresNonceValidator::new(pg.db(), None))
+            .build()
+            .unwrap()
+            .with_hostnames(vec![
+                "acme.example.com".to_string(),
+                "acme.example.org".to_string(),
+            ]);
+
+        assert_that!(state.base_url_for_request("acme.example.com"))
+            .is_ok_containing("http://acme.example.com/".parse::<url::Url>().unwrap());
+        assert_that!(state.base_url_for_request("ACME.example.org:8443"))
+            .is_ok_containing("http://acme.example.org:8443/".parse::<url::Url>().unwrap());
+        assert_that!(state.base_url_for_request("evil.example.com"))
+            .is_err_containing(ConfigError::HostNotAllowed("evil.example.com".to_string()));
+
+        let mut app = App::with_state(state);
+        configure_routes(&mut app, None);
+        let app = TestApp::new(app);
+
+        for (host, base) in vec![
+            ("acme.example.com", "http://acme.example.com"),
+            ("acme.example.org", "http://acme.example.org"),
+            ("evil.example.com", "http://internal.example.com:8000"),
+        ] {
+            let mut res = app
+                .dispatch(
+                    Request::builder()
+                        .method(http::Method::GET)
+                        .uri("/")
+                        .header("Host", host)
+                        .body(Body::default())
+                        .unwrap(),
+                )
+                .await;
+
+            let res = hyper::body::to_bytes(res.body_mut()).await.unwrap();
+            let res = serde_json::from_slice::<Directory>(&res).unwrap();
+
+            assert_that!(res.new_nonce.to_string()).is_equal_to(format!("{}/nonce", base));
+            assert_that!(res.new_order.to_string()).is_equal_to(format!("{}/order", base));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_tenant_directory() {
+        use super::{super::*, Directory};
+        use crate::test::PGTest;
+        use ratpack::app::TestApp;
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        let pg = PGTest::new("test_tenant_directory").await.unwrap();
+        let c = Challenger::new(Some(chrono::Duration::seconds(1)));
+        let mut app = App::with_state(
+            ServiceState::builder()
+                .url("http://example.com/acme".to_string())
+                .db(pg.db())
+                .challenger(c)
+                .ca(CACollector::new(Duration::MAX))
+                .nonce_validator(PostgresNonceValidator::new(pg.db(), None))
+                .build()
+                .unwrap(),
+        );
+        configure_tenant_routes(&mut app, Some("/acme"));
+
+        let app = TestApp::new(app);
+
+        for tenant in vec!["unit-a", "unit-b"] {
+            let mut res = app.get(&format!("/acme/{}/directory", tenant)).await;
+            assert_that!(res.status()).is_equal_to(StatusCode::OK);
+
+            let res = hyper::body::to_bytes(res.body_mut()).await.unwrap();
+            let res = serde_json::from_slice::<Directory>(&res).unwrap();
+
+            assert_that!(res.new_nonce.to_string())
+                .is_equal_to(format!("http://example.com/acme/{}/nonce", tenant));
+            assert_that!(res.new_account.to_string())
+                .is_equal_to(format!("http://example.com/acme/{}/account", tenant));
+            assert_that!(res.new_order.to_string())
+                .is_equal_to(format!("http://example.com/acme/{}/order", tenant));
+
+            // the tenant's routes link back to its directory.
+            let res = app.head(&format!("/acme/{}/nonce", tenant)).await;
+            assert_that!(res.status()).is_equal_to(StatusCode::OK);
+            assert_that!(res.headers().get("Link").unwrap().to_str().unwrap()).is_equal_to(
+                format!(
+                    r#"<http://example.com/acme/{}/directory>;rel="index""#,
+                    tenant
+                )
+                .as_str(),
+            );
+        }
+
+        // shared routes stay below the root path.
+        let res = app.get("/acme/ca-chain").await;
+        assert_that!(res.status()).is_not_equal_to(StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_directory_meta() {
+        use super::{super::*, Directory, DirectoryMeta};
+        use crate::test::PGTest;
+        use ratpack::app::TestApp;
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        let pg = PGTest::new("test_directory_meta").await.unwrap();
+
+        let fetch = |state: ServiceState| async move {
+            let mut app = App::with_state(state);
+            configure_routes(&mut app, None);
+            let app = TestApp::new(app);
+
+            let mut res = app.get("/").await;
+            let res = hyper::body::to_bytes(res.body_mut()).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&res).unwrap()
+        };
+
+        let builder = || {
+            ServiceState::builder()
+                .url("http://example.com".to_string())
+                .db(pg.db())
+                .challenger(Challenger::new(None))
+                .ca(CACollector::new(Duration::MAX))
+                .nonce_validator(PostgresNonceValidator::new(pg.db(), None))
+        };
+
+        // nothing is advertised that was not configured.
+        let dir = fetch(builder().build().unwrap()).await;
+        assert_that!(dir["meta"].get("externalAccountRequired")).is_none();
+        assert_that!(dir["meta"].get("termsOfService")).is_none();
+        assert_that!(dir["meta"].get("website")).is_none();
+        assert_that!(dir["meta"].get("caaIdentities")).is_none();
+
+        // nor is a binding required when it is only checked if supplied.
+        let dir = fetch(
+            builder()
+                .eab_policy(Some(EabPolicy { required: false }))
+                .build()
+                .unwrap(),
+        )
+        .await;
+        assert_that!(dir["meta"].get("externalAccountRequired")).is_none();
+
+        let state = builder()
+            .terms_of_service_url(Some("https://example.com/tos".parse().unwrap()))
+            .caa_identities(vec!["example.com".to_string()])
+            .build()
+            .unwrap()
+            .with_website_url("https://example.com/about".parse().unwrap())
+            .with_eab_policy(EabPolicy { required: true });
+
+        let dir: Directory = serde_json::from_value(fetch(state).await).unwrap();
+        assert_that!(dir.meta).is_equal_to(Some(DirectoryMeta {
+            terms_of_service: Some("https://example.com/tos".to_string()),
+            website: Some("https://example.com/about".to_string()),
+            caa_identities: Some(vec!["example.com".to_string()]),
+            external_account_required: Some(true),
+            ca_chain: Some("http://example.com/ca-chain".parse().unwrap()),
+        }));
+    }
 }
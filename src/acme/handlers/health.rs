@@ -0,0 +1,89 @@
+// the liveness endpoint for container orchestration. This is not a part of ACME.
+
+use ratpack::prelude::*;
+
+use super::{HandlerState, ServiceState};
+
+/// healthz answers liveness probes: `200 OK` if the database answers a trivial query, and
+/// `503 Service Unavailable` if not. It must be enabled with [ServiceState::with_health_check].
+pub(crate) async fn healthz(
+    req: Request<Body>,
+    _resp: Option<Response<Body>>,
+    _params: Params,
+    app: App<ServiceState, HandlerState>,
+    state: HandlerState,
+) -> HTTPResult<HandlerState> {
+    let appstate_opt = app.state().await.clone().unwrap();
+    let appstate = appstate_opt.lock().await;
+
+    if !appstate.health_check {
+        return Err(ratpack::Error::StatusCode(
+            StatusCode::NOT_FOUND,
+            "the health check is not enabled".to_string(),
+        ));
+    }
+
+    let status = match appstate.db.health_check().await {
+        Ok(()) => StatusCode::OK,
+        Err(e) => {
+            log::warn!("request {}: health check failed: {}", state.request_id(), e);
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+    };
+
+    Ok((
+        req,
+        Some(
+            Response::builder()
+                .status(status)
+                .body(Body::default())
+                .unwrap(),
+        ),
+        state,
+    ))
+}
+
+mod tests {
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_healthz() {
+        use super::super::*;
+        use crate::models::PostgresConfig;
+        use crate::test::PGTest;
+        use ratpack::app::TestApp;
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        let pg = PGTest::new("test_healthz").await.unwrap();
+        let broken = Postgres::with_config(
+            PostgresConfig::new("host=/nonexistent dbname=coyote user=postgres connect_timeout=1")
+                .with_max_connections(1),
+        )
+        .await
+        .unwrap();
+
+        for (db, enabled, status) in [
+            (pg.db(), false, StatusCode::NOT_FOUND),
+            (pg.db(), true, StatusCode::OK),
+            (broken, true, StatusCode::SERVICE_UNAVAILABLE),
+        ] {
+            let mut app = App::with_state(
+                ServiceState::builder()
+                    .url("http://example.com".to_string())
+                    .db(db.clone())
+                    .challenger(Challenger::new(Some(chrono::Duration::seconds(1))))
+                    .ca(CACollector::new(Duration::MAX))
+                    .nonce_validator(PostgresNonceValidator::new(db, None))
+                    .build()
+                    .unwrap()
+                    .with_health_check(enabled),
+            );
+            configure_routes(&mut app, None);
+
+            let app = TestApp::new(app);
+            let res = app.get("/healthz").await;
+            assert_that!(res.status()).is_equal_to(status);
+            // probes are not logged, but still tell the client the id of the request.
+            assert_that!(res.headers().contains_key(REQUEST_ID_HEADER)).is_true();
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use std::{sync::Mutex, time::Instant};
+
+use http::{HeaderMap, Method, StatusCode};
+use serde::Serialize;
+
+use super::REQUEST_ID_HEADER;
+use crate::util::make_uuid;
+
+/// the prefix of the `type` of ACME problem documents; RFC8555 6.7.
+const ACME_ERROR_PREFIX: &str = "urn:ietf:params:acme:error:";
+/// the longest `X-Request-ID` adopted from a request; longer ones are replaced.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// LoggingMiddleware follows a single request through its handlers, and logs it as one JSON
+/// object through the `log` facade once a response has been built; see [RequestLog]. Each request
+/// gets its own, which the clones of its [super::HandlerState] share.
+#[derive(Debug)]
+pub(crate) struct LoggingMiddleware {
+    request_id: Mutex<String>,
+    timestamp: chrono::DateTime<chrono::Local>,
+    started: Instant,
+    account: Mutex<Option<url::Url>>,
+}
+
+/// RequestLog is the line logged for each request.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub(crate) struct RequestLog {
+    pub timestamp: chrono::DateTime<chrono::Local>,
+    pub request_id: String,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub duration_ms: u128,
+    /// the account which signed the request by key id, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    /// the ACME error type of a problem document in the response, e.g. `rateLimited`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl LoggingMiddleware {
+    /// new starts the clock on a request and assigns it an id.
+    pub(crate) fn new() -> Self {
+        Self {
+            request_id: Mutex::new(make_uuid()),
+            timestamp: chrono::Local::now(),
+            started: Instant::now(),
+            account: Mutex::new(None),
+        }
+    }
+
+    /// request_id returns the id of the request: the one the client sent, or a UUID.
+    pub(crate) fn request_id(&self) -> String {
+        self.request_id.lock().unwrap().clone()
+    }
+
+    /// adopt_request_id uses the `X-Request-ID` the client sent, if any, as the request's id, so
+    /// that client and server logs can be correlated. Ids which are empty, too long or not
+    /// printable ASCII are ignored.
+    pub(crate) fn adopt_request_id(&self, headers: &HeaderMap) {
+        let id = match headers.get(REQUEST_ID_HEADER).map(|id| id.to_str()) {
+            Some(Ok(id)) => id,
+            _ => return,
+        };
+
+        if !id.is_empty()
+            && id.len() <= MAX_REQUEST_ID_LEN
+            && id.chars().all(|c| c.is_ascii_graphic())
+        {
+            *self.request_id.lock().unwrap() = id.to_string();
+        }
+    }
+
+    /// set_account records the account URL the request was signed for.
+    pub(crate) fn set_account(&self, account: url::Url) {
+        *self.account.lock().unwrap() = Some(account);
+    }
+
+    /// entry builds the log line for the response. `body` is only inspected for an ACME problem
+    /// document; pass None when it was not buffered.
+    pub(crate) fn entry(
+        &self,
+        method: &Method,
+        path: &str,
+        status: StatusCode,
+        body: Option<&[u8]>,
+    ) -> RequestLog {
+        RequestLog {
+            timestamp: self.timestamp,
+            request_id: self.request_id(),
+            method: method.to_string(),
+            path: path.to_string(),
+            status: status.as_u16(),
+            duration_ms: self.started.elapsed().as_millis(),
+            account: self.account.lock().unwrap().as_ref().map(|a| a.to_string()),
+            error: body.and_then(problem_type),
+        }
+    }
+
+    /// log logs the entry for the response at info level.
+    pub(crate) fn log(&self, method: &Method, path: &str, status: StatusCode, body: Option<&[u8]>) {
+        match serde_json::to_string(&self.entry(method, path, status, body)) {
+            Ok(line) => log::info!("{}", line),
+            Err(e) => log::error!("could not log request {}: {}", self.request_id(), e),
+        }
+    }
+}
+
+/// problem_type returns the ACME error type of a problem document, without its URN prefix.
+pub(crate) fn problem_type(body: &[u8]) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_slice(body).ok()?;
+
+    value["type"]
+        .as_str()?
+        .strip_prefix(ACME_ERROR_PREFIX)
+        .map(|t| t.to_string())
+}
+
+mod tests {
+    #[test]
+    fn test_request_log() {
+        use super::LoggingMiddleware;
+        use crate::errors::{Error, RFCError};
+        use http::{Method, StatusCode};
+        use spectral::prelude::*;
+
+        let logger = LoggingMiddleware::new();
+        let id = logger.request_id();
+        assert_that!(id.len()).is_equal_to(36);
+        assert_that!(id.chars().nth(14)).is_equal_to(Some('4'));
+        assert_that!(LoggingMiddleware::new().request_id()).is_not_equal_to(id.clone());
+
+        let entry = logger.entry(&Method::GET, "/", StatusCode::OK, None);
+        assert_that!(entry.request_id).is_equal_to(id.clone());
+        assert_that!(entry.method).is_equal_to("GET".to_string());
+        assert_that!(entry.status).is_equal_to(200);
+        assert_that!(entry.account).is_none();
+        assert_that!(entry.error).is_none();
+
+        logger.set_account("http://example.com/account/abc".parse().unwrap());
+        let problem = serde_json::to_vec(&Error::new(RFCError::RateLimited, "slow down")).unwrap();
+        let entry = logger.entry(
+            &Method::POST,
+            "/order",
+            StatusCode::TOO_MANY_REQUESTS,
+            Some(&problem),
+        );
+        assert_that!(entry.account).is_equal_to(Some("http://example.com/account/abc".to_string()));
+        assert_that!(entry.error).is_equal_to(Some("rateLimited".to_string()));
+
+        // bodies which are not problem documents carry no error.
+        let entry = logger.entry(&Method::POST, "/order", StatusCode::OK, Some(b"{}"));
+        assert_that!(entry.error).is_none();
+
+        let line: serde_json::Value = serde_json::to_value(&entry).unwrap();
+        for key in vec![
+            "timestamp",
+            "request_id",
+            "method",
+            "path",
+            "status",
+            "duration_ms",
+            "account",
+        ] {
+            assert_that!(line.get(key)).is_some();
+        }
+        assert_that!(line.get("error")).is_none();
+    }
+
+    #[test]
+    fn test_adopt_request_id() {
+        use super::LoggingMiddleware;
+        use http::HeaderMap;
+        use spectral::prelude::*;
+
+        let logger = LoggingMiddleware::new();
+        let generated = logger.request_id();
+
+        let mut headers = HeaderMap::new();
+        logger.adopt_request_id(&headers);
+        assert_that!(logger.request_id()).is_equal_to(generated.clone());
+
+        for bad in ["", "has spaces", &"x".repeat(129)] {
+            headers.insert("X-Request-ID", bad.parse().unwrap());
+            logger.adopt_request_id(&headers);
+            assert_that!(logger.request_id()).is_equal_to(generated.clone());
+        }
+
+        headers.insert("x-request-id", "certbot-1234".parse().unwrap());
+        logger.adopt_request_id(&headers);
+        assert_that!(logger.request_id()).is_equal_to("certbot-1234".to_string());
+        assert_that!(
+            logger
+                .entry(&http::Method::GET, "/", http::StatusCode::OK, None)
+                .request_id
+        )
+        .is_equal_to("certbot-1234".to_string());
+    }
+}
@@ -0,0 +1,143 @@
+// the Prometheus metrics endpoint. Like OCSP, this is not a part of ACME.
+
+use prometheus::{Encoder, TextEncoder};
+use ratpack::prelude::*;
+
+use super::{bearer_token_matches, HandlerState, ServiceState};
+
+/// metrics returns the service's metrics in the Prometheus text exposition format. If the
+/// service has a metrics token, requests must present it as a bearer token.
+pub(crate) async fn metrics(
+    req: Request<Body>,
+    _resp: Option<Response<Body>>,
+    _params: Params,
+    app: App<ServiceState, HandlerState>,
+    state: HandlerState,
+) -> HTTPResult<HandlerState> {
+    let appstate_opt = app.state().await.clone().unwrap();
+    let appstate = appstate_opt.lock().await;
+
+    if let Some(token) = &appstate.metrics_token {
+        if !bearer_token_matches(&req, token) {
+            return Ok((
+                req,
+                Some(
+                    Response::builder()
+                        .header(http::header::WWW_AUTHENTICATE, "Bearer")
+                        .status(StatusCode::UNAUTHORIZED)
+                        .body(Body::default())
+                        .unwrap(),
+                ),
+                state,
+            ));
+        }
+    }
+
+    // the database is queried without holding the service state, as that would stall every
+    // other request until it answered.
+    let db = appstate.request_db(&req);
+    let metrics = appstate.metrics.clone();
+    let stats = appstate.ca.stats();
+    drop(appstate);
+
+    // gauges are brought up to date on demand, rather than tracked as things change.
+    let active = db
+        .order_status_counts()
+        .await?
+        .iter()
+        .filter(|(status, _)| matches!(status.as_str(), "pending" | "ready" | "processing"))
+        .map(|(_, count)| *count as i64)
+        .sum();
+    metrics.active_orders.set(active);
+
+    // counters only go up, so they are advanced by what the collector counted since.
+    for (counter, count) in [
+        (&metrics.ca_rotations, stats.ca_rotations),
+        (&metrics.ca_certs_issued, stats.certs_issued),
+        (&metrics.ca_signing_errors, stats.signing_errors),
+    ] {
+        counter.inc_by(count.saturating_sub(counter.get()));
+    }
+
+    metrics.ca_last_rotation.set(
+        stats
+            .last_rotation
+            .and_then(|t| t.duration_since(std::time::SystemTime::UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs() as i64),
+    );
+
+    metrics.db_pool_size.set(db.pool_size() as i64);
+
+    Ok((
+        req,
+        Some(
+            Response::builder()
+                .header("content-type", TextEncoder::new().format_type())
+                .status(StatusCode::OK)
+                .body(Body::from(metrics.encode()?))
+                .unwrap(),
+        ),
+        state,
+    ))
+}
+
+mod tests {
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_metrics() {
+        use crate::test::TestService;
+        use http::StatusCode;
+        use spectral::prelude::*;
+
+        let srv = TestService::new("test_metrics").await;
+
+        let res = srv
+            .clone()
+            .run_certbot_certonly(None, "foo.com", "erik@hollensbe.org")
+            .await;
+        assert_that!(res).is_ok();
+
+        let mut res = srv.app.get("/metrics").await;
+        assert_that!(res.status()).is_equal_to(StatusCode::OK);
+
+        let body = hyper::body::to_bytes(res.body_mut()).await.unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+
+        assert_that!(text).contains(r#"acme_requests_total{endpoint="finalize",status="200"} 1"#);
+        assert_that!(text).contains("acme_certificate_issuance_seconds_count 1");
+        assert_that!(text).contains(r#"acme_nonce_validations_total{result="valid"}"#);
+        assert_that!(text).contains("acme_active_orders 0");
+        assert_that!(text).contains("acme_ca_rotations_total 0");
+        assert_that!(text).contains("acme_ca_certs_issued_total 1");
+        assert_that!(text).contains("acme_ca_signing_errors_total 0");
+        assert_that!(text).contains("acme_ca_last_rotation_timestamp_seconds 0");
+        assert_that!(text).contains("acme_db_pool_size 200");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_metrics_token() {
+        use crate::test::TestService;
+        use http::StatusCode;
+        use spectral::prelude::*;
+
+        let srv = TestService::new_with_state("test_metrics_token", |state| {
+            state.with_metrics_token("s3cret")
+        })
+        .await;
+
+        let res = srv.app.get("/metrics").await;
+        assert_that!(res.status()).is_equal_to(StatusCode::UNAUTHORIZED);
+
+        let req = |token: &str| {
+            http::Request::get("/metrics")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(hyper::Body::default())
+                .unwrap()
+        };
+
+        let res = srv.app.dispatch(req("wrong")).await;
+        assert_that!(res.status()).is_equal_to(StatusCode::UNAUTHORIZED);
+
+        let res = srv.app.dispatch(req("s3cret")).await;
+        assert_that!(res.status()).is_equal_to(StatusCode::OK);
+    }
+}
@@ -1,62 +1,657 @@
-use std::convert::TryInto;
+use std::{convert::TryInto, sync::Arc};
 
 use crate::{
     acme::{
-        ca::CACollector,
+        ca::{CACollector, CRLCollector, CertProfile},
         challenge::Challenger,
         handlers::{
-            account::{new_account, post_account},
+            account::{account_orders, key_change, new_account, post_account},
+            admin::{
+                account_key_history, certificate_order, list_account_orders, list_accounts, vacuum,
+            },
+            ca::{ca_chain, ca_pubkey, crl, crl_issuer},
+            cors::{add_cors_headers, cors_preflight, handle_cors},
             directory::directory,
+            health::healthz,
+            logging::LoggingMiddleware,
+            metrics::metrics,
             nonce::{new_nonce_get, new_nonce_head},
+            ocsp::{ocsp_get, ocsp_post},
             order::{
                 existing_order, finalize_order, get_certificate, new_order, post_authz,
                 post_challenge,
             },
+            renewal::renewal_info,
+            revocation::revoke_cert,
         },
         jose::{ACMEKey, JWK},
+        metrics::Metrics,
+        rate_limit::{RateLimitConfig, RateLimitedEndpoint, RateLimiter},
         NonceValidator, PostgresNonceValidator,
     },
-    errors::{acme::JWSError, ACMEValidationError, Error, HandlerError},
-    models::Postgres,
+    errors::{acme::JWSError, ACMEValidationError, ConfigError, Error, HandlerError},
+    models::{Postgres, Storage, TenantId},
 };
+use builder::{ServiceStateBuilder, Unset};
+#[cfg(debug_assertions)]
+use debug::{debug_challenger, debug_state};
 use http::response::Builder;
 use ratpack::prelude::*;
 
 pub(crate) mod account;
+pub(crate) mod admin;
+pub mod builder;
+pub(crate) mod ca;
+pub(crate) mod cors;
+#[cfg(debug_assertions)]
+pub(crate) mod debug;
 pub(crate) mod directory;
+pub(crate) mod health;
+pub(crate) mod logging;
+pub(crate) mod metrics;
 pub(crate) mod nonce;
+pub(crate) mod ocsp;
 pub(crate) mod order;
+pub(crate) mod renewal;
+pub(crate) mod revocation;
+#[cfg(feature = "tls")]
+pub(crate) mod tls;
+
+pub use account::{AccountStatus, AcmeAccount, ExternalBinding, OrdersList};
+pub use cors::CorsConfig;
+#[cfg(feature = "tls")]
+pub use tls::TlsConfig;
 
-const REPLAY_NONCE_HEADER: &str = "Replay-Nonce";
+pub(crate) const REPLAY_NONCE_HEADER: &str = "Replay-Nonce";
+/// correlates a request across client and server logs; adopted from the request, or generated,
+/// and echoed in the response.
+const REQUEST_ID_HEADER: &str = "X-Request-ID";
 const ACME_CONTENT_TYPE: &str = "application/json";
+/// where the directory of each tenant is served, below its path; see [configure_tenant_routes].
+const TENANT_DIRECTORY: &str = "directory";
+/// how many entries a page of a listing holds; see [request_page].
+const PAGE_SIZE: u32 = 10;
+
+/// how long a new order has to be finalized in, unless set with [ServiceState::with_order_lifetime].
+pub const DEFAULT_ORDER_LIFETIME: std::time::Duration =
+    std::time::Duration::from_secs(7 * 24 * 60 * 60);
+/// how long a new authorization has to be validated in, unless set with
+/// [ServiceState::with_authz_lifetime].
+pub const DEFAULT_AUTHZ_LIFETIME: std::time::Duration =
+    std::time::Duration::from_secs(30 * 24 * 60 * 60);
+/// the fractions of a certificate's validity period between which renewal is suggested, unless
+/// set with [ServiceState::with_renewal_window].
+pub const DEFAULT_RENEWAL_WINDOW: (f64, f64) = (2.0 / 3.0, 5.0 / 6.0);
 
 /// ServiceState is the carried state globally for the application. It contains many items the
 /// handlers need to function.
+///
+/// Clones are cheap: they share the database pool, challenger, CA, nonce validator, storage,
+/// rate limiter buckets and metrics, each of which keeps its state behind an [Arc]. The
+/// configuration, such as the URL, hostnames and policies, is copied, so adjusting a clone with
+/// the `with_*` methods leaves the original as it was.
 #[derive(Clone)]
 pub struct ServiceState {
     baseurl: url::Url,
     db: Postgres,
+    storage: Option<Arc<dyn Storage + Send + Sync>>,
     c: Challenger,
     ca: CACollector,
-    pnv: PostgresNonceValidator,
+    nonces: Arc<dyn NonceValidator + Send + Sync>,
+    hostnames: Vec<String>,
+    debug_log_responses: bool,
+    account_rate_limit: Option<(std::time::Duration, u32)>,
+    max_certificates_per_account: Option<u64>,
+    eab_policy: Option<EabPolicy>,
+    domain_policy: Option<DomainPolicy>,
+    rate_limiter: Option<RateLimiter>,
+    ocsp_responder: bool,
+    crl: Option<CRLCollector>,
+    metrics: Metrics,
+    metrics_token: Option<String>,
+    admin_token: Option<String>,
+    health_check: bool,
+    debug_endpoints: bool,
+    cors: Option<CorsConfig>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>,
+    order_lifetime: std::time::Duration,
+    authz_lifetime: std::time::Duration,
+    tenant: TenantId,
+    order_event_hooks: Vec<Arc<OrderEventHook>>,
+    renewal_window: (f64, f64),
+    renewal_explanation_url: Option<url::Url>,
+    terms_of_service: Option<url::Url>,
+    website: Option<url::Url>,
+    caa_identities: Vec<String>,
+}
+
+/// OrderEvent describes a certificate issued for an order; see
+/// [ServiceState::with_order_event_hook].
+#[derive(Clone, Debug, PartialEq)]
+pub struct OrderEvent {
+    /// the account which placed the order, if known.
+    pub account_id: Option<i32>,
+    pub order_url: url::Url,
+    /// the certificate's serial number, hex encoded as in the admin routes.
+    pub serial: String,
+    /// the certificate followed by the CA chain up to the root, as served to the client.
+    pub chain: String,
+}
+
+/// OrderEventHook is called with an [OrderEvent] for every certificate issued.
+pub type OrderEventHook =
+    Box<dyn Fn(OrderEvent) -> futures::future::BoxFuture<'static, ()> + Send + Sync>;
+
+/// EabPolicy enables external account binding (RFC8555 7.3.4). When set, any binding supplied
+/// with a newAccount request is verified against the credentials stored with
+/// [Postgres::create_eab_credential], and each credential may only be used once.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EabPolicy {
+    /// reject newAccount requests which do not carry a binding.
+    pub required: bool,
+}
+
+/// DomainPolicy restricts the DNS names certificates are issued for; see
+/// [ServiceState::with_domain_policy]. The identifiers of a new order, and each subjectAltName of
+/// a CSR when its order is finalized, are checked, and the request is refused with a
+/// `rejectedIdentifier` problem if any is not permitted. IP addresses are not subject to the
+/// policy.
+#[derive(Clone, Debug)]
+pub enum DomainPolicy {
+    /// only names matching one of the patterns are permitted.
+    AllowList(Vec<regex::Regex>),
+    /// names matching any of the patterns are refused.
+    DenyList(Vec<regex::Regex>),
+}
+
+impl DomainPolicy {
+    /// allow_list compiles `patterns` into a [DomainPolicy::AllowList]. Each pattern must match
+    /// the whole name, e.g. `[^.]+\.test\.local` for the names directly below `test.local`.
+    pub fn allow_list(patterns: &[&str]) -> Result<Self, regex::Error> {
+        Ok(Self::AllowList(Self::compile(patterns)?))
+    }
+
+    /// deny_list compiles `patterns` into a [DomainPolicy::DenyList], anchored like
+    /// [DomainPolicy::allow_list].
+    pub fn deny_list(patterns: &[&str]) -> Result<Self, regex::Error> {
+        Ok(Self::DenyList(Self::compile(patterns)?))
+    }
+
+    fn compile(patterns: &[&str]) -> Result<Vec<regex::Regex>, regex::Error> {
+        patterns
+            .iter()
+            .map(|p| regex::Regex::new(&format!("^(?:{})$", p)))
+            .collect()
+    }
+
+    /// permits reports whether a certificate may be issued for `name`. Names are matched in lower
+    /// case and without a trailing dot, so that no spelling of a name escapes the patterns.
+    pub fn permits(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.').to_lowercase();
+
+        match self {
+            Self::AllowList(patterns) => patterns.iter().any(|p| p.is_match(&name)),
+            Self::DenyList(patterns) => !patterns.iter().any(|p| p.is_match(&name)),
+        }
+    }
 }
 
 impl ServiceState {
-    /// constructor for the service state
+    /// builder starts a [ServiceStateBuilder], which requires the URL, database, challenger, CA
+    /// and nonce validator to be set before the state can be built.
+    pub fn builder() -> ServiceStateBuilder<Unset, Unset, Unset, Unset, Unset> {
+        ServiceStateBuilder::default()
+    }
+
+    /// constructor for the service state. `basepath` is the path the service is reachable under,
+    /// e.g. when it is mounted at a subpath of a reverse proxy; it should match the one given to
+    /// [configure_routes]. Without it, the path of `baseurl` is used.
+    #[deprecated(note = "use ServiceState::builder")]
     pub fn new(
         baseurl: String,
+        basepath: Option<&str>,
         db: Postgres,
         c: Challenger,
         ca: CACollector,
         pnv: PostgresNonceValidator,
     ) -> Result<Self, url::ParseError> {
-        Ok(Self {
-            baseurl: baseurl.parse()?,
-            db,
-            c,
-            ca,
-            pnv,
-        })
+        Self::builder()
+            .url(baseurl)
+            .basepath(basepath)
+            .db(db)
+            .challenger(c)
+            .ca(ca)
+            .nonce_validator(pnv)
+            .build()
+    }
+
+    /// with_nonce_validator replaces the validator given to [ServiceStateBuilder::nonce_validator],
+    /// allowing nonces to be kept in other storage, e.g. a shared cache in edge deployments or a
+    /// [crate::acme::SetValidator] in tests.
+    pub fn with_nonce_validator(
+        mut self,
+        validator: Box<dyn NonceValidator + Send + Sync>,
+    ) -> Self {
+        self.nonces = Arc::from(validator);
+        self
+    }
+
+    /// with_storage replaces the database for the records handlers create through [Storage],
+    /// e.g. with a store which counts or fails calls in tests. Handlers still use the database
+    /// given to [ServiceStateBuilder::db] for everything [Storage] does not cover.
+    pub fn with_storage(mut self, storage: Box<dyn Storage + Send + Sync>) -> Self {
+        self.storage = Some(Arc::from(storage));
+        self
+    }
+
+    /// with_debug_log_responses enables logging of every response body at trace level. This is
+    /// meant for debugging sessions; certificate material in the body is redacted.
+    pub fn with_debug_log_responses(mut self, debug_log_responses: bool) -> Self {
+        self.debug_log_responses = debug_log_responses;
+        self
+    }
+
+    /// with_account_rate_limit limits each account to `max` new orders, and `max` finalizations,
+    /// per `window`. Requests over the limit are answered with `429 Too Many Requests`.
+    pub fn with_account_rate_limit(mut self, window: std::time::Duration, max: u32) -> Self {
+        self.account_rate_limit = Some((window, max));
+        self
+    }
+
+    /// with_max_certificates_per_account sets a lifetime quota of certificates per account.
+    /// Accounts which have reached it may not create new orders. Revoked certificates do not
+    /// count against the quota.
+    pub fn with_max_certificates_per_account(mut self, max: u64) -> Self {
+        self.max_certificates_per_account = Some(max);
+        self
+    }
+
+    /// with_eab_policy enables external account binding for new accounts.
+    pub fn with_eab_policy(mut self, policy: EabPolicy) -> Self {
+        self.eab_policy = Some(policy);
+        self
+    }
+
+    /// with_domain_policy restricts the names certificates are issued for. The policy's patterns
+    /// are compiled once, when it is constructed, and shared by all requests.
+    pub fn with_domain_policy(mut self, policy: DomainPolicy) -> Self {
+        self.domain_policy = Some(policy);
+        self
+    }
+
+    /// with_rate_limits enables per-IP and per-account rate limiting of the newNonce, newAccount
+    /// and newOrder endpoints. Requests over a limit are answered with `429 Too Many Requests` and
+    /// a `Retry-After` header.
+    pub fn with_rate_limits(mut self, config: RateLimitConfig) -> Self {
+        self.rate_limiter = Some(RateLimiter::new(config));
+        self
+    }
+
+    /// with_ocsp_responder enables the OCSP responder at `/ocsp`, which answers with the
+    /// revocation status of certificates issued by the CA. Pair it with [CA::with_ocsp_url] so
+    /// that issued certificates point clients at it.
+    ///
+    /// [CA::with_ocsp_url]: crate::acme::ca::CA::with_ocsp_url
+    pub fn with_ocsp_responder(mut self, enabled: bool) -> Self {
+        self.ocsp_responder = enabled;
+        self
+    }
+
+    /// with_crl serves the CRLs generated by the collector: that of the current CA at `/crl`,
+    /// and that of any CA whose certificates are still valid at `/crl/<issuer>`, by the
+    /// base64url-encoded SHA-1 hash of its key. A CA which may be rotated should advertise the
+    /// latter with [CA::with_crl_url]. The collector must be spawned separately with
+    /// [CRLCollector::spawn_collector].
+    ///
+    /// [CA::with_crl_url]: crate::acme::ca::CA::with_crl_url
+    pub fn with_crl(mut self, collector: CRLCollector) -> Self {
+        self.crl = Some(collector);
+        self
+    }
+
+    /// with_cert_profile sets the validity period of issued certificates, in place of the
+    /// profile of whichever CA the [CACollector] holds.
+    pub fn with_cert_profile(mut self, profile: CertProfile) -> Self {
+        self.ca = self.ca.with_cert_profile(profile);
+        self
+    }
+
+    /// with_metrics replaces the metrics exported at `/metrics`, which are otherwise kept in a
+    /// registry of their own. Use it to export the service's metrics alongside the application's.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// with_metrics_token requires requests to `/metrics` to carry the token as a bearer token
+    /// in the Authorization header.
+    pub fn with_metrics_token(mut self, token: &str) -> Self {
+        self.metrics_token = Some(token.to_string());
+        self
+    }
+
+    /// with_admin_token enables the administrative routes which change things or list
+    /// accounts, such as `/admin/vacuum` and `/admin/accounts`, for requests which carry the token
+    /// as a bearer token in the Authorization header. Without one, they refuse every request.
+    pub fn with_admin_token(mut self, token: &str) -> Self {
+        self.admin_token = Some(token.to_string());
+        self
+    }
+
+    /// with_health_check enables `/healthz`, which answers `200 OK` while the database is
+    /// reachable and `503 Service Unavailable` otherwise; see [Postgres::health_check].
+    pub fn with_health_check(mut self, enabled: bool) -> Self {
+        self.health_check = enabled;
+        self
+    }
+
+    /// with_cors allows browser-based clients on the configured origins to use the ACME API:
+    /// preflight requests to its routes are answered, and responses to allowed origins carry
+    /// `Access-Control-Allow-Origin`. Without it, `OPTIONS` requests to those routes are answered
+    /// with `404 Not Found`.
+    pub fn with_cors(mut self, config: CorsConfig) -> Self {
+        self.cors = Some(config);
+        self
+    }
+
+    /// with_tls serves the service over TLS with the certificate and key of `config`, when it is
+    /// started with [serve]. A `http` URL given to [ServiceStateBuilder::url] becomes `https`, so
+    /// that the directory and the URLs in responses point clients at the right scheme.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, config: TlsConfig) -> Self {
+        if self.baseurl.scheme() == "http" {
+            // cannot fail: http and https are both special schemes.
+            self.baseurl.set_scheme("https").unwrap();
+        }
+
+        self.tls = Some(config);
+        self
+    }
+
+    /// with_debug_endpoints enables `/debug/challenger`, which reports the challenges waiting to
+    /// be validated. Like the other `/debug` routes, it only exists in debug builds.
+    pub fn with_debug_endpoints(mut self, enabled: bool) -> Self {
+        self.debug_endpoints = enabled;
+        self
+    }
+
+    /// with_order_lifetime sets how long new orders have to be finalized in before they expire
+    /// (RFC8555 7.1.3). Expired orders are invalid, and can no longer be finalized.
+    pub fn with_order_lifetime(mut self, lifetime: std::time::Duration) -> Self {
+        self.order_lifetime = lifetime;
+        self
+    }
+
+    /// with_authz_lifetime sets how long the authorizations of new orders have to be validated in
+    /// before they expire (RFC8555 7.1.4).
+    pub fn with_authz_lifetime(mut self, lifetime: std::time::Duration) -> Self {
+        self.authz_lifetime = lifetime;
+        self
+    }
+
+    /// with_tenant serves the ACME API of `tenant` alone, e.g. when several instances of the
+    /// service share a database. Accounts, orders and authorizations of other tenants are
+    /// invisible to it. To serve many tenants from one instance, see [configure_tenant_routes].
+    pub fn with_tenant(mut self, tenant: TenantId) -> Self {
+        self.tenant = tenant;
+        self
+    }
+
+    /// with_order_event_hook registers a hook which is called each time an order is finalized
+    /// and its certificate issued, e.g. to notify other systems without them having to poll. It
+    /// may be called more than once. Hooks run on tasks of their own once the certificate has been
+    /// recorded, so they neither delay nor affect the response to the client.
+    pub fn with_order_event_hook(mut self, hook: OrderEventHook) -> Self {
+        self.order_event_hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// with_renewal_window sets when clients are asked to renew their certificates through
+    /// renewal information (draft-ietf-acme-ari), as fractions of each certificate's validity
+    /// period; e.g. `(0.5, 0.75)` suggests renewing between its half-life and three quarters of
+    /// the way through. Panics unless `0 <= start <= end <= 1`.
+    pub fn with_renewal_window(mut self, start: f64, end: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&start) && (start..=1.0).contains(&end),
+            "renewal window must satisfy 0 <= start <= end <= 1"
+        );
+        self.renewal_window = (start, end);
+        self
+    }
+
+    /// with_renewal_explanation_url sets the page renewal information points clients at, e.g. to
+    /// explain an early renewal window.
+    pub fn with_renewal_explanation_url(mut self, url: url::Url) -> Self {
+        self.renewal_explanation_url = Some(url);
+        self
+    }
+
+    /// with_terms_of_service_url advertises the CA's terms of service in the `meta` object of the
+    /// directory (RFC8555 7.1.1), where clients show it to the user before they agree to it.
+    pub fn with_terms_of_service_url(mut self, url: url::Url) -> Self {
+        self.terms_of_service = Some(url);
+        self
+    }
+
+    /// with_website_url advertises a page about the CA in the `meta` object of the directory.
+    pub fn with_website_url(mut self, url: url::Url) -> Self {
+        self.website = Some(url);
+        self
+    }
+
+    /// with_caa_identities advertises the domains the CA recognizes as its own in the issuer
+    /// property of CAA records (RFC8659), in the `meta` object of the directory.
+    pub fn with_caa_identities(mut self, identities: Vec<String>) -> Self {
+        self.caa_identities = identities;
+        self
+    }
+
+    /// has_order_event_hooks reports whether any [OrderEventHook] is registered, so that events
+    /// are only assembled when someone listens.
+    pub(crate) fn has_order_event_hooks(&self) -> bool {
+        !self.order_event_hooks.is_empty()
+    }
+
+    /// emit_order_event spawns each registered [OrderEventHook] with the event.
+    pub(crate) fn emit_order_event(&self, event: OrderEvent) {
+        for hook in &self.order_event_hooks {
+            tokio::spawn(hook(event.clone()));
+        }
+    }
+
+    /// request_tenant returns the tenant named in the request's path, if it was routed through
+    /// [configure_tenant_routes], and the one set with [ServiceState::with_tenant] otherwise.
+    pub(crate) fn request_tenant(&self, req: &Request<Body>) -> TenantId {
+        req.extensions()
+            .get::<TenantId>()
+            .cloned()
+            .unwrap_or_else(|| self.tenant.clone())
+    }
+
+    /// request_db returns the database handle scoped to the request's tenant; see
+    /// [ServiceState::request_tenant].
+    pub(crate) fn request_db(&self, req: &Request<Body>) -> Postgres {
+        self.db.clone().with_tenant(self.request_tenant(req))
+    }
+
+    /// request_storage returns the storage set with [ServiceState::with_storage], or else the
+    /// database handle, scoped to the request's tenant like [ServiceState::request_db].
+    pub(crate) fn request_storage(&self, req: &Request<Body>) -> Arc<dyn Storage + Send + Sync> {
+        match &self.storage {
+            Some(storage) => storage.for_tenant(&self.request_tenant(req)),
+            None => Arc::new(self.request_db(req)),
+        }
+    }
+
+    /// check_rate_limit counts the request against the configured limits for the endpoint, keyed
+    /// on the peer address and, if the request carries a JWS signed by key id, the account URL.
+    /// If a limit has been reached, the duration until the client may retry is returned.
+    pub(crate) async fn check_rate_limit(
+        &self,
+        endpoint: RateLimitedEndpoint,
+        req: &Request<Body>,
+        jws: Option<crate::acme::jose::JWS>,
+    ) -> Result<Option<std::time::Duration>, ratpack::Error> {
+        let limiter = match &self.rate_limiter {
+            Some(limiter) => limiter,
+            None => return Ok(None),
+        };
+
+        let ip = req
+            .extensions()
+            .get::<std::net::IpAddr>()
+            .map(|ip| ip.to_string());
+
+        let account = match jws {
+            Some(mut jws) => jws.protected()?.kid().map(|kid| kid.to_string()),
+            None => None,
+        };
+
+        Ok(limiter.check(endpoint, ip, account).await)
+    }
+
+    /// account_id_for_jws returns the id of the account which signed the JWS, if the JWS refers
+    /// to one by key id.
+    pub(crate) async fn account_id_for_jws(
+        &self,
+        req: &Request<Body>,
+        mut jws: crate::acme::jose::JWS,
+    ) -> Result<Option<i32>, ratpack::Error> {
+        use crate::models::{account, Record};
+
+        let kid = match jws.protected()?.kid() {
+            Some(kid) => kid,
+            None => return Ok(None),
+        };
+
+        let jwk = account::JWK::find_by_kid(kid, self.request_db(req)).await?;
+        let acct = account::Account::find_by_kid(jwk.id()?.unwrap(), self.request_db(req)).await?;
+
+        Ok(acct.id)
+    }
+
+    /// key_thumbprint_for_account returns the RFC7638 thumbprint of the key of the account
+    /// `account_id`, as key authorizations for its challenges are made with (RFC8555 8.1).
+    pub(crate) async fn key_thumbprint_for_account(
+        &self,
+        req: &Request<Body>,
+        account_id: i32,
+    ) -> Result<String, ratpack::Error> {
+        use crate::models::{account, Record};
+
+        let account = account::Account::find(account_id, self.request_db(req)).await?;
+        let jwk: crate::acme::jose::JWK = account.jwk(self.request_db(req)).await?.try_into()?;
+
+        Ok(jwk.thumbprint()?)
+    }
+
+    /// check_account_rate_limit counts the operation against the account which signed the JWS, if
+    /// rate limiting is configured and the JWS refers to an account. If the account is over its
+    /// limit, the duration until the current window ends is returned.
+    pub(crate) async fn check_account_rate_limit(
+        &self,
+        req: &Request<Body>,
+        jws: crate::acme::jose::JWS,
+        operation: &str,
+    ) -> Result<Option<std::time::Duration>, ratpack::Error> {
+        let (window, max) = match self.account_rate_limit {
+            Some(limit) => limit,
+            None => return Ok(None),
+        };
+
+        let account_id = match self.account_id_for_jws(req, jws).await? {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+
+        if self
+            .db
+            .check_account_rate_limit(account_id, operation, window, max)
+            .await?
+        {
+            return Ok(None);
+        }
+
+        let elapsed = std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            % window.as_secs().max(1);
+
+        Ok(Some(std::time::Duration::from_secs(
+            window.as_secs().max(1) - elapsed,
+        )))
+    }
+
+    /// certificate_quota_exceeded returns true if a certificate quota is configured and the
+    /// account has reached it.
+    pub(crate) async fn certificate_quota_exceeded(
+        &self,
+        account_id: Option<i32>,
+    ) -> Result<bool, ratpack::Error> {
+        match (self.max_certificates_per_account, account_id) {
+            (Some(max), Some(account_id)) => {
+                let count = self.db.account_total_certificate_count(account_id).await?;
+                Ok(count as u64 >= max)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// with_hostnames configures the list of hostnames this service may be reached by, e.g. when
+    /// it sits behind a reverse proxy. Requests carrying one of these names in their `Host` header
+    /// will have their response URLs built against that name instead of the base URL.
+    pub fn with_hostnames(mut self, hostnames: Vec<String>) -> Self {
+        self.hostnames = hostnames.iter().map(|h| h.to_lowercase()).collect();
+        self
+    }
+
+    /// base_url_for_request validates the `Host` header value provided against the list of
+    /// allowed hostnames and returns the base URL to use for the request.
+    pub fn base_url_for_request(&self, host: &str) -> Result<url::Url, ConfigError> {
+        let authority = match host.parse::<http::uri::Authority>() {
+            Ok(authority) => authority,
+            Err(_) => return Err(ConfigError::InvalidHost(host.to_string())),
+        };
+
+        let hostname = authority.host().to_lowercase();
+
+        if !self.hostnames.contains(&hostname) {
+            return Err(ConfigError::HostNotAllowed(hostname));
+        }
+
+        let mut url = self.baseurl.clone();
+        if url.set_host(Some(&hostname)).is_err() || url.set_port(authority.port_u16()).is_err() {
+            return Err(ConfigError::InvalidHost(host.to_string()));
+        }
+
+        Ok(url)
+    }
+
+    /// request_baseurl returns the base URL for the request; this is the configured base URL
+    /// unless the request's `Host` header names one of the allowed hostnames. Requests routed
+    /// through [configure_tenant_routes] have their tenant's path appended.
+    pub(crate) fn request_baseurl(&self, req: &Request<Body>) -> url::Url {
+        let mut url = self.baseurl.clone();
+
+        if let Some(host) = req.headers().get(http::header::HOST) {
+            if let Ok(host) = host.to_str() {
+                if let Ok(hosturl) = self.base_url_for_request(host) {
+                    url = hosturl;
+                }
+            }
+        }
+
+        if let Some(tenant) = req.extensions().get::<TenantId>() {
+            url.path_segments_mut()
+                .expect("the base URL can be a base")
+                .pop_if_empty()
+                .push(tenant.as_str())
+                .push("");
+        }
+
+        url
     }
 }
 
@@ -65,9 +660,42 @@ impl ServiceState {
 pub struct HandlerState {
     jws: Option<crate::acme::jose::JWS>,
     nonce: Option<String>,
+    baseurl: Option<url::Url>,
+    logger: Arc<LoggingMiddleware>,
+    /// the request's origin, if it is allowed by the [CorsConfig].
+    cors_origin: Option<String>,
 }
 
 impl HandlerState {
+    /// rate_limited builds the `429 Too Many Requests` response for an account over one of its
+    /// limits. `retry_after` is omitted for limits which will not reset on their own.
+    pub(crate) fn rate_limited(
+        &self,
+        url: url::Url,
+        detail: &str,
+        retry_after: Option<std::time::Duration>,
+    ) -> Result<Response<Body>, HandlerError> {
+        let error = Error::new(crate::errors::RFCError::RateLimited, detail);
+
+        let mut builder = self
+            .decorate_response(url, Response::builder())?
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header("content-type", "application/problem+json");
+
+        if let Some(retry_after) = retry_after {
+            builder = builder.header("Retry-After", retry_after.as_secs().to_string());
+        }
+
+        Ok(builder
+            .body(Body::from(serde_json::to_string(&error).unwrap()))
+            .unwrap())
+    }
+
+    /// request_id returns the id of the request, which log lines about it should carry.
+    pub(crate) fn request_id(&self) -> String {
+        self.logger.request_id()
+    }
+
     pub(crate) fn decorate_response(
         &self,
         url: url::Url,
@@ -77,13 +705,16 @@ impl HandlerState {
             return Err(ACMEValidationError::NonceNotFound.into());
         }
 
+        // the directory lives at the base URL, which is recorded alongside the nonce.
+        let index = match &self.baseurl {
+            Some(baseurl) => baseurl.clone(),
+            None => url.join("/")?,
+        };
+
         Ok(builder
             .header("content-type", ACME_CONTENT_TYPE)
             .header(REPLAY_NONCE_HEADER, self.clone().nonce.unwrap())
-            .header(
-                "Link",
-                format!(r#"<{}>;rel="index""#, url.join("/")?.to_string()),
-            ))
+            .header("Link", format!(r#"<{}>;rel="index""#, index.to_string())))
     }
 }
 
@@ -92,6 +723,9 @@ impl TransientState for HandlerState {
         Self {
             jws: None,
             nonce: None,
+            baseurl: None,
+            logger: Arc::new(LoggingMiddleware::new()),
+            cors_origin: None,
         }
     }
 }
@@ -103,14 +737,77 @@ pub(crate) async fn uri_to_url(
     baseurl.join(&uri.to_string())
 }
 
-async fn handle_nonce(
+/// bearer_token_matches reports whether the request carries `token` as a bearer token in its
+/// Authorization header. The comparison takes the same time wherever the tokens differ.
+pub(crate) fn bearer_token_matches(req: &Request<Body>, token: &str) -> bool {
+    let presented = req
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    presented.len() == token.len() && openssl::memcmp::eq(presented.as_bytes(), token.as_bytes())
+}
+
+/// request_page returns the page of a listing requested with the `page` query parameter,
+/// counted from zero, and the URL of the page after it. Listings link to the next page only when
+/// there is something on it.
+pub(crate) fn request_page(url: &url::Url) -> Result<(u32, url::Url), ratpack::Error> {
+    let page = match url.query_pairs().find(|(k, _)| k == "page") {
+        Some((_, v)) => match v.parse::<u32>() {
+            Ok(page) => page,
+            Err(_) => {
+                return Err(ratpack::Error::StatusCode(
+                    StatusCode::BAD_REQUEST,
+                    "invalid page".to_string(),
+                ))
+            }
+        },
+        None => 0,
+    };
+
+    let mut next = url.clone();
+    next.query_pairs_mut()
+        .clear()
+        .append_pair("page", &page.saturating_add(1).to_string());
+
+    Ok((page, next))
+}
+
+/// handle_request_id adopts the request's `X-Request-ID`, if it has a usable one, as its id. It
+/// runs first, so that everything logged about the request carries the id.
+async fn handle_request_id(
     req: Request<Body>,
     _resp: Option<Response<Body>>,
     _params: Params,
+    _app: App<ServiceState, HandlerState>,
+    state: HandlerState,
+) -> HTTPResult<HandlerState> {
+    state.logger.adopt_request_id(req.headers());
+    Ok((req, None, state))
+}
+
+async fn handle_nonce(
+    mut req: Request<Body>,
+    _resp: Option<Response<Body>>,
+    params: Params,
     app: App<ServiceState, HandlerState>,
     mut state: HandlerState,
 ) -> HTTPResult<HandlerState> {
-    state.nonce = Some(app.state().await.unwrap().lock().await.pnv.make().await?);
+    let appstate_opt = app.state().await.unwrap();
+    let appstate = appstate_opt.lock().await;
+
+    // the tenant travels with the request, like the peer address, for the handlers which follow.
+    if let Some(tenant) = params.get("tenant") {
+        req.extensions_mut().insert(TenantId(tenant.to_string()));
+    }
+
+    state.nonce = Some(appstate.nonces.make().await?);
+    state.baseurl = Some(match req.extensions().get::<TenantId>() {
+        Some(_) => appstate.request_baseurl(&req).join(TENANT_DIRECTORY)?,
+        None => appstate.request_baseurl(&req),
+    });
     Ok((req, None, state))
 }
 
@@ -132,21 +829,81 @@ async fn handle_jws(
 
         match jws.clone().protected() {
             Ok(mut protected) => {
-                if let Err(e) = protected
+                if let Some(kid) = protected.kid() {
+                    state.logger.set_account(kid);
+                }
+
+                let res = protected
                     .validate(
-                        uri_to_url(appstate.baseurl.clone(), uri).await?,
-                        appstate.pnv.clone(),
+                        uri_to_url(appstate.request_baseurl(&req), uri).await?,
+                        appstate.nonces.clone(),
                     )
-                    .await
-                {
+                    .await;
+
+                let nonce_result = match &res {
+                    Ok(()) => Some("valid"),
+                    Err(ACMEValidationError::NonceNotFound)
+                    | Err(ACMEValidationError::NonceDecodeError) => Some("invalid"),
+                    Err(ACMEValidationError::NonceExpired) => Some("expired"),
+                    Err(ACMEValidationError::NonceFetchError(_)) => Some("error"),
+                    Err(_) => None,
+                };
+
+                if let Some(result) = nonce_result {
+                    appstate
+                        .metrics
+                        .nonce_validations
+                        .with_label_values(&[result])
+                        .inc();
+                }
+
+                if let Err(e) = res {
+                    log::debug!(
+                        "request {}: rejecting protected header: {}",
+                        state.request_id(),
+                        e
+                    );
                     return Err(e.to_status());
                 } else {
+                    // the key of a deactivated account is removed with it, so it is looked for
+                    // before the key itself is.
+                    let deactivated = if let Some(jwk) = protected.jwk() {
+                        // keys which cannot be stored cannot belong to an account either; they
+                        // are rejected below.
+                        let jwk: Result<crate::models::account::JWK, _> =
+                            (&mut jwk.clone()).try_into();
+
+                        match jwk {
+                            Ok(jwk) => {
+                                appstate
+                                    .request_storage(&req)
+                                    .account_deactivated_for_key(&jwk)
+                                    .await?
+                            }
+                            Err(_) => false,
+                        }
+                    } else if let Some(kid) = protected.kid() {
+                        crate::models::account::Account::deactivated_for_kid(
+                            &kid,
+                            appstate.request_db(&req),
+                        )
+                        .await?
+                    } else {
+                        false
+                    };
+
+                    if deactivated {
+                        return Err(ACMEValidationError::AccountDeactivated.to_status());
+                    }
+
                     let key: Result<Option<ACMEKey>, Error> = if let Some(jwk) = protected.jwk() {
                         Ok(Some(jwk.try_into()?))
                     } else if let Some(kid) = protected.kid() {
-                        let jwk =
-                            crate::models::account::JWK::find_by_kid(kid, appstate.db.clone())
-                                .await?;
+                        let jwk = crate::models::account::JWK::find_by_kid(
+                            kid,
+                            appstate.request_db(&req),
+                        )
+                        .await?;
 
                         let localjwk: Result<JWK, JWSError> = jwk.try_into();
                         match localjwk {
@@ -187,29 +944,306 @@ async fn handle_jws(
     ))
 }
 
+/// options_any answers `OPTIONS *`, the server-wide form of OPTIONS browsers may send before
+/// CORS requests.
+async fn options_any(
+    req: Request<Body>,
+    _resp: Option<Response<Body>>,
+    _params: Params,
+    _app: App<ServiceState, HandlerState>,
+    state: HandlerState,
+) -> HTTPResult<HandlerState> {
+    Ok((
+        req,
+        Some(
+            Response::builder()
+                .status(StatusCode::OK)
+                .header("Allow", "GET, HEAD, POST")
+                .header("Access-Control-Allow-Methods", "GET, HEAD, POST, OPTIONS")
+                .body(Body::empty())
+                .unwrap(),
+        ),
+        state,
+    ))
+}
+
+/// log_response runs after the other handlers. It logs the request through the request's
+/// [LoggingMiddleware] and, if enabled in the service state, the response body at trace level.
+/// The body has to be buffered to do so, so it is only read for problem documents unless body
+/// logging is on.
+async fn log_response(
+    req: Request<Body>,
+    resp: Option<Response<Body>>,
+    _params: Params,
+    app: App<ServiceState, HandlerState>,
+    state: HandlerState,
+) -> HTTPResult<HandlerState> {
+    let mut resp = match resp {
+        Some(resp) => resp,
+        None => return Ok((req, None, state)),
+    };
+
+    add_request_id(&state, &mut resp);
+    add_cors_headers(&state, &mut resp);
+
+    let enabled = {
+        let appstate_opt = app.state().await.unwrap();
+        let appstate = appstate_opt.lock().await;
+        appstate
+            .metrics
+            .observe_request(req.uri().path(), resp.status().as_u16());
+        appstate.debug_log_responses && log::log_enabled!(log::Level::Trace)
+    };
+
+    let problem = resp
+        .headers()
+        .get("content-type")
+        .map_or(false, |ct| ct == "application/problem+json");
+
+    if !enabled && !problem {
+        state
+            .logger
+            .log(req.method(), req.uri().path(), resp.status(), None);
+        return Ok((req, Some(resp), state));
+    }
+
+    let (parts, body) = resp.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+
+    state
+        .logger
+        .log(req.method(), req.uri().path(), parts.status, Some(&body));
+
+    if enabled {
+        log::trace!(
+            "{}",
+            format_response_log(req.method(), req.uri(), parts.status, &body)
+        );
+    }
+
+    Ok((
+        req,
+        Some(Response::from_parts(parts, Body::from(body))),
+        state,
+    ))
+}
+
+/// probe_response runs after the handlers of probes, which are kept out of the request log and
+/// metrics. It only adds the request id to the response.
+async fn probe_response(
+    req: Request<Body>,
+    resp: Option<Response<Body>>,
+    _params: Params,
+    _app: App<ServiceState, HandlerState>,
+    state: HandlerState,
+) -> HTTPResult<HandlerState> {
+    let resp = resp.map(|mut resp| {
+        add_request_id(&state, &mut resp);
+        resp
+    });
+
+    Ok((req, resp, state))
+}
+
+/// add_request_id tells the client the id the request was logged under, in `X-Request-ID`.
+fn add_request_id(state: &HandlerState, resp: &mut Response<Body>) {
+    if let Ok(id) = http::HeaderValue::from_str(&state.request_id()) {
+        resp.headers_mut().insert(REQUEST_ID_HEADER, id);
+    }
+}
+
+/// format_response_log renders a response for the debug log, replacing any PEM-encoded
+/// material (certificates, mostly) with `[REDACTED]`.
+fn format_response_log(
+    method: &http::Method,
+    uri: &http::Uri,
+    status: StatusCode,
+    body: &[u8],
+) -> String {
+    let body = String::from_utf8_lossy(body);
+    let mut redacted = String::new();
+    let mut rest: &str = &body;
+
+    while let Some(start) = rest.find("-----BEGIN ") {
+        redacted.push_str(&rest[..start]);
+        redacted.push_str("[REDACTED]");
+
+        rest = &rest[start..];
+        // the END marker is followed by the label and a closing run of dashes.
+        match rest.find("-----END ").and_then(|end| {
+            rest[end + 9..]
+                .find("-----")
+                .map(|close| end + 9 + close + 5)
+        }) {
+            Some(end) => rest = &rest[end..],
+            None => rest = "",
+        }
+    }
+
+    redacted.push_str(rest);
+
+    format!("response: {} {} {}: {}", method, uri, status, redacted)
+}
+
+/// request_head copies what the handlers after a failed one need of the request: its method,
+/// URI, version and headers. The body has been consumed by then.
+fn request_head(req: &Request<Body>) -> Request<Body> {
+    let mut head = Request::new(Body::empty());
+    *head.method_mut() = req.method().clone();
+    *head.uri_mut() = req.uri().clone();
+    *head.version_mut() = req.version();
+    *head.headers_mut() = req.headers().clone();
+    head
+}
+
+/// error_response answers a request with the error a handler returned, as ratpack would, but
+/// marks ACME problem documents as such.
+fn error_response(e: ratpack::Error) -> Response<Body> {
+    let (status, body) = match e {
+        ratpack::Error::StatusCode(status, body) => (status, body),
+        e => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    };
+
+    let mut builder = Response::builder().status(status);
+    if logging::problem_type(body.as_bytes()).is_some() {
+        builder = builder.header("content-type", "application/problem+json");
+    }
+
+    builder.body(Body::from(body)).unwrap()
+}
+
+/// finished_handler composes the handlers provided, followed by `$last`. The first error
+/// returned stops the handlers as usual, but is turned into the response with [error_response]
+/// and handed to `$last`, which so sees error responses like any other.
+macro_rules! finished_handler {
+    ($last:path; $($x:path),+ $(,)?) => {{
+        async fn chain(
+            req: Request<Body>,
+            resp: Option<Response<Body>>,
+            params: Params,
+            app: App<ServiceState, HandlerState>,
+            state: HandlerState,
+        ) -> HTTPResult<HandlerState> {
+            let head = request_head(&req);
+            let (mut req, mut resp, mut state) = (req, resp, state);
+
+            $(
+                let last = state.clone();
+                match $x(req, resp, params.clone(), app.clone(), state).await {
+                    Ok((r, re, s)) => {
+                        req = r;
+                        resp = re;
+                        state = s;
+                    }
+                    Err(e) => return Ok((head, Some(error_response(e)), last)),
+                }
+            )+
+
+            Ok((req, resp, state))
+        }
+
+        compose_handler!(chain, $last)
+    }};
+}
+
+/// logged_handler composes the handlers provided, followed by [log_response], so that errors are
+/// logged, counted and carry the request id and CORS headers like any other response.
+macro_rules! logged_handler {
+    ($($x:path),+ $(,)?) => {
+        finished_handler!(log_response; $($x),+)
+    };
+}
+
 macro_rules! jws_handler {
-    ($($x:path)*) => {
-        compose_handler!(handle_nonce, handle_jws, $($x)*)
+    ($x:path) => {
+        logged_handler!(handle_request_id, handle_cors, handle_nonce, handle_jws, $x)
+    };
+}
+
+macro_rules! preflight_handler {
+    () => {
+        logged_handler!(handle_request_id, handle_cors, cors_preflight)
     };
 }
 
+/// normalize_rootpath turns an optional path prefix such as `/pki/acme` into the form routes and
+/// URLs are built from: rooted, and ending in a slash.
+pub(crate) fn normalize_rootpath(rootpath: Option<&str>) -> String {
+    let rootpath = rootpath.unwrap_or("").trim_matches('/');
+
+    if rootpath.is_empty() {
+        "/".to_string()
+    } else {
+        format!("/{}/", rootpath)
+    }
+}
+
 /// configure_routes sets up the application's routing framework. It needs to be called before
-/// serving the application over TCP.
+/// serving the application over TCP. Every route is mounted below `rootpath` when given, e.g.
+/// `Some("/pki/acme")`; pass the same path to [ServiceStateBuilder::basepath].
 pub fn configure_routes(app: &mut App<ServiceState, HandlerState>, rootpath: Option<&str>) {
-    let rootpath = rootpath.unwrap_or("/").to_string();
+    let rootpath = normalize_rootpath(rootpath);
 
     app.get(
         &(rootpath.clone()),
-        compose_handler!(handle_nonce, directory),
+        logged_handler!(handle_request_id, handle_cors, handle_nonce, directory),
+    );
+
+    app.options(&(rootpath.clone()), preflight_handler!());
+
+    configure_acme_routes(app, &rootpath);
+    configure_service_routes(app, &rootpath);
+}
+
+/// configure_tenant_routes is [configure_routes] for an instance which serves many tenants. The
+/// ACME API of each tenant is mounted below `rootpath` followed by the tenant's name, e.g.
+/// `/acme/{tenant}/directory`, and sees only the accounts, orders and authorizations of that
+/// tenant. Tenants are created as they are first used. Routes which are not a part of ACME, such
+/// as `/ca-chain` or `/metrics`, are shared and stay directly below `rootpath`; tenants should
+/// not be named after them.
+pub fn configure_tenant_routes(app: &mut App<ServiceState, HandlerState>, rootpath: Option<&str>) {
+    let rootpath = normalize_rootpath(rootpath);
+
+    configure_service_routes(app, &rootpath);
+
+    let prefix = rootpath + ":tenant/";
+    app.get(
+        &(prefix.clone() + TENANT_DIRECTORY),
+        logged_handler!(handle_request_id, handle_cors, handle_nonce, directory),
     );
 
+    app.options(&(prefix.clone() + TENANT_DIRECTORY), preflight_handler!());
+
+    configure_acme_routes(app, &prefix);
+}
+
+/// serve serves the application, after its routes have been configured, on `addr`. With the
+/// `tls` feature and a [ServiceState::with_tls] configuration, it is served over TLS; otherwise
+/// over plain HTTP.
+pub async fn serve(app: App<ServiceState, HandlerState>, addr: &str) -> Result<(), ServerError> {
+    #[cfg(feature = "tls")]
+    {
+        let tls = app.state().await.unwrap().lock().await.tls.clone();
+
+        if let Some(tls) = tls {
+            return Ok(app.serve_tls(addr, tls.server_config()?).await?);
+        }
+    }
+
+    Ok(app.serve(addr).await?)
+}
+
+/// configure_acme_routes mounts the ACME API, less the directory, below `prefix`.
+fn configure_acme_routes(app: &mut App<ServiceState, HandlerState>, prefix: &str) {
+    let rootpath = prefix.to_string();
+
     app.head(
         &(rootpath.clone() + "nonce"),
-        compose_handler!(handle_nonce, new_nonce_head),
+        logged_handler!(handle_request_id, handle_cors, handle_nonce, new_nonce_head),
     );
     app.get(
         &(rootpath.clone() + "nonce"),
-        compose_handler!(handle_nonce, new_nonce_get),
+        logged_handler!(handle_request_id, handle_cors, handle_nonce, new_nonce_get),
     );
 
     app.post(&(rootpath.clone() + "account"), jws_handler!(new_account));
@@ -217,6 +1251,11 @@ pub fn configure_routes(app: &mut App<ServiceState, HandlerState>, rootpath: Opt
         &(rootpath.clone() + "account/:key_id"),
         jws_handler!(post_account),
     );
+    app.post(&(rootpath.clone() + "key-change"), jws_handler!(key_change));
+    app.post(
+        &(rootpath.clone() + "orders/:orders_id"),
+        jws_handler!(account_orders),
+    );
 
     app.post(&(rootpath.clone() + "order"), jws_handler!(new_order));
     app.post(
@@ -239,4 +1278,331 @@ pub fn configure_routes(app: &mut App<ServiceState, HandlerState>, rootpath: Opt
         &(rootpath.clone() + "chall/:challenge_id"),
         jws_handler!(post_challenge),
     );
+    app.post(&(rootpath.clone() + "revoke"), jws_handler!(revoke_cert));
+
+    app.get(
+        &(rootpath.clone() + "renewal-info/:cert_id"),
+        logged_handler!(handle_request_id, handle_cors, renewal_info),
+    );
+
+    // browsers ask before making cross-origin requests; see [ServiceState::with_cors].
+    for path in [
+        "nonce",
+        "account",
+        "account/:key_id",
+        "key-change",
+        "orders/:orders_id",
+        "order",
+        "order/:order_id",
+        "order/:order_id/finalize",
+        "order/:order_id/certificate",
+        "authz/:auth_id",
+        "chall/:challenge_id",
+        "revoke",
+        "renewal-info/:cert_id",
+    ] {
+        app.options(&(rootpath.clone() + path), preflight_handler!());
+    }
+}
+
+/// configure_service_routes mounts the routes which are not a part of ACME below `rootpath`.
+fn configure_service_routes(app: &mut App<ServiceState, HandlerState>, rootpath: &str) {
+    let rootpath = rootpath.to_string();
+
+    // the asterisk-form of the request target is never relative to the root path.
+    app.options("*", logged_handler!(handle_request_id, options_any));
+
+    app.get(
+        &(rootpath.clone() + "ca-pubkey"),
+        logged_handler!(handle_request_id, ca_pubkey),
+    );
+    app.get(
+        &(rootpath.clone() + "ca-chain"),
+        logged_handler!(handle_request_id, ca_chain),
+    );
+    app.get(
+        &(rootpath.clone() + "crl"),
+        logged_handler!(handle_request_id, crl),
+    );
+    app.get(
+        &(rootpath.clone() + "crl/:issuer"),
+        logged_handler!(handle_request_id, crl_issuer),
+    );
+
+    app.get(
+        &(rootpath.clone() + "ocsp/:request"),
+        logged_handler!(handle_request_id, ocsp_get),
+    );
+    app.post(
+        &(rootpath.clone() + "ocsp"),
+        logged_handler!(handle_request_id, ocsp_post),
+    );
+
+    app.get(
+        &(rootpath.clone() + "metrics"),
+        logged_handler!(handle_request_id, metrics),
+    );
+
+    // probes are frequent, so they are kept out of the request metrics and request log.
+    app.get(
+        &(rootpath.clone() + "healthz"),
+        finished_handler!(probe_response; handle_request_id, healthz),
+    );
+
+    app.get(
+        &(rootpath.clone() + "admin/certificates/:serial/order"),
+        logged_handler!(handle_request_id, certificate_order),
+    );
+    app.get(
+        &(rootpath.clone() + "admin/accounts"),
+        logged_handler!(handle_request_id, list_accounts),
+    );
+    app.get(
+        &(rootpath.clone() + "admin/accounts/:account_id/key-history"),
+        logged_handler!(handle_request_id, account_key_history),
+    );
+    app.get(
+        &(rootpath.clone() + "admin/accounts/:account_id/orders"),
+        logged_handler!(handle_request_id, list_account_orders),
+    );
+    app.post(
+        &(rootpath.clone() + "admin/vacuum"),
+        logged_handler!(handle_request_id, vacuum),
+    );
+
+    #[cfg(debug_assertions)]
+    app.get(
+        &(rootpath.clone() + "debug/state"),
+        logged_handler!(handle_request_id, debug_state),
+    );
+    #[cfg(debug_assertions)]
+    app.get(
+        &(rootpath.clone() + "debug/challenger"),
+        logged_handler!(handle_request_id, debug_challenger),
+    );
+}
+
+mod tests {
+    #[test]
+    fn test_format_response_log() {
+        use super::format_response_log;
+        use http::{Method, StatusCode, Uri};
+        use spectral::prelude::*;
+
+        let line = format_response_log(
+            &Method::GET,
+            &"/".parse::<Uri>().unwrap(),
+            StatusCode::OK,
+            br#"{"newNonce":"http://example.com/nonce"}"#,
+        );
+        assert_that!(line).is_equal_to(
+            r#"response: GET / 200 OK: {"newNonce":"http://example.com/nonce"}"#.to_string(),
+        );
+
+        let line = format_response_log(
+            &Method::POST,
+            &"/order/1/certificate".parse::<Uri>().unwrap(),
+            StatusCode::OK,
+            b"-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\n-----BEGIN CERTIFICATE-----\nMIIC\n-----END CERTIFICATE-----\n",
+        );
+        assert_that!(line).is_equal_to(
+            "response: POST /order/1/certificate 200 OK: [REDACTED]\n[REDACTED]\n".to_string(),
+        );
+        assert_that!(line.contains("MIIB")).is_false();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_debug_log_responses() {
+        use super::*;
+        use crate::test::{LogCapture, PGTest};
+        use ratpack::app::TestApp;
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        let pg = PGTest::new("test_debug_log_responses").await.unwrap();
+        let logs = LogCapture::new();
+        let c = Challenger::new(Some(chrono::Duration::seconds(1)));
+        let mut app = App::with_state(
+            ServiceState::builder()
+                .url("http://example.com".to_string())
+                .db(pg.db())
+                .challenger(c)
+                .ca(CACollector::new(Duration::MAX))
+                .nonce_validator(PostgresNonceValidator::new(pg.db(), None))
+                .build()
+                .unwrap()
+                .with_debug_log_responses(true),
+        );
+        configure_routes(&mut app, None);
+
+        let app = TestApp::new(app);
+
+        // the body is buffered for logging; it must still arrive intact.
+        let mut res = app.get("/").await;
+        assert_that!(res.status()).is_equal_to(StatusCode::OK);
+        assert_that!(res.headers().get(REPLAY_NONCE_HEADER)).is_some();
+
+        let body = hyper::body::to_bytes(res.body_mut()).await.unwrap();
+        let dir = serde_json::from_slice::<Directory>(&body).unwrap();
+        assert_that!(dir.new_nonce.to_string()).is_equal_to("http://example.com/nonce".to_string());
+
+        // other tests log alongside this one, so look for this response among the trace lines.
+        let line = logs
+            .messages(log::Level::Trace)
+            .into_iter()
+            .find(|line| line.starts_with("response: GET / 200 OK: "))
+            .expect("the directory response was not logged");
+        let logged: serde_json::Value =
+            serde_json::from_str(line.trim_start_matches("response: GET / 200 OK: ")).unwrap();
+        assert_that!(logged)
+            .is_equal_to(serde_json::from_slice::<serde_json::Value>(&body).unwrap());
+        assert_that!(logged["newNonce"].as_str()).is_equal_to(Some("http://example.com/nonce"));
+        assert_that!(logged["newAccount"].as_str()).is_equal_to(Some("http://example.com/account"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_service_state_clone() {
+        use super::*;
+        use crate::test::PGTest;
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        let pg = PGTest::new("test_service_state_clone").await.unwrap();
+        let state = ServiceState::builder()
+            .url("http://example.com".to_string())
+            .db(pg.db())
+            .challenger(Challenger::new(None))
+            .ca(CACollector::new(Duration::MAX))
+            .nonce_validator(PostgresNonceValidator::new(pg.db(), None))
+            .build()
+            .unwrap();
+
+        let mut clone = state.clone();
+        clone.baseurl = "http://other.example.com/acme/".parse().unwrap();
+        let clone = clone
+            .with_hostnames(vec!["other.example.com".to_string()])
+            .with_debug_log_responses(true);
+
+        // configuration is copied,
+        assert_that!(state.baseurl.as_str()).is_equal_to("http://example.com/");
+        assert_that!(clone.baseurl.as_str()).is_equal_to("http://other.example.com/acme/");
+        assert_that!(state.hostnames).is_empty();
+        assert_that!(state.debug_log_responses).is_false();
+
+        // while what keeps state is shared.
+        assert_that!(Arc::ptr_eq(&state.nonces, &clone.nonces)).is_true();
+        clone.metrics.ca_rotations.inc();
+        assert_that!(state.metrics.ca_rotations.get()).is_equal_to(1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_options_any() {
+        use crate::test::TestService;
+        use spectral::prelude::*;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpStream;
+
+        let srv = TestService::new("test_options_any").await;
+        let addr = srv.url.trim_start_matches("http://").to_string();
+
+        // the asterisk-form can't be expressed through most HTTP clients, so speak it directly.
+        let mut stream = TcpStream::connect(&addr).await.unwrap();
+        stream
+            .write_all(b"OPTIONS * HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).await.unwrap();
+        let res = String::from_utf8(buf).unwrap().to_lowercase();
+
+        assert_that!(res.starts_with("http/1.1 200 ok\r\n")).is_true();
+        assert_that!(res.contains("\r\nallow: get, head, post\r\n")).is_true();
+        assert_that!(res.contains("\r\naccess-control-allow-methods: get, head, post, options\r\n"))
+            .is_true();
+        assert_that!(res.contains("\r\ncontent-length: 0\r\n")).is_true();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_request_id() {
+        use super::REQUEST_ID_HEADER;
+        use crate::test::TestService;
+        use hyper::{Body, Request};
+        use spectral::prelude::*;
+
+        let srv = TestService::new("test_request_id").await;
+
+        let res = srv
+            .app
+            .dispatch(
+                Request::builder()
+                    .uri("/nonce")
+                    .header(REQUEST_ID_HEADER, "certbot-1234")
+                    .body(Body::default())
+                    .unwrap(),
+            )
+            .await;
+        assert_that!(res.headers()[REQUEST_ID_HEADER].to_str().unwrap())
+            .is_equal_to("certbot-1234");
+
+        // without one, or with one which is unusable, the request gets a UUID.
+        let res = srv.app.get("/nonce").await;
+        let generated = res.headers()[REQUEST_ID_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert_that!(generated.len()).is_equal_to(36);
+
+        let res = srv
+            .app
+            .dispatch(
+                Request::builder()
+                    .uri("/nonce")
+                    .header(REQUEST_ID_HEADER, "x".repeat(1000))
+                    .body(Body::default())
+                    .unwrap(),
+            )
+            .await;
+        assert_that!(res.headers()[REQUEST_ID_HEADER].len()).is_equal_to(36);
+        assert_that!(res.headers()[REQUEST_ID_HEADER].to_str().unwrap())
+            .is_not_equal_to(generated.as_str());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_error_response_logged() {
+        use super::REQUEST_ID_HEADER;
+        use crate::models::memory::MemoryStore;
+        use crate::test::{LogCapture, TestService, TestServiceOptions};
+        use hyper::{Body, StatusCode};
+        use spectral::prelude::*;
+
+        let srv = TestService::with_options(
+            "test_error_response_logged",
+            TestServiceOptions {
+                memory_store: Some(MemoryStore::new()),
+                ..Default::default()
+            },
+        )
+        .await;
+        let logs = LogCapture::new();
+
+        // a body which is not a JWS at all is refused by handle_jws.
+        let res = srv.app.post("/order", Body::from("garbage")).await;
+        assert_that!(res.status()).is_equal_to(StatusCode::FORBIDDEN);
+        let id = res.headers()[REQUEST_ID_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let logged = logs
+            .messages(log::Level::Info)
+            .into_iter()
+            .filter_map(|line| serde_json::from_str::<serde_json::Value>(&line).ok())
+            .find(|line| line["request_id"] == serde_json::json!(id))
+            .expect("the error response was not logged");
+        assert_that!(logged["path"].as_str()).is_equal_to(Some("/order"));
+        assert_that!(logged["status"].as_u64()).is_equal_to(Some(403));
+
+        srv.shutdown().await;
+    }
 }
@@ -2,6 +2,7 @@
 // in this library.
 
 use super::{uri_to_url, HandlerState, ServiceState};
+use crate::acme::rate_limit::RateLimitedEndpoint;
 use ratpack::prelude::*;
 
 pub(crate) async fn new_nonce_head(
@@ -11,17 +12,24 @@ pub(crate) async fn new_nonce_head(
     app: App<ServiceState, HandlerState>,
     state: HandlerState,
 ) -> HTTPResult<HandlerState> {
-    let uri = req.uri().clone();
+    let appstate_opt = app.state().await.clone().unwrap();
+    let appstate = appstate_opt.lock().await;
+    let url = uri_to_url(appstate.request_baseurl(&req), req.uri().clone()).await?;
+
+    if let Some(retry_after) = appstate
+        .check_rate_limit(RateLimitedEndpoint::NewNonce, &req, None)
+        .await?
+    {
+        let resp =
+            state.rate_limited(url, "too many requests; try again later", Some(retry_after))?;
+        return Ok((req, Some(resp), state));
+    }
 
     Ok((
         req,
         Some(
             state
-                .decorate_response(
-                    uri_to_url(app.state().await.unwrap().lock().await.baseurl.clone(), uri)
-                        .await?,
-                    Response::builder(),
-                )?
+                .decorate_response(url, Response::builder())?
                 .status(StatusCode::OK)
                 .header("Cache-Control", "no-store") // last para of 7.2
                 .body(Body::default())
@@ -38,17 +46,24 @@ pub(crate) async fn new_nonce_get(
     app: App<ServiceState, HandlerState>,
     state: HandlerState,
 ) -> HTTPResult<HandlerState> {
-    let uri = req.uri().clone();
+    let appstate_opt = app.state().await.clone().unwrap();
+    let appstate = appstate_opt.lock().await;
+    let url = uri_to_url(appstate.request_baseurl(&req), req.uri().clone()).await?;
+
+    if let Some(retry_after) = appstate
+        .check_rate_limit(RateLimitedEndpoint::NewNonce, &req, None)
+        .await?
+    {
+        let resp =
+            state.rate_limited(url, "too many requests; try again later", Some(retry_after))?;
+        return Ok((req, Some(resp), state));
+    }
 
     Ok((
         req,
         Some(
             state
-                .decorate_response(
-                    uri_to_url(app.state().await.unwrap().lock().await.baseurl.clone(), uri)
-                        .await?,
-                    Response::builder(),
-                )?
+                .decorate_response(url, Response::builder())?
                 .status(StatusCode::CREATED)
                 .header("Cache-Control", "no-store") // last para of 7.2
                 .body(Body::default())
@@ -70,14 +85,14 @@ mod tests {
         let pg = PGTest::new("test_basic_head").await.unwrap();
         let c = Challenger::new(Some(chrono::Duration::seconds(1)));
         let mut app = App::with_state(
-            ServiceState::new(
-                "http://127.0.0.1:8000".to_string(),
-                pg.db(),
-                c,
-                CACollector::new(Duration::MAX),
-                PostgresNonceValidator::new(pg.db()),
-            )
-            .unwrap(),
+            ServiceState::builder()
+                .url("http://127.0.0.1:8000".to_string())
+                .db(pg.db())
+                .challenger(c)
+                .ca(CACollector::new(Duration::MAX))
+                .nonce_validator(PostgresNonceValidator::new(pg.db(), None))
+                .build()
+                .unwrap(),
         );
 
         configure_routes(&mut app, None);
@@ -128,14 +143,14 @@ mod tests {
         let pg = PGTest::new("test_basic_get").await.unwrap();
         let c = Challenger::new(Some(chrono::Duration::seconds(1)));
         let mut app = App::with_state(
-            ServiceState::new(
-                "http://127.0.0.1:8000".to_string(),
-                pg.db(),
-                c,
-                CACollector::new(Duration::MAX),
-                PostgresNonceValidator::new(pg.db()),
-            )
-            .unwrap(),
+            ServiceState::builder()
+                .url("http://127.0.0.1:8000".to_string())
+                .db(pg.db())
+                .challenger(c)
+                .ca(CACollector::new(Duration::MAX))
+                .nonce_validator(PostgresNonceValidator::new(pg.db(), None))
+                .build()
+                .unwrap(),
         );
 
         configure_routes(&mut app, None);
@@ -170,4 +185,98 @@ mod tests {
             handle.await.unwrap()
         }
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_nonce_validator() {
+        use super::super::*;
+        use crate::acme::SetValidator;
+        use crate::test::PGTest;
+        use ratpack::app::TestApp;
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        let pg = PGTest::new("test_nonce_validator").await.unwrap();
+        let c = Challenger::new(Some(chrono::Duration::seconds(1)));
+        let validator = SetValidator::default();
+
+        let mut app = App::with_state(
+            ServiceState::builder()
+                .url("http://127.0.0.1:8000".to_string())
+                .db(pg.db())
+                .challenger(c)
+                .ca(CACollector::new(Duration::MAX))
+                .nonce_validator(PostgresNonceValidator::new(pg.db(), None))
+                .build()
+                .unwrap()
+                .with_nonce_validator(Box::new(validator.clone())),
+        );
+
+        configure_routes(&mut app, None);
+
+        let app: TestApp<ServiceState, HandlerState> = TestApp::new(app);
+
+        let res = app.head("/nonce").await;
+        assert_that!(res.status()).is_equal_to(StatusCode::OK);
+        let nonce = res
+            .headers()
+            .get(REPLAY_NONCE_HEADER)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        // the nonce was issued by the configured validator, not postgres
+        assert_that!(pg.db().nonce_count().await.unwrap()).is_equal_to(0);
+        assert_that!(validator.validate(&nonce).await).is_ok();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_nonce_memory_store() {
+        use crate::acme::jose::EC_GROUP;
+        use crate::acme::NonceValidator;
+        use crate::models::{memory::MemoryStore, Storage};
+        use crate::test::{StoreNonceValidator, TestService, TestServiceOptions};
+        use hyper::StatusCode;
+        use openssl::ec::EcKey;
+        use serde_json::json;
+        use spectral::prelude::*;
+
+        let store = MemoryStore::new();
+        let srv = TestService::with_options(
+            "test_nonce_memory_store",
+            TestServiceOptions {
+                memory_store: Some(store.clone()),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let res = srv.app.head("/nonce").await;
+        assert_that!(res.status()).is_equal_to(StatusCode::OK);
+        let nonce = res.headers()[super::super::REPLAY_NONCE_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert_that!(nonce.is_empty()).is_false();
+        assert_that!(store.nonce_count().await.unwrap()).is_equal_to(1);
+
+        // a nonce the service did not hand out is refused before anything is looked up.
+        let key = EcKey::generate(&EC_GROUP).unwrap();
+        let mut bogus = crate::util::make_nonce(None);
+        let (res, _) = srv
+            .post_jws(
+                &key,
+                None,
+                &mut bogus,
+                &format!("{}/order", srv.url),
+                &json!({"identifiers": [{"type": "dns", "value": "foo.com"}]}),
+            )
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::BAD_REQUEST);
+
+        // the nonce handed out is still outstanding.
+        assert_that!(StoreNonceValidator(store).validate(&nonce).await).is_ok();
+
+        srv.shutdown().await;
+    }
 }
@@ -0,0 +1,267 @@
+// the OCSP responder (RFC 6960). This is not a part of ACME, but clients of the certificates we
+// issue will want to check their revocation status.
+
+use std::time::Duration;
+
+use openssl::ocsp::OcspResponseStatus;
+use ratpack::prelude::*;
+
+use super::{HandlerState, ServiceState};
+use crate::acme::ca::{ocsp_status_response, parse_ocsp_request, CertificateStatus};
+
+/// how long clients may cache OCSP responses for.
+const OCSP_RESPONSE_VALIDITY: Duration = Duration::from_secs(3600);
+
+/// ocsp_get answers an OCSP request supplied base64-encoded in the path (RFC 6960 A.1).
+pub(crate) async fn ocsp_get(
+    req: Request<Body>,
+    _resp: Option<Response<Body>>,
+    params: Params,
+    app: App<ServiceState, HandlerState>,
+    state: HandlerState,
+) -> HTTPResult<HandlerState> {
+    // the request is url-encoded base64, so only its special characters need decoding.
+    let encoded = params
+        .get("request")
+        .unwrap()
+        .replace("%2B", "+")
+        .replace("%2b", "+")
+        .replace("%2F", "/")
+        .replace("%2f", "/")
+        .replace("%3D", "=")
+        .replace("%3d", "=");
+
+    let body = match base64::decode(encoded) {
+        Ok(der) => ocsp_response(app, &der, &state.request_id()).await?,
+        Err(_) => ocsp_status_response(OcspResponseStatus::MALFORMED_REQUEST),
+    };
+
+    Ok((req, Some(ocsp_http_response(body)), state))
+}
+
+/// ocsp_post answers a DER-encoded OCSP request supplied as the request body.
+pub(crate) async fn ocsp_post(
+    mut req: Request<Body>,
+    _resp: Option<Response<Body>>,
+    _params: Params,
+    app: App<ServiceState, HandlerState>,
+    state: HandlerState,
+) -> HTTPResult<HandlerState> {
+    let der = hyper::body::to_bytes(req.body_mut()).await?;
+    let body = ocsp_response(app, &der, &state.request_id()).await?;
+
+    Ok((req, Some(ocsp_http_response(body)), state))
+}
+
+fn ocsp_http_response(body: Vec<u8>) -> Response<Body> {
+    Response::builder()
+        .header("content-type", "application/ocsp-response")
+        .status(StatusCode::OK)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+/// ocsp_response looks up the status of each certificate in the request and returns the
+/// DER-encoded OCSPResponse. Problems with the request itself, or with the CA, are reported in
+/// the OCSP response status rather than the HTTP status.
+async fn ocsp_response(
+    app: App<ServiceState, HandlerState>,
+    der: &[u8],
+    request_id: &str,
+) -> Result<Vec<u8>, ratpack::Error> {
+    let appstate_opt = app.state().await.clone().unwrap();
+    let appstate = appstate_opt.lock().await;
+
+    if !appstate.ocsp_responder {
+        return Err(ratpack::Error::StatusCode(
+            StatusCode::NOT_FOUND,
+            "the OCSP responder is not enabled".to_string(),
+        ));
+    }
+
+    let requests = match parse_ocsp_request(der) {
+        Ok(requests) => requests,
+        Err(e) => {
+            log::debug!("request {}: rejecting OCSP request: {}", request_id, e);
+            return Ok(ocsp_status_response(OcspResponseStatus::MALFORMED_REQUEST));
+        }
+    };
+
+    // certificates of a CA which was rotated away from are answered for by that CA. A response
+    // is signed by one CA, so certificates another CA issued in the same request are unknown.
+    let issuers = appstate.ca.issuers().await;
+    let ca = match requests
+        .iter()
+        .find_map(|request| {
+            issuers
+                .iter()
+                .find(|ca| ca.is_issuer_of(request).unwrap_or_default())
+        })
+        .or_else(|| issuers.first())
+    {
+        Some(ca) => ca.clone(),
+        None => return Ok(ocsp_status_response(OcspResponseStatus::TRY_LATER)),
+    };
+
+    let mut statuses = Vec::new();
+
+    for request in requests {
+        let status = if !ca.is_issuer_of(&request).unwrap_or_default() {
+            CertificateStatus::Unknown
+        } else if let Some(record) = appstate.db.get_revocation_details(&request.serial).await? {
+            CertificateStatus::Revoked {
+                revoked_at: record.revoked_at.with_timezone(&chrono::Utc),
+                reason: record.reason,
+            }
+        } else if appstate
+            .db
+            .get_orders_for_certificate(&request.serial)
+            .await?
+            .is_empty()
+        {
+            CertificateStatus::Unknown
+        } else {
+            CertificateStatus::Good
+        };
+
+        statuses.push((request, status));
+    }
+
+    match ca.sign_ocsp_response(&statuses, OCSP_RESPONSE_VALIDITY) {
+        Ok(response) => Ok(response),
+        Err(e) => {
+            log::error!(
+                "request {}: could not sign OCSP response: {}",
+                request_id,
+                e
+            );
+            Ok(ocsp_status_response(OcspResponseStatus::INTERNAL_ERROR))
+        }
+    }
+}
+
+mod tests {
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_ocsp_revoked_certificate() {
+        use crate::test::TestService;
+        use openssl::{
+            hash::MessageDigest,
+            ocsp::{OcspCertId, OcspCertStatus, OcspRequest, OcspResponse, OcspResponseStatus},
+            x509::X509,
+        };
+        use spectral::prelude::*;
+
+        let srv = TestService::new_with_state("test_ocsp_revoked_certificate", |state| {
+            state.with_ocsp_responder(true)
+        })
+        .await;
+
+        let dir = srv
+            .clone()
+            .run_certbot_certonly(None, "foo.com", "erik@hollensbe.org")
+            .await
+            .unwrap();
+
+        let mut live = dir.path().to_path_buf();
+        live.push("live/foo.com");
+
+        let cert = X509::from_pem(&std::fs::read(live.join("cert.pem")).unwrap()).unwrap();
+        let issuer = X509::from_pem(&std::fs::read(live.join("chain.pem")).unwrap()).unwrap();
+
+        let request = || {
+            let id = OcspCertId::from_cert(MessageDigest::sha1(), &cert, &issuer).unwrap();
+            let mut req = OcspRequest::new().unwrap();
+            req.add_id(id).unwrap();
+            hyper::Body::from(req.to_der().unwrap())
+        };
+
+        let status = |der: &[u8]| {
+            let resp = OcspResponse::from_der(der).unwrap();
+            assert_that!(resp.status()).is_equal_to(OcspResponseStatus::SUCCESSFUL);
+
+            let id = OcspCertId::from_cert(MessageDigest::sha1(), &cert, &issuer).unwrap();
+            resp.basic()
+                .unwrap()
+                .find_status(&id)
+                .map(|status| status.status)
+                .unwrap()
+        };
+
+        let mut res = srv.app.post("/ocsp", request()).await;
+        let body = hyper::body::to_bytes(res.body_mut()).await.unwrap();
+        assert_that!(status(&body)).is_equal_to(OcspCertStatus::GOOD);
+
+        let revoke = "revoke --cert-path /etc/letsencrypt/live/foo.com/cert.pem --reason keycompromise --no-delete-after-revoke".to_string();
+        assert_that!(srv.clone().certbot(Some(dir.clone()), revoke).await).is_ok();
+
+        let mut res = srv.app.post("/ocsp", request()).await;
+        let body = hyper::body::to_bytes(res.body_mut()).await.unwrap();
+        assert_that!(status(&body)).is_equal_to(OcspCertStatus::REVOKED);
+
+        let mut res = srv.app.post("/ocsp", hyper::Body::from("garbage")).await;
+        let body = hyper::body::to_bytes(res.body_mut()).await.unwrap();
+        assert_that!(OcspResponse::from_der(&body).unwrap().status())
+            .is_equal_to(OcspResponseStatus::MALFORMED_REQUEST);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_ocsp_after_rotation() {
+        use crate::acme::ca::{SigningAlgorithm, CA};
+        use crate::test::TestService;
+        use openssl::{
+            hash::MessageDigest,
+            ocsp::{OcspCertId, OcspCertStatus, OcspFlag, OcspRequest, OcspResponse},
+            stack::Stack,
+            x509::{store::X509StoreBuilder, X509},
+        };
+        use spectral::prelude::*;
+
+        let srv = TestService::new_with_state("test_ocsp_after_rotation", |state| {
+            state.with_ocsp_responder(true)
+        })
+        .await;
+
+        let dir = srv
+            .clone()
+            .run_certbot_certonly(None, "foo.com", "erik@hollensbe.org")
+            .await
+            .unwrap();
+
+        let mut live = dir.path().to_path_buf();
+        live.push("live/foo.com");
+
+        let cert = X509::from_pem(&std::fs::read(live.join("cert.pem")).unwrap()).unwrap();
+        let issuer = X509::from_pem(&std::fs::read(live.join("chain.pem")).unwrap()).unwrap();
+
+        srv.rotate_ca(
+            CA::new_test_ca_with_algorithm(SigningAlgorithm::EcdsaP256).unwrap(),
+            vec![],
+        )
+        .await;
+
+        let id = OcspCertId::from_cert(MessageDigest::sha1(), &cert, &issuer).unwrap();
+        let mut req = OcspRequest::new().unwrap();
+        req.add_id(id).unwrap();
+
+        let mut res = srv
+            .app
+            .post("/ocsp", hyper::Body::from(req.to_der().unwrap()))
+            .await;
+        let body = hyper::body::to_bytes(res.body_mut()).await.unwrap();
+        let basic = OcspResponse::from_der(&body).unwrap().basic().unwrap();
+
+        // the certificate is still known, and the response is signed by the CA which issued it.
+        let id = OcspCertId::from_cert(MessageDigest::sha1(), &cert, &issuer).unwrap();
+        assert_that!(basic.find_status(&id).unwrap().status).is_equal_to(OcspCertStatus::GOOD);
+
+        let mut store = X509StoreBuilder::new().unwrap();
+        store.add_cert(issuer.clone()).unwrap();
+        let store = store.build();
+
+        let mut certs = Stack::new().unwrap();
+        certs.push(issuer).unwrap();
+        assert_that!(basic.verify(&certs, &store, OcspFlag::empty())).is_ok();
+
+        srv.shutdown().await;
+    }
+}
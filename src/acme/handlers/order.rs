@@ -1,10 +1,6 @@
 use http::HeaderValue;
 use serde::{Deserialize, Serialize};
-use std::{
-    collections::HashSet,
-    convert::{TryFrom, TryInto},
-    net::IpAddr,
-};
+use std::{collections::HashSet, convert::TryFrom, net::IpAddr};
 use tokio_postgres::Transaction;
 use url::Url;
 use x509_parser::prelude::*;
@@ -12,12 +8,19 @@ use x509_parser::prelude::*;
 use ratpack::prelude::*;
 
 use crate::{
-    acme::{challenge::ChallengeType, ACMEIdentifier},
-    errors::{db::LoadError, ACMEValidationError},
-    models::{order::Challenge, Record},
+    acme::{
+        ca::CA, challenge::ChallengeType, ip_from_octets, rate_limit::RateLimitedEndpoint,
+        ACMEIdentifier,
+    },
+    errors::{ca::CsrError, db::LoadError, ACMEValidationError},
+    models::{
+        audit::{record_audit_event, AuditEvent, AuditEventType},
+        order::Challenge,
+        with_retry, Record, TenantId,
+    },
 };
 
-use super::{uri_to_url, HandlerState, ServiceState, REPLAY_NONCE_HEADER};
+use super::{uri_to_url, HandlerState, OrderEvent, ServiceState, REPLAY_NONCE_HEADER};
 
 /// RFC8555 7.1.3. Detailed read.
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -47,6 +50,10 @@ pub struct Order {
     pub certificate: Option<Url>,
 }
 
+/// carries the authorization's version (see [crate::models::Postgres::update_authorization_status])
+/// so that callers can perform subsequent updates against it.
+const AUTHORIZATION_VERSION_HEADER: &str = "Coyote-Authorization-Version";
+
 /// RFC8555 7.1.3 & 7.1.6
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
@@ -94,6 +101,39 @@ impl TryFrom<&str> for OrderStatus {
     }
 }
 
+/// unique_identifiers drops repeated identifiers from a newOrder request, keeping the first of
+/// each, so that every name gets one authorization. Names are compared without case or trailing
+/// dot. DNS names must be hostnames (see [crate::acme::dns::DNSName::validate_hostname]), and an
+/// order must be left with at least one identifier.
+fn unique_identifiers(
+    identifiers: Vec<ACMEIdentifier>,
+) -> Result<Vec<ACMEIdentifier>, ACMEValidationError> {
+    let mut seen = HashSet::new();
+    let mut unique = Vec::new();
+
+    for id in identifiers {
+        if let ACMEIdentifier::DNS(name) = &id {
+            name.validate_hostname()
+                .map_err(ACMEValidationError::InvalidIdentifier)?;
+        }
+
+        let key = (
+            id.kind(),
+            id.to_string().trim_end_matches('.').to_lowercase(),
+        );
+
+        if seen.insert(key) {
+            unique.push(id);
+        }
+    }
+
+    if unique.is_empty() {
+        return Err(ACMEValidationError::NoIdentifiers);
+    }
+
+    Ok(unique)
+}
+
 pub(crate) async fn new_order(
     req: Request<Body>,
     _resp: Option<Response<Body>>,
@@ -106,24 +146,111 @@ pub(crate) async fn new_order(
 
     match state.clone().jws {
         Some(jws) => {
+            if let Some(retry_after) = appstate
+                .check_rate_limit(RateLimitedEndpoint::NewOrder, &req, Some(jws.clone()))
+                .await?
+            {
+                let url = uri_to_url(appstate.request_baseurl(&req), req.uri().clone()).await?;
+                let resp = state.rate_limited(
+                    url,
+                    "too many requests; try again later",
+                    Some(retry_after),
+                )?;
+                return Ok((req, Some(resp), state));
+            }
+
+            if let Some(retry_after) = appstate
+                .check_account_rate_limit(&req, jws.clone(), "new-order")
+                .await?
+            {
+                let url = uri_to_url(appstate.request_baseurl(&req), req.uri().clone()).await?;
+                let resp = state.rate_limited(
+                    url,
+                    "too many requests for this account; try again later",
+                    Some(retry_after),
+                )?;
+                return Ok((req, Some(resp), state));
+            }
+
+            let account_id = appstate.account_id_for_jws(&req, jws.clone()).await?;
+
+            if appstate.certificate_quota_exceeded(account_id).await? {
+                let url = uri_to_url(appstate.request_baseurl(&req), req.uri().clone()).await?;
+                let resp = state.rate_limited(
+                    url,
+                    "this account has reached its certificate quota",
+                    None,
+                )?;
+                return Ok((req, Some(resp), state));
+            }
+
             let order: Order = jws.payload()?;
+            let identifiers = unique_identifiers(order.identifiers)?;
+
+            if let Some(policy) = &appstate.domain_policy {
+                for id in &identifiers {
+                    if let ACMEIdentifier::DNS(_) = id {
+                        if !policy.permits(&id.to_string()) {
+                            return Err(
+                                ACMEValidationError::RejectedIdentifier(id.to_string()).into()
+                            );
+                        }
+                    }
+                }
+            }
 
             let mut o = crate::models::order::Order::new(
                 order.not_before.map_or(None, |f| Some(f.into())),
                 order.not_after.map_or(None, |f| Some(f.into())),
             );
-            o.create(appstate.db.clone()).await?;
+            let now = std::time::SystemTime::now();
+            o.account_id = account_id;
+            o.expires = Some((now + appstate.order_lifetime).into());
+            let storage = appstate.request_storage(&req);
+            storage.create_order(&mut o).await?;
+
+            let mut authorizations = Vec::new();
+            let mut pending = false;
+
+            for id in identifiers {
+                // an identifier the account validated recently need not be validated again;
+                // the order takes over that authorization instead (RFC8555 7.1.4).
+                if let Some(account_id) = account_id {
+                    if let Some(authz) = storage
+                        .find_reusable_authorization(account_id, id.kind(), &id.clone().to_string())
+                        .await?
+                    {
+                        storage
+                            .link_authorization(&o.order_id, &authz.reference)
+                            .await?;
+                        authorizations.push(authz);
+                        continue;
+                    }
+                }
+
+                pending = true;
 
-            for id in order.identifiers {
                 let mut authz = crate::models::order::Authorization::default();
                 authz.identifier = Some(id.clone().to_string());
+                authz.kind = id.kind().to_string();
                 authz.order_id = o.order_id.clone();
-                authz.create(appstate.db.clone()).await?;
-
-                // for now at least, schedule one http-01 and dns-01 per name
+                authz.expires = (now + appstate.authz_lifetime).into();
+                storage.create_authorization(&mut authz).await?;
+
+                // for now at least, schedule one http-01, dns-01 and tls-alpn-01 per name. IP
+                // addresses cannot be validated over DNS (RFC8738 section 7), and we cannot
+                // dial them for tls-alpn-01 yet, so they only get http-01.
+                let challenges = match id {
+                    ACMEIdentifier::DNS(_) => vec![
+                        ChallengeType::DNS01,
+                        ChallengeType::HTTP01,
+                        ChallengeType::TLSALPN01,
+                    ],
+                    ACMEIdentifier::IP(_) => vec![ChallengeType::HTTP01],
+                };
 
                 let ip = req.extensions().get::<IpAddr>().unwrap();
-                for chall in vec![ChallengeType::DNS01, ChallengeType::HTTP01] {
+                for chall in challenges {
                     let mut c = Challenge::new(
                         o.order_id.clone(),
                         authz.reference.clone(),
@@ -133,16 +260,22 @@ pub(crate) async fn new_order(
                         OrderStatus::Pending,
                     );
 
-                    c.create(appstate.db.clone()).await?;
+                    storage.create_challenge(&mut c).await?;
                 }
+
+                authorizations.push(authz);
             }
 
-            let url = uri_to_url(appstate.clone().baseurl, req.uri().clone()).await?;
+            let baseurl = appstate.request_baseurl(&req);
+            let url = uri_to_url(baseurl.clone(), req.uri().clone()).await?;
 
-            let order: Order =
-                crate::models::order::Order::find(o.id()?.unwrap(), appstate.db.clone())
-                    .await?
-                    .into_handler_order(url.clone())?;
+            // everything was just created or reused, so there is nothing to load back. an order
+            // made up of reused authorizations only is ready for finalization at once.
+            if !pending {
+                o.status = OrderStatus::Valid;
+            }
+            o.authorizations = Some(authorizations);
+            let order: Order = o.clone().into_handler_order(baseurl.clone())?;
 
             return Ok((
                 req,
@@ -152,7 +285,7 @@ pub(crate) async fn new_order(
                         .status(StatusCode::CREATED)
                         .header(
                             "Location",
-                            url.join(&format!("./order/{}", o.order_id))?.to_string(),
+                            baseurl.join(&format!("order/{}", o.order_id))?.to_string(),
                         )
                         .body(Body::from(serde_json::to_string(&order)?))
                         .unwrap(),
@@ -182,12 +315,13 @@ pub(crate) async fn existing_order(
 
             let o = crate::models::order::Order::find_by_reference(
                 order_id.to_string(),
-                appstate.db.clone(),
+                appstate.request_db(&req),
             )
             .await?;
 
-            let url = uri_to_url(appstate.clone().baseurl, req.uri().clone()).await?;
-            let h_order = serde_json::to_string(&o.clone().into_handler_order(url.clone())?)?;
+            let baseurl = appstate.request_baseurl(&req);
+            let url = uri_to_url(baseurl.clone(), req.uri().clone()).await?;
+            let h_order = serde_json::to_string(&o.clone().into_handler_order(baseurl.clone())?)?;
 
             return Ok((
                 req,
@@ -197,7 +331,7 @@ pub(crate) async fn existing_order(
                         .status(StatusCode::OK)
                         .header(
                             "Location",
-                            url.join(&format!("./order/{}", o.order_id))?.to_string(),
+                            baseurl.join(&format!("order/{}", o.order_id))?.to_string(),
                         )
                         .body(Body::from(h_order))
                         .unwrap(),
@@ -230,13 +364,26 @@ pub(crate) async fn finalize_order(
 
     match state.clone().jws {
         Some(jws) => {
+            if let Some(retry_after) = appstate
+                .check_account_rate_limit(&req, jws.clone(), "finalize")
+                .await?
+            {
+                let url = uri_to_url(appstate.request_baseurl(&req), req.uri().clone()).await?;
+                let resp = state.rate_limited(
+                    url,
+                    "too many requests for this account; try again later",
+                    Some(retry_after),
+                )?;
+                return Ok((req, Some(resp), state));
+            }
+
             let finalize_order: FinalizeOrderRequest = jws.payload()?;
 
             let order_id = params.get("order_id").unwrap();
 
             let order = crate::models::order::Order::find_by_reference(
                 order_id.to_string(),
-                appstate.db.clone(),
+                appstate.request_db(&req),
             )
             .await?;
 
@@ -244,6 +391,12 @@ pub(crate) async fn finalize_order(
                 return Err(ACMEValidationError::InvalidRequest.into());
             }
 
+            if order.is_expired() {
+                return Err(
+                    ACMEValidationError::OrderNotReady("order has expired".to_string()).into(),
+                );
+            }
+
             // this code yields to the x509-parser crate to reap and check the subjectAltName
             // extensions. This is necessary because rust-openssl does not support this
             // functionality.
@@ -261,14 +414,15 @@ pub(crate) async fn finalize_order(
                 &base64::decode_config(finalize_order.csr.clone(), base64::URL_SAFE_NO_PAD)?;
 
             let (_, csr) = X509CertificationRequest::from_der(decoded)?;
-            csr.verify_signature()?;
+            if let Err(e) = csr.verify_signature() {
+                return Err(ACMEValidationError::BadCSR(e.to_string()).into());
+            }
 
             let mut mapping = HashSet::new();
 
-            for id in order.authorizations.clone().unwrap() {
-                for id in id.identifier {
-                    mapping.insert(id);
-                }
+            for authz in order.authorizations.clone().unwrap() {
+                let id = authz.acme_identifier()?;
+                mapping.insert((id.kind(), id.to_string()));
             }
 
             if let Some(extensions) = csr.requested_extensions() {
@@ -278,9 +432,38 @@ pub(crate) async fn finalize_order(
                             for val in name.general_names.iter() {
                                 match val {
                                     GeneralName::DNSName(val) => {
-                                        if mapping.contains(&val.to_string()) {
-                                            mapping.remove(&val.to_string());
-                                        } else {
+                                        if let Some(policy) = &appstate.domain_policy {
+                                            if !policy.permits(val) {
+                                                return Err(
+                                                    ACMEValidationError::RejectedIdentifier(
+                                                        val.to_string(),
+                                                    )
+                                                    .into(),
+                                                );
+                                            }
+                                        }
+
+                                        if !mapping.remove(&("dns", val.to_string())) {
+                                            return Err(ACMEValidationError::Other(
+                                                "CSR contains invalid names".to_string(),
+                                            )
+                                            .into());
+                                        }
+                                    }
+                                    GeneralName::IPAddress(val) => {
+                                        // comparing the parsed addresses also applies RFC5952
+                                        // canonicalization to IPv6.
+                                        let addr = match ip_from_octets(val) {
+                                            Some(addr) => addr,
+                                            None => {
+                                                return Err(ACMEValidationError::Other(
+                                                    "CSR contains invalid names".to_string(),
+                                                )
+                                                .into())
+                                            }
+                                        };
+
+                                        if !mapping.remove(&("ip", addr.to_string())) {
                                             return Err(ACMEValidationError::Other(
                                                 "CSR contains invalid names".to_string(),
                                             )
@@ -309,32 +492,105 @@ pub(crate) async fn finalize_order(
                 .into());
             }
 
-            if order.not_after.is_none() {
-                return Err(ACMEValidationError::Other(
-                    "not_after missing in order cadence".to_string(),
-                )
-                .into());
-            }
-
             let csr = openssl::x509::X509Req::from_der(decoded)?;
+            let serial = appstate.request_db(&req).next_serial().await?;
 
+            let timer = appstate.metrics.certificate_issuance.start_timer();
             let res = appstate
                 .ca
                 .clone()
-                .sign(
+                .sign_with_serial(
                     csr,
                     order.clone().not_before.unwrap().into(),
-                    order.clone().not_after.unwrap().into(),
+                    order.clone().not_after.map(|t| t.into()),
+                    serial,
                 )
                 .await;
+            timer.observe_duration();
+
+            let (cert, chain) = match res {
+                Ok((cert, chain)) => {
+                    // a certificate which does not verify against the chain of the CA which
+                    // signed it is not handed out, nor recorded.
+                    if let Err(e) = CA::verify_chain(&cert, &chain) {
+                        log::error!(
+                            "request {}: certificate issued for order {} failed verification: {}",
+                            state.request_id(),
+                            order.order_id,
+                            e
+                        );
+                        return Err(ratpack::Error::StatusCode(
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            "the issued certificate could not be verified".to_string(),
+                        ));
+                    }
 
-            match res {
-                Ok(cert) => order.record_certificate(cert, appstate.db.clone()).await?,
-                Err(e) => return Err(ACMEValidationError::Other(e.to_string()).into()),
+                    let serial = cert.serial_number().to_bn()?.to_hex_str()?.to_string();
+
+                    // the certificate and its audit entry are kept together or not at all; the
+                    // transaction is rolled back when dropped on an error.
+                    let db = appstate.request_db(&req);
+                    let mut client = db.clone().client().await?;
+                    let tx = client.transaction().await?;
+
+                    order.record_certificate(cert.clone(), &chain, &tx).await?;
+
+                    let mut event = AuditEvent::new(AuditEventType::CertificateIssued)
+                        .with_order_id(&order.order_id)
+                        .with_serial(&serial)
+                        .with_detail(serde_json::json!({
+                            "not_before": cert.not_before().to_string(),
+                            "not_after": cert.not_after().to_string(),
+                        }));
+                    if let Some(account_id) = order.account_id {
+                        event = event.with_account_id(account_id);
+                    }
+                    record_audit_event(&event, db.tenant(), &tx).await?;
+
+                    tx.commit().await?;
+
+                    log::info!(
+                        "request {}: issued certificate {} for order {}",
+                        state.request_id(),
+                        serial,
+                        order.order_id
+                    );
+                    (cert, chain)
+                }
+                Err(e @ (CsrError::ValidityTooLong { .. } | CsrError::InvalidSignature)) => {
+                    return Err(ACMEValidationError::BadCSR(e.to_string()).into())
+                }
+                Err(e) => {
+                    log::warn!(
+                        "request {}: could not sign certificate for order {}: {}",
+                        state.request_id(),
+                        order.order_id,
+                        e
+                    );
+                    return Err(ACMEValidationError::Other(e.to_string()).into());
+                }
             };
 
-            let url = uri_to_url(appstate.clone().baseurl, req.uri().clone()).await?;
-            let h_order = serde_json::to_string(&order.clone().into_handler_order(url.clone())?)?;
+            let baseurl = appstate.request_baseurl(&req);
+            let url = uri_to_url(baseurl.clone(), req.uri().clone()).await?;
+            let h_order =
+                serde_json::to_string(&order.clone().into_handler_order(baseurl.clone())?)?;
+
+            if appstate.has_order_event_hooks() {
+                // the chain of the CA which signed the certificate, which may have been rotated
+                // out since.
+                let mut pem = cert.to_pem()?;
+                for ca in &chain {
+                    pem.append(&mut ca.to_pem()?);
+                }
+
+                appstate.emit_order_event(OrderEvent {
+                    account_id: order.account_id,
+                    order_url: baseurl.join(&format!("order/{}", order.order_id))?,
+                    serial: cert.serial_number().to_bn()?.to_hex_str()?.to_string(),
+                    chain: String::from_utf8_lossy(&pem).to_string(),
+                });
+            }
 
             return Ok((
                 req,
@@ -344,7 +600,8 @@ pub(crate) async fn finalize_order(
                         .status(StatusCode::OK)
                         .header(
                             "Location",
-                            url.join(&format!("./order/{}", order.order_id))?
+                            baseurl
+                                .join(&format!("order/{}", order.order_id))?
                                 .to_string(),
                         )
                         .body(Body::from(h_order))
@@ -375,24 +632,37 @@ pub(crate) async fn get_certificate(
 
             let order = crate::models::order::Order::find_by_reference(
                 order_id.to_string(),
-                appstate.db.clone(),
+                appstate.request_db(&req),
             )
             .await?;
 
-            let cert = order.certificate(appstate.db.clone()).await?;
-            let mut cacert = appstate
-                .ca
-                .clone()
-                .ca()
-                .read()
-                .await
-                .clone()
-                .unwrap()
-                .certificate()
-                .to_pem()?;
+            let cert = order.certificate(appstate.request_db(&req)).await?;
 
+            // the chain of the CA which signed the certificate, rather than of the current CA.
+            // Certificates recorded without one are matched against the CAs still collected.
+            let mut cachain = match cert.chain.clone() {
+                Some(chain) => chain,
+                None => match appstate
+                    .ca
+                    .issuer_of(&openssl::x509::X509::from_pem(&cert.certificate)?)
+                    .await
+                {
+                    Some(ca) => ca.chain_pem()?,
+                    None => appstate
+                        .ca
+                        .clone()
+                        .ca()
+                        .read()
+                        .await
+                        .clone()
+                        .unwrap()
+                        .chain_pem()?,
+                },
+            };
+
+            // leaf, then the CA chain up to the root.
             let mut chain = cert.certificate;
-            chain.append(&mut cacert);
+            chain.append(&mut cachain);
 
             return Ok((
                 req,
@@ -424,15 +694,39 @@ pub struct Authorization {
     wildcard: Option<bool>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub enum AuthStatus {
     Pending,
     Valid,
     Deactivated,
+    Expired,
     Revoked,
 }
 
+impl AuthStatus {
+    /// from_challenges derives the status of an authorization from the statuses of its
+    /// challenges; one valid challenge is enough to make the authorization valid. Expired
+    /// authorizations are expired regardless of their challenges.
+    pub(crate) fn from_challenges<'a>(
+        deactivated: bool,
+        expired: bool,
+        statuses: impl Iterator<Item = &'a OrderStatus> + Clone,
+    ) -> Self {
+        if deactivated {
+            AuthStatus::Deactivated
+        } else if expired {
+            AuthStatus::Expired
+        } else if statuses.clone().any(|s| *s == OrderStatus::Valid) {
+            AuthStatus::Valid
+        } else if statuses.all(|s| *s != OrderStatus::Valid && *s != OrderStatus::Invalid) {
+            AuthStatus::Pending
+        } else {
+            AuthStatus::Revoked
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ChallengeAuthorization {
@@ -460,10 +754,12 @@ impl ChallengeAuthorization {
 impl Authorization {
     async fn from_authorization_id(
         auth_id: &str,
+        tenant: &TenantId,
         url: Url,
         tx: &Transaction<'_>,
     ) -> Result<Self, LoadError> {
-        let auth = crate::models::order::Authorization::find_by_reference(auth_id, &tx).await?;
+        let auth =
+            crate::models::order::Authorization::find_for_tenant(auth_id, tenant, &tx).await?;
         let challenges = auth.challenges(&tx).await?;
 
         let chs = challenges
@@ -482,21 +778,12 @@ impl Authorization {
 
         Ok(Self {
             expires: auth.expires.into(),
-            status: if auth.deleted_at.is_some() {
-                AuthStatus::Deactivated
-            } else {
-                if chs.iter().any(|ca| ca.status == OrderStatus::Valid) {
-                    AuthStatus::Valid
-                } else if chs
-                    .iter()
-                    .all(|ca| ca.status != OrderStatus::Valid && ca.status != OrderStatus::Invalid)
-                {
-                    AuthStatus::Pending
-                } else {
-                    AuthStatus::Revoked
-                }
-            },
-            identifier: auth.identifier.unwrap().try_into()?,
+            status: AuthStatus::from_challenges(
+                auth.deleted_at.is_some(),
+                auth.is_expired(),
+                chs.iter().map(|ca| &ca.status),
+            ),
+            identifier: auth.acme_identifier()?,
             challenges: chs,
             wildcard: None, // FIXME wtf? re-check spec
         })
@@ -517,7 +804,7 @@ pub(crate) async fn post_authz(
         Some(_jws) => {
             let auth_id = params.get("auth_id").unwrap();
 
-            let db = appstate.db.clone();
+            let db = appstate.request_db(&req);
             let mut lockeddb = db.client().await?;
             let tx = lockeddb.transaction().await?;
 
@@ -525,7 +812,8 @@ pub(crate) async fn post_authz(
 
             let authz = Authorization::from_authorization_id(
                 auth_id,
-                uri_to_url(appstate.clone().baseurl, req.uri().clone()).await?,
+                db.tenant(),
+                appstate.request_baseurl(&req),
                 &tx,
             )
             .await?;
@@ -536,7 +824,7 @@ pub(crate) async fn post_authz(
                 }
             }
 
-            let url = uri_to_url(appstate.clone().baseurl, req.uri().clone()).await?;
+            let url = uri_to_url(appstate.request_baseurl(&req), req.uri().clone()).await?;
             let builder = state
                 .decorate_response(url.clone(), Response::builder())?
                 .header(
@@ -544,6 +832,12 @@ pub(crate) async fn post_authz(
                     HeaderValue::from_str(&format!(r#"<{}>;rel="up""#, url.clone()))?,
                 );
 
+            let version =
+                crate::models::order::Authorization::find_for_tenant(auth_id, db.tenant(), &tx)
+                    .await?
+                    .version;
+            let builder = builder.header(AUTHORIZATION_VERSION_HEADER, version.to_string());
+
             let out = serde_json::to_string(&authz)?;
             return Ok((
                 req,
@@ -568,24 +862,69 @@ pub(crate) async fn post_challenge(
     let appstate = appstate_opt.lock().await;
 
     match state.clone().jws {
-        Some(_jws) => {
+        Some(jws) => {
             let challenge_id = params.get("challenge_id").unwrap();
 
-            let db = appstate.db.clone();
-            let mut lockeddb = db.client().await?;
+            let db = appstate.request_db(&req);
+            // only the connection is retried; the update below is not repeated once it may have
+            // been committed.
+            let mut lockeddb = with_retry(db.retry_policy(), || db.clone().client()).await?;
             let tx = lockeddb.transaction().await?;
 
             let mut ch = Challenge::find_by_reference(challenge_id.to_string(), &tx).await?;
-            if ch.status == OrderStatus::Pending {
+            // challenges belong to the tenant of their authorization.
+            let authz = crate::models::order::Authorization::find_for_tenant(
+                &ch.authorization_id,
+                db.tenant(),
+                &tx,
+            )
+            .await?;
+
+            // only the account which placed the order may answer its challenges.
+            let order =
+                crate::models::order::Order::find_by_reference(authz.order_id.clone(), db.clone())
+                    .await?;
+            let account_id = match appstate.account_id_for_jws(&req, jws).await? {
+                Some(account_id) if order.account_id == Some(account_id) => account_id,
+                _ => return Err(ACMEValidationError::ChallengeNotAuthorized.into()),
+            };
+
+            // a challenge is only worth attempting while its authorization and order can still
+            // become valid. No context at all is taken as a read replica which has not caught up
+            // yet, rather than a reason to refuse the attempt.
+            let attemptable = db
+                .get_challenge_with_context(
+                    &ch.authorization_id,
+                    &ch.challenge_type.clone().to_string(),
+                )
+                .await?
+                .map_or(true, |context| {
+                    context.authorization_status == AuthStatus::Pending
+                        && context.order_status != OrderStatus::Invalid
+                });
+
+            if ch.status == OrderStatus::Pending && attemptable {
                 ch.status = OrderStatus::Processing;
                 ch.persist_status(&tx).await?;
-                appstate.c.schedule(ch.clone()).await;
+
+                // the key authorization is made with the key the owner of the order has on
+                // record (RFC8555 8.1).
+                let thumbprint = appstate
+                    .key_thumbprint_for_account(&req, account_id)
+                    .await?;
+                appstate
+                    .c
+                    .schedule_with_key_authorization(
+                        ch.clone(),
+                        format!("{}.{}", ch.token, thumbprint),
+                    )
+                    .await;
             }
 
-            let authz = ch.authorization(&tx).await?;
             tx.commit().await?;
 
-            let url = uri_to_url(appstate.clone().baseurl, req.uri().clone()).await?;
+            let baseurl = appstate.request_baseurl(&req);
+            let url = uri_to_url(baseurl.clone(), req.uri().clone()).await?;
 
             // FIXME 7.5.1 indicates a Retry-After header can be sent to feed the client hints on how
             // often to retry here... we can use the polling value fed to the challenger for this
@@ -597,7 +936,7 @@ pub(crate) async fn post_challenge(
                     "Link",
                     HeaderValue::from_str(&format!(
                         r#"<{}>;rel="up""#,
-                        authz.into_url(url.clone())
+                        authz.into_url(baseurl.clone())
                     ))?,
                 );
 
@@ -606,7 +945,7 @@ pub(crate) async fn post_challenge(
                 Some(
                     builder
                         .body(Body::from(serde_json::to_string(
-                            &ChallengeAuthorization::from_challenge(&ch, ch.into_url(url))?,
+                            &ChallengeAuthorization::from_challenge(&ch, ch.into_url(baseurl))?,
                         )?))
                         .unwrap(),
                 ),
@@ -632,13 +971,10 @@ mod tests {
         let dir = Arc::new(TempDir::new().unwrap());
 
         for _ in 0..10 {
-            let res = srv.clone().certbot(
-                Some(dir.clone()),
-                format!("certonly --http-01-port {} --standalone -d 'foo.com' -m 'erik@hollensbe.org' --agree-tos", 
-                    rand::random::<u16>() % 10000 + 1024)
-                    .to_string(),
-            )
-            .await;
+            let res = srv
+                .clone()
+                .run_certbot_certonly(Some(dir.clone()), "foo.com", "erik@hollensbe.org")
+                .await;
 
             assert_that!(res).is_ok();
 
@@ -663,6 +999,233 @@ mod tests {
         }
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_order_flow_ecdsa() {
+        use crate::test::{EcCurve, TestService};
+        use openssl::x509::X509;
+        use spectral::prelude::*;
+
+        let srv = TestService::new("test_order_flow_ecdsa").await;
+
+        for curve in [EcCurve::P256, EcCurve::P384] {
+            let dir = srv
+                .certbot_with_ecc_key("foo.com", "erik@hollensbe.org", curve)
+                .await
+                .unwrap();
+
+            let cert =
+                X509::from_pem(&std::fs::read(dir.path().join("live/foo.com/cert.pem")).unwrap())
+                    .unwrap();
+
+            // the certificate is for the key certbot made, on the curve it was asked for.
+            let key = cert.public_key().unwrap().ec_key().unwrap();
+            assert_that!(key.group().curve_name())
+                .named(curve.certbot_name())
+                .is_equal_to(Some(curve.nid()));
+
+            assert_that!(srv.zlint("foo.com", dir.clone()).await).is_ok();
+        }
+
+        srv.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_order_flow_certificate_policy() {
+        use crate::acme::ca::{CertificatePolicy, CA};
+        use crate::test::{TestService, TestServiceOptions};
+        use spectral::prelude::*;
+        use x509_parser::prelude::*;
+
+        let ca = CA::new_test_ca()
+            .unwrap()
+            .with_certificate_policy(CertificatePolicy::domain_validated());
+        let srv = TestService::with_options(
+            "test_order_flow_certificate_policy",
+            TestServiceOptions {
+                ca: Some((ca, vec![])),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let dir = srv
+            .run_certbot_certonly(None, "foo.com", "erik@hollensbe.org")
+            .await
+            .unwrap();
+
+        let pem = std::fs::read(dir.path().join("live/foo.com/cert.pem")).unwrap();
+        let (_, pem) = parse_x509_pem(&pem).unwrap();
+        let (_, cert) = X509Certificate::from_der(&pem.contents).unwrap();
+
+        let extension = cert
+            .tbs_certificate
+            .extensions()
+            .iter()
+            .find(|e| {
+                matches!(
+                    e.parsed_extension(),
+                    ParsedExtension::CertificatePolicies(_)
+                )
+            })
+            .unwrap();
+        assert_that!(extension.critical).is_false();
+
+        match extension.parsed_extension() {
+            ParsedExtension::CertificatePolicies(policies) => {
+                assert_that!(policies.len()).is_equal_to(1);
+                assert_that!(policies[0].policy_id.to_id_string())
+                    .is_equal_to("2.23.140.1.2.1".to_string());
+            }
+            _ => unreachable!(),
+        }
+
+        // the CA/Browser Forum lints check DV certificates for subject fields they may not carry.
+        assert_that!(srv.zlint("foo.com", dir.clone()).await).is_ok();
+
+        srv.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_order_flow_intermediate_ca() {
+        use crate::test::TestService;
+        use openssl::x509::X509;
+        use spectral::prelude::*;
+
+        let srv = TestService::new_with_intermediate_ca("test_order_flow_intermediate_ca").await;
+
+        let dir = srv
+            .clone()
+            .run_certbot_certonly(None, "foo.com", "erik@hollensbe.org")
+            .await
+            .unwrap();
+
+        let mut path = dir.path().to_path_buf();
+        path.push("live/foo.com/fullchain.pem");
+        let chain = X509::stack_from_pem(&std::fs::read(path).unwrap()).unwrap();
+
+        // leaf, intermediate, root; each signed by the next.
+        assert_that!(chain.len()).is_equal_to(3);
+        for i in 0..2 {
+            assert_that!(chain[i].issuer_name().to_der().unwrap())
+                .is_equal_to(chain[i + 1].subject_name().to_der().unwrap());
+            assert_that!(chain[i]
+                .verify(&chain[i + 1].public_key().unwrap())
+                .unwrap())
+            .is_true();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_get_certificate_after_rotation() {
+        use crate::acme::{
+            ca::{SigningAlgorithm, CA},
+            jose::{ACMEPrivateKey, ACMEProtectedHeader, EC_GROUP, JWS},
+        };
+        use crate::test::TestService;
+        use hyper::{Body, StatusCode};
+        use openssl::{ec::EcKey, x509::X509};
+        use serde_json::json;
+        use spectral::prelude::*;
+        use url::Url;
+
+        let srv = TestService::new("test_get_certificate_after_rotation").await;
+
+        let dir = srv
+            .clone()
+            .run_certbot_certonly(None, "foo.com", "erik@hollensbe.org")
+            .await
+            .unwrap();
+
+        let mut path = dir.path().to_path_buf();
+        path.push("live/foo.com/fullchain.pem");
+        let issued = X509::stack_from_pem(&std::fs::read(path).unwrap()).unwrap();
+
+        srv.rotate_ca(
+            CA::new_test_ca_with_algorithm(SigningAlgorithm::EcdsaP256).unwrap(),
+            vec![],
+        )
+        .await;
+
+        let key = EcKey::generate(&EC_GROUP).unwrap();
+        let mut nonce = srv.app.head("/nonce").await.headers()[super::REPLAY_NONCE_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let (res, _) = srv
+            .post_jws(
+                &key,
+                None,
+                &mut nonce,
+                &format!("{}/account", srv.url),
+                &json!({"contact": ["mailto:erik@hollensbe.org"], "termsOfServiceAgreed": true}),
+            )
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::CREATED);
+        let kid = res.headers()["Location"].to_str().unwrap().to_string();
+
+        let order_id: String = srv
+            .pg
+            .db()
+            .client()
+            .await
+            .unwrap()
+            .query_one("select order_id from orders_certificate", &[])
+            .await
+            .unwrap()
+            .get(0);
+
+        let url = Url::parse(&format!("{}/order/{}/certificate", srv.url, order_id)).unwrap();
+        let protected = ACMEProtectedHeader::new_kid(Url::parse(&kid).unwrap(), url.clone(), nonce)
+            .with_alg("ES256");
+        let jws = JWS::new(&protected, "")
+            .sign(ACMEPrivateKey::ECDSA(key))
+            .unwrap();
+        let mut res = srv
+            .app
+            .post(url.path(), Body::from(serde_json::to_string(&jws).unwrap()))
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::OK);
+
+        // the certificate is served with the chain of the CA which signed it, not the current one.
+        let pem = hyper::body::to_bytes(res.body_mut()).await.unwrap();
+        let chain = X509::stack_from_pem(&pem).unwrap();
+        assert_that!(chain.len()).is_equal_to(2);
+        assert_that!(chain[1].to_der().unwrap()).is_equal_to(issued[1].to_der().unwrap());
+        assert_that!(chain[0].verify(&chain[1].public_key().unwrap()).unwrap()).is_true();
+
+        srv.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_order_flow_ip_address() {
+        use crate::test::TestService;
+        use spectral::prelude::*;
+        use x509_parser::prelude::*;
+
+        let srv = TestService::new("test_order_flow_ip_address").await;
+
+        let dir = srv
+            .clone()
+            .certbot(
+                None,
+                format!(
+                    "certonly --http-01-port {} --standalone --ip-address '127.0.0.1' --cert-name loopback -m 'erik@hollensbe.org' --agree-tos",
+                    rand::random::<u16>() % 10000 + 1024,
+                ),
+            )
+            .await;
+        assert_that!(dir).is_ok();
+
+        let mut path = dir.unwrap().path().to_path_buf();
+        path.push("live/loopback/cert.pem");
+        let pem = std::fs::read(path).unwrap();
+        let (_, pem) = parse_x509_pem(&pem).unwrap();
+        let (_, cert) = X509Certificate::from_der(&pem.contents).unwrap();
+        let (_, san) = cert.tbs_certificate.subject_alternative_name().unwrap();
+
+        assert_that!(san.general_names).is_equal_to(vec![GeneralName::IPAddress(&[127, 0, 0, 1])]);
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_order_flow_multi_domain() {
         use crate::test::TestService;
@@ -675,15 +1238,10 @@ mod tests {
         let dir = Arc::new(TempDir::new().unwrap());
 
         for domain in vec!["foo.com", "bar.com", "example.org", "example.com"] {
-            let res = srv.clone().certbot(
-                Some(dir.clone()),
-                format!(
-                    "certonly --http-01-port {} --standalone -d '{}' -m 'erik@hollensbe.org' --agree-tos",
-                    rand::random::<u16>() % 10000 + 1024,
-                    domain
-                ),
-            )
-            .await;
+            let res = srv
+                .clone()
+                .run_certbot_certonly(Some(dir.clone()), domain, "erik@hollensbe.org")
+                .await;
 
             assert_that!(res).is_ok();
 
@@ -707,4 +1265,1362 @@ mod tests {
             assert_that!(srv.zlint(domain, dir.clone()).await).is_ok();
         }
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_order_flow_ca_algorithms() {
+        use crate::{acme::ca::SigningAlgorithm, test::TestService};
+        use openssl::x509::X509;
+        use spectral::prelude::*;
+
+        for (name, algorithm) in vec![
+            ("test_order_flow_ca_rsa2048", SigningAlgorithm::Rsa2048),
+            ("test_order_flow_ca_ecdsap256", SigningAlgorithm::EcdsaP256),
+            ("test_order_flow_ca_ed25519", SigningAlgorithm::Ed25519),
+        ] {
+            let srv = TestService::new_with_ca_algorithm(name, algorithm).await;
+
+            let res = srv
+                .clone()
+                .run_certbot_certonly(None, "foo.com", "erik@hollensbe.org")
+                .await;
+
+            assert_that!(res).is_ok();
+
+            let mut root = res.unwrap().path().to_path_buf();
+            root.push("live/foo.com");
+
+            let cert = X509::from_pem(&std::fs::read(root.join("cert.pem")).unwrap()).unwrap();
+            let chain = X509::from_pem(&std::fs::read(root.join("chain.pem")).unwrap()).unwrap();
+
+            assert_that!(cert.verify(&chain.public_key().unwrap()).unwrap()).is_true();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_order_flow_p384_account() {
+        use crate::acme::{
+            ca::SigningAlgorithm,
+            jose::{ACMEPrivateKey, ACMEProtectedHeader, JWK, JWS},
+        };
+        use crate::test::TestService;
+        use hyper::{Body, Response, StatusCode};
+        use openssl::{
+            ec::EcKey,
+            nid::Nid,
+            pkey::{PKey, Private},
+            x509::{X509Req, X509},
+        };
+        use serde_json::{json, Value};
+        use spectral::prelude::*;
+        use std::convert::TryFrom;
+        use url::Url;
+
+        // a minimal ACME client: it signs the payload with the P-384 account key, and keeps the
+        // replay nonce of each response for the next request.
+        async fn post<T: serde::Serialize + ?Sized>(
+            srv: &TestService,
+            key: &EcKey<Private>,
+            kid: Option<&str>,
+            nonce: &mut String,
+            url: &str,
+            payload: &T,
+        ) -> (Response<Body>, Value) {
+            let url = Url::parse(url).unwrap();
+            let protected = match kid {
+                Some(kid) => ACMEProtectedHeader::new_kid(
+                    Url::parse(kid).unwrap(),
+                    url.clone(),
+                    nonce.clone(),
+                ),
+                None => ACMEProtectedHeader::new_jwk(
+                    JWK::try_from(key).unwrap(),
+                    url.clone(),
+                    nonce.clone(),
+                ),
+            }
+            .with_alg("ES384");
+
+            let jws = JWS::new(&protected, payload)
+                .sign(ACMEPrivateKey::ECDSA(key.clone()))
+                .unwrap();
+
+            let mut res = srv
+                .app
+                .post(url.path(), Body::from(serde_json::to_string(&jws).unwrap()))
+                .await;
+
+            *nonce = res.headers()[super::REPLAY_NONCE_HEADER]
+                .to_str()
+                .unwrap()
+                .to_string();
+
+            let body = hyper::body::to_bytes(res.body_mut()).await.unwrap();
+            let value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+            (res, value)
+        }
+
+        let srv = TestService::new_with_ca_algorithm(
+            "test_order_flow_p384_account",
+            SigningAlgorithm::EcdsaP384,
+        )
+        .await;
+
+        let key = EcKey::generate(&crate::acme::jose::EC_GROUP_P384).unwrap();
+        let mut nonce = srv.app.head("/nonce").await.headers()[super::REPLAY_NONCE_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let (res, _) = post(
+            &srv,
+            &key,
+            None,
+            &mut nonce,
+            &format!("{}/account", srv.url),
+            &json!({"contact": ["mailto:erik@hollensbe.org"], "termsOfServiceAgreed": true}),
+        )
+        .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::CREATED);
+        let kid = res.headers()["Location"].to_str().unwrap().to_string();
+
+        let (res, order) = post(
+            &srv,
+            &key,
+            Some(&kid),
+            &mut nonce,
+            &format!("{}/order", srv.url),
+            &json!({"identifiers": [{"type": "dns", "value": "foo.com"}]}),
+        )
+        .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::CREATED);
+        let order_url = res.headers()["Location"].to_str().unwrap().to_string();
+
+        for authz in order["authorizations"].as_array().unwrap() {
+            let authz = authz.as_str().unwrap();
+            let (_, body) = post(&srv, &key, Some(&kid), &mut nonce, authz, "").await;
+
+            let challenge = body["challenges"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|c| c["type"] == "http-01")
+                .unwrap()
+                .clone();
+
+            let (res, _) = post(
+                &srv,
+                &key,
+                Some(&kid),
+                &mut nonce,
+                challenge["url"].as_str().unwrap(),
+                &json!({}),
+            )
+            .await;
+            assert_that!(res.status()).is_equal_to(StatusCode::OK);
+
+            loop {
+                let (_, body) = post(&srv, &key, Some(&kid), &mut nonce, authz, "").await;
+                if body["status"] == "valid" {
+                    break;
+                }
+
+                srv.wait_for_reconcile().await;
+            }
+        }
+
+        // the certificate key is P-384 as well.
+        let certkey =
+            PKey::from_ec_key(EcKey::generate(&crate::acme::jose::EC_GROUP_P384).unwrap()).unwrap();
+        let mut name = openssl::x509::X509Name::builder().unwrap();
+        name.append_entry_by_text("CN", "foo.com").unwrap();
+        let mut csr = X509Req::builder().unwrap();
+        csr.set_subject_name(&name.build()).unwrap();
+        let mut extensions = openssl::stack::Stack::new().unwrap();
+        extensions
+            .push(
+                openssl::x509::extension::SubjectAlternativeName::new()
+                    .dns("foo.com")
+                    .build(&csr.x509v3_context(None))
+                    .unwrap(),
+            )
+            .unwrap();
+        csr.add_extensions(&extensions).unwrap();
+        csr.set_pubkey(&certkey).unwrap();
+        csr.sign(&certkey, openssl::hash::MessageDigest::sha384())
+            .unwrap();
+        let csr = base64::encode_config(csr.build().to_der().unwrap(), base64::URL_SAFE_NO_PAD);
+
+        let (res, _) = post(
+            &srv,
+            &key,
+            Some(&kid),
+            &mut nonce,
+            order["finalize"].as_str().unwrap(),
+            &json!({ "csr": csr }),
+        )
+        .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::OK);
+
+        let (_, order) = post(&srv, &key, Some(&kid), &mut nonce, &order_url, "").await;
+        assert_that!(order["status"]).is_equal_to(json!("valid"));
+
+        let url = Url::parse(order["certificate"].as_str().unwrap()).unwrap();
+        let protected = ACMEProtectedHeader::new_kid(Url::parse(&kid).unwrap(), url.clone(), nonce)
+            .with_alg("ES384");
+        let jws = JWS::new(&protected, "")
+            .sign(ACMEPrivateKey::ECDSA(key))
+            .unwrap();
+        let mut res = srv
+            .app
+            .post(url.path(), Body::from(serde_json::to_string(&jws).unwrap()))
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::OK);
+
+        let pem = hyper::body::to_bytes(res.body_mut()).await.unwrap();
+        let chain = X509::stack_from_pem(&pem).unwrap();
+        assert_that!(chain.len()).is_greater_than_or_equal_to(2);
+        assert_that!(chain[0].public_key().unwrap().public_eq(&certkey)).is_true();
+        assert_that!(chain[0].signature_algorithm().object().nid())
+            .is_equal_to(Nid::ECDSA_WITH_SHA384);
+        assert_that!(chain[0].verify(&chain[1].public_key().unwrap()).unwrap()).is_true();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_order_flow_ed25519_account() {
+        use crate::acme::jose::{ACMEPrivateKey, ACMEProtectedHeader, JWK, JWS};
+        use crate::test::TestService;
+        use hyper::{Body, Response, StatusCode};
+        use openssl::{
+            ec::EcKey,
+            pkey::{PKey, Private},
+            x509::X509Req,
+        };
+        use serde_json::{json, Value};
+        use spectral::prelude::*;
+        use url::Url;
+
+        // a minimal ACME client: it signs the payload with the Ed25519 account key (RFC8037), and
+        // keeps the replay nonce of each response for the next request.
+        async fn post<T: serde::Serialize + ?Sized>(
+            srv: &TestService,
+            key: &PKey<Private>,
+            kid: Option<&str>,
+            nonce: &mut String,
+            url: &str,
+            payload: &T,
+        ) -> (Response<Body>, Value) {
+            let url = Url::parse(url).unwrap();
+            let protected = match kid {
+                Some(kid) => ACMEProtectedHeader::new_kid(
+                    Url::parse(kid).unwrap(),
+                    url.clone(),
+                    nonce.clone(),
+                ),
+                None => ACMEProtectedHeader::new_jwk(
+                    JWK {
+                        alg: Some("EdDSA".to_string()),
+                        crv: Some("Ed25519".to_string()),
+                        kty: "OKP".to_string(),
+                        _use: None,
+                        x: Some(base64::encode_config(
+                            key.raw_public_key().unwrap(),
+                            base64::URL_SAFE_NO_PAD,
+                        )),
+                        y: None,
+                        n: None,
+                        e: None,
+                    },
+                    url.clone(),
+                    nonce.clone(),
+                ),
+            }
+            .with_alg("EdDSA");
+
+            let jws = JWS::new(&protected, payload)
+                .sign(ACMEPrivateKey::EdDSA(key.clone()))
+                .unwrap();
+
+            let mut res = srv
+                .app
+                .post(url.path(), Body::from(serde_json::to_string(&jws).unwrap()))
+                .await;
+
+            *nonce = res.headers()[super::REPLAY_NONCE_HEADER]
+                .to_str()
+                .unwrap()
+                .to_string();
+
+            let body = hyper::body::to_bytes(res.body_mut()).await.unwrap();
+            let value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+            (res, value)
+        }
+
+        let srv = TestService::new("test_order_flow_ed25519_account").await;
+
+        let key = PKey::generate_ed25519().unwrap();
+        let mut nonce = srv.app.head("/nonce").await.headers()[super::REPLAY_NONCE_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let (res, _) = post(
+            &srv,
+            &key,
+            None,
+            &mut nonce,
+            &format!("{}/account", srv.url),
+            &json!({"contact": ["mailto:erik@hollensbe.org"], "termsOfServiceAgreed": true}),
+        )
+        .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::CREATED);
+        let kid = res.headers()["Location"].to_str().unwrap().to_string();
+
+        let (res, order) = post(
+            &srv,
+            &key,
+            Some(&kid),
+            &mut nonce,
+            &format!("{}/order", srv.url),
+            &json!({"identifiers": [{"type": "dns", "value": "foo.com"}]}),
+        )
+        .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::CREATED);
+        let order_url = res.headers()["Location"].to_str().unwrap().to_string();
+
+        for authz in order["authorizations"].as_array().unwrap() {
+            let authz = authz.as_str().unwrap();
+            let (_, body) = post(&srv, &key, Some(&kid), &mut nonce, authz, "").await;
+
+            let challenge = body["challenges"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|c| c["type"] == "http-01")
+                .unwrap()
+                .clone();
+
+            let (res, _) = post(
+                &srv,
+                &key,
+                Some(&kid),
+                &mut nonce,
+                challenge["url"].as_str().unwrap(),
+                &json!({}),
+            )
+            .await;
+            assert_that!(res.status()).is_equal_to(StatusCode::OK);
+
+            loop {
+                let (_, body) = post(&srv, &key, Some(&kid), &mut nonce, authz, "").await;
+                if body["status"] == "valid" {
+                    break;
+                }
+
+                srv.wait_for_reconcile().await;
+            }
+        }
+
+        let certkey =
+            PKey::from_ec_key(EcKey::generate(&crate::acme::jose::EC_GROUP).unwrap()).unwrap();
+        let mut name = openssl::x509::X509Name::builder().unwrap();
+        name.append_entry_by_text("CN", "foo.com").unwrap();
+        let mut csr = X509Req::builder().unwrap();
+        csr.set_subject_name(&name.build()).unwrap();
+        let mut extensions = openssl::stack::Stack::new().unwrap();
+        extensions
+            .push(
+                openssl::x509::extension::SubjectAlternativeName::new()
+                    .dns("foo.com")
+                    .build(&csr.x509v3_context(None))
+                    .unwrap(),
+            )
+            .unwrap();
+        csr.add_extensions(&extensions).unwrap();
+        csr.set_pubkey(&certkey).unwrap();
+        csr.sign(&certkey, openssl::hash::MessageDigest::sha256())
+            .unwrap();
+        let csr = base64::encode_config(csr.build().to_der().unwrap(), base64::URL_SAFE_NO_PAD);
+
+        let (res, _) = post(
+            &srv,
+            &key,
+            Some(&kid),
+            &mut nonce,
+            order["finalize"].as_str().unwrap(),
+            &json!({ "csr": csr }),
+        )
+        .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::OK);
+
+        let (_, order) = post(&srv, &key, Some(&kid), &mut nonce, &order_url, "").await;
+        assert_that!(order["status"]).is_equal_to(json!("valid"));
+
+        let (res, _) = post(
+            &srv,
+            &key,
+            Some(&kid),
+            &mut nonce,
+            order["certificate"].as_str().unwrap(),
+            "",
+        )
+        .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::OK);
+
+        // the key is stored as the OKP key it was registered as.
+        let stored =
+            crate::models::account::JWK::find_by_kid(Url::parse(&kid).unwrap(), srv.pg.db())
+                .await
+                .unwrap();
+        assert_that!(stored.kty).is_equal_to(Some("OKP".to_string()));
+        assert_that!(stored.crv).is_equal_to(Some("Ed25519".to_string()));
+        assert_that!(stored.y).is_none();
+
+        srv.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_order_certificate_quota() {
+        use crate::test::TestService;
+        use spectral::prelude::*;
+        use std::sync::Arc;
+        use tempfile::TempDir;
+
+        let srv = TestService::new_with_state("test_order_certificate_quota", |state| {
+            state.with_max_certificates_per_account(2)
+        })
+        .await;
+
+        // the account is shared between runs through the certbot directory.
+        let dir = Arc::new(TempDir::new().unwrap());
+
+        for (domain, allowed) in vec![("foo.com", true), ("bar.com", true), ("baz.com", false)] {
+            let res = srv
+                .clone()
+                .run_certbot_certonly(Some(dir.clone()), domain, "erik@hollensbe.org")
+                .await;
+
+            // certbot surfaces the rateLimited problem as a failed run.
+            if allowed {
+                assert_that!(res).is_ok();
+            } else {
+                assert_that!(res).is_err();
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_order_expiry() {
+        use crate::test::TestService;
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        let srv = TestService::new("test_order_expiry").await;
+
+        let res = srv
+            .clone()
+            .run_certbot_certonly(None, "foo.com", "erik@hollensbe.org")
+            .await;
+        assert_that!(res).is_ok();
+
+        // new orders and authorizations carry the default lifetimes.
+        let client = srv.pg.db().client().await.unwrap();
+        let row = client
+            .query_one(
+                "
+                select
+                    round(extract(epoch from o.expires - o.created_at) / 86400)::integer as order_days,
+                    round(extract(epoch from a.expires - a.created_at) / 86400)::integer as authz_days
+                from orders o inner join orders_authorizations a on a.order_id = o.order_id
+                ",
+                &[],
+            )
+            .await
+            .unwrap();
+        drop(client);
+
+        assert_that!(row.get::<_, i32>("order_days")).is_equal_to(7);
+        assert_that!(row.get::<_, i32>("authz_days")).is_equal_to(30);
+
+        // an order which expired before it was finalized is refused with orderNotReady, which
+        // certbot surfaces as a failed run.
+        let srv = TestService::new_with_state("test_order_expiry_expired", |state| {
+            state.with_order_lifetime(Duration::ZERO)
+        })
+        .await;
+
+        let res = srv
+            .clone()
+            .run_certbot_certonly(None, "foo.com", "erik@hollensbe.org")
+            .await;
+        assert_that!(res).is_err();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_order_event_hook() {
+        use crate::acme::handlers::OrderEvent;
+        use crate::test::TestService;
+        use futures::FutureExt;
+        use openssl::bn::BigNum;
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<OrderEvent>();
+
+        let srv = TestService::new_with_state("test_order_event_hook", move |state| {
+            state.with_order_event_hook(Box::new(move |event| {
+                let tx = tx.clone();
+                async move { tx.send(event).unwrap() }.boxed()
+            }))
+        })
+        .await;
+
+        let res = srv
+            .clone()
+            .run_certbot_certonly(None, "foo.com", "erik@hollensbe.org")
+            .await;
+        assert_that!(res).is_ok();
+
+        let event = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let orders = srv
+            .pg
+            .db()
+            .get_orders_for_certificate(&BigNum::from_hex_str(&event.serial).unwrap().to_vec())
+            .await
+            .unwrap();
+        assert_that!(orders.len()).is_equal_to(1);
+        assert_that!(event.account_id).is_some();
+        assert_that!(event.account_id).is_equal_to(orders[0].account_id);
+        assert_that!(event.order_url.to_string())
+            .is_equal_to(format!("{}/order/{}", srv.url, orders[0].order_id));
+        assert_that!(event.chain.matches("BEGIN CERTIFICATE").count()).is_greater_than(1);
+
+        // the chain is the one the certificate was signed with, as recorded alongside it.
+        let cert = orders[0].certificate(srv.pg.db()).await.unwrap();
+        let mut chain = cert.certificate;
+        chain.extend(cert.chain.unwrap());
+        assert_that!(event.chain).is_equal_to(String::from_utf8(chain).unwrap());
+
+        // one order, one event.
+        assert_that!(rx.try_recv().is_err()).is_true();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_new_order_storage() {
+        use crate::acme::jose::EC_GROUP;
+        use crate::models::{memory::MemoryStore, Storage};
+        use crate::test::{CountingStorage, TestService, TestServiceOptions};
+        use hyper::StatusCode;
+        use openssl::ec::EcKey;
+        use serde_json::json;
+        use spectral::prelude::*;
+        use std::sync::Arc;
+
+        // no postgres is started: the order is kept in the memory store alone.
+        let store = MemoryStore::new();
+        let mut counting = None;
+        let srv = TestService::with_options_and_state(
+            "test_new_order_storage",
+            TestServiceOptions {
+                memory_store: Some(store.clone()),
+                ..Default::default()
+            },
+            |state| {
+                let storage = CountingStorage::new(Arc::new(store.clone()));
+                counting = Some(storage.clone());
+                state.with_storage(Box::new(storage))
+            },
+        )
+        .await;
+        let counting = counting.unwrap();
+
+        let mut nonce = srv.app.head("/nonce").await.headers()[super::REPLAY_NONCE_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let key = EcKey::generate(&EC_GROUP).unwrap();
+        let (res, body) = srv
+            .post_jws(
+                &key,
+                None,
+                &mut nonce,
+                &format!("{}/order", srv.url),
+                &json!({"identifiers": [{"type": "dns", "value": "foo.com"}]}),
+            )
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::CREATED);
+        assert_that!(body["status"]).is_equal_to(json!("pending"));
+
+        // one order for one name, which gets an authorization with http-01, dns-01 and tls-alpn-01.
+        assert_that!(counting.calls("account_deactivated_for_key")).is_equal_to(1);
+        assert_that!(counting.calls("create_order")).is_equal_to(1);
+        assert_that!(counting.calls("create_authorization")).is_equal_to(1);
+        assert_that!(counting.calls("create_challenge")).is_equal_to(3);
+        assert_that!(counting.calls("create_account")).is_equal_to(0);
+
+        let counts = store.order_status_counts().await.unwrap();
+        assert_that!(counts.get("pending")).is_equal_to(Some(&1));
+
+        srv.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_order_audit_log() {
+        use crate::models::audit::AuditEventType;
+        use crate::test::TestService;
+        use openssl::x509::X509;
+        use spectral::prelude::*;
+        use std::sync::Arc;
+        use tempfile::TempDir;
+
+        let srv = TestService::new("test_order_audit_log").await;
+        let start = chrono::Utc::now() - chrono::Duration::seconds(1);
+
+        let dir = Arc::new(TempDir::new().unwrap());
+        let res = srv
+            .clone()
+            .run_certbot_certonly(Some(dir.clone()), "foo.com", "erik@hollensbe.org")
+            .await;
+        assert_that!(res).is_ok();
+
+        let mut path = dir.path().to_path_buf();
+        path.push("live/foo.com/cert.pem");
+        let cert = X509::from_pem(&std::fs::read(path).unwrap()).unwrap();
+        let serial = cert
+            .serial_number()
+            .to_bn()
+            .unwrap()
+            .to_hex_str()
+            .unwrap()
+            .to_string();
+
+        let events = srv.pg.db().list_audit_events(start, 100).await.unwrap();
+
+        let created = events
+            .iter()
+            .filter(|e| e.event_type == AuditEventType::AccountCreated)
+            .collect::<Vec<_>>();
+        assert_that!(created.len()).is_equal_to(1);
+
+        let issued = events
+            .iter()
+            .filter(|e| e.event_type == AuditEventType::CertificateIssued)
+            .collect::<Vec<_>>();
+        assert_that!(issued.len()).is_equal_to(1);
+        assert_that!(issued[0].serial).is_equal_to(Some(serial));
+        assert_that!(issued[0].order_id).is_some();
+        assert_that!(issued[0].account_id).is_equal_to(created[0].account_id.clone());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_finalize_rollback() {
+        use crate::test::TestService;
+        use spectral::prelude::*;
+
+        let srv = TestService::new("test_finalize_rollback").await;
+
+        // the audit entry of the certificate cannot be written, which fails finalization after
+        // the certificate was signed and recorded.
+        let c = srv.pg.db().client().await.unwrap();
+        c.batch_execute(
+            "alter table audit_log add constraint no_issuance check (event_type <> 'certificate_issued')",
+        )
+        .await
+        .unwrap();
+
+        let res = srv
+            .clone()
+            .run_certbot_certonly(None, "foo.com", "erik@hollensbe.org")
+            .await;
+        assert_that!(res).is_err();
+
+        let row = c
+            .query_one("select count(*) from orders_certificate", &[])
+            .await
+            .unwrap();
+        assert_that!(row.get::<_, i64>(0)).is_equal_to(0);
+
+        // certbot got as far as placing the order.
+        let row = c
+            .query_one("select count(*) from orders", &[])
+            .await
+            .unwrap();
+        assert_that!(row.get::<_, i64>(0)).is_equal_to(1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_order_cert_profile() {
+        use crate::acme::ca::CertProfile;
+        use crate::test::TestService;
+        use openssl::{asn1::Asn1Time, x509::X509};
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        let day = Duration::from_secs(24 * 60 * 60);
+
+        let srv = TestService::new_with_state("test_order_cert_profile", |state| {
+            state.with_cert_profile(CertProfile {
+                not_before_offset: Duration::from_secs(300),
+                not_after_offset: 7 * day,
+                max_validity: Some(8 * day),
+            })
+        })
+        .await;
+
+        let dir = srv
+            .clone()
+            .run_certbot_certonly(None, "foo.com", "erik@hollensbe.org")
+            .await
+            .unwrap();
+
+        let mut path = dir.path().to_path_buf();
+        path.push("live/foo.com/cert.pem");
+        let cert = X509::from_pem(&std::fs::read(path).unwrap()).unwrap();
+
+        let now = Asn1Time::days_from_now(0).unwrap();
+        let diff = now.diff(cert.not_after()).unwrap();
+        assert_that!(diff.days).is_equal_to(6);
+        let diff = cert.not_before().diff(&now).unwrap();
+        assert_that!(diff.days).is_equal_to(0);
+        assert_that!(diff.secs).is_greater_than_or_equal_to(300);
+
+        // certificates longer than max_validity are refused with badCSR.
+        let srv = TestService::new_with_state("test_order_cert_profile_max", |state| {
+            state.with_cert_profile(CertProfile {
+                not_before_offset: Duration::ZERO,
+                not_after_offset: 90 * day,
+                max_validity: Some(30 * day),
+            })
+        })
+        .await;
+
+        let res = srv
+            .clone()
+            .run_certbot_certonly(None, "foo.com", "erik@hollensbe.org")
+            .await;
+        assert_that!(res).is_err();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_order_rate_limit() {
+        use crate::acme::rate_limit::{RateLimitConfig, RateLimitedEndpoint, RequestsPerMinute};
+        use crate::test::TestService;
+        use spectral::prelude::*;
+        use std::sync::Arc;
+        use tempfile::TempDir;
+
+        let srv = TestService::new_with_state("test_order_rate_limit", |state| {
+            state.with_rate_limits(
+                RateLimitConfig::new()
+                    .per_account(RateLimitedEndpoint::NewOrder, RequestsPerMinute(1)),
+            )
+        })
+        .await;
+
+        let dir = Arc::new(TempDir::new().unwrap());
+
+        for (domain, allowed) in vec![("foo.com", true), ("bar.com", false)] {
+            let res = srv
+                .clone()
+                .run_certbot_certonly(Some(dir.clone()), domain, "erik@hollensbe.org")
+                .await;
+
+            if allowed {
+                assert_that!(res).is_ok();
+            } else {
+                assert_that!(res).is_err();
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_order_account_rate_limit() {
+        use crate::acme::jose::EC_GROUP;
+        use crate::test::TestService;
+        use hyper::StatusCode;
+        use openssl::ec::EcKey;
+        use serde_json::json;
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        // a day-long window so the test can't straddle a boundary in practice.
+        let srv = TestService::new_with_state("test_order_account_rate_limit", |state| {
+            state.with_account_rate_limit(Duration::from_secs(86400), 2)
+        })
+        .await;
+        let order_url = format!("{}/order", srv.url);
+
+        let mut nonce = srv.app.head("/nonce").await.headers()[super::REPLAY_NONCE_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let key = EcKey::generate(&EC_GROUP).unwrap();
+        let (res, _) = srv
+            .post_jws(
+                &key,
+                None,
+                &mut nonce,
+                &format!("{}/account", srv.url),
+                &json!({"contact": ["mailto:erik@hollensbe.org"], "termsOfServiceAgreed": true}),
+            )
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::CREATED);
+        let kid = res.headers()["Location"].to_str().unwrap().to_string();
+
+        for domain in vec!["foo.com", "bar.com"] {
+            let (res, _) = srv
+                .post_jws(
+                    &key,
+                    Some(&kid),
+                    &mut nonce,
+                    &order_url,
+                    &json!({"identifiers": [{"type": "dns", "value": domain}]}),
+                )
+                .await;
+            assert_that!(res.status()).is_equal_to(StatusCode::CREATED);
+        }
+
+        let (res, body) = srv
+            .post_jws(
+                &key,
+                Some(&kid),
+                &mut nonce,
+                &order_url,
+                &json!({"identifiers": [{"type": "dns", "value": "baz.com"}]}),
+            )
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::TOO_MANY_REQUESTS);
+        assert_that!(body["type"]).is_equal_to(json!("urn:ietf:params:acme:error:rateLimited"));
+
+        let retry_after = res.headers()["Retry-After"]
+            .to_str()
+            .unwrap()
+            .parse::<u64>()
+            .unwrap();
+        assert_that!(retry_after).is_greater_than(0);
+        assert_that!(retry_after).is_less_than_or_equal_to(86400);
+
+        // another account is not held to the first one's limit.
+        let key = EcKey::generate(&EC_GROUP).unwrap();
+        let (res, _) = srv
+            .post_jws(
+                &key,
+                None,
+                &mut nonce,
+                &format!("{}/account", srv.url),
+                &json!({"contact": ["mailto:erik@hollensbe.org"], "termsOfServiceAgreed": true}),
+            )
+            .await;
+        let kid = res.headers()["Location"].to_str().unwrap().to_string();
+
+        let (res, _) = srv
+            .post_jws(
+                &key,
+                Some(&kid),
+                &mut nonce,
+                &order_url,
+                &json!({"identifiers": [{"type": "dns", "value": "baz.com"}]}),
+            )
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::CREATED);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_order_domain_policy() {
+        use crate::acme::handlers::DomainPolicy;
+        use crate::acme::jose::EC_GROUP;
+        use crate::test::TestService;
+        use hyper::StatusCode;
+        use openssl::ec::EcKey;
+        use serde_json::json;
+        use spectral::prelude::*;
+
+        let policy = DomainPolicy::allow_list(&[r"[^.]+\.test\.local"]).unwrap();
+        assert_that!(policy.permits("foo.test.local")).is_true();
+        assert_that!(policy.permits("FOO.Test.Local.")).is_true();
+        assert_that!(policy.permits("test.local")).is_false();
+        assert_that!(policy.permits("foo.test.local.evil.com")).is_false();
+        assert_that!(policy.permits("evil.com")).is_false();
+
+        let deny = DomainPolicy::deny_list(&[r"evil\.com"]).unwrap();
+        assert_that!(deny.permits("evil.com")).is_false();
+        assert_that!(deny.permits("EVIL.com.")).is_false();
+        assert_that!(deny.permits("notevil.com")).is_true();
+
+        assert_that!(DomainPolicy::allow_list(&["("])).is_err();
+
+        let srv = TestService::new_with_state("test_order_domain_policy", move |state| {
+            state.with_domain_policy(policy)
+        })
+        .await;
+
+        let res = srv
+            .clone()
+            .run_certbot_certonly(None, "foo.test.local", "erik@hollensbe.org")
+            .await;
+        assert_that!(res).is_ok();
+
+        let res = srv
+            .clone()
+            .run_certbot_certonly(None, "evil.com", "erik@hollensbe.org")
+            .await;
+        assert_that!(res).is_err();
+
+        // nothing was issued for evil.com.
+        let c = srv.pg.db().client().await.unwrap();
+        let row = c
+            .query_one("select count(*) from orders_certificate", &[])
+            .await
+            .unwrap();
+        assert_that!(row.get::<_, i64>(0)).is_equal_to(1);
+
+        // the order is refused with a rejectedIdentifier problem, however the name is spelled.
+        let mut nonce = srv.app.head("/nonce").await.headers()[super::REPLAY_NONCE_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let key = EcKey::generate(&EC_GROUP).unwrap();
+        let (res, _) = srv
+            .post_jws(
+                &key,
+                None,
+                &mut nonce,
+                &format!("{}/account", srv.url),
+                &json!({"contact": ["mailto:erik@hollensbe.org"], "termsOfServiceAgreed": true}),
+            )
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::CREATED);
+        let kid = res.headers()["Location"].to_str().unwrap().to_string();
+
+        for name in ["evil.com", "Foo.Test.Local.Evil.Com."] {
+            let (res, body) = srv
+                .post_jws(
+                    &key,
+                    Some(&kid),
+                    &mut nonce,
+                    &format!("{}/order", srv.url),
+                    &json!({"identifiers": [{"type": "dns", "value": name}]}),
+                )
+                .await;
+            assert_that!(res.status()).is_equal_to(StatusCode::FORBIDDEN);
+            assert_that!(res.headers()["content-type"].to_str().unwrap())
+                .is_equal_to("application/problem+json");
+            assert_that!(body["type"])
+                .is_equal_to(json!("urn:ietf:params:acme:error:rejectedIdentifier"));
+        }
+
+        let (res, _) = srv
+            .post_jws(
+                &key,
+                Some(&kid),
+                &mut nonce,
+                &format!("{}/order", srv.url),
+                &json!({"identifiers": [{"type": "dns", "value": "Bar.Test.Local."}]}),
+            )
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::CREATED);
+
+        srv.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_new_order_identifiers() {
+        use crate::acme::jose::EC_GROUP;
+        use crate::test::TestService;
+        use hyper::StatusCode;
+        use openssl::ec::EcKey;
+        use serde_json::json;
+        use spectral::prelude::*;
+
+        let srv = TestService::new("test_new_order_identifiers").await;
+        let order_url = format!("{}/order", srv.url);
+
+        let mut nonce = srv.app.head("/nonce").await.headers()[super::REPLAY_NONCE_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let key = EcKey::generate(&EC_GROUP).unwrap();
+        let (res, _) = srv
+            .post_jws(
+                &key,
+                None,
+                &mut nonce,
+                &format!("{}/account", srv.url),
+                &json!({"contact": ["mailto:erik@hollensbe.org"], "termsOfServiceAgreed": true}),
+            )
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::CREATED);
+        let kid = res.headers()["Location"].to_str().unwrap().to_string();
+
+        // the same name, however it is spelled, gets one authorization.
+        let (res, body) = srv
+            .post_jws(
+                &key,
+                Some(&kid),
+                &mut nonce,
+                &order_url,
+                &json!({"identifiers": [
+                    {"type": "dns", "value": "foo.com"},
+                    {"type": "dns", "value": "FOO.com"},
+                    {"type": "dns", "value": "foo.com."},
+                    {"type": "dns", "value": "bar.com"},
+                    {"type": "dns", "value": "foo.com"},
+                ]}),
+            )
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::CREATED);
+        assert_that!(body["authorizations"].as_array().unwrap().len()).is_equal_to(2);
+
+        let long_label = format!("{}.com", "a".repeat(64));
+        for identifiers in [
+            json!([]),
+            json!([{"type": "dns", "value": "127.0.0.1"}]),
+            json!([{"type": "dns", "value": long_label}]),
+            json!([{"type": "dns", "value": "com"}]),
+            json!([{"type": "dns", "value": "foo.com"}, {"type": "dns", "value": "com"}]),
+        ] {
+            let (res, body) = srv
+                .post_jws(
+                    &key,
+                    Some(&kid),
+                    &mut nonce,
+                    &order_url,
+                    &json!({ "identifiers": identifiers }),
+                )
+                .await;
+            assert_that!(res.status())
+                .named(&identifiers.to_string())
+                .is_equal_to(StatusCode::BAD_REQUEST);
+            // which the client is told with a malformed problem.
+            assert_that!(body["type"])
+                .named(&identifiers.to_string())
+                .is_equal_to(json!("urn:ietf:params:acme:error:malformed"));
+        }
+
+        // no order was placed for any of them.
+        let c = srv.pg.db().client().await.unwrap();
+        let row = c
+            .query_one("select count(*) from orders", &[])
+            .await
+            .unwrap();
+        assert_that!(row.get::<_, i64>(0)).is_equal_to(1);
+
+        srv.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_new_order_reuses_authorizations() {
+        use crate::acme::handlers::order::OrderStatus;
+        use crate::acme::jose::EC_GROUP;
+        use crate::test::TestService;
+        use hyper::StatusCode;
+        use openssl::ec::EcKey;
+        use serde_json::json;
+        use spectral::prelude::*;
+
+        let srv = TestService::new("test_new_order_reuses_authorizations").await;
+        let order_url = format!("{}/order", srv.url);
+
+        let mut nonce = srv.app.head("/nonce").await.headers()[super::REPLAY_NONCE_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let mut accounts = Vec::new();
+        for _ in 0..2 {
+            let key = EcKey::generate(&EC_GROUP).unwrap();
+            let (res, _) = srv
+                .post_jws(
+                    &key,
+                    None,
+                    &mut nonce,
+                    &format!("{}/account", srv.url),
+                    &json!({"contact": ["mailto:erik@hollensbe.org"], "termsOfServiceAgreed": true}),
+                )
+                .await;
+            assert_that!(res.status()).is_equal_to(StatusCode::CREATED);
+            let kid = res.headers()["Location"].to_str().unwrap().to_string();
+            accounts.push((key, kid));
+        }
+
+        let (key, kid) = &accounts[0];
+
+        let (res, body) = srv
+            .post_jws(
+                key,
+                Some(kid),
+                &mut nonce,
+                &order_url,
+                &json!({"identifiers": [{"type": "dns", "value": "foo.com"}]}),
+            )
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::CREATED);
+        let authz_url = body["authorizations"][0].as_str().unwrap().to_string();
+
+        // a pending authorization is not reused.
+        let (_, body) = srv
+            .post_jws(
+                key,
+                Some(kid),
+                &mut nonce,
+                &order_url,
+                &json!({"identifiers": [{"type": "dns", "value": "foo.com"}]}),
+            )
+            .await;
+        assert_that!(body["authorizations"][0].as_str().unwrap()).is_not_equal_to(&*authz_url);
+
+        let reference = authz_url.rsplit('/').next().unwrap();
+        srv.pg
+            .db()
+            .update_authorization_status(reference, OrderStatus::Valid, 0)
+            .await
+            .unwrap();
+
+        // a valid one is, by later orders of the same account.
+        let (res, body) = srv
+            .post_jws(
+                key,
+                Some(kid),
+                &mut nonce,
+                &order_url,
+                &json!({"identifiers": [
+                    {"type": "dns", "value": "foo.com"},
+                    {"type": "dns", "value": "bar.com"},
+                ]}),
+            )
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::CREATED);
+        assert_that!(body["status"]).is_equal_to(json!("pending"));
+        let authorizations = body["authorizations"].as_array().unwrap();
+        assert_that!(authorizations.len()).is_equal_to(2);
+        assert_that!(authorizations[0].as_str().unwrap()).is_equal_to(&*authz_url);
+        assert_that!(authorizations[1].as_str().unwrap()).is_not_equal_to(&*authz_url);
+
+        let (_, body) = srv
+            .post_jws(
+                key,
+                Some(kid),
+                &mut nonce,
+                &order_url,
+                &json!({"identifiers": [{"type": "dns", "value": "foo.com"}]}),
+            )
+            .await;
+        assert_that!(body["status"]).is_equal_to(json!("valid"));
+        assert_that!(body["authorizations"][0].as_str().unwrap()).is_equal_to(&*authz_url);
+
+        // the order loads back with the reused authorization.
+        let location = res.headers()["Location"].to_str().unwrap().to_string();
+        let (_, body) = srv
+            .post_jws(key, Some(kid), &mut nonce, &location, "")
+            .await;
+        assert_that!(body["authorizations"].as_array().unwrap().len()).is_equal_to(2);
+        assert_that!(body["authorizations"]
+            .as_array()
+            .unwrap()
+            .contains(&json!(authz_url)))
+        .is_true();
+
+        // other accounts must validate the name themselves.
+        let (key, kid) = &accounts[1];
+        let (_, body) = srv
+            .post_jws(
+                key,
+                Some(kid),
+                &mut nonce,
+                &order_url,
+                &json!({"identifiers": [{"type": "dns", "value": "foo.com"}]}),
+            )
+            .await;
+        assert_that!(body["status"]).is_equal_to(json!("pending"));
+        assert_that!(body["authorizations"][0].as_str().unwrap()).is_not_equal_to(&*authz_url);
+
+        srv.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_post_challenge_context() {
+        use crate::acme::jose::EC_GROUP;
+        use crate::test::TestService;
+        use hyper::StatusCode;
+        use openssl::ec::EcKey;
+        use serde_json::json;
+        use spectral::prelude::*;
+
+        let srv = TestService::new("test_post_challenge_context").await;
+        let order_url = format!("{}/order", srv.url);
+
+        let mut nonce = srv.app.head("/nonce").await.headers()[super::REPLAY_NONCE_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let key = EcKey::generate(&EC_GROUP).unwrap();
+        let (res, _) = srv
+            .post_jws(
+                &key,
+                None,
+                &mut nonce,
+                &format!("{}/account", srv.url),
+                &json!({"contact": ["mailto:erik@hollensbe.org"], "termsOfServiceAgreed": true}),
+            )
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::CREATED);
+        let kid = res.headers()["Location"].to_str().unwrap().to_string();
+
+        for (expired, status) in vec![(true, "pending"), (false, "processing")] {
+            let (res, body) = srv
+                .post_jws(
+                    &key,
+                    Some(&kid),
+                    &mut nonce,
+                    &order_url,
+                    &json!({"identifiers": [{"type": "dns", "value": "foo.com"}]}),
+                )
+                .await;
+            assert_that!(res.status()).is_equal_to(StatusCode::CREATED);
+            let authz_url = body["authorizations"][0].as_str().unwrap().to_string();
+
+            let (_, body) = srv
+                .post_jws(&key, Some(&kid), &mut nonce, &authz_url, "")
+                .await;
+            let challenge_url = body["challenges"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|c| c["type"] == "http-01")
+                .unwrap()["url"]
+                .as_str()
+                .unwrap()
+                .to_string();
+
+            // an expired authorization can no longer be validated, so its challenges are not
+            // attempted.
+            if expired {
+                let c = srv.pg.db().client().await.unwrap();
+                c.execute(
+                    "update orders_authorizations set expired = true where reference = $1",
+                    &[&authz_url.rsplit('/').next().unwrap()],
+                )
+                .await
+                .unwrap();
+            }
+
+            let (res, body) = srv
+                .post_jws(&key, Some(&kid), &mut nonce, &challenge_url, &json!({}))
+                .await;
+            assert_that!(res.status()).is_equal_to(StatusCode::OK);
+            assert_that!(body["status"])
+                .named(&format!("expired: {}", expired))
+                .is_equal_to(json!(status));
+        }
+
+        srv.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_post_challenge_owner() {
+        use crate::acme::jose::EC_GROUP;
+        use crate::test::TestService;
+        use hyper::StatusCode;
+        use openssl::ec::EcKey;
+        use serde_json::json;
+        use spectral::prelude::*;
+
+        let srv = TestService::new("test_post_challenge_owner").await;
+
+        let mut nonce = srv.app.head("/nonce").await.headers()[super::REPLAY_NONCE_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let mut accounts = Vec::new();
+        for _ in 0..2 {
+            let key = EcKey::generate(&EC_GROUP).unwrap();
+            let (res, _) = srv
+                .post_jws(
+                    &key,
+                    None,
+                    &mut nonce,
+                    &format!("{}/account", srv.url),
+                    &json!({"contact": ["mailto:erik@hollensbe.org"], "termsOfServiceAgreed": true}),
+                )
+                .await;
+            assert_that!(res.status()).is_equal_to(StatusCode::CREATED);
+            let kid = res.headers()["Location"].to_str().unwrap().to_string();
+            accounts.push((key, kid));
+        }
+
+        let (owner, owner_kid) = &accounts[0];
+        let (res, body) = srv
+            .post_jws(
+                owner,
+                Some(owner_kid),
+                &mut nonce,
+                &format!("{}/order", srv.url),
+                &json!({"identifiers": [{"type": "dns", "value": "foo.com"}]}),
+            )
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::CREATED);
+        let authz_url = body["authorizations"][0].as_str().unwrap().to_string();
+
+        let (_, body) = srv
+            .post_jws(owner, Some(owner_kid), &mut nonce, &authz_url, "")
+            .await;
+        let challenge_url = body["challenges"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|c| c["type"] == "http-01")
+            .unwrap()["url"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        // another account may not set the challenge off, and it is left pending.
+        let (other, other_kid) = &accounts[1];
+        let (res, body) = srv
+            .post_jws(
+                other,
+                Some(other_kid),
+                &mut nonce,
+                &challenge_url,
+                &json!({}),
+            )
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::FORBIDDEN);
+        assert_that!(body["type"]).is_equal_to(json!("urn:ietf:params:acme:error:unauthorized"));
+
+        let (_, body) = srv
+            .post_jws(owner, Some(owner_kid), &mut nonce, &authz_url, "")
+            .await;
+        let challenge = body["challenges"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|c| c["type"] == "http-01")
+            .unwrap()
+            .clone();
+        assert_that!(challenge["status"]).is_equal_to(json!("pending"));
+
+        let (res, body) = srv
+            .post_jws(
+                owner,
+                Some(owner_kid),
+                &mut nonce,
+                &challenge_url,
+                &json!({}),
+            )
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::OK);
+        assert_that!(body["status"]).is_equal_to(json!("processing"));
+
+        srv.shutdown().await;
+    }
 }
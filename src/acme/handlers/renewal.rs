@@ -0,0 +1,263 @@
+// ACME renewal information (draft-ietf-acme-ari), which lets the CA suggest when clients should
+// renew the certificates it issued.
+
+use std::time::{Duration, SystemTime};
+
+use openssl::{bn::BigNum, x509::X509};
+use ratpack::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::{HandlerState, ServiceState, ACME_CONTENT_TYPE};
+use crate::acme::ca::asn1_to_st;
+
+/// how long clients should wait before asking about the same certificate again.
+const RENEWAL_INFO_RETRY_AFTER: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// See draft-ietf-acme-ari 4.2
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RenewalInfo {
+    suggested_window: SuggestedWindow,
+    #[serde(rename = "explanationURL", skip_serializing_if = "Option::is_none")]
+    explanation_url: Option<url::Url>,
+}
+
+/// SuggestedWindow is the period in which the client should renew the certificate.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct SuggestedWindow {
+    start: chrono::DateTime<chrono::Utc>,
+    end: chrono::DateTime<chrono::Utc>,
+}
+
+/// renewal_window places the suggested window at the fractions of the certificate's validity
+/// period given, e.g. `(2.0 / 3.0, 5.0 / 6.0)`.
+pub(crate) fn renewal_window(
+    not_before: SystemTime,
+    not_after: SystemTime,
+    window: (f64, f64),
+) -> SuggestedWindow {
+    let validity = not_after.duration_since(not_before).unwrap_or_default();
+
+    SuggestedWindow {
+        start: (not_before + validity.mul_f64(window.0)).into(),
+        end: (not_before + validity.mul_f64(window.1)).into(),
+    }
+}
+
+/// parse_cert_id splits a certID, `base64url(issuerKeyHash) "." base64url(serial)`, into the
+/// issuer key hash and the serial number in the form stored with issued certificates.
+pub(crate) fn parse_cert_id(cert_id: &str) -> Option<(Vec<u8>, Vec<u8>)> {
+    let (key_hash, serial) = cert_id.split_once('.')?;
+    let key_hash = base64::decode_config(key_hash, base64::URL_SAFE_NO_PAD).ok()?;
+    let serial = base64::decode_config(serial, base64::URL_SAFE_NO_PAD).ok()?;
+
+    if key_hash.is_empty() || serial.is_empty() {
+        return None;
+    }
+
+    Some((key_hash, BigNum::from_slice(&serial).ok()?.to_vec()))
+}
+
+/// renewal_info answers with the suggested renewal window of the certificate identified by the
+/// certID in the path. Certificates this CA did not issue are not found.
+pub(crate) async fn renewal_info(
+    req: Request<Body>,
+    _resp: Option<Response<Body>>,
+    params: Params,
+    app: App<ServiceState, HandlerState>,
+    state: HandlerState,
+) -> HTTPResult<HandlerState> {
+    let appstate_opt = app.state().await.clone().unwrap();
+    let appstate = appstate_opt.lock().await;
+
+    let (key_hash, serial) = match parse_cert_id(params.get("cert_id").unwrap()) {
+        Some(id) => id,
+        None => {
+            return Err(ratpack::Error::StatusCode(
+                StatusCode::BAD_REQUEST,
+                "invalid certID".to_string(),
+            ))
+        }
+    };
+
+    let not_found = || {
+        Err(ratpack::Error::StatusCode(
+            StatusCode::NOT_FOUND,
+            "certificate not found".to_string(),
+        ))
+    };
+
+    let db = appstate.db.clone();
+    let order = match db.get_orders_for_certificate(&serial).await?.pop() {
+        Some(order) => order,
+        None => return not_found(),
+    };
+
+    // certificates issued by a CA since rotated away from are still ours; the issuer is recorded
+    // with each certificate, and those recorded without one are checked against the CAs held.
+    let cert = order.certificate(db).await?;
+    let issued = match &cert.issuer_key_hash {
+        Some(hash) => *hash == key_hash,
+        None => appstate.ca.is_known_issuer(&key_hash).await,
+    };
+
+    if !issued {
+        return not_found();
+    }
+
+    let cert = X509::from_pem(&cert.certificate)?;
+    let window = renewal_window(
+        asn1_to_st(cert.not_before())?,
+        asn1_to_st(cert.not_after())?,
+        appstate.renewal_window,
+    );
+
+    let info = RenewalInfo {
+        suggested_window: window,
+        explanation_url: appstate.renewal_explanation_url.clone(),
+    };
+
+    Ok((
+        req,
+        Some(
+            Response::builder()
+                .header("content-type", ACME_CONTENT_TYPE)
+                .header(
+                    "Retry-After",
+                    RENEWAL_INFO_RETRY_AFTER.as_secs().to_string(),
+                )
+                .status(StatusCode::OK)
+                .body(Body::from(serde_json::to_string(&info)?))
+                .unwrap(),
+        ),
+        state,
+    ))
+}
+
+mod tests {
+    #[test]
+    fn test_renewal_window() {
+        use super::renewal_window;
+        use spectral::prelude::*;
+        use std::time::{Duration, SystemTime};
+
+        let not_before = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let not_after = not_before + Duration::from_secs(90 * 24 * 60 * 60);
+
+        let window = renewal_window(not_before, not_after, (2.0 / 3.0, 5.0 / 6.0));
+        assert_that!(window.start).is_equal_to(chrono::DateTime::<chrono::Utc>::from(
+            not_before + Duration::from_secs(60 * 24 * 60 * 60),
+        ));
+        assert_that!(window.end).is_equal_to(chrono::DateTime::<chrono::Utc>::from(
+            not_before + Duration::from_secs(75 * 24 * 60 * 60),
+        ));
+    }
+
+    #[test]
+    fn test_parse_cert_id() {
+        use super::parse_cert_id;
+        use spectral::prelude::*;
+
+        assert_that!(parse_cert_id("aYhba4dGQEHhs3uEe6CuLN4ByNQ.AIdlQyE")).is_equal_to(Some((
+            base64::decode_config("aYhba4dGQEHhs3uEe6CuLN4ByNQ", base64::URL_SAFE_NO_PAD).unwrap(),
+            vec![0x87, 0x65, 0x43, 0x21],
+        )));
+
+        assert_that!(parse_cert_id("aYhba4dGQEHhs3uEe6CuLN4ByNQ")).is_none();
+        assert_that!(parse_cert_id("aYhba4dGQEHhs3uEe6CuLN4ByNQ.")).is_none();
+        assert_that!(parse_cert_id("!!.AIdlQyE")).is_none();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_renewal_info() {
+        use super::RenewalInfo;
+        use crate::test::TestService;
+        use http::StatusCode;
+        use openssl::{hash::MessageDigest, x509::X509};
+        use spectral::prelude::*;
+
+        let srv = TestService::new_with_state("test_renewal_info", |state| {
+            state
+                .with_renewal_window(0.5, 0.75)
+                .with_renewal_explanation_url("https://example.com/renewals".parse().unwrap())
+        })
+        .await;
+
+        let res = srv.app.get("/renewal-info/garbage").await;
+        assert_that!(res.status()).is_equal_to(StatusCode::BAD_REQUEST);
+
+        let dir = srv
+            .clone()
+            .run_certbot_certonly(None, "foo.com", "erik@hollensbe.org")
+            .await
+            .unwrap();
+
+        let mut live = dir.path().to_path_buf();
+        live.push("live/foo.com");
+
+        let cert = X509::from_pem(&std::fs::read(live.join("cert.pem")).unwrap()).unwrap();
+        let issuer = X509::from_pem(&std::fs::read(live.join("chain.pem")).unwrap()).unwrap();
+
+        let key_hash = srv
+            .ca
+            .clone()
+            .ca()
+            .read()
+            .await
+            .as_ref()
+            .unwrap()
+            .key_hash(MessageDigest::sha1())
+            .unwrap();
+        assert_that!(issuer.subject_key_id().map(|id| id.as_slice().to_vec()))
+            .is_equal_to(Some(key_hash.to_vec()));
+
+        let encode = |b: &[u8]| base64::encode_config(b, base64::URL_SAFE_NO_PAD);
+        let serial = cert.serial_number().to_bn().unwrap().to_vec();
+
+        let mut res = srv
+            .app
+            .get(&format!(
+                "/renewal-info/{}.{}",
+                encode(&key_hash),
+                encode(&serial)
+            ))
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::OK);
+        assert_that!(res.headers().get("Retry-After")).is_some();
+
+        let body = hyper::body::to_bytes(res.body_mut()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_that!(json["explanationURL"].as_str())
+            .is_equal_to(Some("https://example.com/renewals"));
+
+        let info: RenewalInfo = serde_json::from_slice(&body).unwrap();
+        let not_before = super::asn1_to_st(cert.not_before()).unwrap();
+        let not_after = super::asn1_to_st(cert.not_after()).unwrap();
+        assert_that!(info.suggested_window).is_equal_to(super::renewal_window(
+            not_before,
+            not_after,
+            (0.5, 0.75),
+        ));
+
+        // unknown serials, and certificates from other issuers, are not found.
+        let res = srv
+            .app
+            .get(&format!(
+                "/renewal-info/{}.{}",
+                encode(&key_hash),
+                encode(&[1, 2, 3])
+            ))
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::NOT_FOUND);
+
+        let res = srv
+            .app
+            .get(&format!(
+                "/renewal-info/{}.{}",
+                encode(&[1; 20]),
+                encode(&serial)
+            ))
+            .await;
+        assert_that!(res.status()).is_equal_to(StatusCode::NOT_FOUND);
+    }
+}
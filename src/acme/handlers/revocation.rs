@@ -0,0 +1,197 @@
+// certificate revocation is covered in RFC8555 section 7.6.
+
+use openssl::{
+    pkey::{PKey, Public},
+    x509::X509,
+};
+use serde::{Deserialize, Serialize};
+
+use ratpack::prelude::*;
+
+use super::{uri_to_url, HandlerState, ServiceState};
+use crate::{
+    acme::jose::ACMEKey,
+    errors::ACMEValidationError,
+    models::{
+        audit::{record_audit_event, AuditEvent, AuditEventType},
+        revocation::insert_revocation,
+    },
+};
+
+/// RFC8555 7.6
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RevokeCertificate {
+    /// the certificate to revoke, base64url encoded DER.
+    certificate: String,
+    /// the RFC5280 CRLReason code; unspecified (0) if omitted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<i32>,
+}
+
+/// is_valid_reason returns true for the CRLReason codes clients may request. 7 is unused and
+/// removeFromCRL (8) only makes sense in delta CRLs.
+fn is_valid_reason(reason: i32) -> bool {
+    matches!(reason, 0..=6 | 9 | 10)
+}
+
+fn public_key(key: ACMEKey) -> Result<PKey<Public>, openssl::error::ErrorStack> {
+    match key {
+        ACMEKey::ECDSA(key) => PKey::from_ec_key(key),
+        ACMEKey::RSA(key) => PKey::from_rsa(key),
+        ACMEKey::EdDSA(key) => Ok(key),
+    }
+}
+
+/// revoke_cert revokes a certificate issued by this CA. The request must either be signed by
+/// the account which ordered the certificate, or by the certificate's own key.
+pub(crate) async fn revoke_cert(
+    req: Request<Body>,
+    _resp: Option<Response<Body>>,
+    _params: Params,
+    app: App<ServiceState, HandlerState>,
+    state: HandlerState,
+) -> HTTPResult<HandlerState> {
+    let appstate_opt = app.state().await.clone().unwrap();
+    let appstate = appstate_opt.lock().await;
+
+    let mut jws = match state.clone().jws {
+        Some(jws) => jws,
+        None => return Err(ACMEValidationError::InvalidRequest.to_status()),
+    };
+
+    let revoke: RevokeCertificate = jws.payload()?;
+    let reason = revoke.reason.unwrap_or_default();
+
+    if !is_valid_reason(reason) {
+        return Err(ACMEValidationError::BadRevocationReason(reason).to_status());
+    }
+
+    let cert = match base64::decode_config(&revoke.certificate, base64::URL_SAFE_NO_PAD)
+        .ok()
+        .and_then(|der| X509::from_der(&der).ok())
+    {
+        Some(cert) => cert,
+        None => return Err(ACMEValidationError::InvalidRequest.to_status()),
+    };
+
+    let db = appstate.request_db(&req);
+    let serial = cert.serial_number().to_bn()?.to_vec();
+    let orders = db.get_orders_for_certificate(&serial).await?;
+
+    // certificates we did not issue are treated like those the requester may not revoke.
+    let authorized = !orders.is_empty()
+        && match jws.protected()?.jwk() {
+            Some(jwk) => public_key(jwk.try_into()?)?.public_eq(&cert.public_key()?),
+            None => {
+                let account_id = appstate.account_id_for_jws(&req, jws.clone()).await?;
+                account_id.is_some() && orders.iter().any(|o| o.account_id == account_id)
+            }
+        };
+
+    if !authorized {
+        return Err(ACMEValidationError::RevocationNotAuthorized.to_status());
+    }
+
+    if db.is_serial_revoked(&serial).await? {
+        return Err(ACMEValidationError::AlreadyRevoked.to_status());
+    }
+
+    let mut event = AuditEvent::new(AuditEventType::CertificateRevoked)
+        .with_serial(cert.serial_number().to_bn()?.to_hex_str()?)
+        .with_detail(serde_json::json!({ "reason": reason }));
+    if let Some(order) = orders.first() {
+        event = event.with_order_id(&order.order_id);
+        if let Some(account_id) = order.account_id {
+            event = event.with_account_id(account_id);
+        }
+    }
+
+    // the revocation and its audit entry are kept together or not at all; the transaction is
+    // rolled back when dropped on an error.
+    let mut client = db.clone().client().await?;
+    let tx = client.transaction().await?;
+    insert_revocation(&serial, reason, &tx).await?;
+    record_audit_event(&event, db.tenant(), &tx).await?;
+    tx.commit().await?;
+
+    if let Some(crl) = &appstate.crl {
+        let revoked = db.list_revoked_certificates().await?;
+        if let Err(e) = crl.refresh(appstate.ca.clone(), &revoked).await {
+            log::warn!(
+                "request {}: could not regenerate CRL after revocation: {}",
+                state.request_id(),
+                e
+            );
+        }
+    }
+
+    let url = uri_to_url(appstate.request_baseurl(&req), req.uri().clone()).await?;
+
+    Ok((
+        req,
+        Some(
+            state
+                .decorate_response(url, Response::builder())?
+                .status(StatusCode::OK)
+                .body(Body::default())
+                .unwrap(),
+        ),
+        state,
+    ))
+}
+
+mod tests {
+    #[test]
+    fn test_is_valid_reason() {
+        use super::is_valid_reason;
+        use spectral::prelude::*;
+
+        for reason in vec![0, 1, 2, 3, 4, 5, 6, 9, 10] {
+            assert_that!(is_valid_reason(reason)).is_true();
+        }
+
+        for reason in vec![-1, 7, 8, 11] {
+            assert_that!(is_valid_reason(reason)).is_false();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_revoke_cert() {
+        use crate::test::TestService;
+        use openssl::x509::X509;
+        use spectral::prelude::*;
+
+        let srv = TestService::new("test_revoke_cert").await;
+
+        let dir = srv
+            .clone()
+            .run_certbot_certonly(None, "foo.com", "erik@hollensbe.org")
+            .await
+            .unwrap();
+
+        let mut path = dir.path().to_path_buf();
+        path.push("live/foo.com/cert.pem");
+        let cert = X509::from_pem(&std::fs::read(path).unwrap()).unwrap();
+        let serial = cert.serial_number().to_bn().unwrap().to_vec();
+
+        assert_that!(srv.pg.db().is_serial_revoked(&serial).await.unwrap()).is_false();
+
+        let revoke = "revoke --cert-path /etc/letsencrypt/live/foo.com/cert.pem --reason keycompromise --no-delete-after-revoke".to_string();
+
+        let res = srv.clone().certbot(Some(dir.clone()), revoke.clone()).await;
+        assert_that!(res).is_ok();
+
+        let details = srv
+            .pg
+            .db()
+            .get_revocation_details(&serial)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_that!(details.reason).is_equal_to(1);
+
+        // the second attempt is answered with alreadyRevoked.
+        let res = srv.clone().certbot(Some(dir.clone()), revoke).await;
+        assert_that!(res).is_err();
+    }
+}
@@ -0,0 +1,154 @@
+// Serving the ACME API over TLS, without a reverse proxy in front of it.
+
+use openssl::{pkey::PKey, x509::X509};
+
+use crate::errors::ConfigError;
+
+/// TlsConfig holds the certificate and key the service is served with; see
+/// [super::ServiceState::with_tls] and [super::serve].
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsConfig {
+    /// the certificate, PEM encoded, followed by any intermediates clients need to verify it.
+    pub cert_pem: Vec<u8>,
+    /// the private key of the certificate, PEM encoded; PKCS#8, RSA and EC keys are accepted.
+    pub key_pem: Vec<u8>,
+}
+
+impl TlsConfig {
+    /// server_config parses the certificate and key into a [rustls::ServerConfig] with safe
+    /// defaults and no client authentication.
+    pub fn server_config(&self) -> Result<rustls::ServerConfig, ConfigError> {
+        let invalid = |e: &dyn std::fmt::Display| ConfigError::InvalidTls(e.to_string());
+
+        let chain = X509::stack_from_pem(&self.cert_pem).map_err(|e| invalid(&e))?;
+        if chain.is_empty() {
+            return Err(ConfigError::InvalidTls(
+                "no certificate in cert_pem".to_string(),
+            ));
+        }
+
+        let mut certs = Vec::new();
+        for cert in chain {
+            certs.push(rustls::Certificate(cert.to_der().map_err(|e| invalid(&e))?));
+        }
+
+        // rustls wants PKCS#8 or PKCS#1 DER; converting through openssl takes any PEM key.
+        let key = PKey::private_key_from_pem(&self.key_pem)
+            .and_then(|key| key.private_key_to_pkcs8())
+            .map_err(|e| invalid(&e))?;
+
+        rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, rustls::PrivateKey(key))
+            .map_err(|e| invalid(&e))
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_server_config() {
+        use super::TlsConfig;
+        use crate::test::write_ssl_certificates;
+        use spectral::prelude::*;
+
+        let dir = tempfile::tempdir().unwrap();
+        write_ssl_certificates(dir.path());
+
+        let config = TlsConfig {
+            cert_pem: std::fs::read(dir.path().join("server.crt")).unwrap(),
+            key_pem: std::fs::read(dir.path().join("server.key")).unwrap(),
+        };
+        assert_that!(config.server_config()).is_ok();
+
+        // a key which does not belong to the certificate is refused.
+        let mismatched = TlsConfig {
+            key_pem: openssl::pkey::PKey::from_rsa(openssl::rsa::Rsa::generate(2048).unwrap())
+                .unwrap()
+                .private_key_to_pem_pkcs8()
+                .unwrap(),
+            ..config.clone()
+        };
+        assert_that!(mismatched.server_config()).is_err();
+
+        let empty = TlsConfig {
+            cert_pem: Vec::new(),
+            ..config
+        };
+        assert_that!(empty.server_config()).is_err();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_serve_tls() {
+        use super::TlsConfig;
+        use crate::acme::{
+            ca::CACollector,
+            challenge::Challenger,
+            handlers::{configure_routes, serve, ServiceState},
+            PostgresNonceValidator,
+        };
+        use crate::test::{write_ssl_certificates, PGTest};
+        use ratpack::prelude::*;
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        let pg = PGTest::new("test_serve_tls").await.unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        write_ssl_certificates(dir.path());
+
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        // the URL is given as http; serving with TLS makes it https.
+        let state = ServiceState::builder()
+            .url(format!("http://localhost:{}", port))
+            .db(pg.db())
+            .challenger(Challenger::new(None))
+            .ca(CACollector::new(Duration::MAX))
+            .nonce_validator(PostgresNonceValidator::new(pg.db(), None))
+            .build()
+            .unwrap()
+            .with_tls(TlsConfig {
+                cert_pem: std::fs::read(dir.path().join("server.crt")).unwrap(),
+                key_pem: std::fs::read(dir.path().join("server.key")).unwrap(),
+            });
+
+        let mut app = App::with_state(state);
+        configure_routes(&mut app, None);
+
+        let addr = format!("127.0.0.1:{}", port);
+        let server = tokio::spawn(async move { serve(app, &addr).await.unwrap() });
+
+        let client = reqwest::Client::builder()
+            .add_root_certificate(
+                reqwest::Certificate::from_pem(&std::fs::read(dir.path().join("ca.pem")).unwrap())
+                    .unwrap(),
+            )
+            .build()
+            .unwrap();
+
+        let url = format!("https://localhost:{}/", port);
+        let mut res = client.get(&url).send().await;
+        for _ in 0..50 {
+            if res.is_ok() {
+                break;
+            }
+
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            res = client.get(&url).send().await;
+        }
+
+        let body = res.unwrap().bytes().await.unwrap();
+        let directory: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_that!(directory["newNonce"].as_str().unwrap())
+            .is_equal_to(format!("https://localhost:{}/nonce", port).as_str());
+
+        // clients which do not trust the CA are turned away.
+        assert_that!(reqwest::Client::new().get(&url).send().await).is_err();
+
+        server.abort();
+    }
+}
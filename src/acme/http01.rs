@@ -0,0 +1,200 @@
+// http-01 is defined in RFC8555 section 8.3. The client serves the key authorization over plain
+// HTTP at a well-known path named by the token; the server fetches it, following redirects, and
+// compares it to the key authorization it expects. Redirects are only followed to http and https
+// URLs on ports 80 and 443, so that the CA cannot be pointed at other services on the networks it
+// reaches.
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use reqwest::redirect::Policy;
+
+use super::challenge::ChallengeHttpClient;
+use crate::errors::ChallengeError;
+
+/// how many redirects an http-01 fetch follows before giving up.
+const MAX_REDIRECTS: usize = 10;
+
+/// the ports redirects may lead to.
+const REDIRECT_PORTS: &[u16] = &[80, 443];
+
+/// the most of a response body an http-01 fetch reads; key authorizations are far shorter.
+const MAX_BODY_SIZE: usize = 8 * 1024;
+
+/// ReqwestHttpClient is the [ChallengeHttpClient] to use outside of tests. It follows up to ten
+/// redirects to http and https URLs on ports 80 and 443, reads at most 8 KiB of the response, and
+/// gives up on a fetch which takes longer than the timeout it was made with.
+#[derive(Clone, Debug)]
+pub struct ReqwestHttpClient {
+    client: reqwest::Client,
+}
+
+impl ReqwestHttpClient {
+    /// new makes a client whose fetches, redirects included, time out after `timeout`.
+    pub fn new(timeout: Duration) -> Result<Self, ChallengeError> {
+        Self::with_redirect_ports(timeout, REDIRECT_PORTS.to_vec())
+    }
+
+    /// with_redirect_ports is [ReqwestHttpClient::new] following redirects to `ports` instead,
+    /// e.g. to the port of a test server.
+    fn with_redirect_ports(timeout: Duration, ports: Vec<u16>) -> Result<Self, ChallengeError> {
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .redirect(redirect_policy(ports))
+            .build()
+            .map_err(|e| ChallengeError::Connect(e.to_string()))?;
+
+        Ok(Self { client })
+    }
+}
+
+/// redirect_policy follows up to [MAX_REDIRECTS] redirects, each to an http or https URL on one
+/// of `ports`.
+fn redirect_policy(ports: Vec<u16>) -> Policy {
+    Policy::custom(move |attempt| {
+        if attempt.previous().len() > MAX_REDIRECTS {
+            return attempt.error("too many redirects");
+        }
+
+        let url = attempt.url();
+        let allowed = matches!(url.scheme(), "http" | "https")
+            && url
+                .port_or_known_default()
+                .map_or(false, |port| ports.contains(&port));
+
+        match allowed {
+            true => attempt.follow(),
+            false => {
+                let reason = format!("{} is not an allowed location", url);
+                attempt.error(reason)
+            }
+        }
+    })
+}
+
+impl Default for ReqwestHttpClient {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(10)).unwrap()
+    }
+}
+
+#[async_trait]
+impl ChallengeHttpClient for ReqwestHttpClient {
+    async fn get(&self, url: &str) -> Result<String, ChallengeError> {
+        let map_err = |e: reqwest::Error| {
+            if e.is_timeout() {
+                ChallengeError::Timeout
+            } else if e.is_redirect() {
+                ChallengeError::Redirect(e.to_string())
+            } else {
+                ChallengeError::Connect(e.to_string())
+            }
+        };
+
+        let mut res = self.client.get(url).send().await.map_err(map_err)?;
+        if !res.status().is_success() {
+            return Err(ChallengeError::HttpStatus(res.status().as_u16()));
+        }
+
+        // the body is read a chunk at a time, so that a client cannot make the CA hold more of it
+        // than a key authorization needs.
+        let mut body = Vec::new();
+        while let Some(chunk) = res.chunk().await.map_err(map_err)? {
+            if body.len() + chunk.len() > MAX_BODY_SIZE {
+                return Err(ChallengeError::BodyTooLarge(MAX_BODY_SIZE));
+            }
+
+            body.extend_from_slice(&chunk);
+        }
+
+        Ok(String::from_utf8_lossy(&body).to_string())
+    }
+}
+
+mod tests {
+    /// serve answers HTTP requests on a local port until the test ends: `/token` with the body
+    /// `token.thumbprint`, `/moved` with a redirect to `/token`, `/elsewhere` with a redirect to
+    /// an ftp URL, `/large` with a body of 16 KiB, `/slow` not at all, and anything else with a
+    /// 404. It returns the base URL.
+    #[cfg(test)]
+    async fn serve() -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        let lis = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", lis.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = lis.accept().await.unwrap();
+
+                tokio::spawn(async move {
+                    let mut buf = vec![0; 4096];
+                    let n = stream.read(&mut buf).await.unwrap();
+                    let request = String::from_utf8_lossy(&buf[..n]).to_string();
+                    let path = request.split_whitespace().nth(1).unwrap_or_default();
+
+                    let response = match path {
+                        "/token" => "HTTP/1.1 200 OK\r\ncontent-length: 16\r\nconnection: close\r\n\r\ntoken.thumbprint".to_string(),
+                        "/moved" => "HTTP/1.1 302 Found\r\nlocation: /token\r\ncontent-length: 0\r\nconnection: close\r\n\r\n".to_string(),
+                        "/elsewhere" => "HTTP/1.1 302 Found\r\nlocation: ftp://127.0.0.1/token\r\ncontent-length: 0\r\nconnection: close\r\n\r\n".to_string(),
+                        "/large" => format!(
+                            "HTTP/1.1 200 OK\r\ncontent-length: 16384\r\nconnection: close\r\n\r\n{}",
+                            "a".repeat(16384)
+                        ),
+                        "/slow" => {
+                            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+                            return;
+                        }
+                        _ => "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n".to_string(),
+                    };
+
+                    // the client hangs up on bodies it will not read in full.
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+
+        url
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_reqwest_http_client() {
+        use super::ReqwestHttpClient;
+        use crate::acme::challenge::ChallengeHttpClient;
+        use crate::errors::ChallengeError;
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        let url = serve().await;
+        let port = url.rsplit(':').next().unwrap().parse::<u16>().unwrap();
+        let client =
+            ReqwestHttpClient::with_redirect_ports(Duration::from_secs(1), vec![port]).unwrap();
+
+        assert_that!(client.get(&format!("{}/token", url)).await)
+            .is_equal_to(Ok("token.thumbprint".to_string()));
+        assert_that!(client.get(&format!("{}/moved", url)).await)
+            .is_equal_to(Ok("token.thumbprint".to_string()));
+        assert_that!(client.get(&format!("{}/missing", url)).await)
+            .is_equal_to(Err(ChallengeError::HttpStatus(404)));
+        assert_that!(client.get(&format!("{}/slow", url)).await)
+            .is_equal_to(Err(ChallengeError::Timeout));
+        assert_that!(client.get(&format!("{}/large", url)).await)
+            .is_equal_to(Err(ChallengeError::BodyTooLarge(8 * 1024)));
+        assert_that!(matches!(
+            client.get(&format!("{}/elsewhere", url)).await,
+            Err(ChallengeError::Redirect(_))
+        ))
+        .is_true();
+
+        // by default, redirects are only followed to ports 80 and 443.
+        let client = ReqwestHttpClient::new(Duration::from_secs(1)).unwrap();
+        assert_that!(client.get(&format!("{}/token", url)).await)
+            .is_equal_to(Ok("token.thumbprint".to_string()));
+        assert_that!(matches!(
+            client.get(&format!("{}/moved", url)).await,
+            Err(ChallengeError::Redirect(_))
+        ))
+        .is_true();
+    }
+}
@@ -9,9 +9,9 @@ use openssl::{
     ecdsa::EcdsaSig,
     hash::MessageDigest,
     nid::Nid,
-    pkey::{PKey, Private, Public},
+    pkey::{HasPublic, Id, PKey, Private, Public},
     rsa::Rsa,
-    sha::sha256,
+    sha::{sha256, sha384},
     sign::{Signer, Verifier},
 };
 
@@ -19,7 +19,7 @@ use serde::{Deserialize, Serialize};
 use url::Url;
 
 use crate::{
-    acme::{NonceValidator, PostgresNonceValidator, ACME_EXPECTED_ALGS},
+    acme::{NonceValidator, ACME_EXPECTED_ALGS},
     errors::{acme::*, ACMEValidationError},
     util::{make_nonce, to_base64},
 };
@@ -27,9 +27,21 @@ use crate::{
 use lazy_static::lazy_static;
 
 const NID_ES256: Nid = Nid::X9_62_PRIME256V1;
+const NID_ES384: Nid = Nid::SECP384R1;
 
 lazy_static! {
     pub(crate) static ref EC_GROUP: EcGroup = EcGroup::from_curve_name(NID_ES256).unwrap();
+    pub(crate) static ref EC_GROUP_P384: EcGroup = EcGroup::from_curve_name(NID_ES384).unwrap();
+}
+
+/// ec_coordinate_size returns the size in bytes of a coordinate (and of each half of a JWS
+/// signature, RFC7518 3.4) for the curve of the key; P-256 unless the key is P-384.
+fn ec_coordinate_size<T>(key: &EcKey<T>) -> usize {
+    if key.group().curve_name() == Some(NID_ES384) {
+        48
+    } else {
+        32
+    }
 }
 
 /// ACMEProtectedHeader identifies an ACME protected header per RFC8555. Typically this function is
@@ -42,6 +54,8 @@ pub struct ACMEProtectedHeader {
     jwk: Option<JWK>,
     #[serde(skip_serializing_if = "Option::is_none")]
     kid: Option<Url>,
+    /// empty only in the inner JWS of a key change (RFC8555 7.3.5), which omits it.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     nonce: String,
     url: Url,
 }
@@ -73,6 +87,22 @@ impl ACMEProtectedHeader {
         }
     }
 
+    /// with_alg replaces the `alg` field, for keys which do not sign with the preferred ES256.
+    pub fn with_alg(mut self, alg: &str) -> Self {
+        self.alg = alg.to_string();
+        self
+    }
+
+    /// alg returns the signature algorithm named in this protected header.
+    pub fn alg(&self) -> String {
+        self.alg.clone()
+    }
+
+    /// url returns the request URL this protected header was signed for.
+    pub fn url(&self) -> Url {
+        self.url.clone()
+    }
+
     /// nonce returns the replay-nonce supplied in this protected header.
     pub fn nonce(&self) -> String {
         self.nonce.clone()
@@ -100,10 +130,10 @@ impl ACMEProtectedHeader {
     ///   [[struct@super::ACME_EXPECTED_ALGS]].
     /// - finally, the nonce is validated against storage, which is expected to implement
     ///   [super::NonceValidator].
-    pub async fn validate(
+    pub async fn validate<V: NonceValidator + Send + Sync>(
         &self,
         request_url: Url,
-        validator: PostgresNonceValidator,
+        validator: V,
     ) -> Result<(), ACMEValidationError> {
         if self.nonce.is_empty() {
             return Err(ACMEValidationError::NonceNotFound);
@@ -140,6 +170,8 @@ impl ACMEProtectedHeader {
 pub enum ACMEKey {
     ECDSA(EcKey<Public>),
     RSA(Rsa<Public>),
+    /// an Ed25519 key (RFC8037)
+    EdDSA(PKey<Public>),
 }
 
 /// ACME Private Key type enumeration
@@ -147,6 +179,8 @@ pub enum ACMEKey {
 pub enum ACMEPrivateKey {
     ECDSA(EcKey<Private>),
     RSA(Rsa<Private>),
+    /// an Ed25519 key (RFC8037)
+    EdDSA(PKey<Private>),
 }
 
 impl TryFrom<&EcPointRef> for ACMEKey {
@@ -195,6 +229,69 @@ impl TryFrom<Rsa<Public>> for ACMEKey {
     }
 }
 
+impl TryFrom<PKey<Public>> for ACMEKey {
+    type Error = JWSError;
+
+    fn try_from(value: PKey<Public>) -> Result<Self, Self::Error> {
+        if value.id() != Id::ED25519 {
+            return Err(JWSError::InvalidPublicKey);
+        }
+
+        Ok((&mut JWK {
+            x: Some(base64::encode_config(
+                &value.raw_public_key()?,
+                base64::URL_SAFE_NO_PAD,
+            )),
+            alg: Some("EdDSA".into()),
+            crv: Some("Ed25519".into()),
+            kty: "OKP".into(),
+            _use: Some("sig".into()),
+            y: None,
+            n: None,
+            e: None,
+        })
+            .try_into()?)
+    }
+}
+
+impl<T: HasPublic> TryFrom<&EcKey<T>> for JWK {
+    type Error = JWSError;
+
+    /// the JWK of a P-256 or P-384 key (RFC7518 6.2), suitable for a newAccount request.
+    fn try_from(key: &EcKey<T>) -> Result<Self, Self::Error> {
+        let (alg, crv) = if ec_coordinate_size(key) == 48 {
+            ("ES384", "P-384")
+        } else {
+            ("ES256", "P-256")
+        };
+
+        let mut ctx = openssl::bn::BigNumContext::new()?;
+        let mut x = openssl::bn::BigNum::new()?;
+        let mut y = openssl::bn::BigNum::new()?;
+        key.public_key()
+            .affine_coordinates_gfp(key.group(), &mut x, &mut y, &mut ctx)?;
+
+        let size = ec_coordinate_size(key) as i32;
+
+        Ok(JWK {
+            x: Some(base64::encode_config(
+                &x.to_vec_padded(size)?,
+                base64::URL_SAFE_NO_PAD,
+            )),
+            y: Some(base64::encode_config(
+                &y.to_vec_padded(size)?,
+                base64::URL_SAFE_NO_PAD,
+            )),
+            alg: Some(alg.into()),
+            crv: Some(crv.into()),
+            _use: Some("sig".into()),
+            kty: "EC".into(),
+            n: None,
+            e: None,
+        })
+    }
+}
+
 impl TryFrom<&mut JWK> for ACMEKey {
     type Error = JWSError;
 
@@ -203,6 +300,7 @@ impl TryFrom<&mut JWK> for ACMEKey {
         match jwk.kty.as_str() {
             "RSA" => Ok(ACMEKey::RSA(jwk.into_rsa()?)),
             "EC" | "ECDSA" => Ok(ACMEKey::ECDSA(jwk.into_ec()?)),
+            "OKP" => Ok(ACMEKey::EdDSA(jwk.into_ed25519()?)),
             _ => Err(JWSError::InvalidPublicKey),
         }
     }
@@ -214,6 +312,7 @@ impl TryInto<ACMEKey> for JWK {
         match self.kty.as_str() {
             "RSA" => Ok(ACMEKey::RSA(self.into_rsa()?)),
             "EC" | "ECDSA" => Ok(ACMEKey::ECDSA(self.into_ec()?)),
+            "OKP" => Ok(ACMEKey::EdDSA(self.into_ed25519()?)),
             _ => Err(JWSError::InvalidPublicKey),
         }
     }
@@ -280,8 +379,14 @@ impl JWK {
         let x = base64::decode_config(self.x.clone().unwrap(), base64::URL_SAFE_NO_PAD)?;
         let y = base64::decode_config(self.y.clone().unwrap(), base64::URL_SAFE_NO_PAD)?;
 
+        let group: &EcGroup = match self.crv.as_deref() {
+            Some("P-384") => &EC_GROUP_P384,
+            None | Some("P-256") => &EC_GROUP,
+            Some(_) => return Err(JWSError::InvalidPublicKey),
+        };
+
         let key = EcKey::from_public_key_affine_coordinates(
-            &EC_GROUP,
+            group,
             BigNum::from_slice(&x)?.as_ref(),
             BigNum::from_slice(&y)?.as_ref(),
         )?;
@@ -289,6 +394,25 @@ impl JWK {
         Ok(key)
     }
 
+    /// into_ed25519 transforms the JWK into an Ed25519 public key (RFC8037 2). Other OKP curves
+    /// are not supported.
+    fn into_ed25519(&self) -> Result<PKey<Public>, JWSError> {
+        if self.crv.as_deref() != Some("Ed25519") {
+            return Err(JWSError::InvalidPublicKey);
+        }
+
+        let x = match &self.x {
+            Some(x) => base64::decode_config(x, base64::URL_SAFE_NO_PAD)?,
+            None => {
+                return Err(JWSError::Encode(
+                    "x parameter missing in OKP JWK translation".to_string(),
+                ))
+            }
+        };
+
+        Ok(PKey::public_key_from_raw_bytes(&x, Id::ED25519)?)
+    }
+
     /// from_jws transforms a JSON web signature into a JWK. It uses the ACME-derived `alg` field
     /// from the protected header to determine what crypto to use.
     #[allow(dead_code)]
@@ -305,6 +429,46 @@ impl JWK {
         return Err(JWSError::InvalidPublicKey);
     }
 
+    /// thumbprint returns the RFC7638 thumbprint of the key: the base64url-encoded SHA-256 digest
+    /// of its required members, serialized in lexical order without whitespace.
+    pub fn thumbprint(&self) -> Result<String, JWSError> {
+        let member = |name: &str, value: &Option<String>| match value {
+            Some(value) => Ok(format!(r#""{}":"{}""#, name, value)),
+            None => Err(JWSError::Encode(format!(
+                "{} parameter missing in JWK thumbprint",
+                name
+            ))),
+        };
+
+        let members = match self.kty.as_str() {
+            "RSA" => vec![
+                member("e", &self.e)?,
+                r#""kty":"RSA""#.to_string(),
+                member("n", &self.n)?,
+            ],
+            "EC" | "ECDSA" => vec![
+                member(
+                    "crv",
+                    &Some(self.crv.clone().unwrap_or("P-256".to_string())),
+                )?,
+                r#""kty":"EC""#.to_string(),
+                member("x", &self.x)?,
+                member("y", &self.y)?,
+            ],
+            "OKP" => vec![
+                member("crv", &self.crv)?,
+                r#""kty":"OKP""#.to_string(),
+                member("x", &self.x)?,
+            ],
+            _ => return Err(JWSError::InvalidPublicKey),
+        };
+
+        Ok(base64::encode_config(
+            sha256(format!("{{{}}}", members.join(",")).as_bytes()),
+            base64::URL_SAFE_NO_PAD,
+        ))
+    }
+
     // just a simple way to unroll private params without making them easy to dink with
     pub(crate) fn params(
         &self,
@@ -352,6 +516,21 @@ impl JWS {
         }
     }
 
+    /// constructor for a POST-as-GET request (RFC8555 6.3), whose payload is empty.
+    pub fn post_as_get(protected: &ACMEProtectedHeader) -> Self {
+        JWS {
+            protected: to_base64(protected).expect("could not encode protected header"),
+            payload: String::default(),
+            signature: Default::default(),
+        }
+    }
+
+    /// is_post_as_get is true when the payload is empty, i.e. the request is a POST-as-GET
+    /// (RFC8555 6.3) rather than one carrying a JSON payload.
+    pub fn is_post_as_get(&self) -> bool {
+        self.payload.is_empty()
+    }
+
     /// returns the [ACMEProtectedHeader].
     pub fn protected(&mut self) -> Result<ACMEProtectedHeader, JWSError> {
         let res = serde_json::from_slice::<ACMEProtectedHeader>(&base64::decode_config(
@@ -375,24 +554,50 @@ impl JWS {
 
     /// verify verifies the protected header and payload were signed by the public key provided.
     pub fn verify(&self, key: ACMEKey) -> Result<bool, JWSValidationError> {
+        // EdDSA signatures are only ever checked against Ed25519 keys and vice versa, so that the
+        // alg header decides the verification path.
+        let alg = self.clone().protected()?.alg;
+        let eddsa_key = matches!(key, ACMEKey::EdDSA(_));
+        if (alg == "EdDSA") != eddsa_key {
+            let expected = if eddsa_key {
+                "EdDSA"
+            } else {
+                "ES256, ES384, RS256"
+            };
+            return Err(JWSValidationError::ACMEValidationError(
+                ACMEValidationError::AlgNotEqual(expected.to_string(), alg),
+            ));
+        }
+
         let to_verify = format!("{}.{}", self.protected, self.payload);
-        let digest = sha256(to_verify.as_bytes());
 
         let decoded = base64::decode_config(self.signature.clone(), base64::URL_SAFE_NO_PAD)?;
 
         match key {
             ACMEKey::ECDSA(key) => {
-                if decoded.len() != 64 {
+                let size = ec_coordinate_size(&key);
+                let expected = if size == 48 { "ES384" } else { "ES256" };
+                if alg != expected {
+                    return Err(JWSValidationError::ACMEValidationError(
+                        ACMEValidationError::AlgNotEqual(expected.to_string(), alg),
+                    ));
+                }
+
+                if decoded.len() != size * 2 {
                     return Err(JWSValidationError::SignatureDecode);
                 }
 
-                let r = BigNum::from_slice(&decoded[0..32])?;
-                let s = BigNum::from_slice(&decoded[32..64])?;
+                let r = BigNum::from_slice(&decoded[0..size])?;
+                let s = BigNum::from_slice(&decoded[size..size * 2])?;
 
                 let signature =
                     EcdsaSig::from_private_components(r, s).expect("could not program components");
 
-                Ok(signature.verify(&digest, &key)?)
+                if size == 48 {
+                    Ok(signature.verify(&sha384(to_verify.as_bytes()), &key)?)
+                } else {
+                    Ok(signature.verify(&sha256(to_verify.as_bytes()), &key)?)
+                }
             }
             ACMEKey::RSA(key) => {
                 let pkey = PKey::from_rsa(key)?;
@@ -400,6 +605,10 @@ impl JWS {
                 verifier.update(to_verify.as_bytes())?;
                 Ok(verifier.verify(&decoded)?)
             }
+            ACMEKey::EdDSA(key) => {
+                let mut verifier = Verifier::new_without_digest(key.as_ref())?;
+                Ok(verifier.verify_oneshot(&decoded, to_verify.as_bytes())?)
+            }
         }
     }
 
@@ -420,24 +629,28 @@ impl JWS {
 
         match key {
             ACMEPrivateKey::ECDSA(key) => {
-                let digest = sha256(to_sign.as_bytes());
-                let signature = EcdsaSig::sign(&digest, &key)?;
+                let size = ec_coordinate_size(&key);
+                let signature = if size == 48 {
+                    EcdsaSig::sign(&sha384(to_sign.as_bytes()), &key)?
+                } else {
+                    EcdsaSig::sign(&sha256(to_sign.as_bytes()), &key)?
+                };
 
                 let r = signature.r().to_vec();
                 let s = signature.s().to_vec();
 
                 let mut v = Vec::with_capacity(r.len() + s.len());
-                let pad = &[0; 32];
+                let pad = &[0; 48];
                 v.extend_from_slice(
                     &pad.iter()
-                        .take(32 - r.len())
+                        .take(size - r.len())
                         .map(|c| *c)
                         .collect::<Vec<u8>>(),
                 );
                 v.extend_from_slice(&r);
                 v.extend_from_slice(
                     &pad.iter()
-                        .take(32 - s.len())
+                        .take(size - s.len())
                         .map(|c| *c)
                         .collect::<Vec<u8>>(),
                 );
@@ -455,6 +668,16 @@ impl JWS {
                 self.signature =
                     base64::encode_config(signer.sign_to_vec().unwrap(), base64::URL_SAFE_NO_PAD);
 
+                Ok(self.clone())
+            }
+            ACMEPrivateKey::EdDSA(key) => {
+                let mut signer = Signer::new_without_digest(key.as_ref())?;
+
+                self.signature = base64::encode_config(
+                    signer.sign_oneshot_to_vec(to_sign.as_bytes())?,
+                    base64::URL_SAFE_NO_PAD,
+                );
+
                 Ok(self.clone())
             }
         }
@@ -475,6 +698,8 @@ impl JWS {
             x: jwk.x.clone(),
             y: jwk.y.clone(),
             alg: aph.alg.clone(),
+            kty: Some(jwk.kty.clone()),
+            crv: jwk.crv.clone(),
             id: None,
             created_at: chrono::DateTime::<chrono::Local>::from(SystemTime::now()),
             deleted_at: None,
@@ -494,7 +719,7 @@ mod tests {
         let good_url = Url::parse("https://one/two").unwrap();
         let bad_url = Url::parse("https://not/one/two").unwrap();
 
-        let validator = crate::acme::PostgresNonceValidator::new(svc.pg.db());
+        let validator = crate::acme::PostgresNonceValidator::new(svc.pg.db(), None);
 
         let kid = Url::parse("http://127.0.0.1:8000/accounts/this_is_a_kid").unwrap();
 
@@ -604,6 +829,115 @@ mod tests {
         }
     }
 
+    #[test]
+    fn jws_eddsa() {
+        use super::{ACMEKey, ACMEPrivateKey, ACMEProtectedHeader, JWK, JWS};
+        use openssl::{
+            ec::EcKey,
+            pkey::{PKey, Public},
+        };
+        use spectral::prelude::*;
+        use std::convert::TryInto;
+        use url::Url;
+
+        let key = PKey::generate_ed25519().unwrap();
+        let pubkey: PKey<Public> =
+            PKey::public_key_from_raw_bytes(&key.raw_public_key().unwrap(), key.id()).unwrap();
+
+        let acmekey: ACMEKey = pubkey.try_into().unwrap();
+        let mut jwk = JWK {
+            alg: None,
+            crv: Some("Ed25519".to_string()),
+            kty: "OKP".to_string(),
+            _use: None,
+            x: Some(base64::encode_config(
+                key.raw_public_key().unwrap(),
+                base64::URL_SAFE_NO_PAD,
+            )),
+            y: None,
+            n: None,
+            e: None,
+        };
+        assert_that!(ACMEKey::try_from(&mut jwk)).is_ok();
+
+        jwk.crv = Some("X25519".to_string());
+        assert_that!(ACMEKey::try_from(&mut jwk)).is_err();
+
+        let url = Url::parse("http://good.url").unwrap();
+        let kid = Url::parse("http://127.0.0.1:8000/accounts/this_is_a_kid").unwrap();
+
+        let mut protected = ACMEProtectedHeader::new_kid(kid, url, "1234".to_string());
+        protected.alg = "EdDSA".to_string();
+
+        let jws = JWS::new(&protected, "payload")
+            .sign(ACMEPrivateKey::EdDSA(key))
+            .unwrap();
+        assert_that!(jws.verify(acmekey.clone()).unwrap()).is_true();
+
+        // an EdDSA signature is never checked against another key type, nor the reverse.
+        let eckey = EcKey::generate(super::EC_GROUP.as_ref()).unwrap();
+        let ecpub: ACMEKey = eckey.public_key().try_into().unwrap();
+        assert_that!(jws.verify(ecpub)).is_err();
+
+        protected.alg = "ES256".to_string();
+        let jws = JWS::new(&protected, "payload")
+            .sign(ACMEPrivateKey::ECDSA(eckey))
+            .unwrap();
+        assert_that!(jws.verify(acmekey)).is_err();
+    }
+
+    #[test]
+    fn jws_es384() {
+        use super::{ACMEKey, ACMEPrivateKey, ACMEProtectedHeader, JWK, JWS};
+        use openssl::ec::EcKey;
+        use spectral::prelude::*;
+        use std::convert::{TryFrom, TryInto};
+        use url::Url;
+
+        let key = EcKey::generate(&super::EC_GROUP_P384).unwrap();
+        let mut jwk = JWK::try_from(&key).unwrap();
+        assert_that!(jwk.crv).is_equal_to(Some("P-384".to_string()));
+        assert_that!(
+            base64::decode_config(jwk.x.clone().unwrap(), base64::URL_SAFE_NO_PAD)
+                .unwrap()
+                .len()
+        )
+        .is_equal_to(48);
+
+        let acmekey: ACMEKey = (&mut jwk).try_into().unwrap();
+
+        let url = Url::parse("http://good.url").unwrap();
+        let mut protected = ACMEProtectedHeader::new_jwk(jwk.clone(), url, "1234".to_string());
+        protected.alg = "ES384".to_string();
+
+        let jws = JWS::new(&protected, "payload")
+            .sign(ACMEPrivateKey::ECDSA(key.clone()))
+            .unwrap();
+        assert_that!(
+            base64::decode_config(jws.signature.clone(), base64::URL_SAFE_NO_PAD)
+                .unwrap()
+                .len()
+        )
+        .is_equal_to(96);
+        assert_that!(jws.verify(acmekey.clone()).unwrap()).is_true();
+
+        // the alg must agree with the curve of the key.
+        protected.alg = "ES256".to_string();
+        let jws = JWS::new(&protected, "payload")
+            .sign(ACMEPrivateKey::ECDSA(key))
+            .unwrap();
+        assert_that!(jws.verify(acmekey)).is_err();
+
+        // a P-256 key is not accepted under a P-384 curve name.
+        let p256 = EcKey::generate(&super::EC_GROUP).unwrap();
+        let mut jwk = JWK::try_from(&p256).unwrap();
+        jwk.crv = Some("P-384".to_string());
+        assert_that!(ACMEKey::try_from(&mut jwk)).is_err();
+
+        jwk.crv = Some("P-521".to_string());
+        assert_that!(ACMEKey::try_from(&mut jwk)).is_err();
+    }
+
     #[test]
     fn jwk_into() {
         use openssl::ec::EcKey;
@@ -648,4 +982,38 @@ mod tests {
         assert_that!(pubkey.public_key_to_der().unwrap())
             .is_equal_to(rsa2.unwrap().public_key_to_der().unwrap());
     }
+
+    #[test]
+    fn jwk_thumbprint() {
+        use super::JWK;
+        use openssl::ec::EcKey;
+        use spectral::prelude::*;
+        use std::convert::TryFrom;
+
+        // RFC7638 3.1
+        let jwk = JWK {
+            alg: Some("RS256".to_string()),
+            crv: None,
+            kty: "RSA".to_string(),
+            _use: None,
+            x: None,
+            y: None,
+            n: Some("0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw".to_string()),
+            e: Some("AQAB".to_string()),
+        };
+        assert_that!(jwk.thumbprint())
+            .is_ok_containing("NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs".to_string());
+
+        // members outside the required set do not change the thumbprint.
+        let key = EcKey::generate(&super::EC_GROUP).unwrap();
+        let jwk = JWK::try_from(&key).unwrap();
+        let mut with_use = jwk.clone();
+        with_use._use = Some("sig".to_string());
+        with_use.alg = None;
+        assert_that!(with_use.thumbprint().unwrap()).is_equal_to(jwk.thumbprint().unwrap());
+
+        let mut missing = jwk.clone();
+        missing.y = None;
+        assert_that!(missing.thumbprint()).is_err();
+    }
 }
@@ -0,0 +1,211 @@
+use std::sync::Arc;
+
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+
+lazy_static::lazy_static! {
+    /// SIGNING_DURATION_HISTOGRAM records how long each certificate took the CA to sign, in
+    /// seconds. It is shared by every CA in the process, and exported with the service's
+    /// [Metrics].
+    pub static ref SIGNING_DURATION_HISTOGRAM: Histogram = Histogram::with_opts(HistogramOpts::new(
+        "acme_ca_signing_duration_seconds",
+        "time taken by the CA to sign each certificate",
+    ))
+    .unwrap();
+}
+
+/// the path segments which name an endpoint; anything else in a request path is an identifier.
+const ENDPOINTS: [&str; 17] = [
+    "nonce",
+    "account",
+    "order",
+    "finalize",
+    "certificate",
+    "authz",
+    "chall",
+    "revoke",
+    "key-change",
+    "orders",
+    "renewal-info",
+    "ocsp",
+    "crl",
+    "ca-pubkey",
+    "ca-chain",
+    "admin",
+    "metrics",
+];
+
+/// Metrics holds the Prometheus metrics the service exports at `/metrics`. Clones share the same
+/// metrics and registry.
+#[derive(Clone, Debug)]
+pub struct Metrics {
+    registry: Arc<Registry>,
+    pub(crate) requests: IntCounterVec,
+    pub(crate) certificate_issuance: Histogram,
+    pub(crate) active_orders: IntGauge,
+    pub(crate) nonce_validations: IntCounterVec,
+    pub(crate) ca_rotations: IntCounter,
+    pub(crate) ca_certs_issued: IntCounter,
+    pub(crate) ca_signing_errors: IntCounter,
+    pub(crate) ca_last_rotation: IntGauge,
+    pub(crate) db_pool_size: IntGauge,
+}
+
+impl Metrics {
+    /// new registers the service's metrics with the registry provided, which may also hold
+    /// metrics of the application embedding the service. It fails if any of the metrics are
+    /// already registered.
+    pub fn new(registry: Arc<Registry>) -> Result<Self, prometheus::Error> {
+        let requests = IntCounterVec::new(
+            Opts::new(
+                "acme_requests_total",
+                "requests answered, by endpoint and status",
+            ),
+            &["endpoint", "status"],
+        )?;
+        let certificate_issuance = Histogram::with_opts(HistogramOpts::new(
+            "acme_certificate_issuance_seconds",
+            "time taken to sign certificates",
+        ))?;
+        let active_orders = IntGauge::new(
+            "acme_active_orders",
+            "orders which are pending, ready or processing",
+        )?;
+        let nonce_validations = IntCounterVec::new(
+            Opts::new(
+                "acme_nonce_validations_total",
+                "replay nonces checked, by result",
+            ),
+            &["result"],
+        )?;
+        let ca_rotations = IntCounter::new(
+            "acme_ca_rotations_total",
+            "times the CA has been replaced with a different certificate",
+        )?;
+        let ca_certs_issued = IntCounter::new(
+            "acme_ca_certs_issued_total",
+            "certificates signed by the CA collector",
+        )?;
+        let ca_signing_errors = IntCounter::new(
+            "acme_ca_signing_errors_total",
+            "CSRs the CA collector could not sign",
+        )?;
+        let ca_last_rotation = IntGauge::new(
+            "acme_ca_last_rotation_timestamp_seconds",
+            "when the CA was last replaced, in seconds since the epoch; 0 if it never was",
+        )?;
+        let db_pool_size = IntGauge::new(
+            "acme_db_pool_size",
+            "the most connections the database pool will open",
+        )?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(certificate_issuance.clone()))?;
+        registry.register(Box::new(active_orders.clone()))?;
+        registry.register(Box::new(nonce_validations.clone()))?;
+        registry.register(Box::new(ca_rotations.clone()))?;
+        registry.register(Box::new(ca_certs_issued.clone()))?;
+        registry.register(Box::new(ca_signing_errors.clone()))?;
+        registry.register(Box::new(ca_last_rotation.clone()))?;
+        registry.register(Box::new(db_pool_size.clone()))?;
+        registry.register(Box::new(SIGNING_DURATION_HISTOGRAM.clone()))?;
+
+        Ok(Self {
+            registry,
+            requests,
+            certificate_issuance,
+            active_orders,
+            nonce_validations,
+            ca_rotations,
+            ca_certs_issued,
+            ca_signing_errors,
+            ca_last_rotation,
+            db_pool_size,
+        })
+    }
+
+    /// returns the registry the metrics are registered with.
+    pub fn registry(&self) -> Arc<Registry> {
+        self.registry.clone()
+    }
+
+    /// encode renders every metric in the registry in the Prometheus text exposition format.
+    pub fn encode(&self) -> Result<Vec<u8>, prometheus::Error> {
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(buf)
+    }
+
+    /// observe_request counts a response to the request path provided.
+    pub(crate) fn observe_request(&self, path: &str, status: u16) {
+        self.requests
+            .with_label_values(&[endpoint_label(path), &status.to_string()])
+            .inc();
+    }
+}
+
+/// endpoint_label names the endpoint a request path was routed to without the identifiers in it,
+/// which would make for unbounded label values. The last known segment wins, so that e.g.
+/// `/order/:order_id/finalize` is labelled `finalize`.
+fn endpoint_label(path: &str) -> &'static str {
+    let segments = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<&str>>();
+
+    for segment in segments.iter().rev() {
+        if let Some(endpoint) = ENDPOINTS.iter().copied().find(|e| e == segment) {
+            return endpoint;
+        }
+    }
+
+    if path.ends_with('/') {
+        "directory"
+    } else {
+        "other"
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_endpoint_label() {
+        use super::endpoint_label;
+        use spectral::prelude::*;
+
+        for (path, label) in vec![
+            ("/", "directory"),
+            ("/acme/", "directory"),
+            ("/nonce", "nonce"),
+            ("/account/abcdef", "account"),
+            ("/order/abcdef", "order"),
+            ("/order/abcdef/finalize", "finalize"),
+            ("/order/abcdef/certificate", "certificate"),
+            ("/chall/abcdef", "chall"),
+            ("/ocsp/MEMwQTA", "ocsp"),
+            ("/favicon.ico", "other"),
+        ] {
+            assert_that!(endpoint_label(path)).is_equal_to(label);
+        }
+    }
+
+    #[test]
+    fn test_metrics_registry() {
+        use super::Metrics;
+        use prometheus::Registry;
+        use spectral::prelude::*;
+        use std::sync::Arc;
+
+        let registry = Arc::new(Registry::new());
+        let metrics = Metrics::new(registry.clone()).unwrap();
+        metrics.observe_request("/order/abcdef", 201);
+        metrics.observe_request("/order/abcdef", 201);
+
+        let text = String::from_utf8(metrics.encode().unwrap()).unwrap();
+        assert_that!(text).contains(r#"acme_requests_total{endpoint="order",status="201"} 2"#);
+
+        // the same metrics cannot be registered twice.
+        assert_that!(Metrics::new(registry)).is_err();
+    }
+}
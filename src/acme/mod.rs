@@ -6,16 +6,34 @@ pub mod challenge;
 pub mod dns;
 /// ACME HTTP handlers
 pub mod handlers;
+/// http-01 challenge support
+pub mod http01;
 /// ACME JOSE implementation
 pub mod jose;
+/// Prometheus metrics
+pub mod metrics;
+/// In-process rate limiting of ACME endpoints
+pub mod rate_limit;
+/// tls-alpn-01 challenge support
+pub mod tls_alpn;
 
-use std::{collections::HashSet, convert::TryFrom, sync::Arc};
+use std::{
+    collections::{HashSet, VecDeque},
+    convert::TryFrom,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use hyper::Body;
 use tokio::sync::Mutex;
 
 use async_trait::async_trait;
 use lazy_static::lazy_static;
+use rand::{rngs::StdRng, RngCore, SeedableRng};
 use serde::{Deserialize, Serialize};
 
 use crate::{
@@ -23,8 +41,8 @@ use crate::{
         db::{LoadError, SaveError},
         ACMEValidationError,
     },
-    models::{nonce::Nonce, Postgres, Record},
-    util::make_nonce,
+    models::{nonce::NonceState, Postgres},
+    util::{make_nonce, DEFAULT_NONCE_SIZE},
 };
 
 use self::dns::DNSName;
@@ -32,7 +50,12 @@ use self::dns::DNSName;
 lazy_static! {
     /// List of supported algorithms, with the ACME preferred one first; in our case this is
     /// "ES256".
-    pub static ref ACME_EXPECTED_ALGS: [String; 2] = ["ES256".to_string(), "RS256".to_string()];
+    pub static ref ACME_EXPECTED_ALGS: [String; 4] = [
+        "ES256".to_string(),
+        "ES384".to_string(),
+        "RS256".to_string(),
+        "EdDSA".to_string()
+    ];
 }
 
 /// A Result<> that calls can return to trampoline through ratpack handlers swiftly by triggering HTTP
@@ -70,6 +93,9 @@ impl From<crate::errors::Error> for ACMEResult {
 #[serde(tag = "type", content = "value")]
 pub enum ACMEIdentifier {
     DNS(dns::DNSName), // NOTE: DNS names cannot be wildcards.
+    /// RFC8738 IP address identifier. Addresses are always rendered in their canonical form
+    /// (RFC5952 for IPv6), so two spellings of the same address compare equal once parsed.
+    IP(IpAddr),
 }
 
 impl TryFrom<String> for ACMEIdentifier {
@@ -84,13 +110,46 @@ impl TryFrom<String> for ACMEIdentifier {
 }
 
 impl ACMEIdentifier {
+    /// new constructs an identifier from the `kind` and `value` stored alongside an
+    /// authorization. `kind` is one of "dns" or "ip", see [ACMEIdentifier::kind].
+    pub fn new(kind: &str, value: String) -> Result<Self, LoadError> {
+        match kind {
+            "dns" => Self::try_from(value),
+            "ip" => match value.parse::<IpAddr>() {
+                Ok(addr) => Ok(ACMEIdentifier::IP(addr)),
+                Err(e) => Err(LoadError::Generic(e.to_string())),
+            },
+            _ => Err(LoadError::InvalidEnum),
+        }
+    }
+
+    /// kind returns the identifier type as it appears in the `type` field of the identifier
+    /// object.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ACMEIdentifier::DNS(_) => "dns",
+            ACMEIdentifier::IP(_) => "ip",
+        }
+    }
+
     pub fn to_string(self) -> String {
         match self {
             ACMEIdentifier::DNS(name) => name.to_string(),
+            ACMEIdentifier::IP(addr) => addr.to_string(),
         }
     }
 }
 
+/// ip_from_octets decodes the iPAddress form of a subjectAltName (RFC5280 section 4.2.1.6), which
+/// is four octets for IPv4 and sixteen for IPv6.
+pub(crate) fn ip_from_octets(octets: &[u8]) -> Option<IpAddr> {
+    match octets.len() {
+        4 => Some(IpAddr::from(<[u8; 4]>::try_from(octets).unwrap())),
+        16 => Some(IpAddr::from(<[u8; 16]>::try_from(octets).unwrap())),
+        _ => None,
+    }
+}
+
 #[async_trait]
 /// NonceValidator is a storage trait that controls the generation and validation of nonces, used
 /// heavily in ACME and especially in the `Replay-Nonce` HTTP header present in all calls, and the
@@ -107,7 +166,19 @@ pub trait NonceValidator {
     async fn make(&self) -> Result<String, SaveError>;
 }
 
-/// Defines a basic (very basic) Nonce validation system
+#[async_trait]
+impl<T: NonceValidator + Send + Sync + ?Sized> NonceValidator for Arc<T> {
+    async fn validate(&self, nonce: &str) -> Result<(), ACMEValidationError> {
+        (**self).validate(nonce).await
+    }
+
+    async fn make(&self) -> Result<String, SaveError> {
+        (**self).make().await
+    }
+}
+
+/// Defines a basic (very basic) Nonce validation system, kept in memory. Useful for tests which
+/// don't need Postgres, or a single-process deployment that can lose its nonces on restart.
 #[derive(Debug, Clone)]
 pub struct SetValidator(Arc<Mutex<HashSet<String>>>);
 
@@ -138,34 +209,546 @@ impl NonceValidator for SetValidator {
     }
 }
 
+/// NonceGenerator makes the nonces a [PostgresNonceValidator] hands out; see
+/// [PostgresNonceValidator::with_generator].
+pub trait NonceGenerator {
+    /// generate returns `len` random bytes, encoded as unpadded URL-safe base64.
+    fn generate(&self, len: usize) -> String;
+}
+
+/// OsNonceGenerator generates nonces from the openssl CSPRNG. It is what validators use unless
+/// told otherwise.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OsNonceGenerator;
+
+impl NonceGenerator for OsNonceGenerator {
+    fn generate(&self, len: usize) -> String {
+        make_nonce(Some(len))
+    }
+}
+
+/// SeededNonceGenerator generates the same sequence of nonces for the same seed, for tests which
+/// need to predict or collide them. Its nonces are not secret; never use it to serve clients.
+#[derive(Debug)]
+pub struct SeededNonceGenerator(std::sync::Mutex<StdRng>);
+
+impl SeededNonceGenerator {
+    /// new starts the sequence of nonces for `seed`.
+    pub fn new(seed: u64) -> Self {
+        Self(std::sync::Mutex::new(StdRng::seed_from_u64(seed)))
+    }
+}
+
+impl NonceGenerator for SeededNonceGenerator {
+    fn generate(&self, len: usize) -> String {
+        let mut r = vec![0; len];
+        self.0.lock().unwrap().fill_bytes(&mut r);
+        base64::encode_config(r, base64::URL_SAFE_NO_PAD)
+    }
+}
+
+/// how long nonces issued by a [PostgresNonceValidator] remain valid, unless configured otherwise.
+pub const DEFAULT_NONCE_TTL: Duration = Duration::from_secs(5 * 60);
+
 #[derive(Clone)]
 /// Defines a PostgreSQL-backed nonce validator
-pub struct PostgresNonceValidator(crate::models::Postgres);
+pub struct PostgresNonceValidator {
+    db: crate::models::Postgres,
+    replica: Option<crate::models::Postgres>,
+    ttl: Duration,
+    generator: Arc<dyn NonceGenerator + Send + Sync>,
+}
+
+impl PostgresNonceValidator {
+    /// new constructs a validator whose nonces expire after `ttl`, or [DEFAULT_NONCE_TTL] if
+    /// None. Nonces which expire without being used are only removed by
+    /// [PostgresNonceValidator::cleanup].
+    pub fn new(pg: Postgres, ttl: Option<Duration>) -> Self {
+        Self {
+            db: pg,
+            replica: None,
+            ttl: ttl.unwrap_or(DEFAULT_NONCE_TTL),
+            generator: Arc::new(OsNonceGenerator),
+        }
+    }
+
+    /// with_replica looks nonces up on `replica` before consuming them. Consuming a nonce is
+    /// always done on the primary, and only the primary decides whether it is valid: a nonce the
+    /// replica does not know of, e.g. one issued moments ago which has not reached it yet, is
+    /// still consumed there, as clients use the nonce of the previous response straight away. If
+    /// the replica cannot be queried, the nonce is consumed as though there were none.
+    pub fn with_replica(mut self, replica: Postgres) -> Self {
+        self.replica = Some(replica);
+        self
+    }
+
+    /// with_generator replaces the [OsNonceGenerator] nonces are made with, e.g. with a
+    /// [SeededNonceGenerator] in tests.
+    pub fn with_generator(mut self, generator: Box<dyn NonceGenerator + Send + Sync>) -> Self {
+        self.generator = Arc::from(generator);
+        self
+    }
+
+    /// returns how long nonces remain valid for.
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// cleanup removes expired nonces, returning how many were removed.
+    pub async fn cleanup(&self) -> Result<u64, SaveError> {
+        self.db.delete_expired_nonces(self.ttl).await
+    }
+
+    /// cleanup_periodically runs [PostgresNonceValidator::cleanup] every `interval`, forever.
+    /// Spawn it in its own task; errors are logged and retried on the next run.
+    pub async fn cleanup_periodically(&self, interval: Duration) {
+        loop {
+            match self.cleanup().await {
+                Ok(removed) => log::debug!("removed {} expired nonces", removed),
+                Err(e) => log::error!("could not remove expired nonces: {}", e),
+            }
+
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
 
 impl PostgresNonceValidator {
-    pub fn new(pg: Postgres) -> Self {
-        Self(pg)
+    /// prefetch_batch makes and stores `n` nonces with a single insert, for handing out later;
+    /// see [NoncePrefetchPool]. The nonces' TTL runs from when they are stored.
+    pub async fn prefetch_batch(&self, n: usize) -> Result<Vec<String>, SaveError> {
+        let nonces = (0..n)
+            .map(|_| self.generator.generate(DEFAULT_NONCE_SIZE))
+            .collect::<Vec<String>>();
+        self.db.insert_nonces(&nonces).await
+    }
+}
+
+/// NoncePrefetchPool hands out nonces from batches stored ahead of time with
+/// [PostgresNonceValidator::prefetch_batch], so that busy services do not store each nonce with
+/// an insert of its own. When fewer than `low_water` nonces remain, another batch is fetched in
+/// the background. It is a [NonceValidator] itself, so it can be given to
+/// [handlers::ServiceState::with_nonce_validator]. Clones share the same pool.
+///
+/// Nonces expire by the validator's TTL whether or not they have been handed out, so batches
+/// should be small enough to be drained well within it; nonces which outlive it in the pool are
+/// dropped rather than handed out.
+#[derive(Clone)]
+pub struct NoncePrefetchPool {
+    validator: PostgresNonceValidator,
+    /// the nonces waiting, each with when its batch was fetched.
+    pool: Arc<Mutex<VecDeque<(Instant, String)>>>,
+    refilling: Arc<AtomicBool>,
+    batch_size: usize,
+    low_water: usize,
+}
+
+impl NoncePrefetchPool {
+    /// new constructs an empty pool which fetches `batch_size` nonces at a time from `validator`.
+    pub fn new(validator: PostgresNonceValidator, batch_size: usize, low_water: usize) -> Self {
+        Self {
+            validator,
+            pool: Arc::new(Mutex::new(VecDeque::new())),
+            refilling: Arc::new(AtomicBool::new(false)),
+            batch_size: batch_size.max(1),
+            low_water,
+        }
+    }
+
+    /// acquire takes a nonce from the pool. If the pool is empty, a batch is fetched first.
+    pub async fn acquire(&self) -> Result<String, SaveError> {
+        let mut pool = self.pool.lock().await;
+
+        let ttl = self.validator.ttl();
+        pool.retain(|(fetched, _)| fetched.elapsed() < ttl);
+
+        let (nonce, remaining) = match pool.pop_front() {
+            Some((_, nonce)) => (nonce, pool.len()),
+            None => {
+                // the batch is fetched without holding the lock, so that other requests can take
+                // the nonces a refill adds in the meantime.
+                drop(pool);
+
+                let (fetched, batch) = self.fetch().await?;
+                let mut batch = batch.into_iter();
+                let nonce = batch
+                    .next()
+                    .ok_or_else(|| SaveError::Generic("no nonces were stored".to_string()))?;
+
+                let mut pool = self.pool.lock().await;
+                pool.extend(batch.map(|nonce| (fetched, nonce)));
+                (nonce, pool.len())
+            }
+        };
+
+        if remaining < self.low_water && !self.refilling.swap(true, Ordering::SeqCst) {
+            let this = self.clone();
+
+            tokio::spawn(async move {
+                match this.fetch().await {
+                    Ok((fetched, nonces)) => this
+                        .pool
+                        .lock()
+                        .await
+                        .extend(nonces.into_iter().map(|nonce| (fetched, nonce))),
+                    Err(e) => log::error!("could not prefetch nonces: {}", e),
+                }
+
+                this.refilling.store(false, Ordering::SeqCst);
+            });
+        }
+
+        Ok(nonce)
+    }
+
+    /// fetch stores a batch of nonces, returning them with when their TTL started at the latest.
+    async fn fetch(&self) -> Result<(Instant, Vec<String>), SaveError> {
+        let fetched = Instant::now();
+        let nonces = self.validator.prefetch_batch(self.batch_size).await?;
+        Ok((fetched, nonces))
+    }
+
+    /// returns how many nonces are waiting in the pool.
+    pub async fn available(&self) -> usize {
+        self.pool.lock().await.len()
     }
 }
 
 #[async_trait]
-impl NonceValidator for PostgresNonceValidator {
+impl NonceValidator for NoncePrefetchPool {
     async fn validate(&self, nonce: &str) -> Result<(), ACMEValidationError> {
-        let nonce = match Nonce::find(nonce.to_string(), self.0.clone()).await {
-            Ok(nonce) => nonce,
-            Err(_) => return Err(ACMEValidationError::NonceNotFound),
-        };
+        self.validator.validate(nonce).await
+    }
+
+    async fn make(&self) -> Result<String, SaveError> {
+        self.acquire().await
+    }
+}
 
-        if let Err(_) = nonce.delete(self.0.clone()).await {
-            return Err(ACMEValidationError::NonceNotFound);
+#[async_trait]
+impl NonceValidator for PostgresNonceValidator {
+    async fn validate(&self, nonce: &str) -> Result<(), ACMEValidationError> {
+        if let Some(replica) = &self.replica {
+            match replica.nonce_exists(nonce).await {
+                Ok(false) => log::debug!("nonce not found on the replica; consuming it anyway"),
+                Ok(true) => {}
+                Err(e) => log::warn!("could not look up nonce on the replica: {}", e),
+            }
         }
 
-        Ok(())
+        match self.db.consume_nonce_with_ttl(nonce, self.ttl).await {
+            Ok(NonceState::Valid) => Ok(()),
+            Ok(NonceState::Expired) => Err(ACMEValidationError::NonceExpired),
+            Ok(NonceState::NotFound) => Err(ACMEValidationError::NonceNotFound),
+            Err(e) => Err(ACMEValidationError::NonceFetchError(e.to_string())),
+        }
     }
 
     async fn make(&self) -> Result<String, SaveError> {
-        let mut nonce = Nonce::new();
-        nonce.create(self.0.clone()).await?;
-        Ok(nonce.id().unwrap().unwrap())
+        let nonce = self.generator.generate(DEFAULT_NONCE_SIZE);
+        self.db.insert_nonce(&nonce).await?;
+        Ok(nonce)
+    }
+}
+
+mod tests {
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_set_validator() {
+        use super::{NonceValidator, SetValidator};
+        use crate::errors::ACMEValidationError;
+        use spectral::prelude::*;
+
+        let validator = SetValidator::default();
+
+        let nonce = validator.make().await.unwrap();
+        assert_that!(nonce.is_empty()).is_false();
+        assert_that!(validator.make().await.unwrap()).is_not_equal_to(nonce.clone());
+
+        assert_that!(validator.validate(&nonce).await).is_ok();
+        // nonces may only be used once
+        assert_that!(validator.validate(&nonce).await.err())
+            .is_equal_to(Some(ACMEValidationError::NonceNotFound));
+        assert_that!(validator.validate("unknown").await.err())
+            .is_equal_to(Some(ACMEValidationError::NonceNotFound));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_postgres_nonce_validator() {
+        use super::{NonceValidator, PostgresNonceValidator};
+        use crate::errors::ACMEValidationError;
+        use crate::test::PGTest;
+        use spectral::prelude::*;
+        use std::sync::Arc;
+
+        let pg = PGTest::new("test_postgres_nonce_validator").await.unwrap();
+        // exercise it through a trait object, as ServiceState holds it
+        let validator: Arc<dyn NonceValidator + Send + Sync> =
+            Arc::new(PostgresNonceValidator::new(pg.db(), None));
+
+        let nonce = validator.make().await.unwrap();
+        assert_that!(pg.db().nonce_count().await.unwrap()).is_equal_to(1);
+
+        assert_that!(validator.validate(&nonce).await).is_ok();
+        assert_that!(pg.db().nonce_count().await.unwrap()).is_equal_to(0);
+        assert_that!(validator.validate(&nonce).await.err())
+            .is_equal_to(Some(ACMEValidationError::NonceNotFound));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_postgres_nonce_validator_replica() {
+        use super::{NonceValidator, PostgresNonceValidator};
+        use crate::errors::ACMEValidationError;
+        use crate::models::{Postgres, PostgresConfig};
+        use crate::test::PGTest;
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        let pg = PGTest::new("test_postgres_nonce_validator_replica")
+            .await
+            .unwrap();
+        // a pool of its own on the same database stands in for the replica.
+        let replica = pg.db().connect_again().await.unwrap();
+        let validator = PostgresNonceValidator::new(pg.db(), Some(Duration::from_secs(1)))
+            .with_replica(replica);
+
+        let nonce = validator.make().await.unwrap();
+        assert_that!(validator.validate(&nonce).await).is_ok();
+        assert_that!(pg.db().nonce_count().await.unwrap()).is_equal_to(0);
+        assert_that!(validator.validate(&nonce).await.err())
+            .is_equal_to(Some(ACMEValidationError::NonceNotFound));
+        assert_that!(validator.validate("unknown").await.err())
+            .is_equal_to(Some(ACMEValidationError::NonceNotFound));
+
+        // the primary still decides whether a nonce found on the replica is valid.
+        let expired = validator.make().await.unwrap();
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_that!(validator.validate(&expired).await.err())
+            .is_equal_to(Some(ACMEValidationError::NonceExpired));
+
+        // a nonce which has not reached the replica yet is still valid on the primary.
+        let lagging = PGTest::new("test_postgres_nonce_validator_lagging")
+            .await
+            .unwrap();
+        let validator = PostgresNonceValidator::new(pg.db(), None).with_replica(lagging.db());
+
+        let nonce = validator.make().await.unwrap();
+        assert_that!(lagging.db().nonce_count().await.unwrap()).is_equal_to(0);
+        assert_that!(validator.validate(&nonce).await).is_ok();
+        assert_that!(validator.validate(&nonce).await.err())
+            .is_equal_to(Some(ACMEValidationError::NonceNotFound));
+
+        // a replica which cannot be reached is passed over.
+        let unreachable = Postgres::with_config(
+            PostgresConfig::new("host=/nonexistent dbname=coyote user=postgres connect_timeout=1")
+                .with_max_connections(1)
+                .with_acquire_timeout(Some(Duration::from_secs(1))),
+        )
+        .await
+        .unwrap();
+        let validator = PostgresNonceValidator::new(pg.db(), None).with_replica(unreachable);
+
+        let nonce = validator.make().await.unwrap();
+        assert_that!(validator.validate(&nonce).await).is_ok();
+        assert_that!(validator.validate(&nonce).await.err())
+            .is_equal_to(Some(ACMEValidationError::NonceNotFound));
+    }
+
+    #[test]
+    fn test_nonce_generators() {
+        use super::{NonceGenerator, OsNonceGenerator, SeededNonceGenerator};
+        use spectral::prelude::*;
+        use std::collections::HashSet;
+
+        assert_that!(OsNonceGenerator.generate(32).len()).is_equal_to(43);
+        assert_that!(SeededNonceGenerator::new(1).generate(16).len()).is_equal_to(22);
+
+        // the same seed makes the same sequence, and the sequence does not repeat itself.
+        let (a, b) = (SeededNonceGenerator::new(1), SeededNonceGenerator::new(1));
+        let other = SeededNonceGenerator::new(2);
+        let mut seen = HashSet::new();
+
+        for _ in 0..1000 {
+            let nonce = a.generate(32);
+            assert_that!(b.generate(32)).is_equal_to(nonce.clone());
+            assert_that!(other.generate(32)).is_not_equal_to(nonce.clone());
+            assert_that!(seen.insert(nonce)).is_true();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_postgres_nonce_collision() {
+        use super::{NonceValidator, PostgresNonceValidator, SeededNonceGenerator};
+        use crate::test::PGTest;
+        use spectral::prelude::*;
+
+        let pg = PGTest::new("test_postgres_nonce_collision").await.unwrap();
+        let validator = PostgresNonceValidator::new(pg.db(), None)
+            .with_generator(Box::new(SeededNonceGenerator::new(42)));
+        let colliding = PostgresNonceValidator::new(pg.db(), None)
+            .with_generator(Box::new(SeededNonceGenerator::new(42)));
+
+        let nonce = validator.make().await.unwrap();
+        assert_that!(validator.make().await.unwrap()).is_not_equal_to(nonce.clone());
+
+        // a nonce which is already outstanding is never handed out twice.
+        assert_that!(colliding.make().await).is_err();
+        assert_that!(pg.db().nonce_count().await.unwrap()).is_equal_to(2);
+
+        // once it has been used, the same nonce may be stored again.
+        assert_that!(validator.validate(&nonce).await).is_ok();
+        let colliding = PostgresNonceValidator::new(pg.db(), None)
+            .with_generator(Box::new(SeededNonceGenerator::new(42)));
+        assert_that!(colliding.make().await.unwrap()).is_equal_to(nonce);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_postgres_nonce_validator_ttl() {
+        use super::{NonceValidator, PostgresNonceValidator, DEFAULT_NONCE_TTL};
+        use crate::errors::ACMEValidationError;
+        use crate::test::PGTest;
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        let pg = PGTest::new("test_postgres_nonce_validator_ttl")
+            .await
+            .unwrap();
+
+        assert_that!(PostgresNonceValidator::new(pg.db(), None).ttl())
+            .is_equal_to(DEFAULT_NONCE_TTL);
+
+        let validator = PostgresNonceValidator::new(pg.db(), Some(Duration::from_secs(1)));
+        assert_that!(validator.ttl()).is_equal_to(Duration::from_secs(1));
+
+        let nonce = validator.make().await.unwrap();
+        assert_that!(validator.validate(&nonce).await).is_ok();
+
+        let expired = validator.make().await.unwrap();
+        let unused = validator.make().await.unwrap();
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let fresh = validator.make().await.unwrap();
+
+        // an expired nonce is rejected, and cannot be retried.
+        assert_that!(validator.validate(&expired).await.err())
+            .is_equal_to(Some(ACMEValidationError::NonceExpired));
+        assert_that!(validator.validate(&expired).await.err())
+            .is_equal_to(Some(ACMEValidationError::NonceNotFound));
+
+        assert_that!(pg.db().nonce_count().await.unwrap()).is_equal_to(2);
+        assert_that!(validator.cleanup().await.unwrap()).is_equal_to(1);
+        assert_that!(pg.db().nonce_count().await.unwrap()).is_equal_to(1);
+
+        assert_that!(validator.validate(&unused).await.err())
+            .is_equal_to(Some(ACMEValidationError::NonceNotFound));
+        assert_that!(validator.validate(&fresh).await).is_ok();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_nonce_prefetch() {
+        use super::{NoncePrefetchPool, NonceValidator, PostgresNonceValidator};
+        use crate::test::PGTest;
+        use spectral::prelude::*;
+        use std::collections::HashSet;
+        use std::sync::atomic::Ordering;
+        use std::time::Duration;
+
+        let pg = PGTest::new("test_nonce_prefetch").await.unwrap();
+        let validator = PostgresNonceValidator::new(pg.db(), None);
+
+        let batch = validator.prefetch_batch(5).await.unwrap();
+        assert_that!(batch.iter().collect::<HashSet<&String>>().len()).is_equal_to(5);
+        assert_that!(pg.db().nonce_count().await.unwrap()).is_equal_to(5);
+        for nonce in &batch {
+            assert_that!(validator.validate(nonce).await).is_ok();
+        }
+
+        let pool = NoncePrefetchPool::new(validator.clone(), 4, 2);
+        let mut seen = HashSet::new();
+
+        for _ in 0..10 {
+            let nonce = pool.make().await.unwrap();
+            assert_that!(seen.insert(nonce.clone())).is_true();
+            assert_that!(pool.validate(&nonce).await).is_ok();
+        }
+
+        // the pool is topped up in the background once it runs low.
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while pool.available().await < 2 || pool.refilling.load(Ordering::SeqCst) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let waiting = pool.available().await as i64;
+        assert_that!(pg.db().nonce_count().await.unwrap()).is_equal_to(waiting);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_nonce_prefetch_ttl() {
+        use super::{NoncePrefetchPool, NonceValidator, PostgresNonceValidator};
+        use crate::test::PGTest;
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        let pg = PGTest::new("test_nonce_prefetch_ttl").await.unwrap();
+        let validator = PostgresNonceValidator::new(pg.db(), Some(Duration::from_secs(1)));
+
+        // no refills, so the rest of the first batch is all the pool holds.
+        let pool = NoncePrefetchPool::new(validator, 4, 0);
+        pool.make().await.unwrap();
+        let stale = pool
+            .pool
+            .lock()
+            .await
+            .iter()
+            .map(|(_, nonce)| nonce.clone())
+            .collect::<Vec<String>>();
+        assert_that!(stale.len()).is_equal_to(3);
+
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+
+        // the nonces left over have expired, so a fresh batch is fetched instead.
+        let fresh = pool.make().await.unwrap();
+        assert_that!(stale.contains(&fresh)).is_false();
+        assert_that!(pool.available().await).is_equal_to(3);
+        assert_that!(pool.validate(&fresh).await).is_ok();
+    }
+
+    #[test]
+    fn test_ip_identifier() {
+        use super::ACMEIdentifier;
+        use spectral::prelude::*;
+        use std::convert::TryFrom;
+
+        let id = serde_json::from_str::<ACMEIdentifier>(r#"{"type":"ip","value":"127.0.0.1"}"#);
+        assert_that!(id).is_ok();
+        let id = id.unwrap();
+        assert_that!(id.kind()).is_equal_to("ip");
+        assert_that!(id.clone().to_string()).is_equal_to("127.0.0.1".to_string());
+        assert_that!(serde_json::to_string(&id).unwrap())
+            .is_equal_to(r#"{"type":"ip","value":"127.0.0.1"}"#.to_string());
+
+        // IPv6 addresses are canonicalized per RFC5952
+        for spelling in ["2001:DB8:0:0:0:0:0:1", "2001:db8::0001", "2001:0db8:0::1"] {
+            let id = serde_json::from_str::<ACMEIdentifier>(&format!(
+                r#"{{"type":"ip","value":"{}"}}"#,
+                spelling
+            ))
+            .unwrap();
+            assert_that!(id.to_string()).is_equal_to("2001:db8::1".to_string());
+        }
+
+        assert_that!(ACMEIdentifier::new("ip", "::ffff:0:1".to_string())
+            .unwrap()
+            .kind())
+        .is_equal_to("ip");
+        assert_that!(ACMEIdentifier::new("ip", "foo.com".to_string())).is_err();
+        assert_that!(ACMEIdentifier::new("dns", "foo.com".to_string()).unwrap())
+            .is_equal_to(ACMEIdentifier::try_from("foo.com".to_string()).unwrap());
+        assert_that!(ACMEIdentifier::new("email", "foo.com".to_string())).is_err();
+        assert_that!(serde_json::from_str::<ACMEIdentifier>(
+            r#"{"type":"ip","value":"256.0.0.1"}"#
+        ))
+        .is_err();
     }
 }
@@ -0,0 +1,308 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Mutex;
+
+/// RateLimitedEndpoint names the endpoints which may be rate limited with a [RateLimitConfig].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RateLimitedEndpoint {
+    /// the newNonce endpoint, via HEAD or GET
+    NewNonce,
+    /// the newAccount endpoint
+    NewAccount,
+    /// the newOrder endpoint
+    NewOrder,
+}
+
+/// RequestsPerMinute is the sustained rate a client may make requests at. Clients may also burst
+/// up to this many requests at once before being limited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RequestsPerMinute(pub u32);
+
+/// RateLimitConfig sets per-endpoint limits for requests made from a single IP address, and for
+/// requests signed by a single account. Endpoints without a limit are not rate limited.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RateLimitConfig {
+    per_ip: HashMap<RateLimitedEndpoint, RequestsPerMinute>,
+    per_account: HashMap<RateLimitedEndpoint, RequestsPerMinute>,
+}
+
+impl RateLimitConfig {
+    /// constructs a configuration with no limits.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// per_ip limits the requests to the endpoint made from any one peer address.
+    pub fn per_ip(mut self, endpoint: RateLimitedEndpoint, limit: RequestsPerMinute) -> Self {
+        self.per_ip.insert(endpoint, limit);
+        self
+    }
+
+    /// per_account limits the requests to the endpoint signed by any one account, as identified
+    /// by the `kid` of the JWS. Requests which are not signed by an account, such as newNonce and
+    /// newAccount, are not subject to this limit.
+    pub fn per_account(mut self, endpoint: RateLimitedEndpoint, limit: RequestsPerMinute) -> Self {
+        self.per_account.insert(endpoint, limit);
+        self
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum BucketKey {
+    Peer(RateLimitedEndpoint, String),
+    Account(RateLimitedEndpoint, String),
+}
+
+#[derive(Clone, Debug)]
+struct Bucket {
+    tokens: f64,
+    capacity: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    // a bucket which has refilled is no different to a new one, so it may be evicted.
+    fn is_full(&self, now: Instant) -> bool {
+        self.tokens + now.duration_since(self.updated).as_secs_f64() * self.capacity / 60.0
+            >= self.capacity
+    }
+}
+
+// how often idle buckets are swept from the limiter.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct Buckets {
+    buckets: HashMap<BucketKey, Bucket>,
+    swept: Instant,
+}
+
+/// RateLimiter is an in-process token bucket limiter for the limits in a [RateLimitConfig].
+/// Clones share their buckets; the buckets are not shared between processes.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl RateLimiter {
+    /// constructs a limiter for the configuration.
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            buckets: Arc::new(Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                swept: Instant::now(),
+            })),
+        }
+    }
+
+    /// check counts a request to the endpoint against the peer address and account, where known
+    /// and limited. If either is over its limit, the duration until another request will be
+    /// accepted is returned and the request is not counted. Buckets which have refilled are
+    /// evicted from time to time, so addresses and accounts which stop making requests are
+    /// forgotten.
+    pub async fn check(
+        &self,
+        endpoint: RateLimitedEndpoint,
+        ip: Option<String>,
+        account: Option<String>,
+    ) -> Option<Duration> {
+        let mut keys = Vec::new();
+
+        if let (Some(ip), Some(limit)) = (ip, self.config.per_ip.get(&endpoint)) {
+            keys.push((BucketKey::Peer(endpoint, ip), *limit));
+        }
+
+        if let (Some(account), Some(limit)) = (account, self.config.per_account.get(&endpoint)) {
+            keys.push((BucketKey::Account(endpoint, account), *limit));
+        }
+
+        let now = Instant::now();
+        let mut state = self.buckets.lock().await;
+
+        if now.duration_since(state.swept) >= SWEEP_INTERVAL {
+            state.buckets.retain(|_, bucket| !bucket.is_full(now));
+            state.swept = now;
+        }
+
+        let buckets = &mut state.buckets;
+        let mut retry_after: Option<Duration> = None;
+
+        for (key, limit) in &keys {
+            let capacity = limit.0 as f64;
+            let rate = capacity / 60.0;
+
+            let bucket = buckets.entry(key.clone()).or_insert(Bucket {
+                tokens: capacity,
+                capacity,
+                updated: now,
+            });
+
+            bucket.tokens = (bucket.tokens
+                + now.duration_since(bucket.updated).as_secs_f64() * rate)
+                .min(capacity);
+            bucket.updated = now;
+
+            if bucket.tokens < 1.0 {
+                let wait = if rate > 0.0 {
+                    Duration::from_secs(((1.0 - bucket.tokens) / rate).ceil().max(1.0) as u64)
+                } else {
+                    Duration::from_secs(60)
+                };
+
+                retry_after = Some(retry_after.map_or(wait, |r| r.max(wait)));
+            }
+        }
+
+        if retry_after.is_none() {
+            for (key, _) in keys {
+                if let Some(bucket) = buckets.get_mut(&key) {
+                    bucket.tokens -= 1.0;
+                }
+            }
+        }
+
+        retry_after
+    }
+
+    #[cfg(test)]
+    async fn len(&self) -> usize {
+        self.buckets.lock().await.buckets.len()
+    }
+
+    #[cfg(test)]
+    async fn age(&self, by: Duration) {
+        let mut state = self.buckets.lock().await;
+        state.swept -= by;
+
+        for bucket in state.buckets.values_mut() {
+            bucket.updated -= by;
+        }
+    }
+}
+
+mod tests {
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rate_limiter() {
+        use super::{RateLimitConfig, RateLimitedEndpoint, RateLimiter, RequestsPerMinute};
+        use spectral::prelude::*;
+
+        let limiter = RateLimiter::new(
+            RateLimitConfig::new()
+                .per_ip(RateLimitedEndpoint::NewNonce, RequestsPerMinute(3))
+                .per_account(RateLimitedEndpoint::NewOrder, RequestsPerMinute(1)),
+        );
+
+        let ip = Some("127.0.0.1".to_string());
+
+        for _ in 0..3 {
+            assert_that!(
+                limiter
+                    .check(RateLimitedEndpoint::NewNonce, ip.clone(), None)
+                    .await
+            )
+            .is_none();
+        }
+
+        let retry_after = limiter
+            .check(RateLimitedEndpoint::NewNonce, ip.clone(), None)
+            .await;
+        assert_that!(retry_after).is_some();
+        assert_that!(retry_after.unwrap().as_secs()).is_greater_than_or_equal_to(1);
+
+        // other addresses and endpoints have their own buckets
+        assert_that!(
+            limiter
+                .check(
+                    RateLimitedEndpoint::NewNonce,
+                    Some("127.0.0.2".to_string()),
+                    None
+                )
+                .await
+        )
+        .is_none();
+        assert_that!(
+            limiter
+                .check(RateLimitedEndpoint::NewAccount, ip.clone(), None)
+                .await
+        )
+        .is_none();
+
+        let account = Some("http://127.0.0.1:8000/account/abc".to_string());
+        assert_that!(
+            limiter
+                .check(RateLimitedEndpoint::NewOrder, ip.clone(), account.clone())
+                .await
+        )
+        .is_none();
+        assert_that!(
+            limiter
+                .check(RateLimitedEndpoint::NewOrder, ip.clone(), account.clone())
+                .await
+        )
+        .is_some();
+        assert_that!(
+            limiter
+                .check(RateLimitedEndpoint::NewOrder, ip.clone(), None)
+                .await
+        )
+        .is_none();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rate_limiter_eviction() {
+        use super::{RateLimitConfig, RateLimitedEndpoint, RateLimiter, RequestsPerMinute};
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        let limiter = RateLimiter::new(
+            RateLimitConfig::new().per_ip(RateLimitedEndpoint::NewNonce, RequestsPerMinute(60)),
+        );
+
+        for i in 0..10 {
+            assert_that!(
+                limiter
+                    .check(
+                        RateLimitedEndpoint::NewNonce,
+                        Some(format!("10.0.0.{}", i)),
+                        None
+                    )
+                    .await
+            )
+            .is_none();
+        }
+
+        assert_that!(limiter.len().await).is_equal_to(10);
+
+        // only the bucket still in use survives the sweep once the others have refilled.
+        limiter.age(Duration::from_secs(120)).await;
+        for _ in 0..60 {
+            limiter
+                .check(
+                    RateLimitedEndpoint::NewNonce,
+                    Some("10.0.0.0".to_string()),
+                    None,
+                )
+                .await;
+        }
+        assert_that!(limiter.len().await).is_equal_to(1);
+
+        limiter.age(Duration::from_secs(120)).await;
+        assert_that!(
+            limiter
+                .check(
+                    RateLimitedEndpoint::NewNonce,
+                    Some("10.0.0.1".to_string()),
+                    None
+                )
+                .await
+        )
+        .is_none();
+        assert_that!(limiter.len().await).is_equal_to(1);
+    }
+}
@@ -0,0 +1,451 @@
+// tls-alpn-01 is defined in RFC8737. The client answers a TLS connection negotiating the
+// `acme-tls/1` protocol with a self-signed certificate for the identifier, carrying the SHA-256
+// digest of the key authorization in the critical id-pe-acmeIdentifier extension.
+
+use std::{
+    io::ErrorKind,
+    net::{IpAddr, TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use openssl::{
+    ssl::{HandshakeError, SslConnector, SslMethod, SslVerifyMode},
+    x509::X509,
+};
+use x509_parser::prelude::*;
+
+use crate::errors::ChallengeError;
+
+/// the ALPN protocol identifier for tls-alpn-01, as it appears in the protocol list.
+pub const ACME_TLS_ALPN_PROTOCOL: &[u8] = b"acme-tls/1";
+
+/// id-pe-acmeIdentifier
+pub const OID_ACME_IDENTIFIER: &str = "1.3.6.1.5.5.7.1.31";
+
+const DIGEST_LENGTH: usize = 32;
+
+/// TlsAlpnConfig controls how the [crate::acme::challenge::Challenger] dials clients for
+/// tls-alpn-01 challenges.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsAlpnConfig {
+    /// the port to connect to; always 443 outside of tests.
+    pub port: u16,
+    /// applied to the connection attempt and to each read and write during the handshake.
+    pub timeout: Duration,
+}
+
+impl Default for TlsAlpnConfig {
+    fn default() -> Self {
+        Self {
+            port: 443,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl TlsAlpnConfig {
+    /// acme_identifier connects to `identifier`, negotiates `acme-tls/1` and returns the digest
+    /// from the acmeIdentifier extension of the presented certificate. The certificate must
+    /// name exactly `identifier` in its subjectAltName. Comparing the digest against the key
+    /// authorization is left to the caller.
+    pub async fn acme_identifier(&self, identifier: &str) -> Result<Vec<u8>, ChallengeError> {
+        let config = self.clone();
+        let identifier = identifier.to_string();
+
+        tokio::task::spawn_blocking(move || config.fetch_acme_identifier(&identifier))
+            .await
+            .map_err(|e| ChallengeError::Connect(e.to_string()))?
+    }
+
+    fn fetch_acme_identifier(&self, identifier: &str) -> Result<Vec<u8>, ChallengeError> {
+        // IP identifiers (RFC8738) are validated with a reverse-mapping name in SNI, which we
+        // do not support yet.
+        if identifier.parse::<IpAddr>().is_ok() {
+            return Err(ChallengeError::UnsupportedIdentifier(
+                identifier.to_string(),
+            ));
+        }
+
+        let addrs = (identifier, self.port)
+            .to_socket_addrs()
+            .map_err(|e| ChallengeError::Connect(e.to_string()))?;
+
+        let mut last_error = ChallengeError::Connect(format!("{} did not resolve", identifier));
+        let mut stream = None;
+
+        for addr in addrs {
+            match TcpStream::connect_timeout(&addr, self.timeout) {
+                Ok(s) => {
+                    stream = Some(s);
+                    break;
+                }
+                Err(e) if is_timeout(e.kind()) => last_error = ChallengeError::Timeout,
+                Err(e) => last_error = ChallengeError::Connect(e.to_string()),
+            }
+        }
+
+        let stream = match stream {
+            Some(stream) => stream,
+            None => return Err(last_error),
+        };
+
+        stream
+            .set_read_timeout(Some(self.timeout))
+            .map_err(|e| ChallengeError::Connect(e.to_string()))?;
+        stream
+            .set_write_timeout(Some(self.timeout))
+            .map_err(|e| ChallengeError::Connect(e.to_string()))?;
+
+        let mut builder = SslConnector::builder(SslMethod::tls())?;
+        // the certificate is self-signed by design; its contents are checked below.
+        builder.set_verify(SslVerifyMode::NONE);
+        builder.set_alpn_protos(&alpn_protos())?;
+        let connector = builder.build();
+
+        let ssl = connector
+            .configure()?
+            .verify_hostname(false)
+            .connect(identifier, stream)
+            .map_err(|e| match e {
+                HandshakeError::Failure(mid) | HandshakeError::WouldBlock(mid) => {
+                    match mid.error().io_error() {
+                        Some(io) if is_timeout(io.kind()) => ChallengeError::Timeout,
+                        _ => ChallengeError::Handshake(mid.error().to_string()),
+                    }
+                }
+                HandshakeError::SetupFailure(es) => ChallengeError::Handshake(es.to_string()),
+            })?;
+
+        if ssl.ssl().selected_alpn_protocol() != Some(ACME_TLS_ALPN_PROTOCOL) {
+            return Err(ChallengeError::AlpnNotNegotiated);
+        }
+
+        let cert = ssl.ssl().peer_certificate().ok_or_else(|| {
+            ChallengeError::InvalidCertificate("no certificate was presented".to_string())
+        })?;
+
+        acme_identifier_from_certificate(&cert, identifier)
+    }
+}
+
+/// the ALPN wire format: each protocol prefixed by its length.
+fn alpn_protos() -> Vec<u8> {
+    let mut protos = vec![ACME_TLS_ALPN_PROTOCOL.len() as u8];
+    protos.extend_from_slice(ACME_TLS_ALPN_PROTOCOL);
+    protos
+}
+
+fn is_timeout(kind: ErrorKind) -> bool {
+    kind == ErrorKind::TimedOut || kind == ErrorKind::WouldBlock
+}
+
+/// acme_identifier_from_certificate checks the certificate presented for a tls-alpn-01
+/// challenge and returns the digest carried in its acmeIdentifier extension.
+fn acme_identifier_from_certificate(
+    cert: &X509,
+    identifier: &str,
+) -> Result<Vec<u8>, ChallengeError> {
+    let der = cert.to_der()?;
+    let (_, cert) = X509Certificate::from_der(&der)
+        .map_err(|e| ChallengeError::InvalidCertificate(e.to_string()))?;
+
+    let names = match cert.tbs_certificate.subject_alternative_name() {
+        Some((_, san)) => san.general_names.as_slice(),
+        None => &[],
+    };
+
+    match names {
+        [GeneralName::DNSName(name)] if name.eq_ignore_ascii_case(identifier) => {}
+        _ => {
+            return Err(ChallengeError::InvalidCertificate(format!(
+                "subjectAltName must contain only {}, found {:?}",
+                identifier, names
+            )))
+        }
+    }
+
+    let ext = cert
+        .tbs_certificate
+        .extensions()
+        .iter()
+        .find(|ext| ext.oid.to_id_string() == OID_ACME_IDENTIFIER)
+        .ok_or(ChallengeError::MissingAcmeIdentifier)?;
+
+    if !ext.critical {
+        return Err(ChallengeError::InvalidCertificate(
+            "acmeIdentifier extension must be critical".to_string(),
+        ));
+    }
+
+    // the extension value is an OCTET STRING holding the digest.
+    match ext.value {
+        [0x04, len, digest @ ..]
+            if *len as usize == DIGEST_LENGTH && digest.len() == DIGEST_LENGTH =>
+        {
+            Ok(digest.to_vec())
+        }
+        _ => Err(ChallengeError::InvalidCertificate(
+            "malformed acmeIdentifier extension".to_string(),
+        )),
+    }
+}
+
+mod tests {
+    #[cfg(test)]
+    fn self_signed_cert(
+        san: &str,
+        acme_identifier: Option<&[u8]>,
+    ) -> (
+        openssl::x509::X509,
+        openssl::pkey::PKey<openssl::pkey::Private>,
+    ) {
+        use openssl::{
+            asn1::{Asn1Object, Asn1OctetString, Asn1Time},
+            bn::BigNum,
+            ec::{EcGroup, EcKey},
+            hash::MessageDigest,
+            nid::Nid,
+            pkey::PKey,
+            x509::{X509Extension, X509Name, X509},
+        };
+
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_text("CN", "acme-tls-alpn").unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder
+            .set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder
+            .append_extension(X509Extension::new(None, None, "subjectAltName", san).unwrap())
+            .unwrap();
+
+        if let Some(digest) = acme_identifier {
+            let mut value = vec![0x04, digest.len() as u8];
+            value.extend_from_slice(digest);
+
+            builder
+                .append_extension(
+                    X509Extension::new_from_der(
+                        &Asn1Object::from_str(super::OID_ACME_IDENTIFIER).unwrap(),
+                        true,
+                        &Asn1OctetString::new_from_bytes(&value).unwrap(),
+                    )
+                    .unwrap(),
+                )
+                .unwrap();
+        }
+
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        (builder.build(), key)
+    }
+
+    /// serve accepts a single connection on a random port, answering with the certificate and
+    /// selecting acme-tls/1 if `alpn` is set.
+    #[cfg(test)]
+    fn serve(
+        cert: openssl::x509::X509,
+        key: openssl::pkey::PKey<openssl::pkey::Private>,
+        alpn: bool,
+    ) -> u16 {
+        use openssl::ssl::{select_next_proto, AlpnError, SslAcceptor, SslMethod};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor.set_certificate(&cert).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        if alpn {
+            acceptor.set_alpn_select_callback(|_, client| {
+                select_next_proto(&super::alpn_protos(), client).ok_or(AlpnError::ALERT_FATAL)
+            });
+        }
+        let acceptor = acceptor.build();
+
+        std::thread::spawn(move || {
+            if let Ok((stream, _)) = listener.accept() {
+                let _ = acceptor.accept(stream);
+            }
+        });
+
+        port
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_acme_identifier() {
+        use super::TlsAlpnConfig;
+        use crate::errors::ChallengeError;
+        use openssl::sha::sha256;
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        let digest = sha256(b"token.thumbprint");
+
+        let (cert, key) = self_signed_cert("DNS:localhost", Some(&digest[..]));
+        let config = TlsAlpnConfig {
+            port: serve(cert, key, true),
+            timeout: Duration::from_secs(5),
+        };
+        assert_that!(config.acme_identifier("localhost").await.unwrap())
+            .is_equal_to(digest.to_vec());
+
+        // the client must negotiate acme-tls/1
+        let (cert, key) = self_signed_cert("DNS:localhost", Some(&digest[..]));
+        let config = TlsAlpnConfig {
+            port: serve(cert, key, false),
+            timeout: Duration::from_secs(5),
+        };
+        assert_that!(config.acme_identifier("localhost").await.err())
+            .is_equal_to(Some(ChallengeError::AlpnNotNegotiated));
+
+        let (cert, key) = self_signed_cert("DNS:localhost", None);
+        let config = TlsAlpnConfig {
+            port: serve(cert, key, true),
+            timeout: Duration::from_secs(5),
+        };
+        assert_that!(config.acme_identifier("localhost").await.err())
+            .is_equal_to(Some(ChallengeError::MissingAcmeIdentifier));
+
+        // an IP SAN alongside the name is not allowed
+        let (cert, key) = self_signed_cert("DNS:localhost,IP:127.0.0.1", Some(&digest[..]));
+        let config = TlsAlpnConfig {
+            port: serve(cert, key, true),
+            timeout: Duration::from_secs(5),
+        };
+        assert_that!(matches!(
+            config.acme_identifier("localhost").await,
+            Err(ChallengeError::InvalidCertificate(_))
+        ))
+        .is_true();
+
+        assert_that!(config.acme_identifier("127.0.0.1").await.err()).is_equal_to(Some(
+            ChallengeError::UnsupportedIdentifier("127.0.0.1".to_string()),
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_challenger_key_authorization() {
+        use super::TlsAlpnConfig;
+        use crate::acme::{
+            challenge::{ChallengeEvidence, ChallengeType, Challenger},
+            handlers::order::OrderStatus,
+        };
+        use crate::models::order::Challenge;
+        use openssl::sha::sha256;
+        use spectral::prelude::*;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        for (key_authorization, passes) in vec![("token.thumbprint", true), ("token.other", false)]
+        {
+            let digest = sha256(b"token.thumbprint");
+            let (cert, key) = self_signed_cert("DNS:localhost", Some(&digest[..]));
+            let c = Challenger::new(None).with_tls_alpn(TlsAlpnConfig {
+                port: serve(cert, key, true),
+                timeout: Duration::from_secs(5),
+            });
+
+            let challenge = Challenge::new(
+                "order".to_string(),
+                "authz".to_string(),
+                ChallengeType::TLSALPN01,
+                "localhost".to_string(),
+                "127.0.0.1".to_string(),
+                OrderStatus::Processing,
+            );
+            c.schedule_with_key_authorization(challenge, key_authorization.to_string())
+                .await;
+
+            // the ticker is only asked about a digest which matched.
+            let asked = Arc::new(Mutex::new(Vec::new()));
+            let seen = asked.clone();
+            c.tick(move |_, evidence| {
+                seen.lock().unwrap().push(evidence);
+                Some(())
+            })
+            .await;
+
+            let asked = asked.lock().unwrap().clone();
+            let counts = c.status_counts().await;
+            if passes {
+                assert_that!(asked)
+                    .is_equal_to(vec![ChallengeEvidence::ACMEIdentifier(digest.to_vec())]);
+                assert_that!(counts.get("valid")).is_equal_to(Some(&1));
+            } else {
+                assert_that!(asked).is_empty();
+                assert_that!(counts.get("valid")).is_none();
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_acme_identifier_failures() {
+        use super::TlsAlpnConfig;
+        use crate::errors::ChallengeError;
+        use spectral::prelude::*;
+        use std::io::Write;
+        use std::net::TcpListener;
+        use std::time::Duration;
+
+        // accepts, but never speaks TLS
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = TlsAlpnConfig {
+            port: silent.local_addr().unwrap().port(),
+            timeout: Duration::from_millis(500),
+        };
+        assert_that!(config.acme_identifier("localhost").await.err())
+            .is_equal_to(Some(ChallengeError::Timeout));
+
+        // answers the handshake with garbage
+        let garbage = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = garbage.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            if let Ok((mut stream, _)) = garbage.accept() {
+                let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n");
+            }
+        });
+        let config = TlsAlpnConfig {
+            port,
+            timeout: Duration::from_secs(5),
+        };
+        assert_that!(matches!(
+            config.acme_identifier("localhost").await,
+            Err(ChallengeError::Handshake(_))
+        ))
+        .is_true();
+
+        // nothing listening
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config = TlsAlpnConfig {
+            port,
+            timeout: Duration::from_secs(5),
+        };
+        assert_that!(matches!(
+            config.acme_identifier("localhost").await,
+            Err(ChallengeError::Connect(_))
+        ))
+        .is_true();
+
+        drop(silent);
+    }
+}
@@ -0,0 +1,146 @@
+use openssl::error::ErrorStack;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// CsrError is returned when a certificate signing request cannot be turned into a certificate,
+/// either because it violates the CA's policy or because of an internal error while signing.
+#[derive(Clone, Error, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CsrError {
+    #[error("openssl error: {0}")]
+    OpenSSL(String),
+    #[error("could not parse CSR: {0}")]
+    Parse(String),
+    #[error("CSR signature does not match its public key")]
+    InvalidSignature,
+    #[error("CSR requests extended key usage not suitable for a TLS server certificate")]
+    ProhibitedExtendedKeyUsage,
+    #[error("weak {algorithm} public key: {reason}")]
+    WeakPublicKey { algorithm: String, reason: String },
+    #[error("CSR requests an IP address of {0} bytes")]
+    InvalidIPAddress(usize),
+    #[error("CSR common name exceeds 64 characters")]
+    CnTooLong,
+    #[error("invalid certificate policy OID: {0}")]
+    InvalidCertificatePolicyOid(String),
+    #[error("requested validity of {requested}s exceeds the maximum of {max}s")]
+    ValidityTooLong { requested: u64, max: u64 },
+}
+
+impl From<ErrorStack> for CsrError {
+    fn from(es: ErrorStack) -> Self {
+        let errors = es
+            .errors()
+            .into_iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<String>>();
+        Self::OpenSSL(errors.join("\n"))
+    }
+}
+
+/// OcspError is returned when an OCSP request cannot be parsed or answered.
+#[derive(Clone, Error, Debug, PartialEq)]
+pub enum OcspError {
+    #[error("openssl error: {0}")]
+    OpenSSL(String),
+    #[error("malformed OCSP request: {0}")]
+    Malformed(String),
+    #[error("the CA key type cannot sign OCSP responses")]
+    UnsupportedKey,
+}
+
+impl From<ErrorStack> for OcspError {
+    fn from(es: ErrorStack) -> Self {
+        let errors = es
+            .errors()
+            .into_iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<String>>();
+        Self::OpenSSL(errors.join("\n"))
+    }
+}
+
+/// CrlError is returned when a certificate revocation list cannot be generated.
+#[derive(Clone, Error, Debug, PartialEq)]
+pub enum CrlError {
+    #[error("openssl error: {0}")]
+    OpenSSL(String),
+    #[error("could not load revocations: {0}")]
+    Load(String),
+    #[error("the CA key type cannot sign CRLs")]
+    UnsupportedKey,
+}
+
+impl From<ErrorStack> for CrlError {
+    fn from(es: ErrorStack) -> Self {
+        let errors = es
+            .errors()
+            .into_iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<String>>();
+        Self::OpenSSL(errors.join("\n"))
+    }
+}
+
+/// ChainError is returned when an issued certificate does not verify against the CA chain it
+/// would be served with; see [crate::acme::ca::CA::verify_chain].
+#[derive(Clone, Error, Debug, PartialEq)]
+pub enum ChainError {
+    #[error("openssl error: {0}")]
+    OpenSSL(String),
+    #[error("the CA chain is empty")]
+    Empty,
+    #[error("certificate chain does not verify: {0}")]
+    Invalid(String),
+}
+
+impl From<ErrorStack> for ChainError {
+    fn from(es: ErrorStack) -> Self {
+        let errors = es
+            .errors()
+            .into_iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<String>>();
+        Self::OpenSSL(errors.join("\n"))
+    }
+}
+
+/// CaLoadError is returned when a CA cannot be loaded from existing key material.
+#[derive(Clone, Error, Debug, PartialEq)]
+pub enum CaLoadError {
+    #[error("openssl error: {0}")]
+    OpenSSL(String),
+    #[error("the private key does not belong to the CA certificate")]
+    KeyMismatch,
+}
+
+impl From<ErrorStack> for CaLoadError {
+    fn from(es: ErrorStack) -> Self {
+        let errors = es
+            .errors()
+            .into_iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<String>>();
+        Self::OpenSSL(errors.join("\n"))
+    }
+}
+
+/// Pkcs12Error is returned when a certificate and its key cannot be exported as PKCS#12; see
+/// [crate::acme::ca::CA::export_pkcs12].
+#[derive(Clone, Error, Debug, PartialEq)]
+pub enum Pkcs12Error {
+    #[error("openssl error: {0}")]
+    OpenSSL(String),
+    #[error("the passphrase is not valid UTF-8")]
+    InvalidPassphrase,
+}
+
+impl From<ErrorStack> for Pkcs12Error {
+    fn from(es: ErrorStack) -> Self {
+        let errors = es
+            .errors()
+            .into_iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<String>>();
+        Self::OpenSSL(errors.join("\n"))
+    }
+}
@@ -1,5 +1,34 @@
 use deadpool_postgres::PoolError;
 use thiserror::Error;
+use tokio_postgres::error::SqlState;
+
+/// Transient is implemented by errors which may not recur if the operation is tried again, such
+/// as those seen while the database restarts; see [crate::models::with_retry].
+pub trait Transient {
+    fn is_transient(&self) -> bool;
+}
+
+/// is_transient_db_error is true for errors of the connection rather than of the statement: the
+/// connection being closed or refused, SQLSTATE class 08, or the server shutting down or not yet
+/// accepting connections.
+fn is_transient_db_error(e: &tokio_postgres::Error) -> bool {
+    if e.is_closed() {
+        return true;
+    }
+
+    match e.code() {
+        Some(code) => {
+            code.code().starts_with("08")
+                || [
+                    SqlState::ADMIN_SHUTDOWN,
+                    SqlState::CRASH_SHUTDOWN,
+                    SqlState::CANNOT_CONNECT_NOW,
+                ]
+                .contains(code)
+        }
+        None => std::error::Error::source(e).map_or(false, |source| source.is::<std::io::Error>()),
+    }
+}
 
 /// ConnectionError is for database connection issues
 #[derive(Debug, Error)]
@@ -12,6 +41,25 @@ pub enum ConnectionError {
     Pool(PoolError),
     #[error("Migration run error: {0}")]
     Migrations(MigrationError),
+    #[error("Timed out after {0:?}")]
+    Timeout(std::time::Duration),
+    #[error("TLS configuration error: {0}")]
+    Tls(openssl::error::ErrorStack),
+}
+
+impl Transient for ConnectionError {
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::DB(e) | Self::Pool(PoolError::Backend(e)) => is_transient_db_error(e),
+            _ => false,
+        }
+    }
+}
+
+impl From<openssl::error::ErrorStack> for ConnectionError {
+    fn from(es: openssl::error::ErrorStack) -> Self {
+        Self::Tls(es)
+    }
 }
 
 impl From<tokio_postgres::Error> for ConnectionError {
@@ -45,6 +93,18 @@ pub enum SaveError {
     ReloadError(LoadError),
     #[error("db connection error: {0}")]
     ConnectionError(ConnectionError),
+    #[error("record was modified concurrently")]
+    ConcurrentModification,
+}
+
+impl Transient for SaveError {
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::DBError(e) => is_transient_db_error(e),
+            Self::ConnectionError(e) => e.is_transient(),
+            _ => false,
+        }
+    }
 }
 
 impl From<ConnectionError> for SaveError {
@@ -86,6 +146,18 @@ pub enum LoadError {
     InvalidEnum,
     #[error("key not found")]
     NotFound,
+    #[error("permission denied: {0}")]
+    Permissions(String),
+}
+
+impl Transient for LoadError {
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::DBError(e) => is_transient_db_error(e),
+            Self::ConnectionError(e) => e.is_transient(),
+            _ => false,
+        }
+    }
 }
 
 impl From<ConnectionError> for LoadError {
@@ -115,6 +187,20 @@ pub enum MigrationError {
     DBError(tokio_postgres::Error),
     #[error("migration error: {0}")]
     Error(refinery::Error),
+    /// another migrator brought the schema up to date while we were migrating it; nothing is
+    /// left to do.
+    #[error("database schema is already current")]
+    AlreadyCurrent,
+    /// some migrations were applied before one failed, so the schema is at neither the old nor
+    /// the new version.
+    #[error("migration {failed} failed after applying {applied:?}: {source}")]
+    PartialMigration {
+        applied: Vec<String>,
+        failed: String,
+        source: refinery::Error,
+    },
+    #[error("could not connect to migrate: {0}")]
+    ConnectionFailed(Box<ConnectionError>),
 }
 
 impl From<tokio_postgres::Error> for MigrationError {
@@ -131,6 +217,6 @@ impl From<refinery::Error> for MigrationError {
 
 impl From<ConnectionError> for MigrationError {
     fn from(e: ConnectionError) -> Self {
-        Self::Generic(e.to_string())
+        Self::ConnectionFailed(Box::new(e))
     }
 }
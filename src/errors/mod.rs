@@ -7,6 +7,8 @@ use self::db::LoadError;
 
 /// Mostly JWS-related errors
 pub mod acme;
+/// CA and CSR-related errors
+pub mod ca;
 /// DB/model-related errors
 pub mod db;
 
@@ -40,6 +42,58 @@ impl From<HandlerError> for Error {
     }
 }
 
+/// ConfigError is for problems with the service's configuration, or requests which do not agree
+/// with it.
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum ConfigError {
+    #[error("invalid host: {0}")]
+    InvalidHost(String),
+    #[error("host {0} is not in the list of allowed hostnames")]
+    HostNotAllowed(String),
+    #[error("invalid TLS configuration: {0}")]
+    InvalidTls(String),
+}
+
+/// ResolverError is returned by [crate::acme::challenge::DnsResolver] implementations when a
+/// lookup could not be completed. A name with no records is not an error.
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum ResolverError {
+    #[error("DNS lookup for {0} failed: {1}")]
+    Lookup(String, String),
+}
+
+/// ChallengeError describes why a challenge could not be checked, as opposed to a check which
+/// ran and failed.
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum ChallengeError {
+    #[error("identifier {0} is not supported for this challenge type")]
+    UnsupportedIdentifier(String),
+    #[error("could not connect: {0}")]
+    Connect(String),
+    #[error("timed out")]
+    Timeout,
+    #[error("TLS handshake failed: {0}")]
+    Handshake(String),
+    #[error("acme-tls/1 was not negotiated")]
+    AlpnNotNegotiated,
+    #[error("invalid challenge certificate: {0}")]
+    InvalidCertificate(String),
+    #[error("challenge certificate has no acmeIdentifier extension")]
+    MissingAcmeIdentifier,
+    #[error("unexpected HTTP status {0}")]
+    HttpStatus(u16),
+    #[error("response body is larger than {0} bytes")]
+    BodyTooLarge(usize),
+    #[error("redirect refused: {0}")]
+    Redirect(String),
+}
+
+impl From<openssl::error::ErrorStack> for ChallengeError {
+    fn from(es: openssl::error::ErrorStack) -> Self {
+        Self::Handshake(es.to_string())
+    }
+}
+
 /// ACMEValidationError is a series of semi-internal errors used to describe problems with
 /// validating the ACME exchange
 #[derive(Error, Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -59,6 +113,9 @@ pub enum ACMEValidationError {
     #[error("could not validate nonce")]
     NonceNotFound,
 
+    #[error("nonce has expired")]
+    NonceExpired,
+
     #[error("Nonce fetching error: {0}")]
     NonceFetchError(String),
 
@@ -73,15 +130,65 @@ pub enum ACMEValidationError {
 
     #[error("account does not exist")]
     AccountDoesNotExist,
+
+    #[error("account has been deactivated")]
+    AccountDeactivated,
+
+    #[error("external account binding rejected: {0}")]
+    ExternalAccountBinding(String),
+
+    #[error("certificate has already been revoked")]
+    AlreadyRevoked,
+
+    #[error("revocation reason {0} is not supported")]
+    BadRevocationReason(i32),
+
+    #[error("not authorized to revoke this certificate")]
+    RevocationNotAuthorized,
+
+    #[error("not authorized to respond to this challenge")]
+    ChallengeNotAuthorized,
+
+    #[error("bad CSR: {0}")]
+    BadCSR(String),
+
+    #[error("key change rejected: {0}")]
+    KeyChange(String),
+
+    #[error("order is not ready: {0}")]
+    OrderNotReady(String),
+
+    #[error("certificates are not issued for {0}")]
+    RejectedIdentifier(String),
+
+    #[error("order has no identifiers")]
+    NoIdentifiers,
+
+    #[error("invalid identifier: {0}")]
+    InvalidIdentifier(String),
+}
+
+impl Error {
+    /// with_status answers with `status` and the problem document as the body (RFC8555 6.7).
+    fn with_status(&self, status: StatusCode) -> ratpack::Error {
+        ratpack::Error::StatusCode(
+            status,
+            serde_json::to_string(self).unwrap_or_else(|_| self.detail.clone()),
+        )
+    }
 }
 
 impl ratpack::ToStatus for Error {
     fn to_status(&self) -> ratpack::Error {
         match self.error_type {
-            RFCError::BadNonce | RFCError::BadPublicKey | RFCError::BadSignatureAlgorithm => {
-                ratpack::Error::StatusCode(StatusCode::BAD_REQUEST, self.detail.clone())
-            }
-            _ => ratpack::Error::StatusCode(StatusCode::FORBIDDEN, self.detail.clone()),
+            RFCError::BadNonce
+            | RFCError::BadPublicKey
+            | RFCError::BadSignatureAlgorithm
+            | RFCError::AlreadyRevoked
+            | RFCError::BadRevocationReason
+            | RFCError::BadCSR
+            | RFCError::Malformed => self.with_status(StatusCode::BAD_REQUEST),
+            _ => self.with_status(StatusCode::FORBIDDEN),
         }
     }
 }
@@ -89,6 +196,12 @@ impl ratpack::ToStatus for Error {
 impl ratpack::ToStatus for ACMEValidationError {
     fn to_status(&self) -> ratpack::Error {
         let e: Error = self.clone().into();
+
+        // RFC8555 7.3.6: requests signed by a deactivated account are refused as unauthorized.
+        if let ACMEValidationError::AccountDeactivated = self {
+            return e.with_status(StatusCode::UNAUTHORIZED);
+        }
+
         e.to_status()
     }
 }
@@ -98,14 +211,21 @@ impl From<ACMEValidationError> for Error {
         match ave.clone() {
             ACMEValidationError::NoKeyProvided
             | ACMEValidationError::NonceDecodeError
-            | ACMEValidationError::InvalidRequest => {
+            | ACMEValidationError::InvalidRequest
+            | ACMEValidationError::KeyChange(_)
+            | ACMEValidationError::NoIdentifiers
+            | ACMEValidationError::InvalidIdentifier(_) => {
                 Self::new(RFCError::Malformed, &ave.to_string())
             }
             ACMEValidationError::Other(_)
             | ACMEValidationError::NonceNotFound
             | ACMEValidationError::NonceFetchError(_)
             | ACMEValidationError::URLNotEqual(_, _)
-            | ACMEValidationError::InvalidSignature => {
+            | ACMEValidationError::InvalidSignature
+            | ACMEValidationError::ExternalAccountBinding(_)
+            | ACMEValidationError::RevocationNotAuthorized
+            | ACMEValidationError::ChallengeNotAuthorized
+            | ACMEValidationError::AccountDeactivated => {
                 Self::new(RFCError::Unauthorized, &ave.to_string())
             }
             ACMEValidationError::AlgNotEqual(_, _) => {
@@ -114,6 +234,20 @@ impl From<ACMEValidationError> for Error {
             ACMEValidationError::AccountDoesNotExist => {
                 Self::new(RFCError::AccountDoesNotExist, &ave.to_string())
             }
+            ACMEValidationError::AlreadyRevoked => {
+                Self::new(RFCError::AlreadyRevoked, &ave.to_string())
+            }
+            ACMEValidationError::BadRevocationReason(_) => {
+                Self::new(RFCError::BadRevocationReason, &ave.to_string())
+            }
+            ACMEValidationError::BadCSR(_) => Self::new(RFCError::BadCSR, &ave.to_string()),
+            ACMEValidationError::OrderNotReady(_) => {
+                Self::new(RFCError::OrderNotReady, &ave.to_string())
+            }
+            ACMEValidationError::RejectedIdentifier(_) => {
+                Self::new(RFCError::RejectedIdentifier, &ave.to_string())
+            }
+            ACMEValidationError::NonceExpired => Self::new(RFCError::BadNonce, &ave.to_string()),
         }
     }
 }
@@ -349,6 +483,11 @@ impl Error {
                         return Err(ValidationError::InvalidIdentifier);
                     }
                 }
+                ACMEIdentifier::IP(addr) => {
+                    if addr.is_unspecified() || addr.is_multicast() {
+                        return Err(ValidationError::InvalidIdentifier);
+                    }
+                }
             }
         }
 
@@ -433,6 +572,26 @@ mod tests {
             .is_err_containing(ValidationError::InvalidIdentifier);
         }
 
+        for (addr, valid) in [
+            ("127.0.0.1", true),
+            ("2001:db8::1", true),
+            ("0.0.0.0", false),
+            ("::", false),
+            ("ff02::1", false),
+        ] {
+            let res = Error::new(RFCError::AccountDoesNotExist, "these are the details")
+                .identifier(ACMEIdentifier::IP(addr.parse().unwrap()))
+                .validate();
+
+            if valid {
+                assert_that!(res).named(addr).is_ok();
+            } else {
+                assert_that!(res)
+                    .named(addr)
+                    .is_err_containing(ValidationError::InvalidIdentifier);
+            }
+        }
+
         assert_that!(
             Error::new(RFCError::AccountDoesNotExist, "these are the details")
                 .subproblems(vec![Error::new(
@@ -11,7 +11,11 @@ use crate::{
     util::make_nonce,
 };
 
-use super::{LoadError, Postgres, Record, SaveError};
+use super::{
+    audit::{record_audit_event, AuditEvent},
+    eab::mark_eab_credential_used,
+    LoadError, Postgres, Record, SaveError, TenantId,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Account {
@@ -19,8 +23,10 @@ pub struct Account {
     jwk_id: i32,
     orders_nonce: String,
     contacts: Vec<String>,
+    terms_of_service_agreed: bool,
     created_at: chrono::DateTime<chrono::Local>,
     deleted_at: Option<chrono::DateTime<chrono::Local>>,
+    deactivated_at: Option<chrono::DateTime<chrono::Local>>,
 }
 
 pub(crate) fn new_accounts(
@@ -46,7 +52,8 @@ pub(crate) fn new_accounts(
             .iter()
             .map(|c| c.to_owned().into())
             .collect::<Vec<String>>(),
-    ))
+    )
+    .with_terms_of_service_agreed(account.terms_of_service_agreed.unwrap_or_default()))
 }
 
 pub async fn get_contacts_for_account(
@@ -71,18 +78,174 @@ impl Account {
             jwk_id,
             contacts,
             orders_nonce: make_nonce(super::NONCE_KEY_SIZE),
+            terms_of_service_agreed: false,
             id: None,
             created_at: chrono::DateTime::<chrono::Local>::from(std::time::SystemTime::now()),
             deleted_at: None,
+            deactivated_at: None,
+        }
+    }
+
+    /// with_terms_of_service_agreed records whether the client agreed to the terms of service.
+    pub fn with_terms_of_service_agreed(mut self, agreed: bool) -> Self {
+        self.terms_of_service_agreed = agreed;
+        self
+    }
+
+    /// contacts are the account's contact URLs, e.g. `mailto:` addresses.
+    pub fn contacts(&self) -> &[String] {
+        &self.contacts
+    }
+
+    pub fn terms_of_service_agreed(&self) -> bool {
+        self.terms_of_service_agreed
+    }
+
+    /// orders_nonce identifies the list of the account's orders (RFC8555 7.1.2.1).
+    pub fn orders_nonce(&self) -> &str {
+        &self.orders_nonce
+    }
+
+    /// deactivated reports whether the account was deactivated with [Account::deactivate].
+    pub fn deactivated(&self) -> bool {
+        self.deactivated_at.is_some()
+    }
+
+    /// deactivate deactivates the account (RFC8555 7.3.6), and removes its key so that it can no
+    /// longer sign requests. Deactivation is final; it fails for an account which already is.
+    /// `event` is recorded in the audit log along with the deactivation.
+    pub async fn deactivate(&mut self, db: Postgres, event: &AuditEvent) -> Result<(), SaveError> {
+        let id = match self.id {
+            Some(id) => id,
+            None => {
+                return Err(SaveError::Generic(
+                    "this account record was never saved".to_string(),
+                ))
+            }
+        };
+
+        let tenant = db.tenant().clone();
+        let mut db = db.client().await?;
+        let tx = db.transaction().await?;
+
+        let row = tx
+            .query_opt(
+                "
+                update accounts set deactivated_at=CURRENT_TIMESTAMP
+                where id=$1 and deactivated_at is null
+                returning deactivated_at
+                ",
+                &[&id],
+            )
+            .await?;
+
+        let row = match row {
+            Some(row) => row,
+            None => {
+                return Err(SaveError::Generic(
+                    "account is already deactivated".to_string(),
+                ))
+            }
+        };
+
+        tx.execute(
+            "update jwks set deleted_at=CURRENT_TIMESTAMP where id=$1 and deleted_at is null",
+            &[&self.jwk_id],
+        )
+        .await?;
+
+        record_audit_event(event, &tenant, &tx).await?;
+
+        tx.commit().await?;
+        self.deactivated_at = row.get("deactivated_at");
+
+        Ok(())
+    }
+
+    /// jwk loads the key the account signs its requests with.
+    pub(crate) async fn jwk(&self, db: Postgres) -> Result<JWK, LoadError> {
+        JWK::find(self.jwk_id, db).await
+    }
+
+    /// deactivated_for_kid reports whether `url`, an account URL as used in the kid of a JWS,
+    /// belongs to a deactivated account.
+    pub async fn deactivated_for_kid(url: &Url, db: Postgres) -> Result<bool, LoadError> {
+        let nonce_key = match url.path_segments().and_then(|mut s| s.next_back()) {
+            Some(nonce_key) => nonce_key.to_string(),
+            None => return Ok(false),
+        };
+
+        let row = db
+            .clone()
+            .client()
+            .await?
+            .query_opt(
+                "
+                select 1 from accounts a join jwks j on j.id = a.jwk_id
+                where j.nonce_key=$1 and a.tenant_id=$2 and a.deactivated_at is not null
+                ",
+                &[&nonce_key, &db.tenant().as_str()],
+            )
+            .await?;
+
+        Ok(row.is_some())
+    }
+
+    /// deactivated_for_key reports whether the public key of `jwk` belonged to an account which
+    /// has since been deactivated; see [JWK::same_key].
+    pub async fn deactivated_for_key(jwk: &JWK, db: Postgres) -> Result<bool, LoadError> {
+        let row = db
+            .clone()
+            .client()
+            .await?
+            .query_opt(
+                "
+                select 1 from accounts a join jwks j on j.id = a.jwk_id
+                where a.deactivated_at is not null
+                    and j.n is not distinct from $1
+                    and j.e is not distinct from $2
+                    and j.x is not distinct from $3
+                    and j.y is not distinct from $4
+                    and a.tenant_id = $5
+                limit 1
+                ",
+                &[&jwk.n, &jwk.e, &jwk.x, &jwk.y, &db.tenant().as_str()],
+            )
+            .await?;
+
+        Ok(row.is_some())
+    }
+
+    /// find_by_orders_nonce finds the account whose orders list is identified by `nonce`.
+    pub async fn find_by_orders_nonce(nonce: &str, db: Postgres) -> Result<Self, LoadError> {
+        let mut lockeddb = db.clone().client().await?;
+        let tx = lockeddb.transaction().await?;
+
+        let res = tx
+            .query_opt(
+                "
+                select * from accounts
+                where orders_nonce=$1 and tenant_id=$2 and deleted_at is null
+                ",
+                &[&nonce, &db.tenant().as_str()],
+            )
+            .await?;
+
+        match res {
+            Some(row) => Self::new_from_row(&row, &tx).await,
+            None => Err(LoadError::NotFound),
         }
     }
 
     pub async fn find_by_kid(jwk_id: i32, db: Postgres) -> Result<Self, LoadError> {
-        let mut lockeddb = db.client().await?;
+        let mut lockeddb = db.clone().client().await?;
         let tx = lockeddb.transaction().await?;
 
         let res = tx
-            .query_one("select * from accounts where jwk_id=$1", &[&jwk_id])
+            .query_one(
+                "select * from accounts where jwk_id=$1 and tenant_id=$2",
+                &[&jwk_id, &db.tenant().as_str()],
+            )
             .await?;
 
         Self::new_from_row(&res, &tx).await
@@ -98,6 +261,242 @@ impl Account {
 
         Self::new_from_row(&res, &tx).await
     }
+
+    /// insert is [Record::create] as a part of `tx`.
+    pub(crate) async fn insert(
+        &mut self,
+        tenant: &TenantId,
+        tx: &Transaction<'_>,
+    ) -> Result<i32, SaveError> {
+        let res = tx
+            .query_one(
+                "
+                    insert into accounts
+                        (jwk_id, orders_nonce, terms_of_service_agreed, tenant_id)
+                        values ($1, $2, $3, $4)
+                    returning id, created_at
+                ",
+                &[
+                    &self.jwk_id,
+                    &self.orders_nonce,
+                    &self.terms_of_service_agreed,
+                    &tenant.as_str(),
+                ],
+            )
+            .await?;
+
+        let id = res.get("id");
+        let created_at = res.get("created_at");
+
+        self.id = Some(id);
+        self.created_at = created_at;
+
+        for contact in &self.contacts {
+            tx.query_one(
+                "
+                        insert into contacts (account_id, contact) values ($1, $2)
+                        returning id, created_at
+                    ",
+                &[&id, &contact],
+            )
+            .await?;
+        }
+
+        Ok(id)
+    }
+}
+
+/// AccountSummary is an account as listed by [Postgres::list_accounts].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AccountSummary {
+    pub id: i32,
+    pub contacts: Vec<String>,
+    pub created_at: chrono::DateTime<chrono::Local>,
+    pub deactivated_at: Option<chrono::DateTime<chrono::Local>>,
+}
+
+/// KeyRollover records a single change of an account's key, identified by the thumbprints of the
+/// old and new keys.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct KeyRollover {
+    pub account_id: i32,
+    pub old_thumbprint: String,
+    pub new_thumbprint: String,
+    pub changed_at: chrono::DateTime<chrono::Local>,
+}
+
+impl Postgres {
+    /// create_account stores the key and the account registered with it, consuming the external
+    /// account binding credential `eab_kid` if one was used, and records `event`, about the new
+    /// account, in the audit log; all in one transaction. None is returned, and nothing is
+    /// written, if the credential is unknown or was already used.
+    pub async fn create_account(
+        &self,
+        jwk: &mut JWK,
+        newacct: NewAccount,
+        eab_kid: Option<&str>,
+        event: &AuditEvent,
+    ) -> Result<Option<Account>, SaveError> {
+        let mut client = self.clone().client().await?;
+        let tx = client.transaction().await?;
+
+        if let Some(kid) = eab_kid {
+            if !mark_eab_credential_used(kid, &tx).await? {
+                return Ok(None);
+            }
+        }
+
+        jwk.insert(self.tenant(), &tx).await?;
+
+        let mut account = new_accounts(newacct, jwk.clone(), self.clone())?;
+        account.insert(self.tenant(), &tx).await?;
+
+        record_audit_event(
+            &event.clone().with_account_id(account.id.unwrap()),
+            self.tenant(),
+            &tx,
+        )
+        .await?;
+
+        tx.commit().await?;
+        Ok(Some(account))
+    }
+
+    /// record_key_rollover appends a key change for the account to its key history.
+    pub async fn record_key_rollover(
+        &self,
+        account_id: i32,
+        old: &str,
+        new: &str,
+    ) -> Result<(), SaveError> {
+        let mut client = self.clone().client().await?;
+        let tx = client.transaction().await?;
+
+        tx.execute(
+            "insert into account_key_history (account_id, old_thumbprint, new_thumbprint) values ($1, $2, $3)",
+            &[&account_id, &old, &new],
+        )
+        .await?;
+
+        Ok(tx.commit().await?)
+    }
+
+    /// rollover_account_key replaces the public key of the account's JWK record with that of
+    /// `new`, keeping the record's key id so the account URL is unchanged, and appends the change
+    /// to the key history and `event` to the audit log. All happen in one transaction.
+    pub async fn rollover_account_key(
+        &self,
+        account_id: i32,
+        jwk_id: i32,
+        new: &JWK,
+        old_thumbprint: &str,
+        new_thumbprint: &str,
+        event: &AuditEvent,
+    ) -> Result<(), SaveError> {
+        let mut client = self.clone().client().await?;
+        let tx = client.transaction().await?;
+
+        let res = tx
+            .execute(
+                "
+                update jwks set alg=$1, n=$2, e=$3, x=$4, y=$5, kty=$6, crv=$7
+                where id=$8 and deleted_at is null
+                ",
+                &[
+                    &new.alg, &new.n, &new.e, &new.x, &new.y, &new.kty, &new.crv, &jwk_id,
+                ],
+            )
+            .await?;
+
+        if res == 0 {
+            return Err(SaveError::Generic(
+                "account key was removed during the key change".to_string(),
+            ));
+        }
+
+        tx.execute(
+            "insert into account_key_history (account_id, old_thumbprint, new_thumbprint) values ($1, $2, $3)",
+            &[&account_id, &old_thumbprint, &new_thumbprint],
+        )
+        .await?;
+
+        record_audit_event(event, self.tenant(), &tx).await?;
+
+        Ok(tx.commit().await?)
+    }
+
+    /// list_accounts returns a page of the tenant's accounts, oldest first, and whether there are
+    /// more after it. Pages are counted from zero; deleted accounts are not listed.
+    pub async fn list_accounts(
+        &self,
+        page: u32,
+        page_size: u32,
+    ) -> Result<(Vec<AccountSummary>, bool), LoadError> {
+        let db = self.read_client().await?;
+        let rows = db
+            .query(
+                "
+                select a.id, a.created_at, a.deactivated_at,
+                    coalesce(array_agg(c.contact order by c.id)
+                        filter (where c.id is not null), '{}') as contacts
+                from accounts a left join contacts c on c.account_id = a.id
+                where a.tenant_id = $1 and a.deleted_at is null
+                group by a.id
+                order by a.created_at asc, a.id asc
+                limit $2 offset $3
+                ",
+                &[
+                    &self.tenant().as_str(),
+                    // one more than fits the page, to tell whether there is a next one.
+                    &(page_size as i64 + 1),
+                    &(page as i64 * page_size as i64),
+                ],
+            )
+            .await?;
+
+        let more = rows.len() > page_size as usize;
+
+        Ok((
+            rows.iter()
+                .take(page_size as usize)
+                .map(|row| AccountSummary {
+                    id: row.get("id"),
+                    contacts: row.get("contacts"),
+                    created_at: row.get("created_at"),
+                    deactivated_at: row.get("deactivated_at"),
+                })
+                .collect(),
+            more,
+        ))
+    }
+
+    /// get_key_history returns the key changes for the account, oldest first.
+    pub async fn get_key_history(&self, account_id: i32) -> Result<Vec<KeyRollover>, LoadError> {
+        let mut client = self.read_client().await?;
+        let tx = client.transaction().await?;
+
+        let rows = tx
+            .query(
+                "
+                select account_id, old_thumbprint, new_thumbprint, changed_at
+                    from account_key_history
+                where account_id = $1
+                order by changed_at ASC, id ASC
+                ",
+                &[&account_id],
+            )
+            .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| KeyRollover {
+                account_id: row.get("account_id"),
+                old_thumbprint: row.get("old_thumbprint"),
+                new_thumbprint: row.get("new_thumbprint"),
+                changed_at: row.get("changed_at"),
+            })
+            .collect())
+    }
 }
 
 #[async_trait]
@@ -108,8 +507,10 @@ impl Record<i32> for Account {
             jwk_id: row.get("jwk_id"),
             orders_nonce: row.get("orders_nonce"),
             contacts: get_contacts_for_account(row.get("id"), tx).await?,
+            terms_of_service_agreed: row.get("terms_of_service_agreed"),
             created_at: row.get("created_at"),
             deleted_at: row.get("deleted_at"),
+            deactivated_at: row.get("deactivated_at"),
         })
     }
 
@@ -132,36 +533,11 @@ impl Record<i32> for Account {
     }
 
     async fn create(&mut self, db: Postgres) -> Result<i32, SaveError> {
+        let tenant = db.tenant().clone();
         let mut db = db.client().await?;
         let tx = db.transaction().await?;
 
-        let res = tx
-            .query_one(
-                "
-                    insert into accounts (jwk_id, orders_nonce) values ($1, $2)
-                    returning id, created_at
-                ",
-                &[&self.jwk_id, &self.orders_nonce],
-            )
-            .await?;
-
-        let id = res.get("id");
-        let created_at = res.get("created_at");
-
-        self.id = Some(id);
-        self.created_at = created_at;
-
-        for contact in &self.contacts {
-            tx.query_one(
-                "
-                        insert into contacts (account_id, contact) values ($1, $2)
-                        returning id, created_at
-                    ",
-                &[&id, &contact],
-            )
-            .await?;
-        }
-
+        let id = self.insert(&tenant, &tx).await?;
         tx.commit().await?;
 
         return Ok(id);
@@ -209,11 +585,50 @@ pub struct JWK {
     pub e: Option<String>,
     pub x: Option<String>,
     pub y: Option<String>,
+    /// the key type (RFC7517 4.1); None for keys stored before it was recorded.
+    pub kty: Option<String>,
+    /// the curve of EC and OKP keys (RFC7518 6.2.1.1, RFC8037 2).
+    pub crv: Option<String>,
     pub created_at: chrono::DateTime<chrono::Local>,
     pub deleted_at: Option<chrono::DateTime<chrono::Local>>,
 }
 
 impl JWK {
+    /// insert is [Record::create] as a part of `tx`.
+    pub(crate) async fn insert(
+        &mut self,
+        tenant: &TenantId,
+        tx: &Transaction<'_>,
+    ) -> Result<i32, SaveError> {
+        let res = tx
+            .query_one(
+                "
+        insert into jwks (nonce_key, n, e, alg, x, y, tenant_id, kty, crv)
+        values ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        returning id, created_at
+        ",
+                &[
+                    &self.nonce_key,
+                    &self.n,
+                    &self.e,
+                    &self.alg,
+                    &self.x,
+                    &self.y,
+                    &tenant.as_str(),
+                    &self.kty,
+                    &self.crv,
+                ],
+            )
+            .await?;
+
+        let id = res.get("id");
+        let created_at = res.get("created_at");
+        self.id = Some(id);
+        self.created_at = created_at;
+
+        Ok(id)
+    }
+
     pub fn new_rs256(n: String, e: String) -> Self {
         Self {
             id: None,
@@ -223,6 +638,8 @@ impl JWK {
             alg: "RS256".into(),
             x: None,
             y: None,
+            kty: Some("RSA".into()),
+            crv: None,
             created_at: chrono::DateTime::<chrono::Local>::from(std::time::SystemTime::now()),
             deleted_at: None,
         }
@@ -237,6 +654,8 @@ impl JWK {
             alg: "ES256".into(),
             e: None,
             n: None,
+            kty: Some("EC".into()),
+            crv: Some("P-256".into()),
             created_at: chrono::DateTime::<chrono::Local>::from(std::time::SystemTime::now()),
             deleted_at: None,
         }
@@ -259,8 +678,8 @@ impl JWK {
             .client()
             .await?
             .query_one(
-                "select id from jwks where nonce_key=$1 and deleted_at is null",
-                &[&nonce_key],
+                "select id from jwks where nonce_key=$1 and tenant_id=$2 and deleted_at is null",
+                &[&nonce_key, &db.tenant().as_str()],
             )
             .await;
 
@@ -289,6 +708,39 @@ impl JWK {
     pub fn nonce_key(&self) -> String {
         self.nonce_key.clone()
     }
+
+    /// same_key reports whether both records hold the same public key, regardless of their ids.
+    pub fn same_key(&self, other: &Self) -> bool {
+        self.n == other.n && self.e == other.e && self.x == other.x && self.y == other.y
+    }
+
+    /// find_by_public_key returns the live record holding the same public key as this one, if
+    /// any; see [JWK::same_key].
+    pub async fn find_by_public_key(&self, db: Postgres) -> Result<Option<Self>, LoadError> {
+        let mut client = db.clone().client().await?;
+        let tx = client.transaction().await?;
+
+        let row = tx
+            .query_opt(
+                "
+                select * from jwks
+                where deleted_at is null
+                    and n is not distinct from $1
+                    and e is not distinct from $2
+                    and x is not distinct from $3
+                    and y is not distinct from $4
+                    and tenant_id = $5
+                limit 1
+                ",
+                &[&self.n, &self.e, &self.x, &self.y, &db.tenant().as_str()],
+            )
+            .await?;
+
+        match row {
+            Some(row) => Ok(Some(Self::new_from_row(&row, &tx).await?)),
+            None => Ok(None),
+        }
+    }
 }
 
 impl TryFrom<&mut jose::JWK> for JWK {
@@ -309,6 +761,8 @@ impl TryFrom<&mut jose::JWK> for JWK {
             x,
             y,
             alg,
+            kty: Some(jwk.kty.clone()),
+            crv: jwk.crv.clone(),
             id: None,
             created_at: chrono::DateTime::<chrono::Local>::from(std::time::SystemTime::now()),
             deleted_at: None,
@@ -320,21 +774,28 @@ impl TryInto<jose::JWK> for JWK {
     type Error = JWSError;
 
     fn try_into(self) -> Result<jose::JWK, Self::Error> {
-        let mut crv = None;
-
-        if self.x.is_some() && self.y.is_some() {
-            // NOTE once more algos are supported this will need to change
-            crv = Some("P-256".to_string())
-        }
+        // keys stored before their type and curve were recorded have them derived from the
+        // algorithm.
+        let crv = self.crv.or_else(|| match self.alg.as_str() {
+            "ES256" if self.x.is_some() && self.y.is_some() => Some("P-256".to_string()),
+            "ES384" => Some("P-384".to_string()),
+            "EdDSA" => Some("Ed25519".to_string()),
+            _ => None,
+        });
 
-        Ok(jose::JWK {
-            _use: None,
-            kty: match self.alg.as_str() {
-                "ES256" => "ECDSA",
+        let kty = self.kty.unwrap_or_else(|| {
+            match self.alg.as_str() {
+                "ES256" | "ES384" => "ECDSA",
                 "RS256" => "RSA",
+                "EdDSA" => "OKP",
                 _ => "you should really be validating this field",
             }
-            .to_string(),
+            .to_string()
+        });
+
+        Ok(jose::JWK {
+            _use: None,
+            kty,
             crv,
             n: self.n,
             e: self.e,
@@ -356,6 +817,8 @@ impl Record<i32> for JWK {
             alg: row.get("alg"),
             x: row.get("x"),
             y: row.get("y"),
+            kty: row.get("kty"),
+            crv: row.get("crv"),
             created_at: row.get("created_at"),
             deleted_at: row.get("deleted_at"),
         })
@@ -380,33 +843,13 @@ impl Record<i32> for JWK {
     }
 
     async fn create(&mut self, db: Postgres) -> Result<i32, SaveError> {
+        let tenant = db.tenant().clone();
         let mut db = db.client().await?;
         let tx = db.transaction().await?;
 
-        let res = tx
-            .query_one(
-                "
-        insert into jwks (nonce_key, n, e, alg, x, y) values ($1, $2, $3, $4, $5, $6)
-        returning id, created_at
-        ",
-                &[
-                    &self.nonce_key,
-                    &self.n,
-                    &self.e,
-                    &self.alg,
-                    &self.x,
-                    &self.y,
-                ],
-            )
-            .await?;
-
+        let id = self.insert(&tenant, &tx).await?;
         tx.commit().await?;
 
-        let id = res.get("id");
-        let created_at = res.get("created_at");
-        self.id = Some(id);
-        self.created_at = created_at;
-
         return Ok(id);
     }
 
@@ -442,6 +885,123 @@ impl Record<i32> for JWK {
 }
 
 mod tests {
+    #[tokio::test(flavor = "multi_thread")]
+    async fn account_key_history() {
+        use spectral::prelude::*;
+
+        use crate::test::PGTest;
+
+        let pg = PGTest::new("account_key_history").await.unwrap();
+        let db = pg.db();
+
+        assert_that!(db.get_key_history(1).await.unwrap()).is_empty();
+
+        db.record_key_rollover(1, "first", "second").await.unwrap();
+        db.record_key_rollover(1, "second", "third").await.unwrap();
+        db.record_key_rollover(2, "other", "another").await.unwrap();
+
+        let history = db.get_key_history(1).await.unwrap();
+        assert_that!(history.len()).is_equal_to(2);
+
+        assert_that!(history[0].account_id).is_equal_to(1);
+        assert_that!(history[0].old_thumbprint.as_str()).is_equal_to("first");
+        assert_that!(history[0].new_thumbprint.as_str()).is_equal_to("second");
+        assert_that!(history[1].old_thumbprint.as_str()).is_equal_to("second");
+        assert_that!(history[1].new_thumbprint.as_str()).is_equal_to("third");
+        assert_that!(history[0].changed_at).is_less_than_or_equal_to(history[1].changed_at);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn account_key_rollover() {
+        use spectral::prelude::*;
+
+        use super::{Account, JWK};
+        use crate::models::audit::{AuditEvent, AuditEventType};
+        use crate::models::Record;
+        use crate::test::PGTest;
+
+        let pg = PGTest::new("account_key_rollover").await.unwrap();
+        let db = pg.db();
+
+        let mut old = JWK::new_es256("x".to_string(), "y".to_string());
+        old.create(db.clone()).await.unwrap();
+        let mut acct = Account::new(old.id.unwrap(), vec![]);
+        acct.create(db.clone()).await.unwrap();
+
+        let new = JWK::new_rs256("n".to_string(), "e".to_string());
+        assert_that!(new.find_by_public_key(db.clone()).await.unwrap()).is_none();
+        assert_that!(old.find_by_public_key(db.clone()).await.unwrap())
+            .is_equal_to(Some(JWK::find(old.id.unwrap(), db.clone()).await.unwrap()));
+
+        db.rollover_account_key(
+            acct.id.unwrap(),
+            old.id.unwrap(),
+            &new,
+            "old",
+            "new",
+            &AuditEvent::new(AuditEventType::AccountKeyChanged).with_account_id(acct.id.unwrap()),
+        )
+        .await
+        .unwrap();
+
+        // the key id, and with it the account URL, survives the change.
+        let rolled = JWK::find_by_nonce(old.nonce_key(), db.clone())
+            .await
+            .unwrap();
+        assert_that!(rolled.id).is_equal_to(old.id);
+        assert_that!(rolled.alg.as_str()).is_equal_to("RS256");
+        assert_that!(rolled.same_key(&new)).is_true();
+        assert_that!(rolled.same_key(&old)).is_false();
+
+        assert_that!(old.find_by_public_key(db.clone()).await.unwrap()).is_none();
+        assert_that!(new.find_by_public_key(db.clone()).await.unwrap()).is_equal_to(Some(rolled));
+
+        let history = db.get_key_history(acct.id.unwrap()).await.unwrap();
+        assert_that!(history.len()).is_equal_to(1);
+        assert_that!(history[0].old_thumbprint.as_str()).is_equal_to("old");
+        assert_that!(history[0].new_thumbprint.as_str()).is_equal_to("new");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn account_tenant_isolation() {
+        use spectral::prelude::*;
+
+        use super::{Account, JWK};
+        use crate::models::{Record, TenantId};
+        use crate::test::PGTest;
+
+        let pg = PGTest::new("account_tenant_isolation").await.unwrap();
+        let a = pg.db().with_tenant(TenantId("a".to_string()));
+        let b = pg.db().with_tenant(TenantId("b".to_string()));
+
+        let mut jwk = JWK::new_es256("x".to_string(), "y".to_string());
+        jwk.create(a.clone()).await.unwrap();
+        let mut acct = Account::new(jwk.id.unwrap(), vec![]);
+        acct.create(a.clone()).await.unwrap();
+
+        assert_that!(JWK::find_by_nonce(jwk.nonce_key(), a.clone()).await).is_ok();
+        assert_that!(Account::find_by_kid(jwk.id.unwrap(), a.clone()).await)
+            .is_ok_containing(acct.clone());
+        assert_that!(jwk.find_by_public_key(a.clone()).await.unwrap()).is_some();
+
+        // neither the other tenant nor the default one see it.
+        for db in vec![b.clone(), pg.db()] {
+            assert_that!(JWK::find_by_nonce(jwk.nonce_key(), db.clone()).await).is_err();
+            assert_that!(Account::find_by_kid(jwk.id.unwrap(), db.clone()).await).is_err();
+            assert_that!(jwk.find_by_public_key(db.clone()).await.unwrap()).is_none();
+        }
+
+        // the same key may hold an account in each tenant.
+        let mut other = JWK::new_es256("x".to_string(), "y".to_string());
+        other.create(b.clone()).await.unwrap();
+        assert_that!(jwk
+            .find_by_public_key(b.clone())
+            .await
+            .unwrap()
+            .map(|k| k.id))
+        .is_equal_to(Some(other.id));
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn account_crud_single_contact() {
         use spectral::prelude::*;
@@ -483,6 +1043,78 @@ mod tests {
         assert_that!(acct).is_equal_to(oldacct);
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn account_create_with_eab() {
+        use spectral::prelude::*;
+
+        use super::{Account, JWK};
+        use crate::acme::handlers::account::NewAccount;
+        use crate::models::audit::{AuditEvent, AuditEventType};
+        use crate::models::Record;
+        use crate::test::PGTest;
+        use std::convert::TryInto;
+
+        let pg = PGTest::new("account_create_with_eab").await.unwrap();
+        let db = pg.db();
+        let since = chrono::Utc::now() - chrono::Duration::minutes(1);
+        let event = AuditEvent::new(AuditEventType::AccountCreated);
+
+        db.create_eab_credential("kid-1", b"secret").await.unwrap();
+
+        let mut acct = NewAccount::default();
+        acct.contact = Some(vec!["mailto:erik@hollensbe.org".try_into().unwrap()]);
+
+        let mut jwk = JWK::new_es256("x".to_string(), "y".to_string());
+        let created = db
+            .create_account(&mut jwk, acct.clone(), Some("kid-1"), &event)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_that!(jwk.id).is_some();
+        assert_that!(Account::find(created.id.unwrap(), db.clone())
+            .await
+            .unwrap())
+        .is_equal_to(created);
+        assert_that!(db.find_eab_credential("kid-1").await.unwrap().unwrap().used).is_true();
+
+        // a used or unknown credential writes nothing at all.
+        for kid in vec!["kid-1", "kid-2"] {
+            let mut jwk = JWK::new_es256("x2".to_string(), "y2".to_string());
+            assert_that!(
+                db.create_account(&mut jwk, acct.clone(), Some(kid), &event)
+                    .await
+            )
+            .is_ok_containing(None);
+        }
+
+        let c = db.clone().client().await.unwrap();
+        let jwks: i64 = c
+            .query_one("select count(*) from jwks", &[])
+            .await
+            .unwrap()
+            .get(0);
+        let accounts: i64 = c
+            .query_one("select count(*) from accounts", &[])
+            .await
+            .unwrap()
+            .get(0);
+        assert_that!(jwks).is_equal_to(1);
+        assert_that!(accounts).is_equal_to(1);
+
+        // the audit entry is written with the account, about it.
+        let events = db.list_audit_events(since, 10).await.unwrap();
+        assert_that!(events.len()).is_equal_to(1);
+        assert_that!(events[0].account_id).is_equal_to(Some(created.id.unwrap().to_string()));
+
+        // and without a binding, no credential is needed.
+        let mut jwk = JWK::new_es256("x3".to_string(), "y3".to_string());
+        assert_that!(db
+            .create_account(&mut jwk, acct, None, &event)
+            .await
+            .unwrap())
+        .is_some();
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn jwk_check_constraint() {
         use spectral::prelude::*;
@@ -513,6 +1145,16 @@ mod tests {
                     &"alg".to_string(),
                 ],
             ),
+            // only OKP keys may have an x without a y.
+            (
+                "insert into jwks (nonce_key, x, alg, kty) values ($1, $2, $3, $4)",
+                &[
+                    &"thirdbad".to_string(),
+                    &"aaaa".to_string(),
+                    &"ES256".to_string(),
+                    &"EC".to_string(),
+                ],
+            ),
         ];
 
         for args in bad.iter() {
@@ -545,6 +1187,15 @@ mod tests {
                     &"alg".to_string(),
                 ],
             ),
+            (
+                "insert into jwks (nonce_key, x, alg, kty) values ($1, $2, $3, $4)",
+                &[
+                    &"thirdgood".to_string(),
+                    &"aaaa".to_string(),
+                    &"EdDSA".to_string(),
+                    &"OKP".to_string(),
+                ],
+            ),
         ];
 
         for args in good.iter() {
@@ -0,0 +1,230 @@
+use serde::Serialize;
+use tokio_postgres::Transaction;
+
+use super::{LoadError, Postgres, SaveError, TenantId};
+
+/// AuditEventType is the kind of lifecycle event an [AuditEvent] records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEventType {
+    /// an account was created through newAccount.
+    AccountCreated,
+    /// the key of an account was replaced through keyChange.
+    AccountKeyChanged,
+    /// an account was deactivated by its client.
+    AccountDeactivated,
+    /// a certificate was issued when its order was finalized.
+    CertificateIssued,
+    /// a certificate was revoked through revokeCert.
+    CertificateRevoked,
+}
+
+impl ToString for AuditEventType {
+    fn to_string(&self) -> String {
+        match self {
+            Self::AccountCreated => "account_created",
+            Self::AccountKeyChanged => "account_key_changed",
+            Self::AccountDeactivated => "account_deactivated",
+            Self::CertificateIssued => "certificate_issued",
+            Self::CertificateRevoked => "certificate_revoked",
+        }
+        .to_string()
+    }
+}
+
+impl TryFrom<&str> for AuditEventType {
+    type Error = LoadError;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        Ok(match s {
+            "account_created" => Self::AccountCreated,
+            "account_key_changed" => Self::AccountKeyChanged,
+            "account_deactivated" => Self::AccountDeactivated,
+            "certificate_issued" => Self::CertificateIssued,
+            "certificate_revoked" => Self::CertificateRevoked,
+            _ => return Err(LoadError::InvalidEnum),
+        })
+    }
+}
+
+/// AuditEvent is an entry of the audit log. Entries are only ever appended, with
+/// [Postgres::insert_audit_event]; the database refuses to change or remove them.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AuditEvent {
+    pub event_type: AuditEventType,
+    pub account_id: Option<String>,
+    pub order_id: Option<String>,
+    /// the serial of the certificate, in hex.
+    pub serial: Option<String>,
+    /// anything else worth keeping about the event, e.g. the reason for a revocation.
+    pub detail: serde_json::Value,
+    /// when the event was recorded; None until it is.
+    pub occurred_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl AuditEvent {
+    /// new creates an event of the provided type about nothing in particular; use the `with_*`
+    /// methods to say what it is about.
+    pub fn new(event_type: AuditEventType) -> Self {
+        Self {
+            event_type,
+            account_id: None,
+            order_id: None,
+            serial: None,
+            detail: serde_json::json!({}),
+            occurred_at: None,
+        }
+    }
+
+    pub fn with_account_id(mut self, account_id: impl ToString) -> Self {
+        self.account_id = Some(account_id.to_string());
+        self
+    }
+
+    pub fn with_order_id(mut self, order_id: impl ToString) -> Self {
+        self.order_id = Some(order_id.to_string());
+        self
+    }
+
+    pub fn with_serial(mut self, serial: impl ToString) -> Self {
+        self.serial = Some(serial.to_string());
+        self
+    }
+
+    pub fn with_detail(mut self, detail: serde_json::Value) -> Self {
+        self.detail = detail;
+        self
+    }
+}
+
+/// record_audit_event is [Postgres::insert_audit_event] as a part of `tx`, for events which must
+/// only be recorded if what they describe is.
+pub(crate) async fn record_audit_event(
+    event: &AuditEvent,
+    tenant: &TenantId,
+    tx: &Transaction<'_>,
+) -> Result<(), SaveError> {
+    tx.execute(
+        "
+        insert into audit_log
+            (event_type, account_id, order_id, serial, detail, tenant_id)
+        values ($1, $2, $3, $4, $5, $6)
+        ",
+        &[
+            &event.event_type.to_string(),
+            &event.account_id,
+            &event.order_id,
+            &event.serial,
+            &event.detail,
+            &tenant.as_str(),
+        ],
+    )
+    .await?;
+
+    Ok(())
+}
+
+impl Postgres {
+    /// insert_audit_event appends the event to the audit log of the tenant. The time it occurred
+    /// is set by the database.
+    pub async fn insert_audit_event(&self, event: &AuditEvent) -> Result<(), SaveError> {
+        let mut db = self.clone().client().await?;
+        let tx = db.transaction().await?;
+
+        record_audit_event(event, self.tenant(), &tx).await?;
+
+        Ok(tx.commit().await?)
+    }
+
+    /// list_audit_events returns at most `limit` events of the tenant which occurred at or after
+    /// `since`, oldest first.
+    pub async fn list_audit_events(
+        &self,
+        since: chrono::DateTime<chrono::Utc>,
+        limit: i64,
+    ) -> Result<Vec<AuditEvent>, LoadError> {
+        let db = self.read_client().await?;
+        let stmt = db
+            .prepare_cached(
+                "
+                select event_type, account_id, order_id, serial, detail, occurred_at
+                from audit_log
+                where tenant_id = $1 and occurred_at >= $2
+                order by occurred_at asc, id asc
+                limit $3
+                ",
+            )
+            .await?;
+
+        let mut ret = Vec::new();
+
+        for row in db
+            .query(&stmt, &[&self.tenant().as_str(), &since, &limit])
+            .await?
+        {
+            let event_type: String = row.get("event_type");
+
+            ret.push(AuditEvent {
+                event_type: AuditEventType::try_from(event_type.as_str())?,
+                account_id: row.get("account_id"),
+                order_id: row.get("order_id"),
+                serial: row.get("serial"),
+                detail: row.get("detail"),
+                occurred_at: Some(row.get("occurred_at")),
+            });
+        }
+
+        Ok(ret)
+    }
+}
+
+mod tests {
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_audit_log() {
+        use super::{AuditEvent, AuditEventType};
+        use crate::models::TenantId;
+        use crate::test::PGTest;
+        use spectral::prelude::*;
+
+        let pg = PGTest::new("test_audit_log").await.unwrap();
+        let db = pg.db();
+        let start = chrono::Utc::now() - chrono::Duration::seconds(1);
+
+        for serial in ["01", "02", "03"] {
+            db.insert_audit_event(
+                &AuditEvent::new(AuditEventType::CertificateIssued)
+                    .with_account_id(1)
+                    .with_order_id("order")
+                    .with_serial(serial)
+                    .with_detail(serde_json::json!({ "n": serial })),
+            )
+            .await
+            .unwrap();
+        }
+
+        let events = db.list_audit_events(start, 10).await.unwrap();
+        assert_that!(events.len()).is_equal_to(3);
+        assert_that!(events[0].event_type).is_equal_to(AuditEventType::CertificateIssued);
+        assert_that!(events[0].account_id).is_equal_to(Some("1".to_string()));
+        assert_that!(events[0].serial).is_equal_to(Some("01".to_string()));
+        assert_that!(events[2].detail).is_equal_to(serde_json::json!({ "n": "03" }));
+        assert_that!(events[0].occurred_at).is_some();
+
+        assert_that!(db.list_audit_events(start, 2).await.unwrap().len()).is_equal_to(2);
+        assert_that!(db
+            .list_audit_events(chrono::Utc::now() + chrono::Duration::hours(1), 10)
+            .await
+            .unwrap())
+        .is_empty();
+
+        // other tenants have their own log.
+        let other = db.clone().with_tenant(TenantId("other".to_string()));
+        assert_that!(other.list_audit_events(start, 10).await.unwrap()).is_empty();
+
+        // and entries may not be changed.
+        let c = db.clone().client().await.unwrap();
+        assert_that!(c.execute("delete from audit_log", &[]).await).is_err();
+        assert_that!(c.execute("update audit_log set serial = '04'", &[]).await).is_err();
+        assert_that!(db.list_audit_events(start, 10).await.unwrap().len()).is_equal_to(3);
+    }
+}
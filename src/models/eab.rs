@@ -0,0 +1,99 @@
+use tokio_postgres::Transaction;
+
+use super::{LoadError, Postgres, SaveError};
+
+/// EabCredential is an external account binding credential (RFC8555 7.3.4) issued to a client
+/// out of band. Each credential may bind a single account.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EabCredential {
+    pub kid: String,
+    pub hmac_key: Vec<u8>,
+    pub used: bool,
+}
+
+impl Postgres {
+    /// create_eab_credential stores a new, unused credential.
+    pub async fn create_eab_credential(&self, kid: &str, hmac_key: &[u8]) -> Result<(), SaveError> {
+        let mut db = self.clone().client().await?;
+        let tx = db.transaction().await?;
+
+        tx.execute(
+            "insert into eab_credentials (kid, hmac_key) values ($1, $2)",
+            &[&kid, &hmac_key],
+        )
+        .await?;
+
+        Ok(tx.commit().await?)
+    }
+
+    /// find_eab_credential returns the credential with the provided key id, if any. This always
+    /// reads from the primary, as credentials are single-use.
+    pub async fn find_eab_credential(&self, kid: &str) -> Result<Option<EabCredential>, LoadError> {
+        let db = self.clone().client().await?;
+
+        Ok(db
+            .query_opt(
+                "select kid, hmac_key, used from eab_credentials where kid = $1",
+                &[&kid],
+            )
+            .await?
+            .map(|row| EabCredential {
+                kid: row.get("kid"),
+                hmac_key: row.get("hmac_key"),
+                used: row.get("used"),
+            }))
+    }
+
+    /// consume_eab_credential marks the credential as used. It returns false if the credential
+    /// does not exist or was already used, so concurrent registrations cannot share one.
+    pub async fn consume_eab_credential(&self, kid: &str) -> Result<bool, SaveError> {
+        let mut db = self.clone().client().await?;
+        let tx = db.transaction().await?;
+
+        let res = mark_eab_credential_used(kid, &tx).await?;
+
+        tx.commit().await?;
+        Ok(res)
+    }
+}
+
+/// mark_eab_credential_used is [Postgres::consume_eab_credential] as a part of `tx`, so that the
+/// credential is only used up if what it was used for is written too.
+pub(crate) async fn mark_eab_credential_used(
+    kid: &str,
+    tx: &Transaction<'_>,
+) -> Result<bool, SaveError> {
+    let res = tx
+        .execute(
+            "update eab_credentials set used = true, used_at = CURRENT_TIMESTAMP where kid = $1 and not used",
+            &[&kid],
+        )
+        .await?;
+
+    Ok(res == 1)
+}
+
+mod tests {
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_eab_credentials() {
+        use crate::test::PGTest;
+        use spectral::prelude::*;
+
+        let pg = PGTest::new("test_eab_credentials").await.unwrap();
+        let db = pg.db();
+
+        assert_that!(db.find_eab_credential("kid-1").await.unwrap()).is_none();
+        assert_that!(db.consume_eab_credential("kid-1").await.unwrap()).is_false();
+
+        db.create_eab_credential("kid-1", b"secret").await.unwrap();
+        assert_that!(db.create_eab_credential("kid-1", b"secret").await).is_err();
+
+        let cred = db.find_eab_credential("kid-1").await.unwrap().unwrap();
+        assert_that!(cred.hmac_key).is_equal_to(b"secret".to_vec());
+        assert_that!(cred.used).is_false();
+
+        assert_that!(db.consume_eab_credential("kid-1").await.unwrap()).is_true();
+        assert_that!(db.consume_eab_credential("kid-1").await.unwrap()).is_false();
+        assert_that!(db.find_eab_credential("kid-1").await.unwrap().unwrap().used).is_true();
+    }
+}
@@ -0,0 +1,129 @@
+use serde::Serialize;
+use tokio_postgres::Transaction;
+
+use super::{LoadError, Postgres, SaveError};
+use crate::acme::challenge::ChallengeType;
+
+/// FailureReason is why an authorization failed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureReason {
+    /// the challenge failed, and so did every retry that
+    /// [crate::acme::challenge::Challenger::with_retries] allows.
+    RetriesExhausted,
+    /// the challenge was not passed before the challenger's expiration.
+    ChallengeExpired,
+    /// the authorization expired before any of its challenges passed (RFC8555 7.1.6).
+    Expired,
+}
+
+impl ToString for FailureReason {
+    fn to_string(&self) -> String {
+        match self {
+            Self::RetriesExhausted => "retries_exhausted",
+            Self::ChallengeExpired => "challenge_expired",
+            Self::Expired => "expired",
+        }
+        .to_string()
+    }
+}
+
+impl TryFrom<&str> for FailureReason {
+    type Error = LoadError;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        Ok(match s {
+            "retries_exhausted" => Self::RetriesExhausted,
+            "challenge_expired" => Self::ChallengeExpired,
+            "expired" => Self::Expired,
+            _ => return Err(LoadError::InvalidEnum),
+        })
+    }
+}
+
+/// FailedAuthz records an authorization which became invalid or expired without being validated,
+/// so that issuance failures can be investigated after the fact.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FailedAuthz {
+    /// the account which placed the order, if known.
+    pub account_id: Option<i32>,
+    pub authorization_id: String,
+    pub identifier: String,
+    /// the type of the challenge which failed; None if the authorization expired.
+    pub challenge_type: Option<ChallengeType>,
+    pub reason: FailureReason,
+    pub created_at: chrono::DateTime<chrono::Local>,
+}
+
+/// record_failed_authorization adds the authorization to the failed authorizations, unless it is
+/// there already; an authorization only fails once, which a unique constraint enforces even
+/// for concurrent callers.
+pub(crate) async fn record_failed_authorization(
+    authorization_id: &str,
+    challenge_type: Option<ChallengeType>,
+    reason: FailureReason,
+    tx: &Transaction<'_>,
+) -> Result<(), SaveError> {
+    tx.execute(
+        "
+        insert into failed_authorizations
+            (account_id, authorization_id, identifier, challenge_type, reason, tenant_id)
+        select orders.account_id, a.reference, a.identifier, $2, $3, a.tenant_id
+            from orders_authorizations a
+            left join orders on orders.order_id = a.order_id
+        where a.reference = $1
+        on conflict (authorization_id) do nothing
+        ",
+        &[
+            &authorization_id,
+            &challenge_type.map(ChallengeType::to_string),
+            &reason.to_string(),
+        ],
+    )
+    .await?;
+
+    Ok(())
+}
+
+impl Postgres {
+    /// list_failed_authorizations returns the authorizations of the account which failed at or
+    /// after `since`, oldest first.
+    pub async fn list_failed_authorizations(
+        &self,
+        account_id: i32,
+        since: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Vec<FailedAuthz>, LoadError> {
+        let db = self.read_client().await?;
+        let stmt = db
+            .prepare_cached(
+                "
+                select account_id, authorization_id, identifier, challenge_type, reason, created_at
+                from failed_authorizations
+                where account_id = $1 and created_at >= $2
+                order by created_at asc, id asc
+                ",
+            )
+            .await?;
+
+        let mut ret = Vec::new();
+
+        for row in db.query(&stmt, &[&account_id, &since]).await? {
+            let challenge_type: Option<String> = row.get("challenge_type");
+            let reason: String = row.get("reason");
+
+            ret.push(FailedAuthz {
+                account_id: row.get("account_id"),
+                authorization_id: row.get("authorization_id"),
+                identifier: row.get("identifier"),
+                challenge_type: match challenge_type {
+                    Some(t) => Some(ChallengeType::try_from(t.as_str())?),
+                    None => None,
+                },
+                reason: FailureReason::try_from(reason.as_str())?,
+                created_at: row.get("created_at"),
+            });
+        }
+
+        Ok(ret)
+    }
+}
@@ -0,0 +1,639 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use tokio::sync::RwLock;
+
+use super::{
+    account::{Account, KeyRollover, JWK},
+    nonce::NonceState,
+    order::{order_status_from_challenges, Authorization, Challenge, ChallengeWithContext, Order},
+    LoadError, Record, SaveError, Storage, TenantId,
+};
+use crate::acme::handlers::order::{AuthStatus, OrderStatus};
+
+/// MemoryStore is a [Storage] which keeps everything in memory, so nothing survives the process.
+/// It is meant for tests which exercise storage semantics without starting Postgres. Clones share
+/// the same collections. Each tenant gets collections of its own; see [Storage::for_tenant].
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    ids: Arc<AtomicI32>,
+    tenant: TenantId,
+    /// the stores of the other tenants, shared by all of them.
+    tenants: Arc<std::sync::Mutex<HashMap<TenantId, MemoryStore>>>,
+    accounts: Arc<RwLock<HashMap<i32, Account>>>,
+    key_history: Arc<RwLock<Vec<KeyRollover>>>,
+    orders: Arc<RwLock<HashMap<String, Order>>>,
+    authorizations: Arc<RwLock<HashMap<String, Authorization>>>,
+    challenges: Arc<RwLock<HashMap<String, Challenge>>>,
+    /// (order_id, authorization reference) for authorizations reused from another order.
+    reused: Arc<RwLock<Vec<(String, String)>>>,
+    nonces: Arc<RwLock<HashMap<String, chrono::DateTime<chrono::Local>>>>,
+}
+
+impl MemoryStore {
+    /// constructs an empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// next_id hands out primary keys, which are unique across all collections.
+    fn next_id(&self) -> i32 {
+        self.ids.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// nonce_cutoff returns the issue time before which nonces have outlived `ttl`. There is no
+    /// database clock here, so nonces are timed by the local one.
+    fn nonce_cutoff(ttl: Duration) -> Result<chrono::DateTime<chrono::Local>, SaveError> {
+        Ok(chrono::Local::now()
+            - chrono::Duration::from_std(ttl).map_err(|e| SaveError::Generic(e.to_string()))?)
+    }
+
+    /// authorization_statuses returns the statuses of the challenges of each authorization of the
+    /// order, its reused ones included, in the order the authorizations were created.
+    async fn authorization_statuses(&self, order_id: &str) -> Vec<Vec<OrderStatus>> {
+        let authorizations = self.authorizations.read().await;
+        let challenges = self.challenges.read().await;
+        let reused = self.reused.read().await;
+
+        let mut authz = authorizations
+            .values()
+            .filter(|a| {
+                a.order_id == order_id
+                    || reused
+                        .iter()
+                        .any(|(o, r)| o == order_id && *r == a.reference)
+            })
+            .collect::<Vec<&Authorization>>();
+        authz.sort_by_key(|a| a.id().ok().flatten().unwrap_or_default());
+
+        let mut statuses = Vec::new();
+        for a in authz {
+            if a.identifier.is_none() {
+                break;
+            }
+
+            statuses.push(
+                challenges
+                    .values()
+                    .filter(|c| c.authorization_id == a.reference)
+                    .map(|c| c.status.clone())
+                    .collect(),
+            );
+        }
+
+        statuses
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStore {
+    fn for_tenant(&self, tenant: &TenantId) -> Arc<dyn Storage + Send + Sync> {
+        if *tenant == self.tenant {
+            return Arc::new(self.clone());
+        }
+
+        let store = self
+            .tenants
+            .lock()
+            .unwrap()
+            .entry(tenant.clone())
+            .or_insert_with(|| MemoryStore {
+                ids: self.ids.clone(),
+                tenant: tenant.clone(),
+                tenants: self.tenants.clone(),
+                ..Default::default()
+            })
+            .clone();
+
+        Arc::new(store)
+    }
+
+    async fn insert_nonce(&self, nonce: &str) -> Result<(), SaveError> {
+        let mut nonces = self.nonces.write().await;
+
+        if nonces.contains_key(nonce) {
+            return Err(SaveError::Generic("nonce already exists".to_string()));
+        }
+
+        nonces.insert(nonce.to_string(), chrono::Local::now());
+        Ok(())
+    }
+
+    async fn nonce_count(&self) -> Result<i64, LoadError> {
+        Ok(self.nonces.read().await.len() as i64)
+    }
+
+    async fn consume_nonce_with_ttl(
+        &self,
+        nonce: &str,
+        ttl: Duration,
+    ) -> Result<NonceState, SaveError> {
+        let cutoff = Self::nonce_cutoff(ttl)?;
+
+        Ok(match self.nonces.write().await.remove(nonce) {
+            Some(created_at) if created_at < cutoff => NonceState::Expired,
+            Some(_) => NonceState::Valid,
+            None => NonceState::NotFound,
+        })
+    }
+
+    async fn delete_expired_nonces(&self, ttl: Duration) -> Result<u64, SaveError> {
+        let cutoff = Self::nonce_cutoff(ttl)?;
+
+        let mut nonces = self.nonces.write().await;
+        let before = nonces.len();
+        nonces.retain(|_, created_at| *created_at >= cutoff);
+
+        Ok((before - nonces.len()) as u64)
+    }
+
+    async fn create_account(&self, account: &mut Account) -> Result<i32, SaveError> {
+        let id = self.next_id();
+        account.id = Some(id);
+        self.accounts.write().await.insert(id, account.clone());
+        Ok(id)
+    }
+
+    async fn record_key_rollover(
+        &self,
+        account_id: i32,
+        old: &str,
+        new: &str,
+    ) -> Result<(), SaveError> {
+        if !self.accounts.read().await.contains_key(&account_id) {
+            return Err(SaveError::Generic(format!(
+                "account {} does not exist",
+                account_id
+            )));
+        }
+
+        self.key_history.write().await.push(KeyRollover {
+            account_id,
+            old_thumbprint: old.to_string(),
+            new_thumbprint: new.to_string(),
+            changed_at: chrono::Local::now(),
+        });
+
+        Ok(())
+    }
+
+    async fn get_key_history(&self, account_id: i32) -> Result<Vec<KeyRollover>, LoadError> {
+        Ok(self
+            .key_history
+            .read()
+            .await
+            .iter()
+            .filter(|k| k.account_id == account_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn account_deactivated_for_key(&self, _jwk: &JWK) -> Result<bool, LoadError> {
+        // accounts are never deactivated here, so no key can have belonged to one which was.
+        Ok(false)
+    }
+
+    async fn create_order(&self, order: &mut Order) -> Result<i32, SaveError> {
+        let id = self.next_id();
+        order.set_id(id);
+        self.orders
+            .write()
+            .await
+            .insert(order.order_id.clone(), order.clone());
+        Ok(id)
+    }
+
+    async fn order_status_counts(&self) -> Result<HashMap<String, usize>, LoadError> {
+        let order_ids = self
+            .orders
+            .read()
+            .await
+            .keys()
+            .cloned()
+            .collect::<Vec<String>>();
+
+        let mut counts = HashMap::new();
+
+        for order_id in order_ids {
+            let status =
+                order_status_from_challenges(&self.authorization_statuses(&order_id).await);
+            *counts.entry(status.to_string()).or_insert(0) += 1;
+        }
+
+        Ok(counts)
+    }
+
+    async fn create_authorization(&self, authz: &mut Authorization) -> Result<i32, SaveError> {
+        if authz.identifier.is_none() {
+            return Err(SaveError::Generic(
+                "cannot insert an authorization without an identifier".to_string(),
+            ));
+        }
+
+        let id = self.next_id();
+        authz.set_id(id);
+        self.authorizations
+            .write()
+            .await
+            .insert(authz.reference.clone(), authz.clone());
+        Ok(id)
+    }
+
+    async fn get_authorization(&self, reference: &str) -> Result<Authorization, LoadError> {
+        self.authorizations
+            .read()
+            .await
+            .get(reference)
+            .cloned()
+            .ok_or(LoadError::NotFound)
+    }
+
+    async fn update_authorization_status(
+        &self,
+        reference: &str,
+        status: OrderStatus,
+        expected_version: i64,
+    ) -> Result<i64, SaveError> {
+        let mut authorizations = self.authorizations.write().await;
+
+        let authz = match authorizations.get_mut(reference) {
+            Some(authz) if authz.version == expected_version && authz.deleted_at.is_none() => authz,
+            _ => return Err(SaveError::ConcurrentModification),
+        };
+
+        authz.version += 1;
+
+        for c in self
+            .challenges
+            .write()
+            .await
+            .values_mut()
+            .filter(|c| c.authorization_id == reference)
+        {
+            c.status = status.clone();
+        }
+
+        Ok(authz.version)
+    }
+
+    async fn find_reusable_authorization(
+        &self,
+        account_id: i32,
+        kind: &str,
+        identifier: &str,
+    ) -> Result<Option<Authorization>, LoadError> {
+        let orders = self.orders.read().await;
+        let challenges = self.challenges.read().await;
+
+        Ok(self
+            .authorizations
+            .read()
+            .await
+            .values()
+            .filter(|a| {
+                a.kind == kind
+                    && a.identifier.as_deref() == Some(identifier)
+                    && a.deleted_at.is_none()
+                    && !a.is_expired()
+                    && orders
+                        .get(&a.order_id)
+                        .map_or(false, |o| o.account_id == Some(account_id))
+                    && challenges.values().any(|c| {
+                        c.authorization_id == a.reference && c.status == OrderStatus::Valid
+                    })
+            })
+            .max_by_key(|a| a.expires)
+            .cloned())
+    }
+
+    async fn link_authorization(&self, order_id: &str, reference: &str) -> Result<(), SaveError> {
+        let mut reused = self.reused.write().await;
+        let link = (order_id.to_string(), reference.to_string());
+
+        if reused.contains(&link) {
+            return Err(SaveError::Generic(
+                "authorization is already linked to the order".to_string(),
+            ));
+        }
+
+        reused.push(link);
+        Ok(())
+    }
+
+    async fn create_challenge(&self, challenge: &mut Challenge) -> Result<i32, SaveError> {
+        let id = self.next_id();
+        challenge.id = Some(id);
+        self.challenges
+            .write()
+            .await
+            .insert(challenge.reference.clone(), challenge.clone());
+        Ok(id)
+    }
+
+    async fn get_challenge_with_context(
+        &self,
+        auth_id: &str,
+        challenge_type: &str,
+    ) -> Result<Option<ChallengeWithContext>, LoadError> {
+        let authorization = match self.authorizations.read().await.get(auth_id) {
+            Some(authz) => authz.clone(),
+            None => return Ok(None),
+        };
+
+        if !self
+            .orders
+            .read()
+            .await
+            .contains_key(&authorization.order_id)
+        {
+            return Ok(None);
+        }
+
+        let challenges = self
+            .challenges
+            .read()
+            .await
+            .values()
+            .filter(|c| c.authorization_id == auth_id)
+            .cloned()
+            .collect::<Vec<Challenge>>();
+
+        let challenge = match challenges
+            .iter()
+            .filter(|c| {
+                c.deleted_at.is_none() && c.challenge_type.clone().to_string() == challenge_type
+            })
+            .max_by_key(|c| (c.created_at, c.id))
+        {
+            Some(c) => c.clone(),
+            None => return Ok(None),
+        };
+
+        let authorization_status = AuthStatus::from_challenges(
+            authorization.deleted_at.is_some(),
+            authorization.is_expired(),
+            challenges.iter().map(|c| &c.status),
+        );
+        let order_status = order_status_from_challenges(
+            &self.authorization_statuses(&authorization.order_id).await,
+        );
+
+        Ok(Some(ChallengeWithContext {
+            challenge,
+            authorization,
+            authorization_status,
+            order_status,
+        }))
+    }
+}
+
+mod tests {
+    /// exercise_storage checks the behavior every [super::Storage] must share.
+    #[cfg(test)]
+    async fn exercise_storage(store: std::sync::Arc<dyn super::Storage + Send + Sync>) {
+        use crate::acme::challenge::ChallengeType;
+        use crate::acme::handlers::order::{AuthStatus, OrderStatus};
+        use crate::errors::db::SaveError;
+        use crate::models::nonce::NonceState;
+        use crate::models::order::{Authorization, Challenge, Order};
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        store.insert_nonce("nonce").await.unwrap();
+        assert_that!(store.nonce_count().await.unwrap()).is_equal_to(1);
+        assert_that!(store
+            .consume_nonce_with_ttl("nonce", Duration::from_secs(60))
+            .await
+            .unwrap())
+        .is_equal_to(NonceState::Valid);
+        assert_that!(store
+            .consume_nonce_with_ttl("nonce", Duration::from_secs(60))
+            .await
+            .unwrap())
+        .is_equal_to(NonceState::NotFound);
+        assert_that!(store
+            .delete_expired_nonces(Duration::from_secs(60))
+            .await
+            .unwrap())
+        .is_equal_to(0);
+
+        let mut order = Order::default();
+        store.create_order(&mut order).await.unwrap();
+
+        let mut authz = Authorization::default();
+        authz.order_id = order.order_id.clone();
+        authz.identifier = Some("example.com".to_string());
+        store.create_authorization(&mut authz).await.unwrap();
+
+        let mut challenge = Challenge::new(
+            order.order_id.clone(),
+            authz.reference.clone(),
+            ChallengeType::HTTP01,
+            "example.com".to_string(),
+            "127.0.0.1".to_string(),
+            OrderStatus::Pending,
+        );
+        store.create_challenge(&mut challenge).await.unwrap();
+        assert_that!(challenge.id).is_some();
+
+        let ctx = store
+            .get_challenge_with_context(&authz.reference, "http-01")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_that!(ctx.challenge.reference).is_equal_to(challenge.reference.clone());
+        assert_that!(ctx.authorization_status).is_equal_to(AuthStatus::Pending);
+        assert_that!(ctx.order_status).is_equal_to(OrderStatus::Pending);
+        assert_that!(store
+            .get_challenge_with_context(&authz.reference, "dns-01")
+            .await
+            .unwrap())
+        .is_none();
+
+        let counts = store.order_status_counts().await.unwrap();
+        assert_that!(counts.get(&OrderStatus::Pending.to_string())).is_equal_to(Some(&1));
+
+        let version = store
+            .update_authorization_status(&authz.reference, OrderStatus::Valid, 0)
+            .await
+            .unwrap();
+        assert_that!(version).is_equal_to(1);
+        assert_that!(
+            store
+                .get_authorization(&authz.reference)
+                .await
+                .unwrap()
+                .version
+        )
+        .is_equal_to(1);
+        assert_that!(matches!(
+            store
+                .update_authorization_status(&authz.reference, OrderStatus::Invalid, 0)
+                .await,
+            Err(SaveError::ConcurrentModification)
+        ))
+        .is_true();
+
+        let ctx = store
+            .get_challenge_with_context(&authz.reference, "http-01")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_that!(ctx.challenge.status).is_equal_to(OrderStatus::Valid);
+        assert_that!(ctx.authorization_status).is_equal_to(AuthStatus::Valid);
+        assert_that!(ctx.order_status).is_equal_to(OrderStatus::Valid);
+
+        let counts = store.order_status_counts().await.unwrap();
+        assert_that!(counts.get(&OrderStatus::Valid.to_string())).is_equal_to(Some(&1));
+
+        // only orders of the same account may reuse a valid authorization.
+        let mut first = Order::default();
+        first.account_id = Some(42);
+        store.create_order(&mut first).await.unwrap();
+
+        let mut authz = Authorization::default();
+        authz.order_id = first.order_id.clone();
+        authz.identifier = Some("reused.example.com".to_string());
+        store.create_authorization(&mut authz).await.unwrap();
+
+        let mut challenge = Challenge::new(
+            first.order_id.clone(),
+            authz.reference.clone(),
+            ChallengeType::HTTP01,
+            "reused.example.com".to_string(),
+            "127.0.0.1".to_string(),
+            OrderStatus::Pending,
+        );
+        store.create_challenge(&mut challenge).await.unwrap();
+
+        assert_that!(store
+            .find_reusable_authorization(42, "dns", "reused.example.com")
+            .await
+            .unwrap())
+        .is_none();
+
+        store
+            .update_authorization_status(&authz.reference, OrderStatus::Valid, 0)
+            .await
+            .unwrap();
+
+        assert_that!(store
+            .find_reusable_authorization(42, "dns", "reused.example.com")
+            .await
+            .unwrap()
+            .map(|a| a.reference))
+        .is_equal_to(Some(authz.reference.clone()));
+        assert_that!(store
+            .find_reusable_authorization(43, "dns", "reused.example.com")
+            .await
+            .unwrap())
+        .is_none();
+        assert_that!(store
+            .find_reusable_authorization(42, "dns", "other.example.com")
+            .await
+            .unwrap())
+        .is_none();
+
+        let mut second = Order::default();
+        second.account_id = Some(42);
+        store.create_order(&mut second).await.unwrap();
+        store
+            .link_authorization(&second.order_id, &authz.reference)
+            .await
+            .unwrap();
+
+        let counts = store.order_status_counts().await.unwrap();
+        assert_that!(counts.get(&OrderStatus::Valid.to_string())).is_equal_to(Some(&3));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_memory_store() {
+        use crate::test::{StorageBackend, TestStore};
+
+        let store = TestStore::new("test_memory_store", StorageBackend::Memory).await;
+        super::tests::exercise_storage(store.storage()).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_postgres_store() {
+        use crate::test::{StorageBackend, TestStore};
+
+        let store = TestStore::new("test_postgres_store", StorageBackend::Postgres).await;
+        super::tests::exercise_storage(store.storage()).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_memory_store_key_history() {
+        use super::{MemoryStore, Storage};
+        use crate::models::account::Account;
+        use spectral::prelude::*;
+
+        let store = MemoryStore::new();
+
+        // the account must exist first.
+        assert_that!(store.record_key_rollover(1, "old", "new").await).is_err();
+
+        let mut account = Account::new(1, vec![]);
+        let account_id = store.create_account(&mut account).await.unwrap();
+        assert_that!(account.id).is_equal_to(Some(account_id));
+
+        store
+            .record_key_rollover(account_id, "old", "new")
+            .await
+            .unwrap();
+        store
+            .record_key_rollover(account_id, "new", "newer")
+            .await
+            .unwrap();
+
+        let history = store.get_key_history(account_id).await.unwrap();
+        assert_that!(history.len()).is_equal_to(2);
+        assert_that!(history[0].old_thumbprint).is_equal_to("old".to_string());
+        assert_that!(history[1].new_thumbprint).is_equal_to("newer".to_string());
+        assert_that!(store.get_key_history(account_id + 1).await.unwrap()).is_empty();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_memory_store_tenants() {
+        use super::{MemoryStore, Storage};
+        use crate::models::{account::Account, TenantId};
+        use spectral::prelude::*;
+
+        let store = MemoryStore::new();
+        let tenant = TenantId("tenant".to_string());
+
+        let mut account = Account::new(1, vec![]);
+        let account_id = store
+            .for_tenant(&tenant)
+            .create_account(&mut account)
+            .await
+            .unwrap();
+        store
+            .for_tenant(&tenant)
+            .record_key_rollover(account_id, "old", "new")
+            .await
+            .unwrap();
+
+        // the tenant's records are kept, apart from those of the default tenant.
+        assert_that!(store
+            .for_tenant(&tenant)
+            .get_key_history(account_id)
+            .await
+            .unwrap())
+        .has_length(1);
+        assert_that!(store.record_key_rollover(account_id, "old", "new").await).is_err();
+        assert_that!(store
+            .for_tenant(&TenantId::default())
+            .get_key_history(account_id)
+            .await
+            .unwrap())
+        .is_empty();
+    }
+}
@@ -1,10 +1,22 @@
-use std::str::FromStr;
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use crate::errors::db::*;
+use crate::{acme::handlers::order::OrderStatus, errors::db::*};
 use async_trait::async_trait;
-use deadpool_postgres::{Manager, ManagerConfig, Object, Pool};
-use refinery::Report;
-use tokio_postgres::{Config, NoTls, Row, Transaction};
+use deadpool_postgres::{Hook, HookError, Manager, ManagerConfig, Object, Pool, Runtime};
+use openssl::ssl::{SslConnector, SslFiletype, SslMethod};
+use postgres_openssl::MakeTlsConnector;
+use rand::Rng;
+use refinery::{Migration, Report, Target};
+use serde::Serialize;
+use tokio_postgres::{
+    config::SslMode, error::SqlState, types::ToSql, Config, NoTls, Row, Transaction,
+};
 
 /// these are the actual migrations that will be executed. this module is automatically generated.
 pub mod migrations {
@@ -14,19 +26,478 @@ pub mod migrations {
 
 /// account operations
 pub mod account;
+/// the audit log of certificate and account lifecycle events
+pub mod audit;
+/// external account binding credentials
+pub mod eab;
+/// the audit trail of failed authorizations
+pub mod failed_authorization;
+/// in-memory storage, for tests which do not need Postgres
+pub mod memory;
 /// operations related to nonce management
 pub mod nonce;
 /// order operations
 pub mod order;
+/// per-account rate limiting
+pub mod rate_limit;
+/// certificate revocation status
+pub mod revocation;
+
+use self::{
+    account::{Account, KeyRollover, JWK},
+    nonce::NonceState,
+    order::{Authorization, Challenge, ChallengeWithContext, Order},
+};
 
 pub(crate) const NONCE_KEY_SIZE: Option<usize> = Some(32);
 
-/// Postgres is our (currently only) implementation of backing storage. It uses a
+/// migrations taking longer than this are logged as a warning by [Postgres::migrate].
+const SLOW_MIGRATION_THRESHOLD: Duration = Duration::from_secs(30);
+
+/// how long [Postgres::health_check] waits for a connection and its query.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// MigrationProgress is emitted by [Postgres::migrate_with_progress] as each pending migration is
+/// run. `index` starts at 1 and `total` is the number of migrations pending when the run began.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MigrationProgress {
+    /// the migration is about to be applied.
+    Applying {
+        index: usize,
+        total: usize,
+        name: String,
+    },
+    /// the migration was applied successfully.
+    Applied {
+        index: usize,
+        total: usize,
+        name: String,
+        elapsed: Duration,
+    },
+}
+
+/// MigrationVersion identifies a migration by its version number and description, e.g. `(14,
+/// "tenants")` for `V14__tenants.sql`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MigrationVersion(pub (u64, String));
+
+impl MigrationVersion {
+    /// the migration's version number.
+    pub fn version(&self) -> u64 {
+        self.0 .0
+    }
+
+    /// the migration's description, taken from its filename.
+    pub fn description(&self) -> &str {
+        &self.0 .1
+    }
+}
+
+impl std::fmt::Display for MigrationVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "V{}__{}", self.version(), self.description())
+    }
+}
+
+impl From<&Migration> for MigrationVersion {
+    fn from(m: &Migration) -> Self {
+        Self((m.version() as u64, m.name().to_string()))
+    }
+}
+
+/// PoolStats is a snapshot of the connection pool's state.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PoolStats {
+    /// the maximum number of connections the pool will open.
+    pub max_size: usize,
+    /// the number of connections currently open.
+    pub size: usize,
+    /// idle connections; negative when callers are waiting for a connection.
+    pub available: isize,
+}
+
+/// TenantId namespaces accounts, orders and authorizations, so that several tenants can share one
+/// database without seeing each other's; see [Postgres::with_tenant]. The default, empty, tenant
+/// is the one used when no other is configured.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TenantId(pub String);
+
+impl TenantId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for TenantId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// SslVerifyMode chooses how much of the server's certificate is checked on connections made
+/// with an [SslConfig]. The names follow libpq's `sslmode`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SslVerifyMode {
+    /// encrypt the connection, but accept any certificate.
+    Require,
+    /// the certificate must chain to the configured CA.
+    VerifyCa,
+    /// the certificate must chain to the configured CA and name the host connected to.
+    VerifyFull,
+}
+
+/// SslConfig makes [Postgres] connect over TLS; see [PostgresConfig::with_ssl]. All paths are to
+/// PEM files. Connections are never made in the clear once it is configured.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SslConfig {
+    /// the CA certificate(s) the server's certificate is verified against.
+    pub ca_cert: PathBuf,
+    /// the certificate to authenticate to the server with, if it asks for one.
+    pub client_cert: Option<PathBuf>,
+    /// the private key of `client_cert`.
+    pub client_key: Option<PathBuf>,
+    pub verify_mode: SslVerifyMode,
+}
+
+impl SslConfig {
+    /// new verifies servers against the CA certificate in `ca_cert` with
+    /// [SslVerifyMode::VerifyFull].
+    pub fn new(ca_cert: impl Into<PathBuf>) -> Self {
+        Self {
+            ca_cert: ca_cert.into(),
+            client_cert: None,
+            client_key: None,
+            verify_mode: SslVerifyMode::VerifyFull,
+        }
+    }
+
+    /// with_client_cert authenticates to the server with the certificate and private key
+    /// provided.
+    pub fn with_client_cert(mut self, cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        self.client_cert = Some(cert.into());
+        self.client_key = Some(key.into());
+        self
+    }
+
+    /// with_verify_mode replaces the default [SslVerifyMode::VerifyFull].
+    pub fn with_verify_mode(mut self, verify_mode: SslVerifyMode) -> Self {
+        self.verify_mode = verify_mode;
+        self
+    }
+
+    /// connector builds the [tokio_postgres] TLS connector for the configuration, reading the
+    /// certificates and key from disk.
+    pub fn connector(&self) -> Result<MakeTlsConnector, ConnectionError> {
+        let mut builder = SslConnector::builder(SslMethod::tls())?;
+        builder.set_ca_file(&self.ca_cert)?;
+
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => {
+                builder.set_certificate_chain_file(cert)?;
+                builder.set_private_key_file(key, SslFiletype::PEM)?;
+                builder.check_private_key()?;
+            }
+            (None, None) => {}
+            _ => {
+                return Err(ConnectionError::Generic(
+                    "client_cert and client_key must be provided together".to_string(),
+                ))
+            }
+        }
+
+        builder.set_verify(match self.verify_mode {
+            SslVerifyMode::Require => openssl::ssl::SslVerifyMode::NONE,
+            SslVerifyMode::VerifyCa | SslVerifyMode::VerifyFull => {
+                openssl::ssl::SslVerifyMode::PEER
+            }
+        });
+
+        let mut connector = MakeTlsConnector::new(builder.build());
+        let verify_hostname = self.verify_mode == SslVerifyMode::VerifyFull;
+        connector.set_callback(move |config, _| {
+            config.set_verify_hostname(verify_hostname);
+            Ok(())
+        });
+
+        Ok(connector)
+    }
+
+    /// configure requires TLS for connections made with `config`, so that a server without it
+    /// is refused rather than talked to in the clear.
+    fn configure(&self, config: &mut Config) {
+        config.ssl_mode(SslMode::Require);
+    }
+}
+
+/// ReadPool is a pool of read-only connections, typically to a replica or with a role which has
+/// only been granted SELECT. Every session it opens has `default_transaction_read_only` set, so
+/// writes are refused even if the role would otherwise permit them. Construct one with
+/// [Postgres::read_pool_with_config] and attach it with [Postgres::with_read_pool].
+#[derive(Clone)]
+pub struct ReadPool {
+    pool: Pool,
+}
+
+impl ReadPool {
+    /// query runs a SELECT statement and returns the rows. Statements which attempt to write
+    /// fail with [LoadError::Permissions].
+    pub async fn query(
+        &self,
+        statement: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Vec<Row>, LoadError> {
+        let client = self.client().await?;
+        client
+            .query(statement, params)
+            .await
+            .map_err(|e| match e.code() {
+                Some(code)
+                    if *code == SqlState::READ_ONLY_SQL_TRANSACTION
+                        || *code == SqlState::INSUFFICIENT_PRIVILEGE =>
+                {
+                    LoadError::Permissions(e.to_string())
+                }
+                _ => LoadError::DBError(e),
+            })
+    }
+
+    pub(crate) async fn client(&self) -> Result<Object, ConnectionError> {
+        Ok(self.pool.get().await?)
+    }
+}
+
+/// how many connections a [PostgresConfig] allows, unless set otherwise.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 16;
+
+/// PostgresConfig describes how [Postgres::with_config] connects to the database and sizes its
+/// pool. The timeouts are checked when a connection is taken from the pool, so connections idle
+/// or old beyond them are closed and replaced rather than handed out.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PostgresConfig {
+    /// the connection configuration, as a standard PostgreSQL DSN.
+    pub dsn: String,
+    /// the most connections the pool will open.
+    pub max_connections: usize,
+    /// connections opened when the pool is created, rather than on first use.
+    pub min_connections: usize,
+    /// how long to wait for a connection when all are in use; None waits forever.
+    pub acquire_timeout: Option<Duration>,
+    /// close connections which have not been used for this long.
+    pub idle_timeout: Option<Duration>,
+    /// close connections which were opened this long ago.
+    pub max_lifetime: Option<Duration>,
+    /// connect over TLS; see [SslConfig].
+    pub ssl: Option<SslConfig>,
+    /// keep the tables in this schema rather than in the first one of the server's
+    /// `search_path`, usually `public`. It is created when the database is migrated.
+    pub schema: Option<String>,
+    /// connect as this role rather than the one the DSN names.
+    pub user: Option<String>,
+    /// how the busiest queries are retried when the database cannot be reached; see
+    /// [with_retry].
+    pub retry: RetryPolicy,
+}
+
+impl PostgresConfig {
+    /// new configures a pool of up to [DEFAULT_MAX_CONNECTIONS] connections to `dsn`, e.g.
+    /// `user=foo hostname=localhost password=quux`. Callers wait up to 30 seconds for a
+    /// connection, and connections are closed after 10 minutes idle or 30 minutes in all.
+    pub fn new(dsn: &str) -> Self {
+        Self {
+            dsn: dsn.to_string(),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            min_connections: 0,
+            acquire_timeout: Some(Duration::from_secs(30)),
+            idle_timeout: Some(Duration::from_secs(10 * 60)),
+            max_lifetime: Some(Duration::from_secs(30 * 60)),
+            ssl: None,
+            schema: None,
+            user: None,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    /// with_max_connections sets the most connections the pool will open.
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max;
+        self
+    }
+
+    /// with_min_connections opens `min` connections when the pool is created.
+    pub fn with_min_connections(mut self, min: usize) -> Self {
+        self.min_connections = min;
+        self
+    }
+
+    /// with_acquire_timeout sets how long to wait for a free connection; None waits forever.
+    pub fn with_acquire_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.acquire_timeout = timeout;
+        self
+    }
+
+    /// with_idle_timeout closes connections unused for `timeout`; None keeps them open.
+    pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// with_max_lifetime closes connections opened `lifetime` ago; None keeps them open.
+    pub fn with_max_lifetime(mut self, lifetime: Option<Duration>) -> Self {
+        self.max_lifetime = lifetime;
+        self
+    }
+
+    /// with_ssl connects over TLS, for the pool and migrations alike.
+    pub fn with_ssl(mut self, ssl: SslConfig) -> Self {
+        self.ssl = Some(ssl);
+        self
+    }
+
+    /// with_schema keeps the tables in `schema`, which must be a lowercase identifier, so that
+    /// several instances can share a database without seeing each other's records.
+    pub fn with_schema(mut self, schema: impl Into<String>) -> Self {
+        self.schema = Some(schema.into());
+        self
+    }
+
+    /// with_user connects as `user` rather than as the role the DSN names, e.g. as a read-only
+    /// role for a [ReadPool] configured from the same DSN as the primary.
+    pub fn with_user(mut self, user: impl Into<String>) -> Self {
+        self.user = Some(user.into());
+        self
+    }
+
+    /// with_retry_policy sets how queries are retried on errors of the connection;
+    /// [RetryPolicy::none] fails them at once.
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+}
+
+/// RetryPolicy bounds how [with_retry] repeats an operation which failed with a [Transient]
+/// error. The delay doubles with each attempt from `base_delay` up to `max_delay`, and a random
+/// part of up to half of it is taken off so that callers which failed together do not retry
+/// together. By default, an operation is attempted three times, waiting 100ms and then 200ms.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// how often the operation is attempted in all, the first attempt included.
+    pub max_attempts: u32,
+    /// the delay after the first failure.
+    pub base_delay: Duration,
+    /// the longest delay between two attempts.
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(2),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// none attempts each operation once.
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// delay returns how long to wait after the `attempt`th attempt failed, counting from 1.
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(1 << attempt.saturating_sub(1).min(31))
+            .min(self.max_delay);
+
+        delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+/// with_retry runs `op` until it succeeds, fails with an error which is not [Transient], or has
+/// been attempted as often as `policy` allows; the last result is returned. Only wrap operations
+/// which may safely be repeated: a connection lost while a transaction commits leaves it unknown
+/// whether the commit took effect.
+pub(crate) async fn with_retry<T, E, F, Fut>(policy: &RetryPolicy, mut op: F) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, E>>,
+    E: Transient + std::fmt::Display,
+{
+    let mut attempt = 1;
+
+    loop {
+        match op().await {
+            Err(e) if e.is_transient() && attempt < policy.max_attempts => {
+                let delay = policy.delay(attempt);
+                log::warn!(
+                    "database unavailable (attempt {} of {}), retrying in {:?}: {}",
+                    attempt,
+                    policy.max_attempts,
+                    delay,
+                    e
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// is_schema_name is true if `schema` is an identifier postgres accepts unquoted and keeps as
+/// is: lowercase letters, digits and underscores, not starting with a digit, at most 63 bytes.
+fn is_schema_name(schema: &str) -> bool {
+    !schema.is_empty()
+        && schema.len() <= 63
+        && !schema.starts_with(|c: char| c.is_ascii_digit())
+        && schema
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+/// is_duplicate_object is true if the migration failed because an object it creates already
+/// exists, as when another instance applied it first.
+fn is_duplicate_object(e: &refinery::Error) -> bool {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(e);
+
+    while let Some(err) = source {
+        if let Some(code) = err
+            .downcast_ref::<tokio_postgres::Error>()
+            .and_then(|e| e.code())
+        {
+            return [
+                SqlState::DUPLICATE_TABLE,
+                SqlState::DUPLICATE_OBJECT,
+                SqlState::DUPLICATE_COLUMN,
+                SqlState::DUPLICATE_SCHEMA,
+                SqlState::DUPLICATE_FUNCTION,
+            ]
+            .contains(code);
+        }
+
+        source = err.source();
+    }
+
+    false
+}
+
+/// Postgres is our primary implementation of backing storage; see also [memory::MemoryStore]. It uses a
 /// [deadpool_postgres] Pool and migrates automatically with [refinery].
 #[derive(Clone)]
 pub struct Postgres {
     pool: Pool,
     config: String,
+    ssl: Option<SslConfig>,
+    read: Option<ReadPool>,
+    tenant: TenantId,
+    schema: Option<String>,
+    retry: RetryPolicy,
 }
 
 impl Postgres {
@@ -44,45 +515,435 @@ impl Postgres {
         Ok(client)
     }
 
+    /// connect_one_with_ssl is [Postgres::connect_one] over TLS, configured by `ssl`.
+    pub async fn connect_one_with_ssl(
+        config: &str,
+        ssl: &SslConfig,
+    ) -> Result<tokio_postgres::Client, ConnectionError> {
+        let mut pg_config = Config::from_str(config)?;
+        ssl.configure(&mut pg_config);
+        let (client, conn) = pg_config.connect(ssl.connector()?).await?;
+
+        tokio::spawn(async move {
+            if let Err(e) = conn.await {
+                log::error!("postgresql connection error: {}", e)
+            }
+        });
+
+        Ok(client)
+    }
+
     /// This function initializes Postgres with a pool size of `pool_size` and connection
     /// configuration `config`. The `config` string is a standard PostgreSQL DSN, e.g.:
     ///
     ///
     /// `user=foo hostname=localhost password=quux`
+    #[deprecated(note = "use Postgres::with_config")]
     pub async fn new(config: &str, pool_size: usize) -> Result<Self, ConnectionError> {
-        let pg_config = Config::from_str(config)?;
-        let mgr_config = ManagerConfig::default();
-        let mgr = Manager::from_config(pg_config, NoTls, mgr_config);
-        // FIXME deadpool's error here is in a private package, so we can't apply Try
-        //       operations
-        let pool = Pool::builder(mgr).max_size(pool_size).build().unwrap();
+        Self::with_config(PostgresConfig::new(config).with_max_connections(pool_size)).await
+    }
+
+    /// with_config initializes Postgres with a pool as described by `config`. Unless
+    /// [PostgresConfig::min_connections] is set, no connection is made until one is needed.
+    pub async fn with_config(config: PostgresConfig) -> Result<Self, ConnectionError> {
+        let pg_config = Self::session_config(&config, &[])?;
+        let pool = Self::build_pool(&config, pg_config)?;
+
+        let mut warm = Vec::new();
+        for _ in 0..config.min_connections.min(config.max_connections) {
+            warm.push(pool.get().await?);
+        }
+        drop(warm);
 
         Ok(Self {
             pool,
-            config: config.to_string(),
+            config: config.dsn,
+            ssl: config.ssl,
+            read: None,
+            tenant: TenantId::default(),
+            schema: config.schema,
+            retry: config.retry,
+        })
+    }
+
+    /// session_config parses the DSN of `config` for the connections of a pool, whose sessions
+    /// start out with each of `settings`, given as `name=value`. If a schema is configured, they
+    /// also start out in it, so no query needs to name it.
+    fn session_config(
+        config: &PostgresConfig,
+        settings: &[&str],
+    ) -> Result<Config, ConnectionError> {
+        let mut pg_config = Config::from_str(&config.dsn)?;
+        if let Some(user) = &config.user {
+            pg_config.user(user);
+        }
+
+        let mut settings = settings
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<String>>();
+
+        if let Some(schema) = &config.schema {
+            if !is_schema_name(schema) {
+                return Err(ConnectionError::Generic(format!(
+                    "invalid schema name: {}",
+                    schema
+                )));
+            }
+
+            settings.push(format!("search_path={}", schema));
+        }
+
+        if !settings.is_empty() {
+            let added = settings
+                .iter()
+                .map(|s| format!("-c {}", s))
+                .collect::<Vec<String>>()
+                .join(" ");
+
+            let options = match pg_config.get_options() {
+                Some(options) => format!("{} {}", options, added),
+                None => added,
+            };
+            pg_config.options(&options);
+        }
+
+        Ok(pg_config)
+    }
+
+    /// build_pool makes the pool described by `config`, of connections configured by
+    /// `pg_config`, over TLS if `config` asks for it. No connection is made yet.
+    fn build_pool(config: &PostgresConfig, mut pg_config: Config) -> Result<Pool, ConnectionError> {
+        let mgr_config = ManagerConfig::default();
+        let mgr = match &config.ssl {
+            Some(ssl) => {
+                ssl.configure(&mut pg_config);
+                Manager::from_config(pg_config, ssl.connector()?, mgr_config)
+            }
+            None => Manager::from_config(pg_config, NoTls, mgr_config),
+        };
+
+        let (idle_timeout, max_lifetime) = (config.idle_timeout, config.max_lifetime);
+        Pool::builder(mgr)
+            .max_size(config.max_connections)
+            .wait_timeout(config.acquire_timeout)
+            .runtime(Runtime::Tokio1)
+            .pre_recycle(Hook::sync_fn(move |_, metrics| {
+                let idle = idle_timeout.map_or(false, |timeout| metrics.last_used() > timeout);
+                let old = max_lifetime.map_or(false, |lifetime| metrics.age() > lifetime);
+
+                if idle || old {
+                    // the connection is dropped, and another taken or opened in its place.
+                    return Err(HookError::Continue(None));
+                }
+
+                Ok(())
+            }))
+            .build()
+            .map_err(|e| ConnectionError::Generic(e.to_string()))
+    }
+
+    /// pool_size returns the most connections the pool will open.
+    pub fn pool_size(&self) -> u32 {
+        self.pool.status().max_size as u32
+    }
+
+    /// connect_direct makes a single connection like [Postgres::connect_one], over TLS if the
+    /// pool uses it. If a schema is configured, it is created if missing and the session is
+    /// moved into it, so migrations land there.
+    async fn connect_direct(&self) -> Result<tokio_postgres::Client, ConnectionError> {
+        let client = match &self.ssl {
+            Some(ssl) => Self::connect_one_with_ssl(&self.config, ssl).await?,
+            None => Self::connect_one(&self.config).await?,
+        };
+
+        if let Some(schema) = &self.schema {
+            client
+                .batch_execute(&format!(
+                    "create schema if not exists {0}; set search_path = {0};",
+                    schema
+                ))
+                .await?;
+        }
+
+        Ok(client)
+    }
+
+    /// new_read_pool creates a [ReadPool] of `pool_size` connections using `config`, which is a
+    /// DSN in the same format accepted by [PostgresConfig::new]. Usually this points at a replica or
+    /// uses a role with only SELECT privileges.
+    #[deprecated(note = "use Postgres::read_pool_with_config")]
+    pub async fn new_read_pool(
+        config: &str,
+        pool_size: usize,
+    ) -> Result<ReadPool, ConnectionError> {
+        Self::read_pool_with_config(PostgresConfig::new(config).with_max_connections(pool_size))
+            .await
+    }
+
+    /// read_pool_with_config creates a [ReadPool] as described by `config`, connecting as the
+    /// primary pool would with the same configuration, TLS and schema included. Usually its DSN
+    /// points at a replica or uses a role with only SELECT privileges.
+    pub async fn read_pool_with_config(
+        config: PostgresConfig,
+    ) -> Result<ReadPool, ConnectionError> {
+        let pg_config = Self::session_config(&config, &["default_transaction_read_only=on"])?;
+
+        Ok(ReadPool {
+            pool: Self::build_pool(&config, pg_config)?,
         })
     }
 
+    /// with_read_pool routes the `get_*` queries through `read` instead of the primary pool.
+    pub fn with_read_pool(mut self, read: ReadPool) -> Self {
+        self.read = Some(read);
+        self
+    }
+
+    /// with_tenant scopes lookups of accounts, orders and authorizations to `tenant`, and records
+    /// it with those created. Clones share the pool, so a handle per tenant is cheap.
+    pub fn with_tenant(mut self, tenant: TenantId) -> Self {
+        self.tenant = tenant;
+        self
+    }
+
+    /// schema returns the schema the tables are kept in, if one was configured.
+    pub(crate) fn schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
+    /// tenant returns the tenant set with [Postgres::with_tenant].
+    pub fn tenant(&self) -> &TenantId {
+        &self.tenant
+    }
+
+    /// retry_policy returns the policy set with [PostgresConfig::with_retry_policy].
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
     /// client returns the db client.
     pub async fn client(self) -> Result<Object, ConnectionError> {
         Ok(self.pool.get().await?)
     }
 
+    /// read_client returns a client from the read pool if one is configured, and from the primary
+    /// pool otherwise. Only use it for queries which tolerate replication lag.
+    pub(crate) async fn read_client(&self) -> Result<Object, ConnectionError> {
+        match &self.read {
+            Some(read) => read.client().await,
+            None => Ok(self.pool.get().await?),
+        }
+    }
+
+    /// health_check runs `SELECT 1` on a pooled connection, failing if the database cannot be
+    /// reached or does not answer within a couple of seconds. It is cheap enough for liveness
+    /// probes.
+    pub async fn health_check(&self) -> Result<(), ConnectionError> {
+        let check = async {
+            let client = self.pool.get().await?;
+            client.simple_query("select 1").await?;
+            Ok::<(), ConnectionError>(())
+        };
+
+        match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, check).await {
+            Ok(res) => res,
+            Err(_) => Err(ConnectionError::Timeout(HEALTH_CHECK_TIMEOUT)),
+        }
+    }
+
+    /// pool_stats returns the current state of the connection pool.
+    pub fn pool_stats(&self) -> PoolStats {
+        let status = self.pool.status();
+        PoolStats {
+            max_size: status.max_size,
+            size: status.size,
+            available: status.available,
+        }
+    }
+
     /// migrate the database. The migration implementation is refinery and the migrations live in
     /// `migrations/` off the root of the repository, but are otherwise compiled into the library.
+    ///
+    /// Progress is logged at info level before and after each migration, and a warning is logged
+    /// for any migration taking longer than 30 seconds. Losing a race with another instance
+    /// migrating the same database is not an error.
     pub async fn migrate(&self) -> Result<Report, MigrationError> {
-        let mut c = Self::connect_one(&self.config).await?;
-        let report = migrations::migrations::runner().run_async(&mut c).await?;
-        Ok(report)
+        let res = self
+            .migrate_with_progress(|progress| match progress {
+                MigrationProgress::Applying { index, total, name } => {
+                    log::info!("Applying migration {} of {}: {}", index, total, name)
+                }
+                MigrationProgress::Applied {
+                    index,
+                    total,
+                    name,
+                    elapsed,
+                } => {
+                    if elapsed > SLOW_MIGRATION_THRESHOLD {
+                        log::warn!(
+                            "Migration {} of {} ({}) was slow: took {:?}",
+                            index,
+                            total,
+                            name,
+                            elapsed
+                        )
+                    } else {
+                        log::info!(
+                            "Applied migration {} of {}: {} in {:?}",
+                            index,
+                            total,
+                            name,
+                            elapsed
+                        )
+                    }
+                }
+            })
+            .await;
+
+        match res {
+            Err(MigrationError::AlreadyCurrent) => {
+                log::info!("Database schema was migrated by another instance");
+                Ok(Report::new(Vec::new()))
+            }
+            res => res,
+        }
+    }
+
+    /// migrate the database like [Postgres::migrate], but hand each [MigrationProgress] event to
+    /// `f` instead of logging it. Migrations are applied one at a time, in version order.
+    ///
+    /// A migration failing because the objects it creates already exist, with no migrations left
+    /// pending afterwards, yields [MigrationError::AlreadyCurrent]. Other failures after some
+    /// migrations were applied yield [MigrationError::PartialMigration].
+    pub async fn migrate_with_progress<F>(&self, mut f: F) -> Result<Report, MigrationError>
+    where
+        F: FnMut(MigrationProgress),
+    {
+        let mut c = self.connect_direct().await?;
+        let pending = Self::pending(&mut c).await?;
+
+        let total = pending.len();
+        let mut applied = Vec::new();
+
+        for (i, migration) in pending.into_iter().enumerate() {
+            let index = i + 1;
+            let name = migration.to_string();
+
+            f(MigrationProgress::Applying {
+                index,
+                total,
+                name: name.clone(),
+            });
+
+            let start = Instant::now();
+            let report = match migrations::migrations::runner()
+                .set_target(Target::Version(migration.version()))
+                .run_async(&mut c)
+                .await
+            {
+                Ok(report) => report,
+                Err(e) => {
+                    if is_duplicate_object(&e) && Self::pending(&mut c).await?.is_empty() {
+                        return Err(MigrationError::AlreadyCurrent);
+                    }
+
+                    if applied.is_empty() {
+                        return Err(e.into());
+                    }
+
+                    return Err(MigrationError::PartialMigration {
+                        applied: applied.iter().map(Migration::to_string).collect(),
+                        failed: name,
+                        source: e,
+                    });
+                }
+            };
+
+            f(MigrationProgress::Applied {
+                index,
+                total,
+                name,
+                elapsed: start.elapsed(),
+            });
+
+            applied.extend_from_slice(report.applied_migrations());
+        }
+
+        Ok(Report::new(applied))
     }
 
-    /// resets the database, destroying all data in the public schema.
-    /// useful for tests.
+    /// list the migrations which have not yet been applied, in the order [Postgres::migrate] would
+    /// apply them. The database is not modified.
+    pub async fn pending_migrations(&self) -> Result<Vec<MigrationVersion>, MigrationError> {
+        let mut c = self.connect_direct().await?;
+        Ok(Self::pending(&mut c)
+            .await?
+            .iter()
+            .map(MigrationVersion::from)
+            .collect())
+    }
+
+    /// return the SQL of each pending migration, in the order [Postgres::migrate] would run it,
+    /// without running any of it.
+    pub async fn migrate_dry_run(&self) -> Result<Vec<String>, MigrationError> {
+        let mut c = self.connect_direct().await?;
+        Ok(Self::pending(&mut c)
+            .await?
+            .iter()
+            .map(|m| m.sql().unwrap_or_default().to_string())
+            .collect())
+    }
+
+    /// the embedded migrations newer than the last one applied to the database.
+    async fn pending(c: &mut tokio_postgres::Client) -> Result<Vec<Migration>, MigrationError> {
+        let runner = migrations::migrations::runner();
+        let last = runner
+            .get_last_applied_migration_async(c)
+            .await?
+            .map(|m| m.version());
+
+        Ok(runner
+            .get_migrations()
+            .iter()
+            .filter(|m| last.map_or(true, |last| m.version() > last))
+            .cloned()
+            .collect())
+    }
+
+    /// resets the database, destroying all data in the configured schema, or the public schema
+    /// if there is none. useful for tests.
     #[cfg(test)]
     pub(crate) async fn reset(&self) -> Result<(), SaveError> {
-        let c = Self::connect_one(&self.config).await?;
-        c.execute("drop schema public cascade", &[]).await?;
-        c.execute("create schema public", &[]).await?;
+        let schema = self.schema.as_deref().unwrap_or("public");
+        let c = self.connect_direct().await?;
+        c.execute(&format!("drop schema {} cascade", schema), &[])
+            .await?;
+        c.execute(&format!("create schema {}", schema), &[]).await?;
+        Ok(())
+    }
+
+    /// connect_again makes another Postgres like this one, with a pool of its own.
+    #[cfg(test)]
+    pub(crate) async fn connect_again(&self) -> Result<Self, ConnectionError> {
+        let mut config = PostgresConfig::new(&self.config);
+        config.ssl = self.ssl.clone();
+        config.schema = self.schema.clone();
+        config.retry = self.retry.clone();
+        Self::with_config(config).await
+    }
+
+    /// drop_schema destroys the configured schema and everything in it; without one, it does
+    /// nothing. Tests sharing a database use it to clean up after themselves.
+    #[cfg(test)]
+    pub(crate) async fn drop_schema(&self) -> Result<(), SaveError> {
+        if let Some(schema) = &self.schema {
+            self.connect_direct()
+                .await?
+                .execute(&format!("drop schema if exists {} cascade", schema), &[])
+                .await?;
+        }
+
         Ok(())
     }
 }
@@ -132,6 +993,183 @@ where
     async fn exists(&self, id: FK, tx: &Transaction<'_>) -> Result<bool, LoadError>;
 }
 
+/// Storage is the interface to the accounts, orders, authorizations, challenges and nonces kept
+/// by the service, independent of where they are kept. It is implemented by [Postgres] and by
+/// [memory::MemoryStore]; see the inherent methods of [Postgres] for the details of each.
+///
+/// Records are otherwise loaded and saved with [Record] and [RecordList], which require Postgres.
+#[async_trait]
+pub trait Storage {
+    /// for_tenant returns the storage as seen by `tenant`, whose records are kept apart from
+    /// those of other tenants; see [Postgres::with_tenant].
+    fn for_tenant(&self, tenant: &TenantId) -> Arc<dyn Storage + Send + Sync>;
+
+    /// stores a nonce for later consumption.
+    async fn insert_nonce(&self, nonce: &str) -> Result<(), SaveError>;
+    /// returns the number of outstanding nonces.
+    async fn nonce_count(&self) -> Result<i64, LoadError>;
+    /// removes the nonce, reporting whether it was outstanding and issued within `ttl`.
+    async fn consume_nonce_with_ttl(
+        &self,
+        nonce: &str,
+        ttl: Duration,
+    ) -> Result<NonceState, SaveError>;
+    /// removes nonces issued more than `ttl` ago, returning how many were removed.
+    async fn delete_expired_nonces(&self, ttl: Duration) -> Result<u64, SaveError>;
+
+    /// saves a new account, returning its id.
+    async fn create_account(&self, account: &mut Account) -> Result<i32, SaveError>;
+    /// appends a key change to the account's key history.
+    async fn record_key_rollover(
+        &self,
+        account_id: i32,
+        old: &str,
+        new: &str,
+    ) -> Result<(), SaveError>;
+    /// returns the key changes for the account, oldest first.
+    async fn get_key_history(&self, account_id: i32) -> Result<Vec<KeyRollover>, LoadError>;
+    /// reports whether the public key of `jwk` belonged to an account which has since been
+    /// deactivated.
+    async fn account_deactivated_for_key(&self, jwk: &JWK) -> Result<bool, LoadError>;
+
+    /// saves a new order, returning its id.
+    async fn create_order(&self, order: &mut Order) -> Result<i32, SaveError>;
+    /// tallies the orders by their current status.
+    async fn order_status_counts(&self) -> Result<HashMap<String, usize>, LoadError>;
+
+    /// saves a new authorization, returning its id.
+    async fn create_authorization(&self, authz: &mut Authorization) -> Result<i32, SaveError>;
+    /// returns the authorization with the provided reference.
+    async fn get_authorization(&self, reference: &str) -> Result<Authorization, LoadError>;
+    /// sets the status of the authorization's challenges if it is still at `expected_version`,
+    /// returning the new version.
+    async fn update_authorization_status(
+        &self,
+        reference: &str,
+        status: OrderStatus,
+        expected_version: i64,
+    ) -> Result<i64, SaveError>;
+
+    /// returns a validated, unexpired authorization for the identifier from an earlier order of
+    /// the account, if there is one.
+    async fn find_reusable_authorization(
+        &self,
+        account_id: i32,
+        kind: &str,
+        identifier: &str,
+    ) -> Result<Option<Authorization>, LoadError>;
+    /// adds the authorization, created for another order, to the authorizations of `order_id`.
+    async fn link_authorization(&self, order_id: &str, reference: &str) -> Result<(), SaveError>;
+
+    /// saves a new challenge, returning its id.
+    async fn create_challenge(&self, challenge: &mut Challenge) -> Result<i32, SaveError>;
+    /// returns the most recent challenge of `challenge_type` for the authorization `auth_id`,
+    /// with its authorization and order context.
+    async fn get_challenge_with_context(
+        &self,
+        auth_id: &str,
+        challenge_type: &str,
+    ) -> Result<Option<ChallengeWithContext>, LoadError>;
+}
+
+#[async_trait]
+impl Storage for Postgres {
+    fn for_tenant(&self, tenant: &TenantId) -> Arc<dyn Storage + Send + Sync> {
+        Arc::new(self.clone().with_tenant(tenant.clone()))
+    }
+
+    async fn insert_nonce(&self, nonce: &str) -> Result<(), SaveError> {
+        Postgres::insert_nonce(self, nonce).await
+    }
+
+    async fn nonce_count(&self) -> Result<i64, LoadError> {
+        Postgres::nonce_count(self).await
+    }
+
+    async fn consume_nonce_with_ttl(
+        &self,
+        nonce: &str,
+        ttl: Duration,
+    ) -> Result<NonceState, SaveError> {
+        Postgres::consume_nonce_with_ttl(self, nonce, ttl).await
+    }
+
+    async fn delete_expired_nonces(&self, ttl: Duration) -> Result<u64, SaveError> {
+        Postgres::delete_expired_nonces(self, ttl).await
+    }
+
+    async fn create_account(&self, account: &mut Account) -> Result<i32, SaveError> {
+        account.create(self.clone()).await
+    }
+
+    async fn record_key_rollover(
+        &self,
+        account_id: i32,
+        old: &str,
+        new: &str,
+    ) -> Result<(), SaveError> {
+        Postgres::record_key_rollover(self, account_id, old, new).await
+    }
+
+    async fn get_key_history(&self, account_id: i32) -> Result<Vec<KeyRollover>, LoadError> {
+        Postgres::get_key_history(self, account_id).await
+    }
+
+    async fn account_deactivated_for_key(&self, jwk: &JWK) -> Result<bool, LoadError> {
+        Account::deactivated_for_key(jwk, self.clone()).await
+    }
+
+    async fn create_order(&self, order: &mut Order) -> Result<i32, SaveError> {
+        order.create(self.clone()).await
+    }
+
+    async fn order_status_counts(&self) -> Result<HashMap<String, usize>, LoadError> {
+        Postgres::order_status_counts(self).await
+    }
+
+    async fn create_authorization(&self, authz: &mut Authorization) -> Result<i32, SaveError> {
+        authz.create(self.clone()).await
+    }
+
+    async fn get_authorization(&self, reference: &str) -> Result<Authorization, LoadError> {
+        Postgres::get_authorization(self, reference).await
+    }
+
+    async fn update_authorization_status(
+        &self,
+        reference: &str,
+        status: OrderStatus,
+        expected_version: i64,
+    ) -> Result<i64, SaveError> {
+        Postgres::update_authorization_status(self, reference, status, expected_version).await
+    }
+
+    async fn find_reusable_authorization(
+        &self,
+        account_id: i32,
+        kind: &str,
+        identifier: &str,
+    ) -> Result<Option<Authorization>, LoadError> {
+        Postgres::find_reusable_authorization(self, account_id, kind, identifier).await
+    }
+
+    async fn link_authorization(&self, order_id: &str, reference: &str) -> Result<(), SaveError> {
+        Postgres::link_authorization(self, order_id, reference).await
+    }
+
+    async fn create_challenge(&self, challenge: &mut Challenge) -> Result<i32, SaveError> {
+        challenge.create(self.clone()).await
+    }
+
+    async fn get_challenge_with_context(
+        &self,
+        auth_id: &str,
+        challenge_type: &str,
+    ) -> Result<Option<ChallengeWithContext>, LoadError> {
+        Postgres::get_challenge_with_context(self, auth_id, challenge_type).await
+    }
+}
+
 mod tests {
     #[tokio::test(flavor = "multi_thread")]
     async fn test_migrate() {
@@ -147,4 +1185,478 @@ mod tests {
         let report = db.migrate().await.unwrap();
         assert_that!(report.applied_migrations().len()).is_equal_to(0);
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_migrate_progress() {
+        use super::{migrations, MigrationProgress};
+        use crate::test::PGTest;
+        use spectral::prelude::*;
+
+        let pg = PGTest::new("test_migrate_progress").await.unwrap();
+        let db = pg.db();
+        db.reset().await.unwrap();
+
+        let mut events = Vec::new();
+        let report = db
+            .migrate_with_progress(|progress| events.push(progress))
+            .await
+            .unwrap();
+
+        let expected = migrations::migrations::runner()
+            .get_migrations()
+            .iter()
+            .map(|m| m.to_string())
+            .collect::<Vec<String>>();
+        let total = expected.len();
+
+        assert_that!(report.applied_migrations().len()).is_equal_to(total);
+        assert_that!(events.len()).is_equal_to(total * 2);
+
+        for (i, name) in expected.iter().enumerate() {
+            match &events[i * 2] {
+                MigrationProgress::Applying {
+                    index,
+                    total: t,
+                    name: n,
+                } => {
+                    assert_that!(*index).is_equal_to(i + 1);
+                    assert_that!(*t).is_equal_to(total);
+                    assert_that!(n).is_equal_to(name);
+                }
+                other => panic!("expected Applying, got {:?}", other),
+            }
+
+            match &events[i * 2 + 1] {
+                MigrationProgress::Applied {
+                    index,
+                    total: t,
+                    name: n,
+                    ..
+                } => {
+                    assert_that!(*index).is_equal_to(i + 1);
+                    assert_that!(*t).is_equal_to(total);
+                    assert_that!(n).is_equal_to(name);
+                }
+                other => panic!("expected Applied, got {:?}", other),
+            }
+        }
+
+        let mut events = Vec::new();
+        let report = db
+            .migrate_with_progress(|progress| events.push(progress))
+            .await
+            .unwrap();
+        assert_that!(report.applied_migrations().len()).is_equal_to(0);
+        assert_that!(events).is_empty();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_pending_migrations() {
+        use super::{migrations, MigrationVersion};
+        use crate::test::PGTest;
+        use spectral::prelude::*;
+
+        let pg = PGTest::new("test_pending_migrations").await.unwrap();
+        let db = pg.db();
+        db.reset().await.unwrap();
+
+        let all = migrations::migrations::runner().get_migrations().clone();
+
+        let pending = db.pending_migrations().await.unwrap();
+        let expected = all
+            .iter()
+            .map(MigrationVersion::from)
+            .collect::<Vec<MigrationVersion>>();
+        assert_that!(pending).is_equal_to(expected);
+        assert_that!(pending[0].version()).is_equal_to(1);
+
+        let sql = db.migrate_dry_run().await.unwrap();
+        assert_that!(sql.len()).is_equal_to(all.len());
+        assert_that!(sql[0]).is_equal_to(all[0].sql().unwrap().to_string());
+
+        // a dry run must not apply anything.
+        assert_that!(db.pending_migrations().await.unwrap().len()).is_equal_to(all.len());
+
+        db.migrate().await.unwrap();
+        assert_that!(db.pending_migrations().await.unwrap()).is_empty();
+        assert_that!(db.migrate_dry_run().await.unwrap()).is_empty();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_migration_errors() {
+        use super::{is_duplicate_object, Postgres, PostgresConfig};
+        use crate::errors::db::MigrationError;
+        use crate::test::PGTest;
+        use spectral::prelude::*;
+
+        let pg = PGTest::new("test_migration_errors").await.unwrap();
+        let db = pg.db();
+
+        // forget V14 and V15 were applied, and undo V14 only: V14 applies again, but V15 finds
+        // its table already there.
+        let c = db.connect_direct().await.unwrap();
+        c.batch_execute(
+            "
+            delete from refinery_schema_history where version >= 14;
+            alter table jwks drop column tenant_id cascade;
+            alter table accounts drop column tenant_id cascade;
+            alter table orders drop column tenant_id cascade;
+            alter table orders_archive drop column tenant_id cascade;
+            alter table orders_authorizations drop column tenant_id cascade;
+            ",
+        )
+        .await
+        .unwrap();
+
+        match db.migrate().await {
+            Err(MigrationError::PartialMigration {
+                applied,
+                failed,
+                source,
+            }) => {
+                assert_that!(applied).is_equal_to(vec!["V14__tenants".to_string()]);
+                assert_that!(failed).is_equal_to("V15__failed_authorizations".to_string());
+                assert_that!(is_duplicate_object(&source)).is_true();
+            }
+            res => panic!("expected a partial migration, got {:?}", res.map(|_| ())),
+        }
+
+        // with nothing left applied this run, the failure is reported as it was.
+        match db.migrate().await {
+            Err(MigrationError::Error(e)) => assert_that!(is_duplicate_object(&e)).is_true(),
+            res => panic!("expected a migration error, got {:?}", res.map(|_| ())),
+        }
+
+        let broken = Postgres::with_config(PostgresConfig::new(
+            "host=/nonexistent dbname=coyote user=postgres connect_timeout=1",
+        ))
+        .await
+        .unwrap();
+
+        match broken.migrate().await {
+            Err(MigrationError::ConnectionFailed(_)) => {}
+            res => panic!("expected a connection failure, got {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_health_check() {
+        use super::{Postgres, PostgresConfig};
+        use crate::test::PGTest;
+        use spectral::prelude::*;
+
+        let pg = PGTest::new("test_health_check").await.unwrap();
+        assert_that!(pg.db().health_check().await).is_ok();
+
+        // nothing listens here, so the pool can never hand out a connection.
+        let broken = Postgres::with_config(
+            PostgresConfig::new("host=/nonexistent dbname=coyote user=postgres connect_timeout=1")
+                .with_max_connections(1),
+        )
+        .await
+        .unwrap();
+        assert_that!(broken.health_check().await).is_err();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_postgres_config() {
+        use super::{Postgres, PostgresConfig, DEFAULT_MAX_CONNECTIONS};
+        use crate::test::PGTest;
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        let pg = PGTest::new("test_postgres_config").await.unwrap();
+
+        let config = PostgresConfig::new(&pg.db().config);
+        assert_that!(config.max_connections).is_equal_to(DEFAULT_MAX_CONNECTIONS);
+        assert_that!(config.min_connections).is_equal_to(0);
+
+        let mut config = config
+            .with_max_connections(4)
+            .with_min_connections(2)
+            .with_idle_timeout(Some(Duration::from_millis(100)));
+        config.ssl = pg.db().ssl.clone();
+
+        let db = Postgres::with_config(config).await.unwrap();
+        assert_that!(db.pool_size()).is_equal_to(4);
+
+        let stats = db.pool_stats();
+        assert_that!(stats.size).is_equal_to(2);
+        assert_that!(stats.available).is_equal_to(2);
+
+        // idle connections are replaced rather than handed out.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_that!(db.health_check().await).is_ok();
+        assert_that!(db.pool_stats().size).is_less_than_or_equal_to(2);
+    }
+
+    /// proxy forwards connections on a local port to the database of `db`, but drops the next
+    /// connections at once while `refuse` is above zero, counting it down, as a restarting
+    /// server would. It returns the DSN to connect through it with.
+    #[cfg(test)]
+    async fn proxy(
+        db: &super::Postgres,
+        refuse: std::sync::Arc<std::sync::atomic::AtomicU32>,
+    ) -> String {
+        use std::str::FromStr;
+        use std::sync::atomic::Ordering;
+        use tokio::net::{TcpListener, TcpStream, UnixStream};
+        use tokio_postgres::config::{Config, Host};
+
+        let config = Config::from_str(&db.config).unwrap();
+        let upstream = config.get_hosts()[0].clone();
+        let port = config.get_ports().first().copied().unwrap_or(5432);
+
+        let lis = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut dsn = format!(
+            "host=127.0.0.1 port={} user={} dbname={}",
+            lis.local_addr().unwrap().port(),
+            config.get_user().unwrap(),
+            config.get_dbname().unwrap_or(config.get_user().unwrap()),
+        );
+        if let Some(password) = config.get_password() {
+            dsn = format!("{} password={}", dsn, String::from_utf8_lossy(password));
+        }
+
+        tokio::spawn(async move {
+            loop {
+                let (mut client, _) = lis.accept().await.unwrap();
+                if refuse
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                    .is_ok()
+                {
+                    continue;
+                }
+
+                let upstream = upstream.clone();
+                tokio::spawn(async move {
+                    let _ = match upstream {
+                        Host::Tcp(host) => {
+                            let mut server =
+                                TcpStream::connect((host.as_str(), port)).await.unwrap();
+                            tokio::io::copy_bidirectional(&mut client, &mut server).await
+                        }
+                        Host::Unix(dir) => {
+                            let path = dir.join(format!(".s.PGSQL.{}", port));
+                            let mut server = UnixStream::connect(path).await.unwrap();
+                            tokio::io::copy_bidirectional(&mut client, &mut server).await
+                        }
+                    };
+                });
+            }
+        });
+
+        dsn
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_retry() {
+        use super::{with_retry, Postgres, PostgresConfig, RetryPolicy};
+        use crate::errors::db::{LoadError, Transient};
+        use crate::test::PGTest;
+        use spectral::prelude::*;
+        use std::sync::atomic::{AtomicU32, Ordering};
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let pg = PGTest::new("test_retry").await.unwrap();
+        let refuse = Arc::new(AtomicU32::new(0));
+        let dsn = proxy(&pg.db(), refuse.clone()).await;
+
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(50),
+        };
+
+        let mut config = PostgresConfig::new(&dsn).with_retry_policy(policy.clone());
+        config.ssl = pg.db().ssl.clone();
+        config.schema = pg.db().schema.clone();
+        let db = Postgres::with_config(config).await.unwrap();
+
+        db.insert_nonce("transient").await.unwrap();
+        db.insert_nonce("unreachable").await.unwrap();
+
+        // the pool has to open a new connection, and the first two attempts are dropped.
+        let db = db.connect_again().await.unwrap();
+        refuse.store(2, Ordering::SeqCst);
+        assert_that!(db.consume_nonce("transient").await).is_ok_containing(true);
+        assert_that!(refuse.load(Ordering::SeqCst)).is_equal_to(0);
+
+        // but not past the policy's attempts.
+        let db = db.connect_again().await.unwrap();
+        refuse.store(5, Ordering::SeqCst);
+        let res = db.consume_nonce("unreachable").await;
+        assert_that!(res.is_err()).is_true();
+        assert_that!(res.unwrap_err().is_transient()).is_true();
+        assert_that!(refuse.load(Ordering::SeqCst)).is_equal_to(2);
+
+        // orders are looked up the same way.
+        let db = db.connect_again().await.unwrap();
+        refuse.store(2, Ordering::SeqCst);
+        let res =
+            crate::models::order::Order::find_by_reference("nope".to_string(), db.clone()).await;
+        assert_that!(matches!(res, Err(LoadError::NotFound))).is_true();
+
+        // errors of the statement rather than the connection are returned at once.
+        let mut calls = 0;
+        let res: Result<(), LoadError> = with_retry(&policy, || {
+            calls += 1;
+            async { Err(LoadError::NotFound) }
+        })
+        .await;
+        assert_that!(res.is_err()).is_true();
+        assert_that!(calls).is_equal_to(1);
+
+        // the delay doubles up to the limit, less up to half of it.
+        for (attempt, full) in [(1, 10), (2, 20), (3, 40), (4, 50), (40, 50)] {
+            let delay = policy.delay(attempt);
+            assert_that!(delay).is_less_than_or_equal_to(Duration::from_millis(full));
+            assert_that!(delay).is_greater_than_or_equal_to(Duration::from_millis(full) / 2);
+        }
+
+        assert_that!(RetryPolicy::none().max_attempts).is_equal_to(1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_read_pool() {
+        use super::{Postgres, PostgresConfig};
+        use crate::errors::db::LoadError;
+        use crate::test::PGTest;
+        use spectral::prelude::*;
+
+        let pg = PGTest::new("test_read_pool").await.unwrap();
+        let db = pg.db();
+
+        db.insert_nonce("read-pool").await.unwrap();
+        db.record_key_rollover(1, "old", "new").await.unwrap();
+
+        let schema = db.schema.clone().unwrap();
+        let config = db.config.clone();
+        let role = pg.create_role("ro").await.unwrap();
+
+        let c = db.clone().client().await.unwrap();
+        c.batch_execute(&format!(
+            "
+            grant usage on schema {0} to {1};
+            grant select on all tables in schema {0} to {1};
+            ",
+            schema, role
+        ))
+        .await
+        .unwrap();
+
+        // the read pools connect over TLS when the primary does, and read from the schema of the
+        // test as it writes to it.
+        let read_config = |dsn: &str| {
+            PostgresConfig {
+                ssl: db.ssl.clone(),
+                ..PostgresConfig::new(dsn).with_max_connections(5)
+            }
+            .with_schema(schema.clone())
+        };
+
+        let read = Postgres::read_pool_with_config(read_config(&config).with_user(role.clone()))
+            .await
+            .unwrap();
+
+        let rows = read
+            .query("select count(*) from nonces", &[])
+            .await
+            .unwrap();
+        assert_that!(rows[0].get::<_, i64>(0)).is_equal_to(1);
+
+        for statement in [
+            "insert into nonces (nonce) values ('denied')",
+            "delete from nonces",
+        ] {
+            match read.query(statement, &[]).await {
+                Err(LoadError::Permissions(_)) => {}
+                other => panic!("expected a permissions error, got {:?}", other.map(|_| ())),
+            }
+        }
+
+        // even a role which may write is read-only through a read pool
+        let superuser = Postgres::read_pool_with_config(read_config(&config))
+            .await
+            .unwrap();
+        assert_that!(matches!(
+            superuser.query("delete from nonces", &[]).await,
+            Err(LoadError::Permissions(_))
+        ))
+        .is_true();
+
+        let db = db.with_read_pool(read);
+        let history = db.get_key_history(1).await.unwrap();
+        assert_that!(history.len()).is_equal_to(1);
+        assert_that!(db.nonce_count().await.unwrap()).is_equal_to(1);
+
+        // the connections of the read pool are closed before their role is dropped.
+        drop(db);
+        pg.teardown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_schema_names() {
+        use super::{is_schema_name, Postgres, PostgresConfig};
+        use spectral::prelude::*;
+
+        for schema in ["coyote", "coyote_0a1b", "_tests"] {
+            assert_that!(is_schema_name(schema)).is_true();
+        }
+
+        for schema in ["", "Coyote", "0coyote", "coyote; drop table nonces", "a-b"] {
+            assert_that!(is_schema_name(schema)).is_false();
+        }
+        assert_that!(is_schema_name(&"a".repeat(64))).is_false();
+
+        // refused before any connection is made.
+        assert_that!(Postgres::with_config(
+            PostgresConfig::new("host=localhost user=postgres").with_schema("public; drop")
+        )
+        .await
+        .is_err())
+        .is_true();
+    }
+
+    #[test]
+    fn test_ssl_config() {
+        use super::{SslConfig, SslVerifyMode};
+        use crate::errors::db::ConnectionError;
+        use crate::test::write_ssl_certificates;
+        use spectral::prelude::*;
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = write_ssl_certificates(dir.path());
+        assert_that!(config.ca_cert).is_equal_to(dir.path().join("ca.pem"));
+        assert_that!(config.verify_mode).is_equal_to(SslVerifyMode::VerifyFull);
+        assert_that!(config.client_cert).is_none();
+        assert_that!(config.connector().is_ok()).is_true();
+
+        let config = config
+            .with_client_cert(dir.path().join("server.crt"), dir.path().join("server.key"))
+            .with_verify_mode(SslVerifyMode::VerifyCa);
+        assert_that!(config.client_cert).is_equal_to(Some(dir.path().join("server.crt")));
+        assert_that!(config.client_key).is_equal_to(Some(dir.path().join("server.key")));
+        assert_that!(config.verify_mode).is_equal_to(SslVerifyMode::VerifyCa);
+        assert_that!(config.connector().is_ok()).is_true();
+
+        // a certificate without its key is refused.
+        let mut partial = config.clone();
+        partial.client_key = None;
+        assert_that!(partial.connector().is_err()).is_true();
+
+        // the key must belong to the certificate.
+        let mismatched =
+            config.with_client_cert(dir.path().join("ca.pem"), dir.path().join("server.key"));
+        assert_that!(matches!(
+            mismatched.connector(),
+            Err(ConnectionError::Tls(_))
+        ))
+        .is_true();
+
+        let missing =
+            SslConfig::new(dir.path().join("missing.pem")).with_verify_mode(SslVerifyMode::Require);
+        assert_that!(matches!(missing.connector(), Err(ConnectionError::Tls(_)))).is_true();
+    }
 }
@@ -1,7 +1,9 @@
-use super::{LoadError, Record, Postgres, SaveError};
+use std::time::Duration;
+
+use super::{with_retry, LoadError, Postgres, Record, SaveError};
 use crate::util::make_nonce;
 use async_trait::async_trait;
-use tokio_postgres::{Row, Transaction};
+use tokio_postgres::{error::SqlState, IsolationLevel, Row, Transaction};
 
 #[derive(Clone)]
 pub struct Nonce {
@@ -28,6 +30,160 @@ impl Nonce {
     }
 }
 
+fn is_serialization_failure(e: &tokio_postgres::Error) -> bool {
+    e.code() == Some(&SqlState::T_R_SERIALIZATION_FAILURE)
+}
+
+/// NonceState is the outcome of consuming a nonce with [Postgres::consume_nonce_with_ttl].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NonceState {
+    /// the nonce was outstanding and has now been consumed.
+    Valid,
+    /// the nonce was outstanding, but was issued longer ago than its TTL. It has been consumed
+    /// all the same.
+    Expired,
+    /// the nonce was never issued, or has already been consumed.
+    NotFound,
+}
+
+impl Postgres {
+    /// insert_nonce stores a nonce for later consumption.
+    pub async fn insert_nonce(&self, nonce: &str) -> Result<(), SaveError> {
+        let mut db = self.clone().client().await?;
+        let tx = db.transaction().await?;
+        tx.execute("insert into nonces (nonce) values ($1)", &[&nonce])
+            .await?;
+        Ok(tx.commit().await?)
+    }
+
+    /// insert_nonces stores many nonces in a single statement, returning those stored.
+    pub async fn insert_nonces(&self, nonces: &[String]) -> Result<Vec<String>, SaveError> {
+        let db = self.clone().client().await?;
+        let rows = db
+            .query(
+                "insert into nonces (nonce) select unnest($1::varchar[]) returning nonce",
+                &[&nonces],
+            )
+            .await?;
+
+        Ok(rows.iter().map(|row| row.get("nonce")).collect())
+    }
+
+    /// nonce_count returns the number of outstanding nonces.
+    pub async fn nonce_count(&self) -> Result<i64, LoadError> {
+        let db = self.clone().client().await?;
+        let row = db.query_one("select count(*) from nonces", &[]).await?;
+        Ok(row.get(0))
+    }
+
+    /// nonce_exists returns true if the nonce is outstanding. It is answered by the read pool if
+    /// one is configured, so it may lag behind nonces issued or consumed just now.
+    pub async fn nonce_exists(&self, nonce: &str) -> Result<bool, LoadError> {
+        let db = self.read_client().await?;
+        let row = db
+            .query_one(
+                "select exists(select 1 from nonces where nonce = $1)",
+                &[&nonce],
+            )
+            .await?;
+        Ok(row.get(0))
+    }
+
+    /// consume_nonce removes the nonce from storage, returning true if this call was the one that
+    /// removed it.
+    pub async fn consume_nonce(&self, nonce: &str) -> Result<bool, SaveError> {
+        Ok(self.take_nonce(nonce, None).await?.is_some())
+    }
+
+    /// consume_nonce_with_ttl is like [Postgres::consume_nonce], but also reports nonces issued
+    /// more than `ttl` ago as expired. The age is measured by the database clock, which
+    /// `created_at` is set by.
+    pub async fn consume_nonce_with_ttl(
+        &self,
+        nonce: &str,
+        ttl: Duration,
+    ) -> Result<NonceState, SaveError> {
+        Ok(match self.take_nonce(nonce, Some(ttl)).await? {
+            Some(true) => NonceState::Expired,
+            Some(false) => NonceState::Valid,
+            None => NonceState::NotFound,
+        })
+    }
+
+    /// delete_expired_nonces removes nonces issued more than `ttl` ago, returning how many were
+    /// removed. The cutoff is taken from the database clock, which `created_at` is set by.
+    pub async fn delete_expired_nonces(&self, ttl: Duration) -> Result<u64, SaveError> {
+        let ttl = format!("{} microseconds", ttl.as_micros());
+
+        let db = self.clone().client().await?;
+        Ok(db
+            .execute(
+                "delete from nonces where created_at < now() - $1::text::interval",
+                &[&ttl],
+            )
+            .await?)
+    }
+
+    /// vacuum_nonces removes nonces issued more than `older_than` ago, returning how many were
+    /// removed. Nonces are deleted as they are consumed, so what piles up are those handed out
+    /// but never used; `older_than` may well be longer than the TTL nonces are validated with.
+    pub async fn vacuum_nonces(&self, older_than: Duration) -> Result<u64, SaveError> {
+        self.delete_expired_nonces(older_than).await
+    }
+
+    /// take_nonce removes the nonce from storage if this call was the one that removed it,
+    /// returning whether it was issued more than `ttl` ago; without a `ttl` it never is. The
+    /// transaction is serializable so that two concurrent requests presenting the same nonce
+    /// cannot both succeed.
+    ///
+    /// It is retried while the database cannot be reached; see [with_retry]. Should the
+    /// connection be lost as the nonce is taken, the retry does not find it, and the client is
+    /// asked for a fresh nonce as for any other it cannot use.
+    async fn take_nonce(
+        &self,
+        nonce: &str,
+        ttl: Option<Duration>,
+    ) -> Result<Option<bool>, SaveError> {
+        let ttl = ttl.map(|ttl| format!("{} microseconds", ttl.as_micros()));
+        with_retry(self.retry_policy(), || self.try_take_nonce(nonce, &ttl)).await
+    }
+
+    /// try_take_nonce is a single attempt of [Postgres::take_nonce].
+    async fn try_take_nonce(
+        &self,
+        nonce: &str,
+        ttl: &Option<String>,
+    ) -> Result<Option<bool>, SaveError> {
+        let mut db = self.clone().client().await?;
+        let tx = db
+            .build_transaction()
+            .isolation_level(IsolationLevel::Serializable)
+            .start()
+            .await?;
+
+        let row = match tx
+            .query_opt(
+                "
+                delete from nonces where nonce = $1
+                returning coalesce(created_at < now() - $2::text::interval, false) as expired
+                ",
+                &[&nonce, ttl],
+            )
+            .await
+        {
+            Ok(row) => row,
+            Err(e) if is_serialization_failure(&e) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        match tx.commit().await {
+            Ok(_) => Ok(row.map(|row| row.get("expired"))),
+            Err(e) if is_serialization_failure(&e) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
 #[async_trait]
 impl Record<String> for Nonce {
     async fn new_from_row(row: &Row, _tx: &Transaction<'_>) -> Result<Self, LoadError> {
@@ -114,4 +270,41 @@ mod tests {
         let res = Nonce::find(found.id().unwrap().unwrap(), db.clone()).await;
         assert_that!(res).is_err();
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn nonce_consume_concurrent() {
+        use spectral::prelude::*;
+
+        use crate::test::PGTest;
+        use crate::util::make_nonce;
+
+        let pg = PGTest::new("nonce_consume_concurrent").await.unwrap();
+        let db = pg.db();
+
+        for _ in 0..100 {
+            let nonce = make_nonce(None);
+            db.insert_nonce(&nonce).await.unwrap();
+
+            let mut handles = Vec::new();
+
+            for _ in 0..2 {
+                let db = db.clone();
+                let nonce = nonce.clone();
+                handles.push(tokio::spawn(async move {
+                    db.consume_nonce(&nonce).await.unwrap()
+                }));
+            }
+
+            let mut accepted = 0;
+
+            for handle in handles {
+                if handle.await.unwrap() {
+                    accepted += 1;
+                }
+            }
+
+            assert_that!(accepted).is_equal_to(1);
+            assert_that!(db.consume_nonce(&nonce).await.unwrap()).is_false();
+        }
+    }
 }
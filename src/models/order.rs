@@ -1,16 +1,25 @@
+use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
-use std::ops::Add;
+use std::time::Duration;
 
 use async_trait::async_trait;
-use openssl::x509::X509;
+use openssl::{hash::MessageDigest, x509::X509};
+use serde::{Deserialize, Serialize};
 use tokio_postgres::{Row, Transaction};
 use url::Url;
 
-use super::{Postgres, Record, RecordList};
+use super::{
+    failed_authorization::{record_failed_authorization, FailureReason},
+    with_retry, Postgres, Record, RecordList, TenantId,
+};
+use crate::acme::ca::key_hash;
 use crate::acme::challenge::ChallengeType;
 use crate::acme::ACMEIdentifier;
 use crate::{
-    acme::{dns::DNSName, handlers::order::OrderStatus},
+    acme::handlers::{
+        order::{AuthStatus, OrderStatus},
+        DEFAULT_AUTHZ_LIFETIME,
+    },
     errors::db::{LoadError, SaveError},
     util::make_nonce,
 };
@@ -19,24 +28,41 @@ use crate::{
 pub struct Order {
     id: Option<i32>,
     pub order_id: String,
+    /// the account which placed the order, if known.
+    pub account_id: Option<i32>,
     pub error: Option<crate::errors::Error>,
     pub status: OrderStatus,
     pub created_at: chrono::DateTime<chrono::Local>,
     pub authorizations: Option<Vec<Authorization>>,
     pub not_before: Option<chrono::DateTime<chrono::Local>>,
     pub not_after: Option<chrono::DateTime<chrono::Local>>,
-    expires: Option<chrono::DateTime<chrono::Local>>,
+    /// when the order must be finalized by; RFC8555 7.1.3. None means it never expires.
+    pub expires: Option<chrono::DateTime<chrono::Local>>,
+    /// set once the order was found past `expires` by [mark_expired].
+    pub expired: bool,
     finalized: bool,
     deleted_at: Option<chrono::DateTime<chrono::Local>>,
 }
 
+/// OrderSummary is an order as listed by [Postgres::list_orders_by_account], without its
+/// authorizations.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OrderSummary {
+    pub order_id: String,
+    pub created_at: chrono::DateTime<chrono::Local>,
+    pub expires: Option<chrono::DateTime<chrono::Local>>,
+    pub finalized: bool,
+}
+
 impl Default for Order {
     fn default() -> Self {
         Self {
             id: None,
             order_id: make_nonce(super::NONCE_KEY_SIZE),
+            account_id: None,
             finalized: false,
             expires: None,
+            expired: false,
             not_before: None,
             not_after: None,
             error: None,
@@ -49,6 +75,12 @@ impl Default for Order {
 }
 
 impl Order {
+    /// set_id records the primary key assigned by storage other than [Postgres], which assigns
+    /// it in `create`.
+    pub(super) fn set_id(&mut self, id: i32) {
+        self.id = Some(id);
+    }
+
     pub(crate) fn new(
         not_before: Option<chrono::DateTime<chrono::Local>>,
         not_after: Option<chrono::DateTime<chrono::Local>>,
@@ -60,23 +92,32 @@ impl Order {
         }
     }
 
+    /// find_by_reference loads the order of the database's tenant named `order_id` in its URL.
+    /// It is retried while the database cannot be reached; see [with_retry].
     pub(crate) async fn find_by_reference(
         order_id: String,
         db: Postgres,
     ) -> Result<Self, LoadError> {
-        let mut client = db.clone().client().await?;
-        let tx = client.transaction().await?;
-        let res = tx
-            .query_one("select id from orders where order_id = $1", &[&order_id])
-            .await;
+        with_retry(db.retry_policy(), || {
+            Self::try_find_by_reference(&order_id, &db)
+        })
+        .await
+    }
 
-        match res {
-            Ok(row) => {
-                let id = row.get(0);
-                drop(tx);
-                Self::find(id, db).await
-            }
-            Err(_) => Err(LoadError::NotFound),
+    /// try_find_by_reference is a single attempt of [Order::find_by_reference].
+    async fn try_find_by_reference(order_id: &str, db: &Postgres) -> Result<Self, LoadError> {
+        let client = db.clone().client().await?;
+        let row = client
+            .query_opt(
+                "select id from orders where order_id = $1 and tenant_id = $2",
+                &[&order_id, &db.tenant().as_str()],
+            )
+            .await?;
+        drop(client);
+
+        match row {
+            Some(row) => Self::find(row.get(0), db.clone()).await,
+            None => Err(LoadError::NotFound),
         }
     }
 
@@ -111,11 +152,7 @@ impl Order {
                     .unwrap()
                     .iter()
                     // FIXME remove these unwraps
-                    .map(|a| {
-                        ACMEIdentifier::DNS(
-                            DNSName::from_str(&a.identifier.clone().unwrap()).unwrap(),
-                        )
-                    })
+                    .map(|a| a.acme_identifier().unwrap())
                     .collect::<Vec<ACMEIdentifier>>()
             } else {
                 Vec::new()
@@ -136,12 +173,12 @@ impl Order {
                 None
             },
             finalize: Some(
-                url.join(&format!("/order/{}/finalize", self.order_id))
+                url.join(&format!("order/{}/finalize", self.order_id))
                     .unwrap(),
             ),
             // FIXME this needs to be at a unique location, not related to the order id
             certificate: Some(
-                url.join(&format!("/order/{}/certificate", self.order_id))
+                url.join(&format!("order/{}/certificate", self.order_id))
                     .unwrap(),
             ),
         };
@@ -149,6 +186,15 @@ impl Order {
         Ok(o)
     }
 
+    /// is_expired reports whether the order is past its `expires` timestamp, whether or not it
+    /// has been marked so yet.
+    pub fn is_expired(&self) -> bool {
+        self.expired
+            || self
+                .expires
+                .map_or(false, |expires| expires <= chrono::Local::now())
+    }
+
     // FIXME this is only used in tests rn
     #[cfg(test)]
     pub(crate) async fn challenges(
@@ -158,10 +204,14 @@ impl Order {
         Challenge::collect(self.order_id.clone(), tx).await
     }
 
+    /// record_certificate saves the certificate issued for the order, with the chain of the CA
+    /// which signed it, as a part of `tx`, so that
+    /// it is only kept if everything else done in finalizing the order is.
     pub(crate) async fn record_certificate(
         &self,
         certificate: X509,
-        db: Postgres,
+        chain: &[X509],
+        tx: &Transaction<'_>,
     ) -> Result<i32, SaveError> {
         let mut cert = Certificate::default();
         cert.order_id = self.order_id.clone();
@@ -171,7 +221,29 @@ impl Order {
         };
 
         cert.certificate = pem;
-        cert.create(db).await
+        cert.serial = match certificate.serial_number().to_bn() {
+            Ok(serial) => Some(serial.to_vec()),
+            Err(e) => return Err(SaveError::Generic(e.to_string())),
+        };
+
+        // the CA which signed it, which may be rotated away from while the certificate is in use.
+        if let Some(issuer) = chain.first() {
+            cert.issuer_key_hash = match key_hash(issuer, MessageDigest::sha1()) {
+                Ok(hash) => Some(hash.to_vec()),
+                Err(e) => return Err(SaveError::Generic(e.to_string())),
+            };
+
+            let mut pem = Vec::new();
+            for cert in chain {
+                match cert.to_pem() {
+                    Ok(cert) => pem.extend(cert),
+                    Err(e) => return Err(SaveError::Generic(e.to_string())),
+                }
+            }
+            cert.chain = Some(pem);
+        }
+
+        cert.insert(tx).await
     }
 
     pub(crate) async fn certificate(&self, db: Postgres) -> Result<Certificate, LoadError> {
@@ -179,6 +251,93 @@ impl Order {
     }
 }
 
+/// order_status_from_challenges derives the status of an order from the statuses of the
+/// challenges of each of its authorizations, in order. At least one challenge must pass for each
+/// identifier (carried in the authorization), and any invalid challenge breaks it.
+pub(crate) fn order_status_from_challenges(authorizations: &[Vec<OrderStatus>]) -> OrderStatus {
+    // FIXME test the shit out of this later
+    let mut status = OrderStatus::Pending;
+    let mut valid = false;
+
+    for challenges in authorizations {
+        valid = false;
+
+        // any invalids breaks it.
+        for chall in challenges {
+            if *chall == OrderStatus::Invalid {
+                status = OrderStatus::Invalid;
+                break;
+            } else if *chall == OrderStatus::Valid {
+                valid = true
+            }
+        }
+
+        // escape hatch for invalid status
+        if status == OrderStatus::Invalid || !valid {
+            break;
+        }
+    }
+
+    if valid {
+        status = OrderStatus::Valid;
+    }
+
+    status
+}
+
+/// mark_expired flags the orders and authorizations which are past their `expires` timestamp as of
+/// `now` as expired. Orders which already have a certificate are left alone. Returns the number of
+/// orders and authorizations marked.
+pub(crate) async fn mark_expired(
+    now: chrono::DateTime<chrono::Local>,
+    tx: &Transaction<'_>,
+) -> Result<(u64, u64), SaveError> {
+    // authorizations which expire while still pending failed; those which were validated did not.
+    let pending = tx
+        .query(
+            "
+            select reference from orders_authorizations
+            where expires <= $1 and not expired and deleted_at is null and not exists (
+                select 1 from orders_challenges
+                where orders_challenges.authorization_id = orders_authorizations.reference
+                    and orders_challenges.status = $2
+            )
+            ",
+            &[&now, &OrderStatus::Valid.to_string()],
+        )
+        .await?;
+
+    for row in pending {
+        let reference: String = row.get("reference");
+        record_failed_authorization(&reference, None, FailureReason::Expired, tx).await?;
+    }
+
+    let authorizations = tx
+        .execute(
+            "
+            update orders_authorizations set expired = true, version = version + 1
+            where expires <= $1 and not expired and deleted_at is null
+            ",
+            &[&now],
+        )
+        .await?;
+
+    let orders = tx
+        .execute(
+            "
+            update orders set expired = true
+            where expires <= $1 and not expired and deleted_at is null and not exists (
+                select 1 from orders_certificate
+                where orders_certificate.order_id = orders.order_id
+            )
+            ",
+            &[&now],
+        )
+        .await?;
+
+    Ok((orders, authorizations))
+}
+
 #[async_trait]
 impl Record<i32> for Order {
     async fn new_from_row(_row: &Row, _tx: &Transaction<'_>) -> Result<Self, LoadError> {
@@ -197,48 +356,41 @@ impl Record<i32> for Order {
             .await?;
 
         let order_id: String = order_row.get("order_id");
-        let mut status = OrderStatus::Pending;
         let authorizations = Authorization::collect(order_id.clone(), &tx).await?;
 
-        // ensure all at least one challenge has passed for each identifier (carried in the
-        // authorization)
-        //
-        // FIXME test the shit out of this later
-        let mut valid = false;
+        let mut statuses = Vec::new();
         for authz in &authorizations {
             if authz.identifier.is_none() {
                 // FIXME this should never happen and we should do something here
                 break;
             }
 
-            valid = false;
-
-            // any invalids breaks it.
-            for chall in authz.challenges(&tx).await? {
-                if chall.status == OrderStatus::Invalid {
-                    status = OrderStatus::Invalid;
-                    break;
-                } else if chall.status == OrderStatus::Valid {
-                    valid = true
-                }
-            }
-
-            // escape hatch for invalid status
-            if status == OrderStatus::Invalid || !valid {
-                break;
-            }
+            statuses.push(
+                authz
+                    .challenges(&tx)
+                    .await?
+                    .into_iter()
+                    .map(|c| c.status)
+                    .collect(),
+            );
         }
 
-        if valid {
-            status = OrderStatus::Valid;
-        }
+        // RFC8555 7.1.6: an order which expires before it is finalized is invalid.
+        let expired: bool = order_row.get("expired");
+        let status = if expired {
+            OrderStatus::Invalid
+        } else {
+            order_status_from_challenges(&statuses)
+        };
 
         let error: Option<String> = order_row.get("error");
 
         Ok(Order {
             id: order_row.get("id"),
             order_id: order_row.get("order_id"),
+            account_id: order_row.get("account_id"),
             expires: order_row.get("expires"),
+            expired,
             not_before: order_row.get("not_before"),
             not_after: order_row.get("not_after"),
             error: if error.is_some() {
@@ -259,6 +411,7 @@ impl Record<i32> for Order {
     }
 
     async fn create(&mut self, db: super::Postgres) -> Result<i32, crate::errors::db::SaveError> {
+        let tenant = db.tenant().clone();
         let mut client = db.client().await?;
         let tx = client.transaction().await?;
 
@@ -272,9 +425,9 @@ impl Record<i32> for Order {
             .query_one(
                 "
             insert into orders
-                (order_id, expires, not_before, not_after, error, finalized)
+                (order_id, expires, not_before, not_after, error, finalized, account_id, tenant_id)
             values 
-                ($1, $2, $3, $4, $5, $6)
+                ($1, $2, $3, $4, $5, $6, $7, $8)
             returning 
                 id, created_at
         ",
@@ -286,12 +439,13 @@ impl Record<i32> for Order {
                         .unwrap_or(chrono::DateTime::<chrono::Local>::from(
                             std::time::SystemTime::now(),
                         )),
-                    &self.clone().not_after.unwrap_or(
-                        chrono::DateTime::<chrono::Local>::from(std::time::SystemTime::now())
-                            .add(chrono::Duration::days(365)),
-                    ),
+                    // left empty unless the client asked for one, so that the CA's
+                    // certificate profile decides.
+                    &self.not_after,
                     &error,
                     &self.finalized,
+                    &self.account_id,
+                    &tenant.as_str(),
                 ],
             )
             .await?;
@@ -361,6 +515,410 @@ impl Record<i32> for Order {
     }
 }
 
+impl Postgres {
+    /// order_status_counts tallies the tenant's orders which have not been deleted by their
+    /// current status, in a single query. Like [Order::find], an expired order, or one with an
+    /// invalid challenge, is invalid, and one with a valid challenge for each authorization is
+    /// valid; anything else is pending.
+    pub async fn order_status_counts(&self) -> Result<HashMap<String, usize>, LoadError> {
+        let client = self.clone().client().await?;
+        let rows = client
+            .query(
+                "
+                with authorizations as (
+                    select o.order_id, o.expired, a.reference,
+                        bool_or(c.status = $2) as invalid,
+                        bool_or(c.status = $3) as valid
+                    from orders o
+                    left join orders_authorizations a on a.order_id = o.order_id or a.reference in (
+                        select r.authorization_id from orders_reused_authorizations r
+                        where r.order_id = o.order_id
+                    )
+                    left join orders_challenges c on c.authorization_id = a.reference
+                    where o.tenant_id = $1 and o.deleted_at is null
+                    group by o.order_id, o.expired, a.reference
+                )
+                select status, count(*) as count from (
+                    select case
+                        when bool_or(expired) or coalesce(bool_or(invalid), false) then $2
+                        when count(reference) > 0 and bool_and(coalesce(valid, false)) then $3
+                        else $4
+                    end as status
+                    from authorizations group by order_id
+                ) statuses
+                group by status
+                ",
+                &[
+                    &self.tenant().as_str(),
+                    &OrderStatus::Invalid.to_string(),
+                    &OrderStatus::Valid.to_string(),
+                    &OrderStatus::Pending.to_string(),
+                ],
+            )
+            .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| (row.get("status"), row.get::<_, i64>("count") as usize))
+            .collect())
+    }
+
+    /// archive_old_orders moves orders created more than `older_than` ago which are finished --
+    /// they have a certificate, or a failed challenge -- out of the orders table and into
+    /// `orders_archive`. Their authorizations and challenges are deleted along with them, save
+    /// for authorizations another order still reuses. Returns the number of orders moved.
+    pub async fn archive_old_orders(&self, older_than: Duration) -> Result<u64, SaveError> {
+        let cutoff = chrono::Local::now()
+            - chrono::Duration::from_std(older_than)
+                .map_err(|e| SaveError::Generic(e.to_string()))?;
+
+        let mut client = self.clone().client().await?;
+        let tx = client.transaction().await?;
+
+        let moved = tx
+            .query(
+                "
+                with moved as (
+                    delete from orders where created_at < $1 and (
+                        exists (
+                            select 1 from orders_certificate
+                            where orders_certificate.order_id = orders.order_id
+                        ) or exists (
+                            select 1 from orders_challenges
+                            where orders_challenges.order_id = orders.order_id and
+                                orders_challenges.status = 'invalid'
+                        )
+                    )
+                    returning *
+                )
+                insert into orders_archive select * from moved returning order_id
+                ",
+                &[&cutoff],
+            )
+            .await?
+            .iter()
+            .map(|row| row.get("order_id"))
+            .collect::<Vec<String>>();
+
+        tx.execute(
+            "delete from orders_reused_authorizations where order_id = any($1)",
+            &[&moved],
+        )
+        .await?;
+
+        let authorizations = tx
+            .query(
+                "
+                delete from orders_authorizations a where a.order_id = any($1) and not exists (
+                    select 1 from orders_reused_authorizations r
+                    where r.authorization_id = a.reference
+                )
+                returning reference
+                ",
+                &[&moved],
+            )
+            .await?
+            .iter()
+            .map(|row| row.get("reference"))
+            .collect::<Vec<String>>();
+
+        tx.execute(
+            "delete from orders_challenges where authorization_id = any($1)",
+            &[&authorizations],
+        )
+        .await?;
+
+        tx.commit().await?;
+        Ok(moved.len() as u64)
+    }
+
+    /// archive_orders_periodically runs [Postgres::archive_old_orders] every `interval`, forever.
+    /// Spawn it in its own task; errors are logged and retried on the next run.
+    pub async fn archive_orders_periodically(&self, older_than: Duration, interval: Duration) {
+        loop {
+            match self.archive_old_orders(older_than).await {
+                Ok(moved) => log::info!("archived {} orders", moved),
+                Err(e) => log::error!("could not archive orders: {}", e),
+            }
+
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// vacuum_orders deletes orders and authorizations created more than `older_than` ago which
+    /// can no longer be used: those which expired, or had a challenge fail. Orders with a
+    /// certificate are kept, as are authorizations another order still reuses. The authorizations
+    /// and challenges of deleted orders are deleted along with them. Unlike
+    /// [Postgres::archive_old_orders], nothing is kept. Returns the number of orders and
+    /// authorizations deleted. Like [Postgres::vacuum_nonces], the cutoff is taken from the
+    /// database clock, which `created_at` is set by.
+    pub async fn vacuum_orders(&self, older_than: Duration) -> Result<(u64, u64), SaveError> {
+        let older_than = format!("{} microseconds", older_than.as_micros());
+        let invalid = OrderStatus::Invalid.to_string();
+
+        let mut client = self.clone().client().await?;
+        let tx = client.transaction().await?;
+
+        let orders = tx
+            .query(
+                "
+                delete from orders where created_at < now() - $1::text::interval and (
+                    expired or exists (
+                        select 1 from orders_challenges
+                        where orders_challenges.order_id = orders.order_id and
+                            orders_challenges.status = $2
+                    )
+                ) and not exists (
+                    select 1 from orders_certificate
+                    where orders_certificate.order_id = orders.order_id
+                )
+                returning order_id
+                ",
+                &[&older_than, &invalid],
+            )
+            .await?
+            .iter()
+            .map(|row| row.get("order_id"))
+            .collect::<Vec<String>>();
+
+        tx.execute(
+            "delete from orders_reused_authorizations where order_id = any($1)",
+            &[&orders],
+        )
+        .await?;
+
+        let authorizations = tx
+            .query(
+                "
+                delete from orders_authorizations a where (
+                    a.order_id = any($3) or a.created_at < now() - $1::text::interval and (
+                        a.expired or exists (
+                            select 1 from orders_challenges c
+                            where c.authorization_id = a.reference and c.status = $2
+                        )
+                    )
+                ) and not exists (
+                    select 1 from orders_reused_authorizations r
+                    where r.authorization_id = a.reference
+                )
+                returning reference
+                ",
+                &[&older_than, &invalid, &orders],
+            )
+            .await?
+            .iter()
+            .map(|row| row.get("reference"))
+            .collect::<Vec<String>>();
+
+        tx.execute(
+            "delete from orders_challenges where authorization_id = any($1)",
+            &[&authorizations],
+        )
+        .await?;
+
+        tx.commit().await?;
+        Ok((orders.len() as u64, authorizations.len() as u64))
+    }
+
+    /// get_orders_expiring_soon returns unfinished orders which will expire within `within`.
+    /// These are orders whose clients seem to have stalled, and may be worth investigating.
+    pub async fn get_orders_expiring_soon(
+        &self,
+        within: Duration,
+    ) -> Result<Vec<Order>, LoadError> {
+        let now = chrono::Local::now();
+        let until = now
+            + chrono::Duration::from_std(within).map_err(|e| LoadError::Generic(e.to_string()))?;
+
+        let client = self.read_client().await?;
+        let rows = client
+            .query(
+                "
+                select id from orders
+                where
+                    deleted_at is null and
+                    finalized = false and
+                    expires > $1 and
+                    expires <= $2
+                order by expires ASC
+                ",
+                &[&now, &until],
+            )
+            .await?;
+        drop(client);
+
+        let mut ret = Vec::new();
+
+        for row in rows {
+            let order = Order::find(row.get(0), self.clone()).await?;
+            if order.status != OrderStatus::Valid && order.status != OrderStatus::Invalid {
+                ret.push(order);
+            }
+        }
+
+        Ok(ret)
+    }
+
+    /// warn_expiring_orders_periodically logs a warning for each order returned by
+    /// [Postgres::get_orders_expiring_soon] every `interval`, forever. Spawn it in its own task.
+    pub async fn warn_expiring_orders_periodically(&self, within: Duration, interval: Duration) {
+        loop {
+            match self.get_orders_expiring_soon(within).await {
+                Ok(orders) => {
+                    for order in orders {
+                        log::warn!(
+                            "order {} (account {}) expires at {} without being finalized",
+                            order.order_id,
+                            order
+                                .account_id
+                                .map_or("unknown".to_string(), |id| id.to_string()),
+                            order
+                                .expires
+                                .map_or("unknown".to_string(), |e| e.to_rfc3339()),
+                        )
+                    }
+                }
+                Err(e) => log::error!("could not fetch expiring orders: {}", e),
+            }
+
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// account_total_certificate_count returns the number of certificates issued for the
+    /// account's orders over its lifetime, not counting revoked or deleted certificates.
+    pub async fn account_total_certificate_count(&self, account_id: i32) -> Result<i64, LoadError> {
+        let client = self.clone().client().await?;
+
+        let row = client
+            .query_one(
+                "
+                select count(*) from orders
+                    join orders_certificate on orders.order_id = orders_certificate.order_id
+                    left join revocations on revocations.serial = orders_certificate.serial
+                where
+                    orders.account_id = $1 and
+                    orders_certificate.deleted_at is null and
+                    revocations.id is null
+                ",
+                &[&account_id],
+            )
+            .await?;
+
+        Ok(row.get(0))
+    }
+
+    /// list_order_ids_for_account returns the order IDs of the account's orders, oldest first
+    /// (RFC8555 7.1.2.1). Deleted orders are not listed.
+    pub async fn list_order_ids_for_account(
+        &self,
+        account_id: i32,
+    ) -> Result<Vec<String>, LoadError> {
+        let db = self.read_client().await?;
+        let rows = db
+            .query(
+                "
+                select order_id from orders
+                where account_id = $1 and tenant_id = $2 and deleted_at is null
+                order by created_at asc, id asc
+                ",
+                &[&account_id, &self.tenant().as_str()],
+            )
+            .await?;
+
+        Ok(rows.iter().map(|row| row.get("order_id")).collect())
+    }
+
+    /// list_orders_by_account returns a page of the account's orders, oldest first (RFC8555
+    /// 7.1.2.1), and whether there are more after it. Pages are counted from zero; deleted orders
+    /// are not listed.
+    pub async fn list_orders_by_account(
+        &self,
+        account_id: i32,
+        page: u32,
+        page_size: u32,
+    ) -> Result<(Vec<OrderSummary>, bool), LoadError> {
+        let db = self.read_client().await?;
+        let rows = db
+            .query(
+                "
+                select order_id, created_at, expires, finalized from orders
+                where account_id = $1 and tenant_id = $2 and deleted_at is null
+                order by created_at asc, id asc
+                limit $3 offset $4
+                ",
+                &[
+                    &account_id,
+                    &self.tenant().as_str(),
+                    // one more than fits the page, to tell whether there is a next one.
+                    &(page_size as i64 + 1),
+                    &(page as i64 * page_size as i64),
+                ],
+            )
+            .await?;
+
+        let more = rows.len() > page_size as usize;
+
+        Ok((
+            rows.iter()
+                .take(page_size as usize)
+                .map(|row| OrderSummary {
+                    order_id: row.get("order_id"),
+                    created_at: row.get("created_at"),
+                    expires: row.get("expires"),
+                    finalized: row.get("finalized"),
+                })
+                .collect(),
+            more,
+        ))
+    }
+
+    /// next_serial returns a certificate serial number which is unique across every instance
+    /// sharing the database; see [crate::acme::ca::CA::generate_and_sign_cert_with_serial].
+    pub async fn next_serial(&self) -> Result<u64, LoadError> {
+        let row = self
+            .clone()
+            .client()
+            .await?
+            .query_one("select nextval('cert_serial_seq')", &[])
+            .await?;
+
+        Ok(row.get::<_, i64>(0) as u64)
+    }
+
+    /// get_orders_for_certificate returns the orders which produced the certificate with the
+    /// provided serial number. An unknown serial yields an empty list.
+    pub async fn get_orders_for_certificate(&self, serial: &[u8]) -> Result<Vec<Order>, LoadError> {
+        let mut client = self.read_client().await?;
+        let tx = client.transaction().await?;
+
+        let rows = tx
+            .query(
+                "
+                select orders.id from orders
+                    join orders_certificate on orders.order_id = orders_certificate.order_id
+                where
+                    orders_certificate.serial = $1 and
+                    orders_certificate.deleted_at is null and
+                    orders.deleted_at is null
+                order by orders.created_at ASC
+                ",
+                &[&serial],
+            )
+            .await?;
+
+        drop(tx);
+
+        let mut ret = Vec::new();
+
+        for row in rows {
+            ret.push(Order::find(row.get(0), self.clone()).await?);
+        }
+
+        Ok(ret)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Challenge {
     pub id: Option<i32>,
@@ -375,6 +933,8 @@ pub struct Challenge {
     pub created_at: chrono::DateTime<chrono::Local>,
     pub deleted_at: Option<chrono::DateTime<chrono::Local>>,
     pub authorization_id: String,
+    /// failed attempts at the challenge so far.
+    pub retry_count: i32,
 }
 
 impl Challenge {
@@ -399,6 +959,7 @@ impl Challenge {
             validated: None,
             created_at: chrono::DateTime::<chrono::Local>::from(std::time::SystemTime::now()),
             deleted_at: None,
+            retry_count: 0,
         }
     }
 
@@ -444,7 +1005,7 @@ impl Challenge {
     }
 
     pub(crate) fn into_url(&self, url: url::Url) -> url::Url {
-        url.join(&format!("/chall/{}", self.reference)).unwrap()
+        url.join(&format!("chall/{}", self.reference)).unwrap()
     }
 
     fn new_from_row(result: &Row) -> Result<Self, LoadError> {
@@ -464,6 +1025,7 @@ impl Challenge {
             status: OrderStatus::try_from(result.get::<_, String>("status"))?,
             created_at: result.get("created_at"),
             deleted_at: result.get("deleted_at"),
+            retry_count: result.get("retry_count"),
         })
     }
 
@@ -496,10 +1058,11 @@ impl Challenge {
         }
 
         tx.execute(
-            "update orders_challenges set status=$1, validated=$2 where authorization_id=$3 and id=$4",
+            "update orders_challenges set status=$1, validated=$2, retry_count=$3 where authorization_id=$4 and id=$5",
             &[
                 &self.status.clone().to_string(),
                 &self.validated,
+                &self.retry_count,
                 &self.authorization_id.clone(),
                 &self.id.unwrap(),
             ],
@@ -507,6 +1070,26 @@ impl Challenge {
         .await?;
         Ok(())
     }
+
+    /// invalidate_authorization marks the challenge's authorization invalid by failing its
+    /// challenges, from which the authorization's status is derived. This goes through
+    /// [Authorization::update_status] at the version read here, so if the authorization is
+    /// changed concurrently [SaveError::ConcurrentModification] is returned instead.
+    pub(crate) async fn invalidate_authorization(
+        &self,
+        tx: &Transaction<'_>,
+    ) -> Result<(), SaveError> {
+        let authz = self.authorization(tx).await?;
+        Authorization::update_status(
+            &self.authorization_id,
+            OrderStatus::Invalid,
+            authz.version,
+            tx,
+        )
+        .await?;
+
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -556,6 +1139,11 @@ pub struct Certificate {
     order_id: String,
     reference: String,
     pub certificate: Vec<u8>,
+    pub serial: Option<Vec<u8>>,
+    /// the SHA-1 key hash of the CA which signed the certificate; see [crate::acme::ca::key_hash].
+    pub issuer_key_hash: Option<Vec<u8>>,
+    /// the PEM chain of the CA which signed the certificate, up to the root.
+    pub chain: Option<Vec<u8>>,
     created_at: chrono::DateTime<chrono::Local>,
     deleted_at: Option<chrono::DateTime<chrono::Local>>,
 }
@@ -567,6 +1155,9 @@ impl Default for Certificate {
             order_id: "".to_string(),
             reference: make_nonce(None),
             certificate: Vec::new(),
+            serial: None,
+            issuer_key_hash: None,
+            chain: None,
             created_at: chrono::DateTime::<chrono::Local>::from(std::time::SystemTime::now()),
             deleted_at: None,
         }
@@ -596,6 +1187,19 @@ impl Certificate {
 
         Self::new_from_row(&result, &tx).await
     }
+
+    /// insert is [Record::create] as a part of `tx`.
+    async fn insert(&mut self, tx: &Transaction<'_>) -> Result<i32, SaveError> {
+        let ret = tx.query_one(
+            "insert into orders_certificate (order_id, reference, certificate, serial, issuer_key_hash, chain) values ($1, $2, $3, $4, $5, $6) returning id, created_at",
+            &[&self.order_id, &self.reference, &self.certificate, &self.serial, &self.issuer_key_hash, &self.chain]
+        ).await?;
+
+        self.id = Some(ret.get("id"));
+        self.created_at = ret.get("created_at");
+
+        Ok(self.id.unwrap())
+    }
 }
 
 #[async_trait]
@@ -606,6 +1210,9 @@ impl Record<i32> for Certificate {
             order_id: row.get("order_id"),
             reference: row.get("reference"),
             certificate: row.get("certificate"),
+            serial: row.get("serial"),
+            issuer_key_hash: row.get("issuer_key_hash"),
+            chain: row.get("chain"),
             created_at: row.get("created_at"),
             deleted_at: row.get("deleted_at"),
         })
@@ -633,17 +1240,10 @@ impl Record<i32> for Certificate {
         let mut client = db.client().await?;
         let tx = client.transaction().await?;
 
-        let ret = tx.query_one(
-            "insert into orders_certificate (order_id, reference, certificate) values ($1, $2, $3) returning id, created_at",
-            &[&self.order_id, &self.reference, &self.certificate]
-        ).await?;
-
-        self.id = Some(ret.get("id"));
-        self.created_at = ret.get("created_at");
-
+        let id = self.insert(&tx).await?;
         tx.commit().await?;
 
-        Ok(self.id.unwrap())
+        Ok(id)
     }
 
     async fn delete(&self, db: super::Postgres) -> Result<(), SaveError> {
@@ -683,7 +1283,13 @@ pub struct Authorization {
     pub order_id: String,
     pub reference: String,
     pub expires: chrono::DateTime<chrono::Local>,
+    /// set once the authorization was found past `expires` by [mark_expired].
+    pub expired: bool,
     pub identifier: Option<String>,
+    /// the identifier type; "dns" or "ip". See [ACMEIdentifier::kind].
+    pub kind: String,
+    /// incremented on each status change; see [Postgres::update_authorization_status].
+    pub version: i64,
     created_at: chrono::DateTime<chrono::Local>,
     pub deleted_at: Option<chrono::DateTime<chrono::Local>>,
 }
@@ -694,8 +1300,13 @@ impl Default for Authorization {
             id: None,
             order_id: "".to_string(),
             identifier: None,
-            expires: chrono::DateTime::<chrono::Local>::from(std::time::SystemTime::now()),
+            kind: "dns".to_string(),
+            expires: chrono::DateTime::<chrono::Local>::from(
+                std::time::SystemTime::now() + DEFAULT_AUTHZ_LIFETIME,
+            ),
+            expired: false,
             reference: make_nonce(None),
+            version: 0,
             created_at: chrono::DateTime::<chrono::Local>::from(std::time::SystemTime::now()),
             deleted_at: None,
         }
@@ -709,6 +1320,12 @@ impl ToString for Authorization {
 }
 
 impl Authorization {
+    /// set_id records the primary key assigned by storage other than [Postgres], which assigns
+    /// it in `create`.
+    pub(super) fn set_id(&mut self, id: i32) {
+        self.id = Some(id);
+    }
+
     pub(crate) async fn find_by_reference(
         reference: &str,
         tx: &Transaction<'_>,
@@ -723,6 +1340,59 @@ impl Authorization {
         Ok(Self::new_from_row(&res, tx).await?)
     }
 
+    /// update_status is [Postgres::update_authorization_status] within a transaction of the
+    /// caller's.
+    pub(crate) async fn update_status(
+        reference: &str,
+        status: OrderStatus,
+        expected_version: i64,
+        tx: &Transaction<'_>,
+    ) -> Result<i64, SaveError> {
+        let row = tx
+            .query_opt(
+                "
+                update orders_authorizations set version = version + 1
+                where reference = $1 and version = $2 and deleted_at is null
+                returning version
+                ",
+                &[&reference, &expected_version],
+            )
+            .await?;
+
+        let version = match row {
+            Some(row) => row.get("version"),
+            None => return Err(SaveError::ConcurrentModification),
+        };
+
+        tx.execute(
+            "update orders_challenges set status = $1 where authorization_id = $2",
+            &[&status.to_string(), &reference],
+        )
+        .await?;
+
+        Ok(version)
+    }
+
+    /// find_for_tenant is [Authorization::find_by_reference] for requests made on behalf of a
+    /// tenant; authorizations of other tenants are not found.
+    pub(crate) async fn find_for_tenant(
+        reference: &str,
+        tenant: &TenantId,
+        tx: &Transaction<'_>,
+    ) -> Result<Self, LoadError> {
+        let res = tx
+            .query_opt(
+                "select * from orders_authorizations where reference = $1 and tenant_id = $2",
+                &[&reference, &tenant.as_str()],
+            )
+            .await?;
+
+        match res {
+            Some(row) => Self::new_from_row(&row, tx).await,
+            None => Err(LoadError::NotFound),
+        }
+    }
+
     pub(crate) async fn challenges(
         &self,
         tx: &Transaction<'_>,
@@ -731,7 +1401,23 @@ impl Authorization {
     }
 
     pub fn into_url(&self, baseurl: Url) -> Url {
-        baseurl.join(&format!("/authz/{}", self.reference)).unwrap()
+        baseurl.join(&format!("authz/{}", self.reference)).unwrap()
+    }
+
+    /// is_expired reports whether the authorization is past its `expires` timestamp, whether or
+    /// not it has been marked so yet.
+    pub fn is_expired(&self) -> bool {
+        self.expired || self.expires <= chrono::Local::now()
+    }
+
+    /// acme_identifier combines the identifier and its kind into an [ACMEIdentifier].
+    pub fn acme_identifier(&self) -> Result<ACMEIdentifier, LoadError> {
+        match self.identifier.clone() {
+            Some(identifier) => ACMEIdentifier::new(&self.kind, identifier),
+            None => Err(LoadError::Generic(
+                "authorization has no identifier".to_string(),
+            )),
+        }
     }
 }
 
@@ -740,9 +1426,16 @@ impl RecordList<String> for Authorization {
     async fn collect(order_id: String, tx: &Transaction<'_>) -> Result<Vec<Self>, LoadError> {
         let mut ret = Vec::new();
 
+        // the order's own authorizations, and those it reused from earlier orders.
         let results = tx
             .query(
-                "select * from orders_authorizations where order_id = $1 order by created_at ASC",
+                "
+                select * from orders_authorizations
+                where order_id = $1 or reference in (
+                    select authorization_id from orders_reused_authorizations where order_id = $1
+                )
+                order by created_at ASC
+                ",
                 &[&order_id],
             )
             .await?;
@@ -772,7 +1465,7 @@ impl RecordList<String> for Authorization {
             ));
         }
 
-        tx.execute("insert into orders_authorizations (order_id, expires, identifier, reference, created_at, deleted_at) values ($1, $2, $3, $4, $5, $6)", &[&order_id, &self.expires, &self.identifier.clone().unwrap(),&self.reference, &self.created_at, &self.deleted_at]).await?;
+        tx.execute("insert into orders_authorizations (order_id, expires, identifier, kind, reference, created_at, deleted_at) values ($1, $2, $3, $4, $5, $6, $7)", &[&order_id, &self.expires, &self.identifier.clone().unwrap(), &self.kind, &self.reference, &self.created_at, &self.deleted_at]).await?;
         Ok(Self::collect(order_id, tx).await?)
     }
 
@@ -804,8 +1497,11 @@ impl Record<i32> for Authorization {
             id: row.get("id"),
             order_id: row.get("order_id"),
             identifier: Some(row.get::<_, String>("identifier")),
+            kind: row.get("kind"),
             reference: row.get("reference"),
             expires: row.get("expires"),
+            expired: row.get("expired"),
+            version: row.get("version"),
             created_at: row.get("created_at"),
             deleted_at: row.get("deleted_at"),
         })
@@ -836,10 +1532,11 @@ impl Record<i32> for Authorization {
             ));
         }
 
+        let tenant = db.tenant().clone();
         let mut client = db.client().await?;
         let tx = client.transaction().await?;
 
-        let ret = tx.query_one("insert into orders_authorizations (order_id, expires, reference, identifier) values ($1, $2, $3, $4) returning id, created_at", &[&self.order_id, &self.expires, &self.reference, &self.identifier]).await?;
+        let ret = tx.query_one("insert into orders_authorizations (order_id, expires, reference, identifier, kind, tenant_id) values ($1, $2, $3, $4, $5, $6) returning id, created_at", &[&self.order_id, &self.expires, &self.reference, &self.identifier, &self.kind, &tenant.as_str()]).await?;
 
         self.id = Some(ret.get("id"));
         self.created_at = ret.get("created_at");
@@ -879,7 +1576,549 @@ impl Record<i32> for Authorization {
     }
 }
 
+/// ChallengeWithContext is a challenge along with the authorization and order it belongs to, and
+/// their statuses at the time it was loaded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChallengeWithContext {
+    pub challenge: Challenge,
+    pub authorization: Authorization,
+    pub authorization_status: AuthStatus,
+    pub order_status: OrderStatus,
+}
+
+impl Postgres {
+    /// get_challenge_with_context returns the most recent challenge of `challenge_type` (e.g.
+    /// `http-01`) for the authorization with reference `auth_id`, with its authorization and order
+    /// context. None is returned if there is no such challenge.
+    pub async fn get_challenge_with_context(
+        &self,
+        auth_id: &str,
+        challenge_type: &str,
+    ) -> Result<Option<ChallengeWithContext>, LoadError> {
+        let mut client = self.read_client().await?;
+        let tx = client.transaction().await?;
+
+        let row = tx
+            .query_opt(
+                "
+                select c.*, o.id as order_pk from orders_challenges c
+                    inner join orders_authorizations a on a.reference = c.authorization_id
+                    inner join orders o on o.order_id = a.order_id
+                where
+                    a.reference = $1 and
+                    c.challenge_type = $2 and
+                    c.deleted_at is null and
+                    o.deleted_at is null
+                order by c.created_at DESC
+                limit 1
+                ",
+                &[&auth_id, &challenge_type],
+            )
+            .await?;
+
+        let row = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+
+        let challenge = Challenge::new_from_row(&row)?;
+        let authorization = challenge.authorization(&tx).await?;
+        let challenges = authorization.challenges(&tx).await?;
+        let authorization_status = AuthStatus::from_challenges(
+            authorization.deleted_at.is_some(),
+            authorization.is_expired(),
+            challenges.iter().map(|c| &c.status),
+        );
+        tx.commit().await?;
+        drop(client);
+
+        let order = Order::find(row.get("order_pk"), self.clone()).await?;
+
+        Ok(Some(ChallengeWithContext {
+            challenge,
+            authorization,
+            authorization_status,
+            order_status: order.status,
+        }))
+    }
+
+    /// get_authorization returns the authorization with the provided reference, including its
+    /// current version.
+    pub async fn get_authorization(&self, reference: &str) -> Result<Authorization, LoadError> {
+        let mut client = self.clone().client().await?;
+        let tx = client.transaction().await?;
+
+        Authorization::find_by_reference(reference, &tx).await
+    }
+
+    /// update_authorization_status sets the status of the authorization's challenges, which is
+    /// what the authorization's own status is derived from. The update only happens if the
+    /// authorization is still at `expected_version`; otherwise
+    /// [SaveError::ConcurrentModification] is returned and nothing is changed. The new version is
+    /// returned on success.
+    pub async fn update_authorization_status(
+        &self,
+        reference: &str,
+        status: OrderStatus,
+        expected_version: i64,
+    ) -> Result<i64, SaveError> {
+        let mut client = self.clone().client().await?;
+        let tx = client.transaction().await?;
+
+        let version =
+            Authorization::update_status(reference, status, expected_version, &tx).await?;
+
+        tx.commit().await?;
+        Ok(version)
+    }
+
+    /// find_reusable_authorization returns an authorization for the identifier from an earlier
+    /// order of the account which was validated and has not expired, so that a new order may
+    /// reuse it rather than have the identifier validated again (RFC8555 7.1.4). Of several, the
+    /// one expiring last is returned.
+    pub async fn find_reusable_authorization(
+        &self,
+        account_id: i32,
+        kind: &str,
+        identifier: &str,
+    ) -> Result<Option<Authorization>, LoadError> {
+        let mut client = self.clone().client().await?;
+        let tx = client.transaction().await?;
+
+        let row = tx
+            .query_opt(
+                "
+                select a.* from orders_authorizations a
+                    inner join orders o on o.order_id = a.order_id
+                where o.account_id = $1 and a.kind = $2 and a.identifier = $3 and a.tenant_id = $4
+                    and a.expires > CURRENT_TIMESTAMP and not a.expired and a.deleted_at is null
+                    and exists (
+                        select 1 from orders_challenges c
+                        where c.authorization_id = a.reference and c.status = $5
+                    )
+                order by a.expires desc
+                limit 1
+                ",
+                &[
+                    &account_id,
+                    &kind,
+                    &identifier,
+                    &self.tenant().as_str(),
+                    &OrderStatus::Valid.to_string(),
+                ],
+            )
+            .await?;
+
+        match row {
+            Some(row) => Ok(Some(Authorization::new_from_row(&row, &tx).await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// link_authorization makes the authorization with `reference`, which was created for another
+    /// order, one of the authorizations of `order_id` as well; see
+    /// [Postgres::find_reusable_authorization].
+    pub async fn link_authorization(
+        &self,
+        order_id: &str,
+        reference: &str,
+    ) -> Result<(), SaveError> {
+        let client = self.clone().client().await?;
+        client
+            .execute(
+                "insert into orders_reused_authorizations (order_id, authorization_id) values ($1, $2)",
+                &[&order_id, &reference],
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
 mod tests {
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_order_tenant_isolation() {
+        use super::{Authorization, Order};
+        use crate::models::{Record, TenantId};
+        use crate::test::PGTest;
+        use spectral::prelude::*;
+
+        let pg = PGTest::new("test_order_tenant_isolation").await.unwrap();
+        let a = pg.db().with_tenant(TenantId("a".to_string()));
+        let b = pg.db().with_tenant(TenantId("b".to_string()));
+
+        let mut order = Order::default();
+        order.create(a.clone()).await.unwrap();
+
+        let mut authz = Authorization::default();
+        authz.order_id = order.order_id.clone();
+        authz.identifier = Some("example.com".to_string());
+        authz.create(a.clone()).await.unwrap();
+
+        assert_that!(Order::find_by_reference(order.order_id.clone(), a.clone()).await).is_ok();
+        assert_that!(Order::find_by_reference(order.order_id.clone(), b.clone()).await).is_err();
+        assert_that!(Order::find_by_reference(order.order_id.clone(), pg.db()).await).is_err();
+
+        let mut client = pg.db().client().await.unwrap();
+        let tx = client.transaction().await.unwrap();
+        assert_that!(Authorization::find_for_tenant(&authz.reference, a.tenant(), &tx).await)
+            .is_ok();
+        assert_that!(Authorization::find_for_tenant(&authz.reference, b.tenant(), &tx).await)
+            .is_err();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_order_status_counts() {
+        use super::{Authorization, Order};
+        use crate::models::{Record, TenantId};
+        use crate::test::PGTest;
+        use spectral::prelude::*;
+        use std::collections::HashMap;
+
+        let pg = PGTest::new("test_order_status_counts").await.unwrap();
+        let a = pg.db().with_tenant(TenantId("a".to_string()));
+        let b = pg.db().with_tenant(TenantId("b".to_string()));
+        let client = pg.db().client().await.unwrap();
+
+        // one order of each status, with a challenge of that status where it takes one.
+        for status in ["pending", "valid", "invalid", "expired"] {
+            let mut order = Order::default();
+            order.create(a.clone()).await.unwrap();
+
+            let mut authz = Authorization::default();
+            authz.order_id = order.order_id.clone();
+            authz.identifier = Some("example.com".to_string());
+            authz.create(a.clone()).await.unwrap();
+
+            match status {
+                "valid" | "invalid" => {
+                    client
+                        .execute(
+                            "
+                            insert into orders_challenges (
+                                order_id, authorization_id, challenge_type, reference,
+                                identifier, token, status, issuing_address
+                            )
+                            values ($1, $2, 'http-01', $3, 'example.com', 'token', $4, '127.0.0.1')
+                            ",
+                            &[
+                                &order.order_id,
+                                &authz.reference,
+                                &format!("{}-challenge", status),
+                                &status,
+                            ],
+                        )
+                        .await
+                        .unwrap();
+                }
+                "expired" => {
+                    client
+                        .execute(
+                            "update orders set expired = true where order_id = $1",
+                            &[&order.order_id],
+                        )
+                        .await
+                        .unwrap();
+                }
+                _ => {}
+            }
+        }
+
+        let mut order = Order::default();
+        order.create(b.clone()).await.unwrap();
+
+        assert_that!(a.order_status_counts().await.unwrap()).is_equal_to(HashMap::from([
+            ("pending".to_string(), 1),
+            ("valid".to_string(), 1),
+            ("invalid".to_string(), 2),
+        ]));
+        assert_that!(b.order_status_counts().await.unwrap())
+            .is_equal_to(HashMap::from([("pending".to_string(), 1)]));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_orders_expiring_soon() {
+        use super::Order;
+        use crate::models::Record;
+        use crate::test::PGTest;
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        let pg = PGTest::new("test_orders_expiring_soon").await.unwrap();
+        let db = pg.db();
+
+        let now = chrono::Local::now();
+
+        let mut soon = Order {
+            expires: Some(now + chrono::Duration::seconds(10)),
+            ..Default::default()
+        };
+        soon.create(db.clone()).await.unwrap();
+
+        let mut later = Order {
+            expires: Some(now + chrono::Duration::hours(1)),
+            ..Default::default()
+        };
+        later.create(db.clone()).await.unwrap();
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+
+        let orders = db
+            .get_orders_expiring_soon(Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert_that!(orders.len()).is_equal_to(1);
+        assert_that!(orders[0].order_id).is_equal_to(soon.order_id);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_archive_old_orders() {
+        use super::{Authorization, Certificate, Challenge, Order};
+        use crate::acme::{challenge::ChallengeType, handlers::order::OrderStatus};
+        use crate::models::Record;
+        use crate::test::PGTest;
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        let pg = PGTest::new("test_archive_old_orders").await.unwrap();
+        let db = pg.db();
+
+        let mut authorizations = Vec::new();
+
+        for _ in 0..50 {
+            let mut order = Order::default();
+            order.create(db.clone()).await.unwrap();
+
+            let mut authz = Authorization {
+                order_id: order.order_id.clone(),
+                identifier: Some("example.com".to_string()),
+                ..Default::default()
+            };
+            authz.create(db.clone()).await.unwrap();
+
+            let mut ch = Challenge::new(
+                order.order_id.clone(),
+                authz.reference.clone(),
+                ChallengeType::HTTP01,
+                "example.com".to_string(),
+                "127.0.0.1".to_string(),
+                OrderStatus::Valid,
+            );
+            ch.create(db.clone()).await.unwrap();
+            authorizations.push(authz);
+
+            let mut cert = Certificate {
+                order_id: order.order_id.clone(),
+                ..Default::default()
+            };
+            cert.create(db.clone()).await.unwrap();
+        }
+
+        // still in progress; must be left alone, along with the authorization it reuses.
+        let mut pending = Order::default();
+        pending.create(db.clone()).await.unwrap();
+        db.link_authorization(&pending.order_id, &authorizations[0].reference)
+            .await
+            .unwrap();
+
+        // nothing is old enough yet
+        assert_that!(db
+            .archive_old_orders(Duration::from_secs(3600))
+            .await
+            .unwrap())
+        .is_equal_to(0);
+
+        assert_that!(db.archive_old_orders(Duration::ZERO).await.unwrap()).is_equal_to(50);
+
+        let client = db.clone().client().await.unwrap();
+        let orders: i64 = client
+            .query_one("select count(*) from orders", &[])
+            .await
+            .unwrap()
+            .get(0);
+        let archived: i64 = client
+            .query_one("select count(*) from orders_archive", &[])
+            .await
+            .unwrap()
+            .get(0);
+
+        assert_that!(orders).is_equal_to(1);
+        assert_that!(archived).is_equal_to(50);
+
+        let authz_count: i64 = client
+            .query_one("select count(*) from orders_authorizations", &[])
+            .await
+            .unwrap()
+            .get(0);
+        let challenge_count: i64 = client
+            .query_one("select count(*) from orders_challenges", &[])
+            .await
+            .unwrap()
+            .get(0);
+
+        assert_that!(authz_count).is_equal_to(1);
+        assert_that!(challenge_count).is_equal_to(1);
+
+        assert_that!(Order::find(pending.id().unwrap().unwrap(), db.clone()).await).is_ok();
+        assert_that!(db.get_authorization(&authorizations[0].reference).await).is_ok();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_challenge_with_context() {
+        use super::{Authorization, Challenge, Order};
+        use crate::acme::{
+            challenge::ChallengeType,
+            handlers::order::{AuthStatus, OrderStatus},
+        };
+        use crate::models::Record;
+        use crate::test::PGTest;
+        use spectral::prelude::*;
+
+        let pg = PGTest::new("test_challenge_with_context").await.unwrap();
+        let db = pg.db();
+
+        let mut order = Order::default();
+        order.create(db.clone()).await.unwrap();
+
+        let mut authz = Authorization {
+            order_id: order.order_id.clone(),
+            identifier: Some("example.com".to_string()),
+            ..Default::default()
+        };
+        authz.create(db.clone()).await.unwrap();
+
+        let mut ch = Challenge::new(
+            order.order_id.clone(),
+            authz.reference.clone(),
+            ChallengeType::HTTP01,
+            "example.com".to_string(),
+            "127.0.0.1".to_string(),
+            OrderStatus::Pending,
+        );
+        ch.create(db.clone()).await.unwrap();
+
+        let ctx = db
+            .get_challenge_with_context(&authz.reference, "http-01")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_that!(ctx.challenge.reference).is_equal_to(ch.reference.clone());
+        assert_that!(ctx.challenge.status).is_equal_to(OrderStatus::Pending);
+        assert_that!(ctx.authorization.reference).is_equal_to(authz.reference.clone());
+        assert_that!(ctx.authorization_status).is_equal_to(AuthStatus::Pending);
+        assert_that!(ctx.order_status).is_equal_to(OrderStatus::Pending);
+
+        // validate the challenge
+        let mut client = db.clone().client().await.unwrap();
+        let tx = client.transaction().await.unwrap();
+        ch.status = OrderStatus::Valid;
+        ch.persist_status(&tx).await.unwrap();
+        tx.commit().await.unwrap();
+        drop(client);
+
+        let ctx = db
+            .get_challenge_with_context(&authz.reference, "http-01")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_that!(ctx.challenge.status).is_equal_to(OrderStatus::Valid);
+        assert_that!(ctx.challenge.validated).is_some();
+        assert_that!(ctx.authorization_status).is_equal_to(AuthStatus::Valid);
+        assert_that!(ctx.order_status).is_equal_to(OrderStatus::Valid);
+
+        assert_that!(db
+            .get_challenge_with_context(&authz.reference, "dns-01")
+            .await
+            .unwrap())
+        .is_none();
+        assert_that!(db
+            .get_challenge_with_context("unknown", "http-01")
+            .await
+            .unwrap())
+        .is_none();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_authorization_version() {
+        use super::{Authorization, Challenge};
+        use crate::acme::{challenge::ChallengeType, handlers::order::OrderStatus};
+        use crate::errors::db::SaveError;
+        use crate::models::Record;
+        use crate::test::PGTest;
+        use crate::util::make_nonce;
+        use spectral::prelude::*;
+
+        let pg = PGTest::new("test_authorization_version").await.unwrap();
+        let db = pg.db();
+
+        let mut authz = Authorization {
+            order_id: make_nonce(None),
+            identifier: Some("example.com".to_string()),
+            ..Default::default()
+        };
+        authz.create(db.clone()).await.unwrap();
+
+        let mut ch = Challenge::new(
+            authz.order_id.clone(),
+            authz.reference.clone(),
+            ChallengeType::HTTP01,
+            "example.com".to_string(),
+            "127.0.0.1".to_string(),
+            OrderStatus::Pending,
+        );
+        ch.create(db.clone()).await.unwrap();
+
+        let found = db.get_authorization(&authz.reference).await.unwrap();
+        assert_that!(found.version).is_equal_to(0);
+
+        // race a number of updates against the same version; exactly one may win.
+        let mut handles = Vec::new();
+        for _ in 0..10 {
+            let db = db.clone();
+            let reference = authz.reference.clone();
+            handles.push(tokio::spawn(async move {
+                db.update_authorization_status(&reference, OrderStatus::Valid, 0)
+                    .await
+            }));
+        }
+
+        let mut won = 0;
+        for handle in handles {
+            match handle.await.unwrap() {
+                Ok(version) => {
+                    assert_that!(version).is_equal_to(1);
+                    won += 1
+                }
+                Err(SaveError::ConcurrentModification) => {}
+                Err(e) => panic!("unexpected error: {}", e),
+            }
+        }
+        assert_that!(won).is_equal_to(1);
+
+        let found = db.get_authorization(&authz.reference).await.unwrap();
+        assert_that!(found.version).is_equal_to(1);
+
+        let mut client = db.clone().client().await.unwrap();
+        let tx = client.transaction().await.unwrap();
+        let challenges = found.challenges(&tx).await.unwrap();
+        assert_that!(challenges[0].status).is_equal_to(OrderStatus::Valid);
+        drop(tx);
+
+        // a stale version is rejected and leaves the record alone
+        assert_that!(matches!(
+            db.update_authorization_status(&authz.reference, OrderStatus::Invalid, 0)
+                .await,
+            Err(SaveError::ConcurrentModification)
+        ))
+        .is_true();
+
+        assert_that!(
+            db.update_authorization_status(&authz.reference, OrderStatus::Invalid, 1)
+                .await
+        )
+        .is_ok_containing(2);
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_order_certificate() {
         use super::Certificate;
@@ -923,6 +2162,58 @@ mod tests {
         }
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_record_certificate_rollback() {
+        use super::{Certificate, Order};
+        use crate::acme::ca::CA;
+        use crate::models::Record;
+        use crate::test::PGTest;
+        use openssl::hash::MessageDigest;
+        use spectral::prelude::*;
+
+        let pg = PGTest::new("test_record_certificate_rollback")
+            .await
+            .unwrap();
+
+        let mut order = Order::default();
+        order.create(pg.db()).await.unwrap();
+
+        let ca = CA::new_test_ca().unwrap();
+        let cert = ca.clone().certificate();
+        let serial = cert.serial_number().to_bn().unwrap().to_vec();
+
+        // a failure after the certificate was recorded drops the transaction uncommitted.
+        {
+            let mut client = pg.db().client().await.unwrap();
+            let tx = client.transaction().await.unwrap();
+            assert_that!(
+                order
+                    .record_certificate(cert.clone(), &ca.chain(), &tx)
+                    .await
+            )
+            .is_ok();
+        }
+
+        assert_that!(Certificate::find_by_order_id(order.order_id.clone(), pg.db()).await).is_err();
+        assert_that!(pg.db().get_orders_for_certificate(&serial).await.unwrap()).is_empty();
+
+        let mut client = pg.db().client().await.unwrap();
+        let tx = client.transaction().await.unwrap();
+        order
+            .record_certificate(cert.clone(), &ca.chain(), &tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        let recorded = Certificate::find_by_order_id(order.order_id.clone(), pg.db())
+            .await
+            .unwrap();
+        assert_that!(recorded.serial).is_equal_to(Some(serial));
+        assert_that!(recorded.issuer_key_hash)
+            .is_equal_to(Some(ca.key_hash(MessageDigest::sha1()).unwrap().to_vec()));
+        assert_that!(recorded.chain).is_equal_to(Some(ca.chain_pem().unwrap()));
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_order_authorization() {
         use super::Authorization;
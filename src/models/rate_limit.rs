@@ -0,0 +1,111 @@
+use std::time::Duration;
+
+use super::{Postgres, SaveError};
+
+impl Postgres {
+    /// check_account_rate_limit counts an `operation` performed by the account against the current
+    /// `window`, and returns false if the account has now performed it more than `max` times in
+    /// that window. Windows are aligned to the unix epoch, so all callers agree on their
+    /// boundaries. The counts of windows which have ended are pruned as it goes.
+    pub async fn check_account_rate_limit(
+        &self,
+        account_id: i32,
+        operation: &str,
+        window: Duration,
+        max: u32,
+    ) -> Result<bool, SaveError> {
+        let mut db = self.clone().client().await?;
+        let tx = db.transaction().await?;
+
+        let row = tx
+            .query_one(
+                "
+                insert into rate_limits (account_id, operation, window_start, count)
+                values (
+                    $1, $2,
+                    to_timestamp(floor(extract(epoch from CURRENT_TIMESTAMP)::float8 / $3::float8) * $3::float8),
+                    1
+                )
+                on conflict (account_id, operation, window_start)
+                    do update set count = rate_limits.count + 1
+                returning count
+                ",
+                &[&account_id, &operation, &window.as_secs_f64()],
+            )
+            .await?;
+
+        tx.execute(
+            "delete from rate_limits where window_start < to_timestamp(extract(epoch from CURRENT_TIMESTAMP)::float8 - $1::float8)",
+            &[&window.as_secs_f64()],
+        )
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(row.get::<_, i32>("count") as i64 <= max as i64)
+    }
+}
+
+mod tests {
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_account_rate_limit() {
+        use crate::test::PGTest;
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        let pg = PGTest::new("test_account_rate_limit").await.unwrap();
+        let db = pg.db();
+
+        // a day-long window so the test can't straddle a boundary in practice.
+        let window = Duration::from_secs(86400);
+
+        for _ in 0..3 {
+            assert_that!(db
+                .check_account_rate_limit(1, "new-order", window, 3)
+                .await
+                .unwrap())
+            .is_true();
+        }
+
+        for _ in 0..2 {
+            assert_that!(db
+                .check_account_rate_limit(1, "new-order", window, 3)
+                .await
+                .unwrap())
+            .is_false();
+        }
+
+        // other accounts and operations are counted separately
+        assert_that!(db
+            .check_account_rate_limit(2, "new-order", window, 3)
+            .await
+            .unwrap())
+        .is_true();
+        assert_that!(db
+            .check_account_rate_limit(1, "finalize", window, 3)
+            .await
+            .unwrap())
+        .is_true();
+
+        // windows which have ended are pruned by the next check.
+        let c = db.clone().client().await.unwrap();
+        c.execute(
+            "insert into rate_limits (account_id, operation, window_start, count) values (3, 'new-order', CURRENT_TIMESTAMP - interval '2 days', 5)",
+            &[],
+        )
+        .await
+        .unwrap();
+
+        assert_that!(db
+            .check_account_rate_limit(1, "new-order", window, 3)
+            .await
+            .unwrap())
+        .is_false();
+
+        let row = c
+            .query_one("select count(*) from rate_limits where account_id = 3", &[])
+            .await
+            .unwrap();
+        assert_that!(row.get::<_, i64>(0)).is_equal_to(0);
+    }
+}
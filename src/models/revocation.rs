@@ -0,0 +1,180 @@
+use serde::Serialize;
+use tokio_postgres::Transaction;
+
+use super::{LoadError, Postgres, SaveError};
+use crate::acme::ca::RevokedCertificate;
+
+/// RevocationRecord describes the revocation of a single certificate, as needed to answer OCSP
+/// requests and build CRLs.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RevocationRecord {
+    pub serial: Vec<u8>,
+    /// the RFC5280 CRLReason code.
+    pub reason: i32,
+    pub revoked_at: chrono::DateTime<chrono::Local>,
+}
+
+impl From<RevocationRecord> for RevokedCertificate {
+    fn from(record: RevocationRecord) -> Self {
+        Self {
+            serial: record.serial,
+            reason: record.reason,
+            revoked_at: record.revoked_at.with_timezone(&chrono::Utc),
+            issuer_key_hash: None,
+        }
+    }
+}
+
+/// insert_revocation is [Postgres::record_revocation] as a part of `tx`, so that what else is
+/// recorded about the revocation is kept with it.
+pub(crate) async fn insert_revocation(
+    serial: &[u8],
+    reason: i32,
+    tx: &Transaction<'_>,
+) -> Result<(), SaveError> {
+    tx.execute(
+        "insert into revocations (serial, reason) values ($1, $2)",
+        &[&serial, &reason],
+    )
+    .await?;
+
+    Ok(())
+}
+
+impl Postgres {
+    /// record_revocation marks the certificate with the provided serial as revoked. Revoking an
+    /// already revoked serial is an error.
+    pub async fn record_revocation(&self, serial: &[u8], reason: i32) -> Result<(), SaveError> {
+        let mut db = self.clone().client().await?;
+        let tx = db.transaction().await?;
+
+        insert_revocation(serial, reason, &tx).await?;
+
+        Ok(tx.commit().await?)
+    }
+
+    /// is_serial_revoked returns true if the certificate with the provided serial has been
+    /// revoked. This is a single index lookup on a cached prepared statement, so it is cheap
+    /// enough to call for every OCSP request or certificate fetch.
+    pub async fn is_serial_revoked(&self, serial: &[u8]) -> Result<bool, LoadError> {
+        let db = self.clone().client().await?;
+        let stmt = db
+            .prepare_cached("select exists(select 1 from revocations where serial = $1)")
+            .await?;
+
+        Ok(db.query_one(&stmt, &[&serial]).await?.get(0))
+    }
+
+    /// get_revocation_details returns the revocation record for the serial, or None if the
+    /// certificate has not been revoked.
+    pub async fn get_revocation_details(
+        &self,
+        serial: &[u8],
+    ) -> Result<Option<RevocationRecord>, LoadError> {
+        let db = self.read_client().await?;
+        let stmt = db
+            .prepare_cached("select serial, reason, revoked_at from revocations where serial = $1")
+            .await?;
+
+        Ok(db
+            .query_opt(&stmt, &[&serial])
+            .await?
+            .map(|row| RevocationRecord {
+                serial: row.get("serial"),
+                reason: row.get("reason"),
+                revoked_at: row.get("revoked_at"),
+            }))
+    }
+
+    /// list_revocations returns every revocation recorded, oldest first.
+    pub async fn list_revocations(&self) -> Result<Vec<RevocationRecord>, LoadError> {
+        let db = self.read_client().await?;
+        let stmt = db
+            .prepare_cached("select serial, reason, revoked_at from revocations order by id asc")
+            .await?;
+
+        Ok(db
+            .query(&stmt, &[])
+            .await?
+            .iter()
+            .map(|row| RevocationRecord {
+                serial: row.get("serial"),
+                reason: row.get("reason"),
+                revoked_at: row.get("revoked_at"),
+            })
+            .collect())
+    }
+
+    /// list_revoked_certificates returns every revocation recorded, oldest first, as the
+    /// entries of a CRL, with the key hash of the CA which issued each certificate if it is known.
+    pub async fn list_revoked_certificates(&self) -> Result<Vec<RevokedCertificate>, LoadError> {
+        let db = self.read_client().await?;
+        let stmt = db
+            .prepare_cached(
+                "
+                select serial, reason, revoked_at, (
+                    select issuer_key_hash from orders_certificate
+                    where orders_certificate.serial = revocations.serial
+                    limit 1
+                ) as issuer_key_hash
+                from revocations order by id asc
+                ",
+            )
+            .await?;
+
+        Ok(db
+            .query(&stmt, &[])
+            .await?
+            .iter()
+            .map(|row| RevokedCertificate {
+                serial: row.get("serial"),
+                reason: row.get("reason"),
+                revoked_at: row
+                    .get::<_, chrono::DateTime<chrono::Local>>("revoked_at")
+                    .with_timezone(&chrono::Utc),
+                issuer_key_hash: row.get("issuer_key_hash"),
+            })
+            .collect())
+    }
+}
+
+mod tests {
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_revocations() {
+        use crate::test::PGTest;
+        use spectral::prelude::*;
+
+        let pg = PGTest::new("test_revocations").await.unwrap();
+        let db = pg.db();
+
+        let serial = vec![0xde, 0xad, 0xbe, 0xef];
+
+        assert_that!(db.is_serial_revoked(&serial).await.unwrap()).is_false();
+        assert_that!(db.get_revocation_details(&serial).await.unwrap()).is_none();
+
+        // keyCompromise
+        db.record_revocation(&serial, 1).await.unwrap();
+        assert_that!(db.record_revocation(&serial, 1).await).is_err();
+
+        assert_that!(db.is_serial_revoked(&serial).await.unwrap()).is_true();
+        assert_that!(db.is_serial_revoked(&[0xca, 0xfe]).await.unwrap()).is_false();
+
+        let details = db.get_revocation_details(&serial).await.unwrap().unwrap();
+        assert_that!(details.serial).is_equal_to(serial);
+        assert_that!(details.reason).is_equal_to(1);
+        assert_that!(details.revoked_at).is_less_than_or_equal_to(
+            chrono::DateTime::<chrono::Local>::from(std::time::SystemTime::now()),
+        );
+
+        db.record_revocation(&[0xca, 0xfe], 0).await.unwrap();
+
+        let serials = db
+            .list_revocations()
+            .await
+            .unwrap()
+            .iter()
+            .map(|r| r.serial.clone())
+            .collect::<Vec<Vec<u8>>>();
+        assert_that!(serials).is_equal_to(vec![serial, vec![0xca, 0xfe]]);
+    }
+}
@@ -1,48 +1,74 @@
 #![cfg(test)]
 
 use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
 use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Once;
 use std::{sync::Arc, time::Duration};
 
-use crate::acme::ca::{CACollector, CA};
-use crate::acme::challenge::Challenger;
-use crate::acme::handlers::{configure_routes, HandlerState, ServiceState};
-use crate::acme::PostgresNonceValidator;
-use crate::errors::db::MigrationError;
-use crate::models::Postgres;
+use crate::acme::ca::{CACollector, RotationPolicy, SigningAlgorithm, CA};
+use crate::acme::challenge::{Challenger, TickStats};
+use crate::acme::handlers::order::OrderStatus;
+use crate::acme::handlers::{configure_routes, HandlerState, ServiceState, REPLAY_NONCE_HEADER};
+use crate::acme::jose::{ACMEPrivateKey, ACMEProtectedHeader, JWK, JWS};
+use crate::acme::{NonceValidator, OsNonceGenerator, PostgresNonceValidator};
+use crate::errors::{
+    db::{LoadError, MigrationError, SaveError},
+    ACMEValidationError,
+};
+use crate::models::{
+    account::{Account, KeyRollover},
+    memory::MemoryStore,
+    nonce::NonceState,
+    order::{Authorization, Challenge, ChallengeWithContext, Order},
+    Postgres, PostgresConfig, RetryPolicy, SslConfig, Storage, TenantId,
+};
 use crate::util::make_nonce;
 
 use bollard::container::{LogsOptions, StartContainerOptions};
-use openssl::error::ErrorStack;
+use openssl::{ec::EcKey, error::ErrorStack, pkey::Private, x509::X509};
 use ratpack::app::TestApp;
 use ratpack::prelude::*;
 
+use async_trait::async_trait;
 use bollard::{
     container::{Config, WaitContainerOptions},
-    models::HostConfig,
+    models::{HealthConfig, HealthStatusEnum, HostConfig},
     Docker,
 };
 use eggshell::EggShell;
-use futures::TryStreamExt;
+use futures::{FutureExt, TryStreamExt};
 use lazy_static::lazy_static;
 use openssl::sha::sha256;
 use tempfile::{tempdir, TempDir};
 use thiserror::Error;
 use tokio::net::TcpListener;
 use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 use url::Url;
 
 const DEBUG_VAR: &str = "DEBUG";
+const PGSSL_VAR: &str = "PGSSL";
+const POSTGRES_URL_VAR: &str = "COYOTE_TEST_POSTGRES_URL";
 const ZLINT_WARN_VAR: &str = "ZLINT_WARN";
 
 const HBA_CONFIG_PATH: &str = "hack/pg_hba.conf";
 
 static INIT: Once = Once::new();
+static LOG_INIT: Once = Once::new();
 
 lazy_static! {
     static ref ZLINT_WARN: bool = !std::env::var(ZLINT_WARN_VAR).unwrap_or_default().is_empty();
     static ref DEBUG: bool = !std::env::var(DEBUG_VAR).unwrap_or_default().is_empty();
+    static ref PGSSL: bool = !std::env::var(PGSSL_VAR).unwrap_or_default().is_empty();
+    static ref POSTGRES_URL: Option<String> = std::env::var(POSTGRES_URL_VAR)
+        .ok()
+        .filter(|url| !url.is_empty());
+    static ref LOG_CAPTURES: std::sync::Mutex<Vec<Arc<std::sync::Mutex<Vec<(log::Level, String)>>>>> =
+        std::sync::Mutex::new(Vec::new());
     static ref IMAGES: Vec<&'static str> = vec![
         "certbot/certbot:latest",
         "postgres:latest",
@@ -56,14 +82,161 @@ impl From<MigrationError> for eggshell::Error {
     }
 }
 
+/// PGTest is a postgres database for a test. It is a container launched for the test, unless
+/// COYOTE_TEST_POSTGRES_URL names a server to use instead; see [PGTest::with_config]. Without a
+/// container, there is no docker connection or eggshell either.
 #[derive(Clone)]
 pub struct PGTest {
-    gs: Arc<Mutex<EggShell>>,
+    gs: Option<Arc<Mutex<EggShell>>>,
     postgres: Postgres,
-    docker: Arc<Mutex<Docker>>,
+    docker: Option<Arc<Mutex<Docker>>>,
     // NOTE: the only reason we keep this is to ensure it lives the same lifetime as the PGTest
     // struct; otherwise the temporary directory is removed prematurely.
-    _temp: Arc<Mutex<TempDir>>,
+    _temp: Option<Arc<Mutex<TempDir>>>,
+    // NOTE: same as above; holds the TLS certificates when PGSSL is set.
+    _ssl: Option<Arc<Mutex<TempDir>>>,
+    /// the roles made by [PGTest::create_role], which [PGTest::teardown] drops.
+    roles: Arc<std::sync::Mutex<Vec<String>>>,
+}
+
+/// PGContainer is a postgres container launched by [PGTest::launch_container], and what must be
+/// kept alive alongside it.
+struct PGContainer {
+    gs: EggShell,
+    docker: Arc<Mutex<Docker>>,
+    temp: TempDir,
+    ssl_temp: TempDir,
+}
+
+/// write_ssl_certificates writes a test CA (`ca.pem`) and a server certificate and key signed by
+/// it (`server.crt`, `server.key`) for localhost and 127.0.0.1 into `dir`. It returns the
+/// [SslConfig] which trusts the CA.
+pub(crate) fn write_ssl_certificates(dir: &std::path::Path) -> SslConfig {
+    use openssl::{
+        ec::EcKey,
+        pkey::PKey,
+        x509::{extension::SubjectAlternativeName, X509NameBuilder, X509Req},
+    };
+    use std::time::SystemTime;
+
+    let ca = CA::new_test_ca_with_algorithm(SigningAlgorithm::EcdsaP256).unwrap();
+    let key = PKey::from_ec_key(EcKey::generate(&crate::acme::jose::EC_GROUP).unwrap()).unwrap();
+
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "localhost").unwrap();
+    let mut req = X509Req::builder().unwrap();
+    req.set_subject_name(&name.build()).unwrap();
+    let mut extensions = openssl::stack::Stack::new().unwrap();
+    extensions
+        .push(
+            SubjectAlternativeName::new()
+                .dns("localhost")
+                .ip("127.0.0.1")
+                .build(&req.x509v3_context(None))
+                .unwrap(),
+        )
+        .unwrap();
+    req.add_extensions(&extensions).unwrap();
+    req.set_pubkey(&key).unwrap();
+    req.sign(&key, openssl::hash::MessageDigest::sha256())
+        .unwrap();
+
+    let now = SystemTime::now();
+    let cert = ca
+        .generate_and_sign_cert(req.build(), now, now + Duration::from_secs(24 * 60 * 60))
+        .unwrap();
+
+    std::fs::write(dir.join("ca.pem"), ca.chain_pem().unwrap()).unwrap();
+    std::fs::write(dir.join("server.crt"), cert.to_pem().unwrap()).unwrap();
+    std::fs::write(
+        dir.join("server.key"),
+        key.private_key_to_pem_pkcs8().unwrap(),
+    )
+    .unwrap();
+
+    SslConfig::new(dir.join("ca.pem"))
+}
+
+/// init_logger installs [TestLogger] as the logger for the test binary, once.
+fn init_logger() {
+    LOG_INIT.call_once(|| {
+        let mut builder = &mut env_logger::builder();
+        if *DEBUG {
+            builder = builder.filter_level(log::LevelFilter::Info)
+        }
+
+        log::set_boxed_logger(Box::new(TestLogger {
+            inner: builder.build(),
+        }))
+        .unwrap();
+        // captures want every level; env_logger still filters what it prints itself.
+        log::set_max_level(log::LevelFilter::Trace);
+    });
+}
+
+/// TestLogger prints records as env_logger is configured to (by DEBUG and RUST_LOG), and also
+/// hands every record to the [LogCapture]s alive at the time.
+struct TestLogger {
+    inner: env_logger::Logger,
+}
+
+impl log::Log for TestLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.inner.enabled(metadata) || !LOG_CAPTURES.lock().unwrap().is_empty()
+    }
+
+    fn log(&self, record: &log::Record) {
+        for capture in LOG_CAPTURES.lock().unwrap().iter() {
+            capture
+                .lock()
+                .unwrap()
+                .push((record.level(), record.args().to_string()));
+        }
+
+        if self.inner.matches(record) {
+            self.inner.log(record)
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+/// LogCapture collects the messages logged, at any level, for as long as it is alive. Tests run
+/// concurrently, so it may well see messages from other tests too.
+pub struct LogCapture {
+    lines: Arc<std::sync::Mutex<Vec<(log::Level, String)>>>,
+}
+
+impl LogCapture {
+    pub fn new() -> Self {
+        init_logger();
+
+        let lines = Arc::new(std::sync::Mutex::new(Vec::new()));
+        LOG_CAPTURES.lock().unwrap().push(lines.clone());
+        Self { lines }
+    }
+
+    /// messages returns the messages captured so far at `level`.
+    pub fn messages(&self, level: log::Level) -> Vec<String> {
+        self.lines
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(l, _)| *l == level)
+            .map(|(_, message)| message.clone())
+            .collect()
+    }
+}
+
+impl Drop for LogCapture {
+    fn drop(&mut self) {
+        LOG_CAPTURES
+            .lock()
+            .unwrap()
+            .retain(|lines| !Arc::ptr_eq(lines, &self.lines));
+    }
 }
 
 fn pull_images(images: Vec<&str>) -> () {
@@ -98,17 +271,64 @@ async fn wait_for_images(images: Vec<&str>) -> () {
     }
 }
 
+/// PGTestConfig bounds how long [PGTest::with_config] waits for postgres to accept connections.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PGTestConfig {
+    /// the longest to wait in all.
+    pub connect_timeout: Duration,
+    /// the most connection attempts to make; they are a second apart.
+    pub max_retries: u32,
+}
+
+impl Default for PGTestConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(30),
+            max_retries: 30,
+        }
+    }
+}
+
 impl PGTest {
     pub async fn new(name: &str) -> Result<Self, eggshell::Error> {
+        Self::with_config(name, PGTestConfig::default()).await
+    }
+
+    /// with_config is like new, but gives up waiting for postgres as `config` describes, or as
+    /// soon as docker reports the container unhealthy or stopped.
+    ///
+    /// Either way, the test keeps its tables in a schema of its own, named by [test_schema_name],
+    /// which [PGTest::teardown] drops. If COYOTE_TEST_POSTGRES_URL is set, no container is
+    /// launched: the tests share the database of that server instead, each in its own schema,
+    /// which is emptied first if left over from an earlier run. The URL may be a
+    /// `postgresql://` URL or key=value pairs, and must allow creating schemas.
+    pub async fn with_config(name: &str, config: PGTestConfig) -> Result<Self, eggshell::Error> {
         INIT.call_once(|| {
-            let mut builder = &mut env_logger::builder();
-            if *DEBUG {
-                builder = builder.filter_level(log::LevelFilter::Info)
+            init_logger();
+
+            if POSTGRES_URL.is_none() {
+                pull_images(IMAGES.to_vec());
             }
-            builder.init();
-            pull_images(IMAGES.to_vec());
         });
 
+        match POSTGRES_URL.as_ref() {
+            Some(url) => {
+                let pg_config = PostgresConfig::new(url).with_max_connections(200);
+                Self::connect(name, pg_config, None, config).await
+            }
+            None => {
+                let (container, pg_config) = Self::launch_container(name, &config).await?;
+                Self::connect(name, pg_config, Some(container), config).await
+            }
+        }
+    }
+
+    /// launch_container starts a postgres container for the test, returning it with the
+    /// configuration to reach it by. It does not wait for postgres to come up.
+    async fn launch_container(
+        name: &str,
+        pg_test_config: &PGTestConfig,
+    ) -> Result<(PGContainer, PostgresConfig), eggshell::Error> {
         wait_for_images(IMAGES.to_vec()).await;
 
         let pwd = std::env::current_dir().unwrap();
@@ -125,6 +345,94 @@ impl PGTest {
 
         log::info!("launching postgres instance: {}", name);
 
+        let mut args = vec![
+            "-c",
+            "shared_buffers=512MB",
+            "-c",
+            "max_connections=200",
+            "-c",
+            "unix_socket_permissions=0777",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<String>>();
+
+        let mut binds = vec![
+            format!(
+                "{}:{}",
+                hbapath.to_string_lossy().to_string(),
+                "/etc/postgresql/pg_hba.conf"
+            ),
+            format!("{}:{}", temp.path().display(), "/var/run/postgresql"),
+        ];
+
+        // with PGSSL set, postgres also listens on a loopback port with TLS, and the tests
+        // connect to it there instead of over the socket, which cannot carry TLS.
+        let ssl_temp = tempdir().unwrap();
+        let (entrypoint, network_mode, config, ssl, ready_args) = if *PGSSL {
+            let ssl = write_ssl_certificates(ssl_temp.path());
+            let port = std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port();
+
+            binds.push(format!(
+                "{}:{}",
+                ssl_temp.path().display(),
+                "/etc/postgresql/ssl"
+            ));
+            args.extend(
+                vec![
+                    "-c".to_string(),
+                    "ssl=on".to_string(),
+                    "-c".to_string(),
+                    "ssl_cert_file=/etc/postgresql/ssl/server.crt".to_string(),
+                    "-c".to_string(),
+                    "ssl_key_file=/var/lib/postgresql/server.key".to_string(),
+                    "-c".to_string(),
+                    "listen_addresses=127.0.0.1".to_string(),
+                    "-c".to_string(),
+                    format!("port={}", port),
+                ]
+                .into_iter(),
+            );
+            args.insert(0, "postgres".to_string());
+
+            (
+                // postgres refuses a key which it does not own, or which others may read.
+                Some(
+                    vec![
+                        "/bin/sh",
+                        "-c",
+                        "install -o postgres -m 0600 /etc/postgresql/ssl/server.key /var/lib/postgresql/server.key && exec docker-entrypoint.sh \"$@\"",
+                        "sh",
+                    ]
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect(),
+                ),
+                Some("host".to_string()),
+                format!("host=127.0.0.1 port={} dbname=coyote user=postgres", port),
+                Some(ssl),
+                vec!["-h".to_string(), "127.0.0.1".to_string(), "-p".to_string(), port.to_string()],
+            )
+        } else {
+            (
+                None,
+                None,
+                format!("host={} dbname=coyote user=postgres", temp.path().display()),
+                None,
+                Vec::new(),
+            )
+        };
+
+        let mut healthcheck = vec!["CMD", "pg_isready", "-U", "postgres", "-d", "coyote"]
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<String>>();
+        healthcheck.extend(ready_args);
+
         gs.launch(
             name,
             bollard::container::Config {
@@ -136,126 +444,689 @@ impl PGTest {
                         .collect(),
                 ),
                 host_config: Some(HostConfig {
-                    binds: Some(vec![
-                        format!(
-                            "{}:{}",
-                            hbapath.to_string_lossy().to_string(),
-                            "/etc/postgresql/pg_hba.conf"
-                        ),
-                        format!("{}:{}", temp.path().display(), "/var/run/postgresql"),
-                    ]),
+                    binds: Some(binds),
+                    network_mode,
+                    ..Default::default()
+                }),
+                entrypoint,
+                cmd: Some(args),
+                healthcheck: Some(HealthConfig {
+                    test: Some(healthcheck),
+                    interval: Some(Duration::from_secs(1).as_nanos() as i64),
+                    timeout: Some(Duration::from_secs(5).as_nanos() as i64),
+                    retries: Some(pg_test_config.max_retries as i64),
                     ..Default::default()
                 }),
-                cmd: Some(
-                    vec![
-                        "-c",
-                        "shared_buffers=512MB",
-                        "-c",
-                        "max_connections=200",
-                        "-c",
-                        "unix_socket_permissions=0777",
-                    ]
-                    .iter()
-                    .map(|x| x.to_string())
-                    .collect(),
-                ),
                 ..Default::default()
             },
             None,
         )
         .await?;
 
+        let mut pg_config = PostgresConfig::new(&config).with_max_connections(200);
+        pg_config.ssl = ssl;
+
+        Ok((
+            PGContainer {
+                gs,
+                docker,
+                temp,
+                ssl_temp,
+            },
+            pg_config,
+        ))
+    }
+
+    /// connect waits for postgres to accept connections as `pg_test_config` describes, and
+    /// migrates the database. A container it gives up on is torn down.
+    async fn connect(
+        name: &str,
+        pg_config: PostgresConfig,
+        mut container: Option<PGContainer>,
+        pg_test_config: PGTestConfig,
+    ) -> Result<Self, eggshell::Error> {
         log::info!("waiting for postgres instance: {}", name);
 
-        let mut postgres: Option<Postgres> = None;
-        let config = format!("host={} dbname=coyote user=postgres", temp.path().display());
+        let schema = test_schema_name(name);
+        log::info!("using schema {} for: {}", schema, name);
 
-        while postgres.is_none() {
-            let pg = Postgres::connect_one(&config).await;
+        let postgres = Postgres::with_config(pg_config.with_schema(schema))
+            .await
+            .unwrap();
+
+        let deadline = tokio::time::Instant::now() + pg_test_config.connect_timeout;
+        let mut attempts = 0;
+
+        while postgres.health_check().await.is_err() {
+            attempts += 1;
+
+            let failure = if attempts >= pg_test_config.max_retries
+                || tokio::time::Instant::now() >= deadline
+            {
+                Some("timed out waiting for postgres".to_string())
+            } else if let Some(container) = &container {
+                let state = container
+                    .docker
+                    .lock()
+                    .await
+                    .inspect_container(name, None)
+                    .await
+                    .ok()
+                    .and_then(|c| c.state);
+
+                match state {
+                    Some(state) if state.running == Some(false) => Some(format!(
+                        "postgres container stopped: {}",
+                        state.error.unwrap_or_default()
+                    )),
+                    Some(state)
+                        if state.health.and_then(|h| h.status)
+                            == Some(HealthStatusEnum::UNHEALTHY) =>
+                    {
+                        Some("postgres container is unhealthy".to_string())
+                    }
+                    _ => None,
+                }
+            } else {
+                None
+            };
+
+            if let Some(failure) = failure {
+                // nothing else will tear the container down, as the caller gets no PGTest.
+                if let Some(container) = container.as_mut() {
+                    if let Err(e) = container.gs.teardown().await {
+                        log::error!("could not tear down containers: {}", e);
+                    }
+                }
 
-            match pg {
-                Ok(_) => postgres = Some(Postgres::new(&config, 200).await.unwrap()),
-                Err(_) => tokio::time::sleep(Duration::new(1, 0)).await,
+                return Err(eggshell::Error::Generic(failure));
             }
+
+            tokio::time::sleep(Duration::new(1, 0)).await;
         }
 
         log::info!("connected to postgres instance: {}", name);
 
-        let postgres = postgres.unwrap();
+        // the schema of a test may be left over from an earlier run on an external server.
+        if container.is_none() {
+            postgres
+                .reset()
+                .await
+                .map_err(|e| eggshell::Error::Generic(e.to_string()))?;
+        }
+
         postgres.migrate().await?;
 
-        Ok(Self {
-            docker,
-            gs: Arc::new(Mutex::new(gs)),
-            postgres,
-            _temp: Arc::new(Mutex::new(temp)),
+        Ok(match container {
+            Some(container) => Self {
+                docker: Some(container.docker),
+                gs: Some(Arc::new(Mutex::new(container.gs))),
+                postgres,
+                _temp: Some(Arc::new(Mutex::new(container.temp))),
+                _ssl: Some(Arc::new(Mutex::new(container.ssl_temp))),
+                roles: Default::default(),
+            },
+            None => Self {
+                docker: None,
+                gs: None,
+                postgres,
+                _temp: None,
+                _ssl: None,
+                roles: Default::default(),
+            },
         })
     }
 
+    /// detached makes a PGTest without a database: no container is launched and nothing is
+    /// connected to, so tests which reach for the database fail rather than wait for one.
+    pub(crate) async fn detached() -> Self {
+        init_logger();
+
+        let postgres = Postgres::with_config(
+            PostgresConfig::new("host=127.0.0.1 port=1 user=coyote")
+                .with_acquire_timeout(Some(Duration::from_secs(1)))
+                .with_retry_policy(RetryPolicy::none()),
+        )
+        .await
+        .unwrap();
+
+        Self {
+            docker: None,
+            gs: None,
+            postgres,
+            _temp: None,
+            _ssl: None,
+            roles: Default::default(),
+        }
+    }
+
     pub fn db(&self) -> Postgres {
         self.postgres.clone()
     }
 
-    pub fn eggshell(self) -> Arc<Mutex<EggShell>> {
+    /// create_role makes a login role for the test, named after its schema and `suffix` so that
+    /// tests sharing a server do not share roles, and returns its name. Roles belong to the
+    /// server rather than the schema, so one left over from an earlier run is reused.
+    pub(crate) async fn create_role(&self, suffix: &str) -> Result<String, SaveError> {
+        let role = format!("{}_{}", self.postgres.schema().unwrap(), suffix);
+
+        self.postgres
+            .clone()
+            .client()
+            .await?
+            .batch_execute(&format!(
+                "
+                do $$
+                begin
+                    if not exists (select 1 from pg_roles where rolname = '{0}') then
+                        create role {0} login;
+                    end if;
+                end
+                $$;
+                ",
+                role
+            ))
+            .await?;
+
+        self.roles.lock().unwrap().push(role.clone());
+        Ok(role)
+    }
+
+    /// teardown removes the container launched for the test or, on an external server, drops
+    /// the roles and the schema of the test. It may be called more than once.
+    pub async fn teardown(&self) {
+        match &self.gs {
+            Some(gs) => {
+                if let Err(e) = gs.lock().await.teardown().await {
+                    log::error!("could not tear down containers: {}", e);
+                }
+            }
+            None => {
+                let roles = std::mem::take(&mut *self.roles.lock().unwrap());
+                for role in roles {
+                    if let Err(e) = self.drop_role(&role).await {
+                        log::error!("could not drop test role {}: {}", role, e);
+                    }
+                }
+
+                if let Err(e) = self.postgres.drop_schema().await {
+                    log::error!("could not drop test schema: {}", e);
+                }
+            }
+        }
+    }
+
+    /// drop_role drops a role made by [PGTest::create_role], and the privileges granted to it.
+    async fn drop_role(&self, role: &str) -> Result<(), SaveError> {
+        self.postgres
+            .clone()
+            .client()
+            .await?
+            .batch_execute(&format!(
+                "drop owned by {0}; drop role if exists {0};",
+                role
+            ))
+            .await?;
+
+        Ok(())
+    }
+
+    /// eggshell returns the eggshell which launched the container, if one was launched.
+    pub fn eggshell(self) -> Option<Arc<Mutex<EggShell>>> {
         self.gs
     }
 }
 
+/// test_schema_name names the schema a test keeps its tables in. Tests run in parallel, and on
+/// a server named by COYOTE_TEST_POSTGRES_URL they share a database, so each needs a schema of
+/// its own; the name is derived from the test's so a run cleans up after an earlier one.
+fn test_schema_name(name: &str) -> String {
+    format!("coyote_{}", &stable_id(name)[..16])
+}
+
+/// StorageBackend chooses where a [TestStore] keeps its records.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum StorageBackend {
+    Postgres,
+    Memory,
+}
+
+/// TestStore is a [Storage] for tests. The memory backend starts no containers, so tests which
+/// only need storage semantics should prefer it; [TestService] still needs Postgres, as the
+/// handlers load and save records in Postgres transactions.
+#[derive(Clone)]
+pub(crate) struct TestStore {
+    storage: Arc<dyn Storage + Send + Sync>,
+    // NOTE: kept so the container lives as long as the store.
+    _pg: Option<PGTest>,
+}
+
+impl TestStore {
+    pub(crate) async fn new(name: &str, backend: StorageBackend) -> Self {
+        match backend {
+            StorageBackend::Postgres => {
+                let pg = PGTest::new(name).await.unwrap();
+                Self {
+                    storage: Arc::new(pg.db()),
+                    _pg: Some(pg),
+                }
+            }
+            StorageBackend::Memory => Self {
+                storage: Arc::new(MemoryStore::new()),
+                _pg: None,
+            },
+        }
+    }
+
+    pub(crate) fn storage(&self) -> Arc<dyn Storage + Send + Sync> {
+        self.storage.clone()
+    }
+}
+
+/// StoreNonceValidator keeps the nonces of a [TestService] in a [Storage], as
+/// [PostgresNonceValidator] does in the database.
+#[derive(Clone)]
+pub(crate) struct StoreNonceValidator<S>(pub S);
+
+#[async_trait]
+impl<S: Storage + Send + Sync> NonceValidator for StoreNonceValidator<S> {
+    async fn validate(&self, nonce: &str) -> Result<(), ACMEValidationError> {
+        match self
+            .0
+            .consume_nonce_with_ttl(nonce, Duration::from_secs(60 * 60))
+            .await
+        {
+            Ok(NonceState::Valid) => Ok(()),
+            Ok(NonceState::Expired) => Err(ACMEValidationError::NonceExpired),
+            Ok(NonceState::NotFound) => Err(ACMEValidationError::NonceNotFound),
+            Err(e) => Err(ACMEValidationError::NonceFetchError(e.to_string())),
+        }
+    }
+
+    async fn make(&self) -> Result<String, SaveError> {
+        let nonce = make_nonce(None);
+        self.0.insert_nonce(&nonce).await?;
+        Ok(nonce)
+    }
+}
+
+/// CountingStorage is a [Storage] which counts the calls made through it, by method, before
+/// passing them on; see [ServiceState::with_storage].
+#[derive(Clone)]
+pub(crate) struct CountingStorage {
+    inner: Arc<dyn Storage + Send + Sync>,
+    calls: Arc<std::sync::Mutex<HashMap<&'static str, usize>>>,
+}
+
+impl CountingStorage {
+    pub(crate) fn new(inner: Arc<dyn Storage + Send + Sync>) -> Self {
+        Self {
+            inner,
+            calls: Default::default(),
+        }
+    }
+
+    /// calls returns how often `method` has been called.
+    pub(crate) fn calls(&self, method: &str) -> usize {
+        self.calls
+            .lock()
+            .unwrap()
+            .get(method)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn count(&self, method: &'static str) {
+        *self.calls.lock().unwrap().entry(method).or_insert(0) += 1;
+    }
+}
+
+#[async_trait]
+impl Storage for CountingStorage {
+    fn for_tenant(&self, tenant: &TenantId) -> Arc<dyn Storage + Send + Sync> {
+        Arc::new(Self {
+            inner: self.inner.for_tenant(tenant),
+            calls: self.calls.clone(),
+        })
+    }
+
+    async fn insert_nonce(&self, nonce: &str) -> Result<(), SaveError> {
+        self.count("insert_nonce");
+        self.inner.insert_nonce(nonce).await
+    }
+
+    async fn nonce_count(&self) -> Result<i64, LoadError> {
+        self.count("nonce_count");
+        self.inner.nonce_count().await
+    }
+
+    async fn consume_nonce_with_ttl(
+        &self,
+        nonce: &str,
+        ttl: Duration,
+    ) -> Result<NonceState, SaveError> {
+        self.count("consume_nonce_with_ttl");
+        self.inner.consume_nonce_with_ttl(nonce, ttl).await
+    }
+
+    async fn delete_expired_nonces(&self, ttl: Duration) -> Result<u64, SaveError> {
+        self.count("delete_expired_nonces");
+        self.inner.delete_expired_nonces(ttl).await
+    }
+
+    async fn create_account(&self, account: &mut Account) -> Result<i32, SaveError> {
+        self.count("create_account");
+        self.inner.create_account(account).await
+    }
+
+    async fn record_key_rollover(
+        &self,
+        account_id: i32,
+        old: &str,
+        new: &str,
+    ) -> Result<(), SaveError> {
+        self.count("record_key_rollover");
+        self.inner.record_key_rollover(account_id, old, new).await
+    }
+
+    async fn get_key_history(&self, account_id: i32) -> Result<Vec<KeyRollover>, LoadError> {
+        self.count("get_key_history");
+        self.inner.get_key_history(account_id).await
+    }
+
+    async fn account_deactivated_for_key(
+        &self,
+        jwk: &crate::models::account::JWK,
+    ) -> Result<bool, LoadError> {
+        self.count("account_deactivated_for_key");
+        self.inner.account_deactivated_for_key(jwk).await
+    }
+
+    async fn create_order(&self, order: &mut Order) -> Result<i32, SaveError> {
+        self.count("create_order");
+        self.inner.create_order(order).await
+    }
+
+    async fn order_status_counts(&self) -> Result<HashMap<String, usize>, LoadError> {
+        self.count("order_status_counts");
+        self.inner.order_status_counts().await
+    }
+
+    async fn create_authorization(&self, authz: &mut Authorization) -> Result<i32, SaveError> {
+        self.count("create_authorization");
+        self.inner.create_authorization(authz).await
+    }
+
+    async fn get_authorization(&self, reference: &str) -> Result<Authorization, LoadError> {
+        self.count("get_authorization");
+        self.inner.get_authorization(reference).await
+    }
+
+    async fn update_authorization_status(
+        &self,
+        reference: &str,
+        status: OrderStatus,
+        expected_version: i64,
+    ) -> Result<i64, SaveError> {
+        self.count("update_authorization_status");
+        self.inner
+            .update_authorization_status(reference, status, expected_version)
+            .await
+    }
+
+    async fn create_challenge(&self, challenge: &mut Challenge) -> Result<i32, SaveError> {
+        self.count("create_challenge");
+        self.inner.create_challenge(challenge).await
+    }
+
+    async fn get_challenge_with_context(
+        &self,
+        auth_id: &str,
+        challenge_type: &str,
+    ) -> Result<Option<ChallengeWithContext>, LoadError> {
+        self.count("get_challenge_with_context");
+        self.inner
+            .get_challenge_with_context(auth_id, challenge_type)
+            .await
+    }
+}
+
+/// EcCurve is the curve of the ECDSA key certbot generates for a certificate; see
+/// [TestService::certbot_with_ecc_key].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum EcCurve {
+    P256,
+    P384,
+}
+
+impl EcCurve {
+    /// certbot_name is the name certbot's `--elliptic-curve` knows the curve by.
+    pub(crate) fn certbot_name(&self) -> &'static str {
+        match self {
+            Self::P256 => "secp256r1",
+            Self::P384 => "secp384r1",
+        }
+    }
+
+    /// nid is the curve's name in openssl, for checking the key of an issued certificate.
+    pub(crate) fn nid(&self) -> openssl::nid::Nid {
+        match self {
+            Self::P256 => openssl::nid::Nid::X9_62_PRIME256V1,
+            Self::P384 => openssl::nid::Nid::SECP384R1,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Error)]
 pub(crate) enum ContainerError {
     #[error("Unknown error encountered: {0}")]
     Generic(String),
 
-    #[error("container failed with exit status: {0}: {1}")]
-    Failed(i64, String),
+    #[error("container failed with exit status: {0}: {}", .1.join("\n"))]
+    Failed(i64, Vec<String>),
 
     #[error("zlint failures follow: {0:?}")]
     ZLint(HashSet<String>),
 }
 
-fn short_hash(s: String) -> String {
-    String::from(
-        &sha256(s.as_bytes())
-            .iter()
-            .map(|c| format!("{:x}", c))
-            .take(10)
-            .collect::<Vec<String>>()
-            .join("")[0..10],
+/// jws_alg is the JWS algorithm for signing with `key`, by its curve.
+pub(crate) fn jws_alg(key: &EcKey<Private>) -> &'static str {
+    if key.group().curve_name() == crate::acme::jose::EC_GROUP_P384.curve_name() {
+        "ES384"
+    } else {
+        "ES256"
+    }
+}
+
+/// stable_id is the full SHA-256 of `s` in hex, so the same input always yields the same ID and
+/// different inputs practically never collide.
+fn stable_id(s: &str) -> String {
+    sha256(s.as_bytes())
+        .iter()
+        .map(|c| format!("{:02x}", c))
+        .collect()
+}
+
+/// container_name names a container launched by a test; the counter keeps names unique within
+/// the test run even if two nonces were to collide.
+fn container_name(prefix: &str) -> String {
+    static CONTAINER_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    format!(
+        "{}-{}-{}",
+        prefix,
+        stable_id(&make_nonce(None)),
+        CONTAINER_COUNTER.fetch_add(1, Ordering::SeqCst)
     )
 }
 
+/// http01_port picks a port for certbot's standalone server to answer http-01 challenges on, so
+/// that certbots running at the same time do not fight over one.
+fn http01_port() -> u16 {
+    rand::random::<u16>() % 10000 + 1024
+}
+
+/// test_ca returns a closure making a self-signed test CA, for [TestService::new_with].
+fn test_ca(algorithm: SigningAlgorithm) -> impl FnOnce() -> (CA, Vec<X509>) + Send + 'static {
+    move || (CA::new_test_ca_with_algorithm(algorithm).unwrap(), vec![])
+}
+
+/// TestServiceOptions adjusts how [TestService::with_options] builds the service. The default
+/// matches [TestService::new].
+#[derive(Default)]
+pub(crate) struct TestServiceOptions {
+    /// the CA and chain to sign with, instead of a freshly generated self-signed test CA.
+    pub ca: Option<(CA, Vec<X509>)>,
+    /// do not run the challenger's reconcile loop, so challenges are never validated.
+    pub skip_reconcile: bool,
+    /// keep records and nonces in the store rather than in a postgres container.
+    /// Nothing is reconciled, and handlers which use the database for what [Storage] does not
+    /// cover fail.
+    pub memory_store: Option<MemoryStore>,
+}
+
 #[derive(Clone)]
 pub(crate) struct TestService {
     pub pg: Box<PGTest>,
     pub app: ratpack::app::TestApp<ServiceState, HandlerState>,
+    pub ca: CACollector,
+    // the CA the collector is given on each poll; see [TestService::rotate_ca].
+    next_ca: Arc<std::sync::Mutex<Option<(CA, Vec<X509>)>>>,
     pub url: String,
+    // stops the server and the challenger's reconciliation; clones share it, so the service is
+    // stopped by shutdown rather than on drop.
+    cancel: CancellationToken,
+    server: Arc<Mutex<Option<JoinHandle<()>>>>,
+    ticks: tokio::sync::watch::Receiver<TickStats>,
 }
 
 impl TestService {
     pub(crate) async fn new(name: &str) -> Self {
-        let pg = PGTest::new(name).await.unwrap();
-        let c = Challenger::new(Some(chrono::Duration::seconds(60)));
-        let validator = PostgresNonceValidator::new(pg.db().clone());
+        Self::new_with_state(name, |state| state).await
+    }
 
-        let c2 = c.clone();
-        let pg2 = pg.db().clone();
+    /// new_with_state is like new, but allows the test to adjust the service state (e.g. with
+    /// the `with_*` methods) before the service is started.
+    pub(crate) async fn new_with_state<F>(name: &str, f: F) -> Self
+    where
+        F: FnOnce(ServiceState) -> ServiceState,
+    {
+        Self::new_with(name, test_ca(SigningAlgorithm::Rsa4096), false, None, f).await
+    }
 
-        tokio::spawn(async move {
-            loop {
-                c2.tick(|_c| Some(())).await;
-                c2.reconcile(pg2.clone()).await.unwrap();
+    /// with_options is like new, but the service is built according to the options provided.
+    pub(crate) async fn with_options(name: &str, options: TestServiceOptions) -> Self {
+        Self::with_options_and_state(name, options, |state| state).await
+    }
 
-                tokio::time::sleep(Duration::new(0, 250)).await;
+    /// with_options_and_state is like with_options, but allows the test to adjust the service
+    /// state as with new_with_state. A [TestServiceOptions::memory_store] is already set as the
+    /// storage of the state `f` is given.
+    pub(crate) async fn with_options_and_state<F>(
+        name: &str,
+        options: TestServiceOptions,
+        f: F,
+    ) -> Self
+    where
+        F: FnOnce(ServiceState) -> ServiceState,
+    {
+        let (skip_reconcile, memory_store) = (options.skip_reconcile, options.memory_store);
+
+        match options.ca {
+            Some(ca) => Self::new_with(name, move || ca, skip_reconcile, memory_store, f).await,
+            None => {
+                Self::new_with(
+                    name,
+                    test_ca(SigningAlgorithm::Rsa4096),
+                    skip_reconcile,
+                    memory_store,
+                    f,
+                )
+                .await
             }
-        });
+        }
+    }
+
+    /// new_with_ca_algorithm is like new, but the test CA signs with the algorithm provided.
+    pub(crate) async fn new_with_ca_algorithm(name: &str, algorithm: SigningAlgorithm) -> Self {
+        Self::new_with(name, test_ca(algorithm), false, None, |state| state).await
+    }
+
+    /// new_with_intermediate_ca is like new, but certificates are signed by an intermediate CA,
+    /// which is signed by a root CA.
+    pub(crate) async fn new_with_intermediate_ca(name: &str) -> Self {
+        let make_ca = || {
+            let root = CA::new_test_root_ca(SigningAlgorithm::Rsa2048).unwrap();
+            let intermediate = CA::new_intermediate(
+                &root,
+                "Intermediate Signing Certificate",
+                SigningAlgorithm::EcdsaP256,
+                Duration::from_secs(90 * 24 * 60 * 60),
+            )
+            .unwrap();
+
+            (intermediate, vec![root.certificate()])
+        };
+
+        Self::new_with(name, make_ca, false, None, |state| state).await
+    }
+
+    /// new_with starts the service with the CA and chain `make_ca` returns, which is called in
+    /// the background as generating keys can be slow. Unless `skip_reconcile` is set, the
+    /// challenger reconciles with the database in the background too. With a `memory_store`, no
+    /// database is started; see [TestServiceOptions::memory_store].
+    async fn new_with<C, F>(
+        name: &str,
+        make_ca: C,
+        skip_reconcile: bool,
+        memory_store: Option<MemoryStore>,
+        f: F,
+    ) -> Self
+    where
+        C: FnOnce() -> (CA, Vec<X509>) + Send + 'static,
+        F: FnOnce(ServiceState) -> ServiceState,
+    {
+        let pg = match memory_store {
+            Some(_) => PGTest::detached().await,
+            None => PGTest::new(name).await.unwrap(),
+        };
+        let c = Challenger::new(Some(chrono::Duration::seconds(60)));
+        let ticks = c.tick_receiver();
+        let validator = PostgresNonceValidator::new(pg.db().clone(), None)
+            .with_generator(Box::new(OsNonceGenerator));
+        let cancel = CancellationToken::new();
+
+        if !skip_reconcile && memory_store.is_none() {
+            let c2 = c.clone();
+            let pg2 = pg.db().clone();
+            let cancel2 = cancel.clone();
+
+            tokio::spawn(async move {
+                while !cancel2.is_cancelled() {
+                    c2.tick(|_c, _| Some(())).await;
+                    c2.reconcile(pg2.clone()).await.unwrap();
+
+                    tokio::time::sleep(Duration::new(0, 250)).await;
+                }
+            });
+        }
 
         let ca = CACollector::new(Duration::new(0, 250));
         let mut ca2 = ca.clone();
+        let next_ca: Arc<std::sync::Mutex<Option<(CA, Vec<X509>)>>> = Default::default();
+        let next_ca2 = next_ca.clone();
 
         tokio::spawn(async move {
-            let ca = CA::new_test_ca().unwrap();
-            ca2.spawn_collector(|| -> Result<CA, ErrorStack> { Ok(ca.clone()) })
-                .await
+            let made = make_ca();
+            next_ca2.lock().unwrap().replace(made);
+
+            ca2.spawn_collector(
+                move || -> Result<(CA, Vec<X509>), ErrorStack> {
+                    Ok(next_ca2.lock().unwrap().clone().unwrap())
+                },
+                RotationPolicy::default(),
+            )
+            .await
         });
 
         let lis = TcpListener::bind("127.0.0.1:0").await.unwrap();
@@ -263,32 +1134,166 @@ impl TestService {
         let url = format!("http://{}", addr);
         drop(lis);
 
-        let mut app = App::with_state(
-            ServiceState::new(url.clone(), pg.db(), c, ca, validator.clone()).unwrap(),
-        );
+        let mut state = ServiceState::builder()
+            .url(url.clone())
+            .db(pg.db())
+            .challenger(c)
+            .ca(ca.clone())
+            .nonce_validator(validator.clone())
+            .build()
+            .unwrap();
+
+        if let Some(store) = memory_store {
+            state = state
+                .with_nonce_validator(Box::new(StoreNonceValidator(store.clone())))
+                .with_storage(Box::new(store));
+        }
+
+        let mut app = App::with_state(f(state));
 
         configure_routes(&mut app, None);
 
         let a = app.clone();
-
-        tokio::spawn(async move {
-            a.serve(&addr.clone().to_string()).await.unwrap();
+        let cancel2 = cancel.clone();
+
+        // ratpack cannot stop serving gracefully, so the server is dropped, closing its
+        // listener, when the service is shut down.
+        let server = tokio::spawn(async move {
+            tokio::select! {
+                res = a.serve(&addr.clone().to_string()) => res.unwrap(),
+                _ = cancel2.cancelled() => {},
+            }
         });
 
         Self {
             pg: Box::new(pg),
             app: TestApp::new(app),
+            ca,
+            next_ca,
             url,
+            cancel,
+            server: Arc::new(Mutex::new(Some(server))),
+            ticks,
         }
     }
 
+    /// rotate_ca has the collector replace the CA the service signs with by the one provided,
+    /// and returns once it has.
+    pub(crate) async fn rotate_ca(&self, ca: CA, chain: Vec<X509>) {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while self.ca.clone().ca().read().await.is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+
+            let rotations = self.ca.rotations();
+            self.next_ca.lock().unwrap().replace((ca, chain));
+
+            while self.ca.rotations() == rotations {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the CA was not rotated in time");
+    }
+
+    /// wait_for_reconcile returns once the challenger next reconciles, rather than after a guess
+    /// at how long that takes. A challenge requested while a tick was under way is only decided by
+    /// the one after, so check and wait again. It panics if no reconcile comes within ten
+    /// seconds, e.g. when the service was made with `skip_reconcile`.
+    pub(crate) async fn wait_for_reconcile(&self) {
+        let mut ticks = self.ticks.clone();
+        ticks.borrow_and_update();
+
+        tokio::time::timeout(Duration::from_secs(10), ticks.changed())
+            .await
+            .expect("the challenger did not reconcile in time")
+            .unwrap();
+    }
+
+    /// run_test_with_cleanup runs the test future provided, and shuts the service down
+    /// afterwards, even if the test panicked. Panics are re-raised after cleanup.
+    pub(crate) async fn run_test_with_cleanup<F, T>(&self, f: F) -> T
+    where
+        F: Future<Output = T>,
+    {
+        let res = AssertUnwindSafe(f).catch_unwind().await;
+
+        self.shutdown().await;
+
+        match res {
+            Ok(res) => res,
+            Err(e) => std::panic::resume_unwind(e),
+        }
+    }
+
+    /// post_jws is a minimal ACME client, for what certbot cannot be made to send: it signs the
+    /// payload for `url` with `key`, identified by `kid` or else by its JWK, and replaces `nonce`
+    /// with the replay nonce of the response for the next request. The body is returned parsed,
+    /// or as null if it is not JSON.
+    pub(crate) async fn post_jws<T: serde::Serialize + ?Sized>(
+        &self,
+        key: &EcKey<Private>,
+        kid: Option<&str>,
+        nonce: &mut String,
+        url: &str,
+        payload: &T,
+    ) -> (Response<Body>, serde_json::Value) {
+        let url = Url::parse(url).unwrap();
+        let protected = match kid {
+            Some(kid) => {
+                ACMEProtectedHeader::new_kid(Url::parse(kid).unwrap(), url.clone(), nonce.clone())
+            }
+            None => ACMEProtectedHeader::new_jwk(
+                JWK::try_from(key).unwrap(),
+                url.clone(),
+                nonce.clone(),
+            ),
+        }
+        .with_alg(jws_alg(key));
+
+        let jws = JWS::new(&protected, payload)
+            .sign(ACMEPrivateKey::ECDSA(key.clone()))
+            .unwrap();
+
+        let mut res = self
+            .app
+            .post(url.path(), Body::from(serde_json::to_string(&jws).unwrap()))
+            .await;
+
+        *nonce = match res.headers().get(REPLAY_NONCE_HEADER) {
+            Some(n) => n.to_str().unwrap().to_string(),
+            None => self.app.head("/nonce").await.headers()[REPLAY_NONCE_HEADER]
+                .to_str()
+                .unwrap()
+                .to_string(),
+        };
+
+        let body = hyper::body::to_bytes(res.body_mut()).await.unwrap();
+        let value = serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null);
+        (res, value)
+    }
+
+    /// shutdown stops the server, waiting for it to release its port, drops the schema of the
+    /// test and removes all containers launched on behalf of this service.
+    pub(crate) async fn shutdown(&self) {
+        self.cancel.cancel();
+
+        if let Some(server) = self.server.lock().await.take() {
+            if let Err(e) = server.await {
+                log::error!("test server did not stop cleanly: {}", e);
+            }
+        }
+
+        self.pg.teardown().await;
+    }
+
     pub(crate) async fn zlint(
         &self,
         domain: &str,
         certs: Arc<TempDir>,
     ) -> Result<(), ContainerError> {
         log::info!("letsencrypt dir: {}", certs.path().display());
-        let name = &format!("zlint-{}", short_hash(make_nonce(None)));
+        let name = &container_name("zlint");
 
         let res = self
             .launch(
@@ -362,7 +1367,6 @@ impl TestService {
         command: String,
     ) -> Result<Arc<TempDir>, ContainerError> {
         let server_url = Url::parse(&self.url).unwrap();
-        let server_url_hash = short_hash(server_url.to_string());
         let certs: Arc<tempfile::TempDir> = match certs {
             Some(certs) => certs,
             None => Arc::new(tempdir().unwrap()),
@@ -370,11 +1374,7 @@ impl TestService {
 
         log::info!("letsencrypt dir: {}", certs.path().display());
 
-        let name = &format!(
-            "certbot-{}-{}",
-            server_url_hash,
-            short_hash(make_nonce(None))
-        );
+        let name = &container_name(&format!("certbot-{}", stable_id(server_url.as_str())));
 
         let res = self
             .launch(
@@ -416,28 +1416,132 @@ impl TestService {
         return Ok(certs);
     }
 
+    /// run_certbot_register registers an account for `email` with the service.
+    pub(crate) async fn run_certbot_register(
+        &self,
+        email: &str,
+    ) -> Result<Arc<TempDir>, ContainerError> {
+        self.certbot(None, format!("register -m '{}' --agree-tos", email))
+            .await
+    }
+
+    /// run_certbot_certonly obtains a certificate for `domain`, answering the http-01 challenge
+    /// with certbot's standalone server, and registering an account for `email` unless `certs`
+    /// already holds one.
+    pub(crate) async fn run_certbot_certonly(
+        &self,
+        certs: Option<Arc<TempDir>>,
+        domain: &str,
+        email: &str,
+    ) -> Result<Arc<TempDir>, ContainerError> {
+        self.certbot(
+            certs,
+            format!(
+                "certonly --http-01-port {} --standalone -d '{}' -m '{}' --agree-tos",
+                http01_port(),
+                domain,
+                email
+            ),
+        )
+        .await
+    }
+
+    /// certbot_with_ecc_key is [TestService::run_certbot_certonly] into a fresh directory, but
+    /// has certbot request the certificate for an ECDSA key on `curve` rather than for an RSA one.
+    pub(crate) async fn certbot_with_ecc_key(
+        &self,
+        domain: &str,
+        email: &str,
+        curve: EcCurve,
+    ) -> Result<Arc<TempDir>, ContainerError> {
+        self.certbot(
+            None,
+            format!(
+                "certonly --http-01-port {} --standalone -d '{}' -m '{}' --agree-tos --key-type ecdsa --elliptic-curve {}",
+                http01_port(),
+                domain,
+                email,
+                curve.certbot_name()
+            ),
+        )
+        .await
+    }
+
+    /// run_certbot_renew renews the certificate for `domain` which was obtained into `certs`
+    /// with [TestService::run_certbot_certonly], whether or not it is due.
+    pub(crate) async fn run_certbot_renew(
+        &self,
+        certs: Arc<TempDir>,
+        domain: &str,
+    ) -> Result<Arc<TempDir>, ContainerError> {
+        self.certbot(
+            Some(certs),
+            format!(
+                "renew --force-renewal --cert-name '{}' --http-01-port {}",
+                domain,
+                http01_port()
+            ),
+        )
+        .await
+    }
+
     async fn launch(
         &self,
         name: &str,
         config: Config<String>,
         start_opts: Option<StartContainerOptions<String>>,
     ) -> Result<(), eggshell::Error> {
-        self.pg.clone().eggshell().lock().await.set_debug(*DEBUG);
+        // without a postgres container, there is no docker to launch anything else with either.
+        let gs = match self.pg.clone().eggshell() {
+            Some(gs) => gs,
+            None => {
+                return Err(eggshell::Error::Generic(format!(
+                    "cannot launch {} without docker",
+                    name
+                )))
+            }
+        };
 
-        self.pg
-            .clone()
-            .eggshell()
-            .lock()
-            .await
-            .launch(name, config, start_opts)
+        let mut gs = gs.lock().await;
+        gs.set_debug(*DEBUG);
+        gs.launch(name, config, start_opts).await
+    }
+
+    /// logs collects everything the container has written to the streams asked for.
+    async fn logs(
+        docker: &Docker,
+        name: &str,
+        stdout: bool,
+        stderr: bool,
+    ) -> Result<Vec<String>, ContainerError> {
+        let logs = docker
+            .logs::<String>(
+                name,
+                Some(LogsOptions::<String> {
+                    stdout,
+                    stderr,
+                    ..Default::default()
+                }),
+            )
+            .try_collect::<Vec<_>>()
             .await
+            .map_err(|e| ContainerError::Generic(e.to_string()))?;
+
+        Ok(logs.iter().map(|l| l.to_string()).collect())
     }
 
     async fn wait(&self, name: &str, pass_stdout: bool) -> Result<Option<String>, ContainerError> {
         loop {
             tokio::time::sleep(Duration::new(1, 0)).await;
 
-            let locked = self.pg.docker.lock().await;
+            let locked = match &self.pg.docker {
+                Some(docker) => docker.lock().await,
+                None => {
+                    return Err(ContainerError::Generic(
+                        "docker is not available".to_string(),
+                    ))
+                }
+            };
             let waitres = locked
                 .wait_container::<String>(
                     name,
@@ -450,49 +1554,49 @@ impl TestService {
 
             if let Ok(Some(res)) = waitres {
                 if res.status_code != 0 || res.error.is_some() {
-                    let mut error = res.error.unwrap_or_default().message;
+                    let mut lines = Vec::new();
+                    if let Some(message) = res.error.unwrap_or_default().message {
+                        lines.push(message);
+                    }
 
-                    let logs = locked
-                        .logs::<String>(
-                            name,
-                            Some(LogsOptions::<String> {
-                                stderr: *DEBUG,
-                                stdout: *DEBUG,
-                                ..Default::default()
-                            }),
-                        )
-                        .try_next()
-                        .await;
-                    if let Ok(Some(logs)) = logs {
-                        error = Some(format!("{}", logs));
-                        let logs = logs.into_bytes();
-                        if logs.len() > 50 && *DEBUG {
-                            std::fs::write("error.log", logs).unwrap();
-                            error = Some("error too long: error written to error.log".to_string())
+                    match Self::logs(&locked, name, true, true).await {
+                        Ok(logs) => {
+                            lines.extend(logs.iter().flat_map(|l| l.lines()).map(|l| l.to_string()))
                         }
+                        Err(e) => lines.push(format!("could not retrieve logs: {}", e)),
                     }
 
-                    return Err(ContainerError::Failed(
-                        res.status_code,
-                        error.unwrap_or_default(),
-                    ));
-                } else if pass_stdout {
-                    let logs = locked
-                        .logs::<String>(
+                    // kept for CI to pick up as an artifact, as the test output may not be.
+                    let path = std::env::temp_dir().join(format!("{}-error.log", name));
+                    if let Err(e) = std::fs::write(&path, lines.join("\n")) {
+                        log::error!("could not write {}: {}", path.display(), e);
+                    }
+
+                    if *DEBUG {
+                        log::error!(
+                            "container {} failed with exit status {}:\n{}",
                             name,
-                            Some(LogsOptions::<String> {
-                                stdout: true,
-                                ..Default::default()
-                            }),
-                        )
-                        .try_next()
-                        .await;
-
-                    if let Ok(Some(logs)) = logs {
-                        return Ok(Some(logs.to_string()));
+                            res.status_code,
+                            lines.join("\n")
+                        );
                     } else {
+                        log::error!(
+                            "container {} failed with exit status {}; logs written to {}",
+                            name,
+                            res.status_code,
+                            path.display()
+                        );
+                    }
+
+                    return Err(ContainerError::Failed(res.status_code, lines));
+                } else if pass_stdout {
+                    let logs = Self::logs(&locked, name, true, false).await?;
+
+                    if logs.is_empty() {
                         return Err(ContainerError::Generic("no logs returned".to_string()));
                     }
+
+                    return Ok(Some(logs.join("")));
                 } else {
                     return Ok(None);
                 }
@@ -510,4 +1614,233 @@ mod tests {
         let res = PGTest::new("pgtest_basic").await;
         assert_that!(res.is_ok()).is_true();
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pgtest_schema() {
+        use super::{test_schema_name, PGTest};
+        use spectral::prelude::*;
+
+        let name = test_schema_name("pgtest_schema");
+        assert_that!(name.len()).is_equal_to(23);
+        assert_that!(name).is_equal_to(test_schema_name("pgtest_schema"));
+        assert_that!(name).is_not_equal_to(test_schema_name("pgtest_schema2"));
+
+        let pg = PGTest::new("pgtest_schema").await.unwrap();
+        let c = pg.db().client().await.unwrap();
+
+        let schema: String = c
+            .query_one("select current_schema()", &[])
+            .await
+            .unwrap()
+            .get(0);
+        assert_that!(schema).is_equal_to(name.clone());
+
+        let tables: i64 = c
+            .query_one(
+                "select count(*) from information_schema.tables where table_schema = $1 and table_name = 'nonces'",
+                &[&name],
+            )
+            .await
+            .unwrap()
+            .get(0);
+        assert_that!(tables).is_equal_to(1);
+
+        pg.teardown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pgtest_timeout() {
+        use super::{PGTest, PGTestConfig};
+        use bollard::Docker;
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        // an external postgres is already up.
+        if super::POSTGRES_URL.is_some() {
+            return;
+        }
+
+        // postgres takes longer than this to initialize its database.
+        let res = PGTest::with_config(
+            "pgtest_timeout",
+            PGTestConfig {
+                connect_timeout: Duration::from_millis(100),
+                max_retries: 1,
+            },
+        )
+        .await;
+
+        match res {
+            Err(eggshell::Error::Generic(e)) => {
+                assert_that!(e.as_str()).is_equal_to("timed out waiting for postgres")
+            }
+            _ => panic!("expected a timeout"),
+        }
+
+        let res = Docker::connect_with_local_defaults()
+            .unwrap()
+            .inspect_container("pgtest_timeout", None)
+            .await;
+        assert_that!(res.is_err()).is_true();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_service_with_options() {
+        use super::{TestService, TestServiceOptions};
+        use crate::acme::ca::CA;
+        use spectral::prelude::*;
+        use std::time::Duration;
+
+        let ca = CA::new_test_ca().unwrap();
+        let der = ca.clone().certificate().to_der().unwrap();
+
+        let srv = TestService::with_options(
+            "test_service_with_options",
+            TestServiceOptions {
+                ca: Some((ca, vec![])),
+                skip_reconcile: true,
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let shared = srv.ca.clone().ca();
+        let collected = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(ca) = shared.read().await.clone() {
+                    return ca;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_that!(collected.certificate().to_der().unwrap()).is_equal_to(der);
+        srv.shutdown().await;
+    }
+
+    #[test]
+    fn test_container_names() {
+        use super::{container_name, stable_id};
+        use spectral::prelude::*;
+        use std::collections::HashSet;
+
+        assert_that!(stable_id("foo")).is_equal_to(
+            "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae".to_string(),
+        );
+        assert_that!(stable_id("foo")).is_equal_to(stable_id("foo"));
+
+        let names = (0..1000)
+            .map(|_| container_name("test"))
+            .collect::<HashSet<String>>();
+        assert_that!(names.len()).is_equal_to(1000);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_shutdown_stops_server() {
+        use super::TestService;
+        use spectral::prelude::*;
+        use tokio::net::TcpStream;
+
+        let srv = TestService::new("test_shutdown_stops_server").await;
+        let addr = url::Url::parse(&srv.url)
+            .unwrap()
+            .socket_addrs(|| None)
+            .unwrap()[0];
+
+        // the server is spawned, so give it a moment to bind.
+        let mut connected = false;
+        for _ in 0..50 {
+            if TcpStream::connect(addr).await.is_ok() {
+                connected = true;
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        assert_that!(connected).is_true();
+
+        srv.shutdown().await;
+        assert_that!(TcpStream::connect(addr).await).is_err();
+
+        // shutting down again, e.g. from a clone, is harmless.
+        srv.clone().shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_run_test_with_cleanup() {
+        use super::TestService;
+        use futures::FutureExt;
+        use spectral::prelude::*;
+        use std::panic::AssertUnwindSafe;
+
+        let srv = TestService::new("test_run_test_with_cleanup").await;
+
+        let res = AssertUnwindSafe(srv.run_test_with_cleanup(async {
+            panic!("this test is supposed to panic");
+        }))
+        .catch_unwind()
+        .await;
+        assert_that!(res.is_err()).is_true();
+
+        let res = srv
+            .pg
+            .docker
+            .as_ref()
+            .unwrap()
+            .lock()
+            .await
+            .inspect_container("test_run_test_with_cleanup", None)
+            .await;
+        assert_that!(res.is_err()).is_true();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_container_failure_logs() {
+        use super::{ContainerError, TestService};
+        use spectral::prelude::*;
+
+        let srv = TestService::new("test_container_failure_logs").await;
+
+        let res = srv
+            .clone()
+            .certbot(None, "certonly --no-such-flag".to_string())
+            .await;
+
+        match res {
+            Err(ContainerError::Failed(status, lines)) => {
+                assert_that!(status).is_not_equal_to(0);
+                assert_that!(lines.iter().any(|l| l.contains("no-such-flag"))).is_true();
+            }
+            res => panic!("expected the container to fail, got {:?}", res.map(|_| ())),
+        }
+
+        srv.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_certbot_renew() {
+        use super::TestService;
+        use spectral::prelude::*;
+
+        let srv = TestService::new("test_certbot_renew").await;
+
+        let dir = srv
+            .run_certbot_certonly(None, "foo.com", "erik@hollensbe.org")
+            .await
+            .unwrap();
+        let archive = dir.path().join("archive/foo.com");
+        assert_that!(archive.join("cert1.pem").exists()).is_true();
+        assert_that!(archive.join("cert2.pem").exists()).is_false();
+
+        let dir = srv.run_certbot_renew(dir, "foo.com").await.unwrap();
+        assert_that!(archive.join("cert2.pem").exists()).is_true();
+        assert_that!(std::fs::read(archive.join("cert2.pem")).unwrap())
+            .is_not_equal_to(std::fs::read(archive.join("cert1.pem")).unwrap());
+
+        // there is nothing to renew for a domain without a certificate.
+        assert_that!(srv.run_certbot_renew(dir, "bar.com").await).is_err();
+
+        srv.shutdown().await;
+    }
 }
@@ -1,16 +1,41 @@
 use rand::Fill;
 
-const DEFAULT_NONCE_SIZE: usize = 64;
+pub(crate) const DEFAULT_NONCE_SIZE: usize = 32;
 
-// generate some random bytes
+// generate some random bytes, base64 encoded; DEFAULT_NONCE_SIZE bytes unless told otherwise
 pub(crate) fn make_nonce(len: Option<usize>) -> String {
-    let mut r = Vec::new();
-    r.resize(len.unwrap_or(DEFAULT_NONCE_SIZE), 0);
+    base64::encode_config(
+        make_nonce_bytes(len.unwrap_or(DEFAULT_NONCE_SIZE)),
+        base64::URL_SAFE_NO_PAD,
+    )
+}
 
+// generate some random bytes from the openssl CSPRNG
+pub(crate) fn make_nonce_bytes(len: usize) -> Vec<u8> {
+    let mut r = vec![0; len];
+    openssl::rand::rand_bytes(&mut r).expect("Couldn't do a random");
+    r
+}
+
+// generate a random (version 4) UUID, in its hyphenated form
+pub(crate) fn make_uuid() -> String {
+    let mut r = [0u8; 16];
     r.try_fill(&mut rand::thread_rng())
         .expect("Couldn't do a random");
 
-    base64::encode_config(r, base64::URL_SAFE_NO_PAD)
+    r[6] = (r[6] & 0x0f) | 0x40;
+    r[8] = (r[8] & 0x3f) | 0x80;
+
+    let hex = r.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
 }
 
 pub(crate) fn to_base64<T>(payload: &T) -> Result<String, serde_json::Error>
@@ -22,3 +47,23 @@ where
         base64::URL_SAFE_NO_PAD,
     ))
 }
+
+mod tests {
+    #[test]
+    fn test_make_nonce() {
+        use super::{make_nonce, make_nonce_bytes};
+        use spectral::prelude::*;
+        use std::collections::HashSet;
+
+        // 32 bytes, unpadded base64
+        assert_that!(make_nonce(None).len()).is_equal_to(43);
+        assert_that!(make_nonce(Some(16)).len()).is_equal_to(22);
+        assert_that!(make_nonce_bytes(7).len()).is_equal_to(7);
+        assert_that!(make_nonce_bytes(0)).is_empty();
+
+        let mut seen = HashSet::new();
+        for _ in 0..10000 {
+            assert_that!(seen.insert(make_nonce(Some(16)))).is_true();
+        }
+    }
+}